@@ -0,0 +1,108 @@
+//! Benchmarks of the storage layer.
+//!
+//! Run with `cargo bench -p hagrid-database`.  Populating the
+//! databases generates every key, so a full run takes a while.
+
+#[macro_use]
+extern crate criterion;
+extern crate hagrid_database;
+extern crate sequoia_openpgp as openpgp;
+extern crate tempfile;
+
+use std::convert::TryFrom;
+
+use criterion::{BatchSize, BenchmarkId, Criterion};
+use openpgp::cert::CertBuilder;
+use openpgp::Cert;
+use tempfile::TempDir;
+
+use hagrid_database::types::{Email, Fingerprint};
+use hagrid_database::{Database, KeyDatabase};
+
+/// Number of keys in the populated databases.
+const SIZES: &[usize] = &[10_000, 100_000];
+
+fn email(i: usize) -> Email {
+    format!("user{}@bench.example.org", i).parse().unwrap()
+}
+
+fn generate(uid: &str) -> Cert {
+    CertBuilder::new()
+        .add_userid(uid)
+        .add_signing_subkey()
+        .generate()
+        .unwrap()
+        .0
+}
+
+/// A database backend under test.
+struct Backend<D: Database> {
+    name: &'static str,
+    open: fn(&TempDir) -> D,
+}
+
+fn filesystem() -> Backend<KeyDatabase> {
+    Backend {
+        name: "fs",
+        open: |tmpdir| KeyDatabase::new_from_base(tmpdir.path()).unwrap(),
+    }
+}
+
+/// Opens a database holding `size` keys with one published address
+/// each.
+fn populate<D: Database>(backend: &Backend<D>, size: usize) -> (TempDir, D) {
+    let tmpdir = TempDir::new().unwrap();
+    let db = (backend.open)(&tmpdir);
+    for i in 0..size {
+        let email = email(i);
+        let cert = generate(&email.to_string());
+        let fpr = Fingerprint::try_from(cert.fingerprint()).unwrap();
+        db.merge(cert).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+    }
+    (tmpdir, db)
+}
+
+fn bench_backend<D: Database>(c: &mut Criterion, backend: Backend<D>) {
+    for &size in SIZES {
+        let (_tmpdir, db) = populate(&backend, size);
+
+        let mut group = c.benchmark_group(backend.name);
+        group.sample_size(10);
+
+        let mut next = size;
+        group.bench_with_input(BenchmarkId::new("merge", size), &size, |b, _| {
+            b.iter_batched(
+                || {
+                    next += 1;
+                    generate(&email(next).to_string())
+                },
+                |cert| db.merge(cert).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+
+        let mut i = 0;
+        group.bench_with_input(BenchmarkId::new("by_email", size), &size, |b, &size| {
+            b.iter(|| {
+                i = (i + 1) % size;
+                db.by_email(&email(i)).unwrap()
+            })
+        });
+
+        group.bench_with_input(
+            BenchmarkId::new("check_consistency", size),
+            &size,
+            |b, _| b.iter(|| db.check_consistency().unwrap()),
+        );
+
+        group.finish();
+    }
+}
+
+fn backends(c: &mut Criterion) {
+    bench_backend(c, filesystem());
+}
+
+criterion_group!(benches, backends);
+criterion_main!(benches);
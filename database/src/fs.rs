@@ -1,29 +1,41 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fs::{
-    create_dir_all, read_link, remove_file, rename, set_permissions, File, OpenOptions, Permissions,
+    create_dir_all, hard_link, metadata, read, remove_file, rename, set_permissions, File,
+    OpenOptions,
 };
-use std::io::Write;
-use std::os::unix::fs::PermissionsExt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
 
 use pathdiff::diff_paths;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tempfile;
 use url::form_urlencoded;
 
+use platform::{self, read_link};
 use sync::FlockMutexGuard;
 use types::{Email, Fingerprint, KeyID};
 use Result;
-use {Database, Query};
+use {
+    Database, Finding, FindingKind, InsufficientSpace, LegalHold, PublicationPolicy,
+    PublishedCounts, Query, Reloadable, Tombstone, UidPolicy, UnhashedAreaAction, AUDIT_LINK_EMAIL,
+    AUDIT_LOG_START, AUDIT_PUBLISH_KEY, AUDIT_UNLINK_EMAIL, AUDIT_UNPUBLISH_KEY, AUDIT_UPDATE_KEY,
+};
 
+use transparency::{self, key_hash};
 use wkd;
 
 use tempfile::NamedTempFile;
 
+use openpgp::parse::Parse;
 use openpgp::Cert;
 use openpgp_utils::POLICY;
 
+/// Free space, in bytes, that must remain after storing a key, for
+/// the links and logs written along with it.
+const FREE_SPACE_RESERVE: u64 = 1024 * 1024;
+
 pub struct Filesystem {
     tmp_dir: PathBuf,
 
@@ -31,18 +43,68 @@ pub struct Filesystem {
     keys_external_dir: PathBuf,
     keys_dir_full: PathBuf,
     keys_dir_quarantined: PathBuf,
+    keys_dir_quarantine_uploaders: PathBuf,
     keys_dir_published: PathBuf,
+    keys_dir_blobs: PathBuf,
     keys_dir_published_wkd: PathBuf,
     keys_dir_log: PathBuf,
+    keys_dir_banned: PathBuf,
+    keys_dir_held: PathBuf,
+    email_bans_file: PathBuf,
+    domain_allowlists_dir: PathBuf,
+    retained_certifiers_dir: PathBuf,
+    audit_log_file: PathBuf,
+    transparency_log_file: PathBuf,
+    transparency_log: Mutex<transparency::Log>,
 
     links_dir_by_fingerprint: PathBuf,
     links_dir_by_keyid: PathBuf,
     links_dir_wkd_by_email: PathBuf,
     links_dir_by_email: PathBuf,
 
+    publication_policy: Reloadable<PublicationPolicy>,
+    domain_role_localpart: Option<String>,
     dry_run: bool,
 }
 
+/// Fails with `InsufficientSpace` unless the volume of `dir` has room
+/// for the file, and some to spare.
+///
+/// Checking up front means we don't end up with a truncated key, and
+/// confusing errors linking to it.
+fn check_free_space(file: &Path, dir: &Path) -> Result<()> {
+    let needed = file.metadata()?.len() + FREE_SPACE_RESERVE;
+    if fs2::available_space(dir)? < needed {
+        return Err(InsufficientSpace.into());
+    }
+    Ok(())
+}
+
+/// Moves the temporary file to the target path.
+///
+/// If the temporary directory is on a different filesystem than the
+/// target, e.g. on a tmpfs, the file can't be renamed.  In that case
+/// we copy it next to the target first, and rename it from there, so
+/// that readers never see a partially written file.
+fn persist(file: NamedTempFile, target: &Path) -> Result<()> {
+    let mut file = match file.persist(target) {
+        Ok(_) => return Ok(()),
+        Err(e) if platform::is_cross_device(&e.error) => e.file,
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut copy = tempfile::Builder::new()
+        .prefix("persist")
+        .rand_bytes(16)
+        .tempfile_in(target.parent().unwrap())?;
+    file.seek(SeekFrom::Start(0))?;
+    io::copy(&mut file, &mut copy)?;
+    set_permissions(copy.path(), file.as_file().metadata()?.permissions())?;
+    copy.as_file().sync_all()?;
+    copy.persist(target)?;
+    Ok(())
+}
+
 /// Returns the given path, ensuring that the parent directory exists.
 ///
 /// Use this on paths returned by .path_to_* before creating the
@@ -84,14 +146,29 @@ impl Filesystem {
         let keys_external_dir: PathBuf = keys_external_dir.into();
         let keys_dir_full = keys_internal_dir.join("full");
         let keys_dir_quarantined = keys_internal_dir.join("quarantined");
+        let keys_dir_quarantine_uploaders = keys_internal_dir.join("quarantine-uploaders");
         let keys_dir_log = keys_internal_dir.join("log");
+        let keys_dir_banned = keys_internal_dir.join("banned");
+        let keys_dir_held = keys_internal_dir.join("held");
+        let email_bans_file = keys_internal_dir.join("banned-emails");
+        let domain_allowlists_dir = keys_internal_dir.join("domain-allowlists");
+        let retained_certifiers_dir = keys_internal_dir.join("retained-certifiers");
+        let audit_log_file = keys_internal_dir.join("audit.log");
+        let transparency_log_file = keys_internal_dir.join("transparency.log");
         let keys_dir_published = keys_external_dir.join("pub");
         let keys_dir_published_wkd = keys_external_dir.join("wkd");
+        let keys_dir_blobs = keys_external_dir.join("blobs");
         create_dir_all(&keys_dir_full)?;
         create_dir_all(&keys_dir_quarantined)?;
+        create_dir_all(&keys_dir_quarantine_uploaders)?;
         create_dir_all(&keys_dir_published)?;
         create_dir_all(&keys_dir_published_wkd)?;
+        create_dir_all(&keys_dir_blobs)?;
         create_dir_all(&keys_dir_log)?;
+        create_dir_all(&keys_dir_banned)?;
+        create_dir_all(&keys_dir_held)?;
+        create_dir_all(&domain_allowlists_dir)?;
+        create_dir_all(&retained_certifiers_dir)?;
 
         let links_dir = keys_external_dir.join("links");
         let links_dir_by_keyid = links_dir.join("by-keyid");
@@ -115,18 +192,79 @@ impl Filesystem {
             keys_dir_full,
             keys_dir_published,
             keys_dir_published_wkd,
+            keys_dir_blobs,
             keys_dir_quarantined,
+            keys_dir_quarantine_uploaders,
             keys_dir_log,
+            keys_dir_banned,
+            keys_dir_held,
+            email_bans_file,
+            domain_allowlists_dir,
+            retained_certifiers_dir,
+            audit_log_file,
+            transparency_log_file,
+            transparency_log: Mutex::default(),
 
             links_dir_by_keyid,
             links_dir_by_fingerprint,
             links_dir_by_email,
             links_dir_wkd_by_email,
 
+            publication_policy: Reloadable::default(),
+            domain_role_localpart: None,
             dry_run,
         })
     }
 
+    /// Sets which user IDs are published.
+    pub fn with_uid_policy(self, uid_policy: UidPolicy) -> Self {
+        self.publication_policy
+            .update(|policy| policy.uid_policy = uid_policy);
+        self
+    }
+
+    /// Sets the maximum number of user IDs in a published key.
+    pub fn with_max_published_uids(self, max_published_uids: usize) -> Self {
+        self.publication_policy
+            .update(|policy| policy.max_published_uids = Some(max_published_uids));
+        self
+    }
+
+    /// Sets the maximum number of self-signatures per user ID or
+    /// subkey in a published key.
+    pub fn with_max_self_signatures(self, max_self_signatures: usize) -> Self {
+        self.publication_policy
+            .update(|policy| policy.max_self_signatures = Some(max_self_signatures));
+        self
+    }
+
+    /// Sets the largest permitted unhashed subpacket area, and what
+    /// to do with signatures that exceed it.
+    pub fn with_unhashed_area_limit(self, max_size: usize, action: UnhashedAreaAction) -> Self {
+        self.publication_policy
+            .update(|policy| policy.unhashed_area_limit = Some((max_size, action)));
+        self
+    }
+
+    /// Sets what is published of keys.
+    pub fn with_publication_policy(self, policy: PublicationPolicy) -> Self {
+        self.publication_policy.set(policy);
+        self
+    }
+
+    /// Returns the publication policy, which can be changed while the
+    /// database is in use.
+    pub fn publication_policy(&self) -> Reloadable<PublicationPolicy> {
+        self.publication_policy.clone()
+    }
+
+    /// Makes lookups of a bare domain by email address resolve to the
+    /// role address with the given local part, e.g. `security`.
+    pub fn with_domain_role_localpart(mut self, localpart: String) -> Self {
+        self.domain_role_localpart = Some(localpart);
+        self
+    }
+
     /// Returns the path to the given Fingerprint.
     fn fingerprint_to_path_full(&self, fingerprint: &Fingerprint) -> PathBuf {
         let hex = fingerprint.to_string();
@@ -139,12 +277,36 @@ impl Filesystem {
         self.keys_dir_quarantined.join(&hex)
     }
 
+    /// Returns the path to the uploader of the given quarantined key.
+    fn fingerprint_to_path_quarantine_uploader(&self, fingerprint: &Fingerprint) -> PathBuf {
+        let hex = fingerprint.to_string();
+        self.keys_dir_quarantine_uploaders.join(&hex)
+    }
+
+    /// Returns the path to the given Fingerprint.
+    fn fingerprint_to_path_held(&self, fingerprint: &Fingerprint) -> PathBuf {
+        let hex = fingerprint.to_string();
+        self.keys_dir_held.join(&hex)
+    }
+
+    /// Returns the path to the given Fingerprint.
+    fn fingerprint_to_path_banned(&self, fingerprint: &Fingerprint) -> PathBuf {
+        let hex = fingerprint.to_string();
+        self.keys_dir_banned.join(&hex)
+    }
+
     /// Returns the path to the given Fingerprint.
     fn fingerprint_to_path_published(&self, fingerprint: &Fingerprint) -> PathBuf {
         let hex = fingerprint.to_string();
         self.keys_dir_published.join(path_split(&hex))
     }
 
+    /// Returns the path to the published key blob with the given
+    /// hash.
+    fn hash_to_path_blob(&self, hash: &str) -> PathBuf {
+        self.keys_dir_blobs.join(path_split(hash))
+    }
+
     /// Returns the path to the given Fingerprint.
     fn fingerprint_to_path_published_wkd(&self, fingerprint: &Fingerprint) -> PathBuf {
         let hex = fingerprint.to_string();
@@ -169,7 +331,24 @@ impl Filesystem {
         self.links_dir_by_email.join(path_split(&email))
     }
 
+    /// Returns the path to the allowlist of the given domain.
+    fn domain_allowlist_path(&self, domain: &str) -> PathBuf {
+        let domain = form_urlencoded::byte_serialize(domain.as_bytes()).collect::<String>();
+        self.domain_allowlists_dir.join(domain)
+    }
+
+    /// Returns the path to the certifiers whose certifications are
+    /// retained on the given key.
+    fn retained_certifiers_path(&self, fingerprint: &Fingerprint) -> PathBuf {
+        let hex = fingerprint.to_string();
+        self.retained_certifiers_dir.join(path_split(&hex))
+    }
+
     /// Returns the WKD path to the given Email.
+    ///
+    /// These links are maintained when addresses are linked and
+    /// unlinked, so that WKD lookups by hash don't need to compute
+    /// anything, and can be served directly from the filesystem.
     fn link_wkd_by_email(&self, email: &Email) -> PathBuf {
         let (encoded_local_part, domain) = wkd::encode_wkd(email.as_str()).unwrap();
         let encoded_domain =
@@ -184,15 +363,25 @@ impl Filesystem {
         .collect()
     }
 
-    /// Returns the WKD path to the given url-encoded domain and wkd-encoded local part.
-    fn link_wkd_by_domain_and_hash(&self, domain: &str, hash: &str) -> PathBuf {
-        [
-            &self.links_dir_wkd_by_email,
-            Path::new(&domain),
-            &path_split(hash),
-        ]
-        .iter()
-        .collect()
+    /// Returns the WKD path to the given domain and wkd-encoded local
+    /// part, if they are well-formed.
+    fn link_wkd_by_domain_and_hash(&self, domain: &str, hash: &str) -> Option<PathBuf> {
+        let domain = domain.to_lowercase();
+        if domain.is_empty() || domain == "." || domain == ".." || !wkd::is_wkd_hash(hash) {
+            return None;
+        }
+        let encoded_domain =
+            form_urlencoded::byte_serialize(domain.as_bytes()).collect::<PathBuf>();
+
+        Some(
+            [
+                &self.links_dir_wkd_by_email,
+                &encoded_domain,
+                &path_split(hash),
+            ]
+            .iter()
+            .collect(),
+        )
     }
 
     #[allow(clippy::nonminimal_bool)]
@@ -205,6 +394,7 @@ impl Filesystem {
             panic!("Attempted to access file outside expected dirs!");
         }
 
+        let path = platform::resolve(path);
         if path.exists() {
             fs::read_to_string(path).ok()
         } else {
@@ -222,6 +412,7 @@ impl Filesystem {
             panic!("Attempted to access file outside expected dirs!");
         }
 
+        let path = platform::resolve(path);
         if path.exists() {
             fs::read(path).ok()
         } else {
@@ -253,9 +444,7 @@ impl Filesystem {
 
     /// Returns the backing primary key fingerprint for any key path.
     pub fn path_to_primary(path: &Path) -> Option<Fingerprint> {
-        use std::fs;
-        let typ = fs::symlink_metadata(&path).ok()?.file_type();
-        if typ.is_symlink() {
+        if platform::is_link(path) {
             let path = read_link(path).ok()?;
             Filesystem::path_to_fingerprint(&path)
         } else {
@@ -268,11 +457,17 @@ impl Filesystem {
         let link = self.link_by_email(email);
         let target = diff_paths(&path, link.parent().unwrap()).unwrap();
 
-        if link == target {
+        // Keep existing links, whose age is the verification date.
+        if read_link(&link).ok().as_ref() == Some(&target) {
             return Ok(());
         }
 
-        symlink(&target, ensure_parent(&link)?)
+        let is_new = read_link(&link).is_err();
+        symlink(&target, ensure_parent(&link)?)?;
+        if is_new {
+            self.write_audit_log(&format!("{} {}", AUDIT_LINK_EMAIL, fpr))?;
+        }
+        Ok(())
     }
 
     fn link_email_wkd(&self, email: &Email, fpr: &Fingerprint) -> Result<()> {
@@ -296,7 +491,10 @@ impl Filesystem {
         )
         .unwrap();
 
-        symlink_unlink_with_check(&link, &expected)
+        if symlink_unlink_with_check(&link, &expected)? {
+            self.write_audit_log(&format!("{} {}", AUDIT_UNLINK_EMAIL, fpr))?;
+        }
+        Ok(())
     }
 
     fn unlink_email_wkd(&self, email: &Email, fpr: &Fingerprint) -> Result<()> {
@@ -308,7 +506,8 @@ impl Filesystem {
         )
         .unwrap();
 
-        symlink_unlink_with_check(&link, &expected)
+        symlink_unlink_with_check(&link, &expected)?;
+        Ok(())
     }
 
     fn open_logfile(&self, file_name: &str) -> Result<File> {
@@ -319,11 +518,416 @@ impl Filesystem {
             .open(file_path)?)
     }
 
+    /// Counts the published keys and addresses by walking the
+    /// database.
+    ///
+    /// Note that this operation may take a long time.  To keep the
+    /// counts up to date, apply the audit log from `audit_log_end`
+    /// onwards instead.
+    pub fn count_published(&self) -> Result<PublishedCounts> {
+        use walkdir::WalkDir;
+
+        let mut counts = PublishedCounts::default();
+        for entry in WalkDir::new(&self.keys_dir_published) {
+            if entry?.file_type().is_file() {
+                counts.keys += 1;
+            }
+        }
+        for entry in WalkDir::new(&self.links_dir_by_email) {
+            if platform::is_link(entry?.path()) {
+                counts.addresses += 1;
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Counts the published addresses per domain, by walking the
+    /// database.
+    ///
+    /// Note that this operation may take a long time.
+    pub fn count_published_by_domain(&self) -> Result<HashMap<String, u64>> {
+        use std::fs;
+        use walkdir::WalkDir;
+
+        let mut counts = HashMap::new();
+        let domain_dirs = match fs::read_dir(&self.links_dir_wkd_by_email) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(counts),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in domain_dirs {
+            let path = entry?.path();
+            // Domains are normalized to ASCII, so they appear
+            // unchanged in the url-encoded directory names.
+            let domain = path.file_name().unwrap().to_string_lossy().into_owned();
+            let mut count = 0;
+            for entry in WalkDir::new(&path) {
+                if platform::is_link(entry?.path()) {
+                    count += 1;
+                }
+            }
+            if count > 0 {
+                counts.insert(domain, count);
+            }
+        }
+        Ok(counts)
+    }
+
+    /// Returns the primary fingerprints of published keys in order,
+    /// starting after the given one, at most `limit` of them.
+    pub fn published_fprs(
+        &self,
+        after: Option<&Fingerprint>,
+        limit: usize,
+    ) -> Result<Vec<Fingerprint>> {
+        use walkdir::WalkDir;
+
+        // The directories are named after prefixes of the fingerprints,
+        // so walking them in order yields the fingerprints in order,
+        // and we can skip those before the cursor entirely.
+        let after = after.map(|fpr| fpr.to_string()).unwrap_or_default();
+        let base = &self.keys_dir_published;
+        let entries = WalkDir::new(base)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .into_iter()
+            .filter_entry(|entry| {
+                let prefix = entry
+                    .path()
+                    .strip_prefix(base)
+                    .map(path_merge)
+                    .unwrap_or_default();
+                prefix.as_str() >= &after[..prefix.len().min(after.len())]
+            });
+
+        let mut fprs = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            if fprs.len() >= limit {
+                break;
+            }
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            match Filesystem::path_to_fingerprint(entry.path()) {
+                Some(fpr) if fpr.to_string() > after => fprs.push(fpr),
+                _ => (),
+            }
+        }
+        Ok(fprs)
+    }
+
+    /// Returns the current end of the audit log.
+    pub fn audit_log_end(&self) -> Result<u64> {
+        match metadata(&self.audit_log_file) {
+            Ok(metadata) => Ok(metadata.len()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Reads the complete lines of the audit log from `offset`.
+    ///
+    /// Returns the lines, and the offset to continue from.  If the
+    /// log has been rotated since, reads the new log from the start.
+    pub fn read_audit_log(&self, offset: u64) -> Result<(Vec<String>, u64)> {
+        let mut file = match File::open(&self.audit_log_file) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((vec![], 0)),
+            Err(e) => return Err(e.into()),
+        };
+        let offset = if file.metadata()?.len() < offset {
+            0
+        } else {
+            offset
+        };
+        file.seek(SeekFrom::Start(offset))?;
+        let mut data = String::new();
+        file.read_to_string(&mut data)?;
+
+        // A partially written line is read next time.
+        let complete = data.rfind('\n').map_or(0, |i| i + 1);
+        let lines = data[..complete].lines().map(str::to_owned).collect();
+        Ok((lines, offset + complete as u64))
+    }
+
+    /// Removes temporary files and link directories older than
+    /// `max_age`, which crashed processes leave behind, and key blobs
+    /// no published key refers to anymore.
+    ///
+    /// Returns the number of entries removed.
+    pub fn prune_stale_tempfiles(&self, max_age: Duration) -> Result<usize> {
+        use std::fs;
+        use walkdir::WalkDir;
+
+        let mut pruned = 0;
+        for entry in fs::read_dir(&self.tmp_dir)? {
+            if remove_if_stale(&entry?.path(), max_age)? {
+                pruned += 1;
+            }
+        }
+
+        for links_dir in &[
+            &self.keys_dir_published,
+            &self.links_dir_by_fingerprint,
+            &self.links_dir_by_keyid,
+            &self.links_dir_by_email,
+            &self.links_dir_wkd_by_email,
+        ] {
+            let mut entries = WalkDir::new(links_dir).into_iter();
+            while let Some(entry) = entries.next() {
+                let entry = entry?;
+                if !entry.file_type().is_dir() || !is_link_tempdir(entry.path()) {
+                    continue;
+                }
+                entries.skip_current_dir();
+                if remove_if_stale(entry.path(), max_age)? {
+                    pruned += 1;
+                }
+            }
+        }
+
+        pruned += self.prune_orphan_blobs(max_age)?;
+
+        Ok(pruned)
+    }
+
+    /// Removes blobs that are not linked from `pub/` anymore.
+    ///
+    /// Young blobs are kept, as they may be about to be linked.  Where
+    /// the platform doesn't count hard links, blobs are never removed.
+    fn prune_orphan_blobs(&self, max_age: Duration) -> Result<usize> {
+        use walkdir::WalkDir;
+
+        let _lock = self.lock()?;
+        let mut pruned = 0;
+        for entry in WalkDir::new(&self.keys_dir_blobs) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            if platform::link_count(entry.path())? == Some(1)
+                && remove_if_stale(entry.path(), max_age)?
+            {
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Removes quarantined keys older than `max_age`, which nobody
+    /// approved or rejected in time, unless they are under legal hold.
+    ///
+    /// Returns the fingerprints of the keys removed.  With `dry_run`,
+    /// nothing is removed, and the keys that would have been are
+    /// returned.
+    pub fn prune_quarantined(&self, max_age: Duration, dry_run: bool) -> Result<Vec<Fingerprint>> {
+        use std::fs;
+
+        let _lock = self.lock()?;
+        let mut pruned = vec![];
+        for entry in fs::read_dir(&self.keys_dir_quarantined)? {
+            let path = entry?.path();
+            let fpr: Fingerprint = match path.file_name().unwrap().to_string_lossy().parse() {
+                Ok(fpr) => fpr,
+                Err(_) => continue,
+            };
+            if self.legal_hold(&fpr).is_some() {
+                continue;
+            }
+            if dry_run {
+                if is_stale(&path, max_age)? {
+                    pruned.push(fpr);
+                }
+            } else if remove_if_stale(&path, max_age)? {
+                self.delete_quarantined(&fpr)?;
+                self.write_audit_log(&format!("expire-quarantined {}", fpr))?;
+                pruned.push(fpr);
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Drops the unverified user IDs of keys that were last changed
+    /// more than `max_age` ago, and for which no address was ever
+    /// verified, unless they are under legal hold.
+    ///
+    /// Nothing is dropped unless the audit log goes back at least
+    /// `max_age`, so that no verification is missed.
+    ///
+    /// Only what is not published is dropped: the stored key is
+    /// replaced by the published one, which remains available by
+    /// fingerprint, along with any revocations.
+    ///
+    /// Returns the primary fingerprints of the keys stripped.  With
+    /// `dry_run`, nothing is changed, and the keys that would have been
+    /// are returned.
+    pub fn sweep_unverified(&self, max_age: Duration, dry_run: bool) -> Result<Vec<Fingerprint>> {
+        use walkdir::WalkDir;
+
+        let verified = match self.ever_verified(max_age)? {
+            Some(verified) => verified,
+            None => {
+                warn!(
+                    "Not sweeping unverified keys: the audit log doesn't go back {:?}",
+                    max_age
+                );
+                return Ok(vec![]);
+            }
+        };
+        let mut swept = vec![];
+        for entry in WalkDir::new(&self.keys_dir_full) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let fpr = match Filesystem::path_to_fingerprint(entry.path()) {
+                Some(fpr) => fpr,
+                None => continue,
+            };
+            if verified.contains(&fpr)
+                || self.legal_hold(&fpr).is_some()
+                || !is_stale(entry.path(), max_age)?
+            {
+                continue;
+            }
+            let result = if dry_run {
+                Ok(self.unverified_published(&fpr).is_some())
+            } else {
+                self.strip_unverified(&fpr, max_age)
+            };
+            match result {
+                Ok(true) => swept.push(fpr),
+                Ok(false) => (),
+                Err(e) => warn!("Error stripping unverified key {}: {:?}", fpr, e),
+            }
+        }
+        Ok(swept)
+    }
+
+    /// Returns the primary fingerprints of the keys an address was
+    /// ever verified for, according to the published addresses and
+    /// the audit log.
+    ///
+    /// Returns `None` if the audit log doesn't start at least
+    /// `max_age` ago, because it was created or rotated since, and
+    /// keys verified before then cannot be told apart.
+    fn ever_verified(&self, max_age: Duration) -> Result<Option<HashSet<Fingerprint>>> {
+        use std::io::{BufRead, BufReader};
+        use walkdir::WalkDir;
+
+        let file = match File::open(&self.audit_log_file) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = BufReader::new(file).lines();
+        let started = match lines.next().transpose()? {
+            Some(line) => {
+                let mut fields = line.split_whitespace();
+                match (fields.next(), fields.next()) {
+                    (Some(timestamp), Some(AUDIT_LOG_START)) => timestamp.parse::<u64>().ok(),
+                    _ => None,
+                }
+            }
+            None => None,
+        };
+        let started = match started {
+            Some(started) => SystemTime::UNIX_EPOCH + Duration::from_secs(started),
+            None => return Ok(None),
+        };
+        if started.elapsed().unwrap_or_default() <= max_age {
+            return Ok(None);
+        }
+
+        let mut verified = HashSet::new();
+        for line in lines {
+            let line = line?;
+            let mut fields = line.split_whitespace().skip(1);
+            match (fields.next(), fields.next()) {
+                (Some(AUDIT_LINK_EMAIL), Some(fpr)) | (Some(AUDIT_UNLINK_EMAIL), Some(fpr)) => {
+                    if let Ok(fpr) = fpr.parse() {
+                        verified.insert(fpr);
+                    }
+                }
+                _ => (),
+            }
+        }
+        for entry in WalkDir::new(&self.links_dir_by_email) {
+            let entry = entry?;
+            if !platform::is_link(entry.path()) {
+                continue;
+            }
+            if let Some(fpr) = Filesystem::path_to_primary(entry.path()) {
+                verified.insert(fpr);
+            }
+        }
+        Ok(Some(verified))
+    }
+
+    /// Returns the published key, if it has no user IDs, while the
+    /// stored one does.
+    fn unverified_published(&self, fpr: &Fingerprint) -> Option<String> {
+        let full = Cert::from_bytes(self.by_fpr_full(fpr)?.as_bytes()).ok()?;
+        let armored = self.by_primary_fpr(fpr)?;
+        let published = Cert::from_bytes(armored.as_bytes()).ok()?;
+        if full.userids().next().is_none() || published.userids().next().is_some() {
+            return None;
+        }
+        Some(armored)
+    }
+
+    fn strip_unverified(&self, fpr: &Fingerprint, max_age: Duration) -> Result<bool> {
+        let _lock = self.lock()?;
+
+        // Check again, now that no upload, verification, or hold can
+        // get in the way.
+        if self.legal_hold(fpr).is_some()
+            || !is_stale(&self.fingerprint_to_path_full(fpr), max_age)?
+        {
+            return Ok(false);
+        }
+        let published = match self.unverified_published(fpr) {
+            Some(published) => published,
+            None => return Ok(false),
+        };
+        let full_tmp = self.write_to_temp(published.as_bytes())?;
+        self.move_tmp_to_full(full_tmp, fpr)?;
+        self.write_audit_log(&format!("expire-unverified {}", fpr))?;
+        Ok(true)
+    }
+
+    /// Unpublishes the addresses of keys that expired more than
+    /// `grace` ago.
+    ///
+    /// Returns the number of keys whose addresses were unpublished.
+    pub fn sweep_expired(&self, grace: Duration) -> Result<usize> {
+        use walkdir::WalkDir;
+
+        let mut swept = 0;
+        for entry in WalkDir::new(&self.keys_dir_published) {
+            let entry = entry?;
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let fpr = match Filesystem::path_to_primary(entry.path()) {
+                Some(fpr) => fpr,
+                None => continue,
+            };
+            match self.unpublish_if_expired(&fpr, grace) {
+                Ok(true) => swept += 1,
+                Ok(false) => (),
+                Err(e) => warn!("Error sweeping expired key {}: {:?}", fpr, e),
+            }
+        }
+        Ok(swept)
+    }
+
     fn perform_checks(
         &self,
         checks_dir: &Path,
-        tpks: &mut HashMap<Fingerprint, Cert>,
-        check: impl Fn(&Path, &Cert, &Fingerprint) -> Result<()>,
+        tpks: &mut HashMap<Fingerprint, Option<Cert>>,
+        findings: &mut Vec<Finding>,
+        check: impl Fn(&Path, &Cert, &Fingerprint) -> std::result::Result<(), Finding>,
     ) -> Result<()> {
         use std::fs;
         use walkdir::WalkDir;
@@ -338,53 +942,175 @@ impl Filesystem {
 
             // Compute the corresponding primary fingerprint just
             // by looking at the paths.
-            let primary_fp = Filesystem::path_to_primary(path)
-                .ok_or_else(|| format_err!("Malformed path: {:?}", path.read_link().unwrap()))?;
-            // Load into cache.
+            let primary_fp = match Filesystem::path_to_primary(path) {
+                Some(primary_fp) => primary_fp,
+                None if platform::is_link(path) && !platform::resolve(path).exists() => {
+                    findings.push(Finding::new(
+                        FindingKind::OrphanSymlink,
+                        path,
+                        format!("Broken symlink {:?}", path),
+                    ));
+                    continue;
+                }
+                None => {
+                    findings.push(Finding::new(
+                        FindingKind::CorruptFile,
+                        path,
+                        format!("Malformed path: {:?}", path),
+                    ));
+                    continue;
+                }
+            };
+
+            // Load into cache.  Keys that can't be parsed are cached
+            // as None, so that they are reported only once.
             if !tpks.contains_key(&primary_fp) {
-                tpks.insert(
-                    primary_fp.clone(),
-                    self.lookup(&Query::ByFingerprint(primary_fp.clone()))?
-                        .ok_or_else(|| format_err!("No Cert with fingerprint {:?}", primary_fp))?,
-                );
+                match self.lookup(&Query::ByFingerprint(primary_fp.clone())) {
+                    Ok(Some(tpk)) => {
+                        tpks.insert(primary_fp.clone(), Some(tpk));
+                    }
+                    Ok(None) => {
+                        findings.push(Finding::new(
+                            FindingKind::OrphanSymlink,
+                            path,
+                            format!("Broken symlink {:?}: No such Key {}", path, primary_fp),
+                        ));
+                        continue;
+                    }
+                    Err(e) => {
+                        findings.push(Finding::new(
+                            FindingKind::CorruptFile,
+                            path,
+                            format!("Cannot parse key {}: {}", primary_fp, e),
+                        ));
+                        tpks.insert(primary_fp.clone(), None);
+                    }
+                }
             }
 
-            let tpk = tpks.get(&primary_fp).ok_or_else(|| {
-                format_err!("Broken symlink {:?}: No such Key {}", path, primary_fp)
-            })?;
-
-            check(path, tpk, &primary_fp)?;
+            if let Some(tpk) = &tpks[&primary_fp] {
+                if let Err(finding) = check(path, tpk, &primary_fp) {
+                    findings.push(finding);
+                }
+            }
         }
 
         Ok(())
     }
 }
 
+/// Temporary directories created next to links while replacing them.
+const LINK_TEMPDIR_PREFIX: &str = "link";
+const LINK_TEMPDIR_RAND_BYTES: usize = 16;
+
+/// Tells link tempdirs apart from the directories of the link
+/// hierarchy, e.g. WKD domains, which may have a similar name.
+fn is_link_tempdir(path: &Path) -> bool {
+    use std::fs;
+
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => return false,
+    };
+    let is_tempdir_name = name.len() == LINK_TEMPDIR_PREFIX.len() + LINK_TEMPDIR_RAND_BYTES
+        && name.starts_with(LINK_TEMPDIR_PREFIX)
+        && name[LINK_TEMPDIR_PREFIX.len()..]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric());
+    if !is_tempdir_name {
+        return false;
+    }
+
+    // A tempdir holds at most the link that is being created.
+    match fs::read_dir(path) {
+        Ok(entries) => entries
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .all(|name| name.map_or(false, |name| name == "link")),
+        Err(_) => false,
+    }
+}
+
+/// Returns whether the file or directory was last modified more than
+/// `max_age` ago.  Entries that don't exist are not stale.
+fn is_stale(path: &Path, max_age: Duration) -> Result<bool> {
+    use std::fs;
+
+    match fs::symlink_metadata(path) {
+        Ok(metadata) => Ok(metadata.modified()?.elapsed().unwrap_or_default() > max_age),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Removes the file or directory, if it is older than `max_age`.
+///
+/// Returns whether it was removed.  Entries that vanish in the
+/// meantime are not an error.
+fn remove_if_stale(path: &Path, max_age: Duration) -> Result<bool> {
+    use std::fs;
+
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e.into()),
+    };
+    let age = metadata.modified()?.elapsed().unwrap_or_default();
+    if age <= max_age {
+        return Ok(false);
+    }
+
+    let result = if metadata.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
 // Like `symlink`, but instead of failing if `symlink_name` already
 // exists, atomically update `symlink_name` to have `symlink_content`.
 fn symlink(symlink_content: &Path, symlink_name: &Path) -> Result<()> {
-    use std::os::unix::fs::symlink;
-
     let symlink_dir = ensure_parent(symlink_name)?.parent().unwrap();
     let tmp_dir = tempfile::Builder::new()
-        .prefix("link")
-        .rand_bytes(16)
+        .prefix(LINK_TEMPDIR_PREFIX)
+        .rand_bytes(LINK_TEMPDIR_RAND_BYTES)
         .tempdir_in(symlink_dir)?;
     let symlink_name_tmp = tmp_dir.path().join("link");
 
-    symlink(&symlink_content, &symlink_name_tmp)?;
+    platform::symlink(symlink_content, &symlink_name_tmp)?;
     rename(&symlink_name_tmp, &symlink_name)?;
     Ok(())
 }
 
-fn symlink_unlink_with_check(link: &Path, expected: &Path) -> Result<()> {
+/// Like `hard_link`, but atomically replaces `link` if it exists.
+fn hard_link_replace(original: &Path, link: &Path) -> Result<()> {
+    let link_dir = ensure_parent(link)?.parent().unwrap();
+    let tmp_dir = tempfile::Builder::new()
+        .prefix(LINK_TEMPDIR_PREFIX)
+        .rand_bytes(LINK_TEMPDIR_RAND_BYTES)
+        .tempdir_in(link_dir)?;
+    let link_tmp = tmp_dir.path().join("link");
+
+    hard_link(original, &link_tmp)?;
+    rename(&link_tmp, &link)?;
+    Ok(())
+}
+
+/// Removes the link if it points to `expected`, returning whether it
+/// did.
+fn symlink_unlink_with_check(link: &Path, expected: &Path) -> Result<bool> {
     if let Ok(target) = read_link(&link) {
         if target == expected {
             remove_file(link)?;
+            return Ok(true);
         }
     }
 
-    Ok(())
+    Ok(false)
 }
 
 impl Database for Filesystem {
@@ -395,6 +1121,26 @@ impl Database for Filesystem {
         FlockMutexGuard::lock(&self.keys_internal_dir)
     }
 
+    fn uid_policy(&self) -> UidPolicy {
+        self.publication_policy.read().uid_policy
+    }
+
+    fn max_published_uids(&self) -> Option<usize> {
+        self.publication_policy.read().max_published_uids
+    }
+
+    fn max_self_signatures(&self) -> Option<usize> {
+        self.publication_policy.read().max_self_signatures
+    }
+
+    fn unhashed_area_limit(&self) -> Option<(usize, UnhashedAreaAction)> {
+        self.publication_policy.read().unhashed_area_limit
+    }
+
+    fn domain_role_localpart(&self) -> Option<&str> {
+        self.domain_role_localpart.as_deref()
+    }
+
     fn write_to_temp(&self, content: &[u8]) -> Result<Self::TempCert> {
         let mut tempfile = tempfile::Builder::new()
             .prefix("key")
@@ -421,9 +1167,10 @@ impl Database for Filesystem {
         if self.dry_run {
             return Ok(());
         }
-        set_permissions(file.path(), Permissions::from_mode(0o640))?;
+        check_free_space(file.path(), &self.keys_dir_full)?;
+        platform::set_mode(file.path(), 0o640)?;
         let target = self.fingerprint_to_path_full(fpr);
-        file.persist(ensure_parent(&target)?)?;
+        persist(file, ensure_parent(&target)?)?;
         Ok(())
     }
 
@@ -431,9 +1178,27 @@ impl Database for Filesystem {
         if self.dry_run {
             return Ok(());
         }
-        set_permissions(file.path(), Permissions::from_mode(0o644))?;
+        check_free_space(file.path(), &self.keys_dir_published)?;
+        platform::set_mode(file.path(), 0o644)?;
         let target = self.fingerprint_to_path_published(fpr);
-        file.persist(ensure_parent(&target)?)?;
+        let is_new = !target.exists();
+
+        // Published keys are hard links to content-addressed blobs,
+        // so that publishing the same key again leaves the file as it
+        // is, and mirrors have nothing to sync.
+        let blob = self.hash_to_path_blob(&key_hash(&read(file.path())?));
+        if !blob.exists() {
+            persist(file, ensure_parent(&blob)?)?;
+        }
+        let changed = !platform::same_file(&blob, &target);
+        if changed {
+            hard_link_replace(&blob, ensure_parent(&target)?)?;
+        }
+        if is_new {
+            self.write_audit_log(&format!("{} {}", AUDIT_PUBLISH_KEY, fpr))?;
+        } else if changed {
+            self.write_audit_log(&format!("{} {}", AUDIT_UPDATE_KEY, fpr))?;
+        }
         Ok(())
     }
 
@@ -447,8 +1212,8 @@ impl Database for Filesystem {
         }
         let target = self.fingerprint_to_path_published_wkd(fpr);
         if let Some(file) = file {
-            set_permissions(file.path(), Permissions::from_mode(0o644))?;
-            file.persist(ensure_parent(&target)?)?;
+            platform::set_mode(file.path(), 0o644)?;
+            persist(file, ensure_parent(&target)?)?;
         } else if target.exists() {
             remove_file(target)?;
         }
@@ -456,7 +1221,26 @@ impl Database for Filesystem {
         Ok(())
     }
 
-    fn write_to_quarantine(&self, fpr: &Fingerprint, content: &[u8]) -> Result<()> {
+    fn write_to_quarantine(
+        &self,
+        fpr: &Fingerprint,
+        content: &[u8],
+        uploader: Option<&str>,
+    ) -> Result<()> {
+        let target = self.fingerprint_to_path_quarantine_uploader(fpr);
+        match uploader {
+            Some(uploader) => {
+                let mut tempfile = tempfile::Builder::new()
+                    .prefix("uploader")
+                    .rand_bytes(16)
+                    .tempfile_in(&self.tmp_dir)?;
+                tempfile.write_all(uploader.as_bytes())?;
+                persist(tempfile, ensure_parent(&target)?)?;
+            }
+            None if target.exists() => remove_file(target)?,
+            None => (),
+        }
+
         let mut tempfile = tempfile::Builder::new()
             .prefix("key")
             .rand_bytes(16)
@@ -464,14 +1248,289 @@ impl Database for Filesystem {
         tempfile.write_all(content).unwrap();
 
         let target = self.fingerprint_to_path_quarantined(fpr);
-        tempfile.persist(ensure_parent(&target)?)?;
+        persist(tempfile, ensure_parent(&target)?)?;
 
         Ok(())
     }
 
-    fn check_link_fpr(
-        &self,
-        fpr: &Fingerprint,
+    fn quarantined(&self) -> Vec<Fingerprint> {
+        use std::fs;
+
+        let mut fprs: Vec<Fingerprint> = match fs::read_dir(&self.keys_dir_quarantined) {
+            Ok(entries) => entries
+                .flatten()
+                .flat_map(|entry| entry.file_name().to_string_lossy().parse().ok())
+                .collect(),
+            Err(_) => vec![],
+        };
+        fprs.sort_by_key(|fpr| fpr.to_string());
+        fprs
+    }
+
+    fn by_fpr_quarantined(&self, fpr: &Fingerprint) -> Option<String> {
+        let path = self.fingerprint_to_path_quarantined(fpr);
+        self.read_from_path(&path, true)
+    }
+
+    fn quarantine_uploader(&self, fpr: &Fingerprint) -> Option<String> {
+        let path = self.fingerprint_to_path_quarantine_uploader(fpr);
+        self.read_from_path(&path, true)
+    }
+
+    fn delete_quarantined(&self, fpr: &Fingerprint) -> Result<()> {
+        for path in &[
+            self.fingerprint_to_path_quarantined(fpr),
+            self.fingerprint_to_path_quarantine_uploader(fpr),
+        ] {
+            if path.exists() {
+                remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_ban(&self, fpr: &Fingerprint, tombstone: &Tombstone) -> Result<()> {
+        let mut tempfile = tempfile::Builder::new()
+            .prefix("ban")
+            .rand_bytes(16)
+            .tempfile_in(&self.tmp_dir)?;
+        serde_json::to_writer(&mut tempfile, tombstone)?;
+
+        let target = self.fingerprint_to_path_banned(fpr);
+        persist(tempfile, ensure_parent(&target)?)?;
+
+        Ok(())
+    }
+
+    fn tombstone(&self, fpr: &Fingerprint) -> Option<Tombstone> {
+        let path = self.fingerprint_to_path_banned(fpr);
+        self.read_from_path(&path, true)
+            .and_then(|tombstone| serde_json::from_str(&tombstone).ok())
+    }
+
+    fn write_legal_hold(&self, fpr: &Fingerprint, hold: Option<&LegalHold>) -> Result<()> {
+        let target = self.fingerprint_to_path_held(fpr);
+        match hold {
+            Some(hold) => {
+                let mut tempfile = tempfile::Builder::new()
+                    .prefix("hold")
+                    .rand_bytes(16)
+                    .tempfile_in(&self.tmp_dir)?;
+                serde_json::to_writer(&mut tempfile, hold)?;
+                persist(tempfile, ensure_parent(&target)?)?;
+            }
+            None => {
+                if target.exists() {
+                    remove_file(target)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn legal_hold(&self, fpr: &Fingerprint) -> Option<LegalHold> {
+        let path = self.fingerprint_to_path_held(fpr);
+        self.read_from_path(&path, true)
+            .and_then(|hold| serde_json::from_str(&hold).ok())
+    }
+
+    fn legal_holds(&self) -> Vec<Fingerprint> {
+        use std::fs;
+
+        let mut fprs: Vec<Fingerprint> = match fs::read_dir(&self.keys_dir_held) {
+            Ok(entries) => entries
+                .flatten()
+                .flat_map(|entry| entry.file_name().to_string_lossy().parse().ok())
+                .collect(),
+            Err(_) => vec![],
+        };
+        fprs.sort_by_key(|fpr| fpr.to_string());
+        fprs
+    }
+
+    fn delete_key(&self, fpr: &Fingerprint) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let was_published = self.fingerprint_to_path_published(fpr).exists();
+        for path in &[
+            self.fingerprint_to_path_published_wkd(fpr),
+            self.fingerprint_to_path_published(fpr),
+            self.fingerprint_to_path_full(fpr),
+            self.retained_certifiers_path(fpr),
+        ] {
+            if path.exists() {
+                remove_file(path)?;
+            }
+        }
+        if was_published {
+            self.write_audit_log(&format!("{} {}", AUDIT_UNPUBLISH_KEY, fpr))?;
+        }
+        Ok(())
+    }
+
+    fn write_email_ban(&self, entry: &str) -> Result<()> {
+        if self.email_bans().iter().any(|ban| ban == entry) {
+            return Ok(());
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.email_bans_file)?;
+        writeln!(file, "{}", entry)?;
+        Ok(())
+    }
+
+    fn email_bans(&self) -> Vec<String> {
+        self.read_from_path(&self.email_bans_file, true)
+            .map(|bans| bans.lines().map(|ban| ban.to_owned()).collect())
+            .unwrap_or_default()
+    }
+
+    fn write_domain_allowlist(&self, domain: &str, allowlist: Option<&[Email]>) -> Result<()> {
+        let path = self.domain_allowlist_path(domain);
+        let allowlist = match allowlist {
+            Some(allowlist) => allowlist,
+            None if path.exists() => return Ok(remove_file(&path)?),
+            None => return Ok(()),
+        };
+
+        let mut tempfile = tempfile::Builder::new()
+            .prefix("allowlist")
+            .rand_bytes(16)
+            .tempfile_in(&self.tmp_dir)?;
+        for email in allowlist {
+            writeln!(tempfile, "{}", email)?;
+        }
+        persist(tempfile, &path)?;
+
+        Ok(())
+    }
+
+    fn domain_allowlist(&self, domain: &str) -> Option<Vec<Email>> {
+        let path = self.domain_allowlist_path(domain);
+        self.read_from_path(&path, true).map(|allowlist| {
+            allowlist
+                .lines()
+                .flat_map(|line| line.parse::<Email>())
+                .collect()
+        })
+    }
+
+    fn write_retained_certifiers(
+        &self,
+        fpr_primary: &Fingerprint,
+        certifiers: &[Fingerprint],
+    ) -> Result<()> {
+        let path = self.retained_certifiers_path(fpr_primary);
+        if certifiers.is_empty() {
+            if path.exists() {
+                remove_file(&path)?;
+            }
+            return Ok(());
+        }
+
+        let mut tempfile = tempfile::Builder::new()
+            .prefix("certifiers")
+            .rand_bytes(16)
+            .tempfile_in(&self.tmp_dir)?;
+        for fpr in certifiers {
+            writeln!(tempfile, "{}", fpr)?;
+        }
+        persist(tempfile, ensure_parent(&path)?)?;
+
+        Ok(())
+    }
+
+    fn retained_certifiers(&self, fpr_primary: &Fingerprint) -> Vec<Fingerprint> {
+        let path = self.retained_certifiers_path(fpr_primary);
+        self.read_from_path(&path, true)
+            .map(|certifiers| {
+                certifiers
+                    .lines()
+                    .flat_map(|line| line.parse::<Fingerprint>())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn primary_fprs_by_domain(&self, domain: &str) -> Vec<Fingerprint> {
+        use std::fs;
+        use walkdir::WalkDir;
+
+        let subdomain_suffix = format!(".{}", domain);
+        let domain_dirs = match fs::read_dir(&self.links_dir_wkd_by_email) {
+            Ok(entries) => entries.flatten().map(|entry| entry.path()),
+            Err(_) => return vec![],
+        };
+
+        let mut fprs: Vec<Fingerprint> = domain_dirs
+            .filter(|path| {
+                // Domains are normalized to ASCII, so they appear
+                // unchanged in the url-encoded directory names.
+                let name = path.file_name().unwrap().to_string_lossy();
+                name == domain || name.ends_with(&subdomain_suffix)
+            })
+            .flat_map(|path| WalkDir::new(path).into_iter().flatten())
+            .filter(|entry| platform::is_link(entry.path()))
+            .flat_map(|entry| Filesystem::path_to_primary(entry.path()))
+            .collect();
+        fprs.sort_by_key(|fpr| fpr.to_string());
+        fprs.dedup();
+        fprs
+    }
+
+    fn write_audit_log(&self, entry: &str) -> Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.audit_log_file)?;
+        // Mark where a new log starts, so that readers can tell it
+        // from one that lost its beginning.
+        if file.metadata()?.len() == 0 {
+            writeln!(file, "{:010} {}", timestamp, AUDIT_LOG_START)?;
+        }
+        writeln!(file, "{:010} {}", timestamp, entry)?;
+        Ok(())
+    }
+
+    fn append_transparency_leaf(&self, leaf: &str) -> Result<()> {
+        if self.dry_run {
+            return Ok(());
+        }
+        let mut log = self.transparency_log.lock().unwrap();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&self.transparency_log_file)?;
+        writeln!(file, "{}", leaf)?;
+        log.read_appended(&mut file)?;
+        Ok(())
+    }
+
+    fn transparency_log(&self) -> MutexGuard<transparency::Log> {
+        let mut log = self.transparency_log.lock().unwrap();
+        // Other processes, like hagridctl, append to the log as well.
+        match File::open(&self.transparency_log_file) {
+            Ok(file) => {
+                if let Err(e) = log.read_appended(file) {
+                    error!("Error reading the transparency log! {}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => error!("Error opening the transparency log! {}", e),
+        }
+        log
+    }
+
+    fn check_link_fpr(
+        &self,
+        fpr: &Fingerprint,
         fpr_target: &Fingerprint,
     ) -> Result<Option<Fingerprint>> {
         let link_keyid = self.link_by_keyid(&fpr.into());
@@ -479,7 +1538,7 @@ impl Database for Filesystem {
 
         let path_published = self.fingerprint_to_path_published(fpr_target);
 
-        if let Ok(link_fpr_target) = link_fpr.canonicalize() {
+        if let Ok(link_fpr_target) = platform::canonicalize(&link_fpr) {
             if !link_fpr_target.ends_with(&path_published) {
                 info!("Fingerprint points to different key for {} (expected {:?} to be suffix of {:?})",
                     fpr, &path_published, &link_fpr_target);
@@ -487,7 +1546,7 @@ impl Database for Filesystem {
             }
         }
 
-        if let Ok(link_keyid_target) = link_keyid.canonicalize() {
+        if let Ok(link_keyid_target) = platform::canonicalize(&link_keyid) {
             if !link_keyid_target.ends_with(&path_published) {
                 info!(
                     "KeyID points to different key for {} (expected {:?} to be suffix of {:?})",
@@ -497,7 +1556,7 @@ impl Database for Filesystem {
             }
         }
 
-        if !link_fpr.exists() || !link_keyid.exists() {
+        if !platform::resolve(&link_fpr).exists() || !platform::resolve(&link_keyid).exists() {
             Ok(Some(fpr.clone()))
         } else {
             Ok(None)
@@ -512,7 +1571,7 @@ impl Database for Filesystem {
             ByEmail(ref email) => self.link_by_email(email),
             _ => return None,
         };
-        path.read_link()
+        read_link(&path)
             .ok()
             .and_then(|link_path| Filesystem::path_to_fingerprint(&link_path))
     }
@@ -586,6 +1645,18 @@ impl Database for Filesystem {
         self.read_from_path(&path, false)
     }
 
+    fn published_modified(&self, fpr: &Fingerprint) -> Option<SystemTime> {
+        let path = self.fingerprint_to_path_published(fpr);
+        metadata(path).and_then(|m| m.modified()).ok()
+    }
+
+    fn email_verified(&self, email: &Email) -> Option<SystemTime> {
+        use std::fs;
+
+        let link = self.link_by_email(email);
+        fs::symlink_metadata(link).and_then(|m| m.modified()).ok()
+    }
+
     // XXX: slow
     fn by_fpr(&self, fpr: &Fingerprint) -> Option<String> {
         let path = self.link_by_fingerprint(fpr);
@@ -604,9 +1675,8 @@ impl Database for Filesystem {
         self.read_from_path_bytes(&path, false)
     }
 
-    // XXX: slow
     fn by_domain_and_hash_wkd(&self, domain: &str, hash: &str) -> Option<Vec<u8>> {
-        let path = self.link_wkd_by_domain_and_hash(domain, hash);
+        let path = self.link_wkd_by_domain_and_hash(domain, hash)?;
         self.read_from_path_bytes(&path, false)
     }
 
@@ -616,164 +1686,244 @@ impl Database for Filesystem {
         self.read_from_path(&path, false)
     }
 
-    /// Checks the database for consistency.
-    ///
-    /// Note that this operation may take a long time, and is
-    /// generally only useful for testing.
-    fn check_consistency(&self) -> Result<()> {
+    fn consistency_findings(&self) -> Result<Vec<Finding>> {
         // A cache of all Certs, for quick lookups.
         let mut tpks = HashMap::new();
+        let mut findings = Vec::new();
 
         self.perform_checks(
             &self.keys_dir_published,
             &mut tpks,
+            &mut findings,
             |path, _, primary_fp| {
                 // The KeyID corresponding with this path.
-                let fp = Filesystem::path_to_fingerprint(path)
-                    .ok_or_else(|| format_err!("Malformed path: {:?}", path))?;
+                let fp = Filesystem::path_to_fingerprint(path).ok_or_else(|| {
+                    Finding::new(
+                        FindingKind::CorruptFile,
+                        path,
+                        format!("Malformed path: {:?}", path),
+                    )
+                })?;
 
                 if fp != *primary_fp {
-                    return Err(format_err!(
-                        "{:?} points to the wrong Cert, expected {} \
-                            but found {}",
+                    return Err(Finding::new(
+                        FindingKind::WrongTarget,
                         path,
-                        fp,
-                        primary_fp
+                        format!(
+                            "{:?} points to the wrong Cert, expected {} \
+                                but found {}",
+                            path, fp, primary_fp
+                        ),
                     ));
                 }
                 Ok(())
             },
         )?;
 
-        self.perform_checks(&self.keys_dir_published, &mut tpks, |_, tpk, primary_fp| {
-            // check that certificate exists in published wkd path
-            let path_wkd = self.fingerprint_to_path_published_wkd(primary_fp);
-            let should_wkd_exist = tpk.userids().next().is_some();
-
-            if should_wkd_exist && !path_wkd.exists() {
-                return Err(format_err!("Missing wkd for fp {}", primary_fp));
-            };
-            if !should_wkd_exist && path_wkd.exists() {
-                return Err(format_err!("Incorrectly present wkd for fp {}", primary_fp));
-            };
-            Ok(())
-        })?;
+        self.perform_checks(
+            &self.keys_dir_published,
+            &mut tpks,
+            &mut findings,
+            |path, tpk, primary_fp| {
+                // check that certificate exists in published wkd path
+                let path_wkd = self.fingerprint_to_path_published_wkd(primary_fp);
+                let should_wkd_exist = tpk.userids().next().is_some();
+
+                if should_wkd_exist && !path_wkd.exists() {
+                    return Err(Finding::new(
+                        FindingKind::MissingLink,
+                        path,
+                        format!("Missing wkd for fp {}", primary_fp),
+                    ));
+                };
+                if !should_wkd_exist && path_wkd.exists() {
+                    return Err(Finding::new(
+                        FindingKind::OrphanSymlink,
+                        path_wkd,
+                        format!("Incorrectly present wkd for fp {}", primary_fp),
+                    ));
+                };
+                Ok(())
+            },
+        )?;
 
         // check that all subkeys are linked
-        self.perform_checks(&self.keys_dir_published, &mut tpks, |_, tpk, primary_fp| {
-            let policy = &POLICY;
-            let fingerprints = tpk
-                .keys()
-                .with_policy(policy, None)
-                .for_certification()
-                .for_signing()
-                .map(|amalgamation| amalgamation.key().fingerprint())
-                .map(Fingerprint::try_from)
-                .flatten();
-
-            for fpr in fingerprints {
-                if let Some(missing_fpr) = self.check_link_fpr(&fpr, primary_fp)? {
-                    return Err(format_err!(
-                        "Missing link to key {} for sub {}",
-                        primary_fp,
-                        missing_fpr
-                    ));
+        self.perform_checks(
+            &self.keys_dir_published,
+            &mut tpks,
+            &mut findings,
+            |path, tpk, primary_fp| {
+                let policy = &POLICY;
+                let fingerprints = tpk
+                    .keys()
+                    .with_policy(policy, None)
+                    .for_certification()
+                    .for_signing()
+                    .map(|amalgamation| amalgamation.key().fingerprint())
+                    .map(Fingerprint::try_from)
+                    .flatten();
+
+                for fpr in fingerprints {
+                    match self.check_link_fpr(&fpr, primary_fp) {
+                        Ok(None) => (),
+                        Ok(Some(missing_fpr)) => {
+                            return Err(Finding::new(
+                                FindingKind::MissingLink,
+                                path,
+                                format!(
+                                    "Missing link to key {} for sub {}",
+                                    primary_fp, missing_fpr
+                                ),
+                            ));
+                        }
+                        Err(e) => {
+                            return Err(Finding::new(
+                                FindingKind::WrongTarget,
+                                self.link_by_fingerprint(&fpr),
+                                e.to_string(),
+                            ));
+                        }
+                    }
                 }
-            }
-            Ok(())
-        })?;
+                Ok(())
+            },
+        )?;
 
         // check that all published uids are linked
-        self.perform_checks(&self.keys_dir_published, &mut tpks, |_, tpk, primary_fp| {
-            let emails = tpk
-                .userids()
-                .map(|binding| binding.userid().clone())
-                .map(|userid| Email::try_from(&userid).unwrap());
-
-            for email in emails {
-                let email_path = self.link_by_email(&email);
-                if !email_path.exists() {
-                    return Err(format_err!(
-                        "Missing link to key {} for email {}",
-                        primary_fp,
-                        email
+        self.perform_checks(
+            &self.keys_dir_published,
+            &mut tpks,
+            &mut findings,
+            |_, tpk, primary_fp| {
+                let emails = tpk
+                    .userids()
+                    .map(|binding| binding.userid().clone())
+                    .map(|userid| Email::try_from(&userid).unwrap());
+
+                for email in emails {
+                    let email_path = self.link_by_email(&email);
+                    if !platform::resolve(&email_path).exists() {
+                        return Err(Finding::new(
+                            FindingKind::MissingLink,
+                            email_path,
+                            format!("Missing link to key {} for email {}", primary_fp, email),
+                        ));
+                    }
+                    let email_wkd_path = self.link_wkd_by_email(&email);
+                    if !platform::resolve(&email_wkd_path).exists() {
+                        return Err(Finding::new(
+                            FindingKind::MissingLink,
+                            email_wkd_path,
+                            format!("Missing wkd link to key {} for email {}", primary_fp, email),
+                        ));
+                    }
+                }
+                Ok(())
+            },
+        )?;
+
+        self.perform_checks(
+            &self.links_dir_by_fingerprint,
+            &mut tpks,
+            &mut findings,
+            |path, tpk, _| {
+                // The KeyID corresponding with this path.
+                let id = Filesystem::path_to_keyid(path).ok_or_else(|| {
+                    Finding::new(
+                        FindingKind::CorruptFile,
+                        path,
+                        format!("Malformed path: {:?}", path),
+                    )
+                })?;
+
+                let found = tpk
+                    .keys()
+                    .map(|amalgamation| KeyID::try_from(amalgamation.key().fingerprint()).unwrap())
+                    .any(|key_fp| key_fp == id);
+                if !found {
+                    return Err(Finding::new(
+                        FindingKind::WrongTarget,
+                        path,
+                        format!(
+                            "{:?} points to the wrong Cert, the Cert does not \
+                                contain the (sub)key {}",
+                            path, id
+                        ),
                     ));
                 }
-                let email_wkd_path = self.link_wkd_by_email(&email);
-                if !email_wkd_path.exists() {
-                    return Err(format_err!(
-                        "Missing wkd link to key {} for email {}",
-                        primary_fp,
-                        email
+                Ok(())
+            },
+        )?;
+
+        self.perform_checks(
+            &self.links_dir_by_keyid,
+            &mut tpks,
+            &mut findings,
+            |path, tpk, _| {
+                // The KeyID corresponding with this path.
+                let id = Filesystem::path_to_keyid(path).ok_or_else(|| {
+                    Finding::new(
+                        FindingKind::CorruptFile,
+                        path,
+                        format!("Malformed path: {:?}", path),
+                    )
+                })?;
+
+                let found = tpk
+                    .keys()
+                    .map(|amalgamation| KeyID::try_from(amalgamation.key().fingerprint()).unwrap())
+                    .any(|key_fp| key_fp == id);
+                if !found {
+                    return Err(Finding::new(
+                        FindingKind::WrongTarget,
+                        path,
+                        format!(
+                            "{:?} points to the wrong Cert, the Cert does not \
+                                contain the (sub)key {}",
+                            path, id
+                        ),
                     ));
                 }
-            }
-            Ok(())
-        })?;
-
-        self.perform_checks(&self.links_dir_by_fingerprint, &mut tpks, |path, tpk, _| {
-            // The KeyID corresponding with this path.
-            let id = Filesystem::path_to_keyid(path)
-                .ok_or_else(|| format_err!("Malformed path: {:?}", path))?;
-
-            let found = tpk
-                .keys()
-                .map(|amalgamation| KeyID::try_from(amalgamation.key().fingerprint()).unwrap())
-                .any(|key_fp| key_fp == id);
-            if !found {
-                return Err(format_err!(
-                    "{:?} points to the wrong Cert, the Cert does not \
-                            contain the (sub)key {}",
-                    path,
-                    id
-                ));
-            }
-            Ok(())
-        })?;
-
-        self.perform_checks(&self.links_dir_by_keyid, &mut tpks, |path, tpk, _| {
-            // The KeyID corresponding with this path.
-            let id = Filesystem::path_to_keyid(path)
-                .ok_or_else(|| format_err!("Malformed path: {:?}", path))?;
-
-            let found = tpk
-                .keys()
-                .map(|amalgamation| KeyID::try_from(amalgamation.key().fingerprint()).unwrap())
-                .any(|key_fp| key_fp == id);
-            if !found {
-                return Err(format_err!(
-                    "{:?} points to the wrong Cert, the Cert does not \
-                            contain the (sub)key {}",
-                    path,
-                    id
-                ));
-            }
-            Ok(())
-        })?;
-
-        self.perform_checks(&self.links_dir_by_email, &mut tpks, |path, tpk, _| {
-            // The Email corresponding with this path.
-            let email = Filesystem::path_to_email(path)
-                .ok_or_else(|| format_err!("Malformed path: {:?}", path))?;
-            let mut found = false;
-            for uidb in tpk.userids() {
-                if Email::try_from(uidb.userid()).unwrap() == email {
-                    found = true;
-                    break;
+                Ok(())
+            },
+        )?;
+
+        self.perform_checks(
+            &self.links_dir_by_email,
+            &mut tpks,
+            &mut findings,
+            |path, tpk, _| {
+                // The Email corresponding with this path.
+                let email = Filesystem::path_to_email(path).ok_or_else(|| {
+                    Finding::new(
+                        FindingKind::CorruptFile,
+                        path,
+                        format!("Malformed path: {:?}", path),
+                    )
+                })?;
+                let mut found = false;
+                for uidb in tpk.userids() {
+                    if Email::try_from(uidb.userid()).unwrap() == email {
+                        found = true;
+                        break;
+                    }
                 }
-            }
-            if !found {
-                return Err(format_err!(
-                    "{:?} points to the wrong Cert, the Cert does not \
-                            contain the email {}",
-                    path,
-                    email
-                ));
-            }
-            Ok(())
-        })?;
+                if !found {
+                    return Err(Finding::new(
+                        FindingKind::WrongTarget,
+                        path,
+                        format!(
+                            "{:?} points to the wrong Cert, the Cert does not \
+                                contain the email {}",
+                            path, email
+                        ),
+                    ));
+                }
+                Ok(())
+            },
+        )?;
 
-        Ok(())
+        Ok(findings)
     }
 }
 
@@ -801,8 +1951,10 @@ fn path_merge(path: &Path) -> String {
 mod tests {
     use super::*;
     use openpgp::cert::CertBuilder;
+    use openpgp::parse::Parse;
     use tempfile::TempDir;
     use test;
+    use Severity;
 
     #[test]
     fn init() {
@@ -818,6 +1970,504 @@ mod tests {
         (tmpdir, db, log_path)
     }
 
+    #[test]
+    fn concurrent_merges() {
+        use std::thread;
+
+        let (tmpdir, db, _log_path) = open_db();
+        let addresses: Vec<String> = (0..4)
+            .map(|i| format!("{}@invalid.example.org", i))
+            .collect();
+        let mut builder = CertBuilder::new();
+        for address in &addresses {
+            builder = builder.add_userid(address.as_str());
+        }
+        let tpk = builder.generate().unwrap().0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+
+        // Each writer uploads a different part of the key, as if from a
+        // different process.
+        let threads: Vec<_> = addresses
+            .iter()
+            .cloned()
+            .map(|address| {
+                let base_dir = tmpdir.path().to_owned();
+                let part = tpk
+                    .clone()
+                    .retain_userids(|uid| uid.userid().value() == address.as_bytes());
+                thread::spawn(move || {
+                    let db = Filesystem::new_from_base(base_dir).unwrap();
+                    db.merge(part).unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let merged = Cert::from_bytes(db.by_fpr_full(&fpr).unwrap().as_bytes()).unwrap();
+        assert_eq!(merged.userids().count(), addresses.len());
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn lookups_dont_lock() {
+        use std::sync::mpsc;
+        use std::thread;
+        use std::time::Duration;
+
+        let (tmpdir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .add_signing_subkey()
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let keyid = KeyID::try_from(tpk.fingerprint()).unwrap();
+        let email: Email = "a@invalid.example.org".parse().unwrap();
+        db.merge(tpk).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+
+        // Holding the lock, as a long-running import would.
+        let _lock = db.lock().unwrap();
+
+        // Look up from another handle, so a lookup taking the lock
+        // would block instead of succeeding.
+        let (sender, receiver) = mpsc::channel();
+        let base_dir = tmpdir.path().to_owned();
+        thread::spawn(move || {
+            let db = Filesystem::new_from_base(base_dir).unwrap();
+            let found = db.lookup_primary_fingerprint(&Query::ByFingerprint(fpr.clone()))
+                == Some(fpr.clone())
+                && db.lookup_primary_fingerprint(&Query::ByKeyID(keyid.clone()))
+                    == Some(fpr.clone())
+                && db.lookup_primary_fingerprint(&Query::ByEmail(email.clone()))
+                    == Some(fpr.clone())
+                && db.by_fpr(&fpr).is_some()
+                && db.by_kid(&keyid).is_some()
+                && db.by_email(&email).is_some()
+                && db.by_email_wkd(&email).is_some();
+            let _ = sender.send(found);
+        });
+
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(10)), Ok(true));
+    }
+
+    #[test]
+    fn consistency_findings() {
+        let (_tmpdir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let email: Email = "a@invalid.example.org".parse().unwrap();
+        db.merge(tpk).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+        assert_eq!(db.consistency_findings().unwrap(), vec![]);
+
+        db.unlink_email(&email, &fpr).unwrap();
+        let findings = db.consistency_findings().unwrap();
+        assert!(!findings.is_empty());
+        assert!(findings
+            .iter()
+            .all(|finding| finding.kind == FindingKind::MissingLink
+                && finding.severity == Severity::Error));
+        db.regenerate_links(&fpr).unwrap();
+
+        // A link to a key that doesn't exist.
+        let missing: Fingerprint = "CBCD8F030588653EEDD7E2659B7DD433F254904A".parse().unwrap();
+        let link = db.link_by_fingerprint(&missing);
+        ensure_parent(&link).unwrap();
+        platform::symlink(&db.fingerprint_to_path_published(&missing), &link).unwrap();
+        let findings = db.consistency_findings().unwrap();
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].kind, FindingKind::OrphanSymlink);
+        assert_eq!(findings[0].severity, Severity::Warning);
+        assert_eq!(findings[0].path, link);
+    }
+
+    #[test]
+    fn prune_stale_tempfiles() {
+        let (_tmpdir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let email: Email = "a@invalid.example.org".parse().unwrap();
+        db.merge(tpk).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+
+        // Left behind by a crashed process.
+        let tempfile = db
+            .write_to_temp(b"key")
+            .unwrap()
+            .into_temp_path()
+            .keep()
+            .unwrap();
+        let link_tempdir = db
+            .link_by_fingerprint(&fpr)
+            .parent()
+            .unwrap()
+            .join("link0123456789abcdef");
+        create_dir_all(&link_tempdir).unwrap();
+
+        assert_eq!(
+            db.prune_stale_tempfiles(Duration::from_secs(3600)).unwrap(),
+            0
+        );
+        assert!(tempfile.exists());
+        assert!(link_tempdir.exists());
+
+        // Along with them goes the blob of the key as published before
+        // the address was verified.
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            db.prune_stale_tempfiles(Duration::from_millis(10)).unwrap(),
+            3
+        );
+        assert!(!tempfile.exists());
+        assert!(!link_tempdir.exists());
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn sweep_unverified() {
+        let (_tmpdir, db, _log_path) = open_db();
+        let verified = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let verified_fpr = Fingerprint::try_from(verified.fingerprint()).unwrap();
+        let email: Email = "a@invalid.example.org".parse().unwrap();
+        db.merge(verified).unwrap();
+        db.set_email_published(&verified_fpr, &email).unwrap();
+        // Unpublishing the address later doesn't make the key
+        // unverified.
+        db.set_email_unpublished(&verified_fpr, &email).unwrap();
+
+        let unverified = CertBuilder::new()
+            .add_userid("b@invalid.example.org")
+            .add_signing_subkey()
+            .generate()
+            .unwrap()
+            .0;
+        let unverified_fpr = Fingerprint::try_from(unverified.fingerprint()).unwrap();
+        db.merge(unverified).unwrap();
+
+        assert!(db
+            .sweep_unverified(Duration::from_secs(3600), false)
+            .unwrap()
+            .is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            db.sweep_unverified(Duration::from_millis(10), true)
+                .unwrap(),
+            vec![unverified_fpr.clone()]
+        );
+        assert!(db.by_fpr_full(&unverified_fpr).is_some());
+
+        assert_eq!(
+            db.sweep_unverified(Duration::from_millis(10), false)
+                .unwrap(),
+            vec![unverified_fpr.clone()]
+        );
+        // The key is still served by fingerprint, but the unverified
+        // user ID is gone.
+        let full = db.by_fpr_full(&unverified_fpr).unwrap();
+        assert_eq!(Some(&full), db.by_primary_fpr(&unverified_fpr).as_ref());
+        let full = Cert::from_bytes(full.as_bytes()).unwrap();
+        assert_eq!(full.userids().count(), 0);
+        assert_eq!(full.keys().subkeys().count(), 1);
+        let full = Cert::from_bytes(db.by_fpr_full(&verified_fpr).unwrap().as_bytes()).unwrap();
+        assert_eq!(full.userids().count(), 1);
+
+        // Once stripped, there is nothing left to sweep.
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(db
+            .sweep_unverified(Duration::from_millis(10), false)
+            .unwrap()
+            .is_empty());
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn sweep_unverified_without_audit_log() {
+        let (_tmpdir, db, _log_path) = open_db();
+        let verified = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let verified_fpr = Fingerprint::try_from(verified.fingerprint()).unwrap();
+        let email: Email = "a@invalid.example.org".parse().unwrap();
+        db.merge(verified).unwrap();
+        db.set_email_published(&verified_fpr, &email).unwrap();
+
+        // The key was verified before the audit log, e.g. before it
+        // was rotated.
+        std::fs::remove_file(&db.audit_log_file).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(db
+            .sweep_unverified(Duration::from_millis(10), false)
+            .unwrap()
+            .is_empty());
+
+        // Once the new log is old enough, the published address still
+        // counts.
+        let unverified = CertBuilder::new()
+            .add_userid("b@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let unverified_fpr = Fingerprint::try_from(unverified.fingerprint()).unwrap();
+        db.merge(unverified).unwrap();
+        let (lines, _) = db.read_audit_log(0).unwrap();
+        assert!(lines[0].ends_with(AUDIT_LOG_START));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            db.sweep_unverified(Duration::from_millis(10), false)
+                .unwrap(),
+            vec![unverified_fpr]
+        );
+        let full = Cert::from_bytes(db.by_fpr_full(&verified_fpr).unwrap().as_bytes()).unwrap();
+        assert_eq!(full.userids().count(), 1);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn prune_quarantined() {
+        let (_tmpdir, db, _log_path) = open_db();
+        let fpr: Fingerprint = "CBCD8F030588653EEDD7E2659B7DD433F254904A".parse().unwrap();
+        db.write_to_quarantine(&fpr, b"key", None).unwrap();
+
+        assert!(db
+            .prune_quarantined(Duration::from_secs(3600), false)
+            .unwrap()
+            .is_empty());
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(
+            db.prune_quarantined(Duration::from_millis(10), true)
+                .unwrap(),
+            vec![fpr.clone()]
+        );
+        assert!(db.by_fpr_quarantined(&fpr).is_some());
+
+        assert_eq!(
+            db.prune_quarantined(Duration::from_millis(10), false)
+                .unwrap(),
+            vec![fpr.clone()]
+        );
+        assert!(db.by_fpr_quarantined(&fpr).is_none());
+    }
+
+    #[test]
+    fn legal_hold_survives_retention() {
+        let (_tmpdir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        db.merge(tpk).unwrap();
+        let quarantined: Fingerprint = "CBCD8F030588653EEDD7E2659B7DD433F254904A".parse().unwrap();
+        db.write_to_quarantine(&quarantined, b"key", None).unwrap();
+
+        db.place_legal_hold(&fpr, "court order").unwrap();
+        db.place_legal_hold(&quarantined, "court order").unwrap();
+        let (lines, _) = db.read_audit_log(0).unwrap();
+        assert!(lines
+            .iter()
+            .any(|line| line.ends_with(&format!("hold-fingerprint {} \"court order\"", fpr))));
+
+        std::thread::sleep(Duration::from_millis(20));
+        let max_age = Duration::from_millis(10);
+        assert!(db.sweep_unverified(max_age, true).unwrap().is_empty());
+        assert!(db.sweep_unverified(max_age, false).unwrap().is_empty());
+        assert!(db.prune_quarantined(max_age, false).unwrap().is_empty());
+        assert!(db.by_fpr_full(&fpr).is_some());
+        assert!(db.by_fpr_quarantined(&quarantined).is_some());
+
+        db.release_legal_hold(&fpr).unwrap();
+        db.release_legal_hold(&quarantined).unwrap();
+        let (lines, _) = db.read_audit_log(0).unwrap();
+        assert!(lines
+            .iter()
+            .any(|line| line.ends_with(&format!("release-fingerprint {}", fpr))));
+        assert_eq!(
+            db.sweep_unverified(max_age, false).unwrap(),
+            vec![fpr.clone()]
+        );
+        assert_eq!(
+            db.prune_quarantined(max_age, false).unwrap(),
+            vec![quarantined]
+        );
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn published_blobs() {
+        let (_tmpdir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let email: Email = "a@invalid.example.org".parse().unwrap();
+        db.merge(tpk.clone()).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+
+        let published = db.fingerprint_to_path_published(&fpr);
+        let blob = db.hash_to_path_blob(&key_hash(&read(&published).unwrap()));
+        assert!(platform::same_file(&blob, &published));
+        let modified = metadata(&published).unwrap().modified().unwrap();
+
+        // Publishing the same key again leaves the file alone.
+        std::thread::sleep(Duration::from_millis(20));
+        db.merge(tpk).unwrap();
+        assert_eq!(metadata(&published).unwrap().modified().unwrap(), modified);
+        assert!(platform::same_file(&blob, &published));
+
+        // Only the blob that is no longer published is pruned.
+        assert_eq!(
+            db.prune_stale_tempfiles(Duration::from_millis(10)).unwrap(),
+            1
+        );
+        assert!(blob.exists());
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn published_counts() {
+        let (_tmpdir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let email: Email = "a@invalid.example.org".parse().unwrap();
+
+        let incremental = |offset: u64, counts: &mut PublishedCounts| {
+            let (lines, offset) = db.read_audit_log(offset).unwrap();
+            for line in lines {
+                counts.apply_audit_entry(&line);
+            }
+            offset
+        };
+        let mut counts = db.count_published().unwrap();
+        assert_eq!(counts, PublishedCounts::default());
+        let offset = db.audit_log_end().unwrap();
+
+        db.merge(tpk.clone()).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+        // Neither re-uploads nor re-verifications change the counts.
+        db.merge(tpk).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+        let offset = incremental(offset, &mut counts);
+        let expected = PublishedCounts {
+            keys: 1,
+            addresses: 1,
+        };
+        assert_eq!(counts, expected);
+        assert_eq!(db.count_published().unwrap(), expected);
+
+        db.set_email_unpublished(&fpr, &email).unwrap();
+        let offset = incremental(offset, &mut counts);
+        let expected = PublishedCounts {
+            keys: 1,
+            addresses: 0,
+        };
+        assert_eq!(counts, expected);
+        assert_eq!(db.count_published().unwrap(), expected);
+
+        db.delete_key(&fpr).unwrap();
+        incremental(offset, &mut counts);
+        assert_eq!(counts, PublishedCounts::default());
+        assert_eq!(db.count_published().unwrap(), PublishedCounts::default());
+    }
+
+    #[test]
+    fn published_counts_by_domain() {
+        let (_tmpdir, db, _log_path) = open_db();
+        assert!(db.count_published_by_domain().unwrap().is_empty());
+
+        for address in &["a@example.org", "b@example.org", "c@example.com"] {
+            let tpk = CertBuilder::new()
+                .add_userid(*address)
+                .generate()
+                .unwrap()
+                .0;
+            let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+            db.merge(tpk).unwrap();
+            db.set_email_published(&fpr, &address.parse().unwrap())
+                .unwrap();
+        }
+        let counts = db.count_published_by_domain().unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["example.org"], 2);
+        assert_eq!(counts["example.com"], 1);
+    }
+
+    #[test]
+    fn published_fprs() {
+        let (_tmpdir, db, _log_path) = open_db();
+        let mut fprs: Vec<Fingerprint> = (0..5)
+            .map(|i| {
+                let tpk = CertBuilder::new()
+                    .add_userid(format!("{}@invalid.example.org", i))
+                    .generate()
+                    .unwrap()
+                    .0;
+                db.merge(tpk.clone()).unwrap();
+                Fingerprint::try_from(tpk.fingerprint()).unwrap()
+            })
+            .collect();
+        fprs.sort_by_key(|fpr| fpr.to_string());
+
+        assert_eq!(db.published_fprs(None, 10).unwrap(), fprs);
+        assert_eq!(db.published_fprs(None, 2).unwrap(), &fprs[..2]);
+        assert_eq!(db.published_fprs(Some(&fprs[1]), 2).unwrap(), &fprs[2..4]);
+        assert!(db.published_fprs(Some(&fprs[4]), 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn email_verified() {
+        let (_tmpdir, db, _log_path) = open_db();
+        let tpk = CertBuilder::new()
+            .add_userid("a@invalid.example.org")
+            .generate()
+            .unwrap()
+            .0;
+        let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+        let email: Email = "a@invalid.example.org".parse().unwrap();
+
+        db.merge(tpk.clone()).unwrap();
+        assert_eq!(db.email_verified(&email), None);
+
+        db.set_email_published(&fpr, &email).unwrap();
+        let verified = db.email_verified(&email);
+        assert!(verified.is_some());
+
+        // Neither re-uploads nor re-verifications change the date.
+        std::thread::sleep(Duration::from_millis(10));
+        db.merge(tpk).unwrap();
+        db.set_email_published(&fpr, &email).unwrap();
+        assert_eq!(db.email_verified(&email), verified);
+
+        db.set_email_unpublished(&fpr, &email).unwrap();
+        assert_eq!(db.email_verified(&email), None);
+    }
+
     #[test]
     fn new() {
         let (_tmp_dir, db, _log_path) = open_db();
@@ -979,6 +2629,171 @@ mod tests {
         db.check_consistency().expect("inconsistent database");
     }
 
+    #[test]
+    fn uid_policy_publish_all() {
+        let (_tmp_dir, db, log_path) = open_db();
+        let mut db = db.with_uid_policy(UidPolicy::PublishAll);
+        test::test_uid_policy_publish_all(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn uid_policy_none() {
+        let (_tmp_dir, db, log_path) = open_db();
+        let mut db = db.with_uid_policy(UidPolicy::NoUids);
+        test::test_uid_policy_none(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn max_published_uids() {
+        let (_tmp_dir, db, log_path) = open_db();
+        let mut db = db.with_max_published_uids(2);
+        test::test_max_published_uids(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn max_published_uids_publish_all() {
+        let (_tmp_dir, db, log_path) = open_db();
+        let mut db = db
+            .with_uid_policy(UidPolicy::PublishAll)
+            .with_max_published_uids(2);
+        test::test_max_published_uids_publish_all(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn max_self_signatures() {
+        let (_tmp_dir, db, log_path) = open_db();
+        let mut db = db.with_max_self_signatures(2);
+        test::test_max_self_signatures(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn compact() {
+        let (_tmp_dir, db, log_path) = open_db();
+        let mut db = db.with_max_self_signatures(2);
+        test::test_compact(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn unhashed_area_strip() {
+        let (_tmp_dir, db, log_path) = open_db();
+        let mut db = db.with_unhashed_area_limit(1024, UnhashedAreaAction::Strip);
+        test::test_unhashed_area_strip(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn unhashed_area_reject() {
+        let (_tmp_dir, db, log_path) = open_db();
+        let mut db = db.with_unhashed_area_limit(1024, UnhashedAreaAction::Reject);
+        test::test_unhashed_area_reject(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn merge_dedup() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_merge_dedup(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn merge_diff() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_merge_diff(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn unbound_subkey() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_unbound_subkey(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn domain_role() {
+        let (_tmp_dir, db, log_path) = open_db();
+        let mut db = db.with_domain_role_localpart("security".to_owned());
+        test::test_domain_role(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn ban() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_ban(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn ban_email() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_ban_email(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn quarantine() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_quarantine(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn unpublish_if_expired() {
+        let (_tmp_dir, mut db, _log_path) = open_db();
+        test::test_unpublish_if_expired(&mut db);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn legal_hold() {
+        let (_tmp_dir, mut db, _log_path) = open_db();
+        test::test_legal_hold(&mut db);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn transparency_log() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_transparency_log(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn lint() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_lint(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn domain_allowlist() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_domain_allowlist(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn published_by_domain() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_published_by_domain(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
+    #[test]
+    fn minimal_export() {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::test_minimal_export(&mut db, &log_path);
+        db.check_consistency().expect("inconsistent database");
+    }
+
     #[test]
     fn reverse_fingerprint_to_path() {
         let tmpdir = TempDir::new().unwrap();
@@ -1001,6 +2816,14 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn retained_certifications() -> Result<()> {
+        let (_tmp_dir, mut db, log_path) = open_db();
+        test::retained_certifications(&mut db, &log_path)?;
+        db.check_consistency()?;
+        Ok(())
+    }
+
     #[test]
     fn nonexportable_sigs() -> Result<()> {
         let (_tmp_dir, mut db, log_path) = open_db();
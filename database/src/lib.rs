@@ -1,9 +1,13 @@
 #![recursion_limit = "1024"]
 
 use std::convert::TryFrom;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::MutexGuard;
+use std::time::{Duration, SystemTime};
 
 use openpgp::serialize::SerializeInto;
+use serde::{Deserialize, Serialize};
 
 use chrono::prelude::Utc;
 
@@ -33,16 +37,26 @@ pub mod types;
 use types::{Email, Fingerprint, KeyID};
 
 pub mod sync;
+pub mod transparency;
 pub mod wkd;
+use transparency::{ConsistencyProof, InclusionProof, TreeHead};
 
 mod fs;
 pub use self::fs::Filesystem as KeyDatabase;
 
+pub mod platform;
+
 mod stateful_tokens;
 pub use stateful_tokens::StatefulTokens;
 
+mod reloadable;
+pub use reloadable::Reloadable;
+
 mod openpgp_utils;
-use openpgp_utils::{is_status_revoked, tpk_clean, tpk_filter_alive_emails, tpk_to_string, POLICY};
+use openpgp_utils::{
+    is_status_revoked, tpk_cap_userids, tpk_clean, tpk_filter_alive_emails, tpk_lint, tpk_minimize,
+    tpk_signatures, tpk_strip_unbound_subkeys, tpk_strip_unhashed_areas, tpk_to_string, POLICY,
+};
 
 #[cfg(test)]
 mod test;
@@ -113,6 +127,246 @@ pub struct TpkStatus {
     pub is_revoked: bool,
     pub email_status: Vec<(Email, EmailAddressStatus)>,
     pub unparsed_uids: usize,
+    /// Addresses left out of the published Cert because it already
+    /// holds the maximum number of user IDs.
+    pub dropped_uids: Vec<Email>,
+    pub warnings: Vec<ImportWarning>,
+}
+
+/// How often a merge is attempted while the key is updated concurrently.
+const MERGE_ATTEMPTS: usize = 5;
+
+/// What a merge changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeDiff {
+    /// Subkeys that weren't in the database before.
+    pub new_subkeys: Vec<Fingerprint>,
+    /// Uploaded user IDs that weren't in the database before.
+    pub new_uids: Vec<String>,
+    /// Number of signatures that weren't in the database before.
+    pub new_signatures: usize,
+    /// Uploaded user IDs of the key that are not published.
+    pub filtered_uids: Vec<String>,
+    /// Number of signatures of the key that are not published.
+    pub filtered_signatures: usize,
+    /// Whether the published key changed.
+    pub published_changed: bool,
+}
+
+/// Why part of a key is not published, or will be ignored by clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LintKind {
+    /// All self-signatures of the component have expired.
+    ExpiredSelfSignature,
+    /// The component is only bound by SHA-1 signatures, which are no
+    /// longer accepted.
+    Sha1OnlyBinding,
+    /// The subkey has no binding signature that could be verified.
+    UnverifiableSubkey,
+    /// The component has self-signatures, but none of them is valid.
+    InvalidSelfSignature,
+    /// The user ID has been revoked.
+    RevokedUserId,
+    /// Photo IDs and other user attributes are never published.
+    FilteredUserAttribute,
+}
+
+/// A problem with one component of a key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LintIssue {
+    pub kind: LintKind,
+    /// The affected subkey's fingerprint, or the affected user ID.
+    ///
+    /// Unpublished user IDs are never named.
+    pub component: Option<String>,
+}
+
+/// Something about an import that the uploader should know about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportWarning {
+    /// This many signatures had their oversized unhashed subpacket
+    /// areas removed.
+    StrippedUnhashedAreas(usize),
+    /// This many subkeys were dropped because they lack a valid
+    /// binding signature.
+    DroppedUnboundSubkeys(usize),
+}
+
+impl ImportWarning {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ImportWarning::StrippedUnhashedAreas(_) => "stripped-unhashed-areas",
+            ImportWarning::DroppedUnboundSubkeys(_) => "dropped-unbound-subkeys",
+        }
+    }
+}
+
+/// What to do with signatures whose unhashed subpacket area is too
+/// large.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnhashedAreaAction {
+    /// Remove the unhashed area, and import the key.
+    Strip,
+    /// Refuse to import the key.
+    Reject,
+}
+
+impl FromStr for UnhashedAreaAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "strip" => Ok(UnhashedAreaAction::Strip),
+            "reject" => Ok(UnhashedAreaAction::Reject),
+            _ => Err(anyhow!("Unknown unhashed area action: {}", s)),
+        }
+    }
+}
+
+/// The key has neither a valid user ID nor a valid subkey, and isn't
+/// revoked either.
+#[derive(Debug)]
+pub struct MalformedKey;
+
+impl std::fmt::Display for MalformedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Not a well-formed key!")
+    }
+}
+
+impl std::error::Error for MalformedKey {}
+
+/// The key contains signatures with oversized unhashed subpacket
+/// areas, and we were configured to reject those.
+#[derive(Debug)]
+pub struct OversizedUnhashedArea;
+
+impl std::fmt::Display for OversizedUnhashedArea {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Key contains oversized unhashed subpacket areas")
+    }
+}
+
+impl std::error::Error for OversizedUnhashedArea {}
+
+/// There is not enough free space left to store the key.
+#[derive(Debug)]
+pub struct InsufficientSpace;
+
+impl std::fmt::Display for InsufficientSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Not enough free space to store the key")
+    }
+}
+
+impl std::error::Error for InsufficientSpace {}
+
+/// What remains of a banned key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// Why the key was banned, for the operators' records.
+    pub reason: String,
+    /// Shown to anyone looking up the key, if set.
+    #[serde(default)]
+    pub notice: Option<String>,
+}
+
+/// Keeps a key from being deleted, e.g. for the duration of legal
+/// proceedings.
+///
+/// Held keys are not banned, compacted, or removed by retention
+/// rules, and neither their owner nor their domain can unpublish
+/// their addresses.  Updates are still merged, so that e.g.
+/// revocations reach users.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegalHold {
+    /// Why the key is held, for the operators' records.
+    pub reason: String,
+    /// When the hold was placed, in seconds since the epoch.
+    pub since: u64,
+}
+
+/// The key has been banned by an operator.
+#[derive(Debug)]
+pub struct BannedKey;
+
+impl std::fmt::Display for BannedKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "This key has been banned")
+    }
+}
+
+impl std::error::Error for BannedKey {}
+
+/// The key is under legal hold.
+#[derive(Debug)]
+pub struct HeldKey;
+
+impl std::fmt::Display for HeldKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "This key is under legal hold")
+    }
+}
+
+impl std::error::Error for HeldKey {}
+
+/// Which user IDs are included in the published variant of a key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UidPolicy {
+    /// Only user IDs whose email address has been verified.
+    VerifiedOnly,
+    /// All user IDs with an email address that aren't revoked.  This
+    /// is meant for internal instances, where all uploads are trusted.
+    PublishAll,
+    /// No user IDs at all.
+    NoUids,
+}
+
+impl Default for UidPolicy {
+    fn default() -> Self {
+        UidPolicy::VerifiedOnly
+    }
+}
+
+/// What is published of keys.
+///
+/// This can be changed while the server is running, see
+/// `KeyDatabase::publication_policy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PublicationPolicy {
+    pub uid_policy: UidPolicy,
+    /// The maximum number of user IDs in a published key.
+    pub max_published_uids: Option<usize>,
+    /// The maximum number of self-signatures per user ID or subkey in
+    /// a published key.
+    pub max_self_signatures: Option<usize>,
+    /// The largest permitted unhashed subpacket area, and what to do
+    /// with signatures that exceed it.
+    pub unhashed_area_limit: Option<(usize, UnhashedAreaAction)>,
+}
+
+impl UidPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UidPolicy::VerifiedOnly => "verified",
+            UidPolicy::PublishAll => "all",
+            UidPolicy::NoUids => "none",
+        }
+    }
+}
+
+impl FromStr for UidPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "verified" => Ok(UidPolicy::VerifiedOnly),
+            "all" => Ok(UidPolicy::PublishAll),
+            "none" => Ok(UidPolicy::NoUids),
+            _ => Err(anyhow!("Unknown user ID policy: {}", s)),
+        }
+    }
 }
 
 pub enum RegenerateResult {
@@ -120,6 +374,144 @@ pub enum RegenerateResult {
     Unchanged,
 }
 
+/// What compacting a published key did.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactResult {
+    /// Whether the published key was rewritten.
+    pub changed: bool,
+    /// Whether it was left alone because it is under legal hold.
+    pub held: bool,
+    /// Its size before and after, in bytes.
+    pub size_before: usize,
+    pub size_after: usize,
+}
+
+/// What setting a domain's allowlist did to its published addresses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AllowlistResult {
+    /// The addresses that were unpublished.
+    pub unpublished: Vec<Email>,
+    /// The addresses that are no longer allowed, but stay published
+    /// because their key is under legal hold.
+    pub held: Vec<Email>,
+}
+
+/// What banning an address or domain did to its published addresses.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EmailBanResult {
+    /// The addresses that were unpublished.
+    pub unpublished: Vec<Email>,
+    /// The banned addresses that stay published because their key is
+    /// under legal hold.
+    pub held: Vec<Email>,
+}
+
+/// Audit log entries recording changes to what is published, so that
+/// it can be counted and mirrored without walking the whole database.
+pub const AUDIT_PUBLISH_KEY: &str = "publish-key";
+pub const AUDIT_UPDATE_KEY: &str = "update-key";
+pub const AUDIT_UNPUBLISH_KEY: &str = "unpublish-key";
+pub const AUDIT_LINK_EMAIL: &str = "link-email";
+pub const AUDIT_UNLINK_EMAIL: &str = "unlink-email";
+
+/// The first entry of every audit log, so that it can be told apart
+/// from one that lost its beginning.
+pub const AUDIT_LOG_START: &str = "audit-log-start";
+
+/// How many keys and verified addresses are published.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PublishedCounts {
+    pub keys: u64,
+    pub addresses: u64,
+}
+
+impl PublishedCounts {
+    /// Updates the counts with a line of the audit log.
+    pub fn apply_audit_entry(&mut self, line: &str) {
+        match line.split_whitespace().nth(1) {
+            Some(AUDIT_PUBLISH_KEY) => self.keys += 1,
+            Some(AUDIT_UNPUBLISH_KEY) => self.keys = self.keys.saturating_sub(1),
+            Some(AUDIT_LINK_EMAIL) => self.addresses += 1,
+            Some(AUDIT_UNLINK_EMAIL) => self.addresses = self.addresses.saturating_sub(1),
+            _ => (),
+        }
+    }
+}
+
+/// How bad an inconsistency is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    /// Harmless leftovers, which lookups don't see.
+    Warning,
+    /// Lookups return wrong or no results.
+    Error,
+}
+
+impl FromStr for Severity {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "warning" => Ok(Severity::Warning),
+            "error" => Ok(Severity::Error),
+            _ => Err(anyhow!("Unknown severity: {}", s)),
+        }
+    }
+}
+
+/// What kind of inconsistency was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FindingKind {
+    /// A link points to a key that doesn't exist.
+    OrphanSymlink,
+    /// A published key or address is missing one of its links.
+    MissingLink,
+    /// A link points to a key that doesn't match it.
+    WrongTarget,
+    /// A file can't be parsed, or has a malformed path.
+    CorruptFile,
+}
+
+impl FindingKind {
+    pub fn severity(&self) -> Severity {
+        match self {
+            FindingKind::OrphanSymlink => Severity::Warning,
+            FindingKind::MissingLink => Severity::Error,
+            FindingKind::WrongTarget => Severity::Error,
+            FindingKind::CorruptFile => Severity::Error,
+        }
+    }
+}
+
+/// An inconsistency found in the database.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub kind: FindingKind,
+    pub severity: Severity,
+    /// The offending file or link.
+    pub path: PathBuf,
+    pub message: String,
+}
+
+impl Finding {
+    pub fn new(kind: FindingKind, path: impl Into<PathBuf>, message: impl Into<String>) -> Self {
+        Finding {
+            kind,
+            severity: kind.severity(),
+            path: path.into(),
+            message: message.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
 pub trait Database: Sync + Send {
     type MutexGuard;
     type TempCert;
@@ -128,10 +520,16 @@ pub trait Database: Sync + Send {
     ///
     /// All basic write operations are atomic so we don't need to lock
     /// read operations to ensure that we return something sane.
+    /// Lookups (`lookup_primary_fingerprint`, `by_fpr`, `by_kid`,
+    /// `by_email`, and the WKD variants) must never take this lock,
+    /// nor any other, so that they don't wait for long-running
+    /// imports.
     fn lock(&self) -> Result<Self::MutexGuard>;
 
     /// Queries the database using Fingerprint, KeyID, or
     /// email-address, returning the primary fingerprint.
+    ///
+    /// Doesn't take the lock.
     fn lookup_primary_fingerprint(&self, term: &Query) -> Option<Fingerprint>;
 
     fn link_email(&self, email: &Email, fpr: &Fingerprint) -> Result<()>;
@@ -140,6 +538,7 @@ pub trait Database: Sync + Send {
     fn link_fpr(&self, from: &Fingerprint, to: &Fingerprint) -> Result<()>;
     fn unlink_fpr(&self, from: &Fingerprint, to: &Fingerprint) -> Result<()>;
 
+    // Lookups, none of which take the lock.
     fn by_fpr(&self, fpr: &Fingerprint) -> Option<String>;
     fn by_kid(&self, kid: &KeyID) -> Option<String>;
     fn by_email(&self, email: &Email) -> Option<String>;
@@ -154,6 +553,10 @@ pub trait Database: Sync + Send {
 
     fn by_fpr_full(&self, fpr: &Fingerprint) -> Option<String>;
     fn by_primary_fpr(&self, fpr: &Fingerprint) -> Option<String>;
+    /// When the published variant of the key last changed.
+    fn published_modified(&self, fpr: &Fingerprint) -> Option<SystemTime>;
+    /// When the address was verified, if it is published.
+    fn email_verified(&self, email: &Email) -> Option<SystemTime>;
 
     fn write_to_temp(&self, content: &[u8]) -> Result<Self::TempCert>;
     fn move_tmp_to_full(&self, content: Self::TempCert, fpr: &Fingerprint) -> Result<()>;
@@ -163,10 +566,119 @@ pub trait Database: Sync + Send {
         content: Option<Self::TempCert>,
         fpr: &Fingerprint,
     ) -> Result<()>;
-    fn write_to_quarantine(&self, fpr: &Fingerprint, content: &[u8]) -> Result<()>;
+    /// Quarantines a key, along with the pseudonym of its uploader,
+    /// if known.
+    fn write_to_quarantine(
+        &self,
+        fpr: &Fingerprint,
+        content: &[u8],
+        uploader: Option<&str>,
+    ) -> Result<()>;
+    fn quarantined(&self) -> Vec<Fingerprint>;
+    fn by_fpr_quarantined(&self, fpr: &Fingerprint) -> Option<String>;
+    fn quarantine_uploader(&self, fpr: &Fingerprint) -> Option<String>;
+    fn delete_quarantined(&self, fpr: &Fingerprint) -> Result<()>;
     fn write_log_append(&self, filename: &str, fpr_primary: &Fingerprint) -> Result<()>;
 
-    fn check_consistency(&self) -> Result<()>;
+    fn write_ban(&self, fpr_primary: &Fingerprint, tombstone: &Tombstone) -> Result<()>;
+    fn tombstone(&self, fpr_primary: &Fingerprint) -> Option<Tombstone>;
+    fn write_legal_hold(&self, fpr_primary: &Fingerprint, hold: Option<&LegalHold>) -> Result<()>;
+    fn legal_hold(&self, fpr_primary: &Fingerprint) -> Option<LegalHold>;
+    fn legal_holds(&self) -> Vec<Fingerprint>;
+    fn delete_key(&self, fpr_primary: &Fingerprint) -> Result<()>;
+    fn write_email_ban(&self, entry: &str) -> Result<()>;
+    fn email_bans(&self) -> Vec<String>;
+    fn write_domain_allowlist(&self, domain: &str, allowlist: Option<&[Email]>) -> Result<()>;
+    fn domain_allowlist(&self, domain: &str) -> Option<Vec<Email>>;
+    fn write_retained_certifiers(
+        &self,
+        fpr_primary: &Fingerprint,
+        certifiers: &[Fingerprint],
+    ) -> Result<()>;
+    fn retained_certifiers(&self, fpr_primary: &Fingerprint) -> Vec<Fingerprint>;
+    fn primary_fprs_by_domain(&self, domain: &str) -> Vec<Fingerprint>;
+    fn write_audit_log(&self, entry: &str) -> Result<()>;
+    fn append_transparency_leaf(&self, leaf: &str) -> Result<()>;
+    /// Returns the transparency log, with everything appended so far.
+    fn transparency_log(&self) -> MutexGuard<transparency::Log>;
+
+    /// Checks the database for consistency, returning all
+    /// inconsistencies found.
+    ///
+    /// Note that this operation may take a long time.
+    fn consistency_findings(&self) -> Result<Vec<Finding>>;
+
+    /// Checks the database for consistency, failing on the first
+    /// inconsistency.
+    fn check_consistency(&self) -> Result<()> {
+        match self.consistency_findings()?.into_iter().next() {
+            Some(finding) => Err(anyhow!("{}", finding)),
+            None => Ok(()),
+        }
+    }
+
+    /// Which user IDs are published.
+    fn uid_policy(&self) -> UidPolicy {
+        UidPolicy::VerifiedOnly
+    }
+
+    /// Maximum number of user IDs in a published Cert, if any.
+    fn max_published_uids(&self) -> Option<usize> {
+        None
+    }
+
+    /// Maximum number of self-signatures per component in a published
+    /// Cert, if any.
+    fn max_self_signatures(&self) -> Option<usize> {
+        None
+    }
+
+    /// Largest permitted unhashed subpacket area in bytes, and what to
+    /// do with signatures that exceed it, if any.
+    fn unhashed_area_limit(&self) -> Option<(usize, UnhashedAreaAction)> {
+        None
+    }
+
+    /// Local part of the role address that a bare domain resolves to
+    /// when looking up keys by email address, e.g. `security`, if
+    /// any.
+    fn domain_role_localpart(&self) -> Option<&str> {
+        None
+    }
+
+    /// Resolves a bare domain to its role address, see
+    /// `domain_role_localpart`.
+    fn domain_role_email(&self, domain: &str) -> Option<Email> {
+        let localpart = self.domain_role_localpart()?;
+        let is_domain = !domain.is_empty()
+            && !domain
+                .chars()
+                .any(|c| c.is_whitespace() || c == '@' || c == '<' || c == '>');
+        if !is_domain {
+            return None;
+        }
+        Email::from_str(&format!("{}@{}", localpart, domain)).ok()
+    }
+
+    /// Caps the number of user IDs in a published Cert, keeping the
+    /// newest ones.  Returns the capped Cert, and the addresses that
+    /// are no longer included.
+    fn cap_published_uids(&self, tpk: Cert) -> (Cert, Vec<Email>) {
+        let max = match self.max_published_uids() {
+            Some(max) => max,
+            None => return (tpk, vec![]),
+        };
+        let (tpk, dropped) = tpk_cap_userids(tpk, max);
+        let kept = tpk_get_emails(&tpk);
+        let mut dropped_emails: Vec<Email> = dropped
+            .iter()
+            .flat_map(|uid| Email::try_from(uid).ok())
+            .filter(|email| !kept.contains(email))
+            .collect();
+        dropped_emails.sort();
+        dropped_emails.dedup();
+        (tpk, dropped_emails)
+    }
 
     /// Queries the database using Fingerprint, KeyID, or
     /// email-address.
@@ -185,35 +697,460 @@ pub trait Database: Sync + Send {
         }
     }
 
+    /// Explains why parts of the key are not published.  User IDs
+    /// that are not published are not reported, see `tpk_lint`.
+    ///
+    /// Returns `None` if the key is not in the database.
+    fn lint(&self, fpr_primary: &Fingerprint) -> Option<Vec<LintIssue>> {
+        let full_tpk = self
+            .by_fpr_full(fpr_primary)
+            .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok())?;
+        let published_tpk = self
+            .by_fpr(fpr_primary)
+            .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok());
+        Some(tpk_lint(&full_tpk, published_tpk.as_ref()))
+    }
+
+    /// Returns the published key stripped down to its current
+    /// components, see `tpk_minimize`.
+    fn by_fpr_minimal(&self, fpr_primary: &Fingerprint) -> Result<Option<String>> {
+        let armored = match self.by_fpr(fpr_primary) {
+            Some(armored) => armored,
+            None => return Ok(None),
+        };
+        let tpk = tpk_minimize(&Cert::from_bytes(armored.as_bytes())?)?;
+        Ok(Some(String::from_utf8(tpk_to_string(&tpk)?)?))
+    }
+
+    /// Returns the published addresses in the domain and its
+    /// subdomains, with the keys they are published on.
+    fn published_by_domain(&self, domain: &str) -> Result<Vec<(Email, Fingerprint)>> {
+        let domain = normalize_domain(domain)?;
+        let domains = [domain.clone()];
+
+        let mut published = Vec::new();
+        for fpr in self.primary_fprs_by_domain(&domain) {
+            let armored = match self.by_fpr(&fpr) {
+                Some(armored) => armored,
+                None => continue,
+            };
+            let tpk = Cert::from_bytes(armored.as_bytes())?;
+            for email in tpk_get_emails(&tpk) {
+                if email_ban_matches(&domains, &email) {
+                    published.push((email, fpr.clone()));
+                }
+            }
+        }
+        published.sort_by(|a, b| a.0.as_str().cmp(b.0.as_str()));
+        Ok(published)
+    }
+
+    fn is_banned(&self, fpr_primary: &Fingerprint) -> bool {
+        self.tombstone(fpr_primary).is_some()
+    }
+
+    /// Returns the public notice for a key removed by an operator, if
+    /// the query is for such a key and there is a notice.
+    fn removal_notice(&self, term: &Query) -> Option<String> {
+        match term {
+            Query::ByFingerprint(ref fpr) => self.tombstone(fpr)?.notice,
+            _ => None,
+        }
+    }
+
+    /// Bans a key.
+    ///
+    /// Unpublishes the key and deletes everything we know about it.
+    /// What remains is a tombstone with the reason for the ban, which
+    /// causes further uploads of the key to be rejected, and an
+    /// optional public notice that is shown to anyone looking it up.
+    ///
+    /// Fails with `HeldKey` if the key is under legal hold.
+    fn ban(&self, fpr_primary: &Fingerprint, reason: &str, notice: Option<&str>) -> Result<()> {
+        let _lock = self.lock()?;
+
+        if self.legal_hold(fpr_primary).is_some() {
+            return Err(HeldKey.into());
+        }
+
+        let tombstone = Tombstone {
+            reason: reason.to_owned(),
+            notice: notice.map(|notice| notice.to_owned()),
+        };
+        self.write_ban(fpr_primary, &tombstone)?;
+        self.write_audit_log(&format!("ban-fingerprint {} {:?}", fpr_primary, reason))?;
+
+        if let Some(published_tpk) = self
+            .by_primary_fpr(fpr_primary)
+            .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok())
+        {
+            for email in tpk_get_emails(&published_tpk) {
+                self.unlink_email(&email, fpr_primary)?;
+            }
+            for fpr in tpk_get_linkable_fprs(&published_tpk) {
+                self.unlink_fpr(&fpr, fpr_primary)?;
+            }
+        }
+        self.delete_key(fpr_primary)?;
+
+        self.update_write_log(fpr_primary);
+
+        Ok(())
+    }
+
+    /// Places a key under legal hold.
+    ///
+    /// Until the hold is released, the key can't be banned, retention
+    /// rules don't delete it, compaction doesn't rewrite it, and
+    /// neither its owner nor its domain can unpublish its addresses.
+    /// Keys we don't know yet can be held by their primary
+    /// fingerprint, which also keeps them in the quarantine.
+    fn place_legal_hold(&self, fpr_primary: &Fingerprint, reason: &str) -> Result<()> {
+        let _lock = self.lock()?;
+
+        let hold = LegalHold {
+            reason: reason.to_owned(),
+            since: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+        };
+        self.write_legal_hold(fpr_primary, Some(&hold))?;
+        self.write_audit_log(&format!("hold-fingerprint {} {:?}", fpr_primary, reason))?;
+
+        Ok(())
+    }
+
+    /// Releases a key from legal hold.
+    fn release_legal_hold(&self, fpr_primary: &Fingerprint) -> Result<()> {
+        let _lock = self.lock()?;
+
+        if self.legal_hold(fpr_primary).is_none() {
+            return Err(anyhow!("Key is not under legal hold!"));
+        }
+        self.write_legal_hold(fpr_primary, None)?;
+        self.write_audit_log(&format!("release-fingerprint {}", fpr_primary))?;
+
+        Ok(())
+    }
+
+    /// Imports a quarantined key after review, and removes it from
+    /// the quarantine.
+    fn approve_quarantined(&self, fpr: &Fingerprint) -> Result<ImportResult> {
+        let tpk = self
+            .by_fpr_quarantined(fpr)
+            .ok_or_else(|| anyhow!("Key not in quarantine!"))
+            .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()))?;
+
+        let import_result = self.merge(tpk)?;
+        self.delete_quarantined(fpr)?;
+        self.write_audit_log(&format!("approve-quarantined {}", fpr))?;
+
+        Ok(import_result)
+    }
+
+    /// Discards a quarantined key after review.
+    ///
+    /// Fails with `HeldKey` if the key is under legal hold.
+    fn reject_quarantined(&self, fpr: &Fingerprint) -> Result<()> {
+        let _lock = self.lock()?;
+
+        if self.by_fpr_quarantined(fpr).is_none() {
+            return Err(anyhow!("Key not in quarantine!"));
+        }
+        if self.legal_hold(fpr).is_some() {
+            return Err(HeldKey.into());
+        }
+        self.delete_quarantined(fpr)?;
+        self.write_audit_log(&format!("reject-quarantined {}", fpr))?;
+
+        Ok(())
+    }
+
+    fn is_email_banned(&self, email: &Email) -> bool {
+        email_ban_matches(&self.email_bans(), email)
+    }
+
+    /// Whether the allowlist of the address's domain, if any, lets
+    /// it be published.
+    ///
+    /// The allowlist of the closest domain applies, e.g. the one of
+    /// `example.org` also applies to `sub.example.org`, unless that
+    /// has its own.
+    fn is_email_allowed_by_domain(&self, email: &Email) -> bool {
+        let mut domain = email.as_str().rsplit('@').next().unwrap_or_default();
+        loop {
+            if let Some(allowlist) = self.domain_allowlist(domain) {
+                return allowlist.contains(email);
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) => domain = parent,
+                None => return true,
+            }
+        }
+    }
+
+    /// Restricts publication of addresses in a domain and its
+    /// subdomains to the given ones, or lifts the restriction.
+    ///
+    /// Published addresses that are no longer allowed are
+    /// unpublished, unless their key is under legal hold.
+    fn set_domain_allowlist(
+        &self,
+        domain: &str,
+        allowlist: Option<Vec<Email>>,
+    ) -> Result<AllowlistResult> {
+        let domain = normalize_domain(domain)?;
+        let domains = [domain.clone()];
+        if let Some(email) = allowlist
+            .iter()
+            .flatten()
+            .find(|email| !email_ban_matches(&domains, email))
+        {
+            return Err(anyhow!("{} is not in {}", email, domain));
+        }
+
+        let _lock = self.lock()?;
+
+        self.write_domain_allowlist(&domain, allowlist.as_deref())?;
+        self.write_audit_log(&format!("domain-allowlist {}", domain))?;
+
+        let published = self.published_by_domain(&domain)?;
+        let mut fprs: Vec<&Fingerprint> = published
+            .iter()
+            .map(|(_, fpr)| fpr)
+            .filter(|fpr| self.legal_hold(fpr).is_none())
+            .collect();
+        fprs.sort_by_key(|fpr| fpr.to_string());
+        fprs.dedup();
+        for fpr in fprs {
+            self.nolock_set_email_unpublished_filter(fpr, |uid| {
+                Email::try_from(uid)
+                    .map(|email| self.is_email_allowed_by_domain(&email))
+                    .unwrap_or(false)
+            })?;
+        }
+
+        let mut result = AllowlistResult::default();
+        for (email, fpr) in published {
+            if self.is_email_allowed_by_domain(&email) {
+                continue;
+            }
+            if self.legal_hold(&fpr).is_some() {
+                result.held.push(email);
+            } else {
+                result.unpublished.push(email);
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns the published keys of the certifiers whose
+    /// certifications are retained on the given key.
+    fn retained_certifier_keys(&self, fpr_primary: &Fingerprint) -> Vec<Cert> {
+        self.retained_certifiers(fpr_primary)
+            .into_iter()
+            .flat_map(|fpr| self.lookup(&Query::ByFingerprint(fpr)).ok().flatten())
+            .collect()
+    }
+
+    /// Publishes the certifications of the key's published user IDs
+    /// made by the given certifiers, replacing any previous choice.
+    ///
+    /// Third-party certifications are not published by default,
+    /// because anyone can add them to a key, and flood it.  The key's
+    /// owner may opt into specific certifiers, of which only the
+    /// newest certification per user ID is published, and only if it
+    /// can be verified using the certifier's published key.
+    ///
+    /// Returns the number of published certifications.
+    fn set_retained_certifiers(
+        &self,
+        fpr_primary: &Fingerprint,
+        certifiers: Vec<Fingerprint>,
+    ) -> Result<usize> {
+        let _lock = self.lock()?;
+
+        let full_tpk = self
+            .by_fpr_full(fpr_primary)
+            .ok_or_else(|| anyhow!("Key not in database!"))
+            .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()))?;
+        let published_emails = self
+            .by_fpr(fpr_primary)
+            .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok())
+            .map(|tpk| tpk_get_emails(&tpk))
+            .unwrap_or_default();
+
+        self.write_retained_certifiers(fpr_primary, &certifiers)?;
+        self.write_audit_log(&format!("retain-certifications {}", fpr_primary))?;
+
+        let published_tpk_new = tpk_filter_alive_emails(&full_tpk, &published_emails);
+        let (published_tpk_new, _) = self.cap_published_uids(published_tpk_new);
+        let published_tpk_clean = tpk_clean(
+            &published_tpk_new,
+            self.max_self_signatures(),
+            &self.retained_certifier_keys(fpr_primary),
+        )?;
+        let published_tpk_tmp = self.write_to_temp(&tpk_to_string(&published_tpk_clean)?)?;
+
+        self.move_tmp_to_published(published_tpk_tmp, fpr_primary)?;
+        self.regenerate_wkd(fpr_primary, &published_tpk_clean)?;
+
+        self.update_write_log(fpr_primary);
+
+        Ok(published_tpk_clean
+            .userids()
+            .map(|uid| uid.certifications().count())
+            .sum())
+    }
+
+    /// Bans an address, or all addresses in a domain and its
+    /// subdomains, from publication.
+    ///
+    /// Existing links are removed, unless their key is under legal
+    /// hold, and the addresses can't be published again.
+    fn ban_email(&self, entry: &str, reason: &str) -> Result<EmailBanResult> {
+        let entry = normalize_email_ban(entry)?;
+
+        let _lock = self.lock()?;
+
+        self.write_email_ban(&entry)?;
+        self.write_audit_log(&format!("ban-email {} {:?}", entry, reason))?;
+
+        let published: Vec<(Email, Fingerprint)> = match Email::from_str(&entry) {
+            Ok(email) => self
+                .lookup_primary_fingerprint(&Query::ByEmail(email.clone()))
+                .map(|fpr| (email, fpr))
+                .into_iter()
+                .collect(),
+            Err(_) => self.published_by_domain(&entry)?,
+        };
+        let mut fprs: Vec<&Fingerprint> = published
+            .iter()
+            .map(|(_, fpr)| fpr)
+            .filter(|fpr| self.legal_hold(fpr).is_none())
+            .collect();
+        fprs.sort_by_key(|fpr| fpr.to_string());
+        fprs.dedup();
+        let bans = self.email_bans();
+        for fpr in fprs {
+            self.nolock_set_email_unpublished_filter(fpr, |uid| {
+                Email::try_from(uid)
+                    .map(|email| !email_ban_matches(&bans, &email))
+                    .unwrap_or(false)
+            })?;
+        }
+
+        let mut result = EmailBanResult::default();
+        for (email, fpr) in published {
+            if self.legal_hold(&fpr).is_some() {
+                result.held.push(email);
+            } else {
+                result.unpublished.push(email);
+            }
+        }
+        Ok(result)
+    }
+
     /// Complex operation that updates a Cert in the database.
     ///
+    /// 0. Strip oversized unhashed subpacket areas from the new Cert,
+    ///    or refuse it, and drop subkeys without valid binding signature
     /// 1. Merge new Cert with old, full Cert
+    ///    - duplicate packets are already gone, because parsing and
+    ///      merging canonicalize the Certs
     ///    - if old full Cert == new full Cert, stop
     /// 2. Prepare new published Cert
-    ///    - retrieve UserIDs from old published Cert
+    ///    - retrieve UserIDs to publish according to the UID policy
     ///    - create new Cert from full Cert by keeping only published UserIDs
+    ///    - drop the oldest UserIDs if there are more than allowed
     /// 3. Write full and published Cert to temporary files
     /// 4. Check for fingerprint and long key id collisions for published Cert
     ///    - abort if any problems come up!
     /// 5. Move full and published temporary Cert to their location
     /// 6. Update all symlinks
     fn merge(&self, new_tpk: Cert) -> Result<ImportResult> {
+        self.merge_with_diff(new_tpk, None)
+            .map(|(import_result, _)| import_result)
+    }
+
+    /// Like `merge`, but also returns what the merge changed.
+    ///
+    /// `uploader` is recorded with the key if it ends up in
+    /// quarantine.
+    fn merge_with_diff(
+        &self,
+        new_tpk: Cert,
+        uploader: Option<&str>,
+    ) -> Result<(ImportResult, MergeDiff)> {
         let fpr_primary = Fingerprint::try_from(new_tpk.primary_key().fingerprint())?;
 
-        let _lock = self.lock()?;
+        let mut warnings = vec![];
+        let new_tpk = match self.unhashed_area_limit() {
+            Some((max_size, action)) => {
+                let (new_tpk, stripped) = tpk_strip_unhashed_areas(new_tpk, max_size)?;
+                if stripped > 0 {
+                    if action == UnhashedAreaAction::Reject {
+                        return Err(OversizedUnhashedArea.into());
+                    }
+                    warnings.push(ImportWarning::StrippedUnhashedAreas(stripped));
+                }
+                new_tpk
+            }
+            None => new_tpk,
+        };
+        let (new_tpk, unbound) = tpk_strip_unbound_subkeys(new_tpk);
+        if unbound > 0 {
+            warnings.push(ImportWarning::DroppedUnboundSubkeys(unbound));
+        }
+
+        for _ in 0..MERGE_ATTEMPTS {
+            let result = self.try_merge_with_diff(
+                &fpr_primary,
+                new_tpk.clone(),
+                warnings.clone(),
+                uploader,
+            )?;
+            if let Some(result) = result {
+                return Ok(result);
+            }
+        }
+        Err(anyhow!(
+            "Key {} is updated too often, try again later",
+            fpr_primary
+        ))
+    }
+
+    /// Merges the key without holding the lock, and persists the
+    /// result only if the stored key didn't change in the meantime.
+    ///
+    /// Returns `None` if it did, so that the merge can be retried.
+    fn try_merge_with_diff(
+        &self,
+        fpr_primary: &Fingerprint,
+        new_tpk: Cert,
+        warnings: Vec<ImportWarning>,
+        uploader: Option<&str>,
+    ) -> Result<Option<(ImportResult, MergeDiff)>> {
+        if self.is_banned(fpr_primary) {
+            return Err(BannedKey.into());
+        }
+
+        let full_bytes_old = self.by_fpr_full(fpr_primary);
+        let published_bytes_old = self.by_fpr(fpr_primary);
 
         let known_uids: Vec<UserID> = new_tpk
             .userids()
             .map(|binding| binding.userid().clone())
             .collect();
 
-        let full_tpk_old = self
-            .by_fpr_full(&fpr_primary)
+        let full_tpk_old = full_bytes_old
+            .as_ref()
             .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok());
         let is_update = full_tpk_old.is_some();
-        let (full_tpk_new, full_tpk_unchanged) = if let Some(full_tpk_old) = full_tpk_old {
+        let (full_tpk_new, full_tpk_unchanged) = if let Some(ref full_tpk_old) = full_tpk_old {
             let full_tpk_new = new_tpk.merge_public(full_tpk_old.clone())?;
-            let full_tpk_unchanged = full_tpk_new == full_tpk_old;
+            let full_tpk_unchanged = full_tpk_new == *full_tpk_old;
             (full_tpk_new, full_tpk_unchanged)
         } else {
             (new_tpk, false)
@@ -226,16 +1163,25 @@ pub trait Database: Sync + Send {
             || full_tpk_new.userids().next().is_some();
         if !is_ok {
             // self.write_to_quarantine(&fpr_primary, &tpk_to_string(&full_tpk_new)?)?;
-            return Err(anyhow!("Not a well-formed key!"));
+            return Err(MalformedKey.into());
         }
 
-        let published_tpk_old = self
-            .by_fpr(&fpr_primary)
+        let published_tpk_old = published_bytes_old
+            .as_ref()
             .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()).ok());
-        let published_emails = published_tpk_old
+        let published_emails_old = published_tpk_old
             .as_ref()
             .map(tpk_get_emails)
             .unwrap_or_default();
+        let mut published_emails = match self.uid_policy() {
+            UidPolicy::VerifiedOnly => published_emails_old.clone(),
+            UidPolicy::PublishAll => tpk_get_emails(&full_tpk_new),
+            UidPolicy::NoUids => vec![],
+        };
+        let email_bans = self.email_bans();
+        published_emails.retain(|email| {
+            !email_ban_matches(&email_bans, email) && self.is_email_allowed_by_domain(email)
+        });
 
         let unparsed_uids = full_tpk_new
             .userids()
@@ -243,6 +1189,13 @@ pub trait Database: Sync + Send {
             .filter(|x| *x)
             .count();
 
+        let published_tpk_new = if is_revoked {
+            tpk_filter_alive_emails(&full_tpk_new, &[])
+        } else {
+            tpk_filter_alive_emails(&full_tpk_new, &published_emails)
+        };
+        let (published_tpk_new, dropped_uids) = self.cap_published_uids(published_tpk_new);
+
         let mut email_status: Vec<_> = full_tpk_new
             .userids()
             .map(|binding| {
@@ -259,7 +1212,10 @@ pub trait Database: Sync + Send {
             .flat_map(|(binding, email)| {
                 if is_status_revoked(binding.revocation_status(&POLICY, None)) {
                     Some((email, EmailAddressStatus::Revoked))
-                } else if !is_revoked && published_emails.contains(&email) {
+                } else if !is_revoked
+                    && published_emails.contains(&email)
+                    && !dropped_uids.contains(&email)
+                {
                     Some((email, EmailAddressStatus::Published))
                 } else {
                     Some((email, EmailAddressStatus::NotPublished))
@@ -273,20 +1229,24 @@ pub trait Database: Sync + Send {
 
         // Abort if no changes were made
         if full_tpk_unchanged {
-            return Ok(ImportResult::Unchanged(TpkStatus {
+            let diff = merge_diff(
+                full_tpk_old.as_ref(),
+                &full_tpk_new,
+                published_tpk_old.as_ref(),
+                &known_uids,
+                false,
+            );
+            let tpk_status = TpkStatus {
                 is_revoked,
                 email_status,
                 unparsed_uids,
-            }));
+                dropped_uids,
+                warnings,
+            };
+            return Ok(Some((ImportResult::Unchanged(tpk_status), diff)));
         }
 
-        let published_tpk_new = if is_revoked {
-            tpk_filter_alive_emails(&full_tpk_new, &[])
-        } else {
-            tpk_filter_alive_emails(&full_tpk_new, &published_emails)
-        };
-
-        let newly_revoked_emails: Vec<&Email> = published_emails
+        let newly_unpublished_emails: Vec<&Email> = published_emails_old
             .iter()
             .filter(|email| {
                 let has_unrevoked_userid = published_tpk_new
@@ -299,67 +1259,110 @@ pub trait Database: Sync + Send {
                 !has_unrevoked_userid
             })
             .collect();
+        let newly_published_emails: Vec<Email> = tpk_get_emails(&published_tpk_new)
+            .into_iter()
+            .filter(|email| !published_emails_old.contains(email))
+            .collect();
+
+        let full_tpk_tmp = self.write_to_temp(&tpk_to_string(&full_tpk_new)?)?;
+        let published_tpk_clean = tpk_clean(
+            &published_tpk_new,
+            self.max_self_signatures(),
+            &self.retained_certifier_keys(fpr_primary),
+        )?;
+        let published_tpk_tmp = self.write_to_temp(&tpk_to_string(&published_tpk_clean)?)?;
+
+        let _lock = self.lock()?;
+
+        if self.is_banned(fpr_primary) {
+            return Err(BannedKey.into());
+        }
+        // Someone else updated the key while we were merging.
+        if self.by_fpr_full(fpr_primary) != full_bytes_old
+            || self.by_fpr(fpr_primary) != published_bytes_old
+        {
+            return Ok(None);
+        }
 
         let fingerprints = tpk_get_linkable_fprs(&published_tpk_new);
 
         let fpr_checks = fingerprints
             .iter()
-            .map(|fpr| self.check_link_fpr(fpr, &fpr_primary))
+            .map(|fpr| self.check_link_fpr(fpr, fpr_primary))
             .collect::<Vec<_>>()
             .into_iter()
             .collect::<Result<Vec<_>>>();
 
         if fpr_checks.is_err() {
-            self.write_to_quarantine(&fpr_primary, &tpk_to_string(&full_tpk_new)?)?;
+            self.write_to_quarantine(fpr_primary, &tpk_to_string(&full_tpk_new)?, uploader)?;
         }
         let fpr_checks = fpr_checks?;
 
         let fpr_not_linked = fpr_checks.into_iter().flatten();
 
-        let full_tpk_tmp = self.write_to_temp(&tpk_to_string(&full_tpk_new)?)?;
-        let published_tpk_clean = tpk_clean(&published_tpk_new)?;
-        let published_tpk_tmp = self.write_to_temp(&tpk_to_string(&published_tpk_clean)?)?;
-
         // these are very unlikely to fail. but if it happens,
         // database consistency might be compromised!
-        self.move_tmp_to_full(full_tpk_tmp, &fpr_primary)?;
-        self.move_tmp_to_published(published_tpk_tmp, &fpr_primary)?;
-        self.regenerate_wkd(&fpr_primary, &published_tpk_clean)?;
+        self.move_tmp_to_full(full_tpk_tmp, fpr_primary)?;
+        self.move_tmp_to_published(published_tpk_tmp, fpr_primary)?;
+        self.regenerate_wkd(fpr_primary, &published_tpk_clean)?;
 
         let published_tpk_changed = published_tpk_old
-            .map(|tpk| tpk != published_tpk_clean)
+            .as_ref()
+            .map(|tpk| *tpk != published_tpk_clean)
             .unwrap_or(true);
         if published_tpk_changed {
-            self.update_write_log(&fpr_primary);
+            self.update_write_log(fpr_primary);
         }
 
         for fpr in fpr_not_linked {
-            if let Err(e) = self.link_fpr(&fpr, &fpr_primary) {
-                info!("Error ensuring symlink! {} {} {:?}", &fpr, &fpr_primary, e);
+            if let Err(e) = self.link_fpr(&fpr, fpr_primary) {
+                info!("Error ensuring symlink! {} {} {:?}", &fpr, fpr_primary, e);
             }
         }
 
-        for revoked_email in newly_revoked_emails {
-            if let Err(e) = self.unlink_email(revoked_email, &fpr_primary) {
+        for unpublished_email in newly_unpublished_emails {
+            if let Err(e) = self.unlink_email(unpublished_email, fpr_primary) {
                 info!(
                     "Error ensuring symlink! {} {} {:?}",
-                    &fpr_primary, &revoked_email, e
+                    fpr_primary, &unpublished_email, e
+                );
+            }
+        }
+
+        for published_email in newly_published_emails {
+            self.nolock_unlink_email_if_other(fpr_primary, &published_email)?;
+            if let Err(e) = self.link_email(&published_email, fpr_primary) {
+                info!(
+                    "Error ensuring email symlink! {} -> {} {:?}",
+                    &published_email, fpr_primary, e
                 );
             }
         }
 
+        let diff = merge_diff(
+            full_tpk_old.as_ref(),
+            &full_tpk_new,
+            Some(&published_tpk_clean),
+            &known_uids,
+            published_tpk_changed,
+        );
+        info!(
+            "Merged {}: {}",
+            fpr_primary,
+            serde_json::to_string(&diff).unwrap_or_default()
+        );
+
+        let tpk_status = TpkStatus {
+            is_revoked,
+            email_status,
+            unparsed_uids,
+            dropped_uids,
+            warnings,
+        };
         if is_update {
-            Ok(ImportResult::Updated(TpkStatus {
-                is_revoked,
-                email_status,
-                unparsed_uids,
-            }))
+            Ok(Some((ImportResult::Updated(tpk_status), diff)))
         } else {
-            Ok(ImportResult::New(TpkStatus {
-                is_revoked,
-                email_status,
-                unparsed_uids,
-            }))
+            Ok(Some((ImportResult::New(tpk_status), diff)))
         }
     }
 
@@ -369,6 +1372,113 @@ pub trait Database: Sync + Send {
         if let Err(e) = self.write_log_append(&log_name, fpr_primary) {
             error!("Error writing to log! {} {} {}", &log_name, &fpr_primary, e);
         }
+
+        let key_hash = self
+            .by_primary_fpr(fpr_primary)
+            .map(|armored| transparency::key_hash(armored.as_bytes()))
+            .unwrap_or_else(|| "-".to_owned());
+        let leaf = format!("{} {} {}", Utc::now().timestamp(), fpr_primary, key_hash);
+        if let Err(e) = self.append_transparency_leaf(&leaf) {
+            error!("Error writing to transparency log! {} {}", &leaf, e);
+        }
+    }
+
+    /// Returns the current head of the transparency log.
+    fn transparency_tree_head(&self) -> TreeHead {
+        let log = self.transparency_log();
+        TreeHead {
+            tree_size: log.len(),
+            root_hash: hex::encode(log.root_hash(log.len())),
+            timestamp: Utc::now().timestamp(),
+        }
+    }
+
+    /// Proves that the latest publication event for the given key is
+    /// included in the transparency log of the given size, or of the
+    /// current size.
+    fn transparency_inclusion_proof(
+        &self,
+        fpr_primary: &Fingerprint,
+        tree_size: Option<usize>,
+    ) -> Option<InclusionProof> {
+        let fpr = fpr_primary.to_string();
+        self.transparency_inclusion_proof_for(tree_size, |log, tree_size| {
+            log.indices_of(&fpr, tree_size).last().copied()
+        })
+    }
+
+    /// Proves that the publication event for the given key with the
+    /// given leaf hash, in hex, is included in the transparency log of
+    /// the given size, or of the current size.
+    fn transparency_inclusion_proof_by_hash(
+        &self,
+        fpr_primary: &Fingerprint,
+        leaf_hash: &str,
+        tree_size: Option<usize>,
+    ) -> Option<InclusionProof> {
+        let fpr = fpr_primary.to_string();
+        let leaf_hash = hex::decode(leaf_hash).ok()?;
+        let leaf_hash = transparency::Hash::try_from(leaf_hash.as_slice()).ok()?;
+        self.transparency_inclusion_proof_for(tree_size, |log, tree_size| {
+            log.indices_of_hash(&leaf_hash, tree_size)
+                .iter()
+                .rev()
+                .copied()
+                .find(|&index| log.leaves()[index].split(' ').nth(1) == Some(fpr.as_str()))
+        })
+    }
+
+    /// Proves inclusion of the leaf that `find` returns the index of,
+    /// given the log and the tree size.
+    fn transparency_inclusion_proof_for(
+        &self,
+        tree_size: Option<usize>,
+        find: impl FnOnce(&transparency::Log, usize) -> Option<usize>,
+    ) -> Option<InclusionProof> {
+        let log = self.transparency_log();
+        let tree_size = tree_size.unwrap_or_else(|| log.len());
+        if tree_size > log.len() {
+            return None;
+        }
+
+        let leaf_index = find(&log, tree_size)?;
+        let audit_path = log
+            .inclusion_proof(leaf_index, tree_size)
+            .iter()
+            .map(hex::encode)
+            .collect();
+
+        Some(InclusionProof {
+            leaf: log.leaves()[leaf_index].clone(),
+            leaf_index,
+            tree_size,
+            audit_path,
+        })
+    }
+
+    /// Proves that the transparency log of size `first` is a prefix of
+    /// the log of size `second`.  Returns `None` unless `first` is at
+    /// most `second`, and `second` at most the current size.
+    fn transparency_consistency_proof(
+        &self,
+        first: usize,
+        second: usize,
+    ) -> Option<ConsistencyProof> {
+        let log = self.transparency_log();
+        if first > second || second > log.len() {
+            return None;
+        }
+        let consistency = log
+            .consistency_proof(first, second)
+            .iter()
+            .map(hex::encode)
+            .collect();
+
+        Some(ConsistencyProof {
+            first,
+            second,
+            consistency,
+        })
     }
 
     fn get_current_log_filename(&self) -> String {
@@ -431,6 +1541,8 @@ pub trait Database: Sync + Send {
             is_revoked,
             email_status,
             unparsed_uids,
+            dropped_uids: vec![],
+            warnings: vec![],
         })
     }
 
@@ -443,13 +1555,26 @@ pub trait Database: Sync + Send {
     /// 3. Prepare new published Cert
     ///    - retrieve UserIDs from old published Cert
     ///    - create new Cert from full Cert by keeping only published UserIDs
+    ///    - drop the oldest UserIDs if there are more than allowed
     /// 4. Check for fingerprint and long key id collisions for published Cert
     ///    - abort if any problems come up!
     /// 5. Move full and published temporary Cert to their location
     /// 6. Update all symlinks
     fn set_email_published(&self, fpr_primary: &Fingerprint, email_new: &Email) -> Result<()> {
+        if self.uid_policy() == UidPolicy::NoUids {
+            return Err(anyhow!("Publishing user IDs is disabled"));
+        }
+
         let _lock = self.lock()?;
 
+        // Bans and allowlists are changed under the lock as well.
+        if self.is_email_banned(email_new) {
+            return Err(anyhow!("Address is banned from publication"));
+        }
+        if !self.is_email_allowed_by_domain(email_new) {
+            return Err(anyhow!("Address is not on its domain's allowlist"));
+        }
+
         self.nolock_unlink_email_if_other(fpr_primary, email_new)?;
 
         let full_tpk = self
@@ -482,6 +1607,10 @@ pub trait Database: Sync + Send {
         published_emails.push(email_new.clone());
 
         let published_tpk_new = tpk_filter_alive_emails(&full_tpk, &published_emails);
+        let (published_tpk_new, dropped_emails) = self.cap_published_uids(published_tpk_new);
+        if dropped_emails.contains(email_new) {
+            return Err(anyhow!("Too many published user IDs!"));
+        }
 
         if !published_tpk_new
             .userids()
@@ -492,7 +1621,11 @@ pub trait Database: Sync + Send {
             return Err(anyhow!("Requested UserID not found!"));
         }
 
-        let published_tpk_clean = tpk_clean(&published_tpk_new)?;
+        let published_tpk_clean = tpk_clean(
+            &published_tpk_new,
+            self.max_self_signatures(),
+            &self.retained_certifier_keys(fpr_primary),
+        )?;
         let published_tpk_tmp = self.write_to_temp(&tpk_to_string(&published_tpk_clean)?)?;
 
         self.move_tmp_to_published(published_tpk_tmp, fpr_primary)?;
@@ -507,6 +1640,15 @@ pub trait Database: Sync + Send {
             );
         }
 
+        for dropped_email in dropped_emails {
+            if let Err(e) = self.unlink_email(&dropped_email, fpr_primary) {
+                info!(
+                    "Error ensuring symlink! {} {} {:?}",
+                    &fpr_primary, &dropped_email, e
+                );
+            }
+        }
+
         Ok(())
     }
 
@@ -579,7 +1721,11 @@ pub trait Database: Sync + Send {
             .iter()
             .filter(|email| !published_emails_new.contains(email));
 
-        let published_tpk_clean = tpk_clean(&published_tpk_new)?;
+        let published_tpk_clean = tpk_clean(
+            &published_tpk_new,
+            self.max_self_signatures(),
+            &self.retained_certifier_keys(fpr_primary),
+        )?;
         let published_tpk_tmp = self.write_to_temp(&tpk_to_string(&published_tpk_clean)?)?;
 
         self.move_tmp_to_published(published_tpk_tmp, fpr_primary)?;
@@ -611,6 +1757,31 @@ pub trait Database: Sync + Send {
         self.set_email_unpublished_filter(fpr_primary, |_| false)
     }
 
+    /// Unpublishes all addresses of the key if it expired more than
+    /// `grace` ago, returning whether it did.
+    fn unpublish_if_expired(&self, fpr_primary: &Fingerprint, grace: Duration) -> Result<bool> {
+        let _lock = self.lock()?;
+
+        let tpk = match self.by_primary_fpr(fpr_primary) {
+            Some(armored) => Cert::from_bytes(armored.as_bytes())?,
+            None => return Ok(false),
+        };
+        if tpk.userids().next().is_none() {
+            return Ok(false);
+        }
+        let expiration = tpk
+            .with_policy(&POLICY, None)?
+            .primary_key()
+            .key_expiration_time();
+        match expiration {
+            Some(expiration) if expiration + grace < SystemTime::now() => {
+                self.nolock_set_email_unpublished_filter(fpr_primary, |_| false)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
     fn regenerate_links(&self, fpr_primary: &Fingerprint) -> Result<RegenerateResult> {
         let tpk = self
             .by_primary_fpr(fpr_primary)
@@ -656,6 +1827,56 @@ pub trait Database: Sync + Send {
         }
     }
 
+    /// Rewrites the published variant of the key, dropping what we
+    /// wouldn't publish today, like duplicate packets, or
+    /// self-signatures beyond `max_self_signatures`.
+    ///
+    /// Published keys written by older versions accumulate such
+    /// cruft.  The key is written back in the canonical serialization,
+    /// and left alone if that is what we already have, or if it is
+    /// under legal hold.
+    fn compact(&self, fpr_primary: &Fingerprint) -> Result<CompactResult> {
+        let _lock = self.lock()?;
+
+        let published = self
+            .by_fpr(fpr_primary)
+            .ok_or_else(|| anyhow!("Key not in database!"))?;
+        if self.legal_hold(fpr_primary).is_some() {
+            return Ok(CompactResult {
+                changed: false,
+                held: true,
+                size_before: published.len(),
+                size_after: published.len(),
+            });
+        }
+        let tpk = Cert::from_bytes(published.as_bytes())?;
+        let tpk_clean = tpk_clean(
+            &tpk,
+            self.max_self_signatures(),
+            &self.retained_certifier_keys(fpr_primary),
+        )?;
+        let compacted = tpk_to_string(&tpk_clean)?;
+
+        let changed = compacted != published.as_bytes();
+        let result = CompactResult {
+            changed,
+            held: false,
+            size_before: published.len(),
+            size_after: compacted.len(),
+        };
+        if !changed {
+            return Ok(result);
+        }
+
+        let published_tpk_tmp = self.write_to_temp(&compacted)?;
+        self.move_tmp_to_published(published_tpk_tmp, fpr_primary)?;
+        self.regenerate_wkd(fpr_primary, &tpk_clean)?;
+
+        self.update_write_log(fpr_primary);
+
+        Ok(result)
+    }
+
     fn regenerate_wkd(&self, fpr_primary: &Fingerprint, published_tpk: &Cert) -> Result<()> {
         let published_wkd_tpk_tmp = if published_tpk.userids().next().is_some() {
             Some(self.write_to_temp(&published_tpk.export_to_vec()?)?)
@@ -668,6 +1889,98 @@ pub trait Database: Sync + Send {
     }
 }
 
+fn tpk_get_uid_strings(cert: &Cert) -> Vec<String> {
+    cert.userids()
+        .map(|binding| String::from_utf8_lossy(binding.userid().value()).into_owned())
+        .collect()
+}
+
+fn tpk_get_subkey_fprs(cert: &Cert) -> Vec<Fingerprint> {
+    cert.keys()
+        .subkeys()
+        .flat_map(|key| Fingerprint::try_from(key.fingerprint()))
+        .collect()
+}
+
+/// Compares the full Cert before and after a merge, and the full Cert
+/// with the published one.
+///
+/// Only user IDs the uploader already knows, i.e. those in the
+/// uploaded Cert, are listed, so that re-uploading a key doesn't
+/// disclose the unverified user IDs others uploaded.
+fn merge_diff(
+    full_tpk_old: Option<&Cert>,
+    full_tpk_new: &Cert,
+    published_tpk: Option<&Cert>,
+    known_uids: &[UserID],
+    published_changed: bool,
+) -> MergeDiff {
+    let uids_old = full_tpk_old.map(tpk_get_uid_strings).unwrap_or_default();
+    let subkeys_old = full_tpk_old.map(tpk_get_subkey_fprs).unwrap_or_default();
+    let sigs_old = full_tpk_old.map(tpk_signatures).unwrap_or_default();
+    let uids_published = published_tpk.map(tpk_get_uid_strings).unwrap_or_default();
+    let sigs_published = published_tpk.map(tpk_signatures).unwrap_or_default();
+
+    let uids_new: Vec<String> = full_tpk_new
+        .userids()
+        .filter(|binding| known_uids.contains(binding.userid()))
+        .map(|binding| String::from_utf8_lossy(binding.userid().value()).into_owned())
+        .collect();
+    let sigs_new = tpk_signatures(full_tpk_new);
+
+    MergeDiff {
+        new_subkeys: tpk_get_subkey_fprs(full_tpk_new)
+            .into_iter()
+            .filter(|fpr| !subkeys_old.contains(fpr))
+            .collect(),
+        new_uids: uids_new
+            .iter()
+            .filter(|uid| !uids_old.contains(uid))
+            .cloned()
+            .collect(),
+        new_signatures: sigs_new.difference(&sigs_old).count(),
+        filtered_uids: uids_new
+            .into_iter()
+            .filter(|uid| !uids_published.contains(uid))
+            .collect(),
+        filtered_signatures: sigs_new.difference(&sigs_published).count(),
+        published_changed,
+    }
+}
+
+/// Normalizes an email ban, which is either an address or a domain.
+fn normalize_email_ban(entry: &str) -> Result<String> {
+    let entry = entry.trim();
+    if entry.contains('@') {
+        return Ok(Email::from_str(entry)?.as_str().to_owned());
+    }
+    normalize_domain(entry)
+}
+
+/// Normalizes a domain the way the domain part of addresses is
+/// normalized, e.g. to ASCII.
+pub fn normalize_domain(domain: &str) -> Result<String> {
+    let domain = domain.trim();
+    if domain.is_empty()
+        || domain
+            .chars()
+            .any(|c| c.is_whitespace() || c == '@' || c == '<' || c == '>')
+    {
+        return Err(anyhow!("malformed domain: {:?}", domain));
+    }
+    let email = Email::from_str(&format!("domain@{}", domain))?;
+    Ok(email.as_str()["domain@".len()..].to_owned())
+}
+
+fn email_ban_matches(bans: &[String], email: &Email) -> bool {
+    let domain = email.as_str().rsplit('@').next().unwrap_or_default();
+    bans.iter().any(|ban| {
+        ban == email.as_str()
+            || ban == domain
+            || (domain.ends_with(ban.as_str()) && domain[..domain.len() - ban.len()].ends_with('.'))
+    })
+}
+
 fn tpk_get_emails(cert: &Cert) -> Vec<Email> {
     cert.userids()
         .map(|binding| Email::try_from(binding.userid()))
@@ -684,6 +1997,7 @@ pub fn tpk_get_linkable_fprs(tpk: &Cert) -> Vec<Fingerprint> {
             Fingerprint::try_from(bundle.key().fingerprint()).map(|fpr| {
                 (
                     fpr,
+                    bundle.self_signatures().count() > 0,
                     bundle
                         .binding_signature(&POLICY, None)
                         .ok()
@@ -691,11 +2005,11 @@ pub fn tpk_get_linkable_fprs(tpk: &Cert) -> Vec<Fingerprint> {
                 )
             })
         })
-        .filter(|(fpr, flags)| {
+        .filter(|(fpr, is_bound, flags)| {
             fpr == fpr_primary
-                || flags.is_none()
-                || !(signing_capable & flags.as_ref().unwrap()).is_empty()
+                || *is_bound
+                    && (flags.is_none() || !(signing_capable & flags.as_ref().unwrap()).is_empty())
         })
-        .map(|(fpr, _)| fpr)
+        .map(|(fpr, _, _)| fpr)
         .collect()
 }
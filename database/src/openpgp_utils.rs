@@ -1,12 +1,17 @@
 use openpgp::Result;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 
 use openpgp::{
-    cert::prelude::*, policy::StandardPolicy, serialize::SerializeInto as _,
-    types::RevocationStatus, Cert,
+    cert::prelude::*,
+    packet::{key, Key, Signature, UserID},
+    policy::StandardPolicy,
+    serialize::{MarshalInto, SerializeInto as _},
+    types::{HashAlgorithm, RevocationStatus},
+    Cert, Packet,
 };
 
-use Email;
+use {Email, LintIssue, LintKind};
 
 pub const POLICY: StandardPolicy = StandardPolicy::new();
 
@@ -22,7 +27,63 @@ pub fn tpk_to_string(tpk: &Cert) -> Result<Vec<u8>> {
     tpk.armored().export_to_vec()
 }
 
-pub fn tpk_clean(tpk: &Cert) -> Result<Cert> {
+/// Returns at most `max` self-signatures, preferring ones that are
+/// alive, and then the newest ones.
+fn newest_self_signatures<'a>(
+    sigs: impl IntoIterator<Item = &'a Signature>,
+    max: Option<usize>,
+) -> Vec<&'a Signature> {
+    let mut sigs: Vec<&Signature> = sigs.into_iter().collect();
+    if let Some(max) = max {
+        sigs.sort_by_key(|s| {
+            (
+                s.signature_alive(None, None).is_ok(),
+                s.signature_creation_time(),
+            )
+        });
+        sigs.reverse();
+        sigs.truncate(max);
+    }
+    sigs
+}
+
+/// Returns the newest certification of the user ID by each of the
+/// certifiers, if it can be verified using their keys.
+fn retained_certifications(
+    uidb: &UserIDAmalgamation,
+    primary: &Key<key::PublicParts, key::PrimaryRole>,
+    certifiers: &[Cert],
+) -> Vec<Signature> {
+    let mut retained = Vec::new();
+    for certifier in certifiers {
+        let newest = uidb
+            .certifications()
+            .filter(|s| {
+                // Checking the issuers first avoids verifying
+                // everyone else's certifications.
+                let issuers = s.get_issuers();
+                certifier.keys().any(|ka| {
+                    let handle = ka.key().key_handle();
+                    issuers.iter().any(|issuer| issuer.aliases(&handle))
+                        && s.clone()
+                            .verify_userid_binding(ka.key(), primary, uidb.userid())
+                            .is_ok()
+                })
+            })
+            .max_by_key(|s| s.signature_creation_time());
+        if let Some(s) = newest {
+            retained.push(s.clone());
+        }
+    }
+    retained
+}
+
+/// Strips the Cert down to what we publish.
+///
+/// If `max_self_sigs` is given, at most that many self-signatures are
+/// kept per component.  Revocations are always kept, and so are the
+/// certifications by the given certifiers the key's owner opted into.
+pub fn tpk_clean(tpk: &Cert, max_self_sigs: Option<usize>, certifiers: &[Cert]) -> Result<Cert> {
     // Iterate over the Cert, pushing packets we want to merge
     // into the accumulator.
     let mut acc = Vec::new();
@@ -30,7 +91,7 @@ pub fn tpk_clean(tpk: &Cert) -> Result<Cert> {
     // The primary key and related signatures.
     let pk_bundle = tpk.primary_key().bundle();
     acc.push(pk_bundle.key().clone().into());
-    for s in pk_bundle.self_signatures() {
+    for s in newest_self_signatures(pk_bundle.self_signatures(), max_self_sigs) {
         acc.push(s.clone().into())
     }
     for s in pk_bundle.self_revocations() {
@@ -43,7 +104,7 @@ pub fn tpk_clean(tpk: &Cert) -> Result<Cert> {
     // The subkeys and related signatures.
     for skb in tpk.keys().subkeys() {
         acc.push(skb.key().clone().into());
-        for s in skb.self_signatures() {
+        for s in newest_self_signatures(skb.self_signatures(), max_self_sigs) {
             acc.push(s.clone().into())
         }
         for s in skb.self_revocations() {
@@ -57,7 +118,7 @@ pub fn tpk_clean(tpk: &Cert) -> Result<Cert> {
     // The UserIDs.
     for uidb in tpk.userids() {
         acc.push(uidb.userid().clone().into());
-        for s in uidb.self_signatures() {
+        for s in newest_self_signatures(uidb.self_signatures(), max_self_sigs) {
             acc.push(s.clone().into())
         }
         for s in uidb.self_revocations() {
@@ -66,6 +127,9 @@ pub fn tpk_clean(tpk: &Cert) -> Result<Cert> {
         for s in uidb.other_revocations() {
             acc.push(s.clone().into())
         }
+        for s in retained_certifications(&uidb, tpk.primary_key().key(), certifiers) {
+            acc.push(s.into())
+        }
 
         // Reasoning about the currently attested certifications
         // requires a policy.
@@ -82,6 +146,69 @@ pub fn tpk_clean(tpk: &Cert) -> Result<Cert> {
     Cert::from_packets(acc.into_iter())
 }
 
+/// Strips the Cert down to what clients need to use it right now.
+///
+/// This keeps the primary key, the user ids that aren't revoked, and
+/// the live signing and encryption subkeys, each with its newest
+/// self-signature.  Revocations of the primary key are kept, so that
+/// clients learn about them.
+pub fn tpk_minimize(tpk: &Cert) -> Result<Cert> {
+    // Without a valid primary key, there is nothing current to keep.
+    tpk.with_policy(&POLICY, None)?;
+    let mut acc = Vec::new();
+
+    let pk_bundle = tpk.primary_key().bundle();
+    acc.push(pk_bundle.key().clone().into());
+    for s in newest_self_signatures(pk_bundle.self_signatures(), Some(1)) {
+        acc.push(s.clone().into())
+    }
+    for s in pk_bundle.self_revocations() {
+        acc.push(s.clone().into())
+    }
+
+    let subkeys: HashSet<_> = tpk
+        .keys()
+        .subkeys()
+        .with_policy(&POLICY, None)
+        .alive()
+        .revoked(false)
+        .filter(|ka| {
+            ka.for_signing() || ka.for_transport_encryption() || ka.for_storage_encryption()
+        })
+        .map(|ka| ka.key().fingerprint())
+        .collect();
+    for skb in tpk.keys().subkeys() {
+        if !subkeys.contains(&skb.key().fingerprint()) {
+            continue;
+        }
+        acc.push(skb.key().clone().into());
+        for s in newest_self_signatures(skb.self_signatures(), Some(1)) {
+            acc.push(s.clone().into())
+        }
+    }
+
+    for uidb in tpk.userids() {
+        if is_status_revoked(uidb.revocation_status(&POLICY, None)) {
+            continue;
+        }
+        acc.push(uidb.userid().clone().into());
+        for s in newest_self_signatures(uidb.self_signatures(), Some(1)) {
+            acc.push(s.clone().into())
+        }
+    }
+
+    Cert::from_packets(acc.into_iter())
+}
+
+/// Returns the serialized form of all signatures in the Cert.
+pub fn tpk_signatures(tpk: &Cert) -> HashSet<Vec<u8>> {
+    tpk.clone()
+        .into_packets()
+        .filter(|packet| matches!(packet, Packet::Signature(_)))
+        .flat_map(|packet| MarshalInto::to_vec(&packet))
+        .collect()
+}
+
 /// Filters the Cert, keeping only UserIDs that aren't revoked, and whose emails match the given list
 pub fn tpk_filter_alive_emails(tpk: &Cert, emails: &[Email]) -> Cert {
     tpk.clone().retain_userids(|uid| {
@@ -94,3 +221,132 @@ pub fn tpk_filter_alive_emails(tpk: &Cert, emails: &[Email]) -> Cert {
         }
     })
 }
+
+/// Limits the number of UserIDs in the Cert, keeping the ones with the newest self-signatures.
+///
+/// Returns the capped Cert and the UserIDs that were dropped.
+pub fn tpk_cap_userids(tpk: Cert, max: usize) -> (Cert, Vec<UserID>) {
+    let mut uids: Vec<_> = tpk
+        .userids()
+        .map(|uidb| {
+            let mut newest = None;
+            for s in uidb.self_signatures() {
+                newest = newest.max(s.signature_creation_time());
+            }
+            (newest, uidb.userid().clone())
+        })
+        .collect();
+    if uids.len() <= max {
+        return (tpk, vec![]);
+    }
+
+    // Stable sort, so that among equally old UserIDs, the first ones win.
+    uids.sort_by(|(t1, _), (t2, _)| t2.cmp(t1));
+    let dropped: Vec<UserID> = uids
+        .split_off(max)
+        .into_iter()
+        .map(|(_, uid)| uid)
+        .collect();
+    let tpk = tpk.retain_userids(|uidb| !dropped.contains(uidb.userid()));
+    (tpk, dropped)
+}
+
+/// Clears all unhashed subpacket areas that are larger than `max_size` bytes.
+///
+/// Returns the Cert and the number of signatures that were stripped.
+pub fn tpk_strip_unhashed_areas(tpk: Cert, max_size: usize) -> Result<(Cert, usize)> {
+    let mut stripped = 0;
+    let packets: Vec<Packet> = tpk
+        .into_packets()
+        .map(|packet| match packet {
+            Packet::Signature(mut sig)
+                if MarshalInto::serialized_len(sig.unhashed_area()) > max_size =>
+            {
+                sig.unhashed_area_mut().clear();
+                stripped += 1;
+                Packet::Signature(sig)
+            }
+            packet => packet,
+        })
+        .collect();
+    Ok((Cert::from_packets(packets.into_iter())?, stripped))
+}
+
+/// Removes subkeys that lack a valid binding signature.
+///
+/// Binding signatures are verified when the Cert is parsed, so a
+/// subkey whose signatures are broken, or were made by a different
+/// primary key, ends up without any.  Returns the Cert and the number
+/// of subkeys that were removed.
+pub fn tpk_strip_unbound_subkeys(tpk: Cert) -> (Cert, usize) {
+    let count_before = tpk.keys().subkeys().count();
+    let tpk = tpk.retain_subkeys(|skb| skb.self_signatures().count() > 0);
+    let removed = count_before - tpk.keys().subkeys().count();
+    (tpk, removed)
+}
+
+/// Explains why a component is not validly bound, if it isn't.
+///
+/// `unbound` is what to report if there are no self-signatures at all.
+fn binding_problem<C>(bundle: &ComponentBundle<C>, unbound: LintKind) -> Option<LintKind> {
+    if bundle.binding_signature(&POLICY, None).is_ok() {
+        return None;
+    }
+
+    let sigs: Vec<&Signature> = bundle.self_signatures().collect();
+    if sigs.is_empty() {
+        Some(unbound)
+    } else if sigs.iter().all(|s| s.hash_algo() == HashAlgorithm::SHA1) {
+        Some(LintKind::Sha1OnlyBinding)
+    } else if sigs.iter().all(|s| s.signature_alive(None, None).is_err()) {
+        Some(LintKind::ExpiredSelfSignature)
+    } else {
+        Some(LintKind::InvalidSelfSignature)
+    }
+}
+
+/// Explains why parts of the full Cert are missing from the published
+/// one, or will be ignored by clients.
+///
+/// Only published user IDs are considered, so that the result does
+/// not reveal anything about the ones that are not.
+pub fn tpk_lint(full_tpk: &Cert, published_tpk: Option<&Cert>) -> Vec<LintIssue> {
+    let mut issues = Vec::new();
+
+    for skb in full_tpk.keys().subkeys() {
+        if let Some(kind) = binding_problem(skb.bundle(), LintKind::UnverifiableSubkey) {
+            issues.push(LintIssue {
+                kind,
+                component: Some(skb.fingerprint().to_hex()),
+            });
+        }
+    }
+
+    let published_uids: Vec<&UserID> = published_tpk
+        .map(|tpk| tpk.userids().map(|uidb| uidb.userid()).collect())
+        .unwrap_or_default();
+    for uidb in full_tpk.userids() {
+        if !published_uids.contains(&uidb.userid()) {
+            continue;
+        }
+
+        let component = Some(String::from_utf8_lossy(uidb.userid().value()).into_owned());
+        if is_status_revoked(uidb.revocation_status(&POLICY, None)) {
+            issues.push(LintIssue {
+                kind: LintKind::RevokedUserId,
+                component,
+            });
+        } else if let Some(kind) = binding_problem(uidb.bundle(), LintKind::InvalidSelfSignature) {
+            issues.push(LintIssue { kind, component });
+        }
+    }
+
+    for _ in full_tpk.user_attributes() {
+        issues.push(LintIssue {
+            kind: LintKind::FilteredUserAttribute,
+            component: None,
+        });
+    }
+
+    issues
+}
@@ -0,0 +1,262 @@
+//! Platform-specific filesystem operations.
+//!
+//! On Unix, links between keys are symlinks, which the web server can
+//! follow when serving WKD requests directly.  Elsewhere, links are
+//! pointer files holding the relative path of the target, which only
+//! hagrid resolves.  That is good enough for development and testing,
+//! but not for serving a production instance.
+
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[cfg(unix)]
+mod imp {
+    use std::fs::{self, File, Permissions};
+    use std::io;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::{Path, PathBuf};
+
+    /// The errno of a rename across filesystems.
+    const EXDEV: i32 = 18;
+
+    pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+        fs::set_permissions(path, Permissions::from_mode(mode))
+    }
+
+    pub fn symlink(content: &Path, name: &Path) -> io::Result<()> {
+        std::os::unix::fs::symlink(content, name)
+    }
+
+    pub fn read_link(path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    pub fn is_link(path: &Path) -> bool {
+        fs::symlink_metadata(path)
+            .map(|metadata| metadata.file_type().is_symlink())
+            .unwrap_or(false)
+    }
+
+    pub fn resolve(path: &Path) -> PathBuf {
+        // The kernel follows symlinks for us.
+        path.to_owned()
+    }
+
+    pub fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+
+    pub fn is_cross_device(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(EXDEV)
+    }
+
+    pub fn open_lock(path: &Path) -> io::Result<File> {
+        File::open(path)
+    }
+
+    pub fn same_file(a: &Path, b: &Path) -> bool {
+        use std::os::unix::fs::MetadataExt;
+
+        match (fs::metadata(a), fs::metadata(b)) {
+            (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+            _ => false,
+        }
+    }
+
+    pub fn link_count(path: &Path) -> io::Result<Option<u64>> {
+        use std::os::unix::fs::MetadataExt;
+
+        Ok(Some(fs::metadata(path)?.nlink()))
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{self, Read, Write};
+    use std::path::{Component, Path, PathBuf};
+
+    /// Marks pointer files, so that they can be told apart from keys.
+    const POINTER_MAGIC: &str = "hagrid-link:";
+
+    /// Directories can't be locked, so we lock this file inside them.
+    const LOCK_FILE: &str = ".lock";
+
+    /// The Windows error for a rename across volumes.
+    const ERROR_NOT_SAME_DEVICE: i32 = 17;
+
+    pub fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+        // There are no permission bits to set.
+        Ok(())
+    }
+
+    pub fn symlink(content: &Path, name: &Path) -> io::Result<()> {
+        let content = content
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Non-UTF-8 link target"))?;
+        let mut file = OpenOptions::new().write(true).create_new(true).open(name)?;
+        write!(file, "{}{}", POINTER_MAGIC, content)?;
+        file.sync_all()
+    }
+
+    pub fn read_link(path: &Path) -> io::Result<PathBuf> {
+        let mut magic = [0; POINTER_MAGIC.len()];
+        let mut file = File::open(path)?;
+        if file.read_exact(&mut magic).is_err() || magic != POINTER_MAGIC.as_bytes() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a link"));
+        }
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        Ok(content.into())
+    }
+
+    pub fn is_link(path: &Path) -> bool {
+        read_link(path).is_ok()
+    }
+
+    pub fn resolve(path: &Path) -> PathBuf {
+        match read_link(path) {
+            Ok(target) => normalize(&path.parent().unwrap().join(target)),
+            Err(_) => path.to_owned(),
+        }
+    }
+
+    pub fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+        let path = resolve(path);
+        // Like on Unix, links to nowhere are an error.
+        fs::metadata(&path)?;
+        Ok(path)
+    }
+
+    pub fn is_cross_device(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(ERROR_NOT_SAME_DEVICE)
+    }
+
+    pub fn open_lock(path: &Path) -> io::Result<File> {
+        if path.is_dir() {
+            OpenOptions::new()
+                .write(true)
+                .create(true)
+                .open(path.join(LOCK_FILE))
+        } else {
+            File::open(path)
+        }
+    }
+
+    pub fn same_file(a: &Path, b: &Path) -> bool {
+        // We can't compare file IDs on stable Rust, but the content
+        // is what matters.
+        match (fs::read(a), fs::read(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    pub fn link_count(_path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+
+    /// Removes `..` components lexically, which is what following a
+    /// link does to its relative target.
+    fn normalize(path: &Path) -> PathBuf {
+        let mut normalized = PathBuf::new();
+        for component in path.components() {
+            match component {
+                Component::ParentDir => {
+                    normalized.pop();
+                }
+                Component::CurDir => (),
+                component => normalized.push(component),
+            }
+        }
+        normalized
+    }
+}
+
+/// Sets the Unix permission bits of the file, where there are any.
+pub fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    imp::set_mode(path, mode)
+}
+
+/// Creates a link `name` pointing to `content`, which is relative to
+/// the directory containing the link.
+pub fn symlink(content: &Path, name: &Path) -> io::Result<()> {
+    imp::symlink(content, name)
+}
+
+/// Returns the target of the link, as it was created.
+pub fn read_link(path: &Path) -> io::Result<PathBuf> {
+    imp::read_link(path)
+}
+
+/// Returns whether the path is a link, without following it.
+pub fn is_link(path: &Path) -> bool {
+    imp::is_link(path)
+}
+
+/// Returns the path to open to read the file behind the link, or the
+/// path itself if it is not a link.
+pub fn resolve(path: &Path) -> PathBuf {
+    imp::resolve(path)
+}
+
+/// Returns the absolute path with links resolved.
+pub fn canonicalize(path: &Path) -> io::Result<PathBuf> {
+    imp::canonicalize(path)
+}
+
+/// Returns whether the error is due to renaming a file to another
+/// filesystem.
+pub fn is_cross_device(e: &io::Error) -> bool {
+    imp::is_cross_device(e)
+}
+
+/// Opens the file to lock in order to lock the given file or
+/// directory.
+pub fn open_lock(path: &Path) -> io::Result<File> {
+    imp::open_lock(path)
+}
+
+/// Returns whether both paths exist and are hard links to the same
+/// file.
+pub fn same_file(a: &Path, b: &Path) -> bool {
+    imp::same_file(a, b)
+}
+
+/// Returns the number of hard links to the file, if the platform
+/// tells.
+pub fn link_count(path: &Path) -> io::Result<Option<u64>> {
+    imp::link_count(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    #[test]
+    fn links() {
+        let tempdir = TempDir::new().unwrap();
+        let dir = tempdir.path();
+        fs::create_dir(dir.join("keys")).unwrap();
+        fs::create_dir(dir.join("links")).unwrap();
+        let key = dir.join("keys").join("key");
+        let link = dir.join("links").join("link");
+        fs::write(&key, "key").unwrap();
+
+        let target = Path::new("../keys/key");
+        symlink(target, &link).unwrap();
+        assert!(is_link(&link));
+        assert!(!is_link(&key));
+        assert_eq!(read_link(&link).unwrap(), target);
+        assert!(read_link(&key).is_err());
+        assert_eq!(fs::read_to_string(resolve(&link)).unwrap(), "key");
+        assert_eq!(canonicalize(&link).unwrap(), canonicalize(&key).unwrap());
+
+        fs::remove_file(&key).unwrap();
+        assert!(is_link(&link));
+        assert!(canonicalize(&link).is_err());
+    }
+}
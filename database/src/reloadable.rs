@@ -0,0 +1,53 @@
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+/// A setting that can be changed while the server is running.
+///
+/// Clones share the value, so whoever holds a clone can change it for
+/// everyone using it.
+#[derive(Debug, Default)]
+pub struct Reloadable<T>(Arc<RwLock<T>>);
+
+impl<T> Clone for Reloadable<T> {
+    fn clone(&self) -> Self {
+        Reloadable(self.0.clone())
+    }
+}
+
+impl<T> Reloadable<T> {
+    pub fn new(value: T) -> Self {
+        Reloadable(Arc::new(RwLock::new(value)))
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<T> {
+        self.0.read().unwrap()
+    }
+
+    pub fn set(&self, value: T) {
+        *self.0.write().unwrap() = value;
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        f(&mut self.0.write().unwrap())
+    }
+}
+
+impl<T: Clone> Reloadable<T> {
+    pub fn get(&self) -> T {
+        self.read().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_between_clones() {
+        let setting = Reloadable::new(1);
+        let other = setting.clone();
+        other.set(2);
+        assert_eq!(setting.get(), 2);
+        setting.update(|value| *value += 1);
+        assert_eq!(other.get(), 3);
+    }
+}
@@ -2,10 +2,14 @@ use std::fs::{create_dir_all, remove_file, File};
 use std::io::{Read, Write};
 use std::path::PathBuf;
 
+use tempfile;
+
 use std::str;
 
 use Result;
 
+/// Tokens stored as files, so that pending verifications survive
+/// restarts of the server.
 pub struct StatefulTokens {
     token_dir: PathBuf,
 }
@@ -32,8 +36,15 @@ impl StatefulTokens {
         let dir = self.token_dir.join(token_type);
         create_dir_all(&dir)?;
 
-        let mut fd = File::create(dir.join(&name))?;
-        fd.write_all(payload)?;
+        // Write the token completely before it can be used, so that a
+        // restart can't leave a truncated one behind.
+        let mut tempfile = tempfile::Builder::new()
+            .prefix(".tmp")
+            .rand_bytes(16)
+            .tempfile_in(&dir)?;
+        tempfile.write_all(payload)?;
+        tempfile.as_file().sync_all()?;
+        tempfile.persist(dir.join(&name))?;
 
         Ok(name)
     }
@@ -53,3 +64,23 @@ impl StatefulTokens {
         Ok(str::from_utf8(&buf)?.to_string())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn tokens_survive_restarts() {
+        let tempdir = TempDir::new().unwrap();
+        let token = StatefulTokens::new(tempdir.path())
+            .unwrap()
+            .new_token("verify", b"payload")
+            .unwrap();
+
+        let tokens = StatefulTokens::new(tempdir.path()).unwrap();
+        assert!(tokens.pop_token("delete", &token).is_err());
+        assert_eq!(tokens.pop_token("verify", &token).unwrap(), "payload");
+        assert!(tokens.pop_token("verify", &token).is_err());
+    }
+}
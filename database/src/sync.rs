@@ -4,18 +4,21 @@ use std::path::Path;
 
 use fs2::FileExt;
 
+use platform;
 use Result;
 
 /// A minimalistic flock-based mutex.
 ///
-/// This just barely implements enough what we need from a mutex.
+/// This just barely implements enough what we need from a mutex.  On
+/// Windows, this is a byte-range lock, and directories are locked
+/// through a lock file inside them.
 pub struct FlockMutexGuard {
     file: File,
 }
 
 impl FlockMutexGuard {
     pub fn lock(path: impl AsRef<Path>) -> Result<Self> {
-        let file = File::open(path)?;
+        let file = platform::open_lock(path.as_ref())?;
         while let Err(e) = file.lock_exclusive() {
             // According to flock(2), possible errors returned are:
             //
@@ -62,6 +65,7 @@ mod tests {
     use tempfile::{NamedTempFile, TempDir};
 
     #[test]
+    #[cfg(unix)]
     fn flock_dir() {
         let tempdir = TempDir::new().unwrap();
         let file = tempdir.path();
@@ -18,33 +18,53 @@ use anyhow::Result;
 use std::convert::{TryFrom, TryInto};
 use std::str::FromStr;
 
-use openpgp::cert::{CertBuilder, UserIDRevocationBuilder};
+use openpgp::cert::{CertBuilder, CertParser, UserIDRevocationBuilder};
 use openpgp::types::{KeyFlags, ReasonForRevocation, SignatureType};
 use openpgp::{
     packet::{signature::*, UserID},
     parse::Parse,
     types::RevocationStatus,
-    Cert, Packet,
+    Cert, Packet, PacketPile,
 };
 use std::fs;
 use std::path::Path;
+use transparency;
 use types::{Email, Fingerprint, KeyID};
+use wkd;
 use Database;
 use Query;
 
-use openpgp_utils::POLICY;
+use openpgp::packet::signature::subpacket::{NotationData, Subpacket, SubpacketValue};
+use openpgp::serialize::MarshalInto;
+use openpgp_utils::{tpk_to_string, POLICY};
 
+use AllowlistResult;
+use BannedKey;
 use EmailAddressStatus;
+use HeldKey;
+use ImportResult;
+use ImportWarning;
+use OversizedUnhashedArea;
 use TpkStatus;
+use {LintIssue, LintKind};
 
 fn check_mail_none(db: &impl Database, email: &Email) {
     assert!(db.by_email(email).is_none());
     assert!(db.by_email_wkd(email).is_none());
+    let (hash, domain) = wkd::encode_wkd(email.as_str()).unwrap();
+    assert!(db.by_domain_and_hash_wkd(&domain, &hash).is_none());
 }
 
 fn check_mail_some(db: &impl Database, email: &Email) {
     assert!(db.by_email(email).is_some());
     assert!(db.by_email_wkd(email).is_some());
+    // Lookups by hash use the same links, whatever the case of the
+    // domain.
+    let (hash, domain) = wkd::encode_wkd(email.as_str()).unwrap();
+    assert_eq!(
+        db.by_domain_and_hash_wkd(&domain.to_uppercase(), &hash),
+        db.by_email_wkd(email)
+    );
 }
 
 pub fn test_uid_verification(db: &mut impl Database, log_path: &Path) {
@@ -74,6 +94,8 @@ pub fn test_uid_verification(db: &mut impl Database, log_path: &Path) {
                 (email2.clone(), EmailAddressStatus::NotPublished),
             ),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -178,6 +200,8 @@ pub fn test_uid_verification(db: &mut impl Database, log_path: &Path) {
                 (email2.clone(), EmailAddressStatus::Published),
             ),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -195,6 +219,8 @@ pub fn test_uid_verification(db: &mut impl Database, log_path: &Path) {
                     (email2.clone(), EmailAddressStatus::Published),
                 ),
                 unparsed_uids: 0,
+                dropped_uids: vec![],
+                warnings: vec![],
             },
             tpk_status
         );
@@ -252,6 +278,8 @@ pub fn test_uid_verification(db: &mut impl Database, log_path: &Path) {
                 (email3.clone(), EmailAddressStatus::NotPublished),
             ),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         }, tpk_status);
 
         // fetch by fpr
@@ -361,6 +389,8 @@ pub fn test_reupload(db: &mut impl Database, log_path: &Path) {
                 (email2.clone(), EmailAddressStatus::NotPublished),
             ),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -472,6 +502,8 @@ pub fn test_uid_deletion(db: &mut impl Database, log_path: &Path) {
                 (email2.clone(), EmailAddressStatus::NotPublished),
             ),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -625,6 +657,8 @@ pub fn test_upload_revoked_tpk(db: &mut impl Database, log_path: &Path) {
                 (email2.clone(), EmailAddressStatus::NotPublished),
             ),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -660,6 +694,8 @@ pub fn test_uid_revocation(db: &mut impl Database, log_path: &Path) {
                 (email2.clone(), EmailAddressStatus::NotPublished),
             ),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -715,6 +751,8 @@ pub fn test_uid_revocation(db: &mut impl Database, log_path: &Path) {
                 (email2.clone(), EmailAddressStatus::Revoked),
             ),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -750,6 +788,8 @@ pub fn test_uid_revocation_fake(db: &mut D) {
             (email.clone(), EmailAddressStatus::NotPublished),
         ),
         unparsed_uids: 0,
+        dropped_uids: vec![],
+        warnings: vec![],
     }, tpk_status);
 
     // verify uid
@@ -797,6 +837,8 @@ pub fn test_uid_revocation_fake(db: &mut D) {
             (email.clone(), EmailAddressStatus::Published),
         ),
         unparsed_uids: 0,
+        dropped_uids: vec![],
+        warnings: vec![],
     }, tpk_status);
 
     // Fail to fetch by the revoked uid, ok by the non-revoked one.
@@ -859,6 +901,8 @@ pub fn test_unlink_uid(db: &mut impl Database, log_path: &Path) {
             is_revoked: false,
             email_status: vec!((email.clone(), EmailAddressStatus::Revoked),),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -912,6 +956,8 @@ pub fn test_same_email_1(db: &mut impl Database, log_path: &Path) {
             is_revoked: false,
             email_status: vec!((email1.clone(), EmailAddressStatus::NotPublished),),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status1
     );
@@ -922,6 +968,8 @@ pub fn test_same_email_1(db: &mut impl Database, log_path: &Path) {
             is_revoked: false,
             email_status: vec!((email2.clone(), EmailAddressStatus::NotPublished),),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status2
     );
@@ -990,6 +1038,8 @@ pub fn test_same_email_1(db: &mut impl Database, log_path: &Path) {
             is_revoked: false,
             email_status: vec!((email2.clone(), EmailAddressStatus::Revoked),),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status2
     );
@@ -1029,6 +1079,8 @@ pub fn test_same_email_2(db: &mut impl Database, log_path: &Path) {
             is_revoked: false,
             email_status: vec!((email.clone(), EmailAddressStatus::NotPublished),),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -1080,6 +1132,8 @@ pub fn test_same_email_2(db: &mut impl Database, log_path: &Path) {
             is_revoked: false,
             email_status: vec!((email.clone(), EmailAddressStatus::Published),),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -1118,6 +1172,8 @@ pub fn test_same_email_3(db: &mut impl Database, log_path: &Path) {
             is_revoked: false,
             email_status: vec!((email.clone(), EmailAddressStatus::NotPublished),),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -1169,6 +1225,8 @@ pub fn test_same_email_3(db: &mut impl Database, log_path: &Path) {
             is_revoked: false,
             email_status: vec!((email.clone(), EmailAddressStatus::Published),),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -1220,6 +1278,8 @@ pub fn test_same_email_4(db: &mut impl Database, log_path: &Path) {
             is_revoked: false,
             email_status: vec!((email.clone(), EmailAddressStatus::Published),),
             unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -1256,6 +1316,8 @@ pub fn test_bad_uids(db: &mut impl Database, log_path: &Path) {
                 (email2.clone(), EmailAddressStatus::NotPublished),
             ),
             unparsed_uids: 1,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
@@ -1273,181 +1335,1234 @@ pub fn test_bad_uids(db: &mut impl Database, log_path: &Path) {
                 (email2, EmailAddressStatus::Published),
             ),
             unparsed_uids: 1,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
 }
 
-pub fn test_no_selfsig(db: &mut impl Database, log_path: &Path) {
-    let (mut tpk, revocation) = CertBuilder::new().generate().unwrap();
+pub fn test_uid_policy_publish_all(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let str_uid2 = "Test B <test_b@example.com>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .add_userid(str_uid2)
+        .generate()
+        .unwrap()
+        .0;
     let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+    let email2 = Email::from_str(str_uid2).unwrap();
 
-    // don't allow upload of naked key
-    assert!(db.merge(tpk.clone()).is_err());
+    let tpk_status = db.merge(tpk).unwrap().into_tpk_status();
+    check_log_entry(log_path, &fpr);
+    assert_eq!(
+        TpkStatus {
+            is_revoked: false,
+            email_status: vec!(
+                (email1.clone(), EmailAddressStatus::Published),
+                (email2.clone(), EmailAddressStatus::Published),
+            ),
+            unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
+        },
+        tpk_status
+    );
+
+    // Both addresses are published without verification.
+    check_mail_some(db, &email1);
+    check_mail_some(db, &email2);
+    let tpk = db.lookup(&Query::ByEmail(email1)).unwrap().unwrap();
+    assert_eq!(tpk.userids().count(), 2);
+}
+
+pub fn test_uid_policy_none(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .add_signing_subkey()
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
 
-    // with revocation, it's ok
-    tpk = tpk.insert_packets(revocation).unwrap();
     let tpk_status = db.merge(tpk).unwrap().into_tpk_status();
     check_log_entry(log_path, &fpr);
     assert_eq!(
         TpkStatus {
-            is_revoked: true,
-            email_status: vec!(),
-            unparsed_uids: 0
+            is_revoked: false,
+            email_status: vec!((email1.clone(), EmailAddressStatus::NotPublished)),
+            unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
         },
         tpk_status
     );
+
+    // Verification is refused, and the key stays without user IDs.
+    assert!(db.set_email_published(&fpr, &email1).is_err());
+    check_mail_none(db, &email1);
+    let tpk = db.lookup(&Query::ByFingerprint(fpr)).unwrap().unwrap();
+    assert_eq!(tpk.userids().count(), 0);
 }
 
-/// Makes sure that attested key signatures are correctly handled.
-pub fn attested_key_signatures(db: &mut impl Database, log_path: &Path) -> Result<()> {
-    use openpgp::types::*;
+/// Adds a user ID bound by a self-signature created at the given time.
+fn add_userid_at(tpk: Cert, uid: &str, time: std::time::SystemTime) -> Cert {
+    let mut keypair = tpk
+        .primary_key()
+        .key()
+        .clone()
+        .parts_into_secret()
+        .unwrap()
+        .into_keypair()
+        .unwrap();
+    let uid = UserID::from(uid);
+    let sig = uid
+        .bind(
+            &mut keypair,
+            &tpk,
+            SignatureBuilder::new(SignatureType::PositiveCertification)
+                .set_signature_creation_time(time)
+                .unwrap(),
+        )
+        .unwrap();
+    tpk.insert_packets(vec![Packet::from(uid), sig.into()])
+        .unwrap()
+}
+
+/// Generates a key with three user IDs, from oldest to newest.
+fn key_with_three_uids(str_uids: [&str; 3]) -> Cert {
     use std::time::{Duration, SystemTime};
     let t0 = SystemTime::now() - Duration::new(5 * 60, 0);
     let t1 = SystemTime::now() - Duration::new(4 * 60, 0);
+    let t2 = SystemTime::now() - Duration::new(3 * 60, 0);
 
-    let (alice, _) = CertBuilder::new()
+    let tpk = CertBuilder::new()
         .set_creation_time(t0)
-        .add_userid("alice@foo.com")
-        .generate()?;
-    let mut alice_signer = alice
-        .primary_key()
-        .key()
-        .clone()
-        .parts_into_secret()?
-        .into_keypair()?;
+        .add_userid(str_uids[0])
+        .generate()
+        .unwrap()
+        .0;
+    let tpk = add_userid_at(tpk, str_uids[1], t1);
+    add_userid_at(tpk, str_uids[2], t2)
+}
 
-    let (bob, _) = CertBuilder::new()
-        .set_creation_time(t0)
-        .add_userid("bob@bar.com")
-        .generate()?;
-    let bobs_fp = Fingerprint::try_from(bob.fingerprint())?;
-    let mut bob_signer = bob
-        .primary_key()
-        .key()
-        .clone()
-        .parts_into_secret()?
-        .into_keypair()?;
+/// Expects a database that publishes at most two user IDs per key.
+pub fn test_max_published_uids(db: &mut impl Database, log_path: &Path) {
+    let str_uids = [
+        "Test A <test_a@example.com>",
+        "Test B <test_b@example.com>",
+        "Test C <test_c@example.com>",
+    ];
+    let tpk = key_with_three_uids(str_uids);
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uids[0]).unwrap();
+    let email2 = Email::from_str(str_uids[1]).unwrap();
+    let email3 = Email::from_str(str_uids[2]).unwrap();
 
-    // Have Alice certify the binding between "bob@bar.com" and
-    // Bob's key.
-    let alice_certifies_bob = bob.userids().next().unwrap().userid().bind(
-        &mut alice_signer,
-        &bob,
-        SignatureBuilder::new(SignatureType::GenericCertification)
-            .set_signature_creation_time(t1)?,
-    )?;
+    db.merge(tpk).unwrap();
+    check_log_entry(log_path, &fpr);
 
-    // Have Bob attest that certification.
-    let attestations = bob.userids().next().unwrap().attest_certifications(
-        &POLICY,
-        &mut bob_signer,
-        vec![&alice_certifies_bob],
-    )?;
-    assert_eq!(attestations.len(), 1);
-    let attestation = attestations[0].clone();
+    db.set_email_published(&fpr, &email1).unwrap();
+    db.set_email_published(&fpr, &email2).unwrap();
+    check_mail_some(db, &email1);
+    check_mail_some(db, &email2);
 
-    // Now for the test.  First, import Bob's cert as is.
-    db.merge(bob.clone())?;
-    check_log_entry(log_path, &bobs_fp);
+    // Publishing a third address drops the oldest one.
+    db.set_email_published(&fpr, &email3).unwrap();
+    check_mail_none(db, &email1);
+    check_mail_some(db, &email2);
+    check_mail_some(db, &email3);
+    let tpk = db
+        .lookup(&Query::ByFingerprint(fpr.clone()))
+        .unwrap()
+        .unwrap();
+    assert_eq!(tpk.userids().count(), 2);
 
-    // Confirm the email so that we can inspect the userid component.
-    db.set_email_published(&bobs_fp, &Email::from_str("bob@bar.com")?)?;
+    // The oldest address can't be published anymore.
+    assert!(db.set_email_published(&fpr, &email1).is_err());
+    check_mail_none(db, &email1);
+}
 
-    // Then, add the certification, merge into the db, check that the
-    // certification is stripped.
-    let bob = bob.insert_packets(vec![alice_certifies_bob.clone()])?;
-    db.merge(bob.clone())?;
-    check_log_entry(log_path, &bobs_fp);
-    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
-    assert_eq!(bob_.bad_signatures().count(), 0);
-    assert_eq!(bob_.userids().next().unwrap().certifications().count(), 0);
+/// Expects a database that publishes all user IDs, but at most two
+/// per key.
+pub fn test_max_published_uids_publish_all(db: &mut impl Database, log_path: &Path) {
+    let str_uids = [
+        "Test A <test_a@example.com>",
+        "Test B <test_b@example.com>",
+        "Test C <test_c@example.com>",
+    ];
+    let tpk = key_with_three_uids(str_uids);
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uids[0]).unwrap();
+    let email2 = Email::from_str(str_uids[1]).unwrap();
+    let email3 = Email::from_str(str_uids[2]).unwrap();
 
-    // Add the attestation, merge into the db, check that the
-    // certification is now included.
-    let bob_attested = bob.clone().insert_packets(vec![attestation])?;
-    db.merge(bob_attested.clone())?;
-    check_log_entry(log_path, &bobs_fp);
-    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
-    assert_eq!(bob_.bad_signatures().count(), 0);
-    assert_eq!(bob_.userids().next().unwrap().certifications().count(), 1);
-    assert_eq!(
-        bob_.with_policy(&POLICY, None)?
-            .userids()
-            .next()
-            .unwrap()
-            .attestation_key_signatures()
-            .count(),
-        1
-    );
+    let tpk_status = db.merge(tpk).unwrap().into_tpk_status();
+    check_log_entry(log_path, &fpr);
     assert_eq!(
-        bob_.with_policy(&POLICY, None)?
-            .userids()
-            .next()
-            .unwrap()
-            .attested_certifications()
-            .count(),
-        1
+        TpkStatus {
+            is_revoked: false,
+            email_status: vec!(
+                (email1.clone(), EmailAddressStatus::NotPublished),
+                (email2.clone(), EmailAddressStatus::Published),
+                (email3.clone(), EmailAddressStatus::Published),
+            ),
+            unparsed_uids: 0,
+            dropped_uids: vec![email1.clone()],
+            warnings: vec![],
+        },
+        tpk_status
     );
 
-    // Make a random merge with Bob's unattested cert, demonstrating
-    // that the attestation still works.
-    db.merge(bob.clone())?;
-    check_log_entry(log_path, &bobs_fp);
-    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
-    assert_eq!(bob_.bad_signatures().count(), 0);
-    assert_eq!(bob_.userids().next().unwrap().certifications().count(), 1);
+    check_mail_none(db, &email1);
+    check_mail_some(db, &email2);
+    check_mail_some(db, &email3);
+}
 
-    // Finally, withdraw consent by overriding the attestation, merge
-    // into the db, check that the certification is now gone.
-    let attestations = bob_attested
-        .userids()
-        .next()
+/// Expects a database that publishes at most two self-signatures per
+/// component.
+pub fn test_max_self_signatures(db: &mut impl Database, log_path: &Path) {
+    use std::time::{Duration, SystemTime};
+    let t0 = SystemTime::now() - Duration::new(5 * 60, 0);
+
+    let str_uid1 = "Test A <test_a@example.com>";
+    let mut tpk = CertBuilder::new()
+        .set_creation_time(t0)
+        .add_userid(str_uid1)
+        .generate()
         .unwrap()
-        .attest_certifications(&POLICY, &mut bob_signer, &[])?;
-    assert_eq!(attestations.len(), 1);
-    let clear_attestation = attestations[0].clone();
+        .0;
+    // Re-sign the user ID a couple of times.
+    for minutes in &[4, 3, 2] {
+        let t = SystemTime::now() - Duration::new(minutes * 60, 0);
+        tpk = add_userid_at(tpk, str_uid1, t);
+    }
+    assert_eq!(tpk.userids().next().unwrap().self_signatures().count(), 4);
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
 
-    let bob = bob.insert_packets(vec![clear_attestation])?;
-    assert_eq!(bob.userids().next().unwrap().certifications().count(), 1);
+    db.merge(tpk.clone()).unwrap();
+    check_log_entry(log_path, &fpr);
+    db.set_email_published(&fpr, &email1).unwrap();
+
+    // The published key has the two newest self-signatures.
+    let published = db.lookup(&Query::ByEmail(email1)).unwrap().unwrap();
+    let uid = published.userids().next().unwrap();
+    assert_eq!(uid.self_signatures().count(), 2);
     assert_eq!(
-        bob.with_policy(&POLICY, None)?
-            .userids()
+        uid.binding_signature(&POLICY, None).unwrap(),
+        tpk.userids()
             .next()
             .unwrap()
-            .attestation_key_signatures()
-            .count(),
-        1
-    );
-    assert_eq!(
-        bob.with_policy(&POLICY, None)?
-            .userids()
-            .next()
+            .binding_signature(&POLICY, None)
             .unwrap()
-            .attested_certifications()
-            .count(),
-        0
     );
 
-    db.merge(bob)?;
-    check_log_entry(log_path, &bobs_fp);
-    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
-    assert_eq!(bob_.bad_signatures().count(), 0);
-    assert_eq!(bob_.userids().next().unwrap().certifications().count(), 0);
+    // The full key keeps all of them.
+    let full = Cert::from_bytes(db.by_fpr_full(&fpr).unwrap().as_bytes()).unwrap();
+    assert_eq!(full.userids().next().unwrap().self_signatures().count(), 4);
+}
+
+/// Checks that compacting rewrites published keys with more than we
+/// would publish, and leaves alone those without.
+pub fn test_compact(db: &mut impl Database, log_path: &Path) {
+    use std::time::{Duration, SystemTime};
+    let t0 = SystemTime::now() - Duration::new(5 * 60, 0);
+
+    let str_uid1 = "Test A <test_a@example.com>";
+    let mut tpk = CertBuilder::new()
+        .set_creation_time(t0)
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    for minutes in &[4, 3, 2] {
+        let t = SystemTime::now() - Duration::new(minutes * 60, 0);
+        tpk = add_userid_at(tpk, str_uid1, t);
+    }
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+
+    db.merge(tpk.clone()).unwrap();
+    check_log_entry(log_path, &fpr);
+    db.set_email_published(&fpr, &email1).unwrap();
+
+    let result = db.compact(&fpr).unwrap();
+    assert!(!result.changed);
+    assert_eq!(result.size_before, result.size_after);
+
+    // Published by an older version, without the limit on
+    // self-signatures.
+    let tmp = db.write_to_temp(&tpk_to_string(&tpk).unwrap()).unwrap();
+    db.move_tmp_to_published(tmp, &fpr).unwrap();
+
+    let result = db.compact(&fpr).unwrap();
+    assert!(result.changed);
+    assert!(result.size_after < result.size_before);
+    check_log_entry(log_path, &fpr);
+
+    let published = db.lookup(&Query::ByEmail(email1)).unwrap().unwrap();
     assert_eq!(
-        bob_.with_policy(&POLICY, None)?
+        published
             .userids()
             .next()
             .unwrap()
-            .attestation_key_signatures()
+            .self_signatures()
             .count(),
-        1
+        2
     );
-    assert_eq!(
-        bob_.with_policy(&POLICY, None)?
-            .userids()
-            .next()
-            .unwrap()
+    assert!(!db.compact(&fpr).unwrap().changed);
+}
+
+/// Checks that minimal exports drop unusable subkeys and old
+/// self-signatures.
+pub fn test_minimal_export(db: &mut impl Database, log_path: &Path) {
+    use std::time::{Duration, SystemTime};
+    let t0 = SystemTime::now() - Duration::new(5 * 60, 0);
+
+    let str_uid1 = "Test A <test_a@example.com>";
+    let mut tpk = CertBuilder::new()
+        .set_creation_time(t0)
+        .add_userid(str_uid1)
+        .add_signing_subkey()
+        .add_transport_encryption_subkey()
+        .add_authentication_subkey()
+        .generate()
+        .unwrap()
+        .0;
+    tpk = add_userid_at(tpk, str_uid1, SystemTime::now() - Duration::new(60, 0));
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+
+    assert!(db.by_fpr_minimal(&fpr).unwrap().is_none());
+    db.merge(tpk.clone()).unwrap();
+    check_log_entry(log_path, &fpr);
+    db.set_email_published(&fpr, &email1).unwrap();
+
+    let minimal = db.by_fpr_minimal(&fpr).unwrap().unwrap();
+    let minimal = Cert::from_bytes(minimal.as_bytes()).unwrap();
+    assert_eq!(minimal.fingerprint(), tpk.fingerprint());
+
+    // Only the signing and encryption subkeys are kept.
+    let subkeys: Vec<_> = minimal
+        .keys()
+        .subkeys()
+        .with_policy(&POLICY, None)
+        .collect();
+    assert_eq!(subkeys.len(), 2);
+    assert!(subkeys.iter().all(|ka| !ka.for_authentication()));
+
+    // The user ID keeps only its newest self-signature.
+    let uid = minimal.userids().next().unwrap();
+    assert_eq!(uid.self_signatures().count(), 1);
+    assert_eq!(
+        uid.binding_signature(&POLICY, None).unwrap(),
+        tpk.userids()
+            .next()
+            .unwrap()
+            .binding_signature(&POLICY, None)
+            .unwrap()
+    );
+}
+
+pub fn test_published_by_domain(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.org>";
+    let str_uid2 = "Test B <test_b@sub.example.org>";
+    let str_uid3 = "Test C <test_c@example.org>";
+    let str_uid4 = "Test D <test_d@notexample.org>";
+    let tpk1 = CertBuilder::new()
+        .add_userid(str_uid1)
+        .add_userid(str_uid4)
+        .generate()
+        .unwrap()
+        .0;
+    let tpk2 = CertBuilder::new()
+        .add_userid(str_uid2)
+        .add_userid(str_uid3)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr1 = Fingerprint::try_from(tpk1.fingerprint()).unwrap();
+    let fpr2 = Fingerprint::try_from(tpk2.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+    let email2 = Email::from_str(str_uid2).unwrap();
+    let email4 = Email::from_str(str_uid4).unwrap();
+
+    db.merge(tpk1).unwrap();
+    check_log_entry(log_path, &fpr1);
+    db.merge(tpk2).unwrap();
+    check_log_entry(log_path, &fpr2);
+    db.set_email_published(&fpr1, &email1).unwrap();
+    db.set_email_published(&fpr1, &email4).unwrap();
+    db.set_email_published(&fpr2, &email2).unwrap();
+
+    // test_c@example.org is not verified, and test_d@notexample.org
+    // is in another domain.
+    assert_eq!(
+        db.published_by_domain("Example.org").unwrap(),
+        vec![(email1, fpr1.clone()), (email2.clone(), fpr2.clone())]
+    );
+    assert_eq!(
+        db.published_by_domain("sub.example.org").unwrap(),
+        vec![(email2, fpr2)]
+    );
+    assert_eq!(
+        db.published_by_domain("notexample.org").unwrap(),
+        vec![(email4, fpr1)]
+    );
+    assert!(db.published_by_domain("example.com").unwrap().is_empty());
+    assert!(db.published_by_domain("not a domain").is_err());
+}
+
+pub fn test_domain_allowlist(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.org>";
+    let str_uid2 = "Test B <test_b@example.org>";
+    let str_uid3 = "Test C <test_c@sub.example.org>";
+    let str_uid4 = "Test D <test_d@notexample.org>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .add_userid(str_uid2)
+        .add_userid(str_uid3)
+        .add_userid(str_uid4)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+    let email2 = Email::from_str(str_uid2).unwrap();
+    let email3 = Email::from_str(str_uid3).unwrap();
+    let email4 = Email::from_str(str_uid4).unwrap();
+
+    db.merge(tpk).unwrap();
+    check_log_entry(log_path, &fpr);
+    for email in &[&email1, &email2, &email3, &email4] {
+        db.set_email_published(&fpr, email).unwrap();
+    }
+
+    // Allowlists only contain addresses in their domain.
+    assert!(db
+        .set_domain_allowlist("example.org", Some(vec![email4.clone()]))
+        .is_err());
+
+    // Addresses not on the list are unpublished, also in subdomains.
+    let result = db
+        .set_domain_allowlist("example.org", Some(vec![email1.clone()]))
+        .unwrap();
+    assert_eq!(result.unpublished, vec![email2.clone(), email3.clone()]);
+    assert!(result.held.is_empty());
+    check_mail_some(db, &email1);
+    check_mail_none(db, &email2);
+    check_mail_none(db, &email3);
+    check_mail_some(db, &email4);
+
+    // And can't be published again.
+    assert!(db.set_email_published(&fpr, &email2).is_err());
+    assert!(!db.is_email_allowed_by_domain(&email3));
+
+    // The closest domain's list applies.
+    db.set_domain_allowlist("sub.example.org", Some(vec![email3.clone()]))
+        .unwrap();
+    db.set_email_published(&fpr, &email3).unwrap();
+    check_mail_some(db, &email3);
+
+    // Lifting the restriction unpublishes nothing.
+    assert_eq!(
+        db.set_domain_allowlist("example.org", None).unwrap(),
+        AllowlistResult::default()
+    );
+    db.set_email_published(&fpr, &email2).unwrap();
+    check_mail_some(db, &email2);
+
+    // Addresses of held keys stay published.
+    db.place_legal_hold(&fpr, "court order").unwrap();
+    let result = db
+        .set_domain_allowlist("example.org", Some(vec![email1.clone()]))
+        .unwrap();
+    assert!(result.unpublished.is_empty());
+    assert_eq!(result.held, vec![email2.clone()]);
+    check_mail_some(db, &email2);
+}
+
+/// Adds a large notation to the unhashed area of the user ID binding
+/// signatures.
+fn flood_unhashed_areas(tpk: Cert) -> Cert {
+    let packets = tpk.into_packets().map(|packet| match packet {
+        Packet::Signature(mut sig) if sig.typ() == SignatureType::PositiveCertification => {
+            let notation = NotationData::new("flood@example.org", vec![0u8; 4096], None);
+            sig.unhashed_area_mut()
+                .add(Subpacket::new(SubpacketValue::NotationData(notation), false).unwrap())
+                .unwrap();
+            Packet::Signature(sig)
+        }
+        packet => packet,
+    });
+    Cert::from_packets(packets).unwrap()
+}
+
+/// Expects a database that strips unhashed areas larger than 1024
+/// bytes.
+pub fn test_unhashed_area_strip(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    let tpk = flood_unhashed_areas(tpk);
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+
+    let tpk_status = db.merge(tpk).unwrap().into_tpk_status();
+    check_log_entry(log_path, &fpr);
+    assert_eq!(
+        tpk_status.warnings,
+        vec![ImportWarning::StrippedUnhashedAreas(1)]
+    );
+
+    // The stripped binding signature is still good.
+    db.set_email_published(&fpr, &email1).unwrap();
+    check_mail_some(db, &email1);
+
+    let full = Cert::from_bytes(db.by_fpr_full(&fpr).unwrap().as_bytes()).unwrap();
+    for sig in full.userids().next().unwrap().self_signatures() {
+        assert!(sig.unhashed_area().serialized_len() <= 1024);
+    }
+}
+
+/// Expects a database that rejects unhashed areas larger than 1024
+/// bytes.
+pub fn test_unhashed_area_reject(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+
+    let err = db.merge(flood_unhashed_areas(tpk.clone())).unwrap_err();
+    assert!(err.downcast_ref::<OversizedUnhashedArea>().is_some());
+    assert!(db.by_fpr_full(&fpr).is_none());
+
+    // The key without the flood is fine.
+    let tpk_status = db.merge(tpk).unwrap().into_tpk_status();
+    check_log_entry(log_path, &fpr);
+    assert!(tpk_status.warnings.is_empty());
+}
+
+pub fn test_merge_dedup(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+
+    db.merge(tpk.clone()).unwrap();
+    check_log_entry(log_path, &fpr);
+    db.set_email_published(&fpr, &email1).unwrap();
+    let full_before = db.by_fpr_full(&fpr).unwrap();
+    let published_before = db.by_fpr(&fpr).unwrap();
+
+    // Upload the key again, this time with a copy of each packet, as
+    // raw bytes that are parsed like uploads are.  Canonicalization
+    // drops the duplicates.
+    let packets: Vec<Packet> = tpk.clone().into_packets().collect();
+    let mut doubled = vec![];
+    for packet in packets.iter().chain(packets.iter()) {
+        doubled.extend_from_slice(&MarshalInto::to_vec(packet).unwrap());
+    }
+    assert_eq!(
+        PacketPile::from_bytes(&doubled).unwrap().children().count(),
+        2 * packets.len()
+    );
+    let tpk_doubled = CertParser::from_bytes(&doubled)
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+
+    match db.merge(tpk_doubled).unwrap() {
+        ImportResult::Unchanged(_) => (),
+        _ => panic!("expected key to be unchanged"),
+    }
+    assert_eq!(full_before, db.by_fpr_full(&fpr).unwrap());
+    assert_eq!(published_before, db.by_fpr(&fpr).unwrap());
+
+    let full = Cert::from_bytes(db.by_fpr_full(&fpr).unwrap().as_bytes()).unwrap();
+    assert_eq!(full.userids().next().unwrap().self_signatures().count(), 1);
+}
+
+pub fn test_merge_diff(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let str_uid2 = "Test B <test_b@example.com>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .add_signing_subkey()
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let sub_fpr =
+        Fingerprint::try_from(tpk.keys().subkeys().next().unwrap().fingerprint()).unwrap();
+
+    // Everything is new, and the user ID is not published yet.
+    let (_, diff) = db.merge_with_diff(tpk.clone(), None).unwrap();
+    check_log_entry(log_path, &fpr);
+    assert_eq!(diff.new_subkeys, vec![sub_fpr]);
+    assert_eq!(diff.new_uids, vec![str_uid1.to_owned()]);
+    assert_eq!(diff.filtered_uids, vec![str_uid1.to_owned()]);
+    assert!(diff.new_signatures > 0);
+    assert!(diff.filtered_signatures > 0);
+    assert!(diff.published_changed);
+
+    // Uploading the same key again changes nothing.
+    let (import_result, diff) = db.merge_with_diff(tpk.clone(), None).unwrap();
+    assert!(matches!(import_result, ImportResult::Unchanged(_)));
+    assert!(diff.new_subkeys.is_empty());
+    assert!(diff.new_uids.is_empty());
+    assert_eq!(diff.new_signatures, 0);
+    assert!(!diff.published_changed);
+
+    // A new user ID comes with one new signature.
+    let tpk = add_userid_at(tpk, str_uid2, std::time::SystemTime::now());
+    let (_, diff) = db.merge_with_diff(tpk.clone(), None).unwrap();
+    assert!(diff.new_subkeys.is_empty());
+    assert_eq!(diff.new_uids, vec![str_uid2.to_owned()]);
+    assert_eq!(diff.new_signatures, 1);
+    assert_eq!(diff.filtered_uids.len(), 2);
+    assert!(!diff.published_changed);
+
+    // Someone else uploading the key with one of the user IDs
+    // doesn't learn about the other, unverified one.
+    let partial = tpk.retain_userids(|binding| binding.userid().value() == str_uid1.as_bytes());
+    let (_, diff) = db.merge_with_diff(partial, None).unwrap();
+    assert!(diff.new_uids.is_empty());
+    assert_eq!(diff.filtered_uids, vec![str_uid1.to_owned()]);
+}
+
+pub fn test_unbound_subkey(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .add_signing_subkey()
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let sub_fpr =
+        Fingerprint::try_from(tpk.keys().subkeys().next().unwrap().fingerprint()).unwrap();
+
+    // Graft a subkey bound to another primary key onto the key.
+    let other = CertBuilder::new()
+        .add_signing_subkey()
+        .generate()
+        .unwrap()
+        .0;
+    let other_subkey = other.keys().subkeys().next().unwrap();
+    let other_fpr = Fingerprint::try_from(other_subkey.fingerprint()).unwrap();
+    let grafted = Cert::from_packets(tpk.into_packets().chain(vec![
+        Packet::from(other_subkey.key().clone().parts_into_public()),
+        other_subkey
+            .binding_signature(&POLICY, None)
+            .unwrap()
+            .clone()
+            .into(),
+    ]))
+    .unwrap();
+    assert_eq!(grafted.keys().subkeys().count(), 2);
+
+    let tpk_status = db.merge(grafted).unwrap().into_tpk_status();
+    check_log_entry(log_path, &fpr);
+    assert_eq!(
+        tpk_status.warnings,
+        vec![ImportWarning::DroppedUnboundSubkeys(1)]
+    );
+
+    // Only the validly bound subkey is stored and linked.
+    let full = Cert::from_bytes(db.by_fpr_full(&fpr).unwrap().as_bytes()).unwrap();
+    assert_eq!(full.keys().subkeys().count(), 1);
+    assert!(db.by_fpr(&sub_fpr).is_some());
+    assert!(db.by_fpr(&other_fpr).is_none());
+    assert!(db.by_kid(&other_fpr.into()).is_none());
+}
+
+pub fn test_lint(db: &mut impl Database, log_path: &Path) {
+    use std::time::{Duration, SystemTime};
+    let t0 = SystemTime::now() - Duration::new(5 * 60, 0);
+
+    let str_uid1 = "Test A <test_a@example.com>";
+    let str_uid2 = "Test B <test_b@example.com>";
+    let tpk = CertBuilder::new()
+        .set_creation_time(t0)
+        .add_userid(str_uid1)
+        .add_signing_subkey()
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email2 = Email::from_str(str_uid2).unwrap();
+
+    // Add a user ID whose self-signature expired a minute after it
+    // was made.
+    let mut keypair = tpk
+        .primary_key()
+        .key()
+        .clone()
+        .parts_into_secret()
+        .unwrap()
+        .into_keypair()
+        .unwrap();
+    let uid2 = UserID::from(str_uid2);
+    let sig = uid2
+        .bind(
+            &mut keypair,
+            &tpk,
+            SignatureBuilder::new(SignatureType::PositiveCertification)
+                .set_signature_creation_time(t0)
+                .unwrap()
+                .set_signature_validity_period(Duration::new(60, 0))
+                .unwrap(),
+        )
+        .unwrap();
+    let tpk = tpk
+        .insert_packets(vec![Packet::from(uid2), sig.into()])
+        .unwrap();
+
+    db.merge(tpk).unwrap();
+    check_log_entry(log_path, &fpr);
+
+    // Nothing is published yet, so user IDs are not reported at all.
+    assert_eq!(db.lint(&fpr).unwrap(), vec![]);
+
+    db.set_email_published(&fpr, &email2).unwrap();
+    assert_eq!(
+        db.lint(&fpr).unwrap(),
+        vec![LintIssue {
+            kind: LintKind::ExpiredSelfSignature,
+            component: Some(str_uid2.to_owned()),
+        }]
+    );
+
+    let unknown = Fingerprint::from_str("CBCD8F030588653EEDD7E2659B7DD433F254904A").unwrap();
+    assert!(db.lint(&unknown).is_none());
+}
+
+/// Expects a database that resolves bare domains to `security@`.
+pub fn test_domain_role(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Security <security@example.org>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+
+    db.merge(tpk).unwrap();
+    check_log_entry(log_path, &fpr);
+
+    assert_eq!(db.domain_role_email("example.org"), Some(email1.clone()));
+    assert_eq!(db.domain_role_email("foo@example.org"), None);
+    assert_eq!(db.domain_role_email("example org"), None);
+    assert_eq!(db.domain_role_email(""), None);
+
+    // Only the verified role address is found.
+    let query = Query::ByEmail(db.domain_role_email("example.org").unwrap());
+    assert!(db.lookup_primary_fingerprint(&query).is_none());
+    db.set_email_published(&fpr, &email1).unwrap();
+    assert_eq!(db.lookup_primary_fingerprint(&query), Some(fpr));
+}
+
+pub fn test_ban(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .add_transport_encryption_subkey()
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let sub_fpr =
+        Fingerprint::try_from(tpk.keys().subkeys().next().unwrap().fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+
+    db.merge(tpk.clone()).unwrap();
+    db.set_email_published(&fpr, &email1).unwrap();
+    check_mail_some(db, &email1);
+    assert!(!db.is_banned(&fpr));
+
+    db.ban(&fpr, "spam", None).unwrap();
+    check_log_entry(log_path, &fpr);
+    assert!(db.is_banned(&fpr));
+    assert_eq!(db.tombstone(&fpr).unwrap().reason, "spam");
+    assert_eq!(db.removal_notice(&Query::ByFingerprint(fpr.clone())), None);
+
+    // Everything is gone.
+    check_mail_none(db, &email1);
+    assert!(db.by_fpr(&fpr).is_none());
+    assert!(db.by_fpr(&sub_fpr).is_none());
+    assert!(db.by_kid(&fpr.clone().into()).is_none());
+    assert!(db.by_fpr_full(&fpr).is_none());
+    assert!(db.set_email_published(&fpr, &email1).is_err());
+
+    // And it stays gone.
+    let err = db.merge(tpk).unwrap_err();
+    assert!(err.downcast_ref::<BannedKey>().is_some());
+    assert!(db.by_fpr(&fpr).is_none());
+
+    // Keys can be banned before they are ever uploaded.
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    db.ban(&fpr, "court order", Some("Removed by court order."))
+        .unwrap();
+    assert!(db.merge(tpk).is_err());
+    assert!(db.by_fpr(&fpr).is_none());
+    assert_eq!(
+        db.removal_notice(&Query::ByFingerprint(fpr.clone())),
+        Some("Removed by court order.".to_owned())
+    );
+    assert_eq!(db.removal_notice(&Query::ByKeyID(fpr.into())), None);
+}
+
+pub fn test_ban_email(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let str_uid2 = "Test B <test_b@sub.example.org>";
+    let str_uid3 = "Test C <test_c@other.org>";
+    let tpk = CertBuilder::new()
+        .add_userid(str_uid1)
+        .add_userid(str_uid2)
+        .add_userid(str_uid3)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+    let email2 = Email::from_str(str_uid2).unwrap();
+    let email3 = Email::from_str(str_uid3).unwrap();
+
+    db.merge(tpk.clone()).unwrap();
+    check_log_entry(log_path, &fpr);
+    db.set_email_published(&fpr, &email1).unwrap();
+    db.set_email_published(&fpr, &email2).unwrap();
+    db.set_email_published(&fpr, &email3).unwrap();
+
+    assert!(db.ban_email("foo bar", "spam").is_err());
+    assert!(db.ban_email("", "spam").is_err());
+
+    // Addresses are normalized.
+    let result = db.ban_email("Test_A@Example.COM", "spam").unwrap();
+    assert_eq!(result.unpublished, vec![email1.clone()]);
+    assert!(result.held.is_empty());
+    assert!(db.is_email_banned(&email1));
+    assert!(!db.is_email_banned(&email2));
+    check_mail_none(db, &email1);
+    check_mail_some(db, &email2);
+    assert!(db.set_email_published(&fpr, &email1).is_err());
+
+    // Domains include their subdomains.
+    db.ban_email("example.org", "spam").unwrap();
+    assert!(db.is_email_banned(&email2));
+    assert!(!db.is_email_banned(&email3));
+    assert!(!db.is_email_banned(&Email::from_str("foo@notexample.org").unwrap()));
+    check_mail_none(db, &email2);
+    check_mail_some(db, &email3);
+
+    // The key itself stays published.
+    assert!(db.by_fpr(&fpr).is_some());
+
+    // Re-uploads don't bring the addresses back.
+    db.merge(tpk).unwrap();
+    check_mail_none(db, &email1);
+    check_mail_none(db, &email2);
+    check_mail_some(db, &email3);
+}
+
+pub fn test_quarantine(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let tpk1 = CertBuilder::new()
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr1 = Fingerprint::try_from(tpk1.fingerprint()).unwrap();
+    let tpk2 = CertBuilder::new()
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr2 = Fingerprint::try_from(tpk2.fingerprint()).unwrap();
+
+    assert!(db.quarantined().is_empty());
+    db.write_to_quarantine(&fpr1, &tpk_to_string(&tpk1).unwrap(), Some("c0ffee"))
+        .unwrap();
+    db.write_to_quarantine(&fpr2, &tpk_to_string(&tpk2).unwrap(), None)
+        .unwrap();
+    let mut expected = vec![fpr1.clone(), fpr2.clone()];
+    expected.sort_by_key(|fpr| fpr.to_string());
+    assert_eq!(db.quarantined(), expected);
+    assert!(db.by_fpr_quarantined(&fpr1).is_some());
+    assert_eq!(db.quarantine_uploader(&fpr1).as_deref(), Some("c0ffee"));
+    assert!(db.quarantine_uploader(&fpr2).is_none());
+
+    // Quarantined keys aren't published.
+    assert!(db.by_fpr(&fpr1).is_none());
+
+    db.approve_quarantined(&fpr1).unwrap();
+    check_log_entry(log_path, &fpr1);
+    assert!(db.by_fpr(&fpr1).is_some());
+    assert!(db.quarantine_uploader(&fpr1).is_none());
+    assert_eq!(db.quarantined(), vec![fpr2.clone()]);
+
+    db.reject_quarantined(&fpr2).unwrap();
+    assert!(db.by_fpr(&fpr2).is_none());
+    assert!(db.quarantined().is_empty());
+
+    assert!(db.approve_quarantined(&fpr2).is_err());
+    assert!(db.reject_quarantined(&fpr2).is_err());
+}
+
+pub fn test_legal_hold(db: &mut impl Database) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let tpk1 = CertBuilder::new()
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr1 = Fingerprint::try_from(tpk1.fingerprint()).unwrap();
+    db.merge(tpk1).unwrap();
+
+    assert!(db.legal_holds().is_empty());
+    assert!(db.legal_hold(&fpr1).is_none());
+    assert!(db.release_legal_hold(&fpr1).is_err());
+
+    db.place_legal_hold(&fpr1, "court order").unwrap();
+    assert_eq!(db.legal_holds(), vec![fpr1.clone()]);
+    assert_eq!(db.legal_hold(&fpr1).unwrap().reason, "court order");
+
+    // Held keys are not compacted.
+    let result = db.compact(&fpr1).unwrap();
+    assert!(result.held);
+    assert!(!result.changed);
+
+    // Nor banned.
+    let err = db.ban(&fpr1, "spam", None).unwrap_err();
+    assert!(err.downcast_ref::<HeldKey>().is_some());
+    assert!(db.by_fpr(&fpr1).is_some());
+    assert!(db.tombstone(&fpr1).is_none());
+
+    // Nor are its addresses unpublished by bans.
+    let email1 = Email::from_str(str_uid1).unwrap();
+    db.set_email_published(&fpr1, &email1).unwrap();
+    let result = db.ban_email("example.com", "spam").unwrap();
+    assert_eq!(result.held, vec![email1.clone()]);
+    assert!(result.unpublished.is_empty());
+    check_mail_some(db, &email1);
+
+    // Quarantined keys can be held by their primary fingerprint.
+    let fpr2: Fingerprint = "CBCD8F030588653EEDD7E2659B7DD433F254904A".parse().unwrap();
+    db.write_to_quarantine(&fpr2, b"key", None).unwrap();
+    db.place_legal_hold(&fpr2, "court order").unwrap();
+    let err = db.reject_quarantined(&fpr2).unwrap_err();
+    assert!(err.downcast_ref::<HeldKey>().is_some());
+    assert!(db.by_fpr_quarantined(&fpr2).is_some());
+    db.release_legal_hold(&fpr2).unwrap();
+    db.reject_quarantined(&fpr2).unwrap();
+
+    db.release_legal_hold(&fpr1).unwrap();
+    assert!(db.legal_holds().is_empty());
+    assert!(db.legal_hold(&fpr1).is_none());
+    assert!(!db.compact(&fpr1).unwrap().held);
+}
+
+pub fn test_transparency_log(db: &mut impl Database, log_path: &Path) {
+    let str_uid1 = "Test A <test_a@example.com>";
+    let tpk1 = CertBuilder::new()
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr1 = Fingerprint::try_from(tpk1.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+    let tpk2 = CertBuilder::new().generate().unwrap().0;
+    let fpr2 = Fingerprint::try_from(tpk2.fingerprint()).unwrap();
+
+    assert_eq!(db.transparency_tree_head().tree_size, 0);
+    assert!(db.transparency_inclusion_proof(&fpr1, None).is_none());
+
+    db.merge(tpk1.clone()).unwrap();
+    check_log_entry(log_path, &fpr1);
+    db.set_email_published(&fpr1, &email1).unwrap();
+    db.merge(tpk2).unwrap_err();
+    db.ban(&fpr1, "spam", None).unwrap();
+
+    // Upload, verification, and removal.
+    let head = db.transparency_tree_head();
+    assert_eq!(head.tree_size, 3);
+    let leaves = db.transparency_log().leaves().to_vec();
+    assert!(leaves.iter().all(|leaf| leaf.contains(&fpr1.to_string())));
+    assert_ne!(leaves[0], leaves[1]);
+    assert!(leaves[2].ends_with(" -"));
+
+    let proof = db.transparency_inclusion_proof(&fpr1, None).unwrap();
+    assert_eq!(proof.leaf_index, 2);
+    assert_eq!(proof.tree_size, 3);
+    let path: Vec<transparency::Hash> = proof
+        .audit_path
+        .iter()
+        .map(|hash| {
+            let mut h = [0; 32];
+            h.copy_from_slice(&hex::decode(hash).unwrap());
+            h
+        })
+        .collect();
+    let mut root = [0; 32];
+    root.copy_from_slice(&hex::decode(&head.root_hash).unwrap());
+    assert!(transparency::verify_inclusion(
+        proof.leaf_index,
+        proof.tree_size,
+        &transparency::leaf_hash(proof.leaf.as_bytes()),
+        &path,
+        &root
+    ));
+
+    // Proofs against older tree heads.
+    let proof = db.transparency_inclusion_proof(&fpr1, Some(1)).unwrap();
+    assert_eq!(proof.leaf_index, 0);
+    assert!(proof.audit_path.is_empty());
+    assert!(db.transparency_inclusion_proof(&fpr1, Some(4)).is_none());
+    assert!(db.transparency_inclusion_proof(&fpr2, None).is_none());
+
+    // Proofs for earlier events, by leaf hash.
+    let leaf_hash = hex::encode(transparency::leaf_hash(leaves[1].as_bytes()));
+    let proof = db
+        .transparency_inclusion_proof_by_hash(&fpr1, &leaf_hash, None)
+        .unwrap();
+    assert_eq!(proof.leaf_index, 1);
+    assert_eq!(proof.leaf, leaves[1]);
+    assert!(db
+        .transparency_inclusion_proof_by_hash(&fpr2, &leaf_hash, None)
+        .is_none());
+    assert!(db
+        .transparency_inclusion_proof_by_hash(&fpr1, &leaf_hash, Some(1))
+        .is_none());
+
+    // The log only grows.
+    let hash = |leaves: &[String]| {
+        let leaf_hashes: Vec<_> = leaves
+            .iter()
+            .map(|leaf| transparency::leaf_hash(leaf.as_bytes()))
+            .collect();
+        transparency::tree_hash(&leaf_hashes)
+    };
+    let proof = db.transparency_consistency_proof(1, 3).unwrap();
+    let path: Vec<transparency::Hash> = proof
+        .consistency
+        .iter()
+        .map(|hash| {
+            let mut h = [0; 32];
+            h.copy_from_slice(&hex::decode(hash).unwrap());
+            h
+        })
+        .collect();
+    assert!(transparency::verify_consistency(
+        1,
+        3,
+        &hash(&leaves[..1]),
+        &root,
+        &path
+    ));
+    assert!(db
+        .transparency_consistency_proof(3, 3)
+        .unwrap()
+        .consistency
+        .is_empty());
+    assert!(db.transparency_consistency_proof(2, 1).is_none());
+    assert!(db.transparency_consistency_proof(1, 4).is_none());
+}
+
+pub fn test_no_selfsig(db: &mut impl Database, log_path: &Path) {
+    let (mut tpk, revocation) = CertBuilder::new().generate().unwrap();
+    let fpr = Fingerprint::try_from(tpk.fingerprint()).unwrap();
+
+    // don't allow upload of naked key
+    assert!(db.merge(tpk.clone()).is_err());
+
+    // with revocation, it's ok
+    tpk = tpk.insert_packets(revocation).unwrap();
+    let tpk_status = db.merge(tpk).unwrap().into_tpk_status();
+    check_log_entry(log_path, &fpr);
+    assert_eq!(
+        TpkStatus {
+            is_revoked: true,
+            email_status: vec!(),
+            unparsed_uids: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
+        },
+        tpk_status
+    );
+}
+
+/// Makes sure that attested key signatures are correctly handled.
+pub fn attested_key_signatures(db: &mut impl Database, log_path: &Path) -> Result<()> {
+    use openpgp::types::*;
+    use std::time::{Duration, SystemTime};
+    let t0 = SystemTime::now() - Duration::new(5 * 60, 0);
+    let t1 = SystemTime::now() - Duration::new(4 * 60, 0);
+
+    let (alice, _) = CertBuilder::new()
+        .set_creation_time(t0)
+        .add_userid("alice@foo.com")
+        .generate()?;
+    let mut alice_signer = alice
+        .primary_key()
+        .key()
+        .clone()
+        .parts_into_secret()?
+        .into_keypair()?;
+
+    let (bob, _) = CertBuilder::new()
+        .set_creation_time(t0)
+        .add_userid("bob@bar.com")
+        .generate()?;
+    let bobs_fp = Fingerprint::try_from(bob.fingerprint())?;
+    let mut bob_signer = bob
+        .primary_key()
+        .key()
+        .clone()
+        .parts_into_secret()?
+        .into_keypair()?;
+
+    // Have Alice certify the binding between "bob@bar.com" and
+    // Bob's key.
+    let alice_certifies_bob = bob.userids().next().unwrap().userid().bind(
+        &mut alice_signer,
+        &bob,
+        SignatureBuilder::new(SignatureType::GenericCertification)
+            .set_signature_creation_time(t1)?,
+    )?;
+
+    // Have Bob attest that certification.
+    let attestations = bob.userids().next().unwrap().attest_certifications(
+        &POLICY,
+        &mut bob_signer,
+        vec![&alice_certifies_bob],
+    )?;
+    assert_eq!(attestations.len(), 1);
+    let attestation = attestations[0].clone();
+
+    // Now for the test.  First, import Bob's cert as is.
+    db.merge(bob.clone())?;
+    check_log_entry(log_path, &bobs_fp);
+
+    // Confirm the email so that we can inspect the userid component.
+    db.set_email_published(&bobs_fp, &Email::from_str("bob@bar.com")?)?;
+
+    // Then, add the certification, merge into the db, check that the
+    // certification is stripped.
+    let bob = bob.insert_packets(vec![alice_certifies_bob.clone()])?;
+    db.merge(bob.clone())?;
+    check_log_entry(log_path, &bobs_fp);
+    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
+    assert_eq!(bob_.bad_signatures().count(), 0);
+    assert_eq!(bob_.userids().next().unwrap().certifications().count(), 0);
+
+    // Add the attestation, merge into the db, check that the
+    // certification is now included.
+    let bob_attested = bob.clone().insert_packets(vec![attestation])?;
+    db.merge(bob_attested.clone())?;
+    check_log_entry(log_path, &bobs_fp);
+    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
+    assert_eq!(bob_.bad_signatures().count(), 0);
+    assert_eq!(bob_.userids().next().unwrap().certifications().count(), 1);
+    assert_eq!(
+        bob_.with_policy(&POLICY, None)?
+            .userids()
+            .next()
+            .unwrap()
+            .attestation_key_signatures()
+            .count(),
+        1
+    );
+    assert_eq!(
+        bob_.with_policy(&POLICY, None)?
+            .userids()
+            .next()
+            .unwrap()
+            .attested_certifications()
+            .count(),
+        1
+    );
+
+    // Make a random merge with Bob's unattested cert, demonstrating
+    // that the attestation still works.
+    db.merge(bob.clone())?;
+    check_log_entry(log_path, &bobs_fp);
+    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
+    assert_eq!(bob_.bad_signatures().count(), 0);
+    assert_eq!(bob_.userids().next().unwrap().certifications().count(), 1);
+
+    // Finally, withdraw consent by overriding the attestation, merge
+    // into the db, check that the certification is now gone.
+    let attestations = bob_attested
+        .userids()
+        .next()
+        .unwrap()
+        .attest_certifications(&POLICY, &mut bob_signer, &[])?;
+    assert_eq!(attestations.len(), 1);
+    let clear_attestation = attestations[0].clone();
+
+    let bob = bob.insert_packets(vec![clear_attestation])?;
+    assert_eq!(bob.userids().next().unwrap().certifications().count(), 1);
+    assert_eq!(
+        bob.with_policy(&POLICY, None)?
+            .userids()
+            .next()
+            .unwrap()
+            .attestation_key_signatures()
+            .count(),
+        1
+    );
+    assert_eq!(
+        bob.with_policy(&POLICY, None)?
+            .userids()
+            .next()
+            .unwrap()
+            .attested_certifications()
+            .count(),
+        0
+    );
+
+    db.merge(bob)?;
+    check_log_entry(log_path, &bobs_fp);
+    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
+    assert_eq!(bob_.bad_signatures().count(), 0);
+    assert_eq!(bob_.userids().next().unwrap().certifications().count(), 0);
+    assert_eq!(
+        bob_.with_policy(&POLICY, None)?
+            .userids()
+            .next()
+            .unwrap()
+            .attestation_key_signatures()
+            .count(),
+        1
+    );
+    assert_eq!(
+        bob_.with_policy(&POLICY, None)?
+            .userids()
+            .next()
+            .unwrap()
             .attested_certifications()
             .count(),
         0
@@ -1456,6 +2571,102 @@ pub fn attested_key_signatures(db: &mut impl Database, log_path: &Path) -> Resul
     Ok(())
 }
 
+/// Makes sure that owners can opt into third-party certifications.
+pub fn retained_certifications(db: &mut impl Database, log_path: &Path) -> Result<()> {
+    use openpgp::crypto::KeyPair;
+    use std::time::{Duration, SystemTime};
+    let t0 = SystemTime::now() - Duration::new(5 * 60, 0);
+    let t1 = SystemTime::now() - Duration::new(4 * 60, 0);
+    let t2 = SystemTime::now() - Duration::new(3 * 60, 0);
+    let t3 = SystemTime::now() - Duration::new(2 * 60, 0);
+
+    let certifier = |uid: &str| -> Result<_> {
+        let (cert, _) = CertBuilder::new()
+            .set_creation_time(t0)
+            .add_userid(uid)
+            .generate()?;
+        let signer = cert
+            .primary_key()
+            .key()
+            .clone()
+            .parts_into_secret()?
+            .into_keypair()?;
+        Ok((cert, signer))
+    };
+    let (alice, mut alice_signer) = certifier("alice@foo.com")?;
+    let (carol, mut carol_signer) = certifier("carol@baz.com")?;
+    let (bob, _) = certifier("bob@bar.com")?;
+    let alices_fp = Fingerprint::try_from(alice.fingerprint())?;
+    let carols_fp = Fingerprint::try_from(carol.fingerprint())?;
+    let bobs_fp = Fingerprint::try_from(bob.fingerprint())?;
+
+    let certify = |signer: &mut KeyPair, time: SystemTime| {
+        bob.userids().next().unwrap().userid().bind(
+            signer,
+            &bob,
+            SignatureBuilder::new(SignatureType::GenericCertification)
+                .set_signature_creation_time(time)?,
+        )
+    };
+
+    // Have Alice certify Bob twice, and Carol once.
+    let certifications = vec![
+        certify(&mut alice_signer, t1)?,
+        certify(&mut alice_signer, t2)?,
+        certify(&mut carol_signer, t1)?,
+    ];
+    let later_certification = certify(&mut alice_signer, t3)?;
+
+    // Only Alice's key is known.
+    db.merge(alice)?;
+    check_log_entry(log_path, &alices_fp);
+    db.merge(bob.clone().insert_packets(certifications)?)?;
+    check_log_entry(log_path, &bobs_fp);
+    db.set_email_published(&bobs_fp, &Email::from_str("bob@bar.com")?)?;
+    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
+    assert_eq!(bob_.userids().next().unwrap().certifications().count(), 0);
+    assert!(db.retained_certifiers(&bobs_fp).is_empty());
+
+    // Opting into Alice publishes her newest certification.
+    assert_eq!(
+        db.set_retained_certifiers(&bobs_fp, vec![alices_fp.clone()])?,
+        1
+    );
+    check_log_entry(log_path, &bobs_fp);
+    assert_eq!(db.retained_certifiers(&bobs_fp), vec![alices_fp.clone()]);
+    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
+    let certifications: Vec<_> = bob_.userids().next().unwrap().certifications().collect();
+    assert_eq!(certifications.len(), 1);
+    assert!(certifications[0].signature_creation_time().unwrap() > t1);
+
+    // Carol's certification can't be verified without her key.
+    assert_eq!(
+        db.set_retained_certifiers(&bobs_fp, vec![alices_fp.clone(), carols_fp.clone()])?,
+        1
+    );
+
+    // The choice is kept across merges.
+    db.merge(carol)?;
+    check_log_entry(log_path, &carols_fp);
+    db.merge(bob.clone().insert_packets(vec![later_certification])?)?;
+    check_log_entry(log_path, &bobs_fp);
+    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
+    let certifications: Vec<_> = bob_.userids().next().unwrap().certifications().collect();
+    assert_eq!(certifications.len(), 2);
+    assert!(certifications
+        .iter()
+        .any(|s| s.signature_creation_time().unwrap() > t2));
+
+    // Withdrawing consent drops the certifications.
+    assert_eq!(db.set_retained_certifiers(&bobs_fp, vec![])?, 0);
+    assert!(db.retained_certifiers(&bobs_fp).is_empty());
+    let bob_ = Cert::from_bytes(&db.by_fpr(&bobs_fp).unwrap())?;
+    assert_eq!(bob_.userids().next().unwrap().certifications().count(), 0);
+    assert_eq!(bob_.userids().count(), 1);
+
+    Ok(())
+}
+
 fn check_log_entry(log_path: &Path, fpr: &Fingerprint) {
     let log_data = fs::read_to_string(log_path).unwrap();
     let last_entry = log_data.lines().last().unwrap().split(' ').last().unwrap();
@@ -1504,3 +2715,46 @@ pub fn nonexportable_sigs(db: &mut impl Database, _log_path: &Path) -> Result<()
 
     Ok(())
 }
+
+pub fn test_unpublish_if_expired(db: &mut impl Database) {
+    use std::time::{Duration, SystemTime};
+    let day = Duration::from_secs(24 * 60 * 60);
+
+    let str_uid1 = "Test A <test_a@example.com>";
+    let tpk1 = CertBuilder::new()
+        .set_creation_time(SystemTime::now() - 3 * day)
+        .set_validity_period(day)
+        .add_userid(str_uid1)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr1 = Fingerprint::try_from(tpk1.fingerprint()).unwrap();
+    let email1 = Email::from_str(str_uid1).unwrap();
+    let str_uid2 = "Test B <test_b@example.com>";
+    let tpk2 = CertBuilder::new()
+        .add_userid(str_uid2)
+        .generate()
+        .unwrap()
+        .0;
+    let fpr2 = Fingerprint::try_from(tpk2.fingerprint()).unwrap();
+    let email2 = Email::from_str(str_uid2).unwrap();
+
+    db.merge(tpk1).unwrap();
+    db.merge(tpk2).unwrap();
+    db.set_email_published(&fpr1, &email1).unwrap();
+    db.set_email_published(&fpr2, &email2).unwrap();
+
+    // Within the grace period.
+    assert!(!db.unpublish_if_expired(&fpr1, 7 * day).unwrap());
+    check_mail_some(db, &email1);
+
+    assert!(db.unpublish_if_expired(&fpr1, day).unwrap());
+    check_mail_none(db, &email1);
+    assert!(db.by_fpr(&fpr1).is_some());
+    assert!(!db.unpublish_if_expired(&fpr1, day).unwrap());
+
+    assert!(!db
+        .unpublish_if_expired(&fpr2, Duration::from_secs(0))
+        .unwrap());
+    check_mail_some(db, &email2);
+}
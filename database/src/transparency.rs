@@ -0,0 +1,492 @@
+//! A Merkle tree over all publication events, as in Certificate
+//! Transparency (RFC 6962, section 2.1).
+//!
+//! Every time the published variant of a key changes, we append a
+//! leaf of the form `<timestamp> <fingerprint> <key hash>`, where the
+//! key hash is the SHA256 of the published armored key, or `-` if the
+//! key was removed.  Signed tree heads and inclusion proofs let third
+//! parties check that everyone is served the same history, and
+//! consistency proofs that the log is only ever appended to.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::openpgp::types::HashAlgorithm;
+use serde::{Deserialize, Serialize};
+
+pub type Hash = [u8; 32];
+
+fn sha256(parts: &[&[u8]]) -> Hash {
+    let mut digest = [0; 32];
+    let mut ctx = HashAlgorithm::SHA256
+        .context()
+        .expect("must be implemented");
+    for part in parts {
+        ctx.update(part);
+    }
+    let _ = ctx.digest(&mut digest);
+    digest
+}
+
+/// Returns the SHA256 of a published key, in hex.
+pub fn key_hash(armored: &[u8]) -> String {
+    hex::encode(sha256(&[armored]))
+}
+
+pub fn leaf_hash(leaf: &[u8]) -> Hash {
+    sha256(&[&[0], leaf])
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    sha256(&[&[1], left, right])
+}
+
+/// The largest power of two smaller than `n`, which must be at
+/// least 2.
+fn split_point(n: usize) -> usize {
+    let mut k = 1;
+    while k << 1 < n {
+        k <<= 1;
+    }
+    k
+}
+
+/// Computes the Merkle tree hash over the given leaf hashes.
+pub fn tree_hash(leaf_hashes: &[Hash]) -> Hash {
+    Subtrees::from_leaf_hashes(leaf_hashes).hash(0, leaf_hashes.len())
+}
+
+/// Computes the audit path for the leaf at `index` in the tree over
+/// the given leaf hashes.
+pub fn inclusion_proof(index: usize, leaf_hashes: &[Hash]) -> Vec<Hash> {
+    Subtrees::from_leaf_hashes(leaf_hashes).inclusion_proof(index, leaf_hashes.len())
+}
+
+/// The hashes of all complete subtrees of a growing tree.
+///
+/// Every other subtree is made up of O(log n) complete ones, so tree
+/// heads and proofs don't need to rehash the leaves.
+#[derive(Debug, Default)]
+struct Subtrees {
+    /// `levels[h][i]` is the hash of the complete subtree over the
+    /// leaves `i << h` up to `(i + 1) << h`.
+    levels: Vec<Vec<Hash>>,
+}
+
+impl Subtrees {
+    fn from_leaf_hashes(leaf_hashes: &[Hash]) -> Self {
+        let mut subtrees = Subtrees::default();
+        for leaf_hash in leaf_hashes {
+            subtrees.push(*leaf_hash);
+        }
+        subtrees
+    }
+
+    fn len(&self) -> usize {
+        self.levels.first().map_or(0, Vec::len)
+    }
+
+    /// Appends a leaf, and completes the subtrees it closes.
+    fn push(&mut self, leaf_hash: Hash) {
+        let mut hash = leaf_hash;
+        for level in 0.. {
+            if self.levels.len() == level {
+                self.levels.push(vec![]);
+            }
+            let hashes = &mut self.levels[level];
+            hashes.push(hash);
+            let n = hashes.len();
+            if n % 2 == 1 {
+                break;
+            }
+            hash = node_hash(&hashes[n - 2], &hashes[n - 1]);
+        }
+    }
+
+    /// Computes the hash of the subtree over the leaves `start` up to
+    /// `end`.
+    fn hash(&self, start: usize, end: usize) -> Hash {
+        let n = end - start;
+        if n == 0 {
+            return sha256(&[]);
+        }
+        if n.is_power_of_two() && start % n == 0 {
+            let level = n.trailing_zeros() as usize;
+            return self.levels[level][start >> level];
+        }
+        let k = split_point(n);
+        node_hash(&self.hash(start, start + k), &self.hash(start + k, end))
+    }
+
+    /// Computes the audit path for the leaf at `index` in the tree of
+    /// the given size.
+    fn inclusion_proof(&self, index: usize, tree_size: usize) -> Vec<Hash> {
+        if tree_size <= 1 || index >= tree_size || tree_size > self.len() {
+            return vec![];
+        }
+        self.path(index, 0, tree_size)
+    }
+
+    /// Computes the proof that the tree of size `m` is a prefix of
+    /// the tree of the given size.
+    fn consistency_proof(&self, m: usize, tree_size: usize) -> Vec<Hash> {
+        if m == 0 || m > tree_size || tree_size > self.len() {
+            return vec![];
+        }
+        self.subproof(m, 0, tree_size, true)
+    }
+
+    fn subproof(&self, m: usize, start: usize, end: usize, complete: bool) -> Vec<Hash> {
+        let n = end - start;
+        if m == n {
+            return if complete {
+                vec![]
+            } else {
+                vec![self.hash(start, end)]
+            };
+        }
+        let k = split_point(n);
+        if m <= k {
+            let mut proof = self.subproof(m, start, start + k, complete);
+            proof.push(self.hash(start + k, end));
+            proof
+        } else {
+            let mut proof = self.subproof(m - k, start + k, end, false);
+            proof.push(self.hash(start, start + k));
+            proof
+        }
+    }
+
+    fn path(&self, index: usize, start: usize, end: usize) -> Vec<Hash> {
+        let n = end - start;
+        if n <= 1 {
+            return vec![];
+        }
+        let k = split_point(n);
+        if index < start + k {
+            let mut path = self.path(index, start, start + k);
+            path.push(self.hash(start + k, end));
+            path
+        } else {
+            let mut path = self.path(index, start + k, end);
+            path.push(self.hash(start, start + k));
+            path
+        }
+    }
+}
+
+/// The leaves of the log, read from its file as it grows.
+///
+/// Reading only what was appended, and keeping the hashes of complete
+/// subtrees and an index of the leaves, keeps tree heads and proofs
+/// cheap to answer.
+#[derive(Debug, Default)]
+pub struct Log {
+    leaves: Vec<String>,
+    subtrees: Subtrees,
+    /// The indices of the leaves of each fingerprint, ascending.
+    by_fingerprint: HashMap<String, Vec<usize>>,
+    /// The indices of the leaves with each hash, ascending.
+    by_hash: HashMap<Hash, Vec<usize>>,
+    /// How much of the file was read.
+    read_len: u64,
+}
+
+impl Log {
+    /// Reads the leaves appended to the log file since the last call.
+    ///
+    /// A leaf that is still being written is left for the next call.
+    pub fn read_appended(&mut self, mut file: impl Read + Seek) -> io::Result<()> {
+        file.seek(SeekFrom::Start(self.read_len))?;
+        let mut appended = String::new();
+        file.read_to_string(&mut appended)?;
+        let complete = appended.rfind('\n').map_or(0, |end| end + 1);
+        for leaf in appended[..complete].lines() {
+            self.push(leaf.to_owned());
+        }
+        self.read_len += complete as u64;
+        Ok(())
+    }
+
+    fn push(&mut self, leaf: String) {
+        let hash = leaf_hash(leaf.as_bytes());
+        self.subtrees.push(hash);
+        self.by_hash
+            .entry(hash)
+            .or_default()
+            .push(self.leaves.len());
+        if let Some(fpr) = leaf.split(' ').nth(1) {
+            self.by_fingerprint
+                .entry(fpr.to_owned())
+                .or_default()
+                .push(self.leaves.len());
+        }
+        self.leaves.push(leaf);
+    }
+
+    pub fn leaves(&self) -> &[String] {
+        &self.leaves
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn leaf_hash(&self, index: usize) -> Option<Hash> {
+        self.subtrees.levels.first()?.get(index).copied()
+    }
+
+    /// Returns the indices of the leaves of the given fingerprint in
+    /// the tree of the given size, ascending.
+    pub fn indices_of(&self, fpr: &str, tree_size: usize) -> &[usize] {
+        indices_before(self.by_fingerprint.get(fpr), tree_size)
+    }
+
+    /// Returns the indices of the leaves with the given hash in the
+    /// tree of the given size, ascending.
+    pub fn indices_of_hash(&self, hash: &Hash, tree_size: usize) -> &[usize] {
+        indices_before(self.by_hash.get(hash), tree_size)
+    }
+
+    /// Computes the root hash of the tree of the given size, which
+    /// must be at most the current size.
+    pub fn root_hash(&self, tree_size: usize) -> Hash {
+        self.subtrees.hash(0, tree_size)
+    }
+
+    /// Computes the audit path for the leaf at `index` in the tree of
+    /// the given size.
+    pub fn inclusion_proof(&self, index: usize, tree_size: usize) -> Vec<Hash> {
+        self.subtrees.inclusion_proof(index, tree_size)
+    }
+
+    /// Computes the proof that the tree of size `m` is a prefix of
+    /// the tree of the given size.
+    pub fn consistency_proof(&self, m: usize, tree_size: usize) -> Vec<Hash> {
+        self.subtrees.consistency_proof(m, tree_size)
+    }
+}
+
+/// Returns the ascending indices that are below `end`.
+fn indices_before(indices: Option<&Vec<usize>>, end: usize) -> &[usize] {
+    let indices = indices.map_or(&[][..], Vec::as_slice);
+    &indices[..indices.partition_point(|&index| index < end)]
+}
+
+/// Checks an audit path, as a client would (RFC 9162, section
+/// 2.1.3.2).
+pub fn verify_inclusion(
+    index: usize,
+    tree_size: usize,
+    leaf_hash: &Hash,
+    path: &[Hash],
+    root_hash: &Hash,
+) -> bool {
+    if index >= tree_size {
+        return false;
+    }
+    let (mut fn_, mut sn) = (index, tree_size - 1);
+    let mut r = *leaf_hash;
+    for p in path {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            r = node_hash(p, &r);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            r = node_hash(&r, p);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    sn == 0 && r == *root_hash
+}
+
+/// Computes the proof that the tree over the first `m` leaf hashes is
+/// a prefix of the tree over all of them (RFC 6962, section 2.1.2).
+pub fn consistency_proof(m: usize, leaf_hashes: &[Hash]) -> Vec<Hash> {
+    Subtrees::from_leaf_hashes(leaf_hashes).consistency_proof(m, leaf_hashes.len())
+}
+
+/// Checks a consistency proof between two tree heads, as a client
+/// would (RFC 9162, section 2.1.4.2).
+pub fn verify_consistency(
+    first: usize,
+    second: usize,
+    first_hash: &Hash,
+    second_hash: &Hash,
+    proof: &[Hash],
+) -> bool {
+    if first > second {
+        return false;
+    }
+    if first == second {
+        return proof.is_empty() && first_hash == second_hash;
+    }
+    if first == 0 {
+        // Every tree extends the empty one.
+        return proof.is_empty();
+    }
+
+    let mut path = proof.to_vec();
+    if first.is_power_of_two() {
+        path.insert(0, *first_hash);
+    }
+    if path.is_empty() {
+        return false;
+    }
+    let (mut fn_, mut sn) = (first - 1, second - 1);
+    while fn_ & 1 == 1 {
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    let (mut fr, mut sr) = (path[0], path[0]);
+    for c in &path[1..] {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            fr = node_hash(c, &fr);
+            sr = node_hash(c, &sr);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            sr = node_hash(&sr, c);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    sn == 0 && fr == *first_hash && sr == *second_hash
+}
+
+/// The head of the tree at some point in time.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TreeHead {
+    pub tree_size: usize,
+    pub root_hash: String,
+    pub timestamp: i64,
+}
+
+impl TreeHead {
+    /// The message that is signed to produce a signed tree head.
+    pub fn to_signed_message(&self) -> String {
+        format!(
+            "hagrid tree head v1\n{}\n{}\n{}\n",
+            self.tree_size, self.root_hash, self.timestamp
+        )
+    }
+}
+
+/// Proves that a leaf is included in the tree of the given size.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InclusionProof {
+    pub leaf: String,
+    pub leaf_index: usize,
+    pub tree_size: usize,
+    pub audit_path: Vec<String>,
+}
+
+/// Proves that the tree of size `first` is a prefix of the tree of
+/// size `second`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConsistencyProof {
+    pub first: usize,
+    pub second: usize,
+    pub consistency: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(n: usize) -> Vec<Hash> {
+        (0..n)
+            .map(|i| leaf_hash(format!("leaf {}", i).as_bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn empty_tree() {
+        // SHA256 of the empty string.
+        assert_eq!(
+            hex::encode(tree_hash(&[])),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn tree_shape() {
+        let l = leaves(3);
+        assert_eq!(tree_hash(&l[..1]), l[0]);
+        assert_eq!(tree_hash(&l), node_hash(&node_hash(&l[0], &l[1]), &l[2]));
+    }
+
+    #[test]
+    fn inclusion_proofs_verify() {
+        for n in 1..20 {
+            let l = leaves(n);
+            let root = tree_hash(&l);
+            for i in 0..n {
+                let path = inclusion_proof(i, &l);
+                assert!(verify_inclusion(i, n, &l[i], &path, &root));
+                if n > 1 {
+                    let other = (i + 1) % n;
+                    assert!(!verify_inclusion(other, n, &l[i], &path, &root));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn log_reads_appended_leaves() {
+        let mut file = io::Cursor::new(b"leaf 0\nleaf 1\nleaf".to_vec());
+        let mut log = Log::default();
+        log.read_appended(&mut file).unwrap();
+        assert_eq!(log.leaves(), &["leaf 0", "leaf 1"]);
+
+        file.get_mut().extend_from_slice(b" 2\nleaf 3\n");
+        log.read_appended(&mut file).unwrap();
+        assert_eq!(log.len(), 4);
+        let l = leaves(4);
+        assert_eq!(log.root_hash(4), tree_hash(&l));
+        assert_eq!(log.root_hash(3), tree_hash(&l[..3]));
+        assert_eq!(log.indices_of("0", 4), &[0]);
+        assert_eq!(log.indices_of("3", 3), &[] as &[usize]);
+        assert_eq!(log.indices_of_hash(&l[2], 4), &[2]);
+        assert_eq!(log.indices_of_hash(&l[3], 3), &[] as &[usize]);
+        for (i, leaf_hash) in l.iter().enumerate() {
+            assert_eq!(log.leaf_hash(i), Some(*leaf_hash));
+            assert_eq!(log.inclusion_proof(i, 3), inclusion_proof(i, &l[..3]));
+            assert_eq!(log.consistency_proof(i, 3), consistency_proof(i, &l[..3]));
+        }
+    }
+
+    #[test]
+    fn consistency_proofs_verify() {
+        for n in 1..20 {
+            let l = leaves(n);
+            let root = tree_hash(&l);
+            for m in 1..=n {
+                let old_root = tree_hash(&l[..m]);
+                let proof = consistency_proof(m, &l);
+                assert!(verify_consistency(m, n, &old_root, &root, &proof));
+                if m < n {
+                    let forged = tree_hash(&l[1..m + 1]);
+                    assert!(!verify_consistency(m, n, &forged, &root, &proof));
+                }
+            }
+        }
+        assert!(consistency_proof(4, &leaves(3)).is_empty());
+        assert!(!verify_consistency(4, 3, &[0; 32], &[0; 32], &[]));
+    }
+}
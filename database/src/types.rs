@@ -24,6 +24,112 @@ impl Email {
     pub fn as_str(&self) -> &str {
         &self.0
     }
+
+    /// Returns whether the local part or a domain label mixes
+    /// scripts, e.g. Latin and Cyrillic letters.
+    ///
+    /// Such addresses may imitate other addresses, see
+    /// https://www.unicode.org/reports/tr39/#Mixed_Script_Detection
+    pub fn is_mixed_script(&self) -> bool {
+        let mut iter = self.0.rsplitn(2, '@');
+        let domain = iter.next().unwrap_or("");
+        let localpart = iter.next().unwrap_or("");
+        let (domain, _) = idna::domain_to_unicode(domain);
+
+        Some(localpart)
+            .into_iter()
+            .chain(domain.split('.'))
+            .any(is_mixed_script)
+    }
+}
+
+/// Scripts of letters that may be confused with each other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Script {
+    Latin,
+    Greek,
+    Cyrillic,
+    Armenian,
+    Hebrew,
+    Arabic,
+    /// Han, Hiragana, and Katakana, which are commonly mixed.
+    Cjk,
+    Hangul,
+    Georgian,
+    Cherokee,
+    Devanagari,
+    Thai,
+}
+
+impl Script {
+    /// Returns the script of a letter, or None for digits and other
+    /// characters used with any script, and for letters of scripts
+    /// not listed here.
+    ///
+    /// The ranges cover all blocks of each script, see
+    /// https://www.unicode.org/Public/UCD/latest/ucd/Scripts.txt
+    fn of(c: char) -> Option<Script> {
+        if c.is_ascii() {
+            return if c.is_ascii_alphabetic() {
+                Some(Script::Latin)
+            } else {
+                None
+            };
+        }
+        if !c.is_alphabetic() {
+            return None;
+        }
+        Some(match c as u32 {
+            0x00AA | 0x00BA | 0x00C0..=0x02AF | 0x1D00..=0x1D7F => Script::Latin,
+            0x1D80..=0x1DBF | 0x1E00..=0x1EFF | 0x2C60..=0x2C7F => Script::Latin,
+            0xA720..=0xA7FF | 0xAB30..=0xAB6F => Script::Latin,
+            0xFF21..=0xFF3A | 0xFF41..=0xFF5A => Script::Latin,
+            0x0370..=0x03FF | 0x1F00..=0x1FFF => Script::Greek,
+            0x0400..=0x052F | 0x1C80..=0x1C8F => Script::Cyrillic,
+            0x2DE0..=0x2DFF | 0xA640..=0xA69F => Script::Cyrillic,
+            0x0530..=0x058F | 0xFB13..=0xFB17 => Script::Armenian,
+            0x0590..=0x05FF | 0xFB1D..=0xFB4F => Script::Hebrew,
+            0x0600..=0x06FF | 0x0750..=0x077F | 0x08A0..=0x08FF => Script::Arabic,
+            0xFB50..=0xFDFF | 0xFE70..=0xFEFF => Script::Arabic,
+            0x2E80..=0x2FDF | 0x3005..=0x3007 | 0x3021..=0x3029 => Script::Cjk,
+            0x3031..=0x3035 | 0x3038..=0x303C | 0x3040..=0x30FF => Script::Cjk,
+            0x31F0..=0x31FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF => Script::Cjk,
+            0xF900..=0xFAFF | 0xFF66..=0xFF9F | 0x1B000..=0x1B16F => Script::Cjk,
+            0x20000..=0x3134F => Script::Cjk,
+            0x1100..=0x11FF | 0x3130..=0x318F | 0xA960..=0xA97F => Script::Hangul,
+            0xAC00..=0xD7FF | 0xFFA0..=0xFFDC => Script::Hangul,
+            0x10A0..=0x10FF | 0x1C90..=0x1CBF | 0x2D00..=0x2D2F => Script::Georgian,
+            0x13A0..=0x13FF | 0xAB70..=0xABBF => Script::Cherokee,
+            0x0900..=0x097F | 0xA8E0..=0xA8FF => Script::Devanagari,
+            0x0E00..=0x0E7F => Script::Thai,
+            _ => return None,
+        })
+    }
+
+    /// Latin letters are commonly used along with CJK scripts.
+    fn mixes_with(self, other: Script) -> bool {
+        self == other
+            || (self == Script::Latin && other == Script::Cjk)
+            || (self == Script::Cjk && other == Script::Latin)
+    }
+}
+
+fn is_mixed_script(s: &str) -> bool {
+    let scripts: Vec<Script> = s.chars().filter_map(Script::of).collect();
+    scripts
+        .iter()
+        .any(|a| scripts.iter().any(|b| !a.mixes_with(*b)))
+}
+
+/// Returns whether the character is invisible, or changes how the
+/// surrounding text is displayed.
+fn is_invisible(c: char) -> bool {
+    c.is_control()
+        || match c {
+            '\u{00AD}' | '\u{200B}'..='\u{200F}' | '\u{202A}'..='\u{202E}' => true,
+            '\u{2060}'..='\u{2064}' | '\u{2066}'..='\u{2069}' | '\u{FEFF}' => true,
+            _ => false,
+        }
 }
 
 impl TryFrom<&UserID> for Email {
@@ -40,6 +146,12 @@ impl TryFrom<&UserID> for Email {
             let domain = idna::domain_to_ascii(domain)
                 .map_err(|e| anyhow!("punycode conversion failed: {:?}", e))?;
 
+            // Invisible characters would let addresses look like
+            // others, and may garble the pages they are shown on.
+            if address.chars().any(is_invisible) {
+                return Err(anyhow!("malformed email address: '{:?}'", uid.value()));
+            }
+
             // TODO this is a hotfix for a lettre vulnerability. remove once fixed upstream.
             if localpart.starts_with('-') {
                 return Err(anyhow!("malformed email address: '{:?}'", uid.value()));
@@ -124,7 +236,11 @@ impl FromStr for Fingerprint {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Fingerprint> {
-        match sequoia_openpgp::Fingerprint::from_hex(s)? {
+        let hex = normalize_hex(s);
+        if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("'{}' is not a valid fingerprint", s));
+        }
+        match sequoia_openpgp::Fingerprint::from_hex(&hex)? {
             sequoia_openpgp::Fingerprint::V4(a) => Ok(Fingerprint(a)),
             sequoia_openpgp::Fingerprint::Invalid(_) => {
                 Err(anyhow!("'{}' is not a valid fingerprint", s))
@@ -178,7 +294,11 @@ impl FromStr for KeyID {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<KeyID> {
-        match sequoia_openpgp::KeyID::from_hex(s)? {
+        let hex = normalize_hex(s);
+        if hex.len() != 16 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow!("'{}' is not a valid long key ID", s));
+        }
+        match sequoia_openpgp::KeyID::from_hex(&hex)? {
             sequoia_openpgp::KeyID::V4(a) => Ok(KeyID(a)),
             sequoia_openpgp::KeyID::Invalid(_) => {
                 Err(anyhow!("'{}' is not a valid long key ID", s))
@@ -188,10 +308,55 @@ impl FromStr for KeyID {
     }
 }
 
+/// Normalizes hex input as users tend to write it: drops a `0x`
+/// prefix and any whitespace, and upper-cases the rest.
+fn normalize_hex(s: &str) -> String {
+    let s = s.trim();
+    let s = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .unwrap_or(s);
+    s.chars()
+        .filter(|c| !c.is_whitespace())
+        .collect::<String>()
+        .to_uppercase()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn fingerprint() {
+        let expected = "CBCD8F030588653EEDD7E2659B7DD433F254904A";
+        let c = |s| Fingerprint::from_str(s).unwrap().to_string();
+        assert_eq!(c(expected), expected);
+        assert_eq!(c("cbcd8f030588653eedd7e2659b7dd433f254904a"), expected);
+        assert_eq!(c("0xCBCD8F030588653EEDD7E2659B7DD433F254904A"), expected);
+        assert_eq!(
+            c("CBCD 8F03 0588 653E EDD7  E265 9B7D D433 F254 904A"),
+            expected
+        );
+        assert_eq!(c(" 0xcbcd8F030588653EEDD7E2659B7DD433F254904A\n"), expected);
+
+        assert!(Fingerprint::from_str("CBCD8F030588653EEDD7E2659B7DD433F254904").is_err());
+        assert!(Fingerprint::from_str("XBCD8F030588653EEDD7E2659B7DD433F254904A").is_err());
+        assert!(Fingerprint::from_str("0x0xCBCD8F030588653EEDD7E2659B7DD433F254904A").is_err());
+        assert!(Fingerprint::from_str("").is_err());
+    }
+
+    #[test]
+    fn keyid() {
+        let expected = "9B7DD433F254904A";
+        let c = |s| KeyID::from_str(s).unwrap().to_string();
+        assert_eq!(c(expected), expected);
+        assert_eq!(c("0x9b7dd433f254904a"), expected);
+        assert_eq!(c("9B7D D433 F254 904A"), expected);
+
+        assert!(KeyID::from_str("F254904A").is_err());
+        assert!(KeyID::from_str("CBCD8F030588653EEDD7E2659B7DD433F254904A").is_err());
+    }
+
     #[test]
     fn email() {
         let c = |s| Email::from_str(s).unwrap();
@@ -210,6 +375,33 @@ mod tests {
         assert_eq!(c("foo@EXAMPLE.ORG").as_str(), "foo@example.org");
     }
 
+    #[test]
+    fn email_invisible() {
+        assert!(Email::from_str("foo\u{202E}gro@example.org").is_err());
+        assert!(Email::from_str("f\u{200B}oo@example.org").is_err());
+        assert!(Email::from_str("foo@exam\u{00AD}ple.org").is_err());
+    }
+
+    #[test]
+    fn email_mixed_script() {
+        let c = |s| Email::from_str(s).unwrap().is_mixed_script();
+        assert!(!c("foo@example.org"));
+        assert!(!c("foo42@example.org"));
+        assert!(!c("jürgen@example.org"));
+        assert!(!c("иван@пример.рф"));
+        assert!(!c("taro山田@example.jp"));
+        // Scripts spanning several blocks.
+        assert!(!c("nguyễn.ɓa@example.org"));
+        assert!(!c("ⱥlice.łukasz@example.org"));
+        assert!(!c("山田𠀋子.やまだ@example.jp"));
+        assert!(!c("ԁаша\u{1C80}@example.org"));
+        assert!(c("ԁаша\u{1E01}@example.org"));
+        // Cyrillic "о" in a Latin local part.
+        assert!(c("f\u{043E}o@example.org"));
+        // Greek "ο" in a Latin domain.
+        assert!(c("foo@ex\u{03BF}mple.org"));
+    }
+
     #[test]
     fn email_vuln() {
         assert!(Email::from_str("foo <-@EXAMPLE.ORG>").is_err());
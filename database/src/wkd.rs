@@ -13,6 +13,13 @@ pub fn encode_wkd(address: impl AsRef<str>) -> Result<(String, String)> {
     Ok((local_part_encoded, domain))
 }
 
+/// Whether the string is a wkd-encoded local part, i.e. a z-base-32
+/// encoded SHA-1 digest.
+pub fn is_wkd_hash(hash: &str) -> bool {
+    const ZBASE32_ALPHABET: &str = "ybndrfg8ejkmcpqxot1uwisza345h769";
+    hash.len() == 32 && hash.chars().all(|c| ZBASE32_ALPHABET.contains(c))
+}
+
 fn split_address(email_address: impl AsRef<str>) -> Result<(String, String)> {
     let email_address = email_address.as_ref();
     let v: Vec<&str> = email_address.split('@').collect();
@@ -52,6 +59,14 @@ mod tests {
         assert_eq!(32, encoded_part.len());
     }
 
+    #[test]
+    fn wkd_hash() {
+        assert!(is_wkd_hash("stnkabub89rpcphiz4ppbxixkwyt1pic"));
+        assert!(!is_wkd_hash("STNKABUB89RPCPHIZ4PPBXIXKWYT1PIC"));
+        assert!(!is_wkd_hash("stnkabub89rpcphiz4ppbxixkwyt1pi"));
+        assert!(!is_wkd_hash("../../by-fpr/00/00/00000000000000"));
+    }
+
     #[test]
     fn email_address_from() {
         let (local_part, domain) = split_address("test1@example.com").unwrap();
@@ -0,0 +1,76 @@
+use anyhow::Result;
+
+use database::types::Fingerprint;
+use database::{Database, KeyDatabase, Query};
+use HagridConfig;
+
+/// Subkey fingerprints refer to the key they belong to.  Keys we don't
+/// know can be referred to by their primary fingerprint.
+fn parse_primary_fingerprint(db: &KeyDatabase, fpr: &str) -> Result<Fingerprint> {
+    let fpr: Fingerprint = fpr.parse()?;
+    Ok(db
+        .lookup_primary_fingerprint(&Query::ByFingerprint(fpr.clone()))
+        .unwrap_or(fpr))
+}
+
+pub fn do_ban(config: &HagridConfig, fpr: &str, reason: &str, notice: Option<&str>) -> Result<()> {
+    let db = config.open_db(false)?;
+
+    let fpr = parse_primary_fingerprint(&db, fpr)?;
+
+    db.ban(&fpr, reason, notice)?;
+    println!("Banned {}", fpr);
+
+    Ok(())
+}
+
+pub fn do_ban_email(config: &HagridConfig, entry: &str, reason: &str) -> Result<()> {
+    let db = config.open_db(false)?;
+
+    let result = db.ban_email(entry, reason)?;
+    println!(
+        "Banned {} ({} addresses unpublished, {} under legal hold)",
+        entry,
+        result.unpublished.len(),
+        result.held.len()
+    );
+    for email in result.held {
+        println!("  {}", email);
+    }
+
+    Ok(())
+}
+
+pub fn do_legal_hold(config: &HagridConfig, fpr: &str, reason: &str) -> Result<()> {
+    let db = config.open_db(false)?;
+
+    let fpr = parse_primary_fingerprint(&db, fpr)?;
+
+    db.place_legal_hold(&fpr, reason)?;
+    println!("Placed {} under legal hold", fpr);
+
+    Ok(())
+}
+
+pub fn do_release_legal_hold(config: &HagridConfig, fpr: &str) -> Result<()> {
+    let db = config.open_db(false)?;
+
+    let fpr = parse_primary_fingerprint(&db, fpr)?;
+
+    db.release_legal_hold(&fpr)?;
+    println!("Released {} from legal hold", fpr);
+
+    Ok(())
+}
+
+pub fn do_list_legal_holds(config: &HagridConfig) -> Result<()> {
+    let db = config.open_db(false)?;
+
+    for fpr in db.legal_holds() {
+        if let Some(hold) = db.legal_hold(&fpr) {
+            println!("{} since {}: {}", fpr, hold.since, hold.reason);
+        }
+    }
+
+    Ok(())
+}
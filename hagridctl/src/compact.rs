@@ -0,0 +1,77 @@
+use anyhow::Result;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use walkdir::WalkDir;
+
+use database::{Database, KeyDatabase};
+use HagridConfig;
+
+/// Rewrites all published keys in their canonical form, dropping what
+/// we wouldn't publish today, and reports the space reclaimed.
+pub fn do_compact(config: &HagridConfig, dry_run: bool) -> Result<()> {
+    let db = config.open_db(dry_run)?;
+    let db = match config.max_self_signatures {
+        Some(max_self_signatures) => db.with_max_self_signatures(max_self_signatures),
+        None => db,
+    };
+
+    let published_dir = config.keys_external_dir.as_ref().unwrap().join("pub");
+    let paths: Vec<_> = WalkDir::new(published_dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .flatten()
+        .filter(|e| e.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+
+    let progress_bar = ProgressBar::new(paths.len() as u64);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {msg}")
+            .progress_chars("##-"),
+    );
+
+    let mut count_compacted = 0;
+    let mut count_held = 0;
+    let mut count_err = 0;
+    let mut size_before = 0;
+    let mut size_after = 0;
+    for path in &paths {
+        progress_bar.inc(1);
+        let fpr = match KeyDatabase::path_to_primary(path) {
+            Some(fpr) => fpr,
+            None => continue,
+        };
+        match db.compact(&fpr) {
+            Ok(result) => {
+                if result.changed {
+                    count_compacted += 1;
+                }
+                if result.held {
+                    count_held += 1;
+                }
+                size_before += result.size_before;
+                size_after += result.size_after;
+            }
+            Err(e) => {
+                progress_bar.println(format!("{}: {}", fpr, e));
+                count_err += 1;
+            }
+        }
+    }
+    progress_bar.finish();
+
+    println!(
+        "Compacted {} of {} keys ({} errors, {} under legal hold), {} bytes reclaimed ({} bytes before, {} after){}",
+        count_compacted,
+        paths.len(),
+        count_err,
+        count_held,
+        size_before.saturating_sub(size_after),
+        size_before,
+        size_after,
+        if dry_run { ", nothing written" } else { "" },
+    );
+
+    Ok(())
+}
@@ -0,0 +1,115 @@
+use anyhow::Result;
+
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use walkdir::WalkDir;
+
+use openpgp::armor;
+use openpgp::packet::signature::SignatureBuilder;
+use openpgp::parse::Parse;
+use openpgp::policy::StandardPolicy;
+use openpgp::serialize::Serialize;
+use openpgp::types::{HashAlgorithm, SignatureType};
+use openpgp::{Cert, Packet};
+
+use HagridConfig;
+
+/// Published keys per file of the dump.
+const KEYS_PER_FILE: usize = 10000;
+
+/// Lists the SHA256 of every file in the dump, in the format of
+/// sha256sum(1).
+const MANIFEST: &str = "MANIFEST";
+
+/// Writes all published keys to the output directory, along with a
+/// manifest and, if a signing key is given, a detached signature over
+/// the manifest.
+pub fn do_export(config: &HagridConfig, output_dir: &Path, sign_key: Option<&Path>) -> Result<()> {
+    // Load the key first, so that we don't find out it's unusable
+    // after writing the whole dump.
+    let sign_key = sign_key.map(load_signing_key).transpose()?;
+
+    let published_dir = config.keys_external_dir.as_ref().unwrap().join("pub");
+    let paths: Vec<_> = WalkDir::new(published_dir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .flatten()
+        .filter(|e| e.file_type().is_file())
+        .map(|entry| entry.into_path())
+        .collect();
+
+    let progress_bar = ProgressBar::new(paths.len() as u64);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {msg}")
+            .progress_chars("##-"),
+    );
+
+    fs::create_dir_all(output_dir)?;
+    let mut manifest = String::new();
+    for (i, chunk) in paths.chunks(KEYS_PER_FILE).enumerate() {
+        let name = format!("keys-{:05}.pgp", i);
+        let mut data = Vec::new();
+        for path in chunk {
+            progress_bar.inc(1);
+            data.extend(fs::read(path)?);
+        }
+        File::create(output_dir.join(&name))?.write_all(&data)?;
+        manifest.push_str(&format!("{}  {}\n", sha256_hex(&data), name));
+    }
+    progress_bar.finish();
+
+    fs::write(output_dir.join(MANIFEST), &manifest)?;
+    if let Some(key) = sign_key {
+        let signature = sign_detached(key, manifest.as_bytes())?;
+        fs::write(output_dir.join(format!("{}.asc", MANIFEST)), signature)?;
+    }
+
+    println!("Exported {} keys to {}", paths.len(), output_dir.display());
+
+    Ok(())
+}
+
+type SigningKey =
+    openpgp::packet::Key<openpgp::packet::key::SecretParts, openpgp::packet::key::UnspecifiedRole>;
+
+/// Uses the first valid, unencrypted signing key of the given
+/// certificate.
+fn load_signing_key(path: &Path) -> Result<SigningKey> {
+    let policy = StandardPolicy::new();
+    let cert = Cert::from_file(path)?;
+    let key = cert
+        .keys()
+        .with_policy(&policy, None)
+        .alive()
+        .revoked(false)
+        .for_signing()
+        .secret()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("No signing key in {}", path.display()))?
+        .key()
+        .clone();
+    Ok(key)
+}
+
+fn sign_detached(key: SigningKey, message: &[u8]) -> Result<Vec<u8>> {
+    let mut keypair = key.into_keypair()?;
+    let sig = SignatureBuilder::new(SignatureType::Binary).sign_message(&mut keypair, message)?;
+
+    let mut writer = armor::Writer::new(Vec::new(), armor::Kind::Signature)?;
+    Packet::from(sig).serialize(&mut writer)?;
+    Ok(writer.finalize()?)
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    let mut digest = [0; 32];
+    let mut ctx = HashAlgorithm::SHA256
+        .context()
+        .expect("must be implemented");
+    ctx.update(data);
+    let _ = ctx.digest(&mut digest);
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,19 @@
+use anyhow::Result;
+
+use database::{Database, Severity};
+use HagridConfig;
+
+/// Checks the database for consistency, printing every finding as a
+/// line of JSON.
+///
+/// Returns whether any finding is at least as severe as `fail_on`.
+pub fn do_fsck(config: &HagridConfig, fail_on: Severity) -> Result<bool> {
+    let db = config.open_db(false)?;
+
+    let findings = db.consistency_findings()?;
+    for finding in &findings {
+        println!("{}", serde_json::to_string(finding)?);
+    }
+
+    Ok(findings.iter().any(|finding| finding.severity >= fail_on))
+}
@@ -120,12 +120,26 @@ fn import_from_files(
     input_files: Vec<PathBuf>,
     multi_progress: Arc<MultiProgress>,
 ) -> Result<()> {
-    let db = KeyDatabase::new_internal(
-        config.keys_internal_dir.as_ref().unwrap(),
-        config.keys_external_dir.as_ref().unwrap(),
-        config.tmp_dir.as_ref().unwrap(),
-        dry_run,
-    )?;
+    let db = config.open_db(dry_run)?;
+    let db = match config.uid_policy {
+        Some(ref uid_policy) => db.with_uid_policy(uid_policy.parse()?),
+        None => db,
+    };
+    let db = match config.max_published_uids {
+        Some(max_published_uids) => db.with_max_published_uids(max_published_uids),
+        None => db,
+    };
+    let db = match config.max_self_signatures {
+        Some(max_self_signatures) => db.with_max_self_signatures(max_self_signatures),
+        None => db,
+    };
+    let db = match config.unhashed_area_limit {
+        Some(max_size) => {
+            let action = config.unhashed_area_action.as_deref().unwrap_or("strip");
+            db.with_unhashed_area_limit(max_size, action.parse()?)
+        }
+        None => db,
+    };
 
     for input_file in input_files {
         import_from_file(&db, &input_file, &multi_progress)?;
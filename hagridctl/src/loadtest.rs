@@ -0,0 +1,292 @@
+use anyhow::Result;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use rand::{thread_rng, Rng};
+
+use openpgp::cert::{CertBuilder, CertParser};
+use openpgp::parse::Parse;
+use openpgp::serialize::SerializeInto;
+use openpgp::Cert;
+
+/// Keys generated when no keyring is given.
+const GENERATED_KEYS: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+enum Operation {
+    /// `GET /pks/lookup?op=get&search=0x<fpr>`
+    Hkp,
+    /// `GET /vks/v1/by-fingerprint/<fpr>`
+    Vks,
+    /// `POST /vks/v1/upload`
+    Upload,
+}
+
+impl FromStr for Operation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "hkp" => Ok(Operation::Hkp),
+            "vks" => Ok(Operation::Vks),
+            "upload" => Ok(Operation::Upload),
+            _ => Err(anyhow::anyhow!("Unknown operation: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Operation::Hkp => "hkp",
+            Operation::Vks => "vks",
+            Operation::Upload => "upload",
+        })
+    }
+}
+
+/// Relative weights of the operations, e.g. `hkp=40,vks=40,upload=20`.
+#[derive(Clone, Debug)]
+pub struct Mix(Vec<(Operation, u32)>);
+
+impl FromStr for Mix {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut weights = Vec::new();
+        for entry in s.split(',') {
+            let mut parts = entry.splitn(2, '=');
+            let operation = parts.next().unwrap_or("").trim().parse()?;
+            let weight = parts
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("Missing weight for {}", entry))?
+                .trim()
+                .parse()?;
+            weights.push((operation, weight));
+        }
+        if weights.iter().map(|(_, weight)| weight).sum::<u32>() == 0 {
+            return Err(anyhow::anyhow!("Mix must contain a nonzero weight"));
+        }
+        Ok(Mix(weights))
+    }
+}
+
+impl Mix {
+    fn pick(&self) -> Operation {
+        let total: u32 = self.0.iter().map(|(_, weight)| weight).sum();
+        let mut n = thread_rng().gen_range(0, total);
+        for &(operation, weight) in &self.0 {
+            if n < weight {
+                return operation;
+            }
+            n -= weight;
+        }
+        unreachable!()
+    }
+}
+
+/// A key to look up and upload, with its fingerprint and armored
+/// upload body prepared in advance.
+struct Key {
+    fpr: String,
+    upload: String,
+}
+
+impl Key {
+    fn new(cert: &Cert) -> Result<Self> {
+        let armored = String::from_utf8(cert.armored().to_vec()?)?;
+        Ok(Key {
+            fpr: cert.fingerprint().to_hex(),
+            upload: serde_json::json!({ "keytext": armored }).to_string(),
+        })
+    }
+}
+
+struct Sample {
+    operation: Operation,
+    status: u16,
+    seconds: f64,
+}
+
+/// Replays a mix of lookups and uploads against a running instance,
+/// and reports latency percentiles per operation.
+pub fn do_loadtest(
+    target: &str,
+    requests: usize,
+    concurrency: usize,
+    mix: &Mix,
+    keyring: Option<&Path>,
+) -> Result<()> {
+    let certs = match keyring {
+        Some(path) => CertParser::from_file(path)?.collect::<openpgp::Result<Vec<_>>>()?,
+        None => (0..GENERATED_KEYS)
+            .map(|_| CertBuilder::new().generate().map(|(cert, _)| cert))
+            .collect::<openpgp::Result<Vec<_>>>()?,
+    };
+    if certs.is_empty() {
+        return Err(anyhow::anyhow!("No keys to test with"));
+    }
+    let keys = Arc::new(certs.iter().map(Key::new).collect::<Result<Vec<_>>>()?);
+
+    // Upload every key once, so that lookups find something.
+    for key in keys.iter() {
+        request(target, Operation::Upload, key)?;
+    }
+
+    let progress_bar = ProgressBar::new(requests as u64);
+    progress_bar.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {msg}")
+            .progress_chars("##-"),
+    );
+
+    let started = Instant::now();
+    let remaining = Arc::new(AtomicUsize::new(requests));
+    let threads: Vec<_> = (0..concurrency.max(1))
+        .map(|_| {
+            let target = target.to_owned();
+            let mix = mix.clone();
+            let keys = keys.clone();
+            let remaining = remaining.clone();
+            let progress_bar = progress_bar.clone();
+            thread::spawn(move || -> Result<Vec<Sample>> {
+                let mut samples = Vec::new();
+                while claim(&remaining) {
+                    let key = &keys[thread_rng().gen_range(0, keys.len())];
+                    samples.push(request(&target, mix.pick(), key)?);
+                    progress_bar.inc(1);
+                }
+                Ok(samples)
+            })
+        })
+        .collect();
+
+    let mut samples = Vec::new();
+    for thread in threads {
+        samples.extend(thread.join().unwrap()?);
+    }
+    progress_bar.finish();
+
+    report(&samples, started.elapsed().as_secs_f64());
+
+    Ok(())
+}
+
+fn claim(remaining: &AtomicUsize) -> bool {
+    remaining
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+        .is_ok()
+}
+
+/// Performs a single request with curl, which also measures its
+/// latency for us.
+fn request(target: &str, operation: Operation, key: &Key) -> Result<Sample> {
+    let target = target.trim_end_matches('/');
+    let mut command = Command::new("curl");
+    command
+        .args(&["--silent", "--show-error", "--max-time", "30"])
+        .args(&["--output", "/dev/null"])
+        .args(&["--write-out", "%{http_code} %{time_total}"]);
+    match operation {
+        Operation::Hkp => {
+            command.arg(format!("{}/pks/lookup?op=get&search=0x{}", target, key.fpr));
+        }
+        Operation::Vks => {
+            command.arg(format!("{}/vks/v1/by-fingerprint/{}", target, key.fpr));
+        }
+        Operation::Upload => {
+            command
+                .args(&["--header", "Content-Type: application/json"])
+                .args(&["--data-binary", "@-"])
+                .arg(format!("{}/vks/v1/upload", target));
+        }
+    }
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    {
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        if operation == Operation::Upload {
+            stdin.write_all(key.upload.as_bytes())?;
+        }
+    }
+    let output = child.wait_with_output()?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut fields = stdout.split_whitespace();
+    let status = fields.next().and_then(|s| s.parse().ok()).unwrap_or(0);
+    let seconds = fields.next().and_then(|s| s.parse().ok()).unwrap_or(0.0);
+    Ok(Sample {
+        operation,
+        status,
+        seconds,
+    })
+}
+
+fn report(samples: &[Sample], elapsed: f64) {
+    println!(
+        "{} requests in {:.1}s ({:.1} requests/s)",
+        samples.len(),
+        elapsed,
+        samples.len() as f64 / elapsed
+    );
+
+    let mut by_operation: HashMap<Operation, Vec<&Sample>> = HashMap::new();
+    for sample in samples {
+        by_operation
+            .entry(sample.operation)
+            .or_default()
+            .push(sample);
+    }
+    let mut operations: Vec<_> = by_operation.into_iter().collect();
+    operations.sort_by_key(|(operation, _)| *operation);
+
+    println!(
+        "{:<8} {:>8} {:>8} {:>9} {:>9} {:>9} {:>9}",
+        "", "requests", "errors", "p50 ms", "p90 ms", "p99 ms", "max ms"
+    );
+    for (operation, samples) in operations {
+        // Connection failures have status 0, and count as errors.
+        let errors = samples
+            .iter()
+            .filter(|sample| !(200..400).contains(&sample.status))
+            .count();
+        let mut latencies: Vec<f64> = samples
+            .iter()
+            .map(|sample| sample.seconds * 1000.0)
+            .collect();
+        latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        println!(
+            "{:<8} {:>8} {:>8} {:>9.1} {:>9.1} {:>9.1} {:>9.1}",
+            operation.to_string(),
+            samples.len(),
+            errors,
+            percentile(&latencies, 50),
+            percentile(&latencies, 90),
+            percentile(&latencies, 99),
+            latencies.last().copied().unwrap_or(0.0)
+        );
+    }
+}
+
+/// Nearest-rank percentile of sorted values.
+fn percentile(sorted: &[f64], p: usize) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = (p * sorted.len() + 99) / 100;
+    sorted[rank.max(1) - 1]
+}
@@ -0,0 +1,130 @@
+use anyhow::Result;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use HagridConfig;
+
+/// The fields we look at of the mails the server queues.
+#[derive(Deserialize)]
+struct QueuedMail {
+    tos: Vec<String>,
+    queued_at: u64,
+    attempts: u32,
+    next_attempt: u64,
+}
+
+#[derive(Default)]
+struct DomainStats {
+    queued: usize,
+    /// Queued mails whose delivery failed at least once, as opposed to
+    /// being held back by the throttle.
+    failing: usize,
+    suppressed: usize,
+}
+
+/// Prints the state of the mail queue, with a breakdown by recipient
+/// domain.
+///
+/// Delivery rates over time are exported as metrics by the server,
+/// this only looks at what is in the queue directory right now.
+pub fn do_status(config: &HagridConfig) -> Result<()> {
+    let queue_dir = config
+        .mail_queue_dir
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("No mail_queue_dir configured"))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+    let mut domains: BTreeMap<String, DomainStats> = BTreeMap::new();
+    let mut depth = 0;
+    let mut due = 0;
+    let mut oldest = None;
+    for content in read_entries(&queue_dir.join("queue"))? {
+        let mail: QueuedMail = serde_json::from_slice(&content)?;
+        depth += 1;
+        if mail.next_attempt <= now {
+            due += 1;
+        }
+        oldest = Some(oldest.map_or(mail.queued_at, |oldest: u64| oldest.min(mail.queued_at)));
+        for to in &mail.tos {
+            let stats = domains.entry(domain(to)).or_default();
+            stats.queued += 1;
+            if mail.attempts > 0 {
+                stats.failing += 1;
+            }
+        }
+    }
+
+    let mut suppressed = 0;
+    for content in read_entries(&queue_dir.join("suppressed"))? {
+        // The address is on the first line, followed by the reason.
+        let content = String::from_utf8_lossy(&content);
+        let address = content.lines().next().unwrap_or_default();
+        domains.entry(domain(address)).or_default().suppressed += 1;
+        suppressed += 1;
+    }
+
+    println!("Queued mails:         {} ({} due)", depth, due);
+    match oldest {
+        Some(oldest) => println!("Oldest queued mail:   {}s", now.saturating_sub(oldest)),
+        None => println!("Oldest queued mail:   -"),
+    }
+    println!("Suppressed addresses: {}", suppressed);
+
+    if domains.is_empty() {
+        return Ok(());
+    }
+    println!();
+    println!(
+        "{:<32} {:>8} {:>8} {:>8} {:>10}",
+        "domain", "queued", "failing", "failed %", "suppressed"
+    );
+    for (domain, stats) in domains {
+        let failure_rate = if stats.queued > 0 {
+            format!("{:.0}", 100.0 * stats.failing as f64 / stats.queued as f64)
+        } else {
+            "-".to_owned()
+        };
+        println!(
+            "{:<32} {:>8} {:>8} {:>8} {:>10}",
+            domain, stats.queued, stats.failing, failure_rate, stats.suppressed
+        );
+    }
+
+    Ok(())
+}
+
+/// Reads the files in the directory, skipping temporary files of
+/// concurrent writers.
+fn read_entries(dir: &Path) -> Result<Vec<Vec<u8>>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        match fs::read(entry.path()) {
+            Ok(content) => files.push(content),
+            // Delivered or given up on in the meantime.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(files)
+}
+
+fn domain(address: &str) -> String {
+    address
+        .rsplit('@')
+        .next()
+        .unwrap_or_default()
+        .to_lowercase()
+}
@@ -1,11 +1,16 @@
 extern crate anyhow;
 extern crate clap;
+extern crate filetime;
 extern crate hagrid_database as database;
+extern crate hex;
 extern crate sequoia_openpgp as openpgp;
 extern crate tempfile;
 #[macro_use]
 extern crate serde_derive;
 extern crate indicatif;
+extern crate rand;
+extern crate serde_json;
+extern crate time;
 extern crate toml;
 extern crate walkdir;
 
@@ -17,7 +22,17 @@ use anyhow::Result;
 
 use clap::{App, Arg, SubCommand};
 
+use database::KeyDatabase;
+
+mod ban;
+mod compact;
+mod export;
+mod fsck;
 mod import;
+mod loadtest;
+mod mail_queue;
+mod mirror;
+mod monitor;
 mod regenerate;
 
 #[derive(Deserialize)]
@@ -39,6 +54,25 @@ pub struct HagridConfig {
     _token_dir: Option<PathBuf>,
     tmp_dir: Option<PathBuf>,
     _maintenance_file: Option<PathBuf>,
+    uid_policy: Option<String>,
+    max_published_uids: Option<usize>,
+    max_self_signatures: Option<usize>,
+    unhashed_area_limit: Option<usize>,
+    unhashed_area_action: Option<String>,
+    transparency_key: Option<PathBuf>,
+    mail_queue_dir: Option<PathBuf>,
+}
+
+impl HagridConfig {
+    /// Opens the key database the configuration points to.
+    fn open_db(&self, dry_run: bool) -> Result<KeyDatabase> {
+        KeyDatabase::new_internal(
+            self.keys_internal_dir.as_ref().unwrap(),
+            self.keys_external_dir.as_ref().unwrap(),
+            self.tmp_dir.as_ref().unwrap(),
+            dry_run,
+        )
+    }
 }
 
 fn main() -> Result<()> {
@@ -63,6 +97,142 @@ fn main() -> Result<()> {
                 .possible_values(&["dev", "stage", "prod"]),
         )
         .subcommand(SubCommand::with_name("regenerate").about("Regenerate symlink directory"))
+        .subcommand(
+            SubCommand::with_name("ban")
+                .about("Unpublish a key and reject future uploads of it")
+                .arg(
+                    Arg::with_name("reason")
+                        .short("r")
+                        .long("reason")
+                        .value_name("REASON")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("notice")
+                        .short("n")
+                        .long("notice")
+                        .value_name("NOTICE")
+                        .help("public notice for lookups of the key")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("fingerprint").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("ban-email")
+                .about("Unpublish an address or domain and prevent publishing it again")
+                .arg(
+                    Arg::with_name("reason")
+                        .short("r")
+                        .long("reason")
+                        .value_name("REASON")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(Arg::with_name("address or domain").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("legal-hold")
+                .about("Keep a key from being deleted or changed, or list the keys held")
+                .arg(
+                    Arg::with_name("reason")
+                        .short("r")
+                        .long("reason")
+                        .value_name("REASON")
+                        .takes_value(true)
+                        .conflicts_with("release"),
+                )
+                .arg(
+                    Arg::with_name("release")
+                        .long("release")
+                        .help("release the key from legal hold"),
+                )
+                .arg(Arg::with_name("fingerprint")),
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+                .about("Drop redundant packets from published keys, reporting space reclaimed")
+                .arg(
+                    Arg::with_name("dry run")
+                        .short("n")
+                        .long("dry-run")
+                        .help("only report what would be reclaimed"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Export all published keys, for mirrors")
+                .arg(
+                    Arg::with_name("sign key")
+                        .short("k")
+                        .long("sign-key")
+                        .value_name("FILE")
+                        .help("sign the manifest with this key instead of the transparency key")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("unsigned")
+                        .long("unsigned")
+                        .help("don't sign the manifest"),
+                )
+                .arg(Arg::with_name("output directory").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("mail-queue")
+                .about("Inspect the queue of mail waiting for delivery")
+                .subcommand(
+                    SubCommand::with_name("status")
+                        .about("Show queue depth, oldest mail, and failures by domain"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("mirror")
+                .about("Update a date-partitioned copy of all published keys, for rsync mirrors")
+                .arg(Arg::with_name("output directory").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("monitor")
+                .about("Follow the transparency log of a remote instance, alerting on forks or rollbacks")
+                .arg(
+                    Arg::with_name("state")
+                        .short("s")
+                        .long("state")
+                        .value_name("FILE")
+                        .help("where to keep the last tree head seen")
+                        .takes_value(true)
+                        .default_value("hagrid-monitor.json"),
+                )
+                .arg(
+                    Arg::with_name("signer")
+                        .short("k")
+                        .long("signer")
+                        .value_name("FILE")
+                        .help("certificate of the key signing the tree heads")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .short("i")
+                        .long("interval")
+                        .value_name("SECONDS")
+                        .help("keep checking at this interval, instead of once")
+                        .takes_value(true),
+                )
+                .arg(Arg::with_name("url").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("fsck")
+                .about("Check the database for consistency, printing findings as JSON lines")
+                .arg(
+                    Arg::with_name("fail on")
+                        .long("fail-on")
+                        .value_name("SEVERITY")
+                        .help("exit non-zero for findings of at least this severity")
+                        .takes_value(true)
+                        .default_value("error")
+                        .possible_values(&["warning", "error"]),
+                ),
+        )
         .subcommand(
             SubCommand::with_name("import")
                 .about("Import keys into Hagrid")
@@ -78,6 +248,52 @@ fn main() -> Result<()> {
                         .multiple(true),
                 ),
         )
+        .subcommand(
+            SubCommand::with_name("loadtest")
+                .about("Measure latencies of a running instance")
+                .arg(
+                    Arg::with_name("target")
+                        .short("t")
+                        .long("target")
+                        .value_name("URL")
+                        .help("base URL of the instance, e.g. http://localhost:8080")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("requests")
+                        .short("n")
+                        .long("requests")
+                        .value_name("COUNT")
+                        .takes_value(true)
+                        .default_value("1000"),
+                )
+                .arg(
+                    Arg::with_name("concurrency")
+                        .short("j")
+                        .long("concurrency")
+                        .value_name("COUNT")
+                        .takes_value(true)
+                        .default_value("8"),
+                )
+                .arg(
+                    Arg::with_name("mix")
+                        .short("m")
+                        .long("mix")
+                        .value_name("MIX")
+                        .help("relative weights of hkp lookups, vks lookups, and uploads")
+                        .takes_value(true)
+                        .default_value("hkp=40,vks=40,upload=20"),
+                )
+                .arg(
+                    Arg::with_name("keyring")
+                        .short("k")
+                        .long("keyring")
+                        .value_name("FILE")
+                        .help("keys to look up and upload, instead of generated ones")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
     let config_file = matches.value_of("config").unwrap_or("Rocket.toml");
@@ -99,6 +315,67 @@ fn main() -> Result<()> {
             .map(|arg| PathBuf::from_str(arg).unwrap())
             .collect();
         import::do_import(&config, dry_run, keyrings)?;
+    } else if let Some(matches) = matches.subcommand_matches("ban") {
+        let fpr = matches.value_of("fingerprint").unwrap();
+        let reason = matches.value_of("reason").unwrap();
+        let notice = matches.value_of("notice");
+        ban::do_ban(&config, fpr, reason, notice)?;
+    } else if let Some(matches) = matches.subcommand_matches("ban-email") {
+        let entry = matches.value_of("address or domain").unwrap();
+        let reason = matches.value_of("reason").unwrap();
+        ban::do_ban_email(&config, entry, reason)?;
+    } else if let Some(matches) = matches.subcommand_matches("legal-hold") {
+        match (matches.value_of("fingerprint"), matches.value_of("reason")) {
+            (Some(fpr), _) if matches.is_present("release") => {
+                ban::do_release_legal_hold(&config, fpr)?
+            }
+            (Some(fpr), Some(reason)) => ban::do_legal_hold(&config, fpr, reason)?,
+            (Some(_), None) => return Err(anyhow::anyhow!("A reason for the hold is required")),
+            (None, _) => ban::do_list_legal_holds(&config)?,
+        }
+    } else if let Some(matches) = matches.subcommand_matches("compact") {
+        let dry_run = matches.occurrences_of("dry run") > 0;
+        compact::do_compact(&config, dry_run)?;
+    } else if let Some(matches) = matches.subcommand_matches("export") {
+        let output_dir = PathBuf::from(matches.value_of("output directory").unwrap());
+        let sign_key = if matches.occurrences_of("unsigned") > 0 {
+            None
+        } else {
+            matches
+                .value_of("sign key")
+                .map(PathBuf::from)
+                .or_else(|| config.transparency_key.clone())
+        };
+        export::do_export(&config, &output_dir, sign_key.as_deref())?;
+    } else if let Some(matches) = matches.subcommand_matches("mirror") {
+        let output_dir = PathBuf::from(matches.value_of("output directory").unwrap());
+        mirror::do_mirror(&config, &output_dir)?;
+    } else if let Some(matches) = matches.subcommand_matches("mail-queue") {
+        if matches.subcommand_matches("status").is_some() {
+            mail_queue::do_status(&config)?;
+        } else {
+            println!("{}", matches.usage());
+        }
+    } else if let Some(matches) = matches.subcommand_matches("loadtest") {
+        let target = matches.value_of("target").unwrap();
+        let requests = matches.value_of("requests").unwrap().parse()?;
+        let concurrency = matches.value_of("concurrency").unwrap().parse()?;
+        let mix = matches.value_of("mix").unwrap().parse()?;
+        let keyring = matches.value_of("keyring").map(PathBuf::from);
+        loadtest::do_loadtest(target, requests, concurrency, &mix, keyring.as_deref())?;
+    } else if let Some(matches) = matches.subcommand_matches("monitor") {
+        let url = matches.value_of("url").unwrap();
+        let state_file = PathBuf::from(matches.value_of("state").unwrap());
+        let signer = matches.value_of("signer").map(PathBuf::from);
+        let interval = matches.value_of("interval").map(str::parse).transpose()?;
+        if monitor::do_monitor(url, &state_file, signer.as_deref(), interval)? {
+            std::process::exit(1);
+        }
+    } else if let Some(matches) = matches.subcommand_matches("fsck") {
+        let fail_on = matches.value_of("fail on").unwrap().parse()?;
+        if fsck::do_fsck(&config, fail_on)? {
+            std::process::exit(1);
+        }
     } else if let Some(_matches) = matches.subcommand_matches("regenerate") {
         regenerate::do_regenerate(&config)?;
     } else {
@@ -0,0 +1,198 @@
+use anyhow::Result;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use filetime::{set_file_mtime, FileTime};
+use walkdir::WalkDir;
+
+use database::types::Fingerprint;
+use database::{
+    Database, KeyDatabase, AUDIT_LINK_EMAIL, AUDIT_PUBLISH_KEY, AUDIT_UNLINK_EMAIL,
+    AUDIT_UNPUBLISH_KEY, AUDIT_UPDATE_KEY,
+};
+use HagridConfig;
+
+/// Where we remember how far we got, and where each key is.  Mirrors
+/// should exclude it, e.g. with `rsync --exclude=/.state`.
+const STATE: &str = ".state";
+
+#[derive(Default, Deserialize, Serialize)]
+struct State {
+    /// The offset in the audit log to continue from.
+    audit_log_offset: u64,
+    /// The partition each exported key is in.
+    partitions: HashMap<String, String>,
+}
+
+/// Maintains a copy of the published keys for rsync-based mirrors.
+///
+/// Keys are stored as `<YYYY>/<MM>/<DD>/<FINGERPRINT>.asc` by the
+/// date they last changed, and their mtime is set to that time, so
+/// that mirrors fetching regularly only need to look at the latest
+/// partitions.  The first run exports all keys, later runs only
+/// those changed according to the audit log.
+pub fn do_mirror(config: &HagridConfig, output_dir: &Path) -> Result<()> {
+    let db = KeyDatabase::new(
+        config.keys_internal_dir.as_ref().unwrap(),
+        config.keys_external_dir.as_ref().unwrap(),
+        config.tmp_dir.as_ref().unwrap(),
+    )?;
+
+    fs::create_dir_all(output_dir)?;
+    let state_path = output_dir.join(STATE);
+    let (mut state, changes) = match fs::read(&state_path) {
+        Ok(state) => {
+            let state: State = serde_json::from_slice(&state)?;
+            let (lines, offset) = db.read_audit_log(state.audit_log_offset)?;
+            let changes = changes_from_audit_log(&lines);
+            (
+                State {
+                    audit_log_offset: offset,
+                    ..state
+                },
+                changes,
+            )
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // Start from the end of the log as it is now, so that
+            // changes during the walk are picked up next time.
+            let state = State {
+                audit_log_offset: db.audit_log_end()?,
+                ..Default::default()
+            };
+            (state, changes_from_published(config)?)
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut count_written = 0;
+    let mut count_removed = 0;
+    for (fpr, timestamp) in &changes {
+        let name = fpr.to_string();
+        let old_path = state
+            .partitions
+            .get(&name)
+            .map(|partition| key_path(output_dir, partition, &name));
+
+        let published = match db.by_primary_fpr(fpr) {
+            Some(published) => published,
+            None => {
+                if let Some(old_path) = old_path {
+                    remove(output_dir, &old_path)?;
+                    count_removed += 1;
+                }
+                state.partitions.remove(&name);
+                continue;
+            }
+        };
+
+        let partition = partition(*timestamp);
+        let path = key_path(output_dir, &partition, &name);
+        if let Some(old_path) = old_path {
+            if old_path == path && fs::read(&path).ok().as_deref() == Some(published.as_bytes()) {
+                continue;
+            }
+            if old_path != path {
+                remove(output_dir, &old_path)?;
+            }
+        }
+
+        fs::create_dir_all(path.parent().unwrap())?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, &published)?;
+        set_file_mtime(&tmp_path, FileTime::from_unix_time(*timestamp as i64, 0))?;
+        fs::rename(&tmp_path, &path)?;
+        state.partitions.insert(name, partition);
+        count_written += 1;
+    }
+
+    // Written last, so that an interrupted run is repeated.
+    let tmp_path = output_dir.join(format!("{}.tmp", STATE));
+    fs::write(&tmp_path, serde_json::to_vec(&state)?)?;
+    fs::rename(&tmp_path, &state_path)?;
+
+    println!(
+        "Mirrored {} changed keys to {}: {} written, {} removed",
+        changes.len(),
+        output_dir.display(),
+        count_written,
+        count_removed
+    );
+
+    Ok(())
+}
+
+/// Returns the keys changed according to the audit log, and when they
+/// changed last.
+fn changes_from_audit_log(lines: &[String]) -> HashMap<Fingerprint, u64> {
+    let mut changes = HashMap::new();
+    for line in lines {
+        let mut fields = line.split(' ');
+        let timestamp = fields.next().and_then(|t| t.parse().ok());
+        let action = fields.next();
+        let fpr = fields.next().and_then(|fpr| fpr.parse().ok());
+        match (timestamp, action, fpr) {
+            (Some(timestamp), Some(action), Some(fpr))
+                if [
+                    AUDIT_PUBLISH_KEY,
+                    AUDIT_UPDATE_KEY,
+                    AUDIT_UNPUBLISH_KEY,
+                    AUDIT_LINK_EMAIL,
+                    AUDIT_UNLINK_EMAIL,
+                ]
+                .contains(&action) =>
+            {
+                changes.insert(fpr, timestamp);
+            }
+            _ => (),
+        }
+    }
+    changes
+}
+
+/// Returns all published keys, and when they changed last.
+fn changes_from_published(config: &HagridConfig) -> Result<HashMap<Fingerprint, u64>> {
+    let published_dir = config.keys_external_dir.as_ref().unwrap().join("pub");
+    let mut changes = HashMap::new();
+    for entry in WalkDir::new(published_dir) {
+        let entry = entry?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        if let Some(fpr) = KeyDatabase::path_to_primary(entry.path()) {
+            let modified = entry.metadata()?.modified()?;
+            let timestamp = modified.duration_since(UNIX_EPOCH)?.as_secs();
+            changes.insert(fpr, timestamp);
+        }
+    }
+    Ok(changes)
+}
+
+/// Returns the partition for keys changed at the given time.
+fn partition(timestamp: u64) -> String {
+    let tm = time::at_utc(time::Timespec::new(timestamp as i64, 0));
+    time::strftime("%Y/%m/%d", &tm).unwrap()
+}
+
+fn key_path(output_dir: &Path, partition: &str, name: &str) -> PathBuf {
+    output_dir.join(partition).join(format!("{}.asc", name))
+}
+
+/// Removes the key, and the partition directories it leaves empty.
+fn remove(output_dir: &Path, path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    for dir in path.ancestors().skip(1) {
+        if dir == output_dir || fs::remove_dir(dir).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,193 @@
+use anyhow::Result;
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+
+use openpgp::parse::Parse;
+use openpgp::policy::StandardPolicy;
+use openpgp::{Cert, Packet, PacketPile};
+
+use database::transparency::{self, TreeHead};
+
+/// A signed tree head, as returned by `/log/v1/sth`.
+#[derive(Deserialize)]
+struct Sth {
+    tree_size: usize,
+    timestamp: i64,
+    sha256_root_hash: String,
+    tree_head_signature: Option<String>,
+}
+
+/// As returned by `/log/v1/consistency/<first>/<second>`.
+#[derive(Deserialize)]
+struct Consistency {
+    consistency: Vec<String>,
+}
+
+/// Follows the signed tree heads of a remote hagrid, and checks that
+/// each one extends the last one seen, which is kept in the state file.
+///
+/// Unless `signer_cert` is given, tree heads are not authenticated.
+/// Without an interval, checks once.  Returns whether the log was
+/// found to misbehave, i.e. was forked or rolled back.
+pub fn do_monitor(
+    url: &str,
+    state_file: &Path,
+    signer_cert: Option<&Path>,
+    interval: Option<u64>,
+) -> Result<bool> {
+    let url = url.trim_end_matches('/');
+    let signer = signer_cert.map(Cert::from_file).transpose()?;
+    if signer.is_none() {
+        eprintln!("Warning: no signer certificate given, tree heads are not authenticated");
+    }
+
+    loop {
+        match check(url, state_file, signer.as_ref()) {
+            Ok(Some(alert)) => {
+                eprintln!("ALERT: {}", alert);
+                return Ok(true);
+            }
+            Ok(None) => (),
+            // Network trouble is not misbehavior, try again later.
+            Err(e) if interval.is_some() => eprintln!("Error checking {}: {}", url, e),
+            Err(e) => return Err(e),
+        }
+        match interval {
+            Some(seconds) => thread::sleep(Duration::from_secs(seconds)),
+            None => return Ok(false),
+        }
+    }
+}
+
+/// Fetches the current tree head, and checks it against the last one
+/// seen.  Returns a description of the misbehavior, if any.
+fn check(url: &str, state_file: &Path, signer: Option<&Cert>) -> Result<Option<String>> {
+    let sth: Sth = serde_json::from_slice(&fetch(&format!("{}/log/v1/sth", url))?)?;
+    let head = TreeHead {
+        tree_size: sth.tree_size,
+        root_hash: sth.sha256_root_hash.to_lowercase(),
+        timestamp: sth.timestamp,
+    };
+    if let Some(cert) = signer {
+        let signature = sth
+            .tree_head_signature
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Tree head is not signed"))?;
+        if let Err(e) = verify_signature(cert, &head.to_signed_message(), signature) {
+            return Ok(Some(format!("bad signature on tree head: {}", e)));
+        }
+    }
+
+    let last: Option<TreeHead> = match fs::read(state_file) {
+        Ok(data) => Some(serde_json::from_slice(&data)?),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+
+    if let Some(last) = last {
+        if head.tree_size < last.tree_size {
+            return Ok(Some(format!(
+                "log was rolled back from size {} to {}",
+                last.tree_size, head.tree_size
+            )));
+        }
+        if head.tree_size == last.tree_size {
+            if head.root_hash != last.root_hash {
+                return Ok(Some(format!(
+                    "log was forked at size {}: root hash {} was {}",
+                    head.tree_size, head.root_hash, last.root_hash
+                )));
+            }
+            return Ok(None);
+        }
+
+        let proof: Consistency = serde_json::from_slice(&fetch(&format!(
+            "{}/log/v1/consistency/{}/{}",
+            url, last.tree_size, head.tree_size
+        ))?)?;
+        let proof = proof
+            .consistency
+            .iter()
+            .map(|hash| decode_hash(hash))
+            .collect::<Result<Vec<_>>>()?;
+        if !transparency::verify_consistency(
+            last.tree_size,
+            head.tree_size,
+            &decode_hash(&last.root_hash)?,
+            &decode_hash(&head.root_hash)?,
+            &proof,
+        ) {
+            return Ok(Some(format!(
+                "log of size {} does not extend the one of size {}",
+                head.tree_size, last.tree_size
+            )));
+        }
+        println!(
+            "Log grew from {} to {} entries, root hash {}",
+            last.tree_size, head.tree_size, head.root_hash
+        );
+    } else {
+        println!(
+            "Recorded initial tree head of {} entries, root hash {}",
+            head.tree_size, head.root_hash
+        );
+    }
+
+    // Replace the state atomically, so that a crash can't lose it.
+    let tmp_file = state_file.with_extension("tmp");
+    fs::write(&tmp_file, serde_json::to_vec(&head)?)?;
+    fs::rename(&tmp_file, state_file)?;
+    Ok(None)
+}
+
+/// Checks that one of the certificate's signing keys made the armored
+/// detached signature over the message.
+fn verify_signature(cert: &Cert, message: &str, armored: &str) -> Result<()> {
+    let policy = StandardPolicy::new();
+    let signatures = PacketPile::from_bytes(armored.as_bytes())?
+        .into_children()
+        .filter_map(|packet| match packet {
+            Packet::Signature(sig) => Some(sig),
+            _ => None,
+        });
+    for mut sig in signatures {
+        for key in cert.keys().with_policy(&policy, None).for_signing() {
+            if sig.verify_message(key.key(), message.as_bytes()).is_ok() {
+                return Ok(());
+            }
+        }
+    }
+    Err(anyhow::anyhow!(
+        "no valid signature by {}",
+        cert.fingerprint()
+    ))
+}
+
+fn decode_hash(hash: &str) -> Result<transparency::Hash> {
+    let bytes = hex::decode(hash)?;
+    if bytes.len() != 32 {
+        return Err(anyhow::anyhow!("Malformed hash: {}", hash));
+    }
+    let mut h = [0; 32];
+    h.copy_from_slice(&bytes);
+    Ok(h)
+}
+
+fn fetch(url: &str) -> Result<Vec<u8>> {
+    let output = Command::new("curl")
+        .args(&["--silent", "--show-error", "--fail", "--max-time", "30"])
+        .arg(url)
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "Fetching {} failed: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output.stdout)
+}
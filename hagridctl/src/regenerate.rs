@@ -72,12 +72,7 @@ impl<'a> RegenerateStats<'a> {
 }
 
 pub fn do_regenerate(config: &HagridConfig) -> Result<()> {
-    let db = KeyDatabase::new_internal(
-        config.keys_internal_dir.as_ref().unwrap(),
-        config.keys_external_dir.as_ref().unwrap(),
-        config.tmp_dir.as_ref().unwrap(),
-        false,
-    )?;
+    let db = config.open_db(false)?;
 
     let published_dir = config
         .keys_external_dir
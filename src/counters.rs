@@ -1,9 +1,24 @@
 use lazy_static::lazy_static;
 use rocket_prometheus::prometheus;
 
+use std::sync::Once;
+use std::time::{Duration, Instant};
+
 use crate::anonymize_utils;
 
 use crate::database::types::Email;
+use crate::database::{KeyDatabase, PublishedCounts};
+use crate::Result;
+
+/// How often the published counts are updated from the audit log.
+pub const PUBLISHED_COUNTS_INTERVAL: Duration = Duration::from_secs(60);
+/// How often the published keys are counted from scratch, to correct
+/// any drift.
+const PUBLISHED_RECOUNT_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Buckets of the request durations, in seconds.
+const REQUEST_DURATION_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
 
 lazy_static! {
     static ref KEY_UPLOAD: LabelCounter =
@@ -13,6 +28,18 @@ lazy_static! {
         "Sent verification mails",
         &["type", "domain"]
     );
+    static ref MAIL_DELIVERY: LabelCounter = LabelCounter::new(
+        "hagrid_mail_delivery",
+        "Outcomes of mail delivery attempts (sent, queued, failed, bounced, suppressed)",
+        &["result", "domain"]
+    );
+    static ref MAIL_QUEUE_DEPTH: prometheus::IntGauge =
+        prometheus::IntGauge::new("hagrid_mail_queue_depth", "Mails waiting in the queue").unwrap();
+    static ref MAIL_QUEUE_OLDEST_AGE: prometheus::IntGauge = prometheus::IntGauge::new(
+        "hagrid_mail_queue_oldest_age_seconds",
+        "Age of the oldest mail in the queue"
+    )
+    .unwrap();
     static ref KEY_ADDRESS_PUBLISHED: LabelCounter = LabelCounter::new(
         "hagrid_key_address_published",
         "Verified email addresses",
@@ -23,15 +50,91 @@ lazy_static! {
         "Unpublished email addresses",
         &["domain"]
     );
+    static ref KEYS_PUBLISHED: prometheus::IntGauge =
+        prometheus::IntGauge::new("hagrid_keys_published", "Published keys").unwrap();
+    static ref ADDRESSES_PUBLISHED: prometheus::IntGauge =
+        prometheus::IntGauge::new("hagrid_addresses_published", "Published email addresses")
+            .unwrap();
+    static ref REQUEST_DURATION: prometheus::HistogramVec = prometheus::HistogramVec::new(
+        prometheus::HistogramOpts::new(
+            "hagrid_request_duration_seconds",
+            "Time taken to handle requests, by route"
+        )
+        .buckets(REQUEST_DURATION_BUCKETS.to_vec()),
+        &["route"]
+    )
+    .unwrap();
+    static ref DISK_USAGE: prometheus::IntGauge = prometheus::IntGauge::new(
+        "hagrid_disk_usage_bytes",
+        "Disk space taken by keys and temporary files"
+    )
+    .unwrap();
+    static ref PANICS: prometheus::IntCounter =
+        prometheus::IntCounter::new("hagrid_panics_total", "Panics in any thread").unwrap();
+    static ref RESPONSES: LabelCounter = LabelCounter::new(
+        "hagrid_responses",
+        "Responses, by route and status class",
+        &["route", "class"]
+    );
+    static ref LOOKUPS: LabelCounter = LabelCounter::new(
+        "hagrid_lookups",
+        "Lookups, by transport (hkp, vks, wkd, web) and result",
+        &["transport", "result"]
+    );
+    static ref RETENTION: LabelCounter = LabelCounter::new(
+        "hagrid_retention_keys",
+        "Keys matched by retention rules, by rule and action (deleted, dry-run)",
+        &["rule", "action"]
+    );
+    static ref JOB_RUNS: LabelCounter = LabelCounter::new(
+        "hagrid_job_runs",
+        "Runs of scheduled jobs, by job and result",
+        &["job", "result"]
+    );
+    static ref JOB_DURATION: prometheus::HistogramVec = prometheus::HistogramVec::new(
+        prometheus::HistogramOpts::new(
+            "hagrid_job_duration_seconds",
+            "Time taken by scheduled jobs, by job"
+        ),
+        &["job"]
+    )
+    .unwrap();
 }
 
 pub fn register_counters(registry: &prometheus::Registry) {
     KEY_UPLOAD.register(registry);
 
     MAIL_SENT.register(registry);
+    MAIL_DELIVERY.register(registry);
+    registry
+        .register(Box::new(MAIL_QUEUE_DEPTH.clone()))
+        .unwrap();
+    registry
+        .register(Box::new(MAIL_QUEUE_OLDEST_AGE.clone()))
+        .unwrap();
 
     KEY_ADDRESS_PUBLISHED.register(registry);
     KEY_ADDRESS_UNPUBLISHED.register(registry);
+
+    registry.register(Box::new(KEYS_PUBLISHED.clone())).unwrap();
+    registry
+        .register(Box::new(ADDRESSES_PUBLISHED.clone()))
+        .unwrap();
+
+    registry
+        .register(Box::new(REQUEST_DURATION.clone()))
+        .unwrap();
+    RESPONSES.register(registry);
+    LOOKUPS.register(registry);
+
+    registry.register(Box::new(DISK_USAGE.clone())).unwrap();
+
+    registry.register(Box::new(PANICS.clone())).unwrap();
+
+    RETENTION.register(registry);
+
+    JOB_RUNS.register(registry);
+    registry.register(Box::new(JOB_DURATION.clone())).unwrap();
 }
 
 pub fn inc_key_upload(upload_result: &str) {
@@ -43,6 +146,16 @@ pub fn inc_mail_sent(mail_type: &str, email: &Email) {
     MAIL_SENT.inc(&[mail_type, &anonymized_adddress]);
 }
 
+pub fn inc_mail_delivery(result: &str, email: &Email) {
+    let anonymized_adddress = anonymize_utils::anonymize_address_fallback(email);
+    MAIL_DELIVERY.inc(&[result, &anonymized_adddress]);
+}
+
+pub fn set_mail_queue(depth: usize, oldest_age: u64) {
+    MAIL_QUEUE_DEPTH.set(depth as i64);
+    MAIL_QUEUE_OLDEST_AGE.set(oldest_age as i64);
+}
+
 pub fn inc_address_published(email: &Email) {
     let anonymized_adddress = anonymize_utils::anonymize_address_fallback(email);
     KEY_ADDRESS_PUBLISHED.inc(&[&anonymized_adddress]);
@@ -53,6 +166,85 @@ pub fn inc_address_unpublished(email: &Email) {
     KEY_ADDRESS_UNPUBLISHED.inc(&[&anonymized_adddress]);
 }
 
+pub fn observe_response(route: &str, status: u16, duration: Duration) {
+    REQUEST_DURATION
+        .with_label_values(&[route])
+        .observe(duration.as_secs_f64());
+    RESPONSES.inc(&[route, &format!("{}xx", status / 100)]);
+}
+
+pub fn inc_lookup(transport: &str, result: &str) {
+    LOOKUPS.inc(&[transport, result]);
+}
+
+pub fn inc_retention(rule: &str, dry_run: bool, keys: usize) {
+    let action = if dry_run { "dry-run" } else { "deleted" };
+    RETENTION.inc_by(&[rule, action], keys as u64);
+}
+
+/// Counts a run of a scheduled job, which ended in `outcome`: success,
+/// error, or panic.
+pub fn observe_job(job: &str, outcome: &str, duration: Duration) {
+    JOB_DURATION
+        .with_label_values(&[job])
+        .observe(duration.as_secs_f64());
+    JOB_RUNS.inc(&[job, outcome]);
+}
+
+pub fn set_disk_usage(bytes: u64) {
+    DISK_USAGE.set(bytes as i64);
+}
+
+/// Counts panics, on top of the default panic hook, which logs them.
+pub fn count_panics() {
+    static INSTALL_HOOK: Once = Once::new();
+    INSTALL_HOOK.call_once(|| {
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            PANICS.inc();
+            default_hook(info);
+        }));
+    });
+}
+
+/// Returns a job that keeps the gauges of published keys and
+/// addresses up to date.
+///
+/// The database is counted on the first run, and then once a day.  In
+/// between, the changes recorded in the audit log are applied.
+pub fn published_counts_job(db: KeyDatabase) -> impl FnMut() -> Result<()> {
+    let mut counts = PublishedCounts::default();
+    let mut offset = 0;
+    let mut last_recount = None;
+    move || update_published_counts(&db, &mut counts, &mut offset, &mut last_recount)
+}
+
+fn update_published_counts(
+    db: &KeyDatabase,
+    counts: &mut PublishedCounts,
+    offset: &mut u64,
+    last_recount: &mut Option<Instant>,
+) -> Result<()> {
+    let recount = last_recount.map_or(true, |last| last.elapsed() >= PUBLISHED_RECOUNT_INTERVAL);
+    if recount {
+        // Changes made during the walk may be counted twice or not at
+        // all, which the next recount corrects.
+        *offset = db.audit_log_end()?;
+        *counts = db.count_published()?;
+        *last_recount = Some(Instant::now());
+    } else {
+        let (lines, new_offset) = db.read_audit_log(*offset)?;
+        for line in lines {
+            counts.apply_audit_entry(&line);
+        }
+        *offset = new_offset;
+    }
+
+    KEYS_PUBLISHED.set(counts.keys as i64);
+    ADDRESSES_PUBLISHED.set(counts.addresses as i64);
+    Ok(())
+}
+
 struct LabelCounter {
     prometheus_counter: prometheus::IntCounterVec,
 }
@@ -73,4 +265,8 @@ impl LabelCounter {
     fn inc(&self, values: &[&str]) {
         self.prometheus_counter.with_label_values(values).inc();
     }
+
+    fn inc_by(&self, values: &[&str], v: u64) {
+        self.prometheus_counter.with_label_values(values).inc_by(v);
+    }
 }
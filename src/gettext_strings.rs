@@ -4,10 +4,37 @@ fn _dummy() {
     t!("Error");
     t!("Looks like something went wrong :(");
     t!("Error message: {{ internal_error }}");
+    t!("Please mention request ID <code>{{ request_id }}</code> when reporting this error.");
     t!("There was an error with your request:");
     t!("We found an entry for <span class=\"email\">{{ query }}</span>:");
     t!("<strong>Hint:</strong> It's more convenient to use <span class=\"brand\">keys.openpgp.org</span> from your OpenPGP software.<br /> Take a look at our <a href=\"/about/usage\">usage guide</a> for details.");
     t!("debug info");
+    t!("Algorithm");
+    t!("Created");
+    t!("Expires");
+    t!("Never");
+    t!("Verified addresses");
+    t!("None");
+    t!("Show subkeys and details");
+    t!("QR code of the fingerprint");
+    t!("Primary key");
+    t!("Fingerprint");
+    t!("Capabilities");
+    t!("certify");
+    t!("sign");
+    t!("encrypt");
+    t!("authenticate");
+    t!("This key has been revoked, and should not be used.");
+    t!("This key has expired.");
+    t!("This key expires soon.");
+    t!("Subkeys");
+    t!("This subkey has been revoked.");
+    t!("This subkey has expired.");
+    t!("This subkey expires soon.");
+    t!("This key has no subkeys.");
+    t!("(mixes scripts, may imitate another address)");
+    t!("Identity proofs");
+    t!("These claims were made by the key holder, and have not been verified by this server.");
     t!("Search by Email Address / Key ID / Fingerprint");
     t!("Search");
     t!("You can also <a href=\"/upload\">upload</a> or <a href=\"/manage\">manage</a> your key.");
@@ -25,12 +52,16 @@ fn _dummy() {
     t!("Managing the key <span class=\"fingerprint\"><a href=\"{{ key_link }}\" target=\"_blank\">{{ key_fpr }}</a></span>.");
     t!("Your key is published with the following identity information:");
     t!("Delete");
+    t!("Verified on {{ verified }}");
     t!("Clicking \"delete\" on any address will remove it from this key. It will no longer appear in a search.<br /> To add another address, <a href=\"/upload\">upload</a> the key again.");
     t!("Your key is published as only non-identity information.  (<a href=\"/about\" target=\"_blank\">What does this mean?</a>)");
     t!("To add an address, <a href=\"/upload\">upload</a> the key again.");
+    t!("These addresses are waiting for verification:");
     t!("We have sent an email with further instructions to <span class=\"email\">{{ address }}</span>.");
     t!("This address has already been verified.");
     t!("Your key <span class=\"fingerprint\">{{ key_fpr }}</span> is now published for the identity <a href=\"{{userid_link}}\" target=\"_blank\"><span class=\"email\">{{ userid }}</span></a>.");
+    t!("You can download it from:");
+    t!("Verification is still pending for these addresses:");
     t!("Upload your key");
     t!("Upload");
     t!("Need more info? Check our <a target=\"_blank\" href=\"/about\">intro</a> and <a target=\"_blank\" href=\"/about/usage\">usage guide</a>.");
@@ -46,6 +77,8 @@ fn _dummy() {
     t!("Send Verification Email");
     t!("This key contains one identity that could not be parsed as an email address.<br /> This identity can't be published on <span class=\"brand\">keys.openpgp.org</span>.  (<a href=\"/about/faq#non-email-uids\" target=\"_blank\">Why?</a>)");
     t!("This key contains {{ count_unparsed }} identities that could not be parsed as an email address.<br /> These identities can't be published on <span class=\"brand\">keys.openpgp.org</span>.  (<a href=\"/about/faq#non-email-uids\" target=\"_blank\">Why?</a>)");
+    t!("This key has more identities than can be published. The oldest one is not published.");
+    t!("This key has more identities than can be published. The {{ count_dropped }} oldest ones are not published.");
     t!("This key contains one revoked identity, which is not published. (<a href=\"/about/faq#revoked-uids\" target=\"_blank\">Why?</a>)");
     t!("This key contains {{ count_revoked }} revoked identities, which are not published. (<a href=\"/about/faq#revoked-uids\" target=\"_blank\">Why?</a>)");
     t!("Your keys have been successfully uploaded:");
@@ -78,6 +111,7 @@ fn _dummy() {
     t!("If you didn't request this message, please ignore it.");
     t!("OpenPGP key: <tt>{{primary_fp}}</tt>");
     t!("To let others find this key from your email address \"<a rel=\"nofollow\" href=\"#\" style=\"text-decoration:none; color: #333\">{{userid}}</a>\", please click the link below:");
+    t!("Or simply reply to this message.");
     t!("You can find more info at <a href=\"{{base_uri}}/about\">{{domain}}/about</a>.");
     t!("distributing OpenPGP keys since 2019");
 
@@ -86,6 +120,7 @@ fn _dummy() {
     t!("If you didn't request this message, please ignore it.");
     t!("OpenPGP key: {{primary_fp}}");
     t!("To let others find this key from your email address \"{{userid}}\",\nplease follow the link below:");
+    t!("Or simply reply to this message.");
     t!("You can find more info at {{base_uri}}/about");
     t!("distributing OpenPGP keys since 2019");
 }
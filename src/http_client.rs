@@ -0,0 +1,180 @@
+//! The client for outbound HTTP requests.
+//!
+//! Like the HTTP mail transport, we hand off the actual work to curl.
+//! All requests go through this client, so that proxy settings, trusted
+//! certificates, key pins and timeouts are configured in one place.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::time::Duration;
+
+use crate::socks::Socks5Proxy;
+use crate::Result;
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    proxy: Option<Socks5Proxy>,
+    /// Trust only the certificates in this file, instead of the
+    /// system's.
+    ca_file: Option<PathBuf>,
+    /// Public key pins by host name, in curl's `sha256//<base64>`
+    /// form.
+    pinned_keys: HashMap<String, String>,
+    connect_timeout: Duration,
+    /// How long a request may take in total.
+    timeout: Duration,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        HttpClient::new(DEFAULT_CONNECT_TIMEOUT, DEFAULT_TIMEOUT)
+    }
+}
+
+impl HttpClient {
+    pub fn new(connect_timeout: Duration, timeout: Duration) -> Self {
+        HttpClient {
+            proxy: None,
+            ca_file: None,
+            pinned_keys: HashMap::new(),
+            connect_timeout,
+            timeout,
+        }
+    }
+
+    pub fn with_proxy(mut self, proxy: Socks5Proxy) -> Self {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    pub fn with_ca_file(mut self, ca_file: PathBuf) -> Self {
+        self.ca_file = Some(ca_file);
+        self
+    }
+
+    /// Only accepts the server key with this hash from `host`.
+    ///
+    /// The pin is the base64 encoded SHA-256 hash of the server's
+    /// public key, optionally prefixed with `sha256//`.
+    pub fn with_pinned_key(mut self, host: &str, pin: &str) -> Result<Self> {
+        let hash = pin.trim_start_matches("sha256//");
+        match base64::decode(hash) {
+            Ok(decoded) if decoded.len() == 32 => (),
+            _ => return Err(anyhow!("Invalid key pin for {}: {}", host, pin)),
+        }
+        self.pinned_keys
+            .insert(host.to_lowercase(), format!("sha256//{}", hash));
+        Ok(self)
+    }
+
+    /// Posts `body` to `url`, and returns the response's status.
+    ///
+    /// `args` are passed to curl on the command line, `secret_config`
+    /// in a private config file, where credentials are not visible to
+    /// other users.  Returns an error if there was no response.
+    pub fn post(
+        &self,
+        url: &str,
+        args: &[String],
+        secret_config: &str,
+        body: &[u8],
+    ) -> Result<u16> {
+        let mut config = tempfile::NamedTempFile::new()?;
+        config.write_all(secret_config.as_bytes())?;
+
+        let mut child = self
+            .command(url)?
+            .args(&["--output", "/dev/null", "--write-out", "%{http_code}"])
+            .arg("--config")
+            .arg(config.path())
+            .args(args)
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        child
+            .stdin
+            .take()
+            .expect("stdin is piped")
+            .write_all(body)?;
+        let output = child.wait_with_output()?;
+
+        match String::from_utf8_lossy(&output.stdout).trim().parse() {
+            Ok(status) if status != 0 => Ok(status),
+            _ => Err(anyhow!(
+                "{}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )),
+        }
+    }
+
+    /// Returns a curl command with the settings for requests to `url`.
+    fn command(&self, url: &str) -> Result<Command> {
+        let mut command = Command::new("curl");
+        command
+            .args(&["--silent", "--show-error"])
+            .arg("--proto")
+            .arg("=https,http")
+            .arg("--connect-timeout")
+            .arg(self.connect_timeout.as_secs().max(1).to_string())
+            .arg("--max-time")
+            .arg(self.timeout.as_secs().max(1).to_string());
+        if let Some(proxy) = &self.proxy {
+            command.arg("--proxy").arg(proxy.curl_url());
+        }
+        if let Some(ca_file) = &self.ca_file {
+            command.arg("--cacert").arg(ca_file);
+        }
+        if let Some(pin) = self.pinned_key(url)? {
+            command.arg("--pinnedpubkey").arg(pin);
+        }
+        Ok(command)
+    }
+
+    fn pinned_key(&self, url: &str) -> Result<Option<&String>> {
+        let url = url::Url::parse(url)?;
+        Ok(url
+            .host_str()
+            .and_then(|host| self.pinned_keys.get(&host.to_lowercase())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIN: &str = "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=";
+
+    #[test]
+    fn pinned_keys() {
+        let client = HttpClient::default()
+            .with_pinned_key("API.example.org", PIN)
+            .unwrap();
+        assert_eq!(
+            client
+                .pinned_key("https://api.example.org/v3/send")
+                .unwrap()
+                .map(String::as_str),
+            Some(format!("sha256//{}", PIN).as_str())
+        );
+        assert_eq!(client.pinned_key("https://example.org/").unwrap(), None);
+
+        let client = HttpClient::default()
+            .with_pinned_key("api.example.org", &format!("sha256//{}", PIN))
+            .unwrap();
+        assert!(client
+            .pinned_key("https://api.example.org/")
+            .unwrap()
+            .is_some());
+
+        assert!(HttpClient::default()
+            .with_pinned_key("api.example.org", "c2hvcnQ=")
+            .is_err());
+    }
+}
@@ -1,20 +1,33 @@
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use crate::counters;
 use handlebars::Handlebars;
-use lettre::message::{Mailbox, MultiPart, SinglePart, header};
-use lettre::{FileTransport, SendmailTransport, Transport as LettreTransport};
+use lettre::message::{header, Attachment, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::transport::smtp::client::{Tls, TlsParameters};
+use lettre::transport::smtp::{PoolConfig, SMTP_PORT, SUBMISSION_PORT};
+use lettre::{FileTransport, SendmailTransport, SmtpTransport, Transport as LettreTransport};
 use serde::Serialize;
 use uuid::Uuid;
 
 use gettext_macros::i18n;
 use rocket_i18n::I18n;
 
+use crate::mail_dns;
+use crate::mail_events::{DeliveryEvent, Outcome};
+use crate::mail_http;
+use crate::mail_queue::{self, DeliveryStatus, ErrorClass, MailQueue};
+use crate::socks::Socks5Proxy;
 use crate::template_helpers;
 
 use crate::database::types::Email;
+use crate::database::Reloadable;
 use crate::Result;
 
+/// How often the mail queue job looks for mail that is due.
+pub const QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
 mod context {
     #[derive(Serialize, Clone)]
     pub struct Verification {
@@ -22,6 +35,8 @@ mod context {
         pub primary_fp: String,
         pub uri: String,
         pub userid: String,
+        /// Whether replying to the mail verifies the address.
+        pub reply: bool,
         pub base_uri: String,
         pub domain: String,
     }
@@ -35,6 +50,32 @@ mod context {
         pub domain: String,
     }
 
+    #[derive(Serialize, Clone)]
+    pub struct DomainAccess {
+        pub lang: String,
+        pub owner_domain: String,
+        pub token: String,
+        pub validity_minutes: u64,
+        pub base_uri: String,
+        pub domain: String,
+    }
+
+    #[derive(Serialize, Clone)]
+    pub struct Submission {
+        pub lang: String,
+        pub results: Vec<super::SubmissionResult>,
+        pub base_uri: String,
+        pub domain: String,
+    }
+
+    #[derive(Serialize, Clone)]
+    pub struct Notification {
+        pub lang: String,
+        pub message: String,
+        pub base_uri: String,
+        pub domain: String,
+    }
+
     #[derive(Serialize, Clone)]
     pub struct Welcome {
         pub lang: String,
@@ -45,16 +86,138 @@ mod context {
     }
 }
 
+#[derive(Clone)]
 pub struct Service {
     from: Mailbox,
     domain: String,
     templates: Handlebars<'static>,
     transport: Transport,
+    queue: Option<MailQueue>,
+    domain_policy: Reloadable<DomainPolicy>,
+    mail_domain_check: Option<mail_dns::Resolver>,
+    assets: MailAssets,
+    reply_to: Option<Mailbox>,
 }
 
+/// The logo and styling of the instance, which make HTML mail
+/// recognizable.
+///
+/// The logo is sent along as a related MIME part, which templates
+/// refer to as `{{logo}}`, the style sheet is inlined as `{{style}}`.
+/// Unlike remote resources, both are shown by clients that don't load
+/// images from the web.
+#[derive(Clone, Debug, Default)]
+pub struct MailAssets {
+    pub logo: Option<InlineImage>,
+    pub style: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct InlineImage {
+    pub content_id: String,
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+impl MailAssets {
+    pub fn load(logo: Option<&Path>, style: Option<&Path>) -> Result<Self> {
+        let logo = match logo {
+            Some(path) => Some(InlineImage::load(path, "logo")?),
+            None => None,
+        };
+        let style = match style {
+            Some(path) => Some(std::fs::read_to_string(path)?),
+            None => None,
+        };
+        Ok(MailAssets { logo, style })
+    }
+}
+
+impl InlineImage {
+    fn load(path: &Path, name: &str) -> Result<Self> {
+        let extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_lowercase);
+        // Mail clients don't generally render SVG.
+        let content_type = match extension.as_deref() {
+            Some("png") => "image/png",
+            Some("jpg") | Some("jpeg") => "image/jpeg",
+            Some("gif") => "image/gif",
+            _ => {
+                return Err(anyhow!(
+                    "Mail images must be PNG, JPEG or GIF: {}",
+                    path.display()
+                ))
+            }
+        };
+        Ok(InlineImage {
+            // Stable across restarts, for mails in the queue.
+            content_id: format!("{}@hagrid", name),
+            content_type,
+            data: std::fs::read(path)?,
+        })
+    }
+}
+
+#[derive(Clone)]
 enum Transport {
     Sendmail,
     Filemail(PathBuf),
+    HttpApi(mail_http::HttpApi),
+    Smtp(SmtpTransport),
+}
+
+/// What became of a key mailed to the submission address.
+#[derive(Serialize, Clone, Debug)]
+pub struct SubmissionResult {
+    /// The fingerprint, if the key could be parsed.
+    pub key: String,
+    pub status: String,
+}
+
+/// Where and how to deliver mail via SMTP.
+pub struct SmtpRelay {
+    pub host: String,
+    pub port: Option<u16>,
+    pub credentials: Option<(String, String)>,
+    pub starttls: bool,
+    /// Maximum number of open connections to the relay.
+    pub pool_size: u32,
+    /// Seconds after which an unused connection is closed.
+    pub idle_timeout: u64,
+    /// Connect to the relay through this proxy.
+    pub proxy: Option<Socks5Proxy>,
+}
+
+/// Which recipient domains we send verification mail to.
+///
+/// Subdomains of listed domains match as well.
+#[derive(Clone, Debug, Default)]
+pub struct DomainPolicy {
+    /// If not empty, only these domains are allowed.
+    pub allow: Vec<String>,
+    /// Domains we never send verification mail to, e.g. known
+    /// disposable mail providers.
+    pub block: Vec<String>,
+}
+
+impl DomainPolicy {
+    pub fn allows(&self, address: &Email) -> bool {
+        let domain = match address.as_str().rsplit('@').next() {
+            Some(domain) => domain,
+            None => return false,
+        };
+        let matches = |listed: &String| {
+            let listed = listed.to_lowercase();
+            domain == listed || domain.ends_with(&format!(".{}", listed))
+        };
+
+        if !self.allow.is_empty() && !self.allow.iter().any(matches) {
+            return false;
+        }
+        !self.block.iter().any(matches)
+    }
 }
 
 impl Service {
@@ -63,7 +226,8 @@ impl Service {
         Self::new(from, base_uri, template_dir, Transport::Sendmail)
     }
 
-    /// Sends mail by storing it in the given directory.
+    /// Sends mail by storing complete RFC822 messages in the given
+    /// directory.
     pub fn filemail(from: &str, base_uri: &str, template_dir: &Path, path: &Path) -> Result<Self> {
         Self::new(
             from,
@@ -73,6 +237,62 @@ impl Service {
         )
     }
 
+    /// Sends mail using the HTTP API of a hosted mail provider.
+    pub fn http_api(
+        from: &str,
+        base_uri: &str,
+        template_dir: &Path,
+        api: mail_http::HttpApi,
+    ) -> Result<Self> {
+        Self::new(from, base_uri, template_dir, Transport::HttpApi(api))
+    }
+
+    /// Sends mail via an SMTP relay.
+    ///
+    /// Connections are pooled, so that bursts of mail (e.g. during
+    /// bulk imports) are sent over a few long-lived connections
+    /// instead of doing a new handshake for every message.  Queued
+    /// mail is sent in batches over one connection, see
+    /// `process_queue`.
+    ///
+    /// Behind a proxy, the transport connects to a local port that is
+    /// forwarded to the relay, and verifies the relay's certificate
+    /// for its real name.
+    pub fn smtp(from: &str, base_uri: &str, template_dir: &Path, relay: SmtpRelay) -> Result<Self> {
+        let mut builder = if let Some(proxy) = &relay.proxy {
+            let port = relay
+                .port
+                .unwrap_or(if relay.starttls { SUBMISSION_PORT } else { SMTP_PORT });
+            let forward = proxy.forward(&relay.host, port)?;
+            let builder = SmtpTransport::builder_dangerous(forward.ip().to_string())
+                .port(forward.port());
+            if relay.starttls {
+                builder.tls(Tls::Required(TlsParameters::new(relay.host.clone())?))
+            } else {
+                builder
+            }
+        } else {
+            let builder = if relay.starttls {
+                SmtpTransport::starttls_relay(&relay.host)?
+            } else {
+                SmtpTransport::builder_dangerous(&relay.host)
+            };
+            match relay.port {
+                Some(port) => builder.port(port),
+                None => builder,
+            }
+        };
+        if let Some((username, password)) = relay.credentials {
+            builder = builder.credentials(Credentials::new(username, password));
+        }
+        let pool = PoolConfig::new()
+            .max_size(relay.pool_size)
+            .idle_timeout(Duration::from_secs(relay.idle_timeout));
+        let transport = builder.pool_config(pool).build();
+
+        Self::new(from, base_uri, template_dir, Transport::Smtp(transport))
+    }
+
     fn new(from: &str, base_uri: &str, template_dir: &Path, transport: Transport) -> Result<Self> {
         let templates = template_helpers::load_handlebars(template_dir)?;
         let domain = url::Url::parse(base_uri)?
@@ -85,9 +305,209 @@ impl Service {
             domain,
             templates,
             transport,
+            queue: None,
+            domain_policy: Reloadable::default(),
+            mail_domain_check: None,
+            assets: MailAssets::default(),
+            reply_to: None,
         })
     }
 
+    /// Queues mail that fails to be delivered temporarily, and stops
+    /// sending mail to addresses that bounced.
+    pub fn with_queue(mut self, queue: MailQueue) -> Self {
+        self.queue = Some(queue);
+        self
+    }
+
+    /// Embeds the instance's logo and styling in HTML mail.
+    pub fn with_assets(mut self, assets: MailAssets) -> Self {
+        self.assets = assets;
+        self
+    }
+
+    /// Directs replies to verification mail to the submission address,
+    /// where they confirm the address like following the link does.
+    pub fn with_reply_to(mut self, address: &str) -> Result<Self> {
+        let reply_to = address
+            .parse()
+            .map_err(|_| anyhow!("Submission address must be valid email address"))?;
+        self.reply_to = Some(reply_to);
+        Ok(self)
+    }
+
+    /// Restricts the domains we send verification mail to.
+    pub fn with_domain_policy(self, domain_policy: DomainPolicy) -> Self {
+        self.domain_policy.set(domain_policy);
+        self
+    }
+
+    /// Returns the domain policy, which can be changed while the
+    /// service is in use.  Clones of the service share it.
+    pub fn domain_policy(&self) -> Reloadable<DomainPolicy> {
+        self.domain_policy.clone()
+    }
+
+    /// Whether we send verification mail to this address at all.
+    pub fn is_domain_allowed(&self, address: &Email) -> bool {
+        self.domain_policy.read().allows(address)
+    }
+
+    /// Checks that recipient domains accept mail before sending
+    /// verification mail, see `accepts_mail`.
+    pub fn with_mail_domain_check(mut self, resolver: mail_dns::Resolver) -> Self {
+        self.mail_domain_check = Some(resolver);
+        self
+    }
+
+    /// Whether the domain of this address accepts mail.
+    ///
+    /// Unless domain checks are enabled, or if we can't tell, this
+    /// assumes that it does.
+    pub fn accepts_mail(&self, address: &Email) -> bool {
+        let resolver = match &self.mail_domain_check {
+            Some(resolver) => resolver,
+            None => return true,
+        };
+        match address.as_str().rsplit('@').next() {
+            Some(domain) => resolver.accepts_mail(domain).unwrap_or(true),
+            None => false,
+        }
+    }
+
+    pub fn is_suppressed(&self, address: &Email) -> bool {
+        self.queue
+            .as_ref()
+            .map(|queue| queue.is_suppressed(address.as_str()))
+            .unwrap_or(false)
+    }
+
+    /// Returns what happened to the last verification mail sent to
+    /// the given address, if we know.
+    pub fn verification_status(&self, address: &Email) -> Option<DeliveryStatus> {
+        self.queue
+            .as_ref()
+            .and_then(|queue| queue.status(address.as_str(), "verify"))
+    }
+
+    /// Retries delivery of all queued mail that is due.
+    ///
+    /// The mails are sent back to back, so that an SMTP relay gets
+    /// them all over the same pooled connection, instead of a new
+    /// handshake for each of them.
+    pub fn process_queue(&self) -> Result<()> {
+        let queue = match self.queue {
+            Some(ref queue) => queue,
+            None => return Ok(()),
+        };
+
+        let now = mail_queue::unix_now();
+        for (path, mail) in queue.due(now)? {
+            if !queue.try_send(&mail.tos, now) {
+                queue.defer(&path, mail, now)?;
+                continue;
+            }
+
+            let tos = match mail
+                .tos
+                .iter()
+                .map(|to| to.parse())
+                .collect::<Result<Vec<Email>>>()
+            {
+                Ok(tos) => tos,
+                Err(e) => {
+                    warn!("Queued mail {} has a bad recipient: {}", path.display(), e);
+                    queue.set_aside(&path)?;
+                    continue;
+                }
+            };
+            let tos: Vec<&Email> = tos.iter().collect();
+
+            match self.deliver(
+                &tos,
+                &mail.kind,
+                &mail.subject,
+                mail.txt.clone(),
+                mail.html.clone(),
+            ) {
+                Ok(()) => {
+                    set_status(queue, &tos, &mail.kind, DeliveryStatus::Sent)?;
+                    queue.remove(&path)?;
+                }
+                Err(e) if ErrorClass::of(&e) == ErrorClass::Permanent => {
+                    set_status(queue, &tos, &mail.kind, DeliveryStatus::Bounced)?;
+                    self.suppress(queue, &tos, &e.to_string())?;
+                    queue.remove(&path)?;
+                }
+                Err(e) => {
+                    for to in &tos {
+                        counters::inc_mail_delivery("failed", to);
+                    }
+                    let kind = mail.kind.clone();
+                    if !queue.retry_later(&path, mail, &e, now)? {
+                        warn!("Giving up on mail to {:?}: {}", tos, e);
+                        set_status(queue, &tos, &kind, DeliveryStatus::Bounced)?;
+                    }
+                }
+            }
+        }
+
+        let stats = queue.stats(mail_queue::unix_now())?;
+        counters::set_mail_queue(stats.depth, stats.oldest_age);
+        Ok(())
+    }
+
+    /// Whether mail that can't be delivered right away is queued, and
+    /// the queue needs processing.
+    pub fn has_queue(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Records what the provider reported about mail it accepted
+    /// earlier, see `mail_events`.
+    pub fn record_delivery_events(&self, events: &[DeliveryEvent]) -> Result<()> {
+        let queue = match self.queue {
+            Some(ref queue) => queue,
+            None => return Ok(()),
+        };
+
+        for event in events {
+            let to: Email = match event.recipient.parse() {
+                Ok(to) => to,
+                Err(_) => continue,
+            };
+            let status = match event.outcome {
+                Outcome::Delivered => {
+                    counters::inc_mail_delivery("delivered", &to);
+                    Some(DeliveryStatus::Sent)
+                }
+                Outcome::Bounced(ref reason) => {
+                    counters::inc_mail_delivery("bounced", &to);
+                    self.suppress(queue, &[&to], reason)?;
+                    Some(DeliveryStatus::Bounced)
+                }
+                Outcome::Complained => {
+                    counters::inc_mail_delivery("complained", &to);
+                    self.suppress(queue, &[&to], "spam complaint")?;
+                    None
+                }
+            };
+            if let (Some(status), Some(kind)) = (status, &event.kind) {
+                queue.set_status(to.as_str(), kind, status)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn suppress(&self, queue: &MailQueue, tos: &[&Email], reason: &str) -> Result<()> {
+        if queue.policy().suppress_permanent {
+            for to in tos {
+                queue.suppress(to.as_str(), reason)?;
+            }
+        }
+        Ok(())
+    }
+
     pub fn send_verification(
         &self,
         i18n: &I18n,
@@ -96,11 +516,16 @@ impl Service {
         userid: &Email,
         token: &str,
     ) -> Result<()> {
+        if !self.is_domain_allowed(userid) {
+            return Err(anyhow!("Not sending mail to blocked domain"));
+        }
+
         let ctx = context::Verification {
             lang: i18n.lang.to_string(),
             primary_fp: tpk_name,
             uri: format!("{}/verify/{}", base_uri, token),
             userid: userid.to_string(),
+            reply: self.reply_to.is_some(),
             base_uri: base_uri.to_owned(),
             domain: self.domain.clone(),
         };
@@ -161,6 +586,10 @@ impl Service {
         userid: &Email,
         token: &str,
     ) -> Result<()> {
+        if !self.is_domain_allowed(userid) {
+            return Err(anyhow!("Not sending mail to blocked domain"));
+        }
+
         let ctx = context::Welcome {
             lang: "en".to_owned(),
             primary_fp: tpk_name,
@@ -180,12 +609,111 @@ impl Service {
         )
     }
 
+    /// Replies to mail sent to the submission address with the status
+    /// of the keys in it.
+    pub fn send_submission_status(
+        &self,
+        base_uri: &str,
+        recipient: &Email,
+        results: Vec<SubmissionResult>,
+    ) -> Result<()> {
+        if !self.is_domain_allowed(recipient) {
+            return Err(anyhow!("Not sending mail to blocked domain"));
+        }
+
+        let ctx = context::Submission {
+            lang: "en".to_owned(),
+            results,
+            base_uri: base_uri.to_owned(),
+            domain: self.domain.clone(),
+        };
+
+        counters::inc_mail_sent("submission", recipient);
+
+        self.send(
+            &[recipient],
+            &format!("Your key submission to {domain}", domain = self.domain),
+            "submission",
+            "en",
+            ctx,
+        )
+    }
+
+    /// Sends an access token for the domain API to an address that
+    /// only the domain's owner should receive mail for.
+    pub fn send_domain_access(
+        &self,
+        base_uri: &str,
+        owner_domain: &str,
+        recipient: &Email,
+        token: &str,
+        validity: u64,
+    ) -> Result<()> {
+        let ctx = context::DomainAccess {
+            lang: "en".to_owned(),
+            owner_domain: owner_domain.to_owned(),
+            token: token.to_owned(),
+            validity_minutes: validity / 60,
+            base_uri: base_uri.to_owned(),
+            domain: self.domain.clone(),
+        };
+
+        counters::inc_mail_sent("domain-access", recipient);
+
+        self.send(
+            &[recipient],
+            &format!(
+                "Access to keys for {owner} on {domain}",
+                owner = owner_domain,
+                domain = self.domain
+            ),
+            "domain-access",
+            "en",
+            ctx,
+        )
+    }
+
+    /// Sends an operator-facing event, see `notify`.
+    ///
+    /// Unlike mail to users, this is not subject to the domain policy.
+    pub fn send_notification(
+        &self,
+        base_uri: &str,
+        recipient: &Email,
+        message: &str,
+    ) -> Result<()> {
+        let ctx = context::Notification {
+            lang: "en".to_owned(),
+            message: message.to_owned(),
+            base_uri: base_uri.to_owned(),
+            domain: self.domain.clone(),
+        };
+
+        counters::inc_mail_sent("notification", recipient);
+
+        self.send(
+            &[recipient],
+            &format!("Notification from {domain}", domain = self.domain),
+            "notification",
+            "en",
+            ctx,
+        )
+    }
+
     fn render_template(
         &self,
         template: &str,
         locale: &str,
         ctx: impl Serialize,
     ) -> Result<(String, String)> {
+        let mut ctx = serde_json::to_value(ctx)?;
+        if let Some(ctx) = ctx.as_object_mut() {
+            let logo = self.assets.logo.as_ref();
+            let logo = logo.map(|logo| format!("cid:{}", logo.content_id));
+            ctx.insert("logo".into(), logo.into());
+            ctx.insert("style".into(), self.assets.style.clone().into());
+        }
+
         let html = self
             .templates
             .render(&format!("{}/{}.htm", locale, template), &ctx)
@@ -217,6 +745,52 @@ impl Service {
             println!("{}", &txt);
         }
 
+        let queue = match self.queue {
+            Some(ref queue) => queue,
+            None => return self.deliver(tos, template, subject, txt, html),
+        };
+
+        if tos.iter().any(|to| queue.is_suppressed(to.as_str())) {
+            set_status(queue, tos, template, DeliveryStatus::Suppressed)?;
+            return Err(anyhow!("Not sending mail to suppressed address"));
+        }
+
+        let to_strings: Vec<String> = tos.iter().map(|to| to.to_string()).collect();
+        let now = mail_queue::unix_now();
+        if !queue.try_send(&to_strings, now) {
+            set_status(queue, tos, template, DeliveryStatus::Queued)?;
+            return queue.enqueue_throttled(
+                template,
+                to_strings,
+                subject.to_owned(),
+                txt,
+                html,
+                now,
+            );
+        }
+
+        match self.deliver(tos, template, subject, txt.clone(), html.clone()) {
+            Ok(()) => set_status(queue, tos, template, DeliveryStatus::Sent),
+            Err(e) if ErrorClass::of(&e) == ErrorClass::Permanent => {
+                set_status(queue, tos, template, DeliveryStatus::Bounced)?;
+                self.suppress(queue, tos, &e.to_string())?;
+                Err(e)
+            }
+            Err(e) => {
+                set_status(queue, tos, template, DeliveryStatus::Queued)?;
+                queue.enqueue(template, to_strings, subject.to_owned(), txt, html, &e)
+            }
+        }
+    }
+
+    fn deliver(
+        &self,
+        tos: &[&Email],
+        kind: &str,
+        subject: &str,
+        txt: String,
+        html: String,
+    ) -> Result<()> {
         let mut email = lettre::Message::builder()
             .from(self.from.clone())
             .subject(subject)
@@ -226,20 +800,33 @@ impl Service {
         for to in tos.iter() {
             email = email.to(to.as_str().parse().unwrap());
         }
+        let reply_to = self.reply_to.as_ref().filter(|_| kind == "verify");
+        if let Some(reply_to) = reply_to {
+            email = email.reply_to(reply_to.clone());
+        }
 
-        let email = email.multipart(
-            MultiPart::alternative()
-                .singlepart(
-                    SinglePart::builder()
-                        .header(header::ContentTransferEncoding::EightBit)
-                        .header(header::ContentType::TEXT_PLAIN)
-                        .body(txt)
-                )
-                .singlepart(SinglePart::builder()
-                        .header(header::ContentTransferEncoding::EightBit)
-                        .header(header::ContentType::TEXT_HTML)
-                        .body(html)),
-        )?;
+        let html_part = SinglePart::builder()
+            .header(header::ContentTransferEncoding::EightBit)
+            .header(header::ContentType::TEXT_HTML)
+            .body(html.clone());
+        let alternative = MultiPart::alternative().singlepart(
+            SinglePart::builder()
+                .header(header::ContentTransferEncoding::EightBit)
+                .header(header::ContentType::TEXT_PLAIN)
+                .body(txt.clone()),
+        );
+        let alternative = match &self.assets.logo {
+            Some(logo) => alternative.multipart(
+                MultiPart::related().singlepart(html_part).singlepart(
+                    Attachment::new_inline(logo.content_id.clone()).body(
+                        logo.data.clone(),
+                        header::ContentType::parse(logo.content_type)?,
+                    ),
+                ),
+            ),
+            None => alternative.singlepart(html_part),
+        };
+        let email = email.multipart(alternative)?;
 
         match self.transport {
             Transport::Sendmail => {
@@ -250,12 +837,41 @@ impl Service {
                 let transport = FileTransport::new(path);
                 transport.send(&email)?;
             }
+            Transport::HttpApi(ref api) => {
+                let from = self.from.email.to_string();
+                let from_name = self.from.name.as_deref();
+                let reply_to = reply_to.map(|reply_to| reply_to.email.to_string());
+                let logo = self.assets.logo.as_ref();
+                api.send(
+                    &email.formatted(),
+                    &from,
+                    from_name,
+                    reply_to.as_deref(),
+                    tos,
+                    kind,
+                    subject,
+                    &txt,
+                    &html,
+                    logo,
+                )?;
+            }
+            Transport::Smtp(ref transport) => {
+                transport.send(&email)?;
+            }
         }
 
         Ok(())
     }
 }
 
+fn set_status(queue: &MailQueue, tos: &[&Email], kind: &str, status: DeliveryStatus) -> Result<()> {
+    for to in tos {
+        counters::inc_mail_delivery(status.as_str(), to);
+        queue.set_status(to.as_str(), kind, status)?;
+    }
+    Ok(())
+}
+
 /// Returns and removes the first mail it finds from the given
 /// directory.
 #[cfg(test)]
@@ -273,14 +889,91 @@ pub fn pop_mail(dir: &Path) -> Result<Option<String>> {
     Ok(None)
 }
 
+/// A mail as written to the spool directory by the filemail
+/// transport, split into headers and body.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct SpooledMail {
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+#[cfg(test)]
+impl SpooledMail {
+    /// Parses a complete RFC822 message.
+    pub fn parse(raw: &str) -> Self {
+        let raw = raw.replace("\r\n", "\n");
+        let (head, body) = match raw.find("\n\n") {
+            Some(idx) => (&raw[..idx], &raw[idx + 2..]),
+            None => (&raw[..], ""),
+        };
+
+        let mut headers: Vec<(String, String)> = Vec::new();
+        for line in head.lines() {
+            if line.starts_with(' ') || line.starts_with('\t') {
+                // Folded header line, belongs to the previous header.
+                if let Some((_, value)) = headers.last_mut() {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            } else if let Some(idx) = line.find(':') {
+                let name = line[..idx].to_owned();
+                let value = line[idx + 1..].trim().to_owned();
+                headers.push((name, value));
+            }
+        }
+
+        SpooledMail {
+            headers,
+            body: body.to_owned(),
+        }
+    }
+
+    /// Returns the value of the first header with the given name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(h, _)| h.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+/// Returns all mails in the given directory, oldest first, without
+/// removing them.
+#[cfg(test)]
+pub fn list_mails(dir: &Path) -> Result<Vec<SpooledMail>> {
+    use std::fs;
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            paths.push((entry.metadata()?.modified()?, entry.path()));
+        }
+    }
+    paths.sort();
+
+    paths
+        .into_iter()
+        .map(|(_, path)| Ok(SpooledMail::parse(&fs::read_to_string(path)?)))
+        .collect()
+}
+
 #[cfg(test)]
 mod test {
     use crate::web::get_i18n;
 
     use super::*;
+    use std::fs;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
     use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
     use tempfile::{tempdir, TempDir};
 
+    use crate::mail_queue::RetryPolicy;
+
     const BASEDIR: &str = "http://localhost/";
     const FROM: &str = "test@localhost";
     const TO: &str = "recipient@example.org";
@@ -296,15 +989,18 @@ mod test {
         rocket_i18n::I18n { catalog, lang }
     }
 
-    fn configure_mail() -> (Service, TempDir) {
-        let template_dir: PathBuf = ::std::env::current_dir()
+    fn template_dir() -> PathBuf {
+        ::std::env::current_dir()
             .unwrap()
             .join("dist/email-templates")
             .to_str()
             .unwrap()
-            .into();
+            .into()
+    }
+
+    fn configure_mail() -> (Service, TempDir) {
         let tempdir = tempdir().unwrap();
-        let service = Service::filemail(FROM, BASEDIR, &template_dir, tempdir.path()).unwrap();
+        let service = Service::filemail(FROM, BASEDIR, &template_dir(), tempdir.path()).unwrap();
         (service, tempdir)
     }
 
@@ -346,6 +1042,34 @@ mod test {
         assert!(pop_mail(tempdir.path()).unwrap().is_none());
     }
 
+    #[test]
+    fn list_mails_parses_spooled_mail() {
+        let (mail, tempdir) = configure_mail();
+        let i18n = configure_i18n("en");
+        let recipient = Email::from_str(TO).unwrap();
+
+        mail.send_verification(
+            &i18n,
+            "test",
+            "fingerprintoo".to_owned(),
+            &recipient,
+            "token",
+        )
+        .unwrap();
+
+        let mails = list_mails(tempdir.path()).unwrap();
+        assert_eq!(mails.len(), 1);
+        assert_eq!(mails[0].header("To"), Some(TO));
+        assert_eq!(mails[0].header("from"), Some(FROM));
+        assert!(mails[0].header("Subject").unwrap().contains(TO));
+        assert!(mails[0].body.contains("test/verify/token"));
+
+        // Listing does not consume the spool.
+        assert_eq!(list_mails(tempdir.path()).unwrap().len(), 1);
+        assert!(pop_mail(tempdir.path()).unwrap().is_some());
+        assert!(list_mails(tempdir.path()).unwrap().is_empty());
+    }
+
     #[test]
     fn check_verification_mail_en() {
         let (mail, tempdir) = configure_mail();
@@ -371,6 +1095,42 @@ mod test {
         assert!(mail_content.contains("To let others find this key"));
     }
 
+    #[test]
+    fn check_verification_mail_assets() {
+        let (mail, tempdir) = configure_mail();
+        let assets_dir = tempdir.path().join("assets");
+        fs::create_dir(&assets_dir).unwrap();
+        fs::write(assets_dir.join("logo.png"), b"\x89PNG").unwrap();
+        fs::write(assets_dir.join("email.css"), "p { color: #050505; }").unwrap();
+        let assets = MailAssets::load(
+            Some(&assets_dir.join("logo.png")),
+            Some(&assets_dir.join("email.css")),
+        )
+        .unwrap();
+        let mail = mail.with_assets(assets);
+        let i18n = configure_i18n("en");
+        let recipient = Email::from_str(TO).unwrap();
+
+        mail.send_verification(
+            &i18n,
+            "test",
+            "fingerprintoo".to_owned(),
+            &recipient,
+            "token",
+        )
+        .unwrap();
+        let mail_content = pop_mail(tempdir.path()).unwrap().unwrap();
+
+        check_headers(&mail_content);
+        assert!(mail_content.contains("multipart/related"));
+        assert!(mail_content.contains("Content-ID: <logo@hagrid>"));
+        assert!(mail_content.contains("Content-Type: image/png"));
+        assert!(mail_content.contains("src=\"cid:logo@hagrid\""));
+        assert!(mail_content.contains("<style>p { color: #050505; }</style>"));
+
+        assert!(MailAssets::load(Some(&assets_dir.join("email.css")), None).is_err());
+    }
+
     #[test]
     fn check_verification_mail_ja() {
         let (mail, tempdir) = configure_mail();
@@ -470,4 +1230,153 @@ mod test {
         assert!(mail_content.contains("test/about"));
         assert!(mail_content.contains("first time"));
     }
+
+    #[test]
+    fn check_notification_mail() {
+        let (mail, tempdir) = configure_mail();
+        let recipient = Email::from_str(TO).unwrap();
+
+        mail.send_notification("test", &recipient, "Job quarantine-gc failed: oops")
+            .unwrap();
+        let mail_content = pop_mail(tempdir.path()).unwrap().unwrap();
+
+        check_headers(&mail_content);
+        assert!(mail_content.contains("Subject: Notification from localhost"));
+        assert!(mail_content.contains("Job quarantine-gc failed: oops"));
+    }
+
+    #[test]
+    fn domain_policy() {
+        let email = |s| Email::from_str(s).unwrap();
+
+        let policy = DomainPolicy::default();
+        assert!(policy.allows(&email("foo@example.org")));
+
+        let policy = DomainPolicy {
+            allow: vec![],
+            block: vec!["Mailinator.com".to_owned()],
+        };
+        assert!(policy.allows(&email("foo@example.org")));
+        assert!(!policy.allows(&email("foo@mailinator.com")));
+        assert!(!policy.allows(&email("foo@eu.mailinator.com")));
+        assert!(policy.allows(&email("foo@notmailinator.com")));
+
+        let policy = DomainPolicy {
+            allow: vec!["example.org".to_owned()],
+            block: vec!["guests.example.org".to_owned()],
+        };
+        assert!(policy.allows(&email("foo@example.org")));
+        assert!(policy.allows(&email("foo@staff.example.org")));
+        assert!(!policy.allows(&email("foo@guests.example.org")));
+        assert!(!policy.allows(&email("foo@example.com")));
+    }
+
+    #[test]
+    fn blocked_domain_mail() {
+        let (mail, tempdir) = configure_mail();
+        let mail = mail.with_domain_policy(DomainPolicy {
+            allow: vec![],
+            block: vec!["example.org".to_owned()],
+        });
+        let recipient = Email::from_str(TO).unwrap();
+
+        assert!(mail
+            .send_welcome("test", "fingerprintoo".to_owned(), &recipient, "token")
+            .is_err());
+        assert!(pop_mail(tempdir.path()).unwrap().is_none());
+    }
+
+    /// Starts an SMTP relay that accepts all mail, and counts the
+    /// connections and the messages sent over them.
+    fn fake_relay() -> (u16, Arc<AtomicUsize>, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let messages = Arc::new(AtomicUsize::new(0));
+
+        let (c, m) = (connections.clone(), messages.clone());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = stream.unwrap();
+                c.fetch_add(1, Ordering::SeqCst);
+                let m = m.clone();
+                thread::spawn(move || serve_smtp(stream, &m));
+            }
+        });
+        (port, connections, messages)
+    }
+
+    fn serve_smtp(stream: TcpStream, messages: &AtomicUsize) {
+        let mut writer = stream.try_clone().unwrap();
+        let reader = BufReader::new(stream);
+        if writer.write_all(b"220 localhost ESMTP\r\n").is_err() {
+            return;
+        }
+
+        let mut in_data = false;
+        for line in reader.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => return,
+            };
+            let reply: &[u8] = if in_data {
+                if line != "." {
+                    continue;
+                }
+                in_data = false;
+                messages.fetch_add(1, Ordering::SeqCst);
+                b"250 queued\r\n"
+            } else if line.starts_with("DATA") {
+                in_data = true;
+                b"354 go ahead\r\n"
+            } else if line.starts_with("QUIT") {
+                let _ = writer.write_all(b"221 bye\r\n");
+                return;
+            } else {
+                b"250 localhost\r\n"
+            };
+            if writer.write_all(reply).is_err() {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn smtp_queue_batch() {
+        let (port, connections, messages) = fake_relay();
+        let tmpdir = tempdir().unwrap();
+        let relay = SmtpRelay {
+            host: "127.0.0.1".to_owned(),
+            port: Some(port),
+            credentials: None,
+            starttls: false,
+            pool_size: 4,
+            idle_timeout: 60,
+            proxy: None,
+        };
+        let queue = MailQueue::new(tmpdir.path(), RetryPolicy::default()).unwrap();
+        let service = Service::smtp(FROM, BASEDIR, &template_dir(), relay)
+            .unwrap()
+            .with_queue(queue);
+
+        let queue = service.queue.as_ref().unwrap();
+        let now = mail_queue::unix_now();
+        for i in 0..3 {
+            queue
+                .enqueue_throttled(
+                    "verify",
+                    vec![format!("recipient{}@example.org", i)],
+                    "Verify".to_owned(),
+                    "Hello".to_owned(),
+                    "<p>Hello</p>".to_owned(),
+                    now,
+                )
+                .unwrap();
+        }
+
+        service.process_queue().unwrap();
+        assert_eq!(messages.load(Ordering::SeqCst), 3);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+        assert!(queue.due(mail_queue::unix_now()).unwrap().is_empty());
+    }
 }
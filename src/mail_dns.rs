@@ -0,0 +1,167 @@
+//! Checks whether a recipient domain accepts mail at all.
+//!
+//! This lets us tell users right away that their address can't
+//! receive mail, instead of letting verification mail rot in the
+//! queue.  Much like the HTTP API transport, we hand off the actual
+//! work to an external program, in this case dig.  If there are no MX
+//! records, we fall back to the domain's address records, as mail
+//! servers do (RFC 5321, section 5.1).
+//!
+//! The TXT record lookup is used by domain owners to prove that they
+//! control a domain.
+//!
+//! Behind a SOCKS5 proxy, queries go over TCP through the proxy to a
+//! configured name server, instead of to the system resolver.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::process::Command;
+
+use crate::socks::Socks5Proxy;
+
+#[derive(Clone, Debug, Default)]
+pub struct Resolver {
+    /// The local end of the forward to the name server through the
+    /// proxy, if any.
+    forward: Option<SocketAddr>,
+}
+
+impl Resolver {
+    /// Uses the system resolver.
+    pub fn system() -> Self {
+        Resolver::default()
+    }
+
+    /// Sends queries to the name server at `host` and `port` through
+    /// the proxy.
+    pub fn via_proxy(proxy: &Socks5Proxy, host: &str, port: u16) -> io::Result<Self> {
+        Ok(Resolver {
+            forward: Some(proxy.forward(host, port)?),
+        })
+    }
+
+    /// Returns whether the domain accepts mail.
+    ///
+    /// Returns `None` if we can't tell, e.g. because the resolver is
+    /// unreachable.  Callers should give the domain the benefit of the
+    /// doubt then.
+    pub fn accepts_mail(&self, domain: &str) -> Option<bool> {
+        if !is_plausible_domain(domain) {
+            return Some(false);
+        }
+
+        match self.dig("MX", domain) {
+            Some(records) if !records.is_empty() => Some(!is_null_mx(&records)),
+            Some(_) => Some(self.has_address(domain)),
+            None if self.has_address(domain) => Some(true),
+            None => None,
+        }
+    }
+
+    /// Returns the TXT records of the name, each with its strings
+    /// joined, or `None` if the lookup failed.
+    pub fn lookup_txt(&self, name: &str) -> Option<Vec<String>> {
+        if !is_plausible_domain(name) {
+            return None;
+        }
+        let records = self.dig("TXT", name)?;
+        Some(records.iter().map(|record| parse_txt(record)).collect())
+    }
+
+    /// Returns the records of the given type, or `None` if the lookup
+    /// failed.
+    fn dig(&self, record_type: &str, domain: &str) -> Option<Vec<String>> {
+        let mut command = Command::new("dig");
+        command.args(&["+short", "+time=5", "+tries=2", record_type]);
+        if let Some(forward) = self.forward {
+            command
+                .arg("+tcp")
+                .arg(format!("@{}", forward.ip()))
+                .arg("-p")
+                .arg(forward.port().to_string());
+        }
+        let output = command.arg(domain).output().ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        // Errors such as timeouts are reported as comments.
+        if stdout.lines().any(|line| line.starts_with(';')) {
+            return None;
+        }
+        Some(
+            stdout
+                .lines()
+                .map(|line| line.trim().to_owned())
+                .filter(|line| !line.is_empty())
+                .collect(),
+        )
+    }
+
+    fn has_address(&self, domain: &str) -> bool {
+        if self.forward.is_some() {
+            // The system resolver would bypass the proxy.
+            return ["A", "AAAA"].iter().any(|record_type| {
+                self.dig(record_type, domain)
+                    .map_or(false, |records| !records.is_empty())
+            });
+        }
+        (domain, 25)
+            .to_socket_addrs()
+            .map(|mut addrs| addrs.next().is_some())
+            .unwrap_or(false)
+    }
+}
+
+/// Keeps anything that isn't a domain name away from the command line.
+fn is_plausible_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && !domain.starts_with('-')
+        && domain
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+}
+
+/// Whether the records consist of a single "null MX" record, which
+/// says that the domain doesn't accept mail (RFC 7505).
+fn is_null_mx(records: &[String]) -> bool {
+    records.len() == 1 && records[0].split_whitespace().nth(1) == Some(".")
+}
+
+/// Joins the quoted strings of a TXT record, as printed by dig.
+fn parse_txt(record: &str) -> String {
+    record.split('"').skip(1).step_by(2).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plausible_domain() {
+        assert!(is_plausible_domain("example.org"));
+        assert!(is_plausible_domain("xn--yp8h.example.org"));
+        assert!(!is_plausible_domain(""));
+        assert!(!is_plausible_domain("-f.example.org"));
+        assert!(!is_plausible_domain("example.org; rm -rf /"));
+        assert!(is_plausible_domain("_hagrid-challenge.example.org"));
+        assert_eq!(Resolver::system().accepts_mail("not a domain"), Some(false));
+    }
+
+    #[test]
+    fn null_mx() {
+        assert!(is_null_mx(&["0 .".to_owned()]));
+        assert!(!is_null_mx(&["10 mx.example.org.".to_owned()]));
+        assert!(!is_null_mx(&[
+            "0 .".to_owned(),
+            "10 mx.example.org.".to_owned()
+        ]));
+    }
+
+    #[test]
+    fn txt_strings() {
+        assert_eq!(parse_txt("\"v=spf1 -all\""), "v=spf1 -all");
+        assert_eq!(parse_txt("\"hagrid-\" \"challenge\""), "hagrid-challenge");
+        assert_eq!(parse_txt(""), "");
+    }
+}
@@ -0,0 +1,362 @@
+//! Delivery notifications from hosted mail providers.
+//!
+//! With the HTTP API transport, the provider accepts mail right away,
+//! and bounces only show up later.  Providers report them, along with
+//! deliveries and spam complaints, to a webhook.  Notifications are
+//! signed, with a shared key for Mailgun and an ECDSA key for
+//! Sendgrid, and have to be recent, so that they can be neither forged
+//! nor replayed much later.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use ring::{digest, hmac, signature};
+use sequoia_openpgp::fmt::hex;
+
+use crate::mail_http::Provider;
+use crate::Result;
+
+/// The custom variable holding the kind of mail, e.g. "verify", which
+/// providers pass back in notifications.
+pub const KIND_VARIABLE: &str = "hagrid_kind";
+
+/// Notifications older than this many seconds are rejected.
+const MAX_AGE: u64 = 10 * 60;
+
+/// What Sendgrid's verification key, a DER-encoded P-256 public key,
+/// starts with.  The uncompressed point follows.
+const P256_SPKI_PREFIX: &[u8] = &[
+    0x30, 0x59, 0x30, 0x13, 0x06, 0x07, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01, 0x06, 0x08, 0x2a,
+    0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07, 0x03, 0x42, 0x00,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Delivered,
+    /// The recipient's server refused the mail for good.
+    Bounced(String),
+    /// The recipient marked the mail as spam.
+    Complained,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryEvent {
+    pub recipient: String,
+    pub kind: Option<String>,
+    pub outcome: Outcome,
+}
+
+enum Key {
+    Hmac(hmac::VerificationKey),
+    /// The uncompressed P-256 point.
+    Ecdsa(Vec<u8>),
+}
+
+pub struct Webhook {
+    provider: Provider,
+    key: Key,
+    /// Mailgun's signatures don't cover the body, so we remember the
+    /// tokens of recent notifications to refuse replays.
+    seen_tokens: Mutex<HashMap<String, u64>>,
+}
+
+impl Webhook {
+    /// Creates a webhook for the provider.
+    ///
+    /// For Mailgun, `key` is the webhook signing key.  For Sendgrid,
+    /// it is the verification key of the signed event webhook, as
+    /// shown in its settings.
+    pub fn new(provider: Provider, key: &str) -> Result<Self> {
+        let key = match provider {
+            Provider::Mailgun => {
+                Key::Hmac(hmac::VerificationKey::new(&digest::SHA256, key.as_bytes()))
+            }
+            Provider::Sendgrid => {
+                let der = base64::decode(key.trim())?;
+                if !der.starts_with(P256_SPKI_PREFIX) {
+                    return Err(anyhow!("Webhook key is not a P-256 public key"));
+                }
+                Key::Ecdsa(der[P256_SPKI_PREFIX.len()..].to_vec())
+            }
+        };
+        Ok(Webhook {
+            provider,
+            key,
+            seen_tokens: Default::default(),
+        })
+    }
+
+    /// Verifies a notification, and returns the events in it we care
+    /// about.
+    ///
+    /// Sendgrid sends the signature and timestamp in headers, Mailgun
+    /// includes them in the body.
+    pub fn events(
+        &self,
+        body: &[u8],
+        signature: Option<&str>,
+        timestamp: Option<&str>,
+        now: u64,
+    ) -> Result<Vec<DeliveryEvent>> {
+        match self.provider {
+            Provider::Mailgun => self.mailgun_events(body, now),
+            Provider::Sendgrid => {
+                let signature = signature.ok_or_else(|| anyhow!("Missing signature"))?;
+                let timestamp = timestamp.ok_or_else(|| anyhow!("Missing timestamp"))?;
+                self.sendgrid_events(body, signature, timestamp, now)
+            }
+        }
+    }
+
+    fn mailgun_events(&self, body: &[u8], now: u64) -> Result<Vec<DeliveryEvent>> {
+        let notification: mailgun::Notification = serde_json::from_slice(body)?;
+        let signature = &notification.signature;
+        check_timestamp(&signature.timestamp, now)?;
+        let message = format!("{}{}", signature.timestamp, signature.token);
+        let expected = hex::decode(&signature.signature)?;
+        match &self.key {
+            Key::Hmac(key) => hmac::verify(key, message.as_bytes(), &expected)
+                .map_err(|_| anyhow!("Bad signature"))?,
+            Key::Ecdsa(_) => unreachable!("Mailgun uses HMAC"),
+        }
+        {
+            let mut seen_tokens = self.seen_tokens.lock().unwrap();
+            seen_tokens.retain(|_, seen| *seen + MAX_AGE >= now);
+            if seen_tokens.insert(signature.token.clone(), now).is_some() {
+                return Err(anyhow!("Replayed notification"));
+            }
+        }
+
+        let event = notification.event_data;
+        let outcome = match (event.event.as_str(), event.severity.as_deref()) {
+            ("delivered", _) => Outcome::Delivered,
+            ("failed", Some("permanent")) => Outcome::Bounced(
+                event
+                    .delivery_status
+                    .and_then(|status| status.message.or(status.description))
+                    .unwrap_or_else(|| "bounced".to_owned()),
+            ),
+            ("complained", _) => Outcome::Complained,
+            // Temporary failures are retried by Mailgun.
+            _ => return Ok(vec![]),
+        };
+        Ok(vec![DeliveryEvent {
+            recipient: event.recipient,
+            kind: event.user_variables.get(KIND_VARIABLE).cloned(),
+            outcome,
+        }])
+    }
+
+    fn sendgrid_events(
+        &self,
+        body: &[u8],
+        signature: &str,
+        timestamp: &str,
+        now: u64,
+    ) -> Result<Vec<DeliveryEvent>> {
+        check_timestamp(timestamp, now)?;
+        let mut message = timestamp.as_bytes().to_vec();
+        message.extend_from_slice(body);
+        let signature = base64::decode(signature.trim())?;
+        match &self.key {
+            Key::Ecdsa(point) => signature::verify(
+                &signature::ECDSA_P256_SHA256_ASN1,
+                untrusted::Input::from(point),
+                untrusted::Input::from(&message),
+                untrusted::Input::from(&signature),
+            )
+            .map_err(|_| anyhow!("Bad signature"))?,
+            Key::Hmac(_) => unreachable!("Sendgrid uses ECDSA"),
+        }
+
+        let events: Vec<sendgrid::Event> = serde_json::from_slice(body)?;
+        Ok(events
+            .into_iter()
+            .filter_map(|event| {
+                let outcome = match (event.event.as_str(), event.r#type.as_deref()) {
+                    ("delivered", _) => Outcome::Delivered,
+                    // Blocks are temporary, and retried by Sendgrid.
+                    ("bounce", Some("blocked")) => return None,
+                    ("bounce", _) | ("dropped", _) => Outcome::Bounced(
+                        event.reason.clone().unwrap_or_else(|| event.event.clone()),
+                    ),
+                    ("spamreport", _) => Outcome::Complained,
+                    _ => return None,
+                };
+                Some(DeliveryEvent {
+                    recipient: event.email,
+                    kind: event.kind,
+                    outcome,
+                })
+            })
+            .collect())
+    }
+}
+
+fn check_timestamp(timestamp: &str, now: u64) -> Result<()> {
+    let timestamp: u64 = timestamp.trim().parse()?;
+    let age = if now > timestamp {
+        now - timestamp
+    } else {
+        timestamp - now
+    };
+    if age > MAX_AGE {
+        return Err(anyhow!("Stale notification"));
+    }
+    Ok(())
+}
+
+mod mailgun {
+    use super::HashMap;
+
+    #[derive(Deserialize)]
+    pub struct Notification {
+        pub signature: Signature,
+        #[serde(rename = "event-data")]
+        pub event_data: EventData,
+    }
+
+    #[derive(Deserialize)]
+    pub struct Signature {
+        pub timestamp: String,
+        pub token: String,
+        pub signature: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct EventData {
+        pub event: String,
+        pub recipient: String,
+        #[serde(default)]
+        pub severity: Option<String>,
+        #[serde(default, rename = "delivery-status")]
+        pub delivery_status: Option<DeliveryStatus>,
+        #[serde(default, rename = "user-variables")]
+        pub user_variables: HashMap<String, String>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct DeliveryStatus {
+        #[serde(default)]
+        pub message: Option<String>,
+        #[serde(default)]
+        pub description: Option<String>,
+    }
+}
+
+mod sendgrid {
+    #[derive(Deserialize)]
+    pub struct Event {
+        pub email: String,
+        pub event: String,
+        #[serde(default)]
+        pub r#type: Option<String>,
+        #[serde(default)]
+        pub reason: Option<String>,
+        /// Custom arguments are passed back as top-level fields.
+        #[serde(default, rename = "hagrid_kind")]
+        pub kind: Option<String>,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn mailgun() {
+        let webhook = Webhook::new(Provider::Mailgun, "sekrit").unwrap();
+        let now = 1_600_000_000;
+        let notification = |timestamp: u64, token: &str, event: &serde_json::Value| {
+            let timestamp = timestamp.to_string();
+            let key = hmac::SigningKey::new(&digest::SHA256, b"sekrit");
+            let signature = hmac::sign(&key, format!("{}{}", timestamp, token).as_bytes());
+            json!({
+                "signature": {
+                    "timestamp": timestamp,
+                    "token": token,
+                    "signature": hex::encode(signature.as_ref()),
+                },
+                "event-data": event,
+            })
+            .to_string()
+        };
+
+        let bounce = json!({
+            "event": "failed",
+            "severity": "permanent",
+            "recipient": "foo@example.org",
+            "delivery-status": { "message": "550 no such user" },
+            "user-variables": { "hagrid_kind": "verify" },
+        });
+        let body = notification(now, "token1", &bounce);
+        let events = webhook.events(body.as_bytes(), None, None, now).unwrap();
+        assert_eq!(
+            events,
+            vec![DeliveryEvent {
+                recipient: "foo@example.org".to_owned(),
+                kind: Some("verify".to_owned()),
+                outcome: Outcome::Bounced("550 no such user".to_owned()),
+            }]
+        );
+
+        // Replayed.
+        assert!(webhook.events(body.as_bytes(), None, None, now).is_err());
+        let stale = notification(now - MAX_AGE - 1, "token2", &bounce);
+        assert!(webhook.events(stale.as_bytes(), None, None, now).is_err());
+
+        // Temporary failures are left to Mailgun.
+        let temporary = json!({
+            "event": "failed",
+            "severity": "temporary",
+            "recipient": "foo@example.org",
+        });
+        let body = notification(now, "token3", &temporary);
+        let wrong_key = Webhook::new(Provider::Mailgun, "other").unwrap();
+        assert!(wrong_key.events(body.as_bytes(), None, None, now).is_err());
+        assert_eq!(
+            webhook.events(body.as_bytes(), None, None, now).unwrap(),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn sendgrid() {
+        // Generated with openssl.
+        let key =
+            "MFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE/CbXk71YSVb2l/KxDu8RReVId8/kzymS3iGCMEFyLXcL\
+                   FQbP80aCBJ7BCagsGAsJKi9hSEFFUMjgVyobapR15A==";
+        let signature =
+            "MEQCIEgOl/VN/TLb3erL6HIDAoPfMoBDfUm/bob5V0qgbVN3AiAO5h18Qsbkz0flt9nX52471Kty\
+                         8W9ZXaJYOI9tRu3CsQ==";
+        let body = r#"[{"email":"foo@example.org","event":"delivered","hagrid_kind":"verify"},{"email":"bar@example.org","event":"spamreport"},{"email":"baz@example.org","event":"open"}]"#;
+        let timestamp = "1600000000";
+        let now = 1_600_000_000;
+        let webhook = Webhook::new(Provider::Sendgrid, key).unwrap();
+
+        let events = webhook
+            .events(body.as_bytes(), Some(signature), Some(timestamp), now)
+            .unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].recipient, "foo@example.org");
+        assert_eq!(events[0].outcome, Outcome::Delivered);
+        assert_eq!(events[0].kind.as_deref(), Some("verify"));
+        assert_eq!(events[1].outcome, Outcome::Complained);
+
+        let tampered = body.replace("spamreport", "delivered");
+        assert!(webhook
+            .events(tampered.as_bytes(), Some(signature), Some(timestamp), now)
+            .is_err());
+        assert!(webhook
+            .events(
+                body.as_bytes(),
+                Some(signature),
+                Some(timestamp),
+                now + MAX_AGE + 1
+            )
+            .is_err());
+        assert!(webhook.events(body.as_bytes(), None, None, now).is_err());
+        assert!(Webhook::new(Provider::Sendgrid, "c2Vrcml0").is_err());
+    }
+}
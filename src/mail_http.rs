@@ -0,0 +1,337 @@
+//! Mail transport using the HTTP APIs of hosted mail providers.
+//!
+//! This is useful for deployments where outbound SMTP is blocked.
+//! Much like the sendmail transport, we hand off the actual work to
+//! an external program, in this case curl.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde_json::json;
+
+use crate::database::types::Email;
+use crate::http_client::HttpClient;
+use crate::mail::InlineImage;
+use crate::mail_events::KIND_VARIABLE;
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Mailgun,
+    Sendgrid,
+}
+
+impl FromStr for Provider {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mailgun" => Ok(Provider::Mailgun),
+            "sendgrid" => Ok(Provider::Sendgrid),
+            _ => Err(anyhow!("Unknown mail API provider: {}", s)),
+        }
+    }
+}
+
+/// Why a single API request failed.
+#[derive(Debug, PartialEq, Eq)]
+enum ApiError {
+    /// Network errors, rate limiting, and server errors.  Worth
+    /// retrying, which is left to the mail queue.
+    Temporary(String),
+    /// The provider refused the message.  Retrying won't help.
+    Permanent(String),
+}
+
+/// The provider refused a message.
+#[derive(Debug)]
+pub struct Rejected(pub String);
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Mail API rejected message: {}", self.0)
+    }
+}
+
+impl std::error::Error for Rejected {}
+
+#[derive(Clone)]
+pub struct HttpApi {
+    provider: Provider,
+    endpoint: String,
+    api_key: String,
+    client: HttpClient,
+}
+
+impl HttpApi {
+    /// Creates a new API transport.
+    ///
+    /// If no endpoint is given, the provider's default is used.  For
+    /// Mailgun, that endpoint depends on the sending domain, which is
+    /// taken from the `from` address.
+    pub fn new(
+        provider: Provider,
+        api_key: String,
+        endpoint: Option<String>,
+        from: &str,
+        client: HttpClient,
+    ) -> Self {
+        let endpoint = endpoint.unwrap_or_else(|| match provider {
+            Provider::Mailgun => {
+                let domain = from.trim_end_matches('>').rsplit('@').next().unwrap_or("");
+                format!("https://api.mailgun.net/v3/{}/messages.mime", domain)
+            }
+            Provider::Sendgrid => "https://api.sendgrid.com/v3/mail/send".to_owned(),
+        });
+        HttpApi {
+            provider,
+            endpoint,
+            api_key,
+            client,
+        }
+    }
+
+    /// Sends a message.
+    ///
+    /// Temporary failures are not retried here, where they would hold
+    /// up the request that sends the mail.  The mail queue retries
+    /// them, if configured.
+    ///
+    /// Mailgun takes the complete message in `raw`, Sendgrid wants
+    /// the individual parts, including the logo the HTML part refers
+    /// to.  The kind of mail is passed along, so
+    /// that delivery notifications can tell it.
+    #[allow(clippy::too_many_arguments)]
+    pub fn send(
+        &self,
+        raw: &[u8],
+        from: &str,
+        from_name: Option<&str>,
+        reply_to: Option<&str>,
+        tos: &[&Email],
+        kind: &str,
+        subject: &str,
+        txt: &str,
+        html: &str,
+        logo: Option<&InlineImage>,
+    ) -> Result<()> {
+        let result = match self.provider {
+            Provider::Mailgun => self.post_mailgun(raw, tos, kind),
+            Provider::Sendgrid => self.post_sendgrid(
+                from, from_name, reply_to, tos, kind, subject, txt, html, logo,
+            ),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(ApiError::Temporary(e)) => Err(anyhow!("Mail API unavailable: {}", e)),
+            Err(ApiError::Permanent(e)) => Err(Rejected(e).into()),
+        }
+    }
+
+    fn post_mailgun(
+        &self,
+        raw: &[u8],
+        tos: &[&Email],
+        kind: &str,
+    ) -> std::result::Result<(), ApiError> {
+        let mut args = vec![];
+        for to in tos {
+            args.push("--form-string".to_owned());
+            args.push(format!("to={}", to));
+        }
+        args.push("--form-string".to_owned());
+        args.push(format!("v:{}={}", KIND_VARIABLE, kind));
+        args.push("--form".to_owned());
+        args.push("message=@-;filename=message.mime".to_owned());
+
+        let auth = format!("user = \"api:{}\"\n", curl_escape(&self.api_key));
+        self.curl(&args, &auth, raw)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn post_sendgrid(
+        &self,
+        from: &str,
+        from_name: Option<&str>,
+        reply_to: Option<&str>,
+        tos: &[&Email],
+        kind: &str,
+        subject: &str,
+        txt: &str,
+        html: &str,
+        logo: Option<&InlineImage>,
+    ) -> std::result::Result<(), ApiError> {
+        let body = sendgrid_body(
+            from, from_name, reply_to, tos, kind, subject, txt, html, logo,
+        )
+        .to_string();
+        let args = vec![
+            "--header".to_owned(),
+            "Content-Type: application/json".to_owned(),
+            "--data-binary".to_owned(),
+            "@-".to_owned(),
+        ];
+
+        let auth = format!(
+            "header = \"Authorization: Bearer {}\"\n",
+            curl_escape(&self.api_key)
+        );
+        self.curl(&args, &auth, body.as_bytes())
+    }
+
+    /// Posts `body` to the endpoint.
+    ///
+    /// Credentials are passed in a private config file rather than on
+    /// the command line, where they would be visible to other users.
+    fn curl(&self, args: &[String], auth: &str, body: &[u8]) -> std::result::Result<(), ApiError> {
+        match self.client.post(&self.endpoint, args, auth, body) {
+            Ok(status) => classify_status(status),
+            Err(e) => Err(ApiError::Temporary(e.to_string())),
+        }
+    }
+}
+
+fn classify_status(status: u16) -> std::result::Result<(), ApiError> {
+    match status {
+        200..=299 => Ok(()),
+        429 | 500..=599 => Err(ApiError::Temporary(format!("HTTP status {}", status))),
+        _ => Err(ApiError::Permanent(format!("HTTP status {}", status))),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn sendgrid_body(
+    from: &str,
+    from_name: Option<&str>,
+    reply_to: Option<&str>,
+    tos: &[&Email],
+    kind: &str,
+    subject: &str,
+    txt: &str,
+    html: &str,
+    logo: Option<&InlineImage>,
+) -> serde_json::Value {
+    let tos: Vec<_> = tos.iter().map(|to| json!({ "email": to })).collect();
+    let mut body = json!({
+        "personalizations": [{ "to": tos }],
+        "from": { "email": from },
+        "subject": subject,
+        "custom_args": { KIND_VARIABLE: kind },
+        "content": [
+            { "type": "text/plain", "value": txt },
+            { "type": "text/html", "value": html },
+        ],
+    });
+    if let Some(from_name) = from_name {
+        body["from"]["name"] = json!(from_name);
+    }
+    if let Some(reply_to) = reply_to {
+        body["reply_to"] = json!({ "email": reply_to });
+    }
+    if let Some(logo) = logo {
+        body["attachments"] = json!([{
+            "content": base64::encode(&logo.data),
+            "type": logo.content_type,
+            "filename": logo.content_id,
+            "disposition": "inline",
+            "content_id": logo.content_id,
+        }]);
+    }
+    body
+}
+
+/// Escapes a value for a quoted string in a curl config file.
+pub fn curl_escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_classes() {
+        assert_eq!(classify_status(200), Ok(()));
+        assert_eq!(classify_status(202), Ok(()));
+        assert!(matches!(classify_status(429), Err(ApiError::Temporary(_))));
+        assert!(matches!(classify_status(503), Err(ApiError::Temporary(_))));
+        assert!(matches!(classify_status(400), Err(ApiError::Permanent(_))));
+        assert!(matches!(classify_status(401), Err(ApiError::Permanent(_))));
+    }
+
+    #[test]
+    fn default_endpoints() {
+        let api = HttpApi::new(
+            Provider::Mailgun,
+            "key".to_owned(),
+            None,
+            "Keys <noreply@example.org>",
+            HttpClient::default(),
+        );
+        assert_eq!(
+            api.endpoint,
+            "https://api.mailgun.net/v3/example.org/messages.mime"
+        );
+        let api = HttpApi::new(
+            Provider::Sendgrid,
+            "key".to_owned(),
+            None,
+            "noreply@example.org",
+            HttpClient::default(),
+        );
+        assert_eq!(api.endpoint, "https://api.sendgrid.com/v3/mail/send");
+    }
+
+    #[test]
+    fn sendgrid_json() {
+        let to: Email = "foo@example.org".parse().unwrap();
+        let body = sendgrid_body(
+            "noreply@example.org",
+            None,
+            None,
+            &[&to],
+            "verify",
+            "Hello",
+            "txt",
+            "<p>html</p>",
+            None,
+        );
+        assert_eq!(
+            body["personalizations"][0]["to"][0]["email"],
+            "foo@example.org"
+        );
+        assert_eq!(body["from"]["email"], "noreply@example.org");
+        assert!(body["from"].get("name").is_none());
+        assert_eq!(body["content"][1]["value"], "<p>html</p>");
+        assert_eq!(body["custom_args"]["hagrid_kind"], "verify");
+        assert!(body.get("attachments").is_none());
+        assert!(body.get("reply_to").is_none());
+
+        let logo = InlineImage {
+            content_id: "logo@hagrid".to_owned(),
+            content_type: "image/png",
+            data: b"png".to_vec(),
+        };
+        let body = sendgrid_body(
+            "noreply@example.org",
+            Some("Keys"),
+            Some("submit@example.org"),
+            &[&to],
+            "verify",
+            "",
+            "",
+            "",
+            Some(&logo),
+        );
+        assert_eq!(body["attachments"][0]["content"], "cG5n");
+        assert_eq!(body["attachments"][0]["disposition"], "inline");
+        assert_eq!(body["attachments"][0]["content_id"], "logo@hagrid");
+        assert_eq!(body["reply_to"]["email"], "submit@example.org");
+        assert_eq!(body["from"]["name"], "Keys");
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(curl_escape(r#"a"b\c"#), r#"a\"b\\c"#);
+    }
+}
@@ -0,0 +1,315 @@
+//! Just enough MIME (RFC 2045, 2046) to read mail sent to us.
+//!
+//! We look at a few headers, and the decoded bodies of the leaf parts,
+//! e.g. to find keys attached to a submission.  Anything we don't
+//! understand is passed through undecoded, rather than rejected.
+
+/// Multiparts nested deeper than this are not split up any further.
+const MAX_DEPTH: usize = 8;
+
+#[derive(Debug)]
+pub struct Part {
+    headers: Vec<(String, String)>,
+    /// The body with the transfer encoding removed, unless this is a
+    /// multipart.
+    body: Vec<u8>,
+    parts: Vec<Part>,
+}
+
+/// Parses a message, which may use either CRLF or LF line endings.
+pub fn parse(raw: &[u8]) -> Part {
+    let normalized: Vec<u8> = String::from_utf8_lossy(raw)
+        .replace("\r\n", "\n")
+        .into_bytes();
+    parse_part(&normalized, 0)
+}
+
+fn parse_part(raw: &[u8], depth: usize) -> Part {
+    let (head, body) = match find(raw, b"\n\n") {
+        Some(end) => (&raw[..end], &raw[end + 2..]),
+        // A part without a body, or without headers.
+        None if raw.starts_with(b"\n") => (&raw[..0], &raw[1..]),
+        None => (raw, &raw[raw.len()..]),
+    };
+    let mut part = Part {
+        headers: parse_headers(&String::from_utf8_lossy(head)),
+        body: vec![],
+        parts: vec![],
+    };
+
+    let boundary = part.header_param("Content-Type", "boundary");
+    match boundary {
+        Some(boundary) if part.content_type().starts_with("multipart/") && depth < MAX_DEPTH => {
+            part.parts = split_multipart(body, &boundary)
+                .into_iter()
+                .map(|section| parse_part(section, depth + 1))
+                .collect();
+        }
+        _ => {
+            let encoding = part
+                .header("Content-Transfer-Encoding")
+                .unwrap_or("7bit")
+                .trim()
+                .to_lowercase();
+            part.body = match encoding.as_str() {
+                "base64" => decode_base64(body).unwrap_or_else(|| body.to_vec()),
+                "quoted-printable" => decode_quoted_printable(body),
+                _ => body.to_vec(),
+            };
+        }
+    }
+    part
+}
+
+impl Part {
+    /// Returns the value of the first header with the given name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the media type, lowercased, without parameters.
+    pub fn content_type(&self) -> String {
+        self.header("Content-Type")
+            .and_then(|value| value.split(';').next())
+            .map(|media_type| media_type.trim().to_lowercase())
+            .filter(|media_type| !media_type.is_empty())
+            .unwrap_or_else(|| "text/plain".to_owned())
+    }
+
+    /// Returns a parameter of a header, e.g. the boundary of a
+    /// Content-Type.
+    pub fn header_param(&self, header: &str, name: &str) -> Option<String> {
+        split_params(self.header(header)?)
+            .into_iter()
+            .skip(1)
+            .filter_map(|param| {
+                let mut kv = param.splitn(2, '=');
+                let key = kv.next()?.trim();
+                let value = kv.next()?.trim().trim_matches('"');
+                Some((key.to_owned(), value.to_owned()))
+            })
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Returns the parts that are not multiparts, in order.
+    pub fn leaves(&self) -> Vec<&Part> {
+        if self.parts.is_empty() {
+            return vec![self];
+        }
+        self.parts.iter().flat_map(Part::leaves).collect()
+    }
+
+    /// Returns the text of the first text/plain part.
+    pub fn text(&self) -> Option<String> {
+        self.leaves()
+            .into_iter()
+            .find(|part| part.content_type() == "text/plain")
+            .map(|part| String::from_utf8_lossy(part.body()).into_owned())
+    }
+}
+
+/// Returns the address of a From or Reply-To header, e.g.
+/// `Alice <alice@example.org>`.
+pub fn address(value: &str) -> Option<String> {
+    let address = match (value.rfind('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value,
+    };
+    let address = address.trim();
+    if address.contains('@') && !address.contains(char::is_whitespace) {
+        Some(address.to_owned())
+    } else {
+        None
+    }
+}
+
+fn parse_headers(head: &str) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = vec![];
+    for line in head.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            // Continuation of a folded header.
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some(colon) = line.find(':') {
+            let name = line[..colon].trim().to_owned();
+            let value = line[colon + 1..].trim().to_owned();
+            headers.push((name, value));
+        }
+    }
+    headers
+}
+
+/// Splits a header value at semicolons outside of quotes.
+fn split_params(value: &str) -> Vec<&str> {
+    let mut params = vec![];
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in value.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ';' if !quoted => {
+                params.push(&value[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    params.push(&value[start..]);
+    params
+}
+
+/// Returns the sections between the boundary delimiters, without the
+/// preamble and epilogue.
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let mut sections = vec![];
+    let mut start = None;
+    let mut offset = 0;
+    for line in body.split(|b| *b == b'\n') {
+        let line_end = offset + line.len();
+        let trimmed = trim_end(line);
+        if trimmed.starts_with(delimiter.as_bytes()) {
+            let rest = &trimmed[delimiter.len()..];
+            if rest.is_empty() || rest == b"--" {
+                if let Some(start) = start {
+                    // The newline before the delimiter belongs to it.
+                    sections.push(&body[start..offset.saturating_sub(1).max(start)]);
+                }
+                if rest == b"--" {
+                    return sections;
+                }
+                start = Some((line_end + 1).min(body.len()));
+            }
+        }
+        offset = line_end + 1;
+    }
+    // Tolerate a missing close delimiter.
+    if let Some(start) = start {
+        sections.push(&body[start..]);
+    }
+    sections
+}
+
+fn trim_end(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(0, |i| i + 1);
+    &line[..end]
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn decode_base64(body: &[u8]) -> Option<Vec<u8>> {
+    let encoded: Vec<u8> = body
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    base64::decode(&encoded).ok()
+}
+
+fn decode_quoted_printable(body: &[u8]) -> Vec<u8> {
+    let hex = |b: u8| (b as char).to_digit(16);
+    let mut decoded = Vec::with_capacity(body.len());
+    let mut i = 0;
+    while i < body.len() {
+        if body[i] == b'=' {
+            // A soft line break.
+            if body.get(i + 1) == Some(&b'\n') {
+                i += 2;
+                continue;
+            }
+            if let (Some(high), Some(low)) = (
+                body.get(i + 1).copied().and_then(hex),
+                body.get(i + 2).copied().and_then(hex),
+            ) {
+                decoded.push((high * 16 + low) as u8);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(body[i]);
+        i += 1;
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MESSAGE: &str = "From: Alice <alice@example.org>\r\n\
+        Subject: my key\r\n\
+        Content-Type: multipart/mixed;\r\n\
+        \tboundary=\"frontier; x\"\r\n\
+        \r\n\
+        This is the preamble.\r\n\
+        --frontier; x\r\n\
+        Content-Type: text/plain; charset=utf-8\r\n\
+        Content-Transfer-Encoding: quoted-printable\r\n\
+        \r\n\
+        Please publish =\r\n\
+        my key=3D\r\n\
+        --frontier; x\r\n\
+        Content-Type: application/pgp-keys\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        \r\n\
+        a2V5\r\n\
+        ZGF0YQ==\r\n\
+        --frontier; x--\r\n\
+        epilogue\r\n";
+
+    #[test]
+    fn multipart() {
+        let message = parse(MESSAGE.as_bytes());
+        assert_eq!(message.header("from"), Some("Alice <alice@example.org>"));
+        assert_eq!(message.content_type(), "multipart/mixed");
+        assert_eq!(
+            message.header_param("Content-Type", "boundary").as_deref(),
+            Some("frontier; x")
+        );
+
+        let leaves = message.leaves();
+        assert_eq!(leaves.len(), 2);
+        assert_eq!(leaves[0].body(), b"Please publish my key=");
+        assert_eq!(leaves[1].content_type(), "application/pgp-keys");
+        assert_eq!(leaves[1].body(), b"keydata");
+        assert_eq!(message.text().as_deref(), Some("Please publish my key="));
+    }
+
+    #[test]
+    fn single_part() {
+        let message = parse(b"Subject: hi\n\nbody\n");
+        assert_eq!(message.content_type(), "text/plain");
+        assert_eq!(message.text().as_deref(), Some("body\n"));
+        assert_eq!(parse(b"").leaves().len(), 1);
+    }
+
+    #[test]
+    fn addresses() {
+        assert_eq!(
+            address("Alice <alice@example.org>").as_deref(),
+            Some("alice@example.org")
+        );
+        assert_eq!(
+            address(" bob@example.org ").as_deref(),
+            Some("bob@example.org")
+        );
+        assert_eq!(address("undisclosed-recipients:;"), None);
+    }
+}
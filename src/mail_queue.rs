@@ -0,0 +1,726 @@
+//! Queue for mail that could not be delivered right away.
+//!
+//! Delivery failures come in two classes.  Temporary failures (e.g.
+//! SMTP 4xx replies, or the relay being unreachable) are retried with
+//! exponential backoff, until the mail is older than the configured
+//! maximum age.  Greylisting mail servers reject the first attempt
+//! and accept a retry after a few minutes, so for errors that look
+//! like greylisting, the first retry comes at a fixed early time.
+//! Permanent failures (SMTP 5xx replies, or the API provider refusing
+//! the message) are not retried, and the recipients are put on the
+//! suppression list, so that we stop sending mail to addresses that
+//! bounce.
+//!
+//! We also remember the delivery status of the last mail of each kind
+//! sent to an address, so that we can show it to the user.
+//!
+//! Finally, the number of mails per minute to each recipient domain
+//! can be limited.  Mail exceeding the limit is queued, and sent once
+//! the domain has capacity again.
+//!
+//! The queue, the suppression list, and the delivery status are kept
+//! in the file system, one file per mail or address.  Queued mails
+//! that can't be parsed are moved aside, so that they don't hold up
+//! the rest of the queue.
+
+use std::collections::HashMap;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::digest;
+use sequoia_openpgp::fmt::hex;
+use tempfile::NamedTempFile;
+
+use crate::mail_http;
+use crate::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Temporary,
+    Permanent,
+}
+
+impl ErrorClass {
+    /// Classifies a delivery error.
+    ///
+    /// Anything we don't positively know to be permanent is assumed
+    /// to be temporary.
+    pub fn of(error: &anyhow::Error) -> Self {
+        use lettre::transport::smtp;
+
+        if let Some(e) = error.downcast_ref::<smtp::Error>() {
+            if e.is_permanent() {
+                return ErrorClass::Permanent;
+            }
+        }
+        if error.downcast_ref::<mail_http::Rejected>().is_some() {
+            return ErrorClass::Permanent;
+        }
+        ErrorClass::Temporary
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Seconds to wait before the first retry.
+    pub initial_interval: u64,
+    /// Upper bound for the time between two retries, in seconds.
+    pub max_interval: u64,
+    /// Seconds after which we give up on a mail.
+    pub max_age: u64,
+    /// Seconds to wait before the first retry of mail that failed with
+    /// an error containing one of these patterns (ignoring case),
+    /// instead of `initial_interval`.
+    pub greylist_retries: HashMap<String, u64>,
+    /// Whether permanent failures put the recipient on the
+    /// suppression list.
+    pub suppress_permanent: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            initial_interval: 5 * 60,
+            max_interval: 4 * 60 * 60,
+            max_age: 3 * 24 * 60 * 60,
+            greylist_retries: [("greylist", 10 * 60), ("try again later", 10 * 60)]
+                .iter()
+                .map(|(pattern, interval)| (pattern.to_string(), *interval))
+                .collect(),
+            suppress_permanent: true,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Returns the number of seconds to wait after the given number
+    /// of failed attempts.
+    fn backoff(&self, attempts: u32) -> u64 {
+        let factor = 1u64
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        self.initial_interval
+            .saturating_mul(factor)
+            .min(self.max_interval)
+    }
+
+    /// Returns the number of seconds to wait after the given number
+    /// of failed attempts, the last one failing with `error`.
+    fn retry_delay(&self, attempts: u32, error: &str) -> u64 {
+        if attempts == 1 {
+            let error = error.to_lowercase();
+            let greylist_retry = self
+                .greylist_retries
+                .iter()
+                .filter(|(pattern, _)| error.contains(&pattern.to_lowercase()))
+                .map(|(_, interval)| *interval)
+                .min();
+            if let Some(interval) = greylist_retry {
+                return interval;
+            }
+        }
+        self.backoff(attempts)
+    }
+}
+
+/// Limits the number of mails per minute to each recipient domain, so
+/// that large providers don't take bulk imports for spam.
+#[derive(Debug, Clone, Default)]
+pub struct DomainThrottle {
+    /// Mails per minute, by domain.
+    limits: HashMap<String, u32>,
+    /// Mails per minute to domains without a limit of their own, if
+    /// any.
+    default_limit: Option<u32>,
+    /// Mails sent in the current minute, by domain.
+    sent: Arc<Mutex<HashMap<String, (u64, u32)>>>,
+}
+
+impl DomainThrottle {
+    pub fn new(limits: HashMap<String, u32>, default_limit: Option<u32>) -> Self {
+        DomainThrottle {
+            limits: limits
+                .into_iter()
+                .map(|(domain, limit)| (domain.to_lowercase(), limit))
+                .collect(),
+            default_limit,
+            sent: Default::default(),
+        }
+    }
+
+    fn limit(&self, domain: &str) -> Option<u32> {
+        self.limits.get(domain).copied().or(self.default_limit)
+    }
+
+    /// Counts a mail to the given recipients against the limits of
+    /// their domains.
+    ///
+    /// Returns `false`, and counts nothing, if that would exceed any
+    /// of the limits.
+    pub fn try_send(&self, tos: &[String], now: u64) -> bool {
+        let minute = now / 60;
+        let domains: Vec<String> = tos
+            .iter()
+            .filter_map(|to| to.rsplit('@').next())
+            .map(|domain| domain.to_lowercase())
+            .collect();
+
+        let mut sent = self.sent.lock().unwrap();
+        sent.retain(|_, (sent_minute, _)| *sent_minute == minute);
+        let within_limits = domains.iter().all(|domain| match self.limit(domain) {
+            Some(limit) => sent.get(domain).map(|(_, count)| *count).unwrap_or(0) < limit,
+            None => true,
+        });
+        if within_limits {
+            for domain in domains {
+                if self.limit(&domain).is_some() {
+                    sent.entry(domain).or_insert((minute, 0)).1 += 1;
+                }
+            }
+        }
+        within_limits
+    }
+}
+
+/// Returns the start of the next throttling window.
+fn next_minute(now: u64) -> u64 {
+    (now / 60 + 1) * 60
+}
+
+/// What happened to the last mail sent to an address.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Queued,
+    Sent,
+    Bounced,
+    Suppressed,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Queued => "queued",
+            DeliveryStatus::Sent => "sent",
+            DeliveryStatus::Bounced => "bounced",
+            DeliveryStatus::Suppressed => "suppressed",
+        }
+    }
+}
+
+/// How the queue is doing, for the metrics.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// The number of queued mails.
+    pub depth: usize,
+    /// The age of the oldest queued mail in seconds, or zero.
+    pub oldest_age: u64,
+}
+
+/// A rendered mail waiting for delivery.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QueuedMail {
+    /// The template this mail was rendered from, e.g. "verify".
+    pub kind: String,
+    pub tos: Vec<String>,
+    pub subject: String,
+    pub txt: String,
+    pub html: String,
+    pub queued_at: u64,
+    pub attempts: u32,
+    pub next_attempt: u64,
+    pub last_error: String,
+}
+
+#[derive(Clone)]
+pub struct MailQueue {
+    queue_dir: PathBuf,
+    corrupt_dir: PathBuf,
+    suppressed_dir: PathBuf,
+    status_dir: PathBuf,
+    policy: RetryPolicy,
+    throttle: DomainThrottle,
+}
+
+impl MailQueue {
+    pub fn new(state_dir: impl AsRef<Path>, policy: RetryPolicy) -> Result<Self> {
+        let queue_dir = state_dir.as_ref().join("queue");
+        let corrupt_dir = state_dir.as_ref().join("corrupt");
+        let suppressed_dir = state_dir.as_ref().join("suppressed");
+        let status_dir = state_dir.as_ref().join("status");
+        create_dir_all(&queue_dir)?;
+        create_dir_all(&corrupt_dir)?;
+        create_dir_all(&suppressed_dir)?;
+        create_dir_all(&status_dir)?;
+
+        Ok(MailQueue {
+            queue_dir,
+            corrupt_dir,
+            suppressed_dir,
+            status_dir,
+            policy,
+            throttle: DomainThrottle::default(),
+        })
+    }
+
+    /// Limits the number of mails per minute to each recipient domain.
+    pub fn with_throttle(mut self, throttle: DomainThrottle) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    pub fn policy(&self) -> &RetryPolicy {
+        &self.policy
+    }
+
+    /// Whether a mail to the given recipients may be sent now, see
+    /// `DomainThrottle::try_send`.
+    pub fn try_send(&self, tos: &[String], now: u64) -> bool {
+        self.throttle.try_send(tos, now)
+    }
+
+    /// Queues a mail whose first delivery attempt failed temporarily.
+    pub fn enqueue(
+        &self,
+        kind: &str,
+        tos: Vec<String>,
+        subject: String,
+        txt: String,
+        html: String,
+        error: &anyhow::Error,
+    ) -> Result<()> {
+        let now = unix_now();
+        let error = error.to_string();
+        let mail = QueuedMail {
+            kind: kind.to_owned(),
+            tos,
+            subject,
+            txt,
+            html,
+            queued_at: now,
+            attempts: 1,
+            next_attempt: now + self.policy.retry_delay(1, &error),
+            last_error: error,
+        };
+        let name = uuid::Uuid::new_v4().to_simple().to_string();
+        self.store(&self.queue_dir.join(name), &mail)
+    }
+
+    /// Queues a mail that was held back by the throttle.
+    ///
+    /// This does not count as a failed delivery attempt.
+    pub fn enqueue_throttled(
+        &self,
+        kind: &str,
+        tos: Vec<String>,
+        subject: String,
+        txt: String,
+        html: String,
+        now: u64,
+    ) -> Result<()> {
+        let mail = QueuedMail {
+            kind: kind.to_owned(),
+            tos,
+            subject,
+            txt,
+            html,
+            queued_at: now,
+            attempts: 0,
+            next_attempt: next_minute(now),
+            last_error: "throttled".to_owned(),
+        };
+        let name = uuid::Uuid::new_v4().to_simple().to_string();
+        self.store(&self.queue_dir.join(name), &mail)
+    }
+
+    /// Postpones a due mail that is held back by the throttle until
+    /// the next window.
+    pub fn defer(&self, path: &Path, mut mail: QueuedMail, now: u64) -> Result<()> {
+        mail.next_attempt = next_minute(now);
+        self.store(path, &mail)
+    }
+
+    /// Returns all queued mails that are due for another attempt.
+    ///
+    /// Queued mails that can't be parsed are set aside.
+    pub fn due(&self, now: u64) -> Result<Vec<(PathBuf, QueuedMail)>> {
+        let mut mails = self.all(true)?;
+        mails.retain(|(_, mail)| mail.next_attempt <= now);
+        mails.sort_by_key(|(_, mail)| mail.queued_at);
+        Ok(mails)
+    }
+
+    pub fn stats(&self, now: u64) -> Result<QueueStats> {
+        let mails = self.all(false)?;
+        let oldest = mails.iter().map(|(_, mail)| mail.queued_at).min();
+        Ok(QueueStats {
+            depth: mails.len(),
+            oldest_age: oldest.map(|t| now.saturating_sub(t)).unwrap_or(0),
+        })
+    }
+
+    /// Returns all queued mails that can be read, and sets aside
+    /// those that can't be parsed if `set_aside` is true.
+    fn all(&self, set_aside: bool) -> Result<Vec<(PathBuf, QueuedMail)>> {
+        let mut mails = Vec::new();
+        for entry in read_dir(&self.queue_dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            // Skip temporary files of concurrent writers.
+            if entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            let mut buf = String::new();
+            match File::open(&path).and_then(|mut f| f.read_to_string(&mut buf)) {
+                Ok(_) => (),
+                // Removed by a concurrent sender.
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    eprintln!("Unreadable queued mail {}: {}", path.display(), e);
+                    continue;
+                }
+            }
+            match serde_json::from_str(&buf) {
+                Ok(mail) => mails.push((path, mail)),
+                Err(e) if set_aside => {
+                    eprintln!("Corrupt queued mail {}: {}", path.display(), e);
+                    if let Err(e) = self.set_aside(&path) {
+                        eprintln!("Setting aside {} failed: {}", path.display(), e);
+                    }
+                }
+                Err(_) => (),
+            }
+        }
+        Ok(mails)
+    }
+
+    /// Moves a queued mail that can't be delivered out of the queue,
+    /// keeping it for inspection.
+    pub fn set_aside(&self, path: &Path) -> Result<()> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("Not a queued mail: {}", path.display()))?;
+        rename(path, self.corrupt_dir.join(name))?;
+        Ok(())
+    }
+
+    /// Records another failed attempt.
+    ///
+    /// Returns `false` if the mail has exceeded the maximum age and
+    /// was dropped from the queue.
+    pub fn retry_later(
+        &self,
+        path: &Path,
+        mut mail: QueuedMail,
+        error: &anyhow::Error,
+        now: u64,
+    ) -> Result<bool> {
+        mail.attempts += 1;
+        mail.last_error = error.to_string();
+        mail.next_attempt = now + self.policy.retry_delay(mail.attempts, &mail.last_error);
+
+        if mail.next_attempt > mail.queued_at + self.policy.max_age {
+            remove_file(path)?;
+            return Ok(false);
+        }
+        self.store(path, &mail)?;
+        Ok(true)
+    }
+
+    pub fn remove(&self, path: &Path) -> Result<()> {
+        remove_file(path)?;
+        Ok(())
+    }
+
+    /// Puts an address on the suppression list.
+    pub fn suppress(&self, address: &str, reason: &str) -> Result<()> {
+        let mut file = NamedTempFile::new_in(&self.suppressed_dir)?;
+        writeln!(file, "{}", address)?;
+        writeln!(file, "{}", reason)?;
+        file.persist(self.suppressed_path(address))?;
+        Ok(())
+    }
+
+    pub fn is_suppressed(&self, address: &str) -> bool {
+        self.suppressed_path(address).exists()
+    }
+
+    /// Removes an address from the suppression list.
+    pub fn unsuppress(&self, address: &str) -> Result<()> {
+        let path = self.suppressed_path(address);
+        if path.exists() {
+            remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Records the delivery status of the last mail of the given kind
+    /// sent to an address.
+    pub fn set_status(&self, address: &str, kind: &str, status: DeliveryStatus) -> Result<()> {
+        let dir = self.status_dir.join(kind);
+        create_dir_all(&dir)?;
+        let mut file = NamedTempFile::new_in(&dir)?;
+        file.write_all(serde_json::to_string(&status)?.as_bytes())?;
+        file.persist(dir.join(address_hash(address)))?;
+        Ok(())
+    }
+
+    pub fn status(&self, address: &str, kind: &str) -> Option<DeliveryStatus> {
+        let path = self.status_dir.join(kind).join(address_hash(address));
+        let mut buf = String::new();
+        File::open(path).ok()?.read_to_string(&mut buf).ok()?;
+        serde_json::from_str(&buf).ok()
+    }
+
+    fn suppressed_path(&self, address: &str) -> PathBuf {
+        self.suppressed_dir.join(address_hash(address))
+    }
+
+    fn store(&self, path: &Path, mail: &QueuedMail) -> Result<()> {
+        let mut file = NamedTempFile::new_in(&self.queue_dir)?;
+        file.write_all(serde_json::to_string(mail)?.as_bytes())?;
+        file.persist(path)?;
+        Ok(())
+    }
+}
+
+fn address_hash(address: &str) -> String {
+    let digest = digest::digest(&digest::SHA256, address.to_lowercase().as_bytes());
+    hex::encode(digest.as_ref())
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            initial_interval: 60,
+            max_interval: 600,
+            max_age: 3600,
+            greylist_retries: HashMap::new(),
+            suppress_permanent: true,
+        }
+    }
+
+    #[test]
+    fn backoff_is_exponential_and_capped() {
+        let policy = policy();
+        assert_eq!(policy.backoff(1), 60);
+        assert_eq!(policy.backoff(2), 120);
+        assert_eq!(policy.backoff(4), 480);
+        assert_eq!(policy.backoff(5), 600);
+        assert_eq!(policy.backoff(100), 600);
+    }
+
+    #[test]
+    fn greylisting_gets_early_retry() {
+        let policy = RetryPolicy {
+            greylist_retries: RetryPolicy::default().greylist_retries,
+            ..policy()
+        };
+        assert_eq!(policy.retry_delay(1, "450 4.2.0 Greylisted, see ..."), 600);
+        assert_eq!(policy.retry_delay(1, "421 Try again later"), 600);
+        assert_eq!(policy.retry_delay(1, "connection refused"), 60);
+        // Afterwards, the usual backoff applies.
+        assert_eq!(policy.retry_delay(2, "450 4.2.0 Greylisted"), 120);
+    }
+
+    #[test]
+    fn classify_errors() {
+        let rejected: anyhow::Error = mail_http::Rejected("HTTP status 400".to_owned()).into();
+        assert_eq!(ErrorClass::of(&rejected), ErrorClass::Permanent);
+        assert_eq!(ErrorClass::of(&anyhow!("timeout")), ErrorClass::Temporary);
+    }
+
+    #[test]
+    fn retry_until_max_age() {
+        let dir = tempdir().unwrap();
+        let queue = MailQueue::new(dir.path(), policy()).unwrap();
+        let error = anyhow!("421 try again later");
+        queue
+            .enqueue(
+                "verify",
+                vec!["foo@example.org".to_owned()],
+                "subject".to_owned(),
+                "txt".to_owned(),
+                "html".to_owned(),
+                &error,
+            )
+            .unwrap();
+
+        let now = unix_now();
+        assert!(queue.due(now).unwrap().is_empty());
+
+        let mut now = now + 60;
+        let mut retries = 0;
+        loop {
+            let mut due = queue.due(now).unwrap();
+            assert_eq!(due.len(), 1);
+            let (path, mail) = due.pop().unwrap();
+            assert_eq!(mail.tos, vec!["foo@example.org".to_owned()]);
+            if !queue.retry_later(&path, mail, &error, now).unwrap() {
+                break;
+            }
+            retries += 1;
+            now += 600;
+        }
+        assert_eq!(retries, 5);
+        assert!(queue.due(u64::MAX).unwrap().is_empty());
+    }
+
+    #[test]
+    fn corrupt_mail_is_set_aside() {
+        let dir = tempdir().unwrap();
+        let queue = MailQueue::new(dir.path(), policy()).unwrap();
+
+        let now = 6000;
+        queue
+            .enqueue_throttled(
+                "verify",
+                vec!["foo@example.org".to_owned()],
+                "subject".to_owned(),
+                "txt".to_owned(),
+                "html".to_owned(),
+                now,
+            )
+            .unwrap();
+        std::fs::write(dir.path().join("queue").join("garbage"), b"{ not json").unwrap();
+
+        let due = queue.due(now + 60).unwrap();
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].1.tos, vec!["foo@example.org".to_owned()]);
+        assert!(!dir.path().join("queue").join("garbage").exists());
+        assert!(dir.path().join("corrupt").join("garbage").exists());
+        assert_eq!(queue.stats(now + 60).unwrap().depth, 1);
+
+        // Only sending sets corrupt mail aside, counting leaves it.
+        std::fs::write(dir.path().join("queue").join("garbage"), b"{ not json").unwrap();
+        assert_eq!(queue.stats(now + 60).unwrap().depth, 1);
+        assert!(dir.path().join("queue").join("garbage").exists());
+    }
+
+    #[test]
+    fn queue_stats() {
+        let dir = tempdir().unwrap();
+        let queue = MailQueue::new(dir.path(), policy()).unwrap();
+
+        let now = 6000;
+        assert_eq!(queue.stats(now).unwrap(), QueueStats::default());
+        for (i, to) in ["foo@example.org", "bar@example.org"].iter().enumerate() {
+            queue
+                .enqueue_throttled(
+                    "verify",
+                    vec![to.to_string()],
+                    "subject".to_owned(),
+                    "txt".to_owned(),
+                    "html".to_owned(),
+                    now + i as u64 * 10,
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            queue.stats(now + 30).unwrap(),
+            QueueStats {
+                depth: 2,
+                oldest_age: 30,
+            }
+        );
+    }
+
+    #[test]
+    fn suppression_list() {
+        let dir = tempdir().unwrap();
+        let queue = MailQueue::new(dir.path(), policy()).unwrap();
+
+        assert!(!queue.is_suppressed("foo@example.org"));
+        queue
+            .suppress("foo@example.org", "550 no such user")
+            .unwrap();
+        assert!(queue.is_suppressed("foo@example.org"));
+        assert!(queue.is_suppressed("Foo@Example.org"));
+        assert!(!queue.is_suppressed("bar@example.org"));
+        queue.unsuppress("foo@example.org").unwrap();
+        assert!(!queue.is_suppressed("foo@example.org"));
+    }
+
+    #[test]
+    fn delivery_status() {
+        let dir = tempdir().unwrap();
+        let queue = MailQueue::new(dir.path(), policy()).unwrap();
+
+        assert_eq!(queue.status("foo@example.org", "verify"), None);
+        queue
+            .set_status("foo@example.org", "verify", DeliveryStatus::Queued)
+            .unwrap();
+        queue
+            .set_status("foo@example.org", "verify", DeliveryStatus::Bounced)
+            .unwrap();
+        assert_eq!(
+            queue.status("foo@example.org", "verify"),
+            Some(DeliveryStatus::Bounced)
+        );
+        assert_eq!(queue.status("foo@example.org", "manage"), None);
+        assert_eq!(queue.status("bar@example.org", "verify"), None);
+    }
+
+    #[test]
+    fn domain_throttle() {
+        let mut limits = HashMap::new();
+        limits.insert("Example.org".to_owned(), 2);
+        let throttle = DomainThrottle::new(limits, None);
+        let to = |s: &str| vec![s.to_owned()];
+
+        let now = 6000;
+        assert!(throttle.try_send(&to("foo@example.org"), now));
+        assert!(throttle.try_send(&to("bar@EXAMPLE.ORG"), now + 1));
+        assert!(!throttle.try_send(&to("baz@example.org"), now + 2));
+        // Other domains are not limited.
+        assert!(throttle.try_send(&to("foo@example.com"), now + 2));
+        // The next minute, there is capacity again.
+        assert!(throttle.try_send(&to("baz@example.org"), now + 60));
+
+        let throttle = DomainThrottle::new(HashMap::new(), Some(1));
+        assert!(throttle.try_send(&to("foo@example.com"), now));
+        assert!(!throttle.try_send(&to("bar@example.com"), now));
+        assert!(throttle.try_send(&to("foo@example.net"), now));
+    }
+
+    #[test]
+    fn throttled_mail_is_deferred() {
+        let dir = tempdir().unwrap();
+        let queue = MailQueue::new(dir.path(), policy()).unwrap();
+
+        let now = 6000;
+        queue
+            .enqueue_throttled(
+                "verify",
+                vec!["foo@example.org".to_owned()],
+                "subject".to_owned(),
+                "txt".to_owned(),
+                "html".to_owned(),
+                now,
+            )
+            .unwrap();
+        assert!(queue.due(now).unwrap().is_empty());
+
+        let mut due = queue.due(now + 60).unwrap();
+        assert_eq!(due.len(), 1);
+        let (path, mail) = due.pop().unwrap();
+        assert_eq!(mail.attempts, 0);
+        queue.defer(&path, mail, now + 60).unwrap();
+        assert!(queue.due(now + 60).unwrap().is_empty());
+        assert_eq!(queue.due(now + 120).unwrap().len(), 1);
+    }
+}
@@ -27,16 +27,38 @@ mod anonymize_utils;
 mod counters;
 mod dump;
 mod gettext_strings;
+mod http_client;
 mod i18n;
 mod i18n_helpers;
 mod mail;
+mod mail_dns;
+mod mail_events;
+mod mail_http;
+mod mail_parse;
+mod mail_queue;
+mod notify;
 mod rate_limiter;
+mod retention;
 mod sealed_state;
+mod scheduler;
+mod service_key;
+mod socks;
 mod template_helpers;
 mod tokens;
+mod upload_cache;
 mod web;
 
-#[launch]
-fn rocket() -> _ {
-    web::serve().expect("Rocket config must succeed")
+#[rocket::main]
+async fn main() {
+    let rocket = web::serve().expect("Rocket config must succeed");
+    let tasks = rocket.state::<scheduler::Tasks>().cloned();
+    let result = rocket.launch().await;
+
+    // Let background jobs finish what they are doing.
+    if let Some(tasks) = tasks {
+        if !tasks.shutdown(scheduler::DRAIN_TIMEOUT) {
+            eprintln!("Background jobs did not stop in time");
+        }
+    }
+    result.expect("Rocket must launch");
 }
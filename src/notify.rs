@@ -0,0 +1,210 @@
+//! Tells the operators about events that need their attention.
+//!
+//! Failing maintenance jobs, e.g. the consistency check, and disk usage
+//! above the quota are pushed to the channels configured in the `ops`
+//! section: mail, a Matrix room, or an XMPP chat room.  Notifications
+//! are best effort, failures to deliver them are only logged.
+
+use serde_json::json;
+use url::Url;
+use uuid::Uuid;
+
+use crate::database::types::Email;
+use crate::http_client::HttpClient;
+use crate::mail;
+use crate::mail_http::curl_escape;
+use crate::Result;
+
+/// The `ops` section of the configuration.
+#[derive(Debug, Default, Deserialize)]
+pub struct OpsConfig {
+    pub notify_mail: Option<String>,
+    pub matrix: Option<MatrixRoom>,
+    pub xmpp: Option<XmppRoom>,
+}
+
+/// A Matrix room, which the account of the access token has joined.
+#[derive(Clone, Debug, Deserialize)]
+pub struct MatrixRoom {
+    /// The base URL of the homeserver, e.g. `https://matrix.example.org`.
+    pub homeserver: String,
+    /// The room ID, e.g. `!abcdef:example.org`.
+    pub room: String,
+    pub access_token: String,
+}
+
+/// An XMPP chat room, which we post to through the HTTP API of the
+/// server, as provided by Prosody's mod_rest.  The account of the token
+/// must have joined the room.
+#[derive(Clone, Debug, Deserialize)]
+pub struct XmppRoom {
+    /// The base URL of the API, e.g. `https://xmpp.example.org/rest`.
+    pub endpoint: String,
+    /// The address of the room, e.g. `ops@conference.example.org`.
+    pub room: String,
+    pub token: String,
+}
+
+#[derive(Clone)]
+enum Channel {
+    Mail {
+        service: mail::Service,
+        base_uri: String,
+        recipient: Email,
+    },
+    Matrix(MatrixRoom),
+    Xmpp(XmppRoom),
+}
+
+#[derive(Clone, Default)]
+pub struct Notifier {
+    channels: Vec<Channel>,
+    client: HttpClient,
+}
+
+impl Notifier {
+    pub fn new(client: HttpClient) -> Self {
+        Notifier {
+            channels: vec![],
+            client,
+        }
+    }
+
+    pub fn with_mail(mut self, service: mail::Service, base_uri: &str, recipient: Email) -> Self {
+        self.channels.push(Channel::Mail {
+            service,
+            base_uri: base_uri.to_owned(),
+            recipient,
+        });
+        self
+    }
+
+    pub fn with_matrix(mut self, room: MatrixRoom) -> Self {
+        self.channels.push(Channel::Matrix(room));
+        self
+    }
+
+    pub fn with_xmpp(mut self, room: XmppRoom) -> Self {
+        self.channels.push(Channel::Xmpp(room));
+        self
+    }
+
+    /// Sends the message to all channels.
+    pub fn notify(&self, message: &str) {
+        for channel in &self.channels {
+            if let Err(e) = self.send(channel, message) {
+                eprintln!("Failed to notify operators of {:?}: {:?}", message, e);
+            }
+        }
+    }
+
+    fn send(&self, channel: &Channel, message: &str) -> Result<()> {
+        match channel {
+            Channel::Mail {
+                service,
+                base_uri,
+                recipient,
+            } => service.send_notification(base_uri, recipient, message),
+            Channel::Matrix(room) => {
+                let url = matrix_url(room, &Uuid::new_v4().to_string())?;
+                let body = json!({ "msgtype": "m.notice", "body": message }).to_string();
+                let args = vec![
+                    "--request".to_owned(),
+                    "PUT".to_owned(),
+                    "--header".to_owned(),
+                    "Content-Type: application/json".to_owned(),
+                    "--data-binary".to_owned(),
+                    "@-".to_owned(),
+                ];
+                let auth = bearer_config(&room.access_token);
+                check_status(self.client.post(&url, &args, &auth, body.as_bytes())?)
+            }
+            Channel::Xmpp(room) => {
+                let url = xmpp_url(room)?;
+                let args = vec![
+                    "--header".to_owned(),
+                    "Content-Type: text/plain; charset=utf-8".to_owned(),
+                    "--data-binary".to_owned(),
+                    "@-".to_owned(),
+                ];
+                let auth = bearer_config(&room.token);
+                check_status(self.client.post(&url, &args, &auth, message.as_bytes())?)
+            }
+        }
+    }
+}
+
+/// Returns the URL to send a message to the room, see the Matrix
+/// client-server API, `PUT /rooms/{roomId}/send/{eventType}/{txnId}`.
+fn matrix_url(room: &MatrixRoom, transaction_id: &str) -> Result<String> {
+    let mut url = Url::parse(&room.homeserver)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Invalid homeserver URL: {}", room.homeserver))?
+        .pop_if_empty()
+        .extend(&[
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            &room.room,
+            "send",
+            "m.room.message",
+            transaction_id,
+        ]);
+    Ok(url.into_string())
+}
+
+/// Returns the URL to send a message to the room, see mod_rest's
+/// `POST /message/{type}/{to}`.
+fn xmpp_url(room: &XmppRoom) -> Result<String> {
+    let mut url = Url::parse(&room.endpoint)?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow!("Invalid XMPP API URL: {}", room.endpoint))?
+        .pop_if_empty()
+        .extend(&["message", "groupchat", &room.room]);
+    Ok(url.into_string())
+}
+
+/// Returns a curl config passing the token, see
+/// `HttpClient::post`.
+fn bearer_config(token: &str) -> String {
+    format!(
+        "header = \"Authorization: Bearer {}\"\n",
+        curl_escape(token)
+    )
+}
+
+fn check_status(status: u16) -> Result<()> {
+    match status {
+        200..=299 => Ok(()),
+        _ => Err(anyhow!("HTTP status {}", status)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        let room = MatrixRoom {
+            homeserver: "https://matrix.example.org/".to_owned(),
+            room: "!abc/def:example.org".to_owned(),
+            access_token: "token".to_owned(),
+        };
+        assert_eq!(
+            matrix_url(&room, "1").unwrap(),
+            "https://matrix.example.org/_matrix/client/v3/rooms/!abc%2Fdef:example.org/send/m.room.message/1"
+        );
+
+        let room = XmppRoom {
+            endpoint: "https://xmpp.example.org/rest".to_owned(),
+            room: "ops@conference.example.org".to_owned(),
+            token: "token".to_owned(),
+        };
+        assert_eq!(
+            xmpp_url(&room).unwrap(),
+            "https://xmpp.example.org/rest/message/groupchat/ops@conference.example.org"
+        );
+    }
+}
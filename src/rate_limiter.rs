@@ -1,57 +1,263 @@
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::fs::{create_dir_all, read_dir, read_to_string, remove_file};
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
 use std::time::{Duration, Instant};
 
+use ring::digest;
+use sequoia_openpgp::fmt::hex;
+use tempfile::NamedTempFile;
+
+use crate::database::Reloadable;
+use crate::mail_queue::unix_now;
+use crate::Result;
+
 pub struct RateLimiter {
+    /// The last action of each identifier, by the identifier's hash.
     locked_map: Mutex<HashMap<String, Instant>>,
     cleanup_last: Mutex<Instant>,
-    cleanup_delay: Duration,
-    timeout: Duration,
+    timeout: Reloadable<Duration>,
+    /// Where the last action of each identifier is kept, if anywhere.
+    state_dir: Option<PathBuf>,
 }
 
 impl RateLimiter {
     pub fn new(timeout_secs: u64) -> Self {
         RateLimiter {
             locked_map: Mutex::new(HashMap::new()),
-            timeout: Duration::from_secs(timeout_secs),
+            timeout: Reloadable::new(Duration::from_secs(timeout_secs)),
             cleanup_last: Mutex::new(Instant::now()),
-            cleanup_delay: Duration::from_secs(timeout_secs * 10),
+            state_dir: None,
+        }
+    }
+
+    /// Keeps the time of the last action of each identifier in the
+    /// given directory, one file per identifier named by its hash, so
+    /// that restarting the server doesn't lift the limits.
+    pub fn with_state_dir(mut self, state_dir: impl Into<PathBuf>) -> Result<Self> {
+        let state_dir = state_dir.into();
+        create_dir_all(&state_dir)?;
+
+        let now = unix_now();
+        let timeout = self.timeout.get().as_secs();
+        {
+            let mut locked_map = self.locked_map.lock().unwrap();
+            for entry in read_dir(&state_dir)? {
+                let path = entry?.path();
+                let key = path.file_name().unwrap().to_string_lossy().into_owned();
+                let age = read_to_string(&path).ok().and_then(|action| {
+                    let performed_at = action.split_whitespace().next()?.parse::<u64>().ok()?;
+                    Some(now.saturating_sub(performed_at))
+                });
+                match age {
+                    Some(age) if age < timeout && !key.starts_with('.') => {
+                        if let Some(instant) = Instant::now().checked_sub(Duration::from_secs(age))
+                        {
+                            locked_map.insert(key, instant);
+                        }
+                    }
+                    // Expired, or left behind by a crash.
+                    _ => {
+                        let _ = remove_file(&path);
+                    }
+                }
+            }
         }
+
+        self.state_dir = Some(state_dir);
+        Ok(self)
+    }
+
+    /// Returns the timeout, which can be changed while the rate
+    /// limiter is in use.
+    pub fn timeout(&self) -> Reloadable<Duration> {
+        self.timeout.clone()
     }
 
     pub fn action_perform(&self, identifier: String) -> bool {
         self.maybe_cleanup();
 
-        let mut locked_map = self.locked_map.lock().unwrap();
-        let action_ok = locked_map
-            .get(&identifier)
-            .map(|instant| instant.elapsed())
-            .map(|duration| duration >= self.timeout)
-            .unwrap_or(true);
+        let key = identifier_hash(&identifier);
+        let timeout = self.timeout.get();
+        let action_ok = {
+            let mut locked_map = self.locked_map.lock().unwrap();
+            let action_ok = locked_map
+                .get(&key)
+                .map(|instant| instant.elapsed())
+                .map(|duration| duration >= timeout)
+                .unwrap_or(true);
+            if action_ok {
+                locked_map.insert(key.clone(), Instant::now());
+            }
+            action_ok
+        };
         if action_ok {
-            locked_map.insert(identifier, Instant::now());
+            self.save(&key);
         }
         action_ok
     }
 
     pub fn action_check(&self, identifier: String) -> bool {
+        let key = identifier_hash(&identifier);
+        let timeout = self.timeout.get();
         let locked_map = self.locked_map.lock().unwrap();
         locked_map
-            .get(&identifier)
+            .get(&key)
             .map(|instant| instant.elapsed())
-            .map(|duration| duration >= self.timeout)
+            .map(|duration| duration >= timeout)
             .unwrap_or(true)
     }
 
     fn maybe_cleanup(&self) {
-        let mut cleanup_last = self.cleanup_last.lock().unwrap();
-        if cleanup_last.elapsed() > self.cleanup_delay {
-            return;
+        let timeout = self.timeout.get();
+        {
+            let mut cleanup_last = self.cleanup_last.lock().unwrap();
+            if cleanup_last.elapsed() < timeout * 10 {
+                return;
+            }
+            *cleanup_last = Instant::now();
+        }
+
+        let mut expired = vec![];
+        self.locked_map.lock().unwrap().retain(|key, instant| {
+            let keep = instant.elapsed() < timeout;
+            if !keep {
+                expired.push(key.clone());
+            }
+            keep
+        });
+        for key in expired {
+            self.forget(&key);
+        }
+    }
+
+    fn save(&self, key: &str) {
+        let state_dir = match &self.state_dir {
+            Some(state_dir) => state_dir,
+            None => return,
+        };
+        let result = NamedTempFile::new_in(state_dir).and_then(|mut file| {
+            writeln!(file, "{}", unix_now())?;
+            file.persist(state_dir.join(key))
+                .map(|_| ())
+                .map_err(|e| e.error)
+        });
+        if let Err(e) = result {
+            eprintln!("Error saving rate limit {}: {:?}", key, e);
+        }
+    }
+
+    fn forget(&self, key: &str) {
+        if let Some(state_dir) = &self.state_dir {
+            let _ = remove_file(state_dir.join(key));
+        }
+    }
+}
+
+fn identifier_hash(identifier: &str) -> String {
+    let digest = digest::digest(&digest::SHA256, identifier.as_bytes());
+    hex::encode(digest.as_ref())
+}
+
+/// Limits the API requests per minute of each client.
+///
+/// Clients presenting a known API token get the quota of that token,
+/// everyone else is limited by address.
+pub struct RequestQuotas {
+    limits: RwLock<QuotaLimits>,
+    /// Requests in the current minute, by the client's hash.
+    requests: Mutex<HashMap<String, (u64, u32)>>,
+}
+
+struct QuotaLimits {
+    /// Requests per minute, by API token.
+    token_limits: HashMap<String, u32>,
+    /// Requests per minute from each address, if limited.
+    anonymous_limit: Option<u32>,
+}
+
+impl RequestQuotas {
+    pub fn new(token_limits: HashMap<String, u32>, anonymous_limit: Option<u32>) -> Self {
+        RequestQuotas {
+            limits: RwLock::new(QuotaLimits {
+                token_limits,
+                anonymous_limit,
+            }),
+            requests: Default::default(),
         }
-        *cleanup_last = Instant::now();
+    }
+
+    /// Changes the limits.  Requests counted so far still count
+    /// against the new limits.
+    pub fn set_limits(&self, token_limits: HashMap<String, u32>, anonymous_limit: Option<u32>) {
+        *self.limits.write().unwrap() = QuotaLimits {
+            token_limits,
+            anonymous_limit,
+        };
+    }
+
+    /// Saves the requests counted so far, so that they can be
+    /// restored after a restart.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let requests = self.requests.lock().unwrap().clone();
+        let requests = serde_json::to_vec(&requests)?;
+        let mut file = NamedTempFile::new_in(path.parent().unwrap())?;
+        file.write_all(&requests)?;
+        file.persist(path)?;
+        Ok(())
+    }
+
+    /// Restores the requests counted before a restart.  Only those
+    /// of the current minute still count.
+    pub fn load(&self, path: &Path, now: u64) -> Result<()> {
+        let saved: HashMap<String, (u64, u32)> = serde_json::from_slice(&std::fs::read(path)?)?;
+        let minute = now / 60;
+        let mut requests = self.requests.lock().unwrap();
+        for (client, (request_minute, count)) in saved {
+            if request_minute == minute {
+                requests.entry(client).or_insert((minute, 0)).1 += count;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        let limits = self.limits.read().unwrap();
+        limits.anonymous_limit.is_some() || !limits.token_limits.is_empty()
+    }
 
-        let mut locked_map = self.locked_map.lock().unwrap();
-        locked_map.retain(|_, instant| instant.elapsed() < self.timeout);
+    /// Counts a request against the quota of the client.
+    ///
+    /// Returns the number of seconds until the client may try again
+    /// if the quota is exhausted.
+    pub fn try_request(
+        &self,
+        token: Option<&str>,
+        address: Option<IpAddr>,
+        now: u64,
+    ) -> Result<(), u64> {
+        let limits = self.limits.read().unwrap();
+        let (client, limit) = match token.and_then(|t| limits.token_limits.get_key_value(t)) {
+            Some((token, limit)) => (format!("token:{}", token), *limit),
+            None => match (address, limits.anonymous_limit) {
+                (Some(address), Some(limit)) => (format!("address:{}", address), limit),
+                (None, Some(limit)) => ("address:unknown".to_owned(), limit),
+                (_, None) => return Ok(()),
+            },
+        };
+
+        let client = identifier_hash(&client);
+        let minute = now / 60;
+        let mut requests = self.requests.lock().unwrap();
+        requests.retain(|_, (request_minute, _)| *request_minute == minute);
+        let count = &mut requests.entry(client).or_insert((minute, 0)).1;
+        if *count >= limit {
+            return Err((minute + 1) * 60 - now);
+        }
+        *count += 1;
+        Ok(())
     }
 }
 
@@ -79,4 +285,126 @@ mod tests {
 
         assert!(rate_limiter.action_perform("action".to_owned()));
     }
+
+    #[test]
+    fn persistent_state() {
+        let state_dir = tempfile::tempdir().unwrap();
+        let rate_limiter = RateLimiter::new(60)
+            .with_state_dir(state_dir.path())
+            .unwrap();
+        assert!(rate_limiter.action_perform("verify-a@example.org".to_owned()));
+
+        let rate_limiter = RateLimiter::new(60)
+            .with_state_dir(state_dir.path())
+            .unwrap();
+        assert!(!rate_limiter.action_check("verify-a@example.org".to_owned()));
+        assert!(!rate_limiter.action_perform("verify-a@example.org".to_owned()));
+        assert!(rate_limiter.action_perform("verify-b@example.org".to_owned()));
+
+        // Only hashes of the identifiers are stored.
+        for entry in read_dir(state_dir.path()).unwrap() {
+            let path = entry.unwrap().path();
+            assert!(!read_to_string(&path).unwrap().contains("example.org"));
+            assert!(!path.to_string_lossy().contains("example.org"));
+        }
+
+        // Expired actions are forgotten.
+        thread::sleep(Duration::from_secs(1));
+        let rate_limiter = RateLimiter::new(1)
+            .with_state_dir(state_dir.path())
+            .unwrap();
+        assert!(rate_limiter.action_check("verify-a@example.org".to_owned()));
+        assert_eq!(read_dir(state_dir.path()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn cleanup_forgets_expired_actions() {
+        let state_dir = tempfile::tempdir().unwrap();
+        let rate_limiter = RateLimiter::new(60)
+            .with_state_dir(state_dir.path())
+            .unwrap();
+        assert!(rate_limiter.action_perform("verify-a@example.org".to_owned()));
+        assert_eq!(read_dir(state_dir.path()).unwrap().count(), 1);
+
+        // Cleaning up is rare, but the expired action is forgotten
+        // once it happens.
+        rate_limiter.timeout().set(Duration::from_secs(0));
+        assert!(rate_limiter.action_perform("verify-b@example.org".to_owned()));
+        assert_eq!(rate_limiter.locked_map.lock().unwrap().len(), 1);
+        assert_eq!(read_dir(state_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn request_quotas_persistent() {
+        let state_dir = tempfile::tempdir().unwrap();
+        let path = state_dir.path().join("api-quotas.json");
+        let address = Some("192.0.2.1".parse().unwrap());
+
+        let quotas = RequestQuotas::new(HashMap::new(), Some(2));
+        assert_eq!(quotas.try_request(None, address, 60), Ok(()));
+        quotas.save(&path).unwrap();
+        assert!(!read_to_string(&path).unwrap().contains("192.0.2.1"));
+
+        let quotas = RequestQuotas::new(HashMap::new(), Some(2));
+        quotas.load(&path, 75).unwrap();
+        assert_eq!(quotas.try_request(None, address, 75), Ok(()));
+        assert_eq!(quotas.try_request(None, address, 75), Err(45));
+
+        // Counts from past minutes don't.
+        let quotas = RequestQuotas::new(HashMap::new(), Some(2));
+        quotas.load(&path, 120).unwrap();
+        assert_eq!(quotas.try_request(None, address, 120), Ok(()));
+        assert_eq!(quotas.try_request(None, address, 120), Ok(()));
+    }
+
+    #[test]
+    fn request_quotas() {
+        let mut token_limits = HashMap::new();
+        token_limits.insert("sekrit".to_owned(), 3);
+        let quotas = RequestQuotas::new(token_limits, Some(1));
+        let address = Some("192.0.2.1".parse().unwrap());
+        let other_address = Some("192.0.2.2".parse().unwrap());
+
+        assert_eq!(quotas.try_request(None, address, 60), Ok(()));
+        assert_eq!(quotas.try_request(None, address, 75), Err(45));
+        assert_eq!(quotas.try_request(None, other_address, 75), Ok(()));
+
+        // Unknown tokens get the anonymous quota.
+        assert_eq!(quotas.try_request(Some("wrong"), address, 75), Err(45));
+
+        for _ in 0..3 {
+            assert_eq!(quotas.try_request(Some("sekrit"), address, 75), Ok(()));
+        }
+        assert_eq!(quotas.try_request(Some("sekrit"), address, 119), Err(1));
+
+        // Quotas are per minute.
+        assert_eq!(quotas.try_request(None, address, 120), Ok(()));
+        assert_eq!(quotas.try_request(Some("sekrit"), address, 120), Ok(()));
+    }
+
+    #[test]
+    fn request_quotas_set_limits() {
+        let quotas = RequestQuotas::new(HashMap::new(), Some(1));
+        let address = Some("192.0.2.1".parse().unwrap());
+
+        assert_eq!(quotas.try_request(None, address, 60), Ok(()));
+        assert_eq!(quotas.try_request(None, address, 60), Err(60));
+
+        quotas.set_limits(HashMap::new(), Some(2));
+        assert_eq!(quotas.try_request(None, address, 60), Ok(()));
+        assert_eq!(quotas.try_request(None, address, 60), Err(60));
+
+        quotas.set_limits(HashMap::new(), None);
+        assert!(!quotas.is_enabled());
+        assert_eq!(quotas.try_request(None, address, 60), Ok(()));
+    }
+
+    #[test]
+    fn request_quotas_disabled() {
+        let quotas = RequestQuotas::new(HashMap::new(), None);
+        assert!(!quotas.is_enabled());
+        for _ in 0..100 {
+            assert_eq!(quotas.try_request(None, None, 0), Ok(()));
+        }
+    }
 }
@@ -0,0 +1,152 @@
+//! Deletes uploads that never made it into the published database,
+//! once they are old enough: the user IDs of keys none of whose
+//! addresses were ever verified, and quarantined keys nobody decided
+//! on.  What is published, e.g. keys without user IDs and their
+//! revocations, is kept.
+//!
+//! Each rule is enabled by configuring its maximum age.  In dry-run
+//! mode, the rules only report what they would delete, so that
+//! operators can review a policy before enforcing it.
+
+use std::time::Duration;
+
+use crate::counters;
+use crate::database::types::Fingerprint;
+use crate::database::KeyDatabase;
+use crate::Result;
+
+/// How often the rules are applied.
+pub const INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The `retention` section of the configuration.  Ages are in seconds.
+#[derive(Debug, Default, Deserialize)]
+pub struct RetentionConfig {
+    /// Unpublished user IDs of keys without verified addresses,
+    /// counted from the last change of the key.
+    pub unverified_max_age: Option<u64>,
+    /// Quarantined keys, counted from their upload.
+    pub quarantined_max_age: Option<u64>,
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rule {
+    Unverified,
+    Quarantined,
+}
+
+impl Rule {
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::Unverified => "unverified",
+            Rule::Quarantined => "quarantined",
+        }
+    }
+}
+
+pub struct Retention {
+    db: KeyDatabase,
+    rules: Vec<(Rule, Duration)>,
+    dry_run: bool,
+}
+
+impl Retention {
+    pub fn new(db: KeyDatabase, config: RetentionConfig) -> Self {
+        let rules = [
+            (Rule::Unverified, config.unverified_max_age),
+            (Rule::Quarantined, config.quarantined_max_age),
+        ]
+        .iter()
+        .filter_map(|(rule, max_age)| Some((*rule, Duration::from_secs((*max_age)?))))
+        .collect();
+        Retention {
+            db,
+            rules,
+            dry_run: config.dry_run,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    /// Applies the rules, and returns the keys each one deleted, or in
+    /// dry-run mode, would have deleted.
+    pub fn run(&self) -> Result<Vec<(Rule, Vec<Fingerprint>)>> {
+        let mut report = vec![];
+        for &(rule, max_age) in &self.rules {
+            let matched = match rule {
+                Rule::Unverified => self.db.sweep_unverified(max_age, self.dry_run)?,
+                Rule::Quarantined => self.db.prune_quarantined(max_age, self.dry_run)?,
+            };
+            counters::inc_retention(rule.name(), self.dry_run, matched.len());
+            if self.dry_run {
+                info!(
+                    "Retention rule {} would delete {} keys",
+                    rule.name(),
+                    matched.len()
+                );
+                for fpr in &matched {
+                    info!("  {}", fpr);
+                }
+            } else if !matched.is_empty() {
+                info!(
+                    "Retention rule {} deleted {} keys",
+                    rule.name(),
+                    matched.len()
+                );
+            }
+            report.push((rule, matched));
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+    use tempfile::TempDir;
+
+    #[test]
+    fn rules() {
+        let tmpdir = TempDir::new().unwrap();
+        let open_db = || KeyDatabase::new_from_base(tmpdir.path()).unwrap();
+        assert!(!Retention::new(open_db(), RetentionConfig::default()).is_enabled());
+
+        let db = open_db();
+
+        let fpr: Fingerprint = "CBCD8F030588653EEDD7E2659B7DD433F254904A".parse().unwrap();
+        db.write_to_quarantine(&fpr, b"key", None).unwrap();
+        std::thread::sleep(Duration::from_millis(1100));
+
+        let config = RetentionConfig {
+            unverified_max_age: Some(14 * 24 * 60 * 60),
+            quarantined_max_age: Some(1),
+            dry_run: true,
+        };
+        let retention = Retention::new(open_db(), config);
+        assert!(retention.is_enabled());
+        assert_eq!(
+            retention.run().unwrap(),
+            vec![
+                (Rule::Unverified, vec![]),
+                (Rule::Quarantined, vec![fpr.clone()])
+            ]
+        );
+        assert!(db.by_fpr_quarantined(&fpr).is_some());
+
+        let config = RetentionConfig {
+            unverified_max_age: None,
+            quarantined_max_age: Some(1),
+            dry_run: false,
+        };
+        let retention = Retention::new(open_db(), config);
+        assert_eq!(
+            retention.run().unwrap(),
+            vec![(Rule::Quarantined, vec![fpr.clone()])]
+        );
+        assert!(db.by_fpr_quarantined(&fpr).is_none());
+    }
+}
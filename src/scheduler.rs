@@ -0,0 +1,354 @@
+//! Runs maintenance jobs periodically, inside the server.
+//!
+//! Each job runs on a thread of its own, once at startup and then
+//! whenever its interval has passed.  The intervals are varied at
+//! random by up to the configured jitter, so that instances started
+//! together don't do the same work at the same time.  Runs are counted
+//! and timed in the metrics.
+//!
+//! The jobs are supervised: a job that panics is restarted after a
+//! backoff that grows while it keeps panicking, and on shutdown, jobs
+//! are stopped after finishing the run they are in.  Operators are
+//! notified when a job starts failing, and when it recovers.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::counters;
+use crate::mail_queue::unix_now;
+use crate::notify::Notifier;
+use crate::Result;
+
+/// How long to wait after the first panic of a job before restarting
+/// it.  The wait doubles with every further panic in a row.
+const PANIC_BACKOFF_MIN: Duration = Duration::from_secs(1);
+/// The longest wait before restarting a job that keeps panicking.
+const PANIC_BACKOFF_MAX: Duration = Duration::from_secs(10 * 60);
+
+/// How long shutdown waits for jobs to finish their current run.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct Job {
+    name: &'static str,
+    interval: Duration,
+    run: Box<dyn FnMut() -> Result<()> + Send>,
+}
+
+pub struct Scheduler {
+    /// The fraction of the interval by which runs may be early or
+    /// late.
+    jitter: f64,
+    jobs: Vec<Job>,
+    notifier: Notifier,
+}
+
+impl Scheduler {
+    pub fn new(jitter: f64) -> Self {
+        Scheduler {
+            jitter: jitter.max(0.0).min(1.0),
+            jobs: vec![],
+            notifier: Notifier::default(),
+        }
+    }
+
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    /// Registers a job to run at the given interval.
+    pub fn register(
+        &mut self,
+        name: &'static str,
+        interval: Duration,
+        run: impl FnMut() -> Result<()> + Send + 'static,
+    ) {
+        self.jobs.push(Job {
+            name,
+            interval,
+            run: Box::new(run),
+        });
+    }
+
+    pub fn job_names(&self) -> Vec<&'static str> {
+        self.jobs.iter().map(|job| job.name).collect()
+    }
+
+    /// Starts running the jobs, supervised by `tasks`.
+    pub fn spawn(self, tasks: &Tasks) {
+        let jitter = self.jitter;
+        for job in self.jobs {
+            let index = {
+                let mut state = tasks.lock();
+                state.running += 1;
+                state.statuses.push(TaskStatus::new(job.name, job.interval));
+                state.statuses.len() - 1
+            };
+            let tasks = tasks.clone();
+            let notifier = self.notifier.clone();
+            std::thread::spawn(move || tasks.supervise(index, job, jitter, notifier));
+        }
+    }
+}
+
+/// What a task is doing.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TaskState {
+    Running,
+    Idle,
+    /// Waiting to be restarted after a panic.
+    Backoff,
+    Stopped,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TaskStatus {
+    pub name: &'static str,
+    pub state: TaskState,
+    pub interval_secs: u64,
+    pub runs: u64,
+    pub failures: u64,
+    pub panics: u64,
+    /// When the last run finished, in seconds since the epoch.
+    pub last_run: Option<u64>,
+    pub last_error: Option<String>,
+    /// Whether the last run succeeded.
+    pub healthy: bool,
+}
+
+impl TaskStatus {
+    fn new(name: &'static str, interval: Duration) -> Self {
+        TaskStatus {
+            name,
+            state: TaskState::Idle,
+            interval_secs: interval.as_secs(),
+            runs: 0,
+            failures: 0,
+            panics: 0,
+            last_run: None,
+            last_error: None,
+            healthy: true,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    stopping: bool,
+    running: usize,
+    statuses: Vec<TaskStatus>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<State>,
+    /// Signals shutdown to the jobs, and jobs stopping to shutdown.
+    changed: Condvar,
+}
+
+/// The running jobs, which can be inspected and stopped.
+#[derive(Clone, Default)]
+pub struct Tasks(Arc<Shared>);
+
+impl Tasks {
+    fn lock(&self) -> MutexGuard<State> {
+        self.0.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub fn status(&self) -> Vec<TaskStatus> {
+        self.lock().statuses.clone()
+    }
+
+    /// Stops all jobs, waiting up to `timeout` for the runs in
+    /// progress to finish.
+    ///
+    /// Returns whether all jobs stopped in time.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        let mut state = self.lock();
+        state.stopping = true;
+        self.0.changed.notify_all();
+        let (state, _) = self
+            .0
+            .changed
+            .wait_timeout_while(state, timeout, |state| state.running > 0)
+            .unwrap_or_else(|e| e.into_inner());
+        state.running == 0
+    }
+
+    /// Sleeps for `duration`, or until shutdown.
+    ///
+    /// Returns whether to go on.
+    fn sleep(&self, duration: Duration) -> bool {
+        let (state, _) = self
+            .0
+            .changed
+            .wait_timeout_while(self.lock(), duration, |state| !state.stopping)
+            .unwrap_or_else(|e| e.into_inner());
+        !state.stopping
+    }
+
+    fn update(&self, index: usize, f: impl FnOnce(&mut TaskStatus)) {
+        f(&mut self.lock().statuses[index]);
+    }
+
+    fn supervise(&self, index: usize, mut job: Job, jitter: f64, notifier: Notifier) {
+        let mut panics_in_a_row = 0;
+        loop {
+            if self.lock().stopping {
+                break;
+            }
+            self.update(index, |status| status.state = TaskState::Running);
+
+            let start = Instant::now();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| (job.run)()));
+            let (outcome, pause) = match &result {
+                Ok(Ok(())) => {
+                    panics_in_a_row = 0;
+                    ("success", jittered(job.interval, jitter))
+                }
+                Ok(Err(e)) => {
+                    panics_in_a_row = 0;
+                    warn!("Error running job {}: {:?}", job.name, e);
+                    ("error", jittered(job.interval, jitter))
+                }
+                Err(_) => {
+                    panics_in_a_row += 1;
+                    warn!("Job {} panicked, restarting it", job.name);
+                    ("panic", backoff(panics_in_a_row))
+                }
+            };
+            counters::observe_job(job.name, outcome, start.elapsed());
+
+            let was_healthy = self.lock().statuses[index].healthy;
+            match &result {
+                Ok(Ok(())) if !was_healthy => {
+                    notifier.notify(&format!("Job {} recovered", job.name));
+                }
+                Ok(Err(e)) if was_healthy => {
+                    notifier.notify(&format!("Job {} failed: {:#}", job.name, e));
+                }
+                Err(panic) if was_healthy => {
+                    notifier.notify(&format!("Job {} {}", job.name, panic_message(&**panic)));
+                }
+                _ => (),
+            }
+
+            self.update(index, |status| {
+                status.last_run = Some(unix_now());
+                status.healthy = outcome == "success";
+                status.state = TaskState::Idle;
+                match result {
+                    Ok(Ok(())) => {
+                        status.runs += 1;
+                        status.last_error = None;
+                    }
+                    Ok(Err(e)) => {
+                        status.failures += 1;
+                        status.last_error = Some(format!("{:#}", e));
+                    }
+                    Err(panic) => {
+                        status.panics += 1;
+                        status.last_error = Some(panic_message(&*panic));
+                        status.state = TaskState::Backoff;
+                    }
+                }
+            });
+
+            if !self.sleep(pause) {
+                break;
+            }
+        }
+
+        let mut state = self.lock();
+        state.statuses[index].state = TaskState::Stopped;
+        state.running -= 1;
+        self.0.changed.notify_all();
+    }
+}
+
+/// Returns how long to wait before restarting a job that panicked
+/// this many times in a row.
+fn backoff(panics_in_a_row: u32) -> Duration {
+    let factor = 1u32 << panics_in_a_row.saturating_sub(1).min(16);
+    (PANIC_BACKOFF_MIN * factor).min(PANIC_BACKOFF_MAX)
+}
+
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        format!("panicked: {}", message)
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        format!("panicked: {}", message)
+    } else {
+        "panicked".into()
+    }
+}
+
+/// Varies the interval at random by up to `jitter` of it.
+fn jittered(interval: Duration, jitter: f64) -> Duration {
+    let mut random = [0; 4];
+    if SystemRandom::new().fill(&mut random).is_err() {
+        return interval;
+    }
+    // Uniform in [-1, 1].
+    let factor = u32::from_le_bytes(random) as f64 / u32::MAX as f64 * 2.0 - 1.0;
+    interval.mul_f64(1.0 + factor * jitter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jitter() {
+        let interval = Duration::from_secs(100);
+        assert_eq!(jittered(interval, 0.0), interval);
+        for _ in 0..100 {
+            let jittered = jittered(interval, 0.1);
+            assert!(jittered >= Duration::from_secs(90));
+            assert!(jittered <= Duration::from_secs(110));
+        }
+    }
+
+    #[test]
+    fn panic_backoff() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(5), Duration::from_secs(16));
+        assert_eq!(backoff(100), PANIC_BACKOFF_MAX);
+    }
+
+    #[test]
+    fn supervision() {
+        let tasks = Tasks::default();
+        let mut scheduler = Scheduler::new(0.0);
+        let mut calls = 0;
+        scheduler.register("flaky", Duration::from_millis(10), move || {
+            calls += 1;
+            match calls {
+                1 => Err(anyhow::anyhow!("failed")),
+                2 => panic!("oops"),
+                _ => Ok(()),
+            }
+        });
+        scheduler.spawn(&tasks);
+
+        // The panic is followed by a backoff of a second.
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while tasks.status()[0].runs == 0 {
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let status = &tasks.status()[0];
+        assert_eq!(status.name, "flaky");
+        assert_eq!(status.failures, 1);
+        assert_eq!(status.panics, 1);
+        assert!(status.healthy);
+
+        assert!(tasks.shutdown(Duration::from_secs(1)));
+        assert_eq!(tasks.status()[0].state, TaskState::Stopped);
+    }
+}
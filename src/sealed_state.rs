@@ -30,6 +30,9 @@ impl SealedState {
     }
 
     pub fn unseal(&self, mut data: Vec<u8>) -> Result<String, &'static str> {
+        if data.len() < NONCE_LEN {
+            return Err("invalid value: too short");
+        }
         let (nonce, sealed) = data.split_at_mut(NONCE_LEN);
         let unsealed = open_in_place(&self.opening_key, nonce, &[], 0, sealed)
             .map_err(|_| "invalid key/nonce/value: bad seal")?;
@@ -0,0 +1,242 @@
+//! The key the service signs with, e.g. tree heads of the
+//! transparency log and keylists.
+//!
+//! By default, the secret key is read from a file.  Alternatively, it
+//! stays in an HSM or other PKCS#11 token, and only the certificate is
+//! on disk.  Like for other external services, we hand off the actual
+//! work to a program, in this case OpenSC's pkcs11-tool.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use sequoia_openpgp::crypto::{mpi, Signer};
+use sequoia_openpgp::packet::key::{PublicParts, SecretParts, UnspecifiedRole};
+use sequoia_openpgp::packet::Key;
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::policy::StandardPolicy;
+use sequoia_openpgp::types::{HashAlgorithm, PublicKeyAlgorithm};
+use sequoia_openpgp::Cert;
+
+use crate::Result;
+
+/// Passed to pkcs11-tool in the environment, rather than on the
+/// command line, where it would be visible to other users.
+const PIN_VARIABLE: &str = "HAGRID_PKCS11_PIN";
+
+/// A key the service can make signatures with.
+pub trait ServiceKey: Send + Sync {
+    fn public(&self) -> &Key<PublicParts, UnspecifiedRole>;
+
+    /// Returns a signer for making a signature.
+    fn signer(&self) -> Result<Box<dyn Signer + Send + Sync>>;
+}
+
+/// A secret key read from a file.
+pub struct SoftwareKey {
+    key: Key<SecretParts, UnspecifiedRole>,
+    public: Key<PublicParts, UnspecifiedRole>,
+}
+
+impl SoftwareKey {
+    /// Uses the first valid, unencrypted signing key of the given
+    /// certificate.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let policy = StandardPolicy::new();
+        let cert = Cert::from_file(path)?;
+        let key = cert
+            .keys()
+            .with_policy(&policy, None)
+            .alive()
+            .revoked(false)
+            .for_signing()
+            .secret()
+            .next()
+            .ok_or_else(|| anyhow!("No signing key in {}", path.display()))?
+            .key()
+            .clone();
+        let public = key.clone().parts_into_public();
+        Ok(SoftwareKey { key, public })
+    }
+}
+
+impl ServiceKey for SoftwareKey {
+    fn public(&self) -> &Key<PublicParts, UnspecifiedRole> {
+        &self.public
+    }
+
+    fn signer(&self) -> Result<Box<dyn Signer + Send + Sync>> {
+        Ok(Box::new(self.key.clone().into_keypair()?))
+    }
+}
+
+/// Where the secret key is kept in a PKCS#11 token.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Pkcs11Config {
+    /// The PKCS#11 module, e.g. `/usr/lib/softhsm/libsofthsm2.so`.
+    pub module: PathBuf,
+    /// The label of the token, if the module has several.
+    pub token: Option<String>,
+    /// The ID of the key object, in hex.
+    pub key_id: String,
+    /// A file containing the user PIN.
+    pub pin_file: Option<PathBuf>,
+}
+
+/// A key in a PKCS#11 token, whose public part is taken from a
+/// certificate.
+#[derive(Clone)]
+pub struct Pkcs11Key {
+    public: Key<PublicParts, UnspecifiedRole>,
+    config: Pkcs11Config,
+    pin: Option<String>,
+}
+
+impl Pkcs11Key {
+    /// Uses the first valid signing key of the given certificate,
+    /// which must be the one in the token.
+    pub fn new(cert_path: &Path, config: Pkcs11Config) -> Result<Self> {
+        let policy = StandardPolicy::new();
+        let cert = Cert::from_file(cert_path)?;
+        let public = cert
+            .keys()
+            .with_policy(&policy, None)
+            .alive()
+            .revoked(false)
+            .for_signing()
+            .next()
+            .ok_or_else(|| anyhow!("No signing key in {}", cert_path.display()))?
+            .key()
+            .clone();
+        mechanism(public.pk_algo())?;
+        let pin = match &config.pin_file {
+            Some(path) => Some(fs::read_to_string(path)?.trim().to_owned()),
+            None => None,
+        };
+        Ok(Pkcs11Key {
+            public,
+            config,
+            pin,
+        })
+    }
+
+    /// Signs the input with the key in the token, and returns the raw
+    /// signature.
+    fn pkcs11_sign(&self, input: &[u8]) -> Result<Vec<u8>> {
+        let input_file = tempfile::NamedTempFile::new()?;
+        fs::write(input_file.path(), input)?;
+        let output_file = tempfile::NamedTempFile::new()?;
+
+        let mut command = Command::new("pkcs11-tool");
+        command
+            .arg("--module")
+            .arg(&self.config.module)
+            .args(&["--sign", "--id", &self.config.key_id])
+            .args(&["--mechanism", mechanism(self.public.pk_algo())?])
+            .arg("--input-file")
+            .arg(input_file.path())
+            .arg("--output-file")
+            .arg(output_file.path());
+        if let Some(token) = &self.config.token {
+            command.args(&["--token-label", token]);
+        }
+        if let Some(pin) = &self.pin {
+            command
+                .args(&["--login", "--pin", &format!("env:{}", PIN_VARIABLE)])
+                .env(PIN_VARIABLE, pin);
+        }
+
+        let output = command.output()?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "pkcs11-tool failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+        Ok(fs::read(output_file.path())?)
+    }
+}
+
+impl ServiceKey for Pkcs11Key {
+    fn public(&self) -> &Key<PublicParts, UnspecifiedRole> {
+        &self.public
+    }
+
+    fn signer(&self) -> Result<Box<dyn Signer + Send + Sync>> {
+        Ok(Box::new(self.clone()))
+    }
+}
+
+impl Signer for Pkcs11Key {
+    fn public(&self) -> &Key<PublicParts, UnspecifiedRole> {
+        &self.public
+    }
+
+    fn sign(&mut self, hash_algo: HashAlgorithm, digest: &[u8]) -> Result<mpi::Signature> {
+        match self.public.pk_algo() {
+            PublicKeyAlgorithm::RSAEncryptSign => {
+                // The token pads, but we encode the DigestInfo.
+                let mut input = hash_algo.oid()?.to_vec();
+                input.extend_from_slice(digest);
+                let signature = self.pkcs11_sign(&input)?;
+                Ok(mpi::Signature::RSA {
+                    s: mpi::MPI::new(&signature),
+                })
+            }
+            PublicKeyAlgorithm::ECDSA | PublicKeyAlgorithm::EdDSA => {
+                let signature = self.pkcs11_sign(digest)?;
+                let (r, s) = split_signature(&signature)?;
+                if self.public.pk_algo() == PublicKeyAlgorithm::ECDSA {
+                    Ok(mpi::Signature::ECDSA { r, s })
+                } else {
+                    Ok(mpi::Signature::EdDSA { r, s })
+                }
+            }
+            algo => Err(anyhow!("Unsupported key algorithm for PKCS#11: {}", algo)),
+        }
+    }
+}
+
+/// Returns pkcs11-tool's name of the mechanism for signing with keys
+/// of this algorithm.
+fn mechanism(algo: PublicKeyAlgorithm) -> Result<&'static str> {
+    match algo {
+        PublicKeyAlgorithm::RSAEncryptSign => Ok("RSA-PKCS"),
+        PublicKeyAlgorithm::ECDSA => Ok("ECDSA"),
+        PublicKeyAlgorithm::EdDSA => Ok("EDDSA"),
+        algo => Err(anyhow!("Unsupported key algorithm for PKCS#11: {}", algo)),
+    }
+}
+
+/// Splits an (EC)DSA signature as returned by PKCS#11, the
+/// concatenation of r and s of equal length.
+fn split_signature(signature: &[u8]) -> Result<(mpi::MPI, mpi::MPI)> {
+    if signature.is_empty() || signature.len() % 2 != 0 {
+        return Err(anyhow!(
+            "Malformed signature of {} bytes from the token",
+            signature.len()
+        ));
+    }
+    let (r, s) = signature.split_at(signature.len() / 2);
+    Ok((mpi::MPI::new(r), mpi::MPI::new(s)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signature_halves() {
+        let (r, s) = split_signature(&[0, 1, 2, 3]).unwrap();
+        assert_eq!(r.value(), &[1]);
+        assert_eq!(s.value(), &[2, 3]);
+        assert!(split_signature(&[1, 2, 3]).is_err());
+        assert!(split_signature(&[]).is_err());
+    }
+
+    #[test]
+    fn mechanisms() {
+        assert_eq!(mechanism(PublicKeyAlgorithm::EdDSA).unwrap(), "EDDSA");
+        assert!(mechanism(PublicKeyAlgorithm::DSA).is_err());
+    }
+}
@@ -0,0 +1,235 @@
+//! Outbound connections through a SOCKS5 proxy (RFC 1928), e.g. Tor.
+//!
+//! Target hosts are passed to the proxy by name, so that the proxy
+//! resolves them, and no DNS queries leave the host directly.  For
+//! clients that can't be told to use a proxy, like the SMTP transport,
+//! we forward a local port to the target through the proxy.
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT: u8 = 1;
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+/// How long to wait for the proxy to set up a connection.  Tor can
+/// take a while to build a circuit.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Socks5Proxy {
+    /// The proxy's address, as `host:port`.
+    address: String,
+}
+
+impl Socks5Proxy {
+    pub fn new(address: impl Into<String>) -> Self {
+        Socks5Proxy {
+            address: address.into(),
+        }
+    }
+
+    /// The proxy URL for curl, which has the proxy resolve names.
+    pub fn curl_url(&self) -> String {
+        format!("socks5h://{}", self.address)
+    }
+
+    /// Connects to `host` on `port` through the proxy.
+    pub fn connect(&self, host: &str, port: u16) -> io::Result<TcpStream> {
+        if host.is_empty() || host.len() > 255 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Host name too long for SOCKS5",
+            ));
+        }
+
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+
+        stream.write_all(&[VERSION, 1, NO_AUTHENTICATION])?;
+        let mut reply = [0; 2];
+        stream.read_exact(&mut reply)?;
+        if reply != [VERSION, NO_AUTHENTICATION] {
+            return Err(protocol_error("Proxy requires authentication"));
+        }
+
+        let mut request = vec![VERSION, CONNECT, 0, ADDRESS_DOMAIN, host.len() as u8];
+        request.extend_from_slice(host.as_bytes());
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[0] != VERSION {
+            return Err(protocol_error("Not a SOCKS5 proxy"));
+        }
+        if reply[1] != 0 {
+            return Err(protocol_error(ReplyError(reply[1])));
+        }
+        // Skip the address the proxy bound, which we don't need.
+        let address_len = match reply[3] {
+            ADDRESS_IPV4 => 4,
+            ADDRESS_IPV6 => 16,
+            ADDRESS_DOMAIN => {
+                let mut len = [0; 1];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            _ => return Err(protocol_error("Unknown address type")),
+        };
+        let mut bound = vec![0; address_len + 2];
+        stream.read_exact(&mut bound)?;
+
+        stream.set_read_timeout(None)?;
+        Ok(stream)
+    }
+
+    /// Forwards connections to a local port to `host` on `port`
+    /// through the proxy, and returns the local address.
+    ///
+    /// The port is bound to the loopback interface, and forwards for
+    /// as long as the process lives.
+    pub fn forward(&self, host: &str, port: u16) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let local_addr = listener.local_addr()?;
+        let proxy = self.clone();
+        let host = host.to_owned();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = match client {
+                    Ok(client) => client,
+                    Err(e) => {
+                        eprintln!("Error accepting connection to forward: {}", e);
+                        continue;
+                    }
+                };
+                let proxy = proxy.clone();
+                let host = host.clone();
+                thread::spawn(move || match proxy.connect(&host, port) {
+                    Ok(upstream) => {
+                        if let Err(e) = splice(client, upstream) {
+                            eprintln!("Error forwarding to {}:{}: {}", host, port, e);
+                        }
+                    }
+                    Err(e) => eprintln!("Error connecting to {}:{} via proxy: {}", host, port, e),
+                });
+            }
+        });
+        Ok(local_addr)
+    }
+}
+
+/// Copies data both ways until either side closes the connection.
+fn splice(client: TcpStream, upstream: TcpStream) -> io::Result<()> {
+    let mut client_read = client.try_clone()?;
+    let mut upstream_write = upstream.try_clone()?;
+    let outbound = thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut upstream_write);
+        let _ = upstream_write.shutdown(Shutdown::Write);
+    });
+
+    let (mut upstream_read, mut client_write) = (upstream, client);
+    let result = io::copy(&mut upstream_read, &mut client_write);
+    let _ = client_write.shutdown(Shutdown::Both);
+    let _ = outbound.join();
+    result.map(|_| ())
+}
+
+fn protocol_error(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, error)
+}
+
+/// A failure reported by the proxy.
+#[derive(Debug)]
+struct ReplyError(u8);
+
+impl fmt::Display for ReplyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self.0 {
+            1 => "general failure",
+            2 => "connection not allowed by ruleset",
+            3 => "network unreachable",
+            4 => "host unreachable",
+            5 => "connection refused",
+            6 => "TTL expired",
+            7 => "command not supported",
+            8 => "address type not supported",
+            _ => "unknown error",
+        };
+        write!(f, "SOCKS5 proxy: {}", reason)
+    }
+}
+
+impl std::error::Error for ReplyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A proxy that accepts one connection, checks the request, and
+    /// then echoes what it receives.
+    fn fake_proxy(reply: u8) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [VERSION, 1, NO_AUTHENTICATION]);
+            stream.write_all(&[VERSION, NO_AUTHENTICATION]).unwrap();
+
+            let mut request = [0; 5];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request[..4], [VERSION, CONNECT, 0, ADDRESS_DOMAIN]);
+            let mut host = vec![0; request[4] as usize + 2];
+            stream.read_exact(&mut host).unwrap();
+            assert_eq!(&host[..], b"mail.example.org\x02\x4b");
+
+            stream
+                .write_all(&[VERSION, reply, 0, ADDRESS_IPV4, 127, 0, 0, 1, 0, 0])
+                .unwrap();
+            if reply == 0 {
+                let mut read = stream.try_clone().unwrap();
+                io::copy(&mut read, &mut stream).unwrap();
+            }
+        });
+        addr
+    }
+
+    #[test]
+    fn connect() {
+        let proxy = Socks5Proxy::new(fake_proxy(0).to_string());
+        let mut stream = proxy.connect("mail.example.org", 587).unwrap();
+        stream.write_all(b"EHLO").unwrap();
+        let mut echo = [0; 4];
+        stream.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"EHLO");
+
+        let proxy = Socks5Proxy::new(fake_proxy(5).to_string());
+        let error = proxy.connect("mail.example.org", 587).unwrap_err();
+        assert!(error.to_string().contains("connection refused"));
+    }
+
+    #[test]
+    fn forward() {
+        let proxy = Socks5Proxy::new(fake_proxy(0).to_string());
+        let local = proxy.forward("mail.example.org", 587).unwrap();
+        let mut stream = TcpStream::connect(local).unwrap();
+        stream.write_all(b"EHLO").unwrap();
+        let mut echo = [0; 4];
+        stream.read_exact(&mut echo).unwrap();
+        assert_eq!(&echo, b"EHLO");
+    }
+
+    #[test]
+    fn curl_url() {
+        let proxy = Socks5Proxy::new("127.0.0.1:9050");
+        assert_eq!(proxy.curl_url(), "socks5h://127.0.0.1:9050");
+    }
+}
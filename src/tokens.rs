@@ -27,6 +27,11 @@ impl Service {
         }
     }
 
+    /// How long tokens are valid, in seconds.
+    pub fn validity(&self) -> u64 {
+        self.validity
+    }
+
     pub fn create(&self, payload_content: &impl StatelessSerializable) -> String {
         let payload = serde_json::to_string(payload_content).unwrap();
         let creation = current_time();
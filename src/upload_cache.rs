@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ring::digest;
+
+use crate::database::types::{Email, Fingerprint};
+
+/// Uploads remembered at most, to bound memory use.
+const CAPACITY: usize = 10000;
+
+/// What an upload was merged as.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CachedUpload {
+    Single {
+        fpr: Fingerprint,
+        addresses: Vec<Email>,
+    },
+    Multiple {
+        key_fprs: Vec<String>,
+    },
+}
+
+/// Remembers recently merged uploads by the hash of their bytes.
+///
+/// Popular keys are uploaded over and over again by clients.  If we
+/// have merged the exact same bytes recently, there is nothing new to
+/// merge, and we can skip straight to the response.
+pub struct UploadCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<Vec<u8>, (Instant, CachedUpload)>>,
+}
+
+impl UploadCache {
+    pub fn new(ttl_secs: u64) -> Self {
+        UploadCache {
+            ttl: Duration::from_secs(ttl_secs),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn hash(data: &[u8]) -> Vec<u8> {
+        digest::digest(&digest::SHA256, data).as_ref().to_vec()
+    }
+
+    pub fn get(&self, hash: &[u8]) -> Option<CachedUpload> {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(hash)
+            .filter(|(merged_at, _)| merged_at.elapsed() < self.ttl)
+            .map(|(_, upload)| upload.clone())
+    }
+
+    pub fn insert(&self, hash: Vec<u8>, upload: CachedUpload) {
+        if self.ttl == Duration::from_secs(0) {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= CAPACITY {
+            let ttl = self.ttl;
+            entries.retain(|_, (merged_at, _)| merged_at.elapsed() < ttl);
+        }
+        if entries.len() < CAPACITY {
+            entries.insert(hash, (Instant::now(), upload));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    fn upload() -> CachedUpload {
+        CachedUpload::Multiple {
+            key_fprs: vec!["CBCD8F030588653EEDD7E2659B7DD433F254904A".to_owned()],
+        }
+    }
+
+    #[test]
+    fn remembers_uploads() {
+        let cache = UploadCache::new(60);
+        let hash = UploadCache::hash(b"key");
+
+        assert_eq!(cache.get(&hash), None);
+        cache.insert(hash.clone(), upload());
+        assert_eq!(cache.get(&hash), Some(upload()));
+        assert_eq!(cache.get(&UploadCache::hash(b"other key")), None);
+    }
+
+    #[test]
+    fn forgets_uploads() {
+        let cache = UploadCache::new(1);
+        let hash = UploadCache::hash(b"key");
+
+        cache.insert(hash.clone(), upload());
+        thread::sleep(Duration::from_secs(1));
+        assert_eq!(cache.get(&hash), None);
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = UploadCache::new(0);
+        let hash = UploadCache::hash(b"key");
+
+        cache.insert(hash.clone(), upload());
+        assert_eq!(cache.get(&hash), None);
+    }
+}
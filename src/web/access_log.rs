@@ -0,0 +1,176 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use chrono::Local;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+
+use crate::web::client_ip::ClientIp;
+use crate::Result;
+
+/// The formats understood by common log analyzers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LogFormat {
+    Common,
+    /// The common format, followed by referrer and user agent.
+    Combined,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "common" => Ok(LogFormat::Common),
+            "combined" => Ok(LogFormat::Combined),
+            _ => Err(anyhow!("Unknown access log format: {}", s)),
+        }
+    }
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+}
+
+/// Writes a line per request to an access log.
+///
+/// Once the log would grow beyond its maximum size, it is renamed to
+/// `<path>.1`, older logs are shifted to `<path>.2` and so on, and the
+/// oldest one is removed.
+pub struct AccessLog {
+    path: PathBuf,
+    format: LogFormat,
+    max_size: u64,
+    keep: usize,
+    file: Mutex<LogFile>,
+}
+
+/// The request line as received, before any fairing rewrote it.
+struct RequestLine(String);
+
+impl RequestLine {
+    fn of(request: &Request<'_>) -> Self {
+        // Rocket doesn't tell which version of HTTP was used.
+        RequestLine(format!("{} {} HTTP/1.1", request.method(), request.uri()))
+    }
+}
+
+impl AccessLog {
+    pub fn new(path: PathBuf, format: LogFormat, max_size: u64, keep: usize) -> Result<Self> {
+        let file = Self::open(&path)?;
+        Ok(AccessLog {
+            path,
+            format,
+            max_size,
+            keep,
+            file: Mutex::new(file),
+        })
+    }
+
+    fn open(path: &Path) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { file, size })
+    }
+
+    fn rotated_path(&self, generation: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", generation));
+        path.into()
+    }
+
+    fn rotate(&self, log: &mut LogFile) -> io::Result<()> {
+        if self.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for generation in (1..self.keep).rev() {
+                let _ = fs::rename(
+                    self.rotated_path(generation),
+                    self.rotated_path(generation + 1),
+                );
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        *log = Self::open(&self.path)?;
+        Ok(())
+    }
+
+    fn write(&self, line: &str) -> io::Result<()> {
+        let mut log = self.file.lock().unwrap();
+        let len = line.len() as u64;
+        if self.max_size > 0 && log.size > 0 && log.size + len > self.max_size {
+            self.rotate(&mut log)?;
+        }
+        log.file.write_all(line.as_bytes())?;
+        log.size += len;
+        Ok(())
+    }
+
+    fn format_line(
+        &self,
+        client_ip: &str,
+        request: &Request<'_>,
+        response: &Response<'_>,
+    ) -> String {
+        let request_line = &request.local_cache(|| RequestLine::of(request)).0;
+        let size = match response.body().preset_size() {
+            Some(size) => size.to_string(),
+            None => "-".to_owned(),
+        };
+
+        let mut line = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            client_ip,
+            Local::now().format("%d/%b/%Y:%H:%M:%S %z"),
+            escape(request_line),
+            response.status().code,
+            size,
+        );
+        if self.format == LogFormat::Combined {
+            let header = |name: &str| escape(request.headers().get_one(name).unwrap_or("-"));
+            line.push_str(&format!(
+                " \"{}\" \"{}\"",
+                header("Referer"),
+                header("User-Agent")
+            ));
+        }
+        line.push('\n');
+        line
+    }
+}
+
+/// Escapes quotes and control characters, which would break up
+/// fields or lines.
+fn escape(field: &str) -> String {
+    field.chars().flat_map(char::escape_default).collect()
+}
+
+#[async_trait]
+impl Fairing for AccessLog {
+    fn info(&self) -> Info {
+        Info {
+            name: "Access Log",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let request_line = RequestLine::of(request);
+        request.local_cache(|| request_line);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let client_ip = match request.guard::<ClientIp>().await.succeeded() {
+            Some(ClientIp(Some(client_ip))) => client_ip.to_string(),
+            _ => "-".to_owned(),
+        };
+        let line = self.format_line(&client_ip, request, response);
+        if let Err(e) = self.write(&line) {
+            eprintln!("Error writing access log: {:?}", e);
+        }
+    }
+}
@@ -0,0 +1,447 @@
+use rocket::form::Form;
+use rocket::http::{Header, Method, Status};
+use rocket::outcome::Outcome;
+use rocket::request;
+use rocket::serde::json::Json;
+use rocket_i18n::I18n;
+use serde_json::json;
+
+use crate::database::types::{Email, Fingerprint};
+use crate::database::{Database, HeldKey, KeyDatabase, LegalHold, Query};
+use crate::scheduler::Tasks;
+use crate::web::bearer;
+use crate::web::csrf;
+use crate::web::maintenance::MaintenanceMode;
+use crate::web::policies::Policies;
+use crate::web::read_only::ReadOnly;
+use crate::web::{MyResponse, RequestOrigin};
+use crate::Result;
+
+use sequoia_openpgp::{parse::Parse, Cert};
+
+pub mod json {
+    #[derive(Deserialize)]
+    pub struct MaintenanceRequest {
+        pub message: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct BanRequest {
+        pub reason: String,
+        /// Public notice for lookups of a banned key.
+        #[serde(default)]
+        pub notice: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    pub struct LegalHoldRequest {
+        pub reason: String,
+    }
+}
+
+mod forms {
+    #[derive(FromForm)]
+    pub struct QuarantineDecision {
+        pub csrf: String,
+    }
+}
+
+mod templates {
+    #[derive(Serialize)]
+    pub struct Quarantine {
+        pub message: Option<String>,
+        pub entries: Vec<QuarantineEntry>,
+    }
+
+    #[derive(Serialize)]
+    pub struct QuarantineEntry {
+        pub key_fpr: String,
+        pub subkey_fprs: Vec<String>,
+        pub userids: Vec<String>,
+        pub size: usize,
+        /// Why the key is under legal hold, if it is.
+        pub legal_hold: Option<String>,
+        /// The salted hash of the address the key was uploaded from,
+        /// if known.
+        pub uploader: Option<String>,
+    }
+}
+
+/// The secret that authenticates administrative requests.
+///
+/// If no token is configured, the admin API is disabled.
+pub struct AdminToken(Option<String>);
+
+impl AdminToken {
+    pub fn new(token: Option<String>) -> Self {
+        AdminToken(token.filter(|token| !token.is_empty()))
+    }
+}
+
+/// Request guard for requests carrying the admin token, either as
+/// bearer token, or for browsers as password of HTTP basic
+/// authentication.
+pub struct Admin;
+
+#[async_trait]
+impl<'r> request::FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r request::Request<'_>,
+    ) -> request::Outcome<Self, Self::Error> {
+        let expected = match request.rocket().state::<AdminToken>() {
+            Some(AdminToken(Some(token))) => token,
+            _ => return Outcome::Failure((Status::NotFound, ())),
+        };
+        let basic = request
+            .headers()
+            .get_one("Authorization")
+            .and_then(|authorization| authorization.strip_prefix("Basic "));
+        let (presented, is_basic) = if let Some(token) = bearer::token(request) {
+            (Some(token.to_owned()), false)
+        } else if let Some(credentials) = basic {
+            (basic_auth_password(credentials), true)
+        } else {
+            (None, false)
+        };
+
+        match presented {
+            Some(token) if bearer::matches(&token, expected) => {}
+            _ => return Outcome::Failure((Status::Unauthorized, ())),
+        }
+
+        // Browsers send basic credentials along with cross-site form
+        // submissions, so we only accept those from our own pages.
+        // Browsers too old to say where a request comes from are
+        // refused as well.
+        let is_same_site = request
+            .headers()
+            .get_one("Sec-Fetch-Site")
+            .map_or(false, |site| site == "same-origin" || site == "none");
+        if is_basic && request.method() != Method::Get && !is_same_site {
+            return Outcome::Failure((Status::Forbidden, ()));
+        }
+
+        Outcome::Success(Admin)
+    }
+}
+
+fn basic_auth_password(credentials: &str) -> Option<String> {
+    let credentials = base64::decode(credentials.trim()).ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (_, password) = credentials.split_once(':')?;
+    Some(password.to_owned())
+}
+
+#[derive(Responder)]
+#[response(status = 401, content_type = "plain")]
+pub struct Unauthorized(&'static str, Header<'static>);
+
+/// Asks browsers to prompt for the admin token.
+#[catch(401)]
+pub fn unauthorized() -> Unauthorized {
+    Unauthorized(
+        "Unauthorized",
+        Header::new("WWW-Authenticate", "Basic realm=\"hagrid admin\""),
+    )
+}
+
+#[post("/admin/v1/ban/<fpr>", format = "json", data = "<data>")]
+pub fn ban(
+    _admin: Admin,
+    db: &rocket::State<KeyDatabase>,
+    fpr: String,
+    data: Json<json::BanRequest>,
+) -> MyResponse {
+    let fpr = match parse_primary_fingerprint(db, &fpr) {
+        Ok(fpr) => fpr,
+        Err(response) => return response,
+    };
+
+    match db.ban(&fpr, &data.reason, data.notice.as_deref()) {
+        Ok(()) => MyResponse::json(json!({
+            "key_fpr": fpr.to_string(),
+            "banned": true,
+        })),
+        Err(e) if e.downcast_ref::<HeldKey>().is_some() => {
+            MyResponse::bad_request_plain(e.to_string())
+        }
+        Err(e) => MyResponse::ise(e),
+    }
+}
+
+#[post("/admin/v1/ban-email/<entry>", format = "json", data = "<data>")]
+pub fn ban_email(
+    _admin: Admin,
+    db: &rocket::State<KeyDatabase>,
+    entry: String,
+    data: Json<json::BanRequest>,
+) -> MyResponse {
+    let to_strings = |emails: Vec<Email>| {
+        emails
+            .iter()
+            .map(|email| email.to_string())
+            .collect::<Vec<_>>()
+    };
+    match db.ban_email(&entry, &data.reason) {
+        Ok(result) => MyResponse::json(json!({
+            "banned": entry,
+            "unpublished": to_strings(result.unpublished),
+            "held": to_strings(result.held),
+        })),
+        Err(e) => MyResponse::bad_request_plain(e.to_string()),
+    }
+}
+
+/// Lists the keys under legal hold.
+#[get("/admin/v1/legal-hold")]
+pub fn legal_holds(_admin: Admin, db: &rocket::State<KeyDatabase>) -> MyResponse {
+    let holds: Vec<_> = db
+        .legal_holds()
+        .iter()
+        .filter_map(|fpr| Some(legal_hold_json(fpr, &db.legal_hold(fpr)?)))
+        .collect();
+    MyResponse::json(json!({ "legal_holds": holds }))
+}
+
+/// Shows whether a key is under legal hold, and what else there is to
+/// know about it.
+#[get("/admin/v1/legal-hold/<fpr>")]
+pub fn legal_hold(_admin: Admin, db: &rocket::State<KeyDatabase>, fpr: String) -> MyResponse {
+    let fpr = match parse_primary_fingerprint(db, &fpr) {
+        Ok(fpr) => fpr,
+        Err(response) => return response,
+    };
+    let hold = db.legal_hold(&fpr);
+    MyResponse::json(json!({
+        "key_fpr": fpr.to_string(),
+        "legal_hold": hold.as_ref().map(|hold| legal_hold_json(&fpr, hold)),
+        "published": db.by_fpr(&fpr).is_some(),
+        "quarantined": db.by_fpr_quarantined(&fpr).is_some(),
+        "banned": db.is_banned(&fpr),
+    }))
+}
+
+#[put("/admin/v1/legal-hold/<fpr>", format = "json", data = "<data>")]
+pub fn legal_hold_place(
+    _admin: Admin,
+    db: &rocket::State<KeyDatabase>,
+    fpr: String,
+    data: Json<json::LegalHoldRequest>,
+) -> MyResponse {
+    let fpr = match parse_primary_fingerprint(db, &fpr) {
+        Ok(fpr) => fpr,
+        Err(response) => return response,
+    };
+    if let Err(e) = db.place_legal_hold(&fpr, &data.reason) {
+        return MyResponse::ise(e);
+    }
+    match db.legal_hold(&fpr) {
+        Some(hold) => MyResponse::json(legal_hold_json(&fpr, &hold)),
+        None => MyResponse::ise(anyhow!("Legal hold was not recorded")),
+    }
+}
+
+#[delete("/admin/v1/legal-hold/<fpr>")]
+pub fn legal_hold_release(
+    _admin: Admin,
+    db: &rocket::State<KeyDatabase>,
+    fpr: String,
+) -> MyResponse {
+    let fpr = match parse_primary_fingerprint(db, &fpr) {
+        Ok(fpr) => fpr,
+        Err(response) => return response,
+    };
+    match db.release_legal_hold(&fpr) {
+        Ok(()) => MyResponse::json(json!({
+            "key_fpr": fpr.to_string(),
+            "legal_hold": null,
+        })),
+        Err(e) => MyResponse::bad_request_plain(e.to_string()),
+    }
+}
+
+/// Subkey fingerprints refer to the key they belong to.  Keys we don't
+/// know can be referred to by their primary fingerprint.
+fn parse_primary_fingerprint(
+    db: &KeyDatabase,
+    fpr: &str,
+) -> std::result::Result<Fingerprint, MyResponse> {
+    let fpr = fpr
+        .parse::<Fingerprint>()
+        .map_err(|_| MyResponse::bad_request_plain("malformed fingerprint"))?;
+    Ok(db
+        .lookup_primary_fingerprint(&Query::ByFingerprint(fpr.clone()))
+        .unwrap_or(fpr))
+}
+
+fn legal_hold_json(fpr: &Fingerprint, hold: &LegalHold) -> serde_json::Value {
+    json!({
+        "key_fpr": fpr.to_string(),
+        "reason": hold.reason,
+        "since": hold.since,
+    })
+}
+
+#[get("/admin/v1/maintenance")]
+pub fn maintenance(_admin: Admin, maintenance: &rocket::State<MaintenanceMode>) -> MyResponse {
+    let message = maintenance.get_maintenance_message();
+    MyResponse::json(json!({
+        "maintenance": message.is_some(),
+        "message": message,
+    }))
+}
+
+#[put("/admin/v1/maintenance", format = "json", data = "<data>")]
+pub fn maintenance_enable(
+    _admin: Admin,
+    maintenance: &rocket::State<MaintenanceMode>,
+    data: Json<json::MaintenanceRequest>,
+) -> MyResponse {
+    match maintenance.enable(&data.message) {
+        Ok(()) => MyResponse::json(json!({
+            "maintenance": true,
+            "message": data.message,
+        })),
+        Err(e) => MyResponse::ise(e),
+    }
+}
+
+#[delete("/admin/v1/maintenance")]
+pub fn maintenance_disable(
+    _admin: Admin,
+    maintenance: &rocket::State<MaintenanceMode>,
+) -> MyResponse {
+    match maintenance.disable() {
+        Ok(()) => MyResponse::json(json!({ "maintenance": false })),
+        Err(e) => MyResponse::ise(e),
+    }
+}
+
+#[get("/admin/v1/read-only")]
+pub fn read_only(_admin: Admin, read_only: &rocket::State<ReadOnly>) -> MyResponse {
+    MyResponse::json(json!({ "read_only": read_only.is_enabled() }))
+}
+
+#[put("/admin/v1/read-only")]
+pub fn read_only_enable(_admin: Admin, read_only: &rocket::State<ReadOnly>) -> MyResponse {
+    read_only.set_enabled(true);
+    MyResponse::json(json!({ "read_only": true }))
+}
+
+#[delete("/admin/v1/read-only")]
+pub fn read_only_disable(_admin: Admin, read_only: &rocket::State<ReadOnly>) -> MyResponse {
+    read_only.set_enabled(false);
+    MyResponse::json(json!({ "read_only": false }))
+}
+
+/// Reloads rate limits, mail domain lists, body limits, and the
+/// publication policy from the configuration file.
+#[post("/admin/v1/reload")]
+pub fn reload(_admin: Admin, policies: &rocket::State<Policies>) -> MyResponse {
+    match policies.reload(&rocket::Config::figment()) {
+        Ok(()) => MyResponse::json(json!({ "reloaded": true })),
+        Err(e) => MyResponse::ise(e),
+    }
+}
+
+/// Lists the background jobs, and how they have been doing.
+#[get("/admin/v1/tasks")]
+pub fn tasks(_admin: Admin, tasks: &rocket::State<Tasks>) -> MyResponse {
+    let tasks = tasks.status();
+    MyResponse::json(json!({
+        "healthy": tasks.iter().all(|task| task.healthy),
+        "tasks": tasks,
+    }))
+}
+
+#[get("/admin/quarantine")]
+pub fn quarantine(
+    _admin: Admin,
+    origin: RequestOrigin,
+    db: &rocket::State<KeyDatabase>,
+    i18n: I18n,
+    _session: csrf::Session,
+) -> MyResponse {
+    quarantine_page(origin, db, i18n, None)
+}
+
+#[post("/admin/quarantine/<fpr>/approve", data = "<request>")]
+pub fn quarantine_approve(
+    _admin: Admin,
+    origin: RequestOrigin,
+    db: &rocket::State<KeyDatabase>,
+    i18n: I18n,
+    session: csrf::Session,
+    fpr: String,
+    request: Form<forms::QuarantineDecision>,
+) -> MyResponse {
+    let result = session
+        .check(&request.csrf, &i18n)
+        .and_then(|()| fpr.parse::<Fingerprint>())
+        .and_then(|fpr| db.approve_quarantined(&fpr));
+    let message = match result {
+        Ok(_) => format!("Approved {}.", fpr),
+        Err(e) => format!("Approving {} failed: {}", fpr, e),
+    };
+    quarantine_page(origin, db, i18n, Some(message))
+}
+
+#[post("/admin/quarantine/<fpr>/reject", data = "<request>")]
+pub fn quarantine_reject(
+    _admin: Admin,
+    origin: RequestOrigin,
+    db: &rocket::State<KeyDatabase>,
+    i18n: I18n,
+    session: csrf::Session,
+    fpr: String,
+    request: Form<forms::QuarantineDecision>,
+) -> MyResponse {
+    let result = session
+        .check(&request.csrf, &i18n)
+        .and_then(|()| fpr.parse::<Fingerprint>())
+        .and_then(|fpr| db.reject_quarantined(&fpr));
+    let message = match result {
+        Ok(()) => format!("Rejected {}.", fpr),
+        Err(e) => format!("Rejecting {} failed: {}", fpr, e),
+    };
+    quarantine_page(origin, db, i18n, Some(message))
+}
+
+fn quarantine_page(
+    origin: RequestOrigin,
+    db: &KeyDatabase,
+    i18n: I18n,
+    message: Option<String>,
+) -> MyResponse {
+    let entries = db
+        .quarantined()
+        .into_iter()
+        .flat_map(|fpr| quarantine_entry(db, &fpr))
+        .collect();
+    let context = templates::Quarantine { message, entries };
+    MyResponse::ok("admin/quarantine", context, i18n, origin)
+}
+
+fn quarantine_entry(db: &KeyDatabase, fpr: &Fingerprint) -> Result<templates::QuarantineEntry> {
+    let armored = db
+        .by_fpr_quarantined(fpr)
+        .ok_or_else(|| anyhow!("Key not in quarantine!"))?;
+    let tpk = Cert::from_bytes(armored.as_bytes())?;
+    Ok(templates::QuarantineEntry {
+        key_fpr: fpr.to_string(),
+        subkey_fprs: tpk
+            .keys()
+            .subkeys()
+            .map(|key| key.fingerprint().to_hex())
+            .collect(),
+        userids: tpk
+            .userids()
+            .map(|uid| String::from_utf8_lossy(uid.value()).into_owned())
+            .collect(),
+        size: armored.len(),
+        legal_hold: db.legal_hold(fpr).map(|hold| hold.reason),
+        uploader: db.quarantine_uploader(fpr),
+    })
+}
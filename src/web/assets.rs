@@ -0,0 +1,130 @@
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+};
+use ring::digest;
+use rocket::fs::NamedFile;
+use rocket::http::Header;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::Result;
+
+/// Length of the content hash in versioned asset names, in hex digits.
+const HASH_LEN: usize = 16;
+
+/// Maps assets to names that change with their contents.
+///
+/// Browsers may keep versioned assets forever, since a changed asset
+/// gets a new name.
+#[derive(Debug, Default)]
+pub struct AssetManifest {
+    /// Versioned names by logical name, e.g. "site.css" =>
+    /// "site.0123456789abcdef.css".
+    versioned: HashMap<String, String>,
+    /// Logical names by versioned name.
+    logical: HashMap<String, String>,
+}
+
+impl AssetManifest {
+    /// Hashes all assets below the given directory.
+    pub fn load(assets_dir: &Path) -> Result<Self> {
+        let mut manifest = AssetManifest::default();
+        manifest.add_dir(assets_dir, Path::new(""))?;
+        Ok(manifest)
+    }
+
+    fn add_dir(&mut self, dir: &Path, prefix: &Path) -> Result<()> {
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            let name = prefix.join(entry.file_name());
+            if path.is_dir() {
+                self.add_dir(&path, &name)?;
+            } else if let Some(name) = name.to_str() {
+                let hash = digest::digest(&digest::SHA256, &fs::read(&path)?);
+                let hash: String = hash.as_ref().iter().map(|b| format!("{:02x}", b)).collect();
+                let versioned = versioned_name(name, &hash[..HASH_LEN]);
+                self.logical.insert(versioned.clone(), name.to_owned());
+                self.versioned.insert(name.to_owned(), versioned);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the URL of the given asset, which is versioned if the
+    /// asset is known.
+    pub fn url(&self, name: &str) -> String {
+        let name = self.versioned.get(name).map(String::as_str).unwrap_or(name);
+        format!("/assets/{}", name)
+    }
+
+    /// Returns the logical name of a versioned asset.
+    pub fn logical_name(&self, path: &Path) -> Option<&str> {
+        self.logical.get(path.to_str()?).map(String::as_str)
+    }
+}
+
+/// Inserts the hash before the extension, if any.
+fn versioned_name(name: &str, hash: &str) -> String {
+    let file_name_start = name.rfind('/').map_or(0, |i| i + 1);
+    match name[file_name_start..].rfind('.') {
+        Some(i) if i > 0 => {
+            let (stem, extension) = name.split_at(file_name_start + i);
+            format!("{}.{}{}", stem, hash, extension)
+        }
+        _ => format!("{}.{}", name, hash),
+    }
+}
+
+#[derive(Responder)]
+pub enum AssetFile {
+    Versioned(NamedFile, Header<'static>),
+    Unversioned(NamedFile),
+}
+
+impl AssetFile {
+    pub fn versioned(file: NamedFile) -> Self {
+        AssetFile::Versioned(
+            file,
+            Header::new("Cache-Control", "public, max-age=31536000, immutable"),
+        )
+    }
+}
+
+/// Resolves logical asset names in templates, e.g.
+/// `{{ asset "site.css" }}`.
+pub struct AssetHelper(pub Arc<AssetManifest>);
+
+impl HelperDef for AssetHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, '_>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let name = h
+            .param(0)
+            .and_then(|name| name.value().as_str())
+            .ok_or_else(|| RenderError::new("{{asset}} takes the name of an asset"))?;
+        out.write(&self.0.url(name))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn versioned_names() {
+        assert_eq!(versioned_name("site.css", "0123"), "site.0123.css");
+        assert_eq!(versioned_name("js/upload.js", "0123"), "js/upload.0123.js");
+        assert_eq!(versioned_name("img/a.b/logo", "0123"), "img/a.b/logo.0123");
+        assert_eq!(versioned_name(".hidden", "0123"), ".hidden.0123");
+    }
+}
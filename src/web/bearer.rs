@@ -0,0 +1,18 @@
+//! Bearer tokens, as used by the admin, research, and domain APIs.
+
+use ring::constant_time::verify_slices_are_equal;
+use rocket::Request;
+
+/// Returns the bearer token from the `Authorization` header, if any.
+pub fn token<'r>(request: &'r Request<'_>) -> Option<&'r str> {
+    request
+        .headers()
+        .get_one("Authorization")
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+}
+
+/// Compares a presented token with the expected one, in constant
+/// time.
+pub fn matches(presented: &str, expected: &str) -> bool {
+    verify_slices_are_equal(presented.as_bytes(), expected.as_bytes()).is_ok()
+}
@@ -0,0 +1,166 @@
+//! Lets key owners opt into publishing third-party certifications.
+//!
+//! Certifications by others are stripped from published keys, since
+//! anyone can add them.  Owners prove control of their key by signing
+//! a challenge, and name the certifiers whose certifications they
+//! want to keep.
+
+use rocket::serde::json::Json;
+use rocket_i18n::I18n;
+use serde_json::json;
+
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::policy::StandardPolicy;
+use sequoia_openpgp::{Cert, Packet};
+
+use std::convert::TryFrom;
+
+use crate::database::types::Fingerprint;
+use crate::database::{Database, KeyDatabase, Query};
+use crate::i18n_helpers::describe_query_error;
+use crate::tokens::{self, StatelessSerializable};
+use crate::web::MyResponse;
+
+/// Certifiers a key's owner may opt into.
+const MAX_CERTIFIERS: usize = 100;
+
+pub mod json {
+    #[derive(Deserialize)]
+    pub struct ChallengeRequest {
+        pub key_fpr: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct RetainRequest {
+        pub challenge: String,
+        pub signature: String,
+        pub certifiers: Vec<String>,
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct CertificationsChallenge {
+    retain_certifications_for: String,
+}
+
+impl StatelessSerializable for CertificationsChallenge {}
+
+/// Checks that the armored signature over the challenge was made by
+/// one of the key's current signing keys.
+///
+/// Signatures over the challenge followed by a newline are accepted
+/// too, as created by signing a file holding it.
+fn is_signed_by(cert: &Cert, challenge: &str, signature: &str) -> bool {
+    let mut sig = match Packet::from_bytes(signature.as_bytes()) {
+        Ok(Packet::Signature(sig)) => sig,
+        _ => return false,
+    };
+    let policy = StandardPolicy::new();
+    let vcert = match cert.with_policy(&policy, None) {
+        Ok(vcert) => vcert,
+        Err(_) => return false,
+    };
+    let messages = [challenge.to_string(), format!("{}\n", challenge)];
+
+    let signing_keys: Vec<_> = vcert
+        .keys()
+        .alive()
+        .revoked(false)
+        .for_signing()
+        .map(|ka| ka.key().clone())
+        .collect();
+    signing_keys.iter().any(|key| {
+        messages
+            .iter()
+            .any(|message| sig.verify_message(key, message.as_bytes()).is_ok())
+    })
+}
+
+fn lookup_key(db: &KeyDatabase, i18n: &I18n, fpr: &str) -> Result<Cert, MyResponse> {
+    let query = match fpr.parse::<Fingerprint>() {
+        Ok(fpr) => Query::ByFingerprint(fpr),
+        Err(_) => return Err(MyResponse::bad_request_json("malformed fingerprint")),
+    };
+    match db.lookup(&query) {
+        Ok(Some(cert)) => Ok(cert),
+        Ok(None) => Err(MyResponse::not_found_plain(describe_query_error(
+            i18n, &query,
+        ))),
+        Err(e) => Err(MyResponse::ise(e)),
+    }
+}
+
+#[post("/vks/v1/certifications/challenge", format = "json", data = "<data>")]
+pub fn challenge(
+    db: &rocket::State<KeyDatabase>,
+    token_service: &rocket::State<tokens::Service>,
+    i18n: I18n,
+    data: Json<json::ChallengeRequest>,
+) -> MyResponse {
+    let cert = match lookup_key(db, &i18n, &data.key_fpr) {
+        Ok(cert) => cert,
+        Err(response) => return response,
+    };
+    let fpr = match Fingerprint::try_from(cert.fingerprint()) {
+        Ok(fpr) => fpr,
+        Err(e) => return MyResponse::ise(e),
+    };
+    let certifiers = db.retained_certifiers(&fpr);
+
+    let challenge = token_service.create(&CertificationsChallenge {
+        retain_certifications_for: fpr.to_string(),
+    });
+    MyResponse::json(json!({
+        "key_fpr": fpr.to_string(),
+        "challenge": challenge,
+        "expires_in": token_service.validity(),
+        "certifiers": certifiers.iter().map(|fpr| fpr.to_string()).collect::<Vec<_>>(),
+    }))
+}
+
+#[post("/vks/v1/certifications/retain", format = "json", data = "<data>")]
+pub fn retain(
+    db: &rocket::State<KeyDatabase>,
+    token_service: &rocket::State<tokens::Service>,
+    i18n: I18n,
+    data: Json<json::RetainRequest>,
+) -> MyResponse {
+    let fpr = match token_service.check::<CertificationsChallenge>(&data.challenge) {
+        Ok(challenge) => challenge.retain_certifications_for,
+        Err(e) => return MyResponse::bad_request_json(e.to_string()),
+    };
+    let cert = match lookup_key(db, &i18n, &fpr) {
+        Ok(cert) => cert,
+        Err(response) => return response,
+    };
+    if !is_signed_by(&cert, &data.challenge, &data.signature) {
+        return MyResponse::forbidden_json("The challenge is not signed by this key");
+    }
+
+    if data.certifiers.len() > MAX_CERTIFIERS {
+        return MyResponse::bad_request_json(format!(
+            "At most {} certifiers can be chosen",
+            MAX_CERTIFIERS
+        ));
+    }
+    let certifiers = match data
+        .certifiers
+        .iter()
+        .map(|certifier| certifier.parse::<Fingerprint>())
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Ok(certifiers) => certifiers,
+        Err(e) => return MyResponse::bad_request_json(e.to_string()),
+    };
+
+    let result = Fingerprint::try_from(cert.fingerprint())
+        .and_then(|fpr| db.set_retained_certifiers(&fpr, certifiers.clone()));
+    match result {
+        Ok(certifications) => MyResponse::json(json!({
+            "key_fpr": fpr,
+            "certifiers": certifiers.iter().map(|fpr| fpr.to_string()).collect::<Vec<_>>(),
+            "certifications": certifications,
+        })),
+        Err(e) => MyResponse::ise(e),
+    }
+}
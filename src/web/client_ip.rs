@@ -0,0 +1,202 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+
+use ring::{digest, hmac};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::Request;
+
+use crate::Result;
+
+/// The proxies whose forwarding headers we believe, as addresses or
+/// CIDR ranges.
+///
+/// Without any, forwarding headers are ignored, and clients are
+/// identified by the address of the connection.
+pub struct TrustedProxies(Vec<(IpAddr, u8)>);
+
+impl TrustedProxies {
+    pub fn new(proxies: &[String]) -> Result<Self> {
+        let ranges = proxies
+            .iter()
+            .map(|proxy| {
+                let (address, prefix) = match proxy.split_once('/') {
+                    Some((address, prefix)) => (address, Some(prefix)),
+                    None => (proxy.as_str(), None),
+                };
+                let address: IpAddr = address
+                    .parse()
+                    .map_err(|e| anyhow!("Trusted proxy {}: {}", proxy, e))?;
+                let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+                let prefix = match prefix {
+                    Some(prefix) => prefix
+                        .parse::<u8>()
+                        .ok()
+                        .filter(|prefix| *prefix <= max_prefix)
+                        .ok_or_else(|| anyhow!("Trusted proxy {}: invalid prefix", proxy))?,
+                    None => max_prefix,
+                };
+                Ok((address, prefix))
+            })
+            .collect::<Result<_>>()?;
+        Ok(TrustedProxies(ranges))
+    }
+
+    fn is_trusted(&self, address: IpAddr) -> bool {
+        self.0
+            .iter()
+            .any(|&(range, prefix)| match (range, address) {
+                (IpAddr::V4(range), IpAddr::V4(address)) => {
+                    prefix_matches(&range.octets(), &address.octets(), prefix)
+                }
+                (IpAddr::V6(range), IpAddr::V6(address)) => {
+                    prefix_matches(&range.octets(), &address.octets(), prefix)
+                }
+                _ => false,
+            })
+    }
+
+    /// Returns the address of the client that sent the request.
+    ///
+    /// Forwarding headers are only followed through trusted proxies:
+    /// going from the connection's peer towards the client, the
+    /// first address that is not a trusted proxy is the client.
+    pub fn client_ip(&self, request: &Request<'_>) -> Option<IpAddr> {
+        let peer = request.remote().map(|remote| remote.ip())?;
+        if !self.is_trusted(peer) {
+            return Some(peer);
+        }
+
+        let mut client = peer;
+        for hop in forwarded_for(request).iter().rev() {
+            match hop {
+                Some(hop) => client = *hop,
+                None => break,
+            }
+            if !self.is_trusted(client) {
+                break;
+            }
+        }
+        Some(client)
+    }
+}
+
+fn prefix_matches(range: &[u8], address: &[u8], prefix: u8) -> bool {
+    let mut bits = prefix as usize;
+    for (range, address) in range.iter().zip(address) {
+        if bits == 0 {
+            break;
+        }
+        let mask = if bits >= 8 { 0xff } else { 0xff << (8 - bits) };
+        if range & mask != address & mask {
+            return false;
+        }
+        bits = bits.saturating_sub(8);
+    }
+    true
+}
+
+/// Returns the addresses the request was forwarded for, from the
+/// `Forwarded` header, or else from `X-Forwarded-For`.
+///
+/// Hops that aren't addresses, like obfuscated identifiers, are
+/// `None`.
+fn forwarded_for(request: &Request<'_>) -> Vec<Option<IpAddr>> {
+    let headers = request.headers();
+    if headers.contains("Forwarded") {
+        headers
+            .get("Forwarded")
+            .flat_map(|value| value.split(','))
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    if key.eq_ignore_ascii_case("for") {
+                        Some(parse_node(value.trim_matches('"')))
+                    } else {
+                        None
+                    }
+                })
+            })
+            .map(Option::flatten)
+            .collect()
+    } else {
+        headers
+            .get("X-Forwarded-For")
+            .flat_map(|value| value.split(','))
+            .map(|hop| parse_node(hop.trim()))
+            .collect()
+    }
+}
+
+/// Parses an address, possibly with a port, and IPv6 addresses in
+/// brackets.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|node| node.strip_suffix(']'))
+                .and_then(|node| node.parse().ok())
+        })
+}
+
+/// Request guard for the address of the client, see
+/// `TrustedProxies::client_ip`.
+pub struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for ClientIp {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let client_ip = request
+            .rocket()
+            .state::<TrustedProxies>()
+            .and_then(|proxies| proxies.client_ip(request))
+            .or_else(|| request.remote().map(|remote| remote.ip()));
+        Outcome::Success(ClientIp(client_ip))
+    }
+}
+
+/// Pseudonymizes client addresses with a keyed hash, so that uploads
+/// from the same address can be told apart from others, without
+/// storing the address itself.
+pub struct ClientIpHasher(hmac::SigningKey);
+
+impl ClientIpHasher {
+    pub fn new(secret: &str) -> Self {
+        ClientIpHasher(hmac::SigningKey::new(&digest::SHA256, secret.as_bytes()))
+    }
+
+    pub fn hash(&self, client_ip: IpAddr) -> String {
+        let message = format!("client-ip {}", client_ip);
+        let signature = hmac::sign(&self.0, message.as_bytes());
+        signature.as_ref()[..8]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Request guard for the hashed address of the client, see
+/// `ClientIpHasher`.
+pub struct ClientIpHash(pub Option<String>);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for ClientIpHash {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let client_ip = match request.guard::<ClientIp>().await {
+            Outcome::Success(ClientIp(client_ip)) => client_ip,
+            _ => None,
+        };
+        let hash = request
+            .rocket()
+            .state::<ClientIpHasher>()
+            .zip(client_ip)
+            .map(|(hasher, client_ip)| hasher.hash(client_ip));
+        Outcome::Success(ClientIpHash(hash))
+    }
+}
@@ -0,0 +1,72 @@
+//! Protects the forms of the web interface against cross-site request
+//! forgery.
+//!
+//! Pages with forms start a session, whose random token is kept in a
+//! cookie and rendered into the forms.  A form is only accepted if it
+//! carries the token of the session, which other sites can neither
+//! read nor set.
+//!
+//! Other pages don't start sessions, so that they can still be cached.
+
+use ring::constant_time::verify_slices_are_equal;
+use rocket::http::{Cookie, SameSite};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest, Request};
+use rocket_i18n::I18n;
+use uuid::Uuid;
+
+use gettext_macros::i18n;
+
+use crate::Result;
+
+const COOKIE_NAME: &str = "hagrid_csrf";
+
+/// Request guard for handlers rendering forms, or accepting them.
+///
+/// If the browser has no session yet, one is started.
+pub struct Session {
+    token: String,
+}
+
+impl Session {
+    /// Checks the token submitted with a form.
+    pub fn check(&self, token: &str, i18n: &I18n) -> Result<()> {
+        if token.is_empty()
+            || verify_slices_are_equal(self.token.as_bytes(), token.as_bytes()).is_err()
+        {
+            return Err(anyhow!(i18n!(
+                i18n.catalog,
+                "Your session has expired. Please reload the page and try again."
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Session {
+    type Error = std::convert::Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        if let Some(token) = session_token(request) {
+            return Outcome::Success(Session { token });
+        }
+        let token = Uuid::new_v4().to_simple().to_string();
+        request.cookies().add(
+            Cookie::build(COOKIE_NAME, token.clone())
+                .path("/")
+                .http_only(true)
+                .same_site(SameSite::Lax)
+                .finish(),
+        );
+        Outcome::Success(Session { token })
+    }
+}
+
+/// Returns the token of the browser's session, if it has one.
+pub fn session_token(request: &Request<'_>) -> Option<String> {
+    request
+        .cookies()
+        .get_pending(COOKIE_NAME)
+        .map(|cookie| cookie.value().to_owned())
+}
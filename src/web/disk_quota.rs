@@ -0,0 +1,176 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::{Data, Request};
+use serde_json::json;
+
+use crate::counters;
+use crate::database::platform;
+use crate::notify::Notifier;
+use crate::web::MyResponse;
+
+/// Limits on the disk space taken by the key and temporary
+/// directories, in bytes.
+///
+/// Above the soft limit, we warn.  Above the hard limit, uploads are
+/// refused, so that a flood of them can't fill the volume.  The usage
+/// is measured periodically by a scheduled job, and exported as a
+/// metric.  Operators are notified when a limit is first exceeded.
+#[derive(Clone)]
+pub struct DiskQuota {
+    dirs: Vec<PathBuf>,
+    soft_limit: Option<u64>,
+    hard_limit: Option<u64>,
+    usage: Arc<AtomicU64>,
+    /// Which limits were exceeded at the last measurement: none, the
+    /// soft one, or the hard one.
+    level: Arc<AtomicU8>,
+    notifier: Notifier,
+}
+
+impl DiskQuota {
+    pub fn new(dirs: Vec<PathBuf>, soft_limit: Option<u64>, hard_limit: Option<u64>) -> Self {
+        DiskQuota {
+            dirs,
+            soft_limit,
+            hard_limit,
+            usage: Default::default(),
+            level: Default::default(),
+            notifier: Notifier::default(),
+        }
+    }
+
+    pub fn with_notifier(mut self, notifier: Notifier) -> Self {
+        self.notifier = notifier;
+        self
+    }
+
+    /// The usage as of the last measurement.
+    pub fn usage(&self) -> u64 {
+        self.usage.load(Ordering::Relaxed)
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.hard_limit.map_or(false, |limit| self.usage() >= limit)
+    }
+
+    /// Measures the usage of all directories.
+    pub fn update(&self) -> io::Result<u64> {
+        let usage = self
+            .dirs
+            .iter()
+            .map(|dir| dir_size(dir))
+            .sum::<io::Result<u64>>()?;
+        self.usage.store(usage, Ordering::Relaxed);
+        counters::set_disk_usage(usage);
+
+        let (level, warning) = match (self.soft_limit, self.hard_limit) {
+            (_, Some(limit)) if usage >= limit => (
+                2,
+                format!(
+                    "Disk usage of {} bytes exceeds the hard limit of {} bytes, refusing uploads",
+                    usage, limit
+                ),
+            ),
+            (Some(limit), _) if usage >= limit => (
+                1,
+                format!(
+                    "Disk usage of {} bytes exceeds the soft limit of {} bytes",
+                    usage, limit
+                ),
+            ),
+            _ => (0, String::new()),
+        };
+        if level > 0 {
+            warn!("{}", warning);
+        }
+        if level > self.level.swap(level, Ordering::Relaxed) {
+            self.notifier.notify(&warning);
+        }
+        Ok(usage)
+    }
+
+    fn is_upload(method: Method, path: &str) -> bool {
+        matches!(
+            (method, path),
+            (Method::Post, "/upload/submit")
+                | (Method::Post, "/pks/add")
+                | (Method::Put, "/")
+                | (Method::Post, "/vks/v1/upload")
+        )
+    }
+}
+
+/// Returns the size of the files below the given path, without
+/// following symlinks.
+///
+/// Files with several hard links, like published keys and their
+/// blobs, are split between the links, so that they count once.
+fn dir_size(path: &Path) -> io::Result<u64> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        // Files may disappear while we walk, e.g. temporary ones.
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    if metadata.file_type().is_symlink() {
+        return Ok(metadata.len());
+    }
+    if !metadata.is_dir() {
+        let links = match platform::link_count(path) {
+            Ok(links) => links.unwrap_or(1).max(1),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        return Ok(metadata.len() / links);
+    }
+
+    let mut size = metadata.len();
+    for entry in fs::read_dir(path)? {
+        size += dir_size(&entry?.path())?;
+    }
+    Ok(size)
+}
+
+#[async_trait]
+impl Fairing for DiskQuota {
+    fn info(&self) -> Info {
+        Info {
+            name: "Disk Quota",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let path = request.uri().path().as_str().to_owned();
+        if !self.is_exhausted() || !Self::is_upload(request.method(), &path) {
+            return;
+        }
+
+        if path.starts_with("/vks/v1/") {
+            request.set_uri(uri!(insufficient_storage_json()));
+        } else {
+            request.set_uri(uri!(insufficient_storage_plain()));
+        }
+        request.set_method(Method::Get);
+    }
+}
+
+#[get("/disk-quota/plain")]
+pub fn insufficient_storage_plain() -> MyResponse {
+    MyResponse::InsufficientStoragePlain(
+        "This server is out of space for new keys, please try again later.".to_owned(),
+    )
+}
+
+#[get("/disk-quota/json")]
+pub fn insufficient_storage_json() -> MyResponse {
+    MyResponse::InsufficientStorageJson(json!({
+        "error": "This server is out of space for new keys, please try again later.",
+    }))
+}
@@ -0,0 +1,376 @@
+//! Lets domain owners list and export the keys published for
+//! addresses in their domain, e.g. to serve them via WKD.
+//!
+//! Owners prove that they control the domain either by publishing a
+//! challenge in a DNS TXT record, or by receiving a token at the
+//! domain's postmaster address.  Either way, they get a token that
+//! grants access to the domain's keys for a while.
+//!
+//! Owners who proved control via DNS can also unpublish addresses in
+//! the domain, e.g. after employees leave, and restrict publication
+//! to an allowlist of addresses.
+
+use std::str::FromStr;
+
+use ring::digest;
+use ring::hmac;
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request;
+use rocket::serde::json::Json;
+use serde_json::json;
+
+use crate::database::types::Email;
+use crate::database::{normalize_domain, Database, KeyDatabase, Query};
+use crate::mail;
+use crate::mail_dns;
+use crate::rate_limiter::RateLimiter;
+use crate::tokens::{self, StatelessSerializable};
+use crate::web::{self, bearer, MyResponse, RequestOrigin};
+
+/// The name below the domain that holds the challenge.
+const CHALLENGE_LABEL: &str = "_hagrid-challenge";
+const CHALLENGE_PREFIX: &str = "hagrid-domain-verification=";
+
+/// Receives access tokens for proofs by email, see RFC 2142.
+const POSTMASTER_LOCALPART: &str = "postmaster";
+
+pub mod json {
+    #[derive(Deserialize)]
+    pub struct DomainRequest {
+        pub domain: String,
+    }
+
+    #[derive(Deserialize)]
+    pub struct AddressesRequest {
+        pub addresses: Vec<String>,
+    }
+}
+
+/// Derives the DNS challenges for domains.
+///
+/// Challenges only depend on the domain, so that owners can leave
+/// the record in place, and get a new token whenever they need one.
+pub struct DomainChallenges(hmac::SigningKey);
+
+impl DomainChallenges {
+    pub fn new(secret: &str) -> Self {
+        DomainChallenges(hmac::SigningKey::new(&digest::SHA256, secret.as_bytes()))
+    }
+
+    fn challenge(&self, domain: &str) -> String {
+        let message = format!("domain-challenge {}", domain);
+        let signature = hmac::sign(&self.0, message.as_bytes());
+        let hex: String = signature.as_ref()[..16]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        format!("{}{}", CHALLENGE_PREFIX, hex)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DomainAccessToken {
+    pub domain_access: String,
+    /// Whether control was proven via DNS, which is required for
+    /// changes.
+    #[serde(default)]
+    pub via_dns: bool,
+}
+
+impl StatelessSerializable for DomainAccessToken {}
+
+/// Request guard for requests carrying a domain access token as
+/// bearer token.
+pub struct DomainOwner {
+    pub domain: String,
+    pub via_dns: bool,
+}
+
+#[async_trait]
+impl<'r> request::FromRequest<'r> for DomainOwner {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r request::Request<'_>,
+    ) -> request::Outcome<Self, Self::Error> {
+        let token_service = match request.rocket().state::<tokens::Service>() {
+            Some(token_service) => token_service,
+            None => return Outcome::Failure((Status::InternalServerError, ())),
+        };
+        let access = bearer::token(request)
+            .and_then(|token| token_service.check::<DomainAccessToken>(token.trim()).ok());
+
+        match access {
+            Some(access) => Outcome::Success(DomainOwner {
+                domain: access.domain_access,
+                via_dns: access.via_dns,
+            }),
+            None => Outcome::Failure((Status::Unauthorized, ())),
+        }
+    }
+}
+
+fn access_granted(token_service: &tokens::Service, domain: String) -> MyResponse {
+    let token = token_service.create(&DomainAccessToken {
+        domain_access: domain.clone(),
+        via_dns: true,
+    });
+    MyResponse::json(json!({
+        "domain": domain,
+        "token": token,
+        "expires_in": token_service.validity(),
+    }))
+}
+
+#[post("/domain/v1/challenge", format = "json", data = "<data>")]
+pub fn challenge(
+    challenges: &rocket::State<DomainChallenges>,
+    data: Json<json::DomainRequest>,
+) -> MyResponse {
+    let domain = match normalize_domain(&data.domain) {
+        Ok(domain) => domain,
+        Err(e) => return MyResponse::bad_request_json(e.to_string()),
+    };
+
+    MyResponse::json(json!({
+        "record": format!("{}.{}", CHALLENGE_LABEL, domain),
+        "value": challenges.challenge(&domain),
+        "domain": domain,
+    }))
+}
+
+#[post("/domain/v1/verify-dns", format = "json", data = "<data>")]
+pub fn verify_dns(
+    challenges: &rocket::State<DomainChallenges>,
+    resolver: &rocket::State<mail_dns::Resolver>,
+    token_service: &rocket::State<tokens::Service>,
+    data: Json<json::DomainRequest>,
+) -> MyResponse {
+    let domain = match normalize_domain(&data.domain) {
+        Ok(domain) => domain,
+        Err(e) => return MyResponse::bad_request_json(e.to_string()),
+    };
+
+    let record = format!("{}.{}", CHALLENGE_LABEL, domain);
+    let expected = challenges.challenge(&domain);
+    match web::blocking(|| resolver.lookup_txt(&record)) {
+        Some(values) if values.contains(&expected) => access_granted(token_service, domain),
+        _ => MyResponse::forbidden_json(format!(
+            "No TXT record \"{}\" found for {}",
+            expected, record
+        )),
+    }
+}
+
+#[post("/domain/v1/verify-email", format = "json", data = "<data>")]
+pub fn verify_email(
+    origin: RequestOrigin,
+    mail_service: &rocket::State<mail::Service>,
+    rate_limiter: &rocket::State<RateLimiter>,
+    token_service: &rocket::State<tokens::Service>,
+    data: Json<json::DomainRequest>,
+) -> MyResponse {
+    let recipient = match normalize_domain(&data.domain)
+        .and_then(|domain| Email::from_str(&format!("{}@{}", POSTMASTER_LOCALPART, domain)))
+    {
+        Ok(recipient) => recipient,
+        Err(e) => return MyResponse::bad_request_json(e.to_string()),
+    };
+    let domain = recipient.as_str()[POSTMASTER_LOCALPART.len() + 1..].to_owned();
+
+    if !rate_limiter.action_perform(format!("domain-{}", domain)) {
+        return MyResponse::bad_request_json(
+            "A request has already been sent for this domain recently.",
+        );
+    }
+
+    let token = token_service.create(&DomainAccessToken {
+        domain_access: domain.clone(),
+        via_dns: false,
+    });
+    if let Err(e) = web::blocking(|| {
+        mail_service.send_domain_access(
+            origin.get_base_uri(),
+            &domain,
+            &recipient,
+            &token,
+            token_service.validity(),
+        )
+    }) {
+        return MyResponse::ise(e);
+    }
+
+    MyResponse::json(json!({
+        "domain": domain,
+        "sent_to": recipient.to_string(),
+    }))
+}
+
+#[get("/domain/v1/keys")]
+pub fn keys(owner: DomainOwner, db: &rocket::State<KeyDatabase>) -> MyResponse {
+    let published = match db.published_by_domain(&owner.domain) {
+        Ok(published) => published,
+        Err(e) => return MyResponse::ise(e),
+    };
+
+    let addresses: Vec<_> = published
+        .iter()
+        .map(|(email, fpr)| {
+            json!({
+                "address": email.to_string(),
+                "key_fpr": fpr.to_string(),
+            })
+        })
+        .collect();
+    MyResponse::json(json!({
+        "domain": owner.domain,
+        "addresses": addresses,
+    }))
+}
+
+#[get("/domain/v1/export")]
+pub fn export(owner: DomainOwner, db: &rocket::State<KeyDatabase>) -> MyResponse {
+    let published = match db.published_by_domain(&owner.domain) {
+        Ok(published) => published,
+        Err(e) => return MyResponse::ise(e),
+    };
+
+    let mut fprs: Vec<_> = published.into_iter().map(|(_, fpr)| fpr).collect();
+    fprs.sort_by_key(|fpr| fpr.to_string());
+    fprs.dedup();
+
+    let armored: String = fprs.iter().flat_map(|fpr| db.by_fpr(fpr)).collect();
+    MyResponse::keyring(armored, &owner.domain)
+}
+
+/// Parses addresses, which must be in the owner's domain.
+fn parse_addresses(owner: &DomainOwner, addresses: &[String]) -> Result<Vec<Email>, MyResponse> {
+    let subdomain_suffix = format!(".{}", owner.domain);
+    addresses
+        .iter()
+        .map(|address| {
+            let email = address
+                .parse::<Email>()
+                .map_err(|e| MyResponse::bad_request_json(e.to_string()))?;
+            let domain = email.as_str().rsplit('@').next().unwrap_or_default();
+            if domain != owner.domain && !domain.ends_with(&subdomain_suffix) {
+                return Err(MyResponse::bad_request_json(format!(
+                    "{} is not in {}",
+                    email, owner.domain
+                )));
+            }
+            Ok(email)
+        })
+        .collect()
+}
+
+fn require_dns_proof(owner: &DomainOwner) -> Result<(), MyResponse> {
+    if owner.via_dns {
+        Ok(())
+    } else {
+        Err(MyResponse::forbidden_json(
+            "Changes require proving control of the domain via DNS",
+        ))
+    }
+}
+
+#[post("/domain/v1/unpublish", format = "json", data = "<data>")]
+pub fn unpublish(
+    owner: DomainOwner,
+    db: &rocket::State<KeyDatabase>,
+    data: Json<json::AddressesRequest>,
+) -> MyResponse {
+    let emails =
+        match require_dns_proof(&owner).and_then(|_| parse_addresses(&owner, &data.addresses)) {
+            Ok(emails) => emails,
+            Err(response) => return response,
+        };
+
+    let mut unpublished = Vec::new();
+    let mut held = Vec::new();
+    for email in emails {
+        let fpr = match db.lookup_primary_fingerprint(&Query::ByEmail(email.clone())) {
+            Some(fpr) => fpr,
+            None => continue,
+        };
+        if db.legal_hold(&fpr).is_some() {
+            held.push(email.to_string());
+            continue;
+        }
+        if let Err(e) = db.set_email_unpublished(&fpr, &email) {
+            return MyResponse::ise(e);
+        }
+        unpublished.push(email.to_string());
+    }
+
+    MyResponse::json(json!({
+        "domain": owner.domain,
+        "unpublished": unpublished,
+        "held": held,
+    }))
+}
+
+#[get("/domain/v1/allowlist")]
+pub fn allowlist(owner: DomainOwner, db: &rocket::State<KeyDatabase>) -> MyResponse {
+    let allowlist = db.domain_allowlist(&owner.domain).map(|allowlist| {
+        allowlist
+            .iter()
+            .map(|email| email.to_string())
+            .collect::<Vec<_>>()
+    });
+    MyResponse::json(json!({
+        "domain": owner.domain,
+        "allowlist": allowlist,
+    }))
+}
+
+#[put("/domain/v1/allowlist", format = "json", data = "<data>")]
+pub fn allowlist_set(
+    owner: DomainOwner,
+    db: &rocket::State<KeyDatabase>,
+    data: Json<json::AddressesRequest>,
+) -> MyResponse {
+    let emails =
+        match require_dns_proof(&owner).and_then(|_| parse_addresses(&owner, &data.addresses)) {
+            Ok(emails) => emails,
+            Err(response) => return response,
+        };
+    set_allowlist(owner, db, Some(emails))
+}
+
+#[delete("/domain/v1/allowlist")]
+pub fn allowlist_delete(owner: DomainOwner, db: &rocket::State<KeyDatabase>) -> MyResponse {
+    if let Err(response) = require_dns_proof(&owner) {
+        return response;
+    }
+    set_allowlist(owner, db, None)
+}
+
+fn set_allowlist(
+    owner: DomainOwner,
+    db: &KeyDatabase,
+    allowlist: Option<Vec<Email>>,
+) -> MyResponse {
+    let allowlist_json = allowlist.as_ref().map(|allowlist| {
+        allowlist
+            .iter()
+            .map(|email| email.to_string())
+            .collect::<Vec<_>>()
+    });
+    let to_strings = |emails: Vec<Email>| {
+        emails
+            .iter()
+            .map(|email| email.to_string())
+            .collect::<Vec<_>>()
+    };
+    match db.set_domain_allowlist(&owner.domain, allowlist) {
+        Ok(result) => MyResponse::json(json!({
+            "domain": owner.domain,
+            "allowlist": allowlist_json,
+            "unpublished": to_strings(result.unpublished),
+            "held": to_strings(result.held),
+        })),
+        Err(e) => MyResponse::ise(e),
+    }
+}
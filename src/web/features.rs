@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use handlebars::{
+    Context, Handlebars, Helper, HelperDef, HelperResult, Output, RenderContext, RenderError,
+    Renderable,
+};
+
+use crate::Result;
+
+/// The optional parts of the service, and whether they are enabled
+/// unless configured otherwise.
+///
+/// New surfaces start out disabled here, so that operators can enable
+/// them one instance at a time.
+const FEATURES: &[(&str, bool)] = &[
+    // Web Key Directory lookups.
+    ("wkd", false),
+    // The OPENPGPKEY record API.
+    ("dane", false),
+    // The key linting API.
+    ("lint", false),
+    // Publishing certifications by certifiers the key owner chose.
+    ("certifications", false),
+];
+
+/// Which optional parts of the service are enabled.
+///
+/// Routes of disabled features are not mounted.  Handlers can check
+/// features through the managed state, templates with the `feature`
+/// block helper.
+#[derive(Clone, Debug)]
+pub struct Features(HashMap<&'static str, bool>);
+
+impl Features {
+    /// Applies the configured flags to the defaults.  Unknown features
+    /// are an error, to catch typos.
+    pub fn new(configured: &HashMap<String, bool>) -> Result<Self> {
+        let mut features: HashMap<&'static str, bool> = FEATURES.iter().copied().collect();
+        for (name, enabled) in configured {
+            match features.get_mut(name.as_str()) {
+                Some(feature) => *feature = *enabled,
+                None => return Err(anyhow!("Unknown feature: {}", name)),
+            }
+        }
+        Ok(Features(features))
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.0.get(name).copied().unwrap_or(false)
+    }
+}
+
+/// Renders its block if the named feature is enabled, and the `else`
+/// block otherwise, e.g. `{{#feature "wkd"}}...{{/feature}}`.
+pub struct FeatureHelper(pub Features);
+
+impl HelperDef for FeatureHelper {
+    fn call<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        r: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        rc: &mut RenderContext<'reg, 'rc>,
+        out: &mut dyn Output,
+    ) -> HelperResult {
+        let name = h
+            .param(0)
+            .and_then(|name| name.value().as_str())
+            .ok_or_else(|| RenderError::new("{{#feature}} takes the name of a feature"))?;
+        let template = if self.0.is_enabled(name) {
+            h.template()
+        } else {
+            h.inverse()
+        };
+        match template {
+            Some(template) => template.render(r, ctx, rc, out),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configured_features() {
+        let mut configured = HashMap::new();
+        let features = Features::new(&configured).unwrap();
+        assert!(FEATURES.iter().all(|(name, _)| !features.is_enabled(name)));
+        assert!(!features.is_enabled("teleportation"));
+
+        configured.insert("wkd".to_owned(), true);
+        let features = Features::new(&configured).unwrap();
+        assert!(features.is_enabled("wkd"));
+        assert!(!features.is_enabled("lint"));
+
+        configured.insert("teleportation".to_owned(), true);
+        assert!(Features::new(&configured).is_err());
+    }
+}
@@ -7,18 +7,22 @@ use std::time::SystemTime;
 use rocket::http::ContentType;
 use rocket::Data;
 use rocket_i18n::I18n;
-use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
 
 use crate::database::types::{Email, Fingerprint, KeyID};
-use crate::database::{Database, KeyDatabase, Query};
+use crate::database::{Database, KeyDatabase, Query, Reloadable};
 
 use crate::i18n_helpers::describe_query_error;
 use crate::rate_limiter::RateLimiter;
 
 use crate::tokens;
+use crate::upload_cache::UploadCache;
 
 use crate::mail;
 use crate::web;
+use crate::web::client_ip::ClientIpHash;
+use crate::web::limits::BodyLimits;
+use crate::web::slow_requests::RequestTimings;
+use crate::web::timeouts::Deadline;
 use crate::web::vks::response::EmailStatus;
 use crate::web::vks::response::UploadResponse;
 use crate::web::{vks_web, MyResponse, RequestOrigin};
@@ -70,19 +74,33 @@ impl std::str::FromStr for Hkp {
 
 #[post("/pks/add", format = "multipart/form-data", data = "<data>")]
 pub async fn pks_add_form_data(
+    origin: RequestOrigin,
     db: &rocket::State<KeyDatabase>,
     tokens_stateless: &rocket::State<tokens::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
+    upload_cache: &rocket::State<UploadCache>,
+    uploader: ClientIpHash,
+    body_limits: &rocket::State<Reloadable<BodyLimits>>,
+    deadline: Deadline,
+    mail_service: &rocket::State<mail::Service>,
     i18n: I18n,
     cont_type: &ContentType,
     data: Data<'_>,
 ) -> MyResponse {
-    match vks_web::process_post_form_data(db, tokens_stateless, rate_limiter, i18n, cont_type, data)
-        .await
-    {
-        Ok(_) => MyResponse::plain("Ok".into()),
-        Err(err) => MyResponse::ise(err),
-    }
+    let result = vks_web::process_post_form_data(
+        db,
+        tokens_stateless,
+        rate_limiter,
+        upload_cache,
+        uploader.0.as_deref(),
+        &body_limits.get(),
+        deadline,
+        &i18n,
+        cont_type,
+        data,
+    )
+    .await;
+    pks_add_response(result, origin, mail_service, rate_limiter, i18n)
 }
 
 #[post(
@@ -95,11 +113,39 @@ pub async fn pks_add_form(
     db: &rocket::State<KeyDatabase>,
     tokens_stateless: &rocket::State<tokens::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
+    upload_cache: &rocket::State<UploadCache>,
+    uploader: ClientIpHash,
+    body_limits: &rocket::State<Reloadable<BodyLimits>>,
+    deadline: Deadline,
     mail_service: &rocket::State<mail::Service>,
     i18n: I18n,
     data: Data<'_>,
 ) -> MyResponse {
-    match vks_web::process_post_form(db, tokens_stateless, rate_limiter, &i18n, data).await {
+    let result = vks_web::process_post_form(
+        db,
+        tokens_stateless,
+        rate_limiter,
+        upload_cache,
+        uploader.0.as_deref(),
+        &body_limits.get(),
+        deadline,
+        &i18n,
+        data,
+    )
+    .await;
+    pks_add_response(result, origin, mail_service, rate_limiter, i18n)
+}
+
+/// Turns the result of an upload into the plain text response HKP
+/// clients expect.
+fn pks_add_response(
+    result: anyhow::Result<UploadResponse>,
+    origin: RequestOrigin,
+    mail_service: &mail::Service,
+    rate_limiter: &RateLimiter,
+    i18n: I18n,
+) -> MyResponse {
+    match result {
         Ok(UploadResponse::Ok {
             is_new_key,
             key_fpr,
@@ -120,11 +166,14 @@ pub async fn pks_add_form(
             );
             MyResponse::plain(msg)
         }
-        Ok(_) => {
+        Ok(UploadResponse::OkMulti { .. }) => {
             let msg = format!("Upload successful. Please note that identity information will only be published after verification. See {baseuri}/about/usage#gnupg-upload", baseuri = origin.get_base_uri());
             MyResponse::plain(msg)
         }
-        Err(err) => MyResponse::ise(err),
+        Ok(UploadResponse::Error(code, error)) => {
+            MyResponse::upload_rejected("400-plain", code, error, i18n, origin)
+        }
+        Err(err) => MyResponse::bad_request("400-plain", err, i18n, origin),
     }
 }
 
@@ -167,22 +216,32 @@ fn send_welcome_mail(
     primary_uid: &Email,
     token: String,
 ) -> bool {
-    mail_service
-        .send_welcome(origin.get_base_uri(), fpr, primary_uid, &token)
+    web::blocking(|| mail_service.send_welcome(origin.get_base_uri(), fpr, primary_uid, &token))
         .is_ok()
 }
 
 #[get("/pks/lookup?<op>&<search>")]
 pub fn pks_lookup(
     db: &rocket::State<KeyDatabase>,
+    timings: &RequestTimings,
     i18n: I18n,
     op: Option<String>,
     search: Option<String>,
 ) -> MyResponse {
+    if let Some(op) = op.as_deref().filter(|op| is_unsupported_op(op)) {
+        return MyResponse::not_implemented_json(
+            "unsupported-op",
+            format!("The {} operation is not supported by this server.", op),
+        );
+    }
+
     let search = search.unwrap_or_default();
     let key = match Hkp::from_str(&search) {
         Ok(key) => key,
-        Err(_) => return MyResponse::bad_request_plain("Invalid search query!"),
+        Err(_) => match db.domain_role_email(&search) {
+            Some(email) => Hkp::Email { email },
+            None => return MyResponse::bad_request_plain("Invalid search query!"),
+        },
     };
     let query = match key {
         Hkp::Fingerprint { fpr } => Query::ByFingerprint(fpr),
@@ -197,12 +256,8 @@ pub fn pks_lookup(
 
     if let Some(op) = op {
         match op.as_str() {
-            "index" => key_to_hkp_index(db, i18n, query),
-            "get" => web::key_to_response_plain(db, i18n, query),
-            "vindex" => MyResponse::not_implemented_plain("vindex not implemented"),
-            s if s.starts_with("x-") => {
-                MyResponse::not_implemented_plain("x-* operations not implemented")
-            }
+            "index" => key_to_hkp_index(db, timings, i18n, query),
+            "get" => web::key_to_response_plain(db, timings, i18n, query),
             &_ => MyResponse::bad_request_plain("Invalid op parameter!"),
         }
     } else {
@@ -210,23 +265,36 @@ pub fn pks_lookup(
     }
 }
 
+/// Whether `op` is an HKP operation we know about, but don't
+/// implement.
+fn is_unsupported_op(op: &str) -> bool {
+    op == "vindex" || op == "stats" || op.starts_with("x-")
+}
+
 #[get("/pks/internal/index/<query_string>")]
 pub fn pks_internal_index(
     db: &rocket::State<KeyDatabase>,
+    timings: &RequestTimings,
     i18n: I18n,
     query_string: String,
 ) -> MyResponse {
     match query_string.parse() {
-        Ok(query) => key_to_hkp_index(db, i18n, query),
+        Ok(query) => key_to_hkp_index(db, timings, i18n, query),
         Err(_) => MyResponse::bad_request_plain("Invalid search query!"),
     }
 }
 
-fn key_to_hkp_index(db: &rocket::State<KeyDatabase>, i18n: I18n, query: Query) -> MyResponse {
+fn key_to_hkp_index(
+    db: &rocket::State<KeyDatabase>,
+    timings: &RequestTimings,
+    i18n: I18n,
+    query: Query,
+) -> MyResponse {
     use sequoia_openpgp::policy::StandardPolicy;
     use sequoia_openpgp::types::RevocationStatus;
 
-    let tpk = match db.lookup(&query) {
+    timings.set_query(&query);
+    let tpk = match timings.db("lookup", || db.lookup(&query)) {
         Ok(Some(tpk)) => tpk,
         Ok(None) => return MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
         Err(err) => {
@@ -266,7 +334,7 @@ fn key_to_hkp_index(db: &rocket::State<KeyDatabase>, i18n: I18n, query: Query) -
 
     for uid in tpk.userids() {
         let uidstr = uid.userid().to_string();
-        let u = utf8_percent_encode(&uidstr, DEFAULT_ENCODE_SET).to_string();
+        let u = escape_index_field(&uidstr);
         let ctime = uid
             .binding_signature(policy, None)
             .ok()
@@ -286,6 +354,24 @@ fn key_to_hkp_index(db: &rocket::State<KeyDatabase>, i18n: I18n, query: Query) -
     MyResponse::plain(out)
 }
 
+/// Escapes a field of the machine readable index.
+///
+/// Besides what `DEFAULT_ENCODE_SET` covers, this escapes the field
+/// separator and the escape character itself, so that user ids can't
+/// forge fields or records.
+fn escape_index_field(field: &str) -> String {
+    field
+        .bytes()
+        .map(|b| match b {
+            b' ' | b'"' | b'#' | b'%' | b':' | b'<' | b'>' | b'?' | b'`' | b'{' | b'}' => {
+                format!("%{:02X}", b)
+            }
+            0x21..=0x7E => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use rocket::http::ContentType;
@@ -296,6 +382,19 @@ mod tests {
     use crate::mail::pop_mail;
     use crate::web::tests::*;
 
+    #[test]
+    fn index_field_escaping() {
+        assert_eq!(
+            super::escape_index_field("Foo <foo@example.org>"),
+            "Foo%20%3Cfoo@example.org%3E"
+        );
+        assert_eq!(
+            super::escape_index_field("a:b%3A\r\nuid:x"),
+            "a%3Ab%253A%0D%0Auid%3Ax"
+        );
+        assert_eq!(super::escape_index_field("jürgen"), "j%C3%BCrgen");
+    }
+
     #[test]
     fn hkp() {
         let (tmpdir, client) = client().unwrap();
@@ -436,4 +535,65 @@ mod tests {
 
         assert_consistency(client.rocket());
     }
+
+    #[test]
+    fn hkp_add_rejected() {
+        let (_tmpdir, client) = client().unwrap();
+
+        // Secret keys are rejected with a reason.
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut armored = Vec::new();
+        {
+            use sequoia_openpgp::armor::{Kind, Writer};
+            let mut w = Writer::new(&mut armored, Kind::SecretKey).unwrap();
+            tpk.as_tsk().serialize(&mut w).unwrap();
+            w.finalize().unwrap();
+        }
+        let mut post_data = String::from("keytext=");
+        for enc in url::form_urlencoded::byte_serialize(&armored) {
+            post_data.push_str(enc);
+        }
+
+        let response = client
+            .post("/pks/add")
+            .body(post_data.as_bytes())
+            .header(ContentType::Form)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let body = response.into_string().unwrap();
+        assert!(body.contains("(secret-key)"));
+
+        // So are requests without a key.
+        let response = client
+            .post("/pks/add")
+            .body("foo=bar")
+            .header(ContentType::Form)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+
+        assert_consistency(client.rocket());
+    }
+
+    #[test]
+    fn hkp_unsupported_op() {
+        let (_tmpdir, client) = client().unwrap();
+
+        for uri in &[
+            "/pks/lookup?op=vindex&search=foo@invalid.example.com",
+            "/pks/lookup?op=stats",
+            "/pks/lookup?op=x-foo&search=bogus",
+        ] {
+            let response = client.get(*uri).dispatch();
+            assert_eq!(response.status(), Status::NotImplemented);
+            let body: serde_json::Value =
+                serde_json::from_str(&response.into_string().unwrap()).unwrap();
+            assert_eq!(body["code"], "unsupported-op");
+        }
+
+        // Unknown operations are still a client error.
+        let response = client
+            .get("/pks/lookup?op=frobnicate&search=foo@invalid.example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
 }
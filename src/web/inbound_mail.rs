@@ -0,0 +1,368 @@
+//! Accepts keys mailed to the submission address.
+//!
+//! The MTA delivers mail for e.g. `submit@keys.example.org` into a
+//! maildir, which we poll.  Keys attached to a message, or pasted into
+//! it, go through the same pipeline as keys uploaded on the web.  If
+//! the sender's address is on a key, we send it a verification mail,
+//! as if it had been requested on the upload page.  Finally, the sender
+//! gets a reply with the status of each key.
+//!
+//! The sender is the envelope sender, which the MTA records in the
+//! Return-Path header on delivery.  From and Reply-To are whatever the
+//! sender claims, and replying to them would let anyone direct our
+//! mail at a third party.  For the same reason, the reply doesn't
+//! quote anything from the message.
+//!
+//! Verification mail is sent with the submission address as Reply-To.
+//! Replies quote the verification link, and the token in it confirms
+//! the address, for users whose mail clients mangle the link.
+//!
+//! Processed messages are moved to `cur`, so that the maildir doubles
+//! as an archive.  Messages we fail to process are flagged there.
+
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use rocket_i18n::I18n;
+
+use crate::database::types::Email;
+use crate::database::{Database, KeyDatabase, StatefulTokens};
+use crate::mail::{self, SubmissionResult};
+use crate::mail_parse::{self, Part};
+use crate::rate_limiter::RateLimiter;
+use crate::tokens;
+use crate::upload_cache::UploadCache;
+use crate::web::vks;
+use crate::web::vks::response::{EmailStatus, PublishResponse, UploadResponse};
+use crate::web::RequestOrigin;
+use crate::Result;
+
+pub const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Larger messages are not processed.
+const MAX_MESSAGE_SIZE: u64 = 4 * 1024 * 1024;
+
+const ARMOR_BEGIN: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----";
+const ARMOR_END: &str = "-----END PGP PUBLIC KEY BLOCK-----";
+
+/// Precedes the token in verification links.
+const VERIFY_PATH: &str = "/verify/";
+/// Length of the tokens in verification links, see
+/// `StatefulTokens::new_token`.
+const VERIFY_TOKEN_LEN: usize = 43;
+
+pub struct InboundMail {
+    pub maildir: PathBuf,
+    pub db: KeyDatabase,
+    pub origin: RequestOrigin,
+    pub i18n: I18n,
+    pub tokens_stateless: tokens::Service,
+    pub tokens_stateful: StatefulTokens,
+    pub mail_service: mail::Service,
+    pub rate_limiter: RateLimiter,
+    pub upload_cache: UploadCache,
+}
+
+impl InboundMail {
+    /// Processes all new messages, and returns how many there were.
+    pub fn process_maildir(&self) -> Result<usize> {
+        let new_dir = self.maildir.join("new");
+        let cur_dir = self.maildir.join("cur");
+        fs::create_dir_all(&new_dir)?;
+        fs::create_dir_all(&cur_dir)?;
+
+        let mut processed = 0;
+        for entry in fs::read_dir(&new_dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+
+            // Maildir flags: seen, or flagged for the operator to look at.
+            let flags = match self.process_file(&entry.path()) {
+                Ok(()) => "S",
+                Err(e) => {
+                    warn!("Error processing submitted mail {}: {:?}", name, e);
+                    "F"
+                }
+            };
+            fs::rename(entry.path(), cur_dir.join(format!("{}:2,{}", name, flags)))?;
+            processed += 1;
+        }
+        Ok(processed)
+    }
+
+    fn process_file(&self, path: &Path) -> Result<()> {
+        let size = fs::metadata(path)?.len();
+        if size > MAX_MESSAGE_SIZE {
+            return Err(anyhow!("Message too large ({} bytes)", size));
+        }
+        self.process(&fs::read(path)?)
+    }
+
+    /// Confirms the addresses whose verification links the message
+    /// quotes, imports its keys, and replies to the sender.
+    pub fn process(&self, raw: &[u8]) -> Result<()> {
+        let message = mail_parse::parse(raw);
+        let sender = message
+            .header("Return-Path")
+            .and_then(mail_parse::address)
+            .and_then(|address| Email::from_str(&address).ok());
+
+        // Bounces and autoresponders may quote our verification mail,
+        // but must not verify the address.  Never reply to them either,
+        // lest we loop.
+        let is_automatic = is_automatic(&message);
+        let mut results: Vec<SubmissionResult> = if is_automatic {
+            vec![]
+        } else {
+            find_verify_tokens(&message)
+                .into_iter()
+                .map(|token| self.verify(token, sender.as_ref()))
+                .collect()
+        };
+        results.extend(
+            find_keys(&message)
+                .into_iter()
+                .map(|key| self.submit(key, sender.as_ref())),
+        );
+
+        let sender = match sender {
+            Some(sender) if !is_automatic => sender,
+            _ => return Ok(()),
+        };
+        if !self
+            .rate_limiter
+            .action_perform(format!("submission-reply-{}", sender))
+        {
+            return Ok(());
+        }
+        self.mail_service
+            .send_submission_status(self.origin.get_base_uri(), &sender, results)
+    }
+
+    /// Confirms an address, as following the verification link does.
+    ///
+    /// Tokens are used up, so if the link doesn't work anymore, we
+    /// tell whether the sender's address is published already.
+    fn verify(&self, token: String, sender: Option<&Email>) -> SubmissionResult {
+        match vks::verify_confirm(&self.db, &self.i18n, &self.tokens_stateful, token) {
+            PublishResponse::Ok { fingerprint, email } => SubmissionResult {
+                key: fingerprint,
+                status: format!("{}: verified and published", email),
+            },
+            PublishResponse::Error(_) => SubmissionResult {
+                key: "-".to_owned(),
+                status: match sender {
+                    Some(sender) if self.db.email_verified(sender).is_some() => {
+                        format!("{}: already verified", sender)
+                    }
+                    _ => "verification link invalid or expired".to_owned(),
+                },
+            },
+        }
+    }
+
+    /// Runs a key through the upload pipeline, and requests
+    /// verification of the sender's address if it is on the key.
+    fn submit(&self, key: Vec<u8>, sender: Option<&Email>) -> SubmissionResult {
+        let mut response = vks::process_key(
+            &self.db,
+            &self.i18n,
+            &self.tokens_stateless,
+            &self.rate_limiter,
+            &self.upload_cache,
+            None,
+            Cursor::new(key),
+        );
+
+        if let (UploadResponse::Ok { token, status, .. }, Some(sender)) = (&response, sender) {
+            if status.get(sender.as_str()) == Some(&EmailStatus::Unpublished) {
+                response = vks::request_verify(
+                    &self.db,
+                    &self.origin,
+                    &self.tokens_stateful,
+                    &self.tokens_stateless,
+                    &self.mail_service,
+                    &self.rate_limiter,
+                    &self.i18n,
+                    token.clone(),
+                    vec![sender.to_string()],
+                );
+            }
+        }
+
+        describe(response)
+    }
+}
+
+/// Returns the keys attached to, or pasted into the message.
+fn find_keys(message: &Part) -> Vec<Vec<u8>> {
+    let mut keys = vec![];
+    for part in message.leaves() {
+        let content_type = part.content_type();
+        let filename = part
+            .header_param("Content-Disposition", "filename")
+            .or_else(|| part.header_param("Content-Type", "name"))
+            .unwrap_or_default()
+            .to_lowercase();
+        let is_key_file = [".asc", ".gpg", ".pgp", ".key"]
+            .iter()
+            .any(|extension| filename.ends_with(extension));
+
+        if content_type == "application/pgp-keys" || is_key_file {
+            keys.push(part.body().to_vec());
+        } else if content_type.starts_with("text/") {
+            let text = String::from_utf8_lossy(part.body());
+            keys.extend(armored_blocks(&text).into_iter().map(String::into_bytes));
+        }
+    }
+    keys
+}
+
+/// Returns the tokens of the verification links quoted in the message.
+fn find_verify_tokens(message: &Part) -> Vec<String> {
+    let mut texts: Vec<String> = message
+        .header("Subject")
+        .map(str::to_owned)
+        .into_iter()
+        .collect();
+    texts.extend(
+        message
+            .leaves()
+            .into_iter()
+            .filter(|part| part.content_type().starts_with("text/"))
+            .map(|part| String::from_utf8_lossy(part.body()).into_owned()),
+    );
+
+    let mut tokens = vec![];
+    for text in texts {
+        for (start, _) in text.match_indices(VERIFY_PATH) {
+            let token: String = text[start + VERIFY_PATH.len()..]
+                .chars()
+                .take_while(char::is_ascii_alphanumeric)
+                .collect();
+            if token.len() == VERIFY_TOKEN_LEN && !tokens.contains(&token) {
+                tokens.push(token);
+            }
+        }
+    }
+    tokens
+}
+
+fn armored_blocks(text: &str) -> Vec<String> {
+    let mut blocks = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find(ARMOR_BEGIN) {
+        let end = match rest[start..].find(ARMOR_END) {
+            Some(end) => start + end + ARMOR_END.len(),
+            None => break,
+        };
+        blocks.push(rest[start..end].to_owned());
+        rest = &rest[end..];
+    }
+    blocks
+}
+
+/// Whether the message was sent automatically (RFC 3834).
+fn is_automatic(message: &Part) -> bool {
+    let auto_submitted = message
+        .header("Auto-Submitted")
+        .map_or(false, |value| !value.trim().eq_ignore_ascii_case("no"));
+    let bulk = message.header("Precedence").map_or(false, |value| {
+        let value = value.trim().to_lowercase();
+        value == "bulk" || value == "junk"
+    });
+    let bounce = message.header("Return-Path").map(str::trim) == Some("<>");
+    auto_submitted || bulk || bounce
+}
+
+fn describe(response: UploadResponse) -> SubmissionResult {
+    match response {
+        UploadResponse::Ok {
+            key_fpr, status, ..
+        } => {
+            let mut addresses: Vec<_> = status
+                .iter()
+                .map(|(address, status)| {
+                    let status = match status {
+                        EmailStatus::Unpublished => "not published",
+                        EmailStatus::Pending => "verification mail sent",
+                        EmailStatus::Published => "published",
+                        EmailStatus::Revoked => "revoked",
+                    };
+                    format!("{}: {}", address, status)
+                })
+                .collect();
+            addresses.sort();
+            let status = if addresses.is_empty() {
+                "imported".to_owned()
+            } else {
+                format!("imported; {}", addresses.join(", "))
+            };
+            SubmissionResult {
+                key: key_fpr,
+                status,
+            }
+        }
+        UploadResponse::OkMulti { key_fprs } => SubmissionResult {
+            key: key_fprs.join(", "),
+            status: "imported".to_owned(),
+        },
+        UploadResponse::Error(code, message) => SubmissionResult {
+            key: "-".to_owned(),
+            status: format!("rejected ({}): {}", code.as_str(), message),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn armor() {
+        let text = format!(
+            "Here is my key:\n\n{}\n\nabc\n{}\n\nand another\n{}\ndef\n{}\n{}\n",
+            ARMOR_BEGIN, ARMOR_END, ARMOR_BEGIN, ARMOR_END, ARMOR_BEGIN
+        );
+        let blocks = armored_blocks(&text);
+        assert_eq!(blocks.len(), 2);
+        assert!(blocks[0].starts_with(ARMOR_BEGIN));
+        assert!(blocks[0].contains("abc"));
+        assert!(blocks[1].contains("def"));
+    }
+
+    #[test]
+    fn verify_tokens() {
+        let token = "a".repeat(VERIFY_TOKEN_LEN);
+        let message = format!(
+            "Subject: Re: Verify\n\
+             Content-Type: multipart/alternative; boundary=b\n\n\
+             --b\n\n\
+             > https://keys.example.org/verify/{}\n\
+             > https://keys.example.org/verify/short\n\
+             --b\n\
+             Content-Type: text/html\n\n\
+             <a href=\"https://keys.example.org/verify/{}\">\n\
+             --b--\n",
+            token, token
+        );
+        let message = mail_parse::parse(message.as_bytes());
+        assert_eq!(find_verify_tokens(&message), vec![token]);
+    }
+
+    #[test]
+    fn automatic() {
+        let message = mail_parse::parse(b"Auto-Submitted: auto-replied\n\nOut of office\n");
+        assert!(is_automatic(&message));
+        let message = mail_parse::parse(b"Auto-Submitted: no\nPrecedence: list\n\nHi\n");
+        assert!(!is_automatic(&message));
+        let message = mail_parse::parse(b"Return-Path: <>\n\nUndeliverable\n");
+        assert!(is_automatic(&message));
+    }
+}
@@ -0,0 +1,122 @@
+//! Signed keylists, as in draft-mccain-keylist.
+//!
+//! Each configured keylist covers a set of fingerprints, and the keys
+//! published for addresses in a set of domains.  The document and its
+//! detached signature are generated on request, byte for byte the
+//! same as long as the covered keys don't change.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json::json;
+
+use crate::database::types::Fingerprint;
+use crate::database::{Database, KeyDatabase, Query};
+use crate::web::transparency::TreeHeadSigner;
+use crate::web::{MyResponse, RequestOrigin};
+use crate::Result;
+
+#[derive(Deserialize)]
+pub struct KeylistConfig {
+    #[serde(default)]
+    fingerprints: Vec<String>,
+    #[serde(default)]
+    domains: Vec<String>,
+    comment: Option<String>,
+}
+
+/// The configured keylists, by name.
+pub struct Keylists(HashMap<String, KeylistConfig>);
+
+impl Keylists {
+    pub fn new(keylists: HashMap<String, KeylistConfig>) -> Result<Self> {
+        for (name, keylist) in keylists.iter() {
+            for fpr in keylist.fingerprints.iter() {
+                fpr.parse::<Fingerprint>()
+                    .map_err(|e| anyhow!("Keylist {}: {}: {}", name, fpr, e))?;
+            }
+        }
+        Ok(Keylists(keylists))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the keylist document, or None if there is no keylist
+    /// of that name.
+    fn document(&self, db: &KeyDatabase, name: &str, base_uri: &str) -> Result<Option<String>> {
+        let keylist = match self.0.get(name) {
+            Some(keylist) => keylist,
+            None => return Ok(None),
+        };
+
+        // Published keys only, sorted by fingerprint, with one of
+        // their addresses if they are covered by a domain.
+        let mut keys = BTreeMap::new();
+        for fpr in keylist.fingerprints.iter() {
+            let query = Query::ByFingerprint(fpr.parse()?);
+            if let Some(fpr) = db.lookup_primary_fingerprint(&query) {
+                keys.insert(fpr.to_string(), None);
+            }
+        }
+        for domain in keylist.domains.iter() {
+            for (email, fpr) in db.published_by_domain(domain)? {
+                let address = keys.entry(fpr.to_string()).or_insert(None);
+                if address.is_none() {
+                    *address = Some(email.to_string());
+                }
+            }
+        }
+
+        let keys: Vec<_> = keys
+            .into_iter()
+            .map(|(fpr, email)| match email {
+                Some(email) => json!({ "fingerprint": fpr, "email": email }),
+                None => json!({ "fingerprint": fpr }),
+            })
+            .collect();
+        let mut metadata = json!({
+            "signature_uri": format!("{}/vks/v1/keylist/{}/keylist.json.asc", base_uri, name),
+        });
+        if let Some(ref comment) = keylist.comment {
+            metadata["comment"] = json!(comment);
+        }
+
+        let document = json!({ "metadata": metadata, "keys": keys });
+        Ok(Some(serde_json::to_string_pretty(&document)? + "\n"))
+    }
+}
+
+#[get("/vks/v1/keylist/<name>/keylist.json")]
+pub fn keylist(
+    db: &rocket::State<KeyDatabase>,
+    keylists: &rocket::State<Keylists>,
+    origin: RequestOrigin,
+    name: String,
+) -> MyResponse {
+    match keylists.document(db, &name, origin.get_base_uri()) {
+        Ok(Some(document)) => MyResponse::json_document(document),
+        Ok(None) => MyResponse::not_found_plain("No such keylist."),
+        Err(e) => MyResponse::ise(e),
+    }
+}
+
+#[get("/vks/v1/keylist/<name>/keylist.json.asc")]
+pub fn keylist_signature(
+    db: &rocket::State<KeyDatabase>,
+    keylists: &rocket::State<Keylists>,
+    signer: &rocket::State<TreeHeadSigner>,
+    origin: RequestOrigin,
+    name: String,
+) -> MyResponse {
+    let document = match keylists.document(db, &name, origin.get_base_uri()) {
+        Ok(Some(document)) => document,
+        Ok(None) => return MyResponse::not_found_plain("No such keylist."),
+        Err(e) => return MyResponse::ise(e),
+    };
+    match signer.sign(&document) {
+        Ok(Some((_, signature))) => MyResponse::signature(signature),
+        Ok(None) => MyResponse::not_found_plain("Keylists are not signed on this server."),
+        Err(e) => MyResponse::ise(e),
+    }
+}
@@ -0,0 +1,108 @@
+use rocket::data::ByteUnit;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::{Data, Request};
+use serde_json::json;
+
+use crate::database::Reloadable;
+use crate::web::MyResponse;
+
+/// Size limits for request bodies, in bytes.
+///
+/// Requests announcing a larger body are turned away before we read
+/// any of it.  Bodies without a length are cut off at the limit by
+/// the handlers.
+#[derive(Clone, Copy, Debug)]
+pub struct BodyLimits {
+    /// Keys submitted through the upload form or HKP.
+    pub publish_form: u64,
+    /// Keys uploaded as the raw request body, or as JSON.
+    pub raw_upload: u64,
+    /// Requests of the manage form.
+    pub manage_form: u64,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        BodyLimits {
+            publish_form: ByteUnit::Mebibyte(1).as_u64(),
+            raw_upload: ByteUnit::Mebibyte(1).as_u64(),
+            manage_form: ByteUnit::Kibibyte(32).as_u64(),
+        }
+    }
+}
+
+impl BodyLimits {
+    pub fn publish_form(&self) -> ByteUnit {
+        ByteUnit::from(self.publish_form)
+    }
+
+    pub fn raw_upload(&self) -> ByteUnit {
+        ByteUnit::from(self.raw_upload)
+    }
+
+    fn limit(&self, method: Method, path: &str) -> Option<u64> {
+        match (method, path) {
+            (Method::Post, "/upload/submit") | (Method::Post, "/pks/add") => {
+                Some(self.publish_form)
+            }
+            (Method::Put, "/") | (Method::Post, "/vks/v1/upload") => Some(self.raw_upload),
+            (Method::Post, "/manage") | (Method::Post, "/manage/unpublish") => {
+                Some(self.manage_form)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Turns away requests whose body exceeds the limits, which can be
+/// changed while the server is running.
+pub struct EnforceBodyLimits(pub Reloadable<BodyLimits>);
+
+#[async_trait]
+impl Fairing for EnforceBodyLimits {
+    fn info(&self) -> Info {
+        Info {
+            name: "Body Limits",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let path = request.uri().path().as_str().to_owned();
+        let limit = match self.0.get().limit(request.method(), &path) {
+            Some(limit) => limit,
+            None => return,
+        };
+        let length = request
+            .headers()
+            .get_one("Content-Length")
+            .and_then(|length| length.parse::<u64>().ok());
+        if length.map_or(true, |length| length <= limit) {
+            return;
+        }
+
+        if path.starts_with("/vks/v1/") {
+            request.set_uri(uri!(payload_too_large_json(limit)));
+        } else {
+            request.set_uri(uri!(payload_too_large_plain(limit)));
+        }
+        request.set_method(Method::Get);
+    }
+}
+
+#[get("/limits/plain/<limit>")]
+pub fn payload_too_large_plain(limit: u64) -> MyResponse {
+    MyResponse::PayloadTooLargePlain(format!(
+        "Request body exceeds the limit of {} bytes.",
+        limit
+    ))
+}
+
+#[get("/limits/json/<limit>")]
+pub fn payload_too_large_json(limit: u64) -> MyResponse {
+    MyResponse::PayloadTooLargeJson(json!({
+        "error": "Request body too large.",
+        "limit": limit,
+    }))
+}
@@ -0,0 +1,117 @@
+//! Remembers the language a user chose, in a sealed cookie.
+//!
+//! Pages, and mail sent on the user's behalf, are localized according
+//! to the Accept-Language header.  If the request carries a valid
+//! language cookie, we replace that header before routing, so that the
+//! choice takes precedence over the browser's settings.
+
+use std::sync::Arc;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Cookie, CookieJar, Header, SameSite};
+use rocket::{Data, Request};
+
+use crate::sealed_state::SealedState;
+use crate::web::MyResponse;
+
+const COOKIE_NAME: &str = "hagrid_lang";
+/// Sealed along with the language, so that other sealed values can't
+/// be passed off as a language cookie.
+const COOKIE_PREFIX: &str = "lang:";
+
+#[derive(Clone)]
+pub struct LocalePreference {
+    sealed_state: Arc<SealedState>,
+    languages: Vec<&'static str>,
+}
+
+impl LocalePreference {
+    pub fn new(secret: &str, languages: Vec<&'static str>) -> Self {
+        LocalePreference {
+            sealed_state: Arc::new(SealedState::new(secret)),
+            languages,
+        }
+    }
+
+    pub fn languages(&self) -> &[&'static str] {
+        &self.languages
+    }
+
+    fn known_language(&self, lang: &str) -> Option<&'static str> {
+        self.languages.iter().find(|known| **known == lang).copied()
+    }
+
+    fn seal(&self, lang: &str) -> String {
+        let sealed = self
+            .sealed_state
+            .seal(&format!("{}{}", COOKIE_PREFIX, lang));
+        base64::encode_config(&sealed, base64::URL_SAFE_NO_PAD)
+    }
+
+    /// Returns the language in the cookie, if it is valid and one we
+    /// know.
+    fn unseal(&self, value: &str) -> Option<&'static str> {
+        let sealed = base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok()?;
+        let unsealed = self.sealed_state.unseal(sealed).ok()?;
+        self.known_language(unsealed.strip_prefix(COOKIE_PREFIX)?)
+    }
+}
+
+#[async_trait]
+impl Fairing for LocalePreference {
+    fn info(&self) -> Info {
+        Info {
+            name: "Locale Preference",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let lang = request
+            .cookies()
+            .get(COOKIE_NAME)
+            .and_then(|cookie| self.unseal(cookie.value()));
+        if let Some(lang) = lang {
+            request.replace_header(Header::new("Accept-Language", lang));
+        }
+    }
+}
+
+/// Sets the language, and takes the user back to the start page.
+#[get("/language/<lang>")]
+pub fn set_language(
+    locale: &rocket::State<LocalePreference>,
+    cookies: &CookieJar<'_>,
+    lang: String,
+) -> MyResponse {
+    let lang = match locale.known_language(&lang) {
+        Some(lang) => lang,
+        None => return MyResponse::not_found_plain(format!("Unknown language: {}", lang)),
+    };
+    cookies.add(
+        Cookie::build(COOKIE_NAME, locale.seal(lang))
+            .path("/")
+            .http_only(true)
+            .same_site(SameSite::Lax)
+            .permanent()
+            .finish(),
+    );
+    MyResponse::see_other("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_cookie() {
+        let locale = LocalePreference::new("secret", vec!["en", "de"]);
+        assert_eq!(locale.unseal(&locale.seal("de")), Some("de"));
+        // Unknown languages, tampered, or foreign cookies are ignored.
+        assert_eq!(locale.unseal(&locale.seal("xx")), None);
+        assert_eq!(locale.unseal("de"), None);
+        assert_eq!(locale.unseal(""), None);
+        let other = LocalePreference::new("other secret", vec!["en", "de"]);
+        assert_eq!(other.unseal(&locale.seal("de")), None);
+    }
+}
@@ -0,0 +1,65 @@
+//! Receives delivery notifications from the mail API provider, see
+//! `mail_events`.
+
+use rocket::data::{Data, ToByteUnit};
+use rocket::outcome::Outcome;
+use rocket::request;
+use serde_json::json;
+
+use crate::mail;
+use crate::mail_events::Webhook;
+use crate::mail_queue::unix_now;
+use crate::web::{self, MyResponse};
+
+/// Providers send notifications in batches, but not huge ones.
+const MAX_BODY_KIB: u64 = 1024;
+
+/// The signature Sendgrid sends in headers.
+pub struct EventSignature {
+    signature: Option<String>,
+    timestamp: Option<String>,
+}
+
+#[async_trait]
+impl<'r> request::FromRequest<'r> for EventSignature {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r request::Request<'_>,
+    ) -> request::Outcome<Self, Self::Error> {
+        let header = |name| request.headers().get_one(name).map(str::to_owned);
+        Outcome::Success(EventSignature {
+            signature: header("X-Twilio-Email-Event-Webhook-Signature"),
+            timestamp: header("X-Twilio-Email-Event-Webhook-Timestamp"),
+        })
+    }
+}
+
+#[post("/mail/v1/events", data = "<data>")]
+pub async fn events(
+    webhook: &rocket::State<Webhook>,
+    mail_service: &rocket::State<mail::Service>,
+    signature: EventSignature,
+    data: Data<'_>,
+) -> MyResponse {
+    let body = match data.open(MAX_BODY_KIB.kibibytes()).into_bytes().await {
+        Ok(body) if body.is_complete() => body.into_inner(),
+        Ok(_) => return MyResponse::bad_request_plain("Notification too large"),
+        Err(e) => return MyResponse::bad_request_plain(e.to_string()),
+    };
+
+    let events = match webhook.events(
+        &body,
+        signature.signature.as_deref(),
+        signature.timestamp.as_deref(),
+        unix_now(),
+    ) {
+        Ok(events) => events,
+        Err(e) => return MyResponse::forbidden_json(e.to_string()),
+    };
+
+    match web::blocking(|| mail_service.record_delivery_events(&events)) {
+        Ok(()) => MyResponse::json(json!({ "events": events.len() })),
+        Err(e) => MyResponse::ise(e),
+    }
+}
@@ -6,21 +6,28 @@ use rocket_i18n::I18n;
 use serde_json::json;
 
 use std::fs;
+use std::io;
 use std::path::PathBuf;
 
 use crate::web::MyResponse;
+use crate::Result;
 
+/// Turns away requests that would change anything while the
+/// maintenance file exists, with its contents as message.  Lookups
+/// are still served.
+#[derive(Clone)]
 pub struct MaintenanceMode {
     maintenance_file: PathBuf,
 }
 
 mod templates {
+    use crate::web::templates::Common;
+
     #[derive(Serialize)]
     pub struct MaintenanceMode {
+        #[serde(flatten)]
+        pub common: Common,
         pub message: String,
-        pub commit: String,
-        pub version: String,
-        pub lang: String,
     }
 }
 
@@ -40,7 +47,7 @@ impl Fairing for MaintenanceMode {
         };
 
         let path = request.uri().path().as_str();
-        if self.is_request_json(path) {
+        if self.is_request_json(path, request.method()) {
             request.set_uri(uri!(maintenance_error_json(message)));
             request.set_method(Method::Get);
         } else if self.is_request_plain(path, request.method()) {
@@ -58,19 +65,34 @@ impl MaintenanceMode {
         MaintenanceMode { maintenance_file }
     }
 
-    fn is_request_json(&self, path: &str) -> bool {
-        path.starts_with("/vks/v1/upload") || path.starts_with("/vks/v1/request-verify")
+    fn is_request_json(&self, path: &str, method: Method) -> bool {
+        let is_mutating = method != Method::Get && method != Method::Head;
+        path.starts_with("/vks/v1/upload")
+            || path.starts_with("/vks/v1/request-verify")
+            || (is_mutating && (path.starts_with("/vks/v1/") || path.starts_with("/domain/v1/")))
     }
 
     fn is_request_plain(&self, path: &str, method: Method) -> bool {
-        path.starts_with("/pks/add") || method == Method::Put
+        path.starts_with("/pks/add") || (path == "/" && method == Method::Put)
     }
 
     fn is_request_web(&self, path: &str) -> bool {
         path.starts_with("/upload") || path.starts_with("/manage") || path.starts_with("/verify")
     }
 
-    fn get_maintenance_message(&self) -> Option<String> {
+    pub fn enable(&self, message: &str) -> Result<()> {
+        fs::write(&self.maintenance_file, message)?;
+        Ok(())
+    }
+
+    pub fn disable(&self) -> Result<()> {
+        match fs::remove_file(&self.maintenance_file) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get_maintenance_message(&self) -> Option<String> {
         if !self.maintenance_file.exists() {
             return None;
         }
@@ -96,10 +118,8 @@ pub fn maintenance_error_json(message: String) -> MyResponse {
 #[get("/maintenance/web/<message>")]
 pub fn maintenance_error_web(message: String, i18n: I18n) -> MyResponse {
     let ctx = templates::MaintenanceMode {
+        common: templates::Common::new(i18n.lang),
         message,
-        version: env!("VERGEN_SEMVER").to_string(),
-        commit: env!("VERGEN_SHA_SHORT").to_string(),
-        lang: i18n.lang.to_owned(),
     };
     MyResponse::Maintenance(Template::render("maintenance", ctx))
 }
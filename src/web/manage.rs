@@ -8,10 +8,14 @@ use gettext_macros::i18n;
 use crate::counters;
 use crate::database::{types::Email, types::Fingerprint, Database, KeyDatabase};
 use crate::mail;
+use crate::mail_queue::DeliveryStatus;
 use crate::rate_limiter::RateLimiter;
 use crate::tokens::{self, StatelessSerializable};
+use crate::web::csrf;
 use crate::web::vks_web;
-use crate::web::{MyResponse, RequestOrigin};
+use crate::web::{self, MyResponse, RequestOrigin};
+
+use sequoia_openpgp::{parse::Parse, Cert};
 
 #[derive(Debug, Serialize, Deserialize)]
 struct StatelessVerifyToken {
@@ -26,6 +30,9 @@ mod templates {
         pub key_link: String,
         pub base_uri: String,
         pub uid_status: Vec<ManageKeyUidStatus>,
+        /// Addresses we sent a verification email to, which are not
+        /// published yet.
+        pub pending: Vec<ManageKeyUidStatus>,
         pub token: String,
     }
 
@@ -38,24 +45,28 @@ mod templates {
     pub struct ManageKeyUidStatus {
         pub address: String,
         pub published: bool,
+        pub verified: Option<String>,
+        pub delivery_status: Option<String>,
     }
 }
 
 pub mod forms {
     #[derive(FromForm)]
     pub struct ManageRequest {
+        pub csrf: String,
         pub search_term: String,
     }
 
     #[derive(FromForm)]
     pub struct ManageDelete {
+        pub csrf: String,
         pub token: String,
         pub address: String,
     }
 }
 
 #[get("/manage")]
-pub fn vks_manage(origin: RequestOrigin, i18n: I18n) -> MyResponse {
+pub fn vks_manage(origin: RequestOrigin, i18n: I18n, _session: csrf::Session) -> MyResponse {
     MyResponse::ok_bare("manage/manage", i18n, origin)
 }
 
@@ -66,6 +77,8 @@ pub fn vks_manage_key(
     i18n: I18n,
     token: String,
     token_service: &rocket::State<tokens::Service>,
+    mail_service: &rocket::State<mail::Service>,
+    _session: csrf::Session,
 ) -> MyResponse {
     use crate::database::types::Fingerprint;
     use std::convert::TryFrom;
@@ -80,11 +93,41 @@ pub fn vks_manage_key(
                     .collect();
                 emails.sort_unstable();
                 emails.dedup();
+                let mut pending: Vec<Email> = db
+                    .by_fpr_full(&fp)
+                    .and_then(|armored| Cert::from_bytes(armored.as_bytes()).ok())
+                    .map(|tpk_full| {
+                        tpk_full
+                            .userids()
+                            .flat_map(|u| u.userid().to_string().parse::<Email>())
+                            .filter(|email| !emails.contains(email))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                pending.sort_unstable();
+                pending.dedup();
                 let uid_status = emails
                     .into_iter()
                     .map(|email| templates::ManageKeyUidStatus {
                         address: email.to_string(),
                         published: true,
+                        verified: db.email_verified(&email).map(vks_web::format_date),
+                        delivery_status: mail_service
+                            .verification_status(&email)
+                            .map(|status| describe_delivery_status(&i18n, status)),
+                    })
+                    .collect();
+                // Addresses nobody asked to verify are left out.
+                let pending = pending
+                    .into_iter()
+                    .flat_map(|email| {
+                        let status = mail_service.verification_status(&email)?;
+                        Some(templates::ManageKeyUidStatus {
+                            address: email.to_string(),
+                            published: false,
+                            verified: None,
+                            delivery_status: Some(describe_delivery_status(&i18n, status)),
+                        })
                     })
                     .collect();
                 let key_link = uri!(vks_web::search(q = fp.to_string())).to_string();
@@ -92,6 +135,7 @@ pub fn vks_manage_key(
                     key_fpr: fp.to_string(),
                     key_link,
                     uid_status,
+                    pending,
                     token,
                     base_uri: origin.get_base_uri().to_owned(),
                 };
@@ -115,6 +159,27 @@ pub fn vks_manage_key(
     }
 }
 
+fn describe_delivery_status(i18n: &I18n, status: DeliveryStatus) -> String {
+    match status {
+        DeliveryStatus::Queued => i18n!(
+            i18n.catalog,
+            "The last verification email to this address is queued for delivery."
+        ),
+        DeliveryStatus::Sent => i18n!(
+            i18n.catalog,
+            "The last verification email to this address was sent."
+        ),
+        DeliveryStatus::Bounced => i18n!(
+            i18n.catalog,
+            "The last verification email to this address could not be delivered."
+        ),
+        DeliveryStatus::Suppressed => i18n!(
+            i18n.catalog,
+            "No more email is sent to this address, because earlier emails could not be delivered."
+        ),
+    }
+}
+
 #[post("/manage", data = "<request>")]
 pub fn vks_manage_post(
     db: &rocket::State<KeyDatabase>,
@@ -122,11 +187,16 @@ pub fn vks_manage_post(
     mail_service: &rocket::State<mail::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
     i18n: I18n,
+    session: csrf::Session,
     request: Form<forms::ManageRequest>,
     token_service: &rocket::State<tokens::Service>,
 ) -> MyResponse {
     use std::convert::TryInto;
 
+    if let Err(err) = session.check(&request.csrf, &i18n) {
+        return MyResponse::bad_request("manage/manage", err, i18n, origin);
+    }
+
     let email = match request.search_term.parse::<Email>() {
         Ok(email) => email,
         Err(_) => {
@@ -179,7 +249,9 @@ pub fn vks_manage_post(
     let link_path = uri!(vks_manage_key(token)).to_string();
 
     let base_uri = origin.get_base_uri();
-    if let Err(e) = mail_service.send_manage_token(&i18n, base_uri, fpr_text, &email, &link_path) {
+    if let Err(e) = web::blocking(|| {
+        mail_service.send_manage_token(&i18n, base_uri, fpr_text, &email, &link_path)
+    }) {
         return MyResponse::ise(e);
     }
 
@@ -195,9 +267,22 @@ pub fn vks_manage_unpublish(
     db: &rocket::State<KeyDatabase>,
     i18n: I18n,
     token_service: &rocket::State<tokens::Service>,
+    mail_service: &rocket::State<mail::Service>,
+    session: csrf::Session,
     request: Form<forms::ManageDelete>,
 ) -> MyResponse {
-    match vks_manage_unpublish_or_fail(origin, db, token_service, i18n, request) {
+    if let Err(err) = session.check(&request.csrf, &i18n) {
+        return MyResponse::bad_request("manage/manage", err, i18n, origin);
+    }
+    match vks_manage_unpublish_or_fail(
+        origin,
+        db,
+        token_service,
+        mail_service,
+        i18n,
+        session,
+        request,
+    ) {
         Ok(response) => response,
         Err(e) => MyResponse::ise(e),
     }
@@ -207,12 +292,26 @@ pub fn vks_manage_unpublish_or_fail(
     origin: RequestOrigin,
     db: &rocket::State<KeyDatabase>,
     token_service: &rocket::State<tokens::Service>,
+    mail_service: &rocket::State<mail::Service>,
     i18n: I18n,
+    session: csrf::Session,
     request: Form<forms::ManageDelete>,
 ) -> Result<MyResponse> {
     let verify_token = token_service.check::<StatelessVerifyToken>(&request.token)?;
     let email = request.address.parse::<Email>()?;
 
+    if db.legal_hold(&verify_token.fpr).is_some() {
+        return Ok(MyResponse::bad_request(
+            "manage/manage",
+            anyhow!(i18n!(
+                i18n.catalog,
+                "This key is under legal hold, so its addresses can't be removed at this time."
+            )),
+            i18n,
+            origin,
+        ));
+    }
+
     db.set_email_unpublished(&verify_token.fpr, &email)?;
     counters::inc_address_unpublished(&email);
 
@@ -222,5 +321,7 @@ pub fn vks_manage_unpublish_or_fail(
         i18n,
         request.token.to_owned(),
         token_service,
+        mail_service,
+        session,
     ))
 }
@@ -1,4 +1,5 @@
 use hyperx::header::{Charset, ContentDisposition, DispositionParam, DispositionType};
+use rocket::fairing::AdHoc;
 use rocket::figment::Figment;
 use rocket::fs::NamedFile;
 use rocket::http::{Header, Status};
@@ -14,32 +15,107 @@ use gettext_macros::{compile_i18n, include_i18n};
 
 use serde::Serialize;
 
+use chrono::{DateTime, Utc};
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use crate::counters;
+use crate::http_client::{self, HttpClient};
 use crate::i18n::I18NHelper;
 use crate::i18n_helpers::describe_query_error;
 use crate::mail;
-use crate::rate_limiter::RateLimiter;
+use crate::mail_dns;
+use crate::mail_events;
+use crate::mail_http;
+use crate::mail_queue;
+use crate::notify::{Notifier, OpsConfig};
+use crate::rate_limiter::{RateLimiter, RequestQuotas};
+use crate::retention::{self, Retention, RetentionConfig};
+use crate::scheduler::{Scheduler, Tasks};
+use crate::service_key::{Pkcs11Config, Pkcs11Key, SoftwareKey};
+use crate::socks::Socks5Proxy;
 use crate::template_helpers::TemplateOverrides;
 use crate::tokens;
+use crate::upload_cache::UploadCache;
 
 use crate::database::types::Fingerprint;
-use crate::database::{Database, KeyDatabase, Query};
+use crate::database::{Database, KeyDatabase, PublicationPolicy, Query, Reloadable};
 use crate::Result;
 
 use std::convert::TryInto;
 
+mod access_log;
+mod admin;
+mod assets;
+mod bearer;
+mod certifications;
+mod client_ip;
+mod csrf;
 mod debug_web;
+mod disk_quota;
+mod domain;
+mod features;
 mod hkp;
+mod inbound_mail;
+mod keylist;
+mod limits;
+mod locale;
+mod mail_webhook;
 mod maintenance;
 mod manage;
+mod policies;
+mod proofs;
+mod quota;
+mod read_only;
+mod request_id;
+mod research;
+mod route_metrics;
+mod slow_requests;
+mod timeouts;
+mod transparency;
+mod usage_stats;
 mod vks;
 mod vks_api;
 mod vks_web;
 mod wkd;
 
+use crate::web::access_log::AccessLog;
+use crate::web::admin::AdminToken;
+use crate::web::assets::{AssetFile, AssetHelper, AssetManifest};
+use crate::web::client_ip::{ClientIpHasher, TrustedProxies};
+use crate::web::disk_quota::DiskQuota;
+use crate::web::domain::DomainChallenges;
+use crate::web::features::{FeatureHelper, Features};
+use crate::web::inbound_mail::InboundMail;
+use crate::web::keylist::Keylists;
+use crate::web::limits::{BodyLimits, EnforceBodyLimits};
+use crate::web::locale::LocalePreference;
 use crate::web::maintenance::MaintenanceMode;
+use crate::web::policies::Policies;
+use crate::web::quota::ApiQuotas;
+use crate::web::read_only::ReadOnly;
+use crate::web::request_id::{RequestId, RequestIds};
+use crate::web::research::{DomainCounts, ResearchTokens};
+use crate::web::route_metrics::RouteMetrics;
+use crate::web::slow_requests::{RequestTimings, SlowRequests};
+use crate::web::timeouts::Timeouts;
+use crate::web::transparency::TreeHeadSigner;
+use crate::web::usage_stats::UsageStats;
+use crate::web::vks::response::RejectionCode;
+use crate::web::wkd::WkdDomains;
+
+/// Runs blocking work, like sending mail or querying DNS, from a
+/// handler.
+///
+/// The async workers are handed over to other requests meanwhile, so
+/// a slow mail server doesn't stall requests that don't need it.
+pub fn blocking<T>(f: impl FnOnce() -> T) -> T {
+    rocket::tokio::task::block_in_place(f)
+}
 
 pub struct HagridTemplate(&'static str, serde_json::Value, I18n, RequestOrigin);
 
@@ -55,7 +131,7 @@ impl<'r> Responder<'r, 'static> for HagridTemplate {
             .state()
             .expect("TemplateOverrides must be in managed state");
         let template_override = template_overrides.get_template_override(i18n.lang, tmpl);
-        let layout_context = templates::HagridLayout::new(ctx, i18n, origin);
+        let layout_context = templates::HagridLayout::new(ctx, i18n, origin).with_request(req);
 
         if let Some(template_override) = template_override {
             Template::render(template_override, layout_context)
@@ -66,30 +142,86 @@ impl<'r> Responder<'r, 'static> for HagridTemplate {
     }
 }
 
+/// The error page of an internal error, which is logged along with
+/// the id of the request.
+pub struct ServerErrorPage(anyhow::Error);
+
+impl<'r> Responder<'r, 'static> for ServerErrorPage {
+    fn respond_to(
+        self,
+        req: &'r rocket::Request,
+    ) -> std::result::Result<Response<'static>, Status> {
+        let request_id = RequestId::of(req);
+        eprintln!("Internal error in request {}: {:?}", request_id, self.0);
+        let ctx = templates::FiveHundred {
+            common: templates::Common::new("en"),
+            internal_error: self.0.to_string(),
+            request_id: request_id.to_owned(),
+        };
+        Template::render("500", ctx).respond_to(req)
+    }
+}
+
 #[derive(Responder)]
 pub enum MyResponse {
     #[response(status = 200, content_type = "html")]
     Success(HagridTemplate),
     #[response(status = 200, content_type = "plain")]
     Plain(String),
+    #[response(status = 200, content_type = "json")]
+    Json(serde_json::Value),
+    #[response(status = 200, content_type = "json")]
+    JsonDocument(String),
+    #[response(status = 200, content_type = "application/pgp-signature")]
+    Signature(String),
     #[response(status = 200, content_type = "xml")]
     Xml(HagridTemplate),
     #[response(status = 200, content_type = "application/pgp-keys")]
     Key(String, Header<'static>),
     #[response(status = 200, content_type = "application/octet-stream")]
     WkdKey(Vec<u8>, Header<'static>),
+    #[response(status = 200, content_type = "application/pgp-keys")]
+    KeyHead((), Header<'static>, Header<'static>, Header<'static>),
+    #[response(status = 200, content_type = "image/svg+xml")]
+    Svg(String, Header<'static>),
+    #[response(status = 200)]
+    Exists((), Header<'static>),
+    #[response(status = 404)]
+    NotExists((), Header<'static>),
     #[response(status = 500, content_type = "html")]
-    ServerError(Template),
+    ServerError(ServerErrorPage),
+    #[response(status = 303)]
+    SeeOther((), Header<'static>),
     #[response(status = 404, content_type = "html")]
     NotFound(HagridTemplate),
     #[response(status = 404, content_type = "html")]
     NotFoundPlain(String),
+    #[response(status = 410, content_type = "html")]
+    Gone(HagridTemplate),
+    #[response(status = 410, content_type = "plain")]
+    GonePlain(String),
+    #[response(status = 410, content_type = "json")]
+    GoneJson(serde_json::Value),
     #[response(status = 400, content_type = "html")]
     BadRequest(HagridTemplate),
     #[response(status = 400, content_type = "html")]
     BadRequestPlain(String),
-    #[response(status = 501, content_type = "html")]
-    NotImplementedPlain(String),
+    #[response(status = 400, content_type = "json")]
+    BadRequestJson(serde_json::Value),
+    #[response(status = 403, content_type = "json")]
+    ForbiddenJson(serde_json::Value),
+    #[response(status = 413, content_type = "plain")]
+    PayloadTooLargePlain(String),
+    #[response(status = 413, content_type = "json")]
+    PayloadTooLargeJson(serde_json::Value),
+    #[response(status = 429, content_type = "json")]
+    TooManyRequestsJson(serde_json::Value, Header<'static>),
+    #[response(status = 501, content_type = "json")]
+    NotImplementedJson(serde_json::Value),
+    #[response(status = 507, content_type = "plain")]
+    InsufficientStoragePlain(String),
+    #[response(status = 507, content_type = "json")]
+    InsufficientStorageJson(serde_json::Value),
     #[response(status = 503, content_type = "html")]
     Maintenance(Template),
     #[response(status = 503, content_type = "json")]
@@ -118,47 +250,68 @@ impl MyResponse {
         MyResponse::Plain(s)
     }
 
+    pub fn json(value: serde_json::Value) -> Self {
+        MyResponse::Json(value)
+    }
+
+    /// JSON that is served exactly as given, e.g. because it is
+    /// signed.
+    pub fn json_document(document: String) -> Self {
+        MyResponse::JsonDocument(document)
+    }
+
+    /// An armored detached signature.
+    pub fn signature(armored_signature: String) -> Self {
+        MyResponse::Signature(armored_signature)
+    }
+
     pub fn key(armored_key: String, fp: &Fingerprint) -> Self {
-        let content_disposition = Header::new(
-            rocket::http::hyper::header::CONTENT_DISPOSITION.as_str(),
-            ContentDisposition {
-                disposition: DispositionType::Attachment,
-                parameters: vec![DispositionParam::Filename(
-                    Charset::Us_Ascii,
-                    None,
-                    (fp.to_string() + ".asc").into_bytes(),
-                )],
-            }
-            .to_string(),
-        );
-        MyResponse::Key(armored_key, content_disposition)
+        MyResponse::Key(armored_key, attachment(fp.to_string() + ".asc"))
+    }
+
+    /// Several armored keys, named after what they have in common.
+    pub fn keyring(armored_keys: String, name: &str) -> Self {
+        MyResponse::Key(armored_keys, attachment(name.to_string() + ".asc"))
     }
 
     pub fn wkd(binary_key: Vec<u8>, wkd_hash: &str) -> Self {
-        let content_disposition = Header::new(
-            rocket::http::hyper::header::CONTENT_DISPOSITION.as_str(),
-            ContentDisposition {
-                disposition: DispositionType::Attachment,
-                parameters: vec![DispositionParam::Filename(
-                    Charset::Us_Ascii,
-                    None,
-                    (wkd_hash.to_string() + ".pgp").into_bytes(),
-                )],
-            }
-            .to_string(),
-        );
-        MyResponse::WkdKey(binary_key, content_disposition)
+        MyResponse::WkdKey(binary_key, attachment(wkd_hash.to_string() + ".pgp"))
+    }
+
+    /// Answers a HEAD request for a key, without reading it.
+    pub fn key_head(fp: &Fingerprint, modified: SystemTime) -> Self {
+        let modified: DateTime<Utc> = modified.into();
+        let etag = format!("\"{}-{}\"", fp, modified.timestamp_nanos());
+        MyResponse::KeyHead(
+            (),
+            Header::new("ETag", etag),
+            Header::new(
+                "Last-Modified",
+                modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            ),
+            Header::new("Cache-Control", "public, max-age=300"),
+        )
+    }
+
+    /// An image that depends only on the request, and can be cached
+    /// for a day.
+    pub fn svg(image: String) -> Self {
+        MyResponse::Svg(image, Header::new("Cache-Control", "public, max-age=86400"))
+    }
+
+    /// An empty response to an existence check, which caches can
+    /// hold on to for a while either way.
+    pub fn exists(exists: bool) -> Self {
+        let cache_control = Header::new("Cache-Control", "public, max-age=3600");
+        if exists {
+            MyResponse::Exists((), cache_control)
+        } else {
+            MyResponse::NotExists((), cache_control)
+        }
     }
 
     pub fn ise(e: anyhow::Error) -> Self {
-        eprintln!("Internal error: {:?}", e);
-        let ctx = templates::FiveHundred {
-            internal_error: e.to_string(),
-            version: env!("CARGO_PKG_VERSION").to_string(),
-            commit: env!("VERGEN_SHA_SHORT").to_string(),
-            lang: "en".to_string(),
-        };
-        MyResponse::ServerError(Template::render("500", ctx))
+        MyResponse::ServerError(ServerErrorPage(e))
     }
 
     pub fn bad_request(
@@ -169,6 +322,24 @@ impl MyResponse {
     ) -> Self {
         let ctx = templates::Error {
             error: format!("{}", e),
+            error_code: None,
+        };
+        let context_json = serde_json::to_value(ctx).unwrap();
+        MyResponse::BadRequest(HagridTemplate(template, context_json, i18n, origin))
+    }
+
+    /// Like `bad_request`, but for rejected uploads, which carry a
+    /// machine-readable code.
+    pub fn upload_rejected(
+        template: &'static str,
+        code: RejectionCode,
+        message: impl Into<String>,
+        i18n: I18n,
+        origin: RequestOrigin,
+    ) -> Self {
+        let ctx = templates::Error {
+            error: message.into(),
+            error_code: Some(code.as_str()),
         };
         let context_json = serde_json::to_value(ctx).unwrap();
         MyResponse::BadRequest(HagridTemplate(template, context_json, i18n, origin))
@@ -178,12 +349,55 @@ impl MyResponse {
         MyResponse::BadRequestPlain(message.into())
     }
 
+    pub fn bad_request_json(message: impl Into<String>) -> Self {
+        MyResponse::BadRequestJson(serde_json::json!({ "error": message.into() }))
+    }
+
+    pub fn forbidden_json(message: impl Into<String>) -> Self {
+        MyResponse::ForbiddenJson(serde_json::json!({ "error": message.into() }))
+    }
+
+    pub fn upload_rejected_json(code: RejectionCode, message: impl Into<String>) -> Self {
+        MyResponse::BadRequestJson(serde_json::json!({
+            "error": message.into(),
+            "code": code,
+        }))
+    }
+
+    pub fn see_other(location: &str) -> Self {
+        MyResponse::SeeOther((), Header::new("Location", location.to_owned()))
+    }
+
     pub fn not_found_plain(message: impl Into<String>) -> Self {
         MyResponse::NotFoundPlain(message.into())
     }
 
-    pub fn not_implemented_plain(message: impl Into<String>) -> Self {
-        MyResponse::NotImplementedPlain(message.into())
+    pub fn not_implemented_json(code: &str, message: impl Into<String>) -> Self {
+        MyResponse::NotImplementedJson(serde_json::json!({
+            "error": message.into(),
+            "code": code,
+        }))
+    }
+
+    /// For keys removed by an operator, with a public notice.
+    pub fn gone(notice: String, i18n: I18n, origin: RequestOrigin) -> Self {
+        let ctx = templates::Error {
+            error: removal_message(&i18n, &notice),
+            error_code: None,
+        };
+        let context_json = serde_json::to_value(ctx).unwrap();
+        MyResponse::Gone(HagridTemplate("index", context_json, i18n, origin))
+    }
+
+    pub fn gone_plain(notice: String, i18n: &I18n) -> Self {
+        MyResponse::GonePlain(removal_message(i18n, &notice))
+    }
+
+    pub fn gone_json(notice: String, i18n: &I18n) -> Self {
+        MyResponse::GoneJson(serde_json::json!({
+            "error": removal_message(i18n, &notice),
+            "notice": notice,
+        }))
     }
 
     pub fn not_found(
@@ -194,6 +408,7 @@ impl MyResponse {
     ) -> Self {
         let ctx = templates::Error {
             error: message.into().unwrap_or_else(|| "Key not found".to_owned()),
+            error_code: None,
         };
         let context_json = serde_json::to_value(ctx).unwrap();
         MyResponse::NotFound(HagridTemplate(
@@ -205,32 +420,109 @@ impl MyResponse {
     }
 }
 
+fn attachment(filename: String) -> Header<'static> {
+    Header::new(
+        rocket::http::hyper::header::CONTENT_DISPOSITION.as_str(),
+        ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(
+                Charset::Us_Ascii,
+                None,
+                filename.into_bytes(),
+            )],
+        }
+        .to_string(),
+    )
+}
+
 mod templates {
-    use super::{I18n, RequestOrigin};
+    use super::{csrf, I18n, LocalePreference, RequestOrigin};
+
+    /// Context shared by every rendered page, so that handlers only
+    /// provide what is specific to their page.
+    #[derive(Serialize)]
+    pub struct Common {
+        pub commit: &'static str,
+        pub version: &'static str,
+        pub lang: String,
+        pub htmldir: &'static str,
+        pub htmlclass: &'static str,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub base_uri: Option<String>,
+        /// The host name of `base_uri`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub domain: Option<String>,
+        /// The languages users can switch to.
+        pub languages: Vec<&'static str>,
+        /// The token that forms of the page submit along, see `csrf`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub csrf_token: Option<String>,
+    }
+
+    impl Common {
+        pub fn new(lang: &str) -> Self {
+            let is_rtl = lang == "ar";
+            Common {
+                commit: env!("VERGEN_SHA_SHORT"),
+                version: env!("CARGO_PKG_VERSION"),
+                lang: lang.to_owned(),
+                htmldir: if is_rtl { "rtl" } else { "ltr" },
+                htmlclass: if is_rtl { "rtl" } else { "" },
+                base_uri: None,
+                domain: None,
+                languages: vec![],
+                csrf_token: None,
+            }
+        }
+
+        pub fn with_origin(mut self, origin: &RequestOrigin) -> Self {
+            let base_uri = origin.get_base_uri();
+            let domain = base_uri
+                .split("://")
+                .last()
+                .unwrap_or(base_uri)
+                .trim_end_matches('/');
+            self.domain = Some(domain.to_owned());
+            self.base_uri = Some(base_uri.to_owned());
+            self
+        }
+
+        /// Adds what depends on the user's session.
+        pub fn with_request(mut self, req: &rocket::Request<'_>) -> Self {
+            if let Some(locale) = req.rocket().state::<LocalePreference>() {
+                self.languages = locale.languages().to_vec();
+            }
+            self.csrf_token = csrf::session_token(req);
+            self
+        }
+    }
 
     #[derive(Serialize)]
     pub struct FiveHundred {
+        #[serde(flatten)]
+        pub common: Common,
         pub internal_error: String,
-        pub commit: String,
-        pub version: String,
-        pub lang: String,
+        pub request_id: String,
     }
 
     #[derive(Serialize)]
     pub struct HagridLayout<T: serde::Serialize> {
+        #[serde(flatten)]
+        pub common: Common,
         pub error: Option<String>,
-        pub commit: String,
-        pub version: String,
-        pub base_uri: String,
-        pub lang: String,
-        pub htmldir: String,
-        pub htmlclass: String,
         pub page: T,
     }
 
     #[derive(Serialize)]
     pub struct Error {
         pub error: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        pub error_code: Option<&'static str>,
+    }
+
+    #[derive(Serialize)]
+    pub struct Stats {
+        pub lookups: Vec<super::usage_stats::Total>,
     }
 
     #[derive(Serialize)]
@@ -241,26 +533,17 @@ mod templates {
 
     impl<T: serde::Serialize> HagridLayout<T> {
         pub fn new(page: T, i18n: I18n, origin: RequestOrigin) -> Self {
-            let is_rtl = (i18n.lang) == "ar";
             Self {
+                common: Common::new(i18n.lang).with_origin(&origin),
                 error: None,
-                version: env!("CARGO_PKG_VERSION").to_string(),
-                commit: env!("VERGEN_SHA_SHORT").to_string(),
-                base_uri: origin.get_base_uri().to_string(),
                 page,
-                lang: i18n.lang.to_string(),
-                htmldir: if is_rtl {
-                    "rtl".to_owned()
-                } else {
-                    "ltr".to_owned()
-                },
-                htmlclass: if is_rtl {
-                    "rtl".to_owned()
-                } else {
-                    "".to_owned()
-                },
             }
         }
+
+        pub fn with_request(mut self, req: &rocket::Request<'_>) -> Self {
+            self.common = self.common.with_request(req);
+            self
+        }
     }
 }
 
@@ -271,8 +554,26 @@ pub struct HagridState {
     /// XXX
     base_uri: String,
     base_uri_onion: String,
+
+    /// Served as /robots.txt
+    robots_txt: String,
+    /// Served as /.well-known/security.txt, if set
+    security_txt: Option<String>,
 }
 
+/// Keeps crawlers away from lookups and token links, which are
+/// expensive and of no use in a search index.
+const DEFAULT_ROBOTS_TXT: &str = "\
+User-agent: *
+Disallow: /vks/
+Disallow: /pks/
+Disallow: /.well-known/openpgpkey/
+Disallow: /search
+Disallow: /upload/
+Disallow: /verify/
+Disallow: /manage/
+";
+
 #[derive(Debug)]
 pub enum RequestOrigin {
     Direct(String),
@@ -306,28 +607,68 @@ impl RequestOrigin {
 
 pub fn key_to_response_plain(
     db: &rocket::State<KeyDatabase>,
+    timings: &RequestTimings,
     i18n: I18n,
     query: Query,
 ) -> MyResponse {
+    timings.set_query(&query);
     if query.is_invalid() {
         return MyResponse::bad_request_plain(describe_query_error(&i18n, &query));
     }
 
-    let fp = if let Some(fp) = db.lookup_primary_fingerprint(&query) {
+    let fp = if let Some(fp) = timings.db("lookup_primary_fingerprint", || {
+        db.lookup_primary_fingerprint(&query)
+    }) {
         fp
+    } else if let Some(notice) = timings.db("removal_notice", || db.removal_notice(&query)) {
+        return MyResponse::gone_plain(notice, &i18n);
     } else {
         return MyResponse::not_found_plain(describe_query_error(&i18n, &query));
     };
 
-    match db.by_fpr(&fp) {
+    match timings.db("by_fpr", || db.by_fpr(&fp)) {
         Some(armored) => MyResponse::key(armored, &fp),
         None => MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
     }
 }
 
+fn removal_message(i18n: &I18n, notice: &str) -> String {
+    i18n!(
+        i18n.catalog,
+        "This key has been removed from this server: {}";
+        notice
+    )
+}
+
 #[get("/assets/<file..>")]
-async fn files(file: PathBuf, state: &rocket::State<HagridState>) -> Option<NamedFile> {
-    NamedFile::open(state.assets_dir.join(file)).await.ok()
+async fn files(
+    file: PathBuf,
+    state: &rocket::State<HagridState>,
+    assets: &rocket::State<Arc<AssetManifest>>,
+) -> Option<AssetFile> {
+    match assets.logical_name(&file) {
+        Some(name) => NamedFile::open(state.assets_dir.join(name))
+            .await
+            .ok()
+            .map(AssetFile::versioned),
+        None => NamedFile::open(state.assets_dir.join(file))
+            .await
+            .ok()
+            .map(AssetFile::Unversioned),
+    }
+}
+
+#[get("/robots.txt")]
+fn robots_txt(state: &rocket::State<HagridState>) -> MyResponse {
+    MyResponse::plain(state.robots_txt.clone())
+}
+
+#[get("/.well-known/security.txt")]
+fn security_txt(state: &rocket::State<HagridState>) -> MyResponse {
+    match &state.security_txt {
+        Some(security_txt) => MyResponse::plain(security_txt.clone()),
+        None => MyResponse::not_found_plain("No security.txt configured"),
+    }
 }
 
 #[get("/")]
@@ -371,8 +712,18 @@ fn apidoc(origin: RequestOrigin, i18n: I18n) -> MyResponse {
 }
 
 #[get("/about/stats")]
-fn stats(origin: RequestOrigin, i18n: I18n) -> MyResponse {
-    MyResponse::ok_bare("about/stats", i18n, origin)
+fn stats(usage_stats: &rocket::State<UsageStats>, origin: RequestOrigin, i18n: I18n) -> MyResponse {
+    let context = templates::Stats {
+        lookups: usage_stats.totals(mail_queue::unix_now()),
+    };
+    MyResponse::ok("about/stats", context, i18n, origin)
+}
+
+/// Lookups per day, transport, and result.
+#[get("/about/stats/lookups.json")]
+fn stats_lookups(usage_stats: &rocket::State<UsageStats>) -> MyResponse {
+    let days = usage_stats.buckets(mail_queue::unix_now());
+    MyResponse::json(serde_json::json!({ "days": days }))
 }
 
 #[get("/errors/<code>/<template>")]
@@ -396,6 +747,13 @@ fn errors(
     Ok(Custom(status_code, response_body))
 }
 
+/// Handlers that panicked, and guards that failed, end up here.  The
+/// panic message is only logged.
+#[catch(500)]
+fn internal_error() -> MyResponse {
+    MyResponse::ise(anyhow!("The request could not be handled"))
+}
+
 pub fn serve() -> Result<rocket::Rocket<rocket::Build>> {
     rocket_factory(rocket::build())
 }
@@ -411,7 +769,7 @@ pub fn get_i18n() -> Vec<(&'static str, gettext::Catalog)> {
 fn rocket_factory(
     mut rocket: rocket::Rocket<rocket::Build>,
 ) -> Result<rocket::Rocket<rocket::Build>> {
-    let routes = routes![
+    let mut routes = routes![
         // infra
         root,
         about,
@@ -423,17 +781,34 @@ fn rocket_factory(
         usage,
         files,
         stats,
+        stats_lookups,
         errors,
+        robots_txt,
+        security_txt,
+        locale::set_language,
         // VKSv1
         vks_api::vks_v1_by_email,
+        vks_api::vks_v1_exists_by_email,
+        vks_api::vks_v1_by_email_head,
         vks_api::vks_v1_by_fingerprint,
         vks_api::vks_v1_by_keyid,
+        vks_api::vks_v1_qr,
+        proofs::vks_v1_proofs,
+        transparency::tree_head,
+        transparency::inclusion_proof,
+        transparency::entries,
+        transparency::sth,
+        transparency::inclusion_proof_by_hash,
+        transparency::consistency_proof,
+        keylist::keylist,
+        keylist::keylist_signature,
         vks_api::upload_json,
         vks_api::upload_fallback,
         vks_api::request_verify_json,
         vks_api::request_verify_fallback,
         // User interaction.
         vks_web::search,
+        vks_web::key_page,
         vks_web::upload,
         vks_web::upload_post_form,
         vks_web::upload_post_form_data,
@@ -450,9 +825,6 @@ fn rocket_factory(
         hkp::pks_add_form,
         hkp::pks_add_form_data,
         hkp::pks_internal_index,
-        // WKD
-        wkd::wkd_policy,
-        wkd::wkd_query,
         // Manage
         manage::vks_manage,
         manage::vks_manage_key,
@@ -462,30 +834,198 @@ fn rocket_factory(
         maintenance::maintenance_error_web,
         maintenance::maintenance_error_json,
         maintenance::maintenance_error_plain,
+        // Body limit errors
+        limits::payload_too_large_plain,
+        limits::payload_too_large_json,
+        // Quota error
+        quota::quota_exceeded,
+        // Disk quota errors
+        disk_quota::insufficient_storage_plain,
+        disk_quota::insufficient_storage_json,
+        // Admin
+        admin::ban,
+        admin::ban_email,
+        admin::legal_holds,
+        admin::legal_hold,
+        admin::legal_hold_place,
+        admin::legal_hold_release,
+        admin::maintenance,
+        admin::maintenance_enable,
+        admin::maintenance_disable,
+        admin::read_only,
+        admin::read_only_enable,
+        admin::read_only_disable,
+        admin::reload,
+        admin::tasks,
+        admin::quarantine,
+        admin::quarantine_approve,
+        admin::quarantine_reject,
+        // Domain owners
+        domain::challenge,
+        domain::verify_dns,
+        domain::verify_email,
+        domain::keys,
+        domain::export,
+        domain::unpublish,
+        domain::allowlist,
+        domain::allowlist_set,
+        domain::allowlist_delete,
+        // Researchers
+        research::keys,
+        research::domains,
     ];
 
     let figment = rocket.figment();
+    let features = configure_features(figment)?;
+    if features.is_enabled("wkd") {
+        routes.extend(routes![
+            wkd::wkd_policy,
+            wkd::wkd_policy_direct,
+            wkd::wkd_query,
+            wkd::wkd_query_direct,
+        ]);
+    }
+    if features.is_enabled("dane") {
+        routes.extend(routes![vks_api::vks_v1_dane]);
+    }
+    if features.is_enabled("lint") {
+        routes.extend(routes![vks_api::vks_v1_lint]);
+    }
+    if features.is_enabled("certifications") {
+        routes.extend(routes![certifications::challenge, certifications::retain]);
+    }
+    let mail_webhook = configure_mail_webhook(figment)?;
+    if mail_webhook.is_some() {
+        routes.extend(routes![mail_webhook::events]);
+    }
+
     let db_service = configure_db_service(figment)?;
     let hagrid_state = configure_hagrid_state(figment)?;
     let stateful_token_service = configure_stateful_token_service(figment)?;
     let stateless_token_service = configure_stateless_token_service(figment)?;
-    let mail_service = configure_mail_service(figment)?;
+    let proxy = configure_socks5_proxy(figment);
+    let resolver = configure_resolver(figment, proxy.as_ref())?;
+    let http_client = configure_http_client(figment, proxy.as_ref())?;
+    let mail_service = configure_mail_service(figment, proxy.as_ref(), &resolver, &http_client)?;
+    let notifier = configure_notifier(figment, &mail_service, &http_client)?;
     let rate_limiter = configure_rate_limiter(figment)?;
     let maintenance_mode = configure_maintenance_mode(figment)?;
+    let read_only = configure_read_only(figment);
+    let api_quotas = configure_api_quotas(figment);
+    let api_quotas_file = configure_api_quotas_file(figment)?;
+    let usage_stats_file = configure_usage_stats_file(figment);
+    let trusted_proxies = configure_trusted_proxies(figment)?;
+    let client_ip_hasher = configure_client_ip_hasher(figment)?;
+    let disk_quota = configure_disk_quota(figment, &notifier)?;
+    let body_limits = Reloadable::new(configure_body_limits(figment));
+    let timeouts = configure_timeouts(figment);
+    let upload_cache = configure_upload_cache(figment);
+    let tmp_max_age = configure_tmp_max_age(figment);
+    let admin_token = configure_admin_token(figment);
+    let research_tokens = configure_research_tokens(figment);
+    let domain_counts = configure_domain_counts(figment);
+    let domain_challenges = configure_domain_challenges(figment)?;
+    let locale_preference = configure_locale_preference(figment)?;
+    let wkd_domains = configure_wkd_domains(figment);
+    let tree_head_signer = configure_tree_head_signer(figment)?;
+    let keylists = configure_keylists(figment)?;
+    let asset_manifest = configure_asset_manifest(figment)?;
     let localized_template_list = configure_localized_template_list(figment)?;
     println!("{:?}", localized_template_list);
+    let policies = Policies::new(
+        &body_limits,
+        &api_quotas,
+        &rate_limiter,
+        &mail_service,
+        &db_service,
+    );
 
     let prometheus = configure_prometheus(figment);
+    let access_log = configure_access_log(figment)?;
+    let slow_requests = configure_slow_requests(figment);
+
+    // Before any fairing rewrites requests.
+    if let Some(access_log) = access_log {
+        rocket = rocket.attach(access_log);
+    }
+    if prometheus.is_some() {
+        rocket = rocket.attach(RouteMetrics);
+    }
+    let usage_stats = UsageStats::default();
+    if let Some(path) = &usage_stats_file {
+        usage_stats.load(path);
+    }
+    rocket = rocket.attach(usage_stats.clone()).manage(usage_stats);
+    if let Some(slow_requests) = slow_requests {
+        rocket = rocket.attach(slow_requests);
+    }
+
+    if let Some(path) = &api_quotas_file {
+        api_quotas.load(path);
+    }
+
+    if let Some(mail_webhook) = mail_webhook {
+        rocket = rocket.manage(mail_webhook);
+    }
+
+    let disk_usage_interval = disk_quota.as_ref().map(|(_, interval)| *interval);
+    if let Some((disk_quota, _)) = disk_quota {
+        rocket = rocket.attach(disk_quota.clone()).manage(disk_quota);
+    }
 
     rocket = rocket
-        .attach(Template::custom(|engines: &mut Engines| {
-            let i18ns = get_i18n();
-            let i18n_helper = I18NHelper::new(i18ns);
-            engines
-                .handlebars
-                .register_helper("text", Box::new(i18n_helper));
+        .attach(Template::custom({
+            let asset_manifest = asset_manifest.clone();
+            let features = features.clone();
+            move |engines: &mut Engines| {
+                let i18ns = get_i18n();
+                let i18n_helper = I18NHelper::new(i18ns);
+                engines
+                    .handlebars
+                    .register_helper("text", Box::new(i18n_helper));
+                engines
+                    .handlebars
+                    .register_helper("asset", Box::new(AssetHelper(asset_manifest.clone())));
+                engines
+                    .handlebars
+                    .register_helper("feature", Box::new(FeatureHelper(features.clone())));
+            }
+        }))
+        .attach(RequestIds)
+        .attach(locale_preference.clone())
+        .attach(maintenance_mode.clone())
+        .attach(read_only.clone())
+        .attach(api_quotas.clone())
+        .attach(EnforceBodyLimits(body_limits.clone()))
+        .attach(timeouts)
+        .attach(AdHoc::on_liftoff("Policy reloading", |rocket| {
+            Box::pin(async move {
+                if let Some(policies) = rocket.state::<Policies>() {
+                    policies.clone().reload_on_hangup();
+                }
+            })
+        }))
+        .attach(AdHoc::on_liftoff("Scheduler", move |rocket| {
+            let api_quotas_file = api_quotas_file.clone();
+            let usage_stats_file = usage_stats_file.clone();
+            Box::pin(async move {
+                match configure_scheduler(
+                    rocket,
+                    tmp_max_age,
+                    disk_usage_interval,
+                    api_quotas_file,
+                    usage_stats_file,
+                ) {
+                    Ok(scheduler) => {
+                        info!("Scheduled jobs: {}", scheduler.job_names().join(", "));
+                        if let Some(tasks) = rocket.state::<Tasks>() {
+                            scheduler.spawn(tasks);
+                        }
+                    }
+                    Err(e) => warn!("Not running scheduled jobs: {:?}", e),
+                }
+            })
         }))
-        .attach(maintenance_mode)
         .manage(get_i18n())
         .manage(hagrid_state)
         .manage(stateless_token_service)
@@ -493,8 +1033,32 @@ fn rocket_factory(
         .manage(mail_service)
         .manage(db_service)
         .manage(rate_limiter)
+        .manage(api_quotas)
+        .manage(maintenance_mode)
+        .manage(read_only)
+        .manage(trusted_proxies)
+        .manage(client_ip_hasher)
+        .manage(body_limits)
+        .manage(policies)
+        .manage(timeouts)
+        .manage(upload_cache)
         .manage(localized_template_list)
-        .mount("/", routes);
+        .manage(admin_token)
+        .manage(research_tokens)
+        .manage(domain_counts)
+        .manage(domain_challenges)
+        .manage(locale_preference)
+        .manage(resolver)
+        .manage(wkd_domains)
+        .manage(tree_head_signer)
+        .manage(keylists)
+        .manage(asset_manifest)
+        .manage(features)
+        .manage(notifier)
+        .manage(Tasks::default())
+        .mount("/", routes)
+        .register("/", catchers![internal_error])
+        .register("/admin", catchers![admin::unauthorized]);
 
     if let Some(prometheus) = prometheus {
         rocket = rocket
@@ -505,12 +1069,35 @@ fn rocket_factory(
     Ok(rocket)
 }
 
+fn configure_access_log(config: &Figment) -> Result<Option<AccessLog>> {
+    let path: PathBuf = match config.extract_inner("access_log") {
+        Ok(path) => path,
+        Err(_) => return Ok(None),
+    };
+    let format: String = config
+        .extract_inner("access_log_format")
+        .unwrap_or_else(|_| "combined".to_owned());
+    let max_size = config
+        .extract_inner("access_log_max_size")
+        .unwrap_or(100 * 1024 * 1024);
+    let keep = config.extract_inner("access_log_keep").unwrap_or(5);
+    Ok(Some(AccessLog::new(path, format.parse()?, max_size, keep)?))
+}
+
+fn configure_slow_requests(config: &Figment) -> Option<SlowRequests> {
+    let threshold: u64 = config.extract_inner("slow_request_threshold").ok()?;
+    Some(SlowRequests {
+        threshold: Duration::from_millis(threshold),
+    })
+}
+
 fn configure_prometheus(config: &Figment) -> Option<PrometheusMetrics> {
     if !config.extract_inner("enable_prometheus").unwrap_or(false) {
         return None;
     }
     let prometheus = PrometheusMetrics::new();
     counters::register_counters(prometheus.registry());
+    counters::count_panics();
     Some(prometheus)
 }
 
@@ -519,10 +1106,36 @@ fn configure_db_service(config: &Figment) -> Result<KeyDatabase> {
     let keys_external_dir: PathBuf = config.extract_inner("keys_external_dir")?;
     let tmp_dir: PathBuf = config.extract_inner("tmp_dir")?;
 
-    let fs_db = KeyDatabase::new(keys_internal_dir, keys_external_dir, tmp_dir)?;
+    let fs_db = KeyDatabase::new(keys_internal_dir, keys_external_dir, tmp_dir)?
+        .with_publication_policy(configure_publication_policy(config)?);
+    let fs_db = match config.extract_inner::<String>("domain_role_localpart") {
+        Ok(localpart) => fs_db.with_domain_role_localpart(localpart),
+        Err(_) => fs_db,
+    };
     Ok(fs_db)
 }
 
+fn configure_publication_policy(config: &Figment) -> Result<PublicationPolicy> {
+    let uid_policy: String = config
+        .extract_inner("uid_policy")
+        .unwrap_or_else(|_| "verified".to_owned());
+    let unhashed_area_limit = match config.extract_inner::<usize>("unhashed_area_limit") {
+        Ok(max_size) => {
+            let action: String = config
+                .extract_inner("unhashed_area_action")
+                .unwrap_or_else(|_| "strip".to_owned());
+            Some((max_size, action.parse()?))
+        }
+        Err(_) => None,
+    };
+    Ok(PublicationPolicy {
+        uid_policy: uid_policy.parse()?,
+        max_published_uids: config.extract_inner("max_published_uids").ok(),
+        max_self_signatures: config.extract_inner("max_self_signatures").ok(),
+        unhashed_area_limit,
+    })
+}
+
 fn configure_hagrid_state(config: &Figment) -> Result<HagridState> {
     let assets_dir: PathBuf = config.extract_inner("assets_dir")?;
 
@@ -531,13 +1144,24 @@ fn configure_hagrid_state(config: &Figment) -> Result<HagridState> {
     let base_uri_onion = config
         .extract_inner::<String>("base-URI-Onion")
         .unwrap_or_else(|_| base_uri.clone());
+    let robots_txt = config
+        .extract_inner("robots_txt")
+        .unwrap_or_else(|_| DEFAULT_ROBOTS_TXT.to_owned());
+    let security_txt = config.extract_inner("security_txt").ok();
     Ok(HagridState {
         assets_dir,
         base_uri,
         base_uri_onion,
+        robots_txt,
+        security_txt,
     })
 }
 
+fn configure_asset_manifest(config: &Figment) -> Result<Arc<AssetManifest>> {
+    let assets_dir: PathBuf = config.extract_inner("assets_dir")?;
+    Ok(Arc::new(AssetManifest::load(&assets_dir)?))
+}
+
 fn configure_stateful_token_service(config: &Figment) -> Result<database::StatefulTokens> {
     let token_dir: PathBuf = config.extract_inner("token_dir")?;
     database::StatefulTokens::new(token_dir)
@@ -549,7 +1173,148 @@ fn configure_stateless_token_service(config: &Figment) -> Result<tokens::Service
     Ok(tokens::Service::init(&secret, validity))
 }
 
-fn configure_mail_service(config: &Figment) -> Result<mail::Service> {
+/// Queried over TCP, which Tor exit relays generally allow.
+const DEFAULT_SOCKS5_DNS_SERVER: &str = "9.9.9.9:53";
+
+/// All outbound connections go through this proxy, if configured.
+fn configure_socks5_proxy(config: &Figment) -> Option<Socks5Proxy> {
+    config
+        .extract_inner::<String>("socks5_proxy")
+        .ok()
+        .map(Socks5Proxy::new)
+}
+
+/// Behind a proxy, DNS queries go through it to a public name server,
+/// so that they don't leak to the local network.
+fn configure_resolver(config: &Figment, proxy: Option<&Socks5Proxy>) -> Result<mail_dns::Resolver> {
+    let proxy = match proxy {
+        Some(proxy) => proxy,
+        None => return Ok(mail_dns::Resolver::system()),
+    };
+    let server: SocketAddr = config
+        .extract_inner::<String>("socks5_dns_server")
+        .unwrap_or_else(|_| DEFAULT_SOCKS5_DNS_SERVER.to_owned())
+        .parse()?;
+    Ok(mail_dns::Resolver::via_proxy(
+        proxy,
+        &server.ip().to_string(),
+        server.port(),
+    )?)
+}
+
+/// The client for all outbound HTTP requests.
+fn configure_http_client(config: &Figment, proxy: Option<&Socks5Proxy>) -> Result<HttpClient> {
+    let mut client = HttpClient::new(
+        config
+            .extract_inner("http_connect_timeout")
+            .map(Duration::from_secs)
+            .unwrap_or(http_client::DEFAULT_CONNECT_TIMEOUT),
+        config
+            .extract_inner("http_timeout")
+            .map(Duration::from_secs)
+            .unwrap_or(http_client::DEFAULT_TIMEOUT),
+    );
+    if let Some(proxy) = proxy {
+        client = client.with_proxy(proxy.clone());
+    }
+    if let Ok(ca_file) = config.extract_inner::<PathBuf>("http_ca_file") {
+        client = client.with_ca_file(ca_file);
+    }
+    let pinned_keys: HashMap<String, String> =
+        config.extract_inner("http_pinned_keys").unwrap_or_default();
+    for (host, pin) in pinned_keys {
+        client = client.with_pinned_key(&host, &pin)?;
+    }
+    Ok(client)
+}
+
+fn configure_mail_service(
+    config: &Figment,
+    proxy: Option<&Socks5Proxy>,
+    resolver: &mail_dns::Resolver,
+    http_client: &HttpClient,
+) -> Result<mail::Service> {
+    let service = configure_mail_transport(config, proxy, http_client)?
+        .with_domain_policy(configure_domain_policy(config))
+        .with_assets(configure_mail_assets(config)?);
+    let service = if config.extract_inner("mail_check_domains").unwrap_or(false) {
+        service.with_mail_domain_check(resolver.clone())
+    } else {
+        service
+    };
+    let service = match config.extract_inner::<String>("submission_address") {
+        Ok(address) => service.with_reply_to(&address)?,
+        Err(_) => service,
+    };
+
+    let queue_dir: Option<PathBuf> = config.extract_inner("mail_queue_dir").ok();
+    if let Some(queue_dir) = queue_dir {
+        let defaults = mail_queue::RetryPolicy::default();
+        let policy = mail_queue::RetryPolicy {
+            initial_interval: config
+                .extract_inner("mail_retry_initial_interval")
+                .unwrap_or(defaults.initial_interval),
+            max_interval: config
+                .extract_inner("mail_retry_max_interval")
+                .unwrap_or(defaults.max_interval),
+            max_age: config
+                .extract_inner("mail_retry_max_age")
+                .unwrap_or(defaults.max_age),
+            greylist_retries: config
+                .extract_inner("mail_greylist_retries")
+                .unwrap_or(defaults.greylist_retries),
+            suppress_permanent: config
+                .extract_inner("mail_suppress_permanent")
+                .unwrap_or(defaults.suppress_permanent),
+        };
+        let throttle = mail_queue::DomainThrottle::new(
+            config
+                .extract_inner("mail_domain_rate_limits")
+                .unwrap_or_default(),
+            config.extract_inner("mail_domain_rate_limit_default").ok(),
+        );
+        let queue = mail_queue::MailQueue::new(queue_dir, policy)?.with_throttle(throttle);
+        Ok(service.with_queue(queue))
+    } else {
+        Ok(service)
+    }
+}
+
+/// Delivery notifications are only received from the configured mail
+/// API provider, and only if a key to verify them is given.
+fn configure_mail_webhook(config: &Figment) -> Result<Option<mail_events::Webhook>> {
+    let provider: mail_http::Provider = match config.extract_inner::<String>("mail_api") {
+        Ok(provider) => provider.parse()?,
+        Err(_) => return Ok(None),
+    };
+    match config.extract_inner::<String>("mail_api_webhook_key") {
+        Ok(key) => Ok(Some(mail_events::Webhook::new(provider, &key)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn configure_mail_assets(config: &Figment) -> Result<mail::MailAssets> {
+    let logo: Option<PathBuf> = config.extract_inner("email_logo").ok();
+    let style: Option<PathBuf> = config.extract_inner("email_style").ok();
+    mail::MailAssets::load(logo.as_deref(), style.as_deref())
+}
+
+fn configure_domain_policy(config: &Figment) -> mail::DomainPolicy {
+    mail::DomainPolicy {
+        allow: config
+            .extract_inner("mail_domain_allowlist")
+            .unwrap_or_default(),
+        block: config
+            .extract_inner("mail_domain_blocklist")
+            .unwrap_or_default(),
+    }
+}
+
+fn configure_mail_transport(
+    config: &Figment,
+    proxy: Option<&Socks5Proxy>,
+    http_client: &HttpClient,
+) -> Result<mail::Service> {
     // Mail service
     let email_template_dir: PathBuf = config.extract_inner("email_template_dir")?;
 
@@ -557,93 +1322,477 @@ fn configure_mail_service(config: &Figment) -> Result<mail::Service> {
     let from: String = config.extract_inner("from")?;
 
     let filemail_into: Option<PathBuf> = config.extract_inner::<PathBuf>("filemail_into").ok();
+    let mail_api: Option<String> = config.extract_inner("mail_api").ok();
 
     if let Some(path) = filemail_into {
         mail::Service::filemail(&from, &base_uri, &email_template_dir, &path)
+    } else if let Some(provider) = mail_api {
+        let provider: mail_http::Provider = provider.parse()?;
+        let api_key: String = config.extract_inner("mail_api_key")?;
+        let endpoint: Option<String> = config.extract_inner("mail_api_endpoint").ok();
+        let api = mail_http::HttpApi::new(provider, api_key, endpoint, &from, http_client.clone());
+        mail::Service::http_api(&from, &base_uri, &email_template_dir, api)
+    } else if let Some(relay) = configure_smtp_relay(config, proxy) {
+        mail::Service::smtp(&from, &base_uri, &email_template_dir, relay)
     } else {
         mail::Service::sendmail(&from, &base_uri, &email_template_dir)
     }
 }
 
+/// Mail is sent through an SMTP relay, if `smtp_host` is configured.
+fn configure_smtp_relay(config: &Figment, proxy: Option<&Socks5Proxy>) -> Option<mail::SmtpRelay> {
+    let host: String = config.extract_inner("smtp_host").ok()?;
+    let username: Option<String> = config.extract_inner("smtp_username").ok();
+    let password: Option<String> = config.extract_inner("smtp_password").ok();
+    Some(mail::SmtpRelay {
+        host,
+        port: config.extract_inner("smtp_port").ok(),
+        credentials: username.zip(password),
+        starttls: config.extract_inner("smtp_starttls").unwrap_or(true),
+        pool_size: config.extract_inner("smtp_pool_size").unwrap_or(4),
+        idle_timeout: config.extract_inner("smtp_idle_timeout").unwrap_or(60),
+        proxy: proxy.cloned(),
+    })
+}
+
 fn configure_rate_limiter(config: &Figment) -> Result<RateLimiter> {
+    let rate_limiter = RateLimiter::new(configure_mail_rate_limit(config)?);
+    match config.extract_inner::<PathBuf>("rate_limit_dir") {
+        Ok(dir) => rate_limiter.with_state_dir(dir.join("mail")),
+        Err(_) => Ok(rate_limiter),
+    }
+}
+
+fn configure_mail_rate_limit(config: &Figment) -> Result<u64> {
     let timeout_secs: i32 = config.extract_inner("mail_rate_limit").unwrap_or(60);
-    let timeout_secs = timeout_secs.try_into()?;
-    Ok(RateLimiter::new(timeout_secs))
+    Ok(timeout_secs.try_into()?)
 }
 
-fn configure_localized_template_list(config: &Figment) -> Result<TemplateOverrides> {
-    let template_dir: PathBuf = config.extract_inner("template_dir")?;
-    TemplateOverrides::load(&template_dir, "localized")
+fn configure_api_quotas(config: &Figment) -> ApiQuotas {
+    let (token_limits, anonymous_limit) = configure_api_rate_limits(config);
+    ApiQuotas::new(RequestQuotas::new(token_limits, anonymous_limit))
 }
 
-fn configure_maintenance_mode(config: &Figment) -> Result<MaintenanceMode> {
-    let maintenance_file: PathBuf = config
-        .extract_inner("maintenance_file")
-        .unwrap_or_else(|_| PathBuf::from("maintenance"));
-    Ok(MaintenanceMode::new(maintenance_file))
+/// Where the API quotas are saved across restarts, if anywhere.
+fn configure_api_quotas_file(config: &Figment) -> Result<Option<PathBuf>> {
+    match config.extract_inner::<PathBuf>("rate_limit_dir") {
+        Ok(dir) => {
+            std::fs::create_dir_all(&dir)?;
+            Ok(Some(dir.join("api-quotas.json")))
+        }
+        Err(_) => Ok(None),
+    }
 }
 
-#[cfg(test)]
-pub mod tests {
-    use regex;
-    use rocket::http::ContentType;
-    use rocket::http::Header;
-    use rocket::http::Status;
-    use rocket::local::blocking::{Client, LocalResponse};
-    use std::fs;
-    use std::fs::File;
-    use std::io::Write;
-    use std::path::Path;
-    use tempfile::{tempdir, TempDir};
+/// Where the usage stats are saved across restarts, if anywhere.
+fn configure_usage_stats_file(config: &Figment) -> Option<PathBuf> {
+    config.extract_inner("usage_stats_file").ok()
+}
 
-    use sequoia_openpgp::cert::CertBuilder;
-    use sequoia_openpgp::parse::Parse;
-    use sequoia_openpgp::serialize::Serialize;
-    use sequoia_openpgp::Cert;
+fn configure_api_rate_limits(config: &Figment) -> (HashMap<String, u32>, Option<u32>) {
+    (
+        config
+            .extract_inner("api_token_rate_limits")
+            .unwrap_or_default(),
+        config.extract_inner("api_rate_limit").ok(),
+    )
+}
 
-    use std::time::SystemTime;
+fn configure_disk_quota(
+    config: &Figment,
+    notifier: &Notifier,
+) -> Result<Option<(DiskQuota, Duration)>> {
+    let soft_limit: Option<u64> = config.extract_inner("disk_quota_soft").ok();
+    let hard_limit: Option<u64> = config.extract_inner("disk_quota_hard").ok();
+    if soft_limit.is_none() && hard_limit.is_none() {
+        return Ok(None);
+    }
 
-    use mail::pop_mail;
+    let dirs = vec![
+        config.extract_inner("keys_internal_dir")?,
+        config.extract_inner("keys_external_dir")?,
+        config.extract_inner("tmp_dir")?,
+    ];
+    let interval = config.extract_inner("disk_usage_interval").unwrap_or(60);
+    Ok(Some((
+        DiskQuota::new(dirs, soft_limit, hard_limit).with_notifier(notifier.clone()),
+        Duration::from_secs(interval),
+    )))
+}
 
-    use super::*;
+fn configure_trusted_proxies(config: &Figment) -> Result<TrustedProxies> {
+    let proxies: Vec<String> = config.extract_inner("trusted_proxies").unwrap_or_default();
+    TrustedProxies::new(&proxies)
+}
 
-    /// Fake base URI to use in tests.
-    const BASE_URI: &str = "http://local.connection";
-    const BASE_URI_ONION: &str = "http://local.connection.onion";
+fn configure_client_ip_hasher(config: &Figment) -> Result<ClientIpHasher> {
+    let secret: String = config.extract_inner("token_secret")?;
+    Ok(ClientIpHasher::new(&secret))
+}
 
-    pub fn build_cert(name: &str) -> Cert {
-        let (tpk, _) = CertBuilder::new()
-            .add_signing_subkey()
-            .add_transport_encryption_subkey()
-            .add_userid(name)
-            .generate()
-            .unwrap();
-        tpk
+fn configure_wkd_domains(config: &Figment) -> WkdDomains {
+    WkdDomains::new(config.extract_inner("wkd_domains").unwrap_or_default())
+}
+
+fn configure_upload_cache(config: &Figment) -> UploadCache {
+    UploadCache::new(config.extract_inner("upload_cache_ttl").unwrap_or(300))
+}
+
+/// How often stale temporary files are looked for.
+const TEMPFILE_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+fn configure_tmp_max_age(config: &Figment) -> Option<Duration> {
+    match config.extract_inner("tmp_max_age").unwrap_or(24 * 60 * 60) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
     }
+}
 
-    /// Creates a configuration and empty state dir for testing purposes.
-    ///
-    /// Note that you need to keep the returned TempDir alive for the
-    /// duration of your test.  To debug the test, mem::forget it to
-    /// prevent cleanup.
-    pub fn configuration() -> Result<(TempDir, rocket::figment::Figment)> {
-        let root = tempdir()?;
-        let filemail = root.path().join("filemail");
-        ::std::fs::create_dir_all(&filemail)?;
+fn configure_retention(config: &Figment) -> Result<Retention> {
+    let mut retention: RetentionConfig = match config.find_value("retention") {
+        Ok(_) => config.extract_inner("retention")?,
+        Err(_) => RetentionConfig::default(),
+    };
+    // Before there were retention rules, quarantined keys were
+    // configured on their own.
+    if retention.quarantined_max_age.is_none() {
+        retention.quarantined_max_age = config.extract_inner("quarantine_max_age").ok();
+    }
+    Ok(Retention::new(configure_db_service(config)?, retention))
+}
 
-        let base_dir: PathBuf = root.path().into();
+/// How often expired keys are looked for.
+const KEY_SWEEP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
 
-        let config = rocket::Config::figment()
-            .select("staging")
-            .merge(("root", root.path()))
-            .merge((
-                "template_dir",
-                ::std::env::current_dir()
-                    .unwrap()
-                    .join("dist/templates")
-                    .to_str()
-                    .unwrap(),
-            ))
+fn configure_max_age(config: &Figment, key: &str) -> Option<Duration> {
+    config.extract_inner(key).ok().map(Duration::from_secs)
+}
+
+/// Registers the maintenance jobs that are enabled in the
+/// configuration.
+fn configure_scheduler(
+    rocket: &rocket::Rocket<rocket::Orbit>,
+    tmp_max_age: Option<Duration>,
+    disk_usage_interval: Option<Duration>,
+    api_quotas_file: Option<PathBuf>,
+    usage_stats_file: Option<PathBuf>,
+) -> Result<Scheduler> {
+    let config = rocket.figment();
+    let mut scheduler = Scheduler::new(config.extract_inner("scheduler_jitter").unwrap_or(0.1));
+    if let Some(notifier) = rocket.state::<Notifier>() {
+        scheduler = scheduler.with_notifier(notifier.clone());
+    }
+
+    if let Some(mail_service) = rocket.state::<mail::Service>() {
+        if mail_service.has_queue() {
+            let mail_service = mail_service.clone();
+            scheduler.register("mail-queue", mail::QUEUE_POLL_INTERVAL, move || {
+                mail_service.process_queue()
+            });
+        }
+    }
+
+    if let Some(max_age) = tmp_max_age {
+        // Removes temporary files left behind by crashed processes.
+        let db = configure_db_service(config)?;
+        scheduler.register("tempfile-prune", TEMPFILE_PRUNE_INTERVAL, move || {
+            let pruned = db.prune_stale_tempfiles(max_age)?;
+            if pruned > 0 {
+                info!("Pruned {} stale temporary files", pruned);
+            }
+            Ok(())
+        });
+    }
+
+    if config.extract_inner("enable_prometheus").unwrap_or(false) {
+        let db = configure_db_service(config)?;
+        scheduler.register(
+            "published-counts",
+            counters::PUBLISHED_COUNTS_INTERVAL,
+            counters::published_counts_job(db),
+        );
+    }
+
+    if let (Some(disk_quota), Some(interval)) = (rocket.state::<DiskQuota>(), disk_usage_interval) {
+        let disk_quota = disk_quota.clone();
+        scheduler.register("disk-usage", interval, move || {
+            disk_quota.update()?;
+            Ok(())
+        });
+    }
+
+    if let (Some(api_quotas), Some(path)) = (rocket.state::<ApiQuotas>(), api_quotas_file) {
+        let api_quotas = api_quotas.clone();
+        scheduler.register("api-quota-save", quota::SAVE_INTERVAL, move || {
+            api_quotas.save(&path)
+        });
+    }
+
+    if let (Some(usage_stats), Some(path)) = (rocket.state::<UsageStats>(), usage_stats_file) {
+        let usage_stats = usage_stats.clone();
+        scheduler.register("usage-stats-save", usage_stats::SAVE_INTERVAL, move || {
+            usage_stats.save(&path)
+        });
+    }
+
+    let retention = configure_retention(config)?;
+    if retention.is_enabled() {
+        scheduler.register("retention", retention::INTERVAL, move || {
+            retention.run()?;
+            Ok(())
+        });
+    }
+
+    if let Some(grace) = configure_max_age(config, "expired_key_grace") {
+        let db = configure_db_service(config)?;
+        scheduler.register("expired-key-sweep", KEY_SWEEP_INTERVAL, move || {
+            let swept = db.sweep_expired(grace)?;
+            if swept > 0 {
+                info!("Unpublished addresses of {} expired keys", swept);
+            }
+            Ok(())
+        });
+    }
+
+    if let Some(interval) = configure_max_age(config, "consistency_check_interval") {
+        let db = configure_db_service(config)?;
+        scheduler.register("consistency-check", interval, move || {
+            db.check_consistency()
+        });
+    }
+
+    if let Some(inbound_mail) = configure_inbound_mail(rocket)? {
+        scheduler.register("inbound-mail", inbound_mail::POLL_INTERVAL, move || {
+            let processed = inbound_mail.process_maildir()?;
+            if processed > 0 {
+                info!("Processed {} submitted mails", processed);
+            }
+            Ok(())
+        });
+    }
+
+    Ok(scheduler)
+}
+
+/// Sets up processing of the key submissions in the maildir, if one is
+/// configured.
+fn configure_inbound_mail(rocket: &rocket::Rocket<rocket::Orbit>) -> Result<Option<InboundMail>> {
+    let config = rocket.figment();
+    let maildir: PathBuf = match config.extract_inner("submission_maildir") {
+        Ok(maildir) => maildir,
+        Err(_) => return Ok(None),
+    };
+    let mail_service = rocket
+        .state::<mail::Service>()
+        .ok_or_else(|| anyhow!("Mail submission needs the mail service"))?
+        .clone();
+    let base_uri: String = config.extract_inner("base-URI")?;
+    Ok(Some(InboundMail {
+        maildir,
+        db: configure_db_service(config)?,
+        origin: RequestOrigin::Direct(base_uri),
+        i18n: I18n {
+            catalog: get_i18n().remove(0).1,
+            lang: "en",
+        },
+        tokens_stateless: configure_stateless_token_service(config)?,
+        tokens_stateful: configure_stateful_token_service(config)?,
+        mail_service,
+        rate_limiter: configure_rate_limiter(config)?,
+        upload_cache: configure_upload_cache(config),
+    }))
+}
+
+/// Sets up the channels operators are notified through, configured
+/// in the `ops` section.
+fn configure_notifier(
+    config: &Figment,
+    mail_service: &mail::Service,
+    http_client: &HttpClient,
+) -> Result<Notifier> {
+    let ops: OpsConfig = match config.find_value("ops") {
+        Ok(_) => config.extract_inner("ops")?,
+        Err(_) => OpsConfig::default(),
+    };
+    let mut notifier = Notifier::new(http_client.clone());
+    if let Some(address) = ops.notify_mail {
+        let base_uri: String = config.extract_inner("base-URI")?;
+        notifier = notifier.with_mail(mail_service.clone(), &base_uri, address.parse()?);
+    }
+    if let Some(room) = ops.matrix {
+        notifier = notifier.with_matrix(room);
+    }
+    if let Some(room) = ops.xmpp {
+        notifier = notifier.with_xmpp(room);
+    }
+    Ok(notifier)
+}
+
+fn configure_body_limits(config: &Figment) -> BodyLimits {
+    let defaults = BodyLimits::default();
+    BodyLimits {
+        publish_form: config
+            .extract_inner("body_limit_publish_form")
+            .unwrap_or(defaults.publish_form),
+        raw_upload: config
+            .extract_inner("body_limit_raw_upload")
+            .unwrap_or(defaults.raw_upload),
+        manage_form: config
+            .extract_inner("body_limit_manage_form")
+            .unwrap_or(defaults.manage_form),
+    }
+}
+
+fn configure_timeouts(config: &Figment) -> Timeouts {
+    let defaults = Timeouts::default();
+    Timeouts {
+        read: config
+            .extract_inner("read_timeout")
+            .unwrap_or(defaults.read),
+        request: config
+            .extract_inner("request_timeout")
+            .unwrap_or(defaults.request),
+    }
+}
+
+fn configure_localized_template_list(config: &Figment) -> Result<TemplateOverrides> {
+    let template_dir: PathBuf = config.extract_inner("template_dir")?;
+    TemplateOverrides::load(&template_dir, "localized")
+}
+
+fn configure_admin_token(config: &Figment) -> AdminToken {
+    AdminToken::new(config.extract_inner("admin_token").ok())
+}
+
+fn configure_research_tokens(config: &Figment) -> ResearchTokens {
+    ResearchTokens::new(config.extract_inner("research_tokens").unwrap_or_default())
+}
+
+/// Domains with fewer verified addresses are left out of the counts
+/// per domain.
+const DEFAULT_DOMAIN_STATS_THRESHOLD: u64 = 10;
+
+fn configure_domain_counts(config: &Figment) -> DomainCounts {
+    DomainCounts::new(
+        config
+            .extract_inner("domain_stats_threshold")
+            .unwrap_or(DEFAULT_DOMAIN_STATS_THRESHOLD),
+    )
+}
+
+fn configure_domain_challenges(config: &Figment) -> Result<DomainChallenges> {
+    let secret: String = config.extract_inner("token_secret")?;
+    Ok(DomainChallenges::new(&secret))
+}
+
+fn configure_locale_preference(config: &Figment) -> Result<LocalePreference> {
+    let secret: String = config.extract_inner("token_secret")?;
+    let languages = get_i18n().into_iter().map(|(lang, _)| lang).collect();
+    Ok(LocalePreference::new(&secret, languages))
+}
+
+fn configure_keylists(config: &Figment) -> Result<Keylists> {
+    let keylists = Keylists::new(config.extract_inner("keylists").unwrap_or_default())?;
+    if !keylists.is_empty() && config.extract_inner::<PathBuf>("transparency_key").is_err() {
+        return Err(anyhow!(
+            "Keylists need a transparency_key to be signed with"
+        ));
+    }
+    Ok(keylists)
+}
+
+fn configure_tree_head_signer(config: &Figment) -> Result<TreeHeadSigner> {
+    let path = match config.extract_inner::<PathBuf>("transparency_key") {
+        Ok(path) => path,
+        Err(_) => return Ok(TreeHeadSigner::disabled()),
+    };
+    if config.find_value("transparency_pkcs11").is_err() {
+        return Ok(TreeHeadSigner::new(SoftwareKey::from_file(&path)?));
+    }
+    let pkcs11: Pkcs11Config = config.extract_inner("transparency_pkcs11")?;
+    Ok(TreeHeadSigner::new(Pkcs11Key::new(&path, pkcs11)?))
+}
+
+fn configure_features(config: &Figment) -> Result<Features> {
+    let configured: HashMap<String, bool> = config.extract_inner("features").unwrap_or_default();
+    Features::new(&configured)
+}
+
+fn configure_read_only(config: &Figment) -> ReadOnly {
+    ReadOnly::new(config.extract_inner("read_only").unwrap_or(false))
+}
+
+fn configure_maintenance_mode(config: &Figment) -> Result<MaintenanceMode> {
+    let maintenance_file: PathBuf = config
+        .extract_inner("maintenance_file")
+        .unwrap_or_else(|_| PathBuf::from("maintenance"));
+    Ok(MaintenanceMode::new(maintenance_file))
+}
+
+#[cfg(test)]
+pub mod tests {
+    use regex;
+    use rocket::http::Accept;
+    use rocket::http::ContentType;
+    use rocket::http::Cookie;
+    use rocket::http::Header;
+    use rocket::http::Method;
+    use rocket::http::Status;
+    use rocket::local::blocking::{Client, LocalResponse};
+    use std::fs;
+    use std::fs::File;
+    use std::io::Write;
+    use std::path::Path;
+    use tempfile::{tempdir, TempDir};
+
+    use sequoia_openpgp::cert::CertBuilder;
+    use sequoia_openpgp::parse::Parse;
+    use sequoia_openpgp::serialize::Serialize;
+    use sequoia_openpgp::Cert;
+
+    use std::time::SystemTime;
+
+    use mail::{list_mails, pop_mail};
+
+    use super::*;
+
+    /// Fake base URI to use in tests.
+    const BASE_URI: &str = "http://local.connection";
+    const BASE_URI_ONION: &str = "http://local.connection.onion";
+
+    pub fn build_cert(name: &str) -> Cert {
+        let (tpk, _) = CertBuilder::new()
+            .add_signing_subkey()
+            .add_transport_encryption_subkey()
+            .add_userid(name)
+            .generate()
+            .unwrap();
+        tpk
+    }
+
+    /// Creates a configuration and empty state dir for testing purposes.
+    ///
+    /// Note that you need to keep the returned TempDir alive for the
+    /// duration of your test.  To debug the test, mem::forget it to
+    /// prevent cleanup.
+    pub fn configuration() -> Result<(TempDir, rocket::figment::Figment)> {
+        let root = tempdir()?;
+        let filemail = root.path().join("filemail");
+        ::std::fs::create_dir_all(&filemail)?;
+
+        let base_dir: PathBuf = root.path().into();
+
+        let config = rocket::Config::figment()
+            .select("staging")
+            .merge(("root", root.path()))
+            .merge((
+                "template_dir",
+                ::std::env::current_dir()
+                    .unwrap()
+                    .join("dist/templates")
+                    .to_str()
+                    .unwrap(),
+            ))
             .merge((
                 "email_template_dir",
                 ::std::env::current_dir()
@@ -685,6 +1834,13 @@ pub mod tests {
                     .into_os_string()
                     .into_string()
                     .expect("path is valid UTF8"),
+            ))
+            .merge((
+                "features",
+                ["wkd", "dane", "lint", "certifications"]
+                    .iter()
+                    .map(|feature| (*feature, true))
+                    .collect::<HashMap<_, _>>(),
             ));
         Ok((root, config))
     }
@@ -717,6 +1873,94 @@ pub mod tests {
         assert!(response.into_string().unwrap().contains("Hagrid"));
     }
 
+    #[test]
+    fn locale_cookie() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.get("/language/de").dispatch();
+        assert_eq!(response.status(), Status::SeeOther);
+        assert_eq!(response.headers().get_one("Location"), Some("/"));
+        let set_cookie = response.headers().get_one("Set-Cookie").unwrap();
+        let cookie = Cookie::parse(set_cookie.to_owned()).unwrap();
+        assert_eq!(cookie.name(), "hagrid_lang");
+        assert_eq!(cookie.http_only(), Some(true));
+
+        // The cookie takes precedence over the browser's preference.
+        let response = client
+            .get("/")
+            .header(Header::new("Accept-Language", "en"))
+            .cookie(cookie)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.into_string().unwrap().contains("lang=\"de\""));
+
+        // Forged cookies are ignored.
+        let response = client
+            .get("/")
+            .header(Header::new("Accept-Language", "en"))
+            .cookie(Cookie::new("hagrid_lang", "de"))
+            .dispatch();
+        assert!(response.into_string().unwrap().contains("lang=\"en\""));
+
+        let response = client.get("/language/xx").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        // Pages offer all languages.
+        let response = client.get("/").dispatch();
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("href=\"/language/de\""));
+    }
+
+    #[test]
+    fn forms_need_session_token() {
+        let (_tmpdir, client) = client().unwrap();
+
+        // Pages without forms don't start a session.
+        let response = client.get("/").dispatch();
+        assert!(response.headers().get_one("Set-Cookie").is_none());
+
+        let session = csrf_session(&client);
+        let manage = |csrf: &str, cookie: Option<Cookie<'static>>| {
+            let encoded = ::url::form_urlencoded::Serializer::new(String::new())
+                .append_pair("csrf", csrf)
+                .append_pair("search_term", "foo@invalid.example.com")
+                .finish();
+            let request = client
+                .post("/manage")
+                .header(ContentType::Form)
+                .body(encoded);
+            match cookie {
+                Some(cookie) => request.cookie(cookie),
+                None => request,
+            }
+            .dispatch()
+        };
+
+        // Forms from other sites carry neither the token, nor the
+        // cookie along with it.
+        let response = manage(session.value(), None);
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("session has expired"));
+        let response = manage("forged", Some(session.clone()));
+        assert_eq!(response.status(), Status::BadRequest);
+
+        // There is no key for the address, but the form is accepted.
+        let response = manage(session.value(), Some(session.clone()));
+        assert_eq!(response.status(), Status::NotFound);
+
+        // The session keeps its token.
+        let response = client.get("/manage").cookie(session.clone()).dispatch();
+        assert!(response.headers().get_one("Set-Cookie").is_none());
+        assert!(response.into_string().unwrap().contains(session.value()));
+    }
+
     #[test]
     fn basics() {
         let (_tmpdir, config) = configuration().unwrap();
@@ -804,40 +2048,368 @@ pub mod tests {
             .contains("maintenance-message"));
     }
 
-    fn check_maintenance(client: &Client, uri: &str, content_type: ContentType) {
-        let response = client.get(uri).dispatch();
+    #[test]
+    fn admin_maintenance() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("admin_token", "sekrit"));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let auth = || Header::new("Authorization", "Bearer sekrit");
+
+        let response = client
+            .put("/admin/v1/maintenance")
+            .header(ContentType::JSON)
+            .body(r#"{"message":"maintenance-message"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let response = client
+            .put("/admin/v1/maintenance")
+            .header(ContentType::JSON)
+            .header(auth())
+            .body(r#"{"message":"maintenance-message"}"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // Changes are turned away, lookups are served.
+        let response = client
+            .post("/vks/v1/certifications/challenge")
+            .header(ContentType::JSON)
+            .body(r#"{"key_fpr":"0123456789ABCDEF0123456789ABCDEF01234567"}"#)
+            .dispatch();
         assert_eq!(response.status(), Status::ServiceUnavailable);
-        assert_eq!(response.content_type(), Some(content_type));
-        assert!(response
-            .into_string()
-            .unwrap()
-            .contains("maintenance-message"));
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        check_maintenance(&client, "/upload", ContentType::HTML);
+        let response = client
+            .get("/vks/v1/by-fingerprint/0123456789ABCDEF0123456789ABCDEF01234567")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let response = client
+            .get("/admin/v1/maintenance")
+            .header(auth())
+            .dispatch();
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(result["maintenance"], true);
+        assert_eq!(result["message"], "maintenance-message");
+
+        let response = client
+            .delete("/admin/v1/maintenance")
+            .header(auth())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/upload").dispatch();
+        assert_eq!(response.status(), Status::Ok);
     }
 
     #[test]
-    fn upload_verify_single() {
-        let (tmpdir, client) = client().unwrap();
-        let filemail_into = tmpdir.path().join("filemail");
+    fn admin_read_only() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config
+            .merge(("admin_token", "sekrit"))
+            .merge(("read_only", true));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let auth = || Header::new("Authorization", "Bearer sekrit");
 
-        // Generate a key and upload it.
         let tpk = build_cert("foo@invalid.example.com");
-
         let mut tpk_serialized = Vec::new();
         tpk.serialize(&mut tpk_serialized).unwrap();
-        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
 
-        // Prior to email confirmation, we should not be able to look
-        // it up by email address.
-        check_null_responses_by_email(&client, "foo@invalid.example.com");
+        // Uploads are refused, pages and lookups are served.
+        let response = client.put("/").body(&tpk_serialized).dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert!(response.into_string().unwrap().contains("read-only"));
+        let response = client
+            .post("/vks/v1/upload")
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let response = client.get("/upload").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get("/vks/v1/by-email/foo@invalid.example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
 
-        // And check that we can get it back via the machine readable
-        // interface.
-        check_mr_responses_by_fingerprint(&client, &tpk, 0);
+        let response = client
+            .delete("/admin/v1/read-only")
+            .header(auth())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let (status, _) = vks_publish_submit_json(&client, &tpk_serialized);
+        assert_eq!(status, Status::Ok);
 
-        // And check that we can see the human-readable result page.
-        check_hr_responses_by_fingerprint(&client, &tpk, 0);
+        let response = client.put("/admin/v1/read-only").header(auth()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client.get("/admin/v1/read-only").header(auth()).dispatch();
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(result["read_only"], true);
+    }
 
-        // Check the verification link
+    #[test]
+    fn reload_policies() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config
+            .merge(("admin_token", "sekrit"))
+            .merge(("api_rate_limit", 1));
+        let rocket = rocket_factory(rocket::custom(config.clone())).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let lookup = || {
+            client
+                .get("/vks/v1/by-email/foo@invalid.example.com")
+                .dispatch()
+                .status()
+        };
+        let upload = |body: &[u8]| {
+            client
+                .put("/")
+                .header(Header::new("Content-Length", body.len().to_string()))
+                .body(body)
+                .dispatch()
+                .status()
+        };
+
+        assert_eq!(lookup(), Status::NotFound);
+        assert_eq!(lookup(), Status::TooManyRequests);
+
+        let policies = client.rocket().state::<Policies>().unwrap();
+        let reloaded = config
+            .clone()
+            .merge(("api_rate_limit", 3))
+            .merge(("body_limit_raw_upload", 100));
+        policies.reload(&reloaded).unwrap();
+        assert_eq!(lookup(), Status::NotFound);
+        assert_eq!(upload(&[b'x'; 200]), Status::PayloadTooLarge);
+
+        // Invalid policies leave the current ones in place.
+        let invalid = reloaded.merge(("uid_policy", "bogus"));
+        assert!(policies.reload(&invalid).is_err());
+        assert_eq!(lookup(), Status::NotFound);
+        assert_eq!(lookup(), Status::TooManyRequests);
+
+        let response = client.post("/admin/v1/reload").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+    }
+
+    #[test]
+    fn task_status() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("admin_token", "sekrit"));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client
+            .get("/admin/v1/tasks")
+            .header(Header::new("Authorization", "Bearer sekrit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert!(result["tasks"].is_array());
+        assert!(result["healthy"].is_boolean());
+
+        let response = client.get("/admin/v1/tasks").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let tasks = client.rocket().state::<Tasks>().unwrap();
+        assert!(tasks.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn smtp_relay_config() {
+        let (_tmpdir, config) = configuration().unwrap();
+        assert!(configure_smtp_relay(&config, None).is_none());
+
+        let config = config.merge(("smtp_host", "mail.example.org"));
+        let relay = configure_smtp_relay(&config, None).unwrap();
+        assert_eq!(relay.host, "mail.example.org");
+        assert_eq!(relay.port, None);
+        assert!(relay.credentials.is_none());
+        assert!(relay.starttls);
+        assert_eq!(relay.pool_size, 4);
+        assert_eq!(relay.idle_timeout, 60);
+
+        let config = config
+            .merge(("smtp_port", 2525))
+            .merge(("smtp_username", "hagrid"))
+            .merge(("smtp_password", "sekrit"))
+            .merge(("smtp_starttls", false))
+            .merge(("smtp_pool_size", 1))
+            .merge(("smtp_idle_timeout", 5));
+        let relay = configure_smtp_relay(&config, None).unwrap();
+        assert_eq!(relay.port, Some(2525));
+        assert_eq!(
+            relay.credentials,
+            Some(("hagrid".to_owned(), "sekrit".to_owned()))
+        );
+        assert!(!relay.starttls);
+        assert_eq!(relay.pool_size, 1);
+        assert_eq!(relay.idle_timeout, 5);
+    }
+
+    #[test]
+    fn mail_webhook() {
+        use ring::{digest, hmac};
+        use sequoia_openpgp::fmt::hex;
+
+        let (tmpdir, config) = configuration().unwrap();
+        let config = config
+            .merge(("mail_queue_dir", tmpdir.path().join("mail")))
+            .merge(("mail_api", "mailgun"))
+            .merge(("mail_api_webhook_key", "sekrit"));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let timestamp = mail_queue::unix_now().to_string();
+        let key = hmac::SigningKey::new(&digest::SHA256, b"sekrit");
+        let signature = hmac::sign(&key, format!("{}token", timestamp).as_bytes());
+        let notification = serde_json::json!({
+            "signature": {
+                "timestamp": timestamp,
+                "token": "token",
+                "signature": hex::encode(signature.as_ref()),
+            },
+            "event-data": {
+                "event": "failed",
+                "severity": "permanent",
+                "recipient": "foo@invalid.example.com",
+                "user-variables": { "hagrid_kind": "verify" },
+            },
+        })
+        .to_string();
+
+        let response = client
+            .post("/mail/v1/events")
+            .header(ContentType::JSON)
+            .body(&notification)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let mail_service = client.rocket().state::<mail::Service>().unwrap();
+        let email = "foo@invalid.example.com".parse().unwrap();
+        assert!(mail_service.is_suppressed(&email));
+        assert_eq!(
+            mail_service.verification_status(&email),
+            Some(mail_queue::DeliveryStatus::Bounced)
+        );
+
+        // Replays are refused.
+        let response = client
+            .post("/mail/v1/events")
+            .header(ContentType::JSON)
+            .body(&notification)
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+    }
+
+    #[test]
+    fn disk_quota() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config
+            .merge(("disk_quota_hard", 1))
+            .merge(("disk_usage_interval", 3600));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let disk_quota = client.rocket().state::<DiskQuota>().unwrap();
+        assert!(disk_quota.update().unwrap() > 0);
+        assert!(disk_quota.is_exhausted());
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let response = client.put("/").body(&tpk_serialized).dispatch();
+        assert_eq!(response.status(), Status::InsufficientStorage);
+        let response = client
+            .post("/vks/v1/upload")
+            .header(ContentType::JSON)
+            .body("{}")
+            .dispatch();
+        assert_eq!(response.status(), Status::InsufficientStorage);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+
+        // Lookups are still served.
+        let response = client
+            .get("/vks/v1/by-email/foo@invalid.example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn features() {
+        let (_tmpdir, client) = client().unwrap();
+        check_response(
+            &client,
+            "/.well-known/openpgpkey/invalid.example.com/policy",
+            Status::Ok,
+            "protocol-version: 14",
+        );
+        check_response(&client, "/about/usage", Status::Ok, "WKD as a Service");
+
+        let (_tmpdir, config) = configuration().unwrap();
+        let mut features = std::collections::HashMap::new();
+        features.insert("wkd", false);
+        let config = config.merge(("features", features));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let response = client
+            .get("/.well-known/openpgpkey/invalid.example.com/policy")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        let response = client.get("/about/usage").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(!response.into_string().unwrap().contains("WKD as a Service"));
+        assert!(client
+            .rocket()
+            .state::<Features>()
+            .unwrap()
+            .is_enabled("lint"));
+
+        let (_tmpdir, config) = configuration().unwrap();
+        let mut features = std::collections::HashMap::new();
+        features.insert("teleportation", true);
+        let config = config.merge(("features", features));
+        assert!(rocket_factory(rocket::custom(config)).is_err());
+    }
+
+    fn check_maintenance(client: &Client, uri: &str, content_type: ContentType) {
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::ServiceUnavailable);
+        assert_eq!(response.content_type(), Some(content_type));
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("maintenance-message"));
+    }
+
+    #[test]
+    fn upload_verify_single() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        // Generate a key and upload it.
+        let tpk = build_cert("foo@invalid.example.com");
+
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        // Prior to email confirmation, we should not be able to look
+        // it up by email address.
+        check_null_responses_by_email(&client, "foo@invalid.example.com");
+
+        // And check that we can get it back via the machine readable
+        // interface.
+        check_mr_responses_by_fingerprint(&client, &tpk, 0);
+
+        // And check that we can see the human-readable result page.
+        check_hr_responses_by_fingerprint(&client, &tpk, 0);
+
+        // Check the verification link
         check_verify_link(&client, &token, "foo@invalid.example.com", "");
 
         // Now check for the verification mail.
@@ -866,134 +2438,2170 @@ pub mod tests {
         // But lookup by fingerprint should still work.
         check_mr_responses_by_fingerprint(&client, &tpk, 0);
 
-        // And check that we can see the human-readable result page.
-        check_hr_responses_by_fingerprint(&client, &tpk, 0);
+        // And check that we can see the human-readable result page.
+        check_hr_responses_by_fingerprint(&client, &tpk, 0);
+
+        assert_consistency(client.rocket());
+    }
+
+    #[test]
+    fn upload_verify_lang() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        // Generate a key and upload it.
+        let tpk = build_cert("foo@invalid.example.com");
+
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        check_verify_link(&client, &token, "foo@invalid.example.com", "de");
+        let mail_content = pop_mail(&filemail_into).unwrap().unwrap();
+        assert!(mail_content.contains("Dies ist eine automatisierte Nachricht"));
+        assert!(mail_content.contains("Subject: =?utf-8?b?QmVzdMOkdGlnZQ==?= foo@invalid.example.com \n =?utf-8?b?ZsO8cg==?= deinen =?utf-8?b?U2NobMO8c3NlbA==?= auf \n local.connection"));
+    }
+
+    #[test]
+    fn upload_verify_spooled_mail() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+
+        let mails = list_mails(&filemail_into).unwrap();
+        assert_eq!(mails.len(), 1);
+        let mail = &mails[0];
+        assert_eq!(mail.header("To"), Some("foo@invalid.example.com"));
+        assert_eq!(mail.header("From"), Some("from@example.com"));
+        assert!(mail
+            .header("Subject")
+            .unwrap()
+            .contains("foo@invalid.example.com"));
+        assert!(mail.body.contains(&tpk.fingerprint().to_hex()));
+
+        // Follow the link from the actual mail.
+        let link_re = regex::Regex::new(&format!("{}(/verify/[^ \t\n]*)", BASE_URI)).unwrap();
+        let confirm_uri = link_re
+            .captures(&mail.body)
+            .unwrap()
+            .get(1)
+            .unwrap()
+            .as_str();
+        let response = post_verify_link(&client, confirm_uri);
+        assert_eq!(response.status(), Status::Ok);
+
+        check_responses_by_email(&client, "foo@invalid.example.com", &tpk, 1);
+        assert_consistency(client.rocket());
+    }
+
+    #[test]
+    fn upload_by_mail() {
+        let (tmpdir, config) = configuration().unwrap();
+        let maildir = tmpdir.path().join("submissions");
+        let config = config.merge(("submission_maildir", maildir.to_str().unwrap()));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let message = format!(
+            "Return-Path: <foo@invalid.example.com>\r\n\
+             From: Foo <foo@invalid.example.com>\r\n\
+             Reply-To: victim@invalid.example.org\r\n\
+             Subject: my key\r\n\
+             Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+             \r\n\
+             --b\r\n\
+             Content-Type: text/plain\r\n\
+             \r\n\
+             Please publish my key.\r\n\
+             --b\r\n\
+             Content-Type: application/pgp-keys\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             {}\r\n\
+             --b--\r\n",
+            base64::encode(&tpk_serialized)
+        );
+        fs::create_dir_all(maildir.join("new")).unwrap();
+        fs::write(maildir.join("new/1.submission"), message).unwrap();
+
+        let inbound_mail = configure_inbound_mail(client.rocket()).unwrap().unwrap();
+        assert_eq!(inbound_mail.process_maildir().unwrap(), 1);
+        assert!(maildir.join("cur/1.submission:2,S").exists());
+        assert_eq!(inbound_mail.process_maildir().unwrap(), 0);
+
+        // The sender gets a verification mail, and the status.
+        let mails = list_mails(&filemail_into).unwrap();
+        assert_eq!(mails.len(), 2);
+        assert!(mails
+            .iter()
+            .all(|mail| mail.header("To") == Some("foo@invalid.example.com")));
+        let reply = mails
+            .iter()
+            .find(|mail| mail.header("Subject") == Some("Your key submission to local.connection"))
+            .unwrap();
+        assert!(reply.body.contains(&tpk.fingerprint().to_hex()));
+        assert!(reply.body.contains("verification mail sent"));
+        assert!(!reply.body.contains("my key"));
+
+        // Nothing is published until the address is verified.
+        check_null_responses_by_email(&client, "foo@invalid.example.com");
+    }
+
+    #[test]
+    fn verify_by_reply() {
+        let (tmpdir, config) = configuration().unwrap();
+        let maildir = tmpdir.path().join("submissions");
+        let config = config
+            .merge(("submission_maildir", maildir.to_str().unwrap()))
+            .merge(("submission_address", "submit@local.connection"));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+
+        let mail = pop_mail(&filemail_into).unwrap().unwrap();
+        assert!(mail.contains("Reply-To: submit@local.connection"));
+        assert!(mail.contains("Or simply reply to this message."));
+
+        // Reply, quoting the verification mail.
+        let quoted: String = mail.lines().map(|line| format!("> {}\n", line)).collect();
+        let reply = format!(
+            "Return-Path: <foo@invalid.example.com>\n\
+             From: foo@invalid.example.com\n\
+             Subject: Re: Verify\n\n{}",
+            quoted
+        );
+        let inbound_mail = configure_inbound_mail(client.rocket()).unwrap().unwrap();
+        inbound_mail.process(reply.as_bytes()).unwrap();
+        check_responses_by_email(&client, "foo@invalid.example.com", &tpk, 1);
+        let status = pop_mail(&filemail_into).unwrap().unwrap();
+        assert!(status.contains("foo@invalid.example.com: verified and published"));
+
+        // Replying again does not mail the sender again right away.
+        inbound_mail.process(reply.as_bytes()).unwrap();
+        assert!(pop_mail(&filemail_into).unwrap().is_none());
+        assert_consistency(client.rocket());
+    }
+
+    #[test]
+    fn upload_two() {
+        let (_tmpdir, config) = configuration().unwrap();
+
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        // Generate two keys and upload them.
+        let tpk_0 = build_cert("foo@invalid.example.com");
+        let tpk_1 = build_cert("bar@invalid.example.com");
+
+        let mut tpk_serialized = Vec::new();
+        tpk_0.serialize(&mut tpk_serialized).unwrap();
+        tpk_1.serialize(&mut tpk_serialized).unwrap();
+        vks_publish_submit_multiple(&client, &tpk_serialized);
+
+        // Prior to email confirmation, we should not be able to look
+        // them up by email address.
+        check_null_responses_by_email(&client, "foo@invalid.example.com");
+        check_null_responses_by_email(&client, "bar@invalid.example.com");
+
+        // And check that we can get them back via the machine readable
+        // interface.
+        check_mr_responses_by_fingerprint(&client, &tpk_0, 0);
+        check_mr_responses_by_fingerprint(&client, &tpk_1, 0);
+
+        // And check that we can see the human-readable result page.
+        check_hr_responses_by_fingerprint(&client, &tpk_0, 0);
+        check_hr_responses_by_fingerprint(&client, &tpk_1, 0);
+    }
+
+    #[test]
+    fn upload_verify_two() {
+        let (tmpdir, config) = configuration().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        // Generate two keys and upload them.
+        let tpk_1 = build_cert("foo@invalid.example.com");
+        let tpk_2 = build_cert("bar@invalid.example.com");
+
+        let mut tpk_serialized_1 = Vec::new();
+        tpk_1.serialize(&mut tpk_serialized_1).unwrap();
+        let token_1 = vks_publish_submit_get_token(&client, &tpk_serialized_1);
+
+        let mut tpk_serialized_2 = Vec::new();
+        tpk_2.serialize(&mut tpk_serialized_2).unwrap();
+        let token_2 = vks_publish_json_get_token(&client, &tpk_serialized_2);
+
+        // Prior to email confirmation, we should not be able to look
+        // them up by email address.
+        check_null_responses_by_email(&client, "foo@invalid.example.com");
+        check_null_responses_by_email(&client, "bar@invalid.example.com");
+
+        // And check that we can get them back via the machine readable
+        // interface.
+        check_mr_responses_by_fingerprint(&client, &tpk_1, 0);
+        check_mr_responses_by_fingerprint(&client, &tpk_2, 0);
+
+        // And check that we can see the human-readable result page.
+        check_hr_responses_by_fingerprint(&client, &tpk_1, 0);
+        check_hr_responses_by_fingerprint(&client, &tpk_2, 0);
+
+        // Check the verification link
+        check_verify_link(&client, &token_1, "foo@invalid.example.com", "");
+        check_verify_link_json(&client, &token_2, "bar@invalid.example.com");
+
+        // Now check for the verification mails.
+        check_mails_and_verify_email(&client, &filemail_into);
+        check_mails_and_verify_email(&client, &filemail_into);
+
+        // Now lookups using the mail address should work.
+        check_responses_by_email(&client, "foo@invalid.example.com", &tpk_1, 1);
+        check_responses_by_email(&client, "bar@invalid.example.com", &tpk_2, 1);
+
+        // Request deletion of the bindings.
+        vks_manage(&client, "foo@invalid.example.com");
+        check_mails_and_confirm_deletion(&client, &filemail_into, "foo@invalid.example.com");
+        vks_manage(&client, "bar@invalid.example.com");
+        check_mails_and_confirm_deletion(&client, &filemail_into, "bar@invalid.example.com");
+
+        // Now, we should no longer be able to look it up by email
+        // address.
+        check_null_responses_by_email(&client, "foo@invalid.example.com");
+        check_null_responses_by_email(&client, "bar@invalid.example.com");
+
+        // But lookup by fingerprint should still work.
+        check_mr_responses_by_fingerprint(&client, &tpk_1, 0);
+        check_mr_responses_by_fingerprint(&client, &tpk_2, 0);
+
+        // And check that we can see the human-readable result page.
+        check_hr_responses_by_fingerprint(&client, &tpk_1, 0);
+        check_hr_responses_by_fingerprint(&client, &tpk_2, 0);
+
+        assert_consistency(client.rocket());
+    }
+
+    #[test]
+    fn upload_no_key() {
+        let (_tmpdir, client) = client().unwrap();
+        let response = vks_publish_submit_response(&client, b"");
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn upload_json_status() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let fpr = tpk.fingerprint().to_hex();
+
+        let (status, result) = vks_publish_submit_json(&client, &tpk_serialized);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(result["key_fpr"], fpr);
+        assert_eq!(result["key_status"], "published-without-identity");
+        assert_eq!(
+            result["status"]["foo@invalid.example.com"],
+            "pending-verification"
+        );
+        assert_eq!(
+            result["merge_diff"]["new_subkeys"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(result["merge_diff"]["published_changed"], true);
+
+        let token = result["token"].as_str().unwrap();
+        check_verify_link(&client, token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        let (status, result) = vks_publish_submit_json(&client, &tpk_serialized);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(result["key_status"], "published");
+        assert_eq!(result["status"]["foo@invalid.example.com"], "published");
+        assert_eq!(result["merge_diff"]["new_signatures"], 0);
+        assert_eq!(result["merge_diff"]["published_changed"], false);
+
+        let (status, result) = vks_publish_submit_json(&client, b"");
+        assert_eq!(status, Status::BadRequest);
+        assert!(result["error"].is_string());
+        assert!(result["code"].is_string());
+
+        let mut tsk_serialized = Vec::new();
+        tpk.as_tsk().serialize(&mut tsk_serialized).unwrap();
+        let (status, result) = vks_publish_submit_json(&client, &tsk_serialized);
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(result["code"], "secret-key");
+    }
+
+    #[test]
+    fn lint() {
+        let (_tmpdir, client) = client().unwrap();
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let fpr = tpk.fingerprint().to_hex();
+        vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        let response = client.get(format!("/vks/v1/lint/{}", fpr)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(result["key_fpr"], fpr);
+        assert_eq!(result["issues"], serde_json::json!([]));
+
+        let response = client
+            .get("/vks/v1/lint/CBCD8F030588653EEDD7E2659B7DD433F254904A")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn admin_ban() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("admin_token", "sekrit"));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let fpr = tpk.fingerprint().to_hex();
+        vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_mr_responses_by_fingerprint(&client, &tpk, 0);
+
+        let ban = |token: &str| {
+            client
+                .post(format!("/admin/v1/ban/{}", fpr))
+                .header(ContentType::JSON)
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .body(r#"{ "reason": "spam" }"#)
+                .dispatch()
+                .status()
+        };
+        assert_eq!(ban("wrong"), Status::Unauthorized);
+        check_mr_responses_by_fingerprint(&client, &tpk, 0);
+
+        assert_eq!(ban("sekrit"), Status::Ok);
+        check_null_response(&client, &format!("/vks/v1/by-fingerprint/{}", fpr));
+        assert_consistency(client.rocket());
+
+        // Re-uploads are rejected.
+        let (status, result) = vks_publish_submit_json(&client, &tpk_serialized);
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(result["code"], "banned-fingerprint");
+    }
+
+    #[test]
+    fn admin_legal_hold() {
+        let (tmpdir, config) = configuration().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+        let config = config.merge(("admin_token", "sekrit"));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let authorization = Header::new("Authorization", "Bearer sekrit");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let fpr = tpk.fingerprint().to_hex();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, &filemail_into);
+
+        let response = client
+            .put(format!("/admin/v1/legal-hold/{}", fpr))
+            .header(ContentType::JSON)
+            .header(authorization.clone())
+            .body(r#"{ "reason": "court order" }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get(format!("/admin/v1/legal-hold/{}", fpr))
+            .header(authorization.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let inspection: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(inspection["legal_hold"]["reason"], "court order");
+        assert_eq!(inspection["published"], true);
+        assert_eq!(inspection["banned"], false);
+
+        // Held keys can't be banned.
+        let response = client
+            .post(format!("/admin/v1/ban/{}", fpr))
+            .header(ContentType::JSON)
+            .header(authorization.clone())
+            .body(r#"{ "reason": "spam" }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response.into_string().unwrap().contains("legal hold"));
+        check_responses_by_email(&client, "foo@invalid.example.com", &tpk, 1);
+
+        // The owner can't unpublish the addresses of held keys.
+        vks_manage(&client, "foo@invalid.example.com");
+        let pattern = format!("{}/manage/([^ \t\n]*)", BASE_URI);
+        let manage_token = pop_mail_capture_pattern(&filemail_into, &pattern);
+        let session = csrf_session(&client);
+        let encoded = ::url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("csrf", session.value())
+            .append_pair("token", &manage_token)
+            .append_pair("address", "foo@invalid.example.com")
+            .finish();
+        let response = client
+            .post("/manage/unpublish")
+            .header(ContentType::Form)
+            .cookie(session.clone())
+            .body(encoded.as_bytes())
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response.into_string().unwrap().contains("legal hold"));
+        check_responses_by_email(&client, "foo@invalid.example.com", &tpk, 1);
+
+        let response = client
+            .delete(format!("/admin/v1/legal-hold/{}", fpr))
+            .header(authorization.clone())
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let response = client
+            .get("/admin/v1/legal-hold")
+            .header(authorization)
+            .dispatch();
+        let holds: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(holds["legal_holds"], serde_json::json!([]));
+
+        vks_manage_delete(&client, &manage_token, "foo@invalid.example.com");
+        check_null_responses_by_email(&client, "foo@invalid.example.com");
+        assert_consistency(client.rocket());
+    }
+
+    #[test]
+    fn search_result_details() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        let uri = format!("/search?q={}", tpk.fingerprint().to_hex());
+        let created = chrono::DateTime::<chrono::Utc>::from(tpk.primary_key().creation_time())
+            .format("%Y-%m-%d")
+            .to_string();
+
+        // The address is not listed until it is verified.
+        let body = client.get(&uri).dispatch().into_string().unwrap();
+        assert!(body.contains("EdDSA, Ed25519"));
+        assert!(body.contains(&created));
+        assert!(body.contains("Never"));
+        assert!(!body.contains("foo@invalid.example.com"));
+
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        let body = client.get(&uri).dispatch().into_string().unwrap();
+        assert!(body.contains("<span class=\"email\">foo@invalid.example.com</span>"));
+    }
+
+    #[test]
+    fn key_page() {
+        let (_tmpdir, client) = client().unwrap();
+
+        let (tpk, _) = CertBuilder::new()
+            .add_signing_subkey()
+            .add_transport_encryption_subkey()
+            .add_userid("foo@invalid.example.com")
+            .set_validity_period(Duration::from_secs(7 * 24 * 60 * 60))
+            .generate()
+            .unwrap();
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        let response = client
+            .get(format!("/key/{}", tpk.fingerprint().to_hex()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        for key in tpk.keys() {
+            assert!(body.contains(&key.fingerprint().to_hex()));
+        }
+        assert!(body.contains("sign"));
+        assert!(body.contains("encrypt"));
+        assert!(body.contains("This key expires soon."));
+        assert!(!body.contains("revoked"));
+
+        let response = client.get("/key/0123456789ABCDEF").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client
+            .get("/key/0123456789ABCDEF0123456789ABCDEF01234567")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn retained_certifications() {
+        use sequoia_openpgp::armor;
+        use sequoia_openpgp::packet::signature::SignatureBuilder;
+        use sequoia_openpgp::policy::StandardPolicy;
+        use sequoia_openpgp::types::SignatureType;
+        use sequoia_openpgp::Packet;
+
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let sign = |tpk: &Cert, message: &str| {
+            let policy = StandardPolicy::new();
+            let mut keypair = tpk
+                .keys()
+                .with_policy(&policy, None)
+                .for_signing()
+                .secret()
+                .next()
+                .unwrap()
+                .key()
+                .clone()
+                .into_keypair()
+                .unwrap();
+            let sig = SignatureBuilder::new(SignatureType::Binary)
+                .sign_message(&mut keypair, message.as_bytes())
+                .unwrap();
+            let mut writer = armor::Writer::new(Vec::new(), armor::Kind::Signature).unwrap();
+            Packet::from(sig).serialize(&mut writer).unwrap();
+            String::from_utf8(writer.finalize().unwrap()).unwrap()
+        };
+
+        let certifier = build_cert("bar@invalid.example.com");
+        let mut certifier_signer = certifier
+            .primary_key()
+            .key()
+            .clone()
+            .parts_into_secret()
+            .unwrap()
+            .into_keypair()
+            .unwrap();
+        let tpk = build_cert("foo@invalid.example.com");
+        let certification = tpk
+            .userids()
+            .next()
+            .unwrap()
+            .userid()
+            .bind(
+                &mut certifier_signer,
+                &tpk,
+                SignatureBuilder::new(SignatureType::GenericCertification),
+            )
+            .unwrap();
+        let certified = tpk.clone().insert_packets(vec![certification]).unwrap();
+
+        let mut tpk_serialized = Vec::new();
+        certifier.serialize(&mut tpk_serialized).unwrap();
+        vks_publish_submit_get_token(&client, &tpk_serialized);
+        let mut tpk_serialized = Vec::new();
+        certified.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        let fpr = tpk.fingerprint().to_hex();
+        let certifications = || {
+            let response = client
+                .get(format!("/vks/v1/by-fingerprint/{}", fpr))
+                .dispatch();
+            let published = Cert::from_bytes(response.into_string().unwrap().as_bytes()).unwrap();
+            published.userids().next().unwrap().certifications().count()
+        };
+        assert_eq!(certifications(), 0);
+
+        let response = client
+            .post("/vks/v1/certifications/challenge")
+            .header(ContentType::JSON)
+            .body(format!(r#"{{ "key_fpr": "{}" }}"#, fpr))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let challenge = result["challenge"].as_str().unwrap().to_owned();
+        assert_eq!(result["certifiers"], serde_json::json!([]));
+
+        let retain = |signature: String| {
+            client
+                .post("/vks/v1/certifications/retain")
+                .header(ContentType::JSON)
+                .body(
+                    serde_json::json!({
+                        "challenge": challenge,
+                        "signature": signature,
+                        "certifiers": [certifier.fingerprint().to_hex()],
+                    })
+                    .to_string(),
+                )
+                .dispatch()
+        };
+
+        // Only the key's owner can opt in.
+        let response = retain(sign(&certifier, &challenge));
+        assert_eq!(response.status(), Status::Forbidden);
+        assert_eq!(certifications(), 0);
+
+        let response = retain(sign(&tpk, &challenge));
+        assert_eq!(response.status(), Status::Ok);
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(result["certifications"], 1);
+        assert_eq!(certifications(), 1);
+    }
+
+    #[test]
+    fn identity_proofs() {
+        use sequoia_openpgp::packet::signature::SignatureBuilder;
+        use sequoia_openpgp::packet::UserID;
+        use sequoia_openpgp::types::SignatureType;
+        use sequoia_openpgp::Packet;
+
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = CertBuilder::new().generate().unwrap().0;
+        let mut keypair = tpk
+            .primary_key()
+            .key()
+            .clone()
+            .parts_into_secret()
+            .unwrap()
+            .into_keypair()
+            .unwrap();
+        let uid = UserID::from("foo@invalid.example.com");
+        let sig = uid
+            .bind(
+                &mut keypair,
+                &tpk,
+                SignatureBuilder::new(SignatureType::PositiveCertification)
+                    .add_notation("proof@ariadne.id", b"dns:example.org?type=TXT", None, false)
+                    .unwrap(),
+            )
+            .unwrap();
+        let tpk = tpk
+            .insert_packets(vec![Packet::from(uid), sig.into()])
+            .unwrap();
+
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        let fpr = tpk.fingerprint().to_hex();
+        let response = client.get(format!("/vks/v1/proofs/{}", fpr)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(result["verified"], false);
+        assert_eq!(
+            result["proofs"],
+            serde_json::json!([{
+                "uri": "dns:example.org?type=TXT",
+                "address": "foo@invalid.example.com",
+            }])
+        );
+
+        check_response(
+            &client,
+            &format!("/key/{}", fpr),
+            Status::Ok,
+            "dns:example.org",
+        );
+        check_response(
+            &client,
+            &format!("/key/{}", fpr),
+            Status::Ok,
+            "have not been verified",
+        );
+        check_response(
+            &client,
+            "/vks/v1/proofs/0123456789ABCDEF0123456789ABCDEF01234567",
+            Status::NotFound,
+            "",
+        );
+    }
+
+    #[test]
+    fn manage_key_uids() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+        let today = chrono::Utc::now().format("%Y-%m-%d").to_string();
+
+        vks_manage(&client, "foo@invalid.example.com");
+        let pattern = format!("{}/manage/([^ \t\n]*)", BASE_URI);
+        let token = pop_mail_capture_pattern(filemail_into.as_path(), &pattern);
+
+        let response = client.get(format!("/manage/{}", token)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains("foo@invalid.example.com"));
+        assert!(body.contains(&format!("Verified on {}", today)));
+        assert!(body.contains("value=\"Delete\""));
+
+        vks_manage_delete(&client, &token, "foo@invalid.example.com");
+        check_null_responses_by_email(&client, "foo@invalid.example.com");
+    }
+
+    #[test]
+    fn manage_key_delivery_status() {
+        let (tmpdir, config) = configuration().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+        let config = config.merge(("mail_queue_dir", tmpdir.path().join("mail")));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let (tpk, _) = CertBuilder::new()
+            .add_userid("foo@invalid.example.com")
+            .add_userid("bar@invalid.example.com")
+            .add_userid("baz@invalid.example.com")
+            .generate()
+            .unwrap();
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        // Verification of the second address is requested, but not
+        // completed.
+        check_verify_link(&client, &token, "bar@invalid.example.com", "");
+        let pattern = format!("{}(/verify/[^ \t\n]*)", BASE_URI);
+        pop_mail_capture_pattern(filemail_into.as_path(), &pattern);
+
+        vks_manage(&client, "foo@invalid.example.com");
+        let pattern = format!("{}/manage/([^ \t\n]*)", BASE_URI);
+        let token = pop_mail_capture_pattern(filemail_into.as_path(), &pattern);
+
+        let response = client.get(format!("/manage/{}", token)).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains("waiting for verification"));
+        let pending = &body[body.find("waiting for verification").unwrap()..];
+        assert!(pending.contains("bar@invalid.example.com"));
+        assert!(pending.contains("The last verification email to this address was sent."));
+        // Addresses nobody asked to verify are not listed.
+        assert!(!body.contains("baz@invalid.example.com"));
+    }
+
+    #[test]
+    fn verify_result_links() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let (tpk, _) = CertBuilder::new()
+            .add_userid("foo@invalid.example.com")
+            .add_userid("bar@invalid.example.com")
+            .generate()
+            .unwrap();
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_verify_link(&client, &token, "bar@invalid.example.com", "");
+
+        let pattern = format!("{}(/verify/[^ \t\n]*)", BASE_URI);
+        let confirm_uri = pop_mail_capture_pattern(filemail_into.as_path(), &pattern);
+        let response = post_verify_link(&client, &confirm_uri);
+        assert_eq!(response.status(), Status::Ok);
+        let body = response.into_string().unwrap();
+        assert!(body.contains(&format!(
+            "{}/vks/v1/by-fingerprint/{}",
+            BASE_URI,
+            tpk.fingerprint().to_hex()
+        )));
+        assert!(body.contains(&format!("{}/vks/v1/by-email/", BASE_URI)));
+        // One address is now published, the other one is pending.
+        assert!(body.contains("still pending"));
+        assert!(body.contains("foo@invalid.example.com"));
+        assert!(body.contains("bar@invalid.example.com"));
+
+        let confirm_uri = pop_mail_capture_pattern(filemail_into.as_path(), &pattern);
+        let response = post_verify_link(&client, &confirm_uri);
+        assert_eq!(response.status(), Status::Ok);
+        assert!(!response.into_string().unwrap().contains("still pending"));
+    }
+
+    #[get("/internal-error")]
+    fn internal_error() -> MyResponse {
+        MyResponse::ise(anyhow!("something broke"))
+    }
+
+    #[test]
+    fn request_ids() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let rocket = rocket_factory(rocket::custom(config))
+            .unwrap()
+            .mount("/test", routes![internal_error]);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.get("/about").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert!(response.headers().get_one("X-Request-Id").is_some());
+
+        let response = client.get("/test/internal-error").dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+        let request_id = response
+            .headers()
+            .get_one("X-Request-Id")
+            .unwrap()
+            .to_owned();
+        let body = response.into_string().unwrap();
+        assert!(body.contains("something broke"));
+        assert!(body.contains(&format!("<code>{}</code>", request_id)));
+    }
+
+    #[test]
+    fn fingerprint_qr() {
+        let (_tmpdir, client) = client().unwrap();
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        let response = client
+            .get(format!("/vks/v1/qr/{}", tpk.fingerprint().to_hex()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::SVG));
+        assert!(response.into_string().unwrap().contains("<svg"));
+
+        let response = client.get("/vks/v1/qr/0123456789ABCDEF").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let response = client
+            .get("/vks/v1/qr/0123456789ABCDEF0123456789ABCDEF01234567")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn minimal_by_fingerprint() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        let fpr = tpk.fingerprint().to_hex();
+        let response = client
+            .get(format!("/vks/v1/by-fingerprint/{}?minimal=1", fpr))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let minimal = Cert::from_bytes(response.into_string().unwrap().as_bytes()).unwrap();
+        assert_eq!(minimal.fingerprint(), tpk.fingerprint());
+        assert_eq!(minimal.userids().count(), 1);
+
+        let response = client
+            .get("/vks/v1/by-fingerprint/0123456789ABCDEF0123456789ABCDEF01234567?minimal=1")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn dane_by_email() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("hugh@example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        // Unverified addresses have no record.
+        check_response(
+            &client,
+            "/vks/v1/dane/hugh@example.com",
+            Status::NotFound,
+            "",
+        );
+
+        check_verify_link(&client, &token, "hugh@example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        let response = client.get("/vks/v1/dane/hugh%40example.com").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let record: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+
+        // The example from RFC 7929, section 3.
+        let name =
+            "c93f1e400f26708f98cb19d936620da35eec8f72e57f9eec01c1afd6._openpgpkey.example.com.";
+        assert_eq!(record["name"], name);
+        let rdata = base64::decode(record["rdata"].as_str().unwrap()).unwrap();
+        let published = Cert::from_bytes(&rdata).unwrap();
+        assert_eq!(published.fingerprint(), tpk.fingerprint());
+        assert_eq!(published.userids().count(), 1);
+        assert_eq!(
+            record["presentation"],
+            format!(
+                "{} IN OPENPGPKEY {}",
+                name,
+                record["rdata"].as_str().unwrap()
+            )
+        );
+        assert!(record["generic"].as_str().unwrap().starts_with(&format!(
+            "{} IN TYPE61 \\# {} ",
+            name,
+            rdata.len()
+        )));
+
+        check_response(
+            &client,
+            "/vks/v1/dane/example.com",
+            Status::BadRequest,
+            "malformed",
+        );
+    }
+
+    #[test]
+    fn domain_keys() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        let post = |uri: &'static str, body: &'static str| {
+            client
+                .post(uri)
+                .header(ContentType::JSON)
+                .body(body)
+                .dispatch()
+        };
+        let get = |uri: &'static str, token: &str| {
+            client
+                .get(uri)
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .dispatch()
+        };
+
+        // Challenges are stable.
+        let challenge = |body| {
+            let response = post("/domain/v1/challenge", body);
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_str::<serde_json::Value>(&response.into_string().unwrap()).unwrap()
+        };
+        let result = challenge(r#"{ "domain": "Invalid.Example.com" }"#);
+        assert_eq!(result["record"], "_hagrid-challenge.invalid.example.com");
+        assert!(result["value"]
+            .as_str()
+            .unwrap()
+            .starts_with("hagrid-domain-verification="));
+        assert_eq!(result, challenge(r#"{ "domain": "invalid.example.com" }"#));
+        let response = post("/domain/v1/challenge", r#"{ "domain": "not a domain" }"#);
+        assert_eq!(response.status(), Status::BadRequest);
+
+        assert_eq!(
+            get("/domain/v1/keys", "wrong").status(),
+            Status::Unauthorized
+        );
+
+        let body = r#"{ "domain": "invalid.example.com" }"#;
+        let response = post("/domain/v1/verify-email", body);
+        assert_eq!(response.status(), Status::Ok);
+        let access_token = pop_mail_capture_pattern(&filemail_into, r"minutes:\s+([A-Za-z0-9_-]+)");
+        let response = post("/domain/v1/verify-email", body);
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let response = get("/domain/v1/keys", &access_token);
+        assert_eq!(response.status(), Status::Ok);
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(result["domain"], "invalid.example.com");
+        assert_eq!(
+            result["addresses"],
+            serde_json::json!([{
+                "address": "foo@invalid.example.com",
+                "key_fpr": tpk.fingerprint().to_hex(),
+            }])
+        );
+
+        let response = get("/domain/v1/export", &access_token);
+        assert_eq!(response.status(), Status::Ok);
+        let exported = Cert::from_bytes(response.into_string().unwrap().as_bytes()).unwrap();
+        assert_eq!(exported.fingerprint(), tpk.fingerprint());
+    }
+
+    #[test]
+    fn domain_admin() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let addresses = ["foo@invalid.example.com", "bar@sub.invalid.example.com"];
+        for address in &addresses {
+            let tpk = build_cert(address);
+            let mut tpk_serialized = Vec::new();
+            tpk.serialize(&mut tpk_serialized).unwrap();
+            let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+            check_verify_link(&client, &token, address, "");
+            check_mails_and_verify_email(&client, filemail_into.as_path());
+        }
+
+        // Tests can't publish DNS records, so we issue the tokens here.
+        let token_service = tokens::Service::init("hagrid", 3600);
+        let access_token = |via_dns| {
+            token_service.create(&domain::DomainAccessToken {
+                domain_access: "invalid.example.com".to_owned(),
+                via_dns,
+            })
+        };
+        let dns_token = access_token(true);
+        let request = |method, uri: &'static str, token: &str, body: &'static str| {
+            client
+                .req(method, uri)
+                .header(ContentType::JSON)
+                .header(Header::new("Authorization", format!("Bearer {}", token)))
+                .body(body)
+                .dispatch()
+        };
+        let json = |response: LocalResponse| {
+            assert_eq!(response.status(), Status::Ok);
+            serde_json::from_str::<serde_json::Value>(&response.into_string().unwrap()).unwrap()
+        };
+
+        // Changes require the DNS proof.
+        let response = request(
+            Method::Post,
+            "/domain/v1/unpublish",
+            &access_token(false),
+            r#"{ "addresses": ["foo@invalid.example.com"] }"#,
+        );
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .get(format!("/vks/v1/by-email/{}", addresses[0]))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // Only addresses in the domain can be changed.
+        let response = request(
+            Method::Post,
+            "/domain/v1/unpublish",
+            &dns_token,
+            r#"{ "addresses": ["foo@example.com"] }"#,
+        );
+        assert_eq!(response.status(), Status::BadRequest);
+
+        let result = json(request(
+            Method::Put,
+            "/domain/v1/allowlist",
+            &dns_token,
+            r#"{ "addresses": ["foo@invalid.example.com"] }"#,
+        ));
+        assert_eq!(
+            result["unpublished"],
+            serde_json::json!(["bar@sub.invalid.example.com"])
+        );
+        check_null_responses_by_email(&client, addresses[1]);
+        let result = json(request(Method::Get, "/domain/v1/allowlist", &dns_token, ""));
+        assert_eq!(
+            result["allowlist"],
+            serde_json::json!(["foo@invalid.example.com"])
+        );
+
+        let result = json(request(
+            Method::Post,
+            "/domain/v1/unpublish",
+            &dns_token,
+            r#"{ "addresses": ["foo@invalid.example.com"] }"#,
+        ));
+        assert_eq!(
+            result["unpublished"],
+            serde_json::json!(["foo@invalid.example.com"])
+        );
+        check_null_responses_by_email(&client, addresses[0]);
+
+        let result = json(request(
+            Method::Delete,
+            "/domain/v1/allowlist",
+            &dns_token,
+            "",
+        ));
+        assert_eq!(result["allowlist"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn template_context() {
+        let origin = RequestOrigin::Direct("https://keys.example.org/".to_owned());
+        let common = templates::Common::new("ar").with_origin(&origin);
+        assert_eq!(common.domain.as_deref(), Some("keys.example.org"));
+        assert_eq!(common.htmldir, "rtl");
+
+        let layout = templates::HagridLayout::new(
+            templates::Bare { dummy: () },
+            I18n {
+                catalog: get_i18n().remove(0).1,
+                lang: "en",
+            },
+            RequestOrigin::OnionService("http://example.onion".to_owned()),
+        );
+        let context = serde_json::to_value(layout).unwrap();
+        assert_eq!(context["lang"], "en");
+        assert_eq!(context["htmldir"], "ltr");
+        assert_eq!(context["base_uri"], "http://example.onion");
+        assert_eq!(context["domain"], "example.onion");
+        assert_eq!(context["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[test]
+    fn versioned_assets() {
+        let (_tmpdir, client) = client().unwrap();
+
+        let response = client.get("/about").dispatch();
+        let page = response.into_string().unwrap();
+        let regex = regex::Regex::new(r#"href="(/assets/site\.[0-9a-f]{16}\.css)""#).unwrap();
+        let url = regex.captures(&page).unwrap()[1].to_owned();
+
+        let response = client.get(url.as_str()).dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::CSS));
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("public, max-age=31536000, immutable")
+        );
+        let versioned = response.into_bytes().unwrap();
+
+        let response = client.get("/assets/site.css").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.headers().get_one("Cache-Control"), None);
+        assert_eq!(response.into_bytes().unwrap(), versioned);
+
+        let response = client.get("/assets/site.0000000000000000.css").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn robots_and_security_txt() {
+        let (_tmpdir, client) = client().unwrap();
+
+        let response = client.get("/robots.txt").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.content_type(), Some(ContentType::Plain));
+        assert!(response.into_string().unwrap().contains("Disallow: /vks/"));
+
+        let response = client.get("/.well-known/security.txt").dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config
+            .merge(("robots_txt", "User-agent: *\nDisallow: /\n"))
+            .merge(("security_txt", "Contact: mailto:security@example.org\n"));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.get("/robots.txt").dispatch();
+        assert_eq!(
+            response.into_string().unwrap(),
+            "User-agent: *\nDisallow: /\n"
+        );
+
+        let response = client.get("/.well-known/security.txt").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.into_string().unwrap(),
+            "Contact: mailto:security@example.org\n"
+        );
+    }
+
+    #[test]
+    fn exists_by_email() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        let response = client
+            .get("/vks/v1/exists/by-email/foo@invalid.example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("public, max-age=3600")
+        );
+        assert_eq!(response.into_string().unwrap_or_default(), "");
+
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        let response = client
+            .get("/vks/v1/exists/by-email/foo%40invalid.example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("public, max-age=3600")
+        );
+        assert_eq!(response.into_string().unwrap_or_default(), "");
+
+        let response = client.get("/vks/v1/exists/by-email/foo").dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
+
+    #[test]
+    fn head_by_email() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        let response = client
+            .head("/vks/v1/by-email/foo@invalid.example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        let response = client
+            .head("/vks/v1/by-email/foo@invalid.example.com")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let etag = response.headers().get_one("ETag").unwrap().to_owned();
+        assert!(etag.contains(&tpk.fingerprint().to_hex()));
+        assert!(response.headers().get_one("Last-Modified").is_some());
+        assert_eq!(
+            response.headers().get_one("Cache-Control"),
+            Some("public, max-age=300")
+        );
+        assert_eq!(response.into_string().unwrap_or_default(), "");
+
+        // Unchanged keys keep their tag.
+        let response = client
+            .head("/vks/v1/by-email/foo@invalid.example.com")
+            .dispatch();
+        assert_eq!(response.headers().get_one("ETag"), Some(etag.as_str()));
+    }
+
+    #[test]
+    fn api_quotas() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let mut token_limits = std::collections::HashMap::new();
+        token_limits.insert("sekrit", 3);
+        let config = config
+            .merge(("api_rate_limit", 2))
+            .merge(("api_token_rate_limits", token_limits));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let uri = "/vks/v1/by-email/foo@invalid.example.com";
+        for _ in 0..2 {
+            let response = client.get(uri).dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+        }
+        let response = client.get(uri).dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let retry_after: u64 = response
+            .headers()
+            .get_one("Retry-After")
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!((1..=60).contains(&retry_after));
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(result["retry_after"], retry_after);
+
+        // API tokens have quotas of their own.
+        for _ in 0..3 {
+            let response = client
+                .get(uri)
+                .header(Header::new("Authorization", "Bearer sekrit"))
+                .dispatch();
+            assert_eq!(response.status(), Status::NotFound);
+        }
+        let response = client
+            .get(uri)
+            .header(Header::new("Authorization", "Bearer sekrit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::TooManyRequests);
+
+        // The web interface isn't limited.
+        let response = client.get("/about").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+    }
+
+    #[test]
+    fn trusted_proxies() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config
+            .merge(("api_rate_limit", 1))
+            .merge(("trusted_proxies", vec!["127.0.0.1", "10.0.0.0/8"]));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let uri = "/vks/v1/by-email/foo@invalid.example.com";
+        let request = |peer: &str, forwarded_for: &str| {
+            client
+                .get(uri)
+                .remote(peer.parse().unwrap())
+                .header(Header::new("X-Forwarded-For", forwarded_for.to_owned()))
+                .dispatch()
+                .status()
+        };
+
+        // Clients behind trusted proxies have their own quotas.
+        assert_eq!(request("127.0.0.1:1234", "192.0.2.1"), Status::NotFound);
+        assert_eq!(
+            request("127.0.0.1:1234", "192.0.2.1, 10.1.2.3"),
+            Status::TooManyRequests
+        );
+        assert_eq!(request("127.0.0.1:1234", "192.0.2.2"), Status::NotFound);
+
+        // Other peers can't claim to forward for someone else.
+        assert_eq!(request("198.51.100.1:1234", "192.0.2.3"), Status::NotFound);
+        assert_eq!(
+            request("198.51.100.1:1234", "192.0.2.4"),
+            Status::TooManyRequests
+        );
+
+        // Neither can the clients of trusted proxies.
+        assert_eq!(
+            request("127.0.0.1:1234", "192.0.2.5, 192.0.2.1"),
+            Status::TooManyRequests
+        );
+    }
+
+    #[test]
+    fn access_log() {
+        let (tmpdir, config) = configuration().unwrap();
+        let path = tmpdir.path().join("access.log");
+        let config = config
+            .merge(("access_log", path.to_str().unwrap()))
+            .merge(("access_log_max_size", 200))
+            .merge(("access_log_keep", 1));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        client
+            .get("/about?lang=en")
+            .remote("192.0.2.1:1234".parse().unwrap())
+            .header(Header::new("User-Agent", "Test \"Agent\""))
+            .dispatch();
+        let log = fs::read_to_string(&path).unwrap();
+        let line = regex::Regex::new(
+            r#"^192\.0\.2\.1 - - \[\d{2}/\w{3}/\d{4}:\d{2}:\d{2}:\d{2} [+-]\d{4}\] "GET /about\?lang=en HTTP/1\.1" 200 \d+ "-" "Test \\"Agent\\""\n$"#,
+        )
+        .unwrap();
+        assert!(line.is_match(&log), "{}", log);
+
+        // The third line doesn't fit anymore.
+        client.get("/about").dispatch();
+        client.get("/about").dispatch();
+        let rotated = fs::read_to_string(tmpdir.path().join("access.log.1")).unwrap();
+        assert_eq!(rotated.lines().count(), 2);
+        assert!(rotated.starts_with(&log));
+        let log = fs::read_to_string(&path).unwrap();
+        assert_eq!(log.lines().count(), 1);
+    }
+
+    #[test]
+    fn route_metrics() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("enable_prometheus", true));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        client.get("/about").dispatch();
+        client.get("/pks/lookup?op=get&search=0x0").dispatch();
+
+        let metrics = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(metrics.contains(r#"hagrid_responses{class="2xx",route="/about"}"#));
+        assert!(metrics.contains(r#"hagrid_request_duration_seconds_count{route="/about"}"#));
+        assert!(metrics.contains(r#"hagrid_responses{class="4xx",route="/pks/lookup"#));
+    }
+
+    #[test]
+    fn usage_stats() {
+        let (tmpdir, config) = configuration().unwrap();
+        let stats_path = tmpdir.path().join("usage-stats.json");
+        let today = mail_queue::unix_now() / (24 * 60 * 60) * (24 * 60 * 60);
+        fs::write(
+            &stats_path,
+            serde_json::json!([
+                { "day": today, "transport": "wkd", "result": "found", "count": 5 },
+                { "day": 0, "transport": "wkd", "result": "found", "count": 7 },
+            ])
+            .to_string(),
+        )
+        .unwrap();
+        let config = config
+            .merge(("enable_prometheus", true))
+            .merge(("usage_stats_file", stats_path.to_str().unwrap()));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        vks_publish_submit_get_token(&client, &tpk_serialized);
+        let fpr = tpk.fingerprint().to_hex();
+
+        client
+            .get(format!("/vks/v1/by-fingerprint/{}", fpr))
+            .dispatch();
+        client
+            .get(format!("/vks/v1/by-fingerprint/{}", "0".repeat(40)))
+            .dispatch();
+        client
+            .get(format!("/pks/lookup?op=get&search=0x{}", fpr))
+            .dispatch();
+        client.get("/about").dispatch();
+
+        let response = client.get("/about/stats/lookups.json").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let stats: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        let mut days: Vec<(String, String, u64)> = stats["days"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|bucket| {
+                assert_eq!(bucket["day"], today);
+                (
+                    bucket["transport"].as_str().unwrap().to_owned(),
+                    bucket["result"].as_str().unwrap().to_owned(),
+                    bucket["count"].as_u64().unwrap(),
+                )
+            })
+            .collect();
+        days.sort();
+        let expected = [
+            ("hkp", "found", 1),
+            ("vks", "found", 1),
+            ("vks", "not-found", 1),
+            ("wkd", "found", 5),
+        ];
+        let expected: Vec<_> = expected
+            .iter()
+            .map(|(transport, result, count)| (transport.to_string(), result.to_string(), *count))
+            .collect();
+        assert_eq!(days, expected);
+
+        check_response(&client, "/about/stats", Status::Ok, "not-found");
+        let metrics = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(metrics.contains(r#"hagrid_lookups{result="found",transport="vks"}"#));
+    }
+
+    #[get("/test/panic")]
+    fn panic_route() -> &'static str {
+        panic!("secret panic message");
+    }
+
+    #[test]
+    fn panics() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("enable_prometheus", true));
+        let rocket = rocket_factory(rocket::custom(config))
+            .unwrap()
+            .mount("/", routes![panic_route]);
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let response = client.get("/test/panic").dispatch();
+        assert_eq!(response.status(), Status::InternalServerError);
+        let request_id = response
+            .headers()
+            .get_one("X-Request-Id")
+            .unwrap()
+            .to_owned();
+        let body = response.into_string().unwrap();
+        assert!(body.contains(&request_id));
+        assert!(!body.contains("secret panic message"));
+
+        let metrics = client.get("/metrics").dispatch().into_string().unwrap();
+        assert!(metrics.contains("hagrid_panics_total"));
+    }
+
+    #[test]
+    fn body_limits() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("body_limit_raw_upload", 100));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let body = vec![b'x'; 200];
+        let response = client
+            .put("/")
+            .header(Header::new("Content-Length", body.len().to_string()))
+            .body(&body)
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        assert!(response.into_string().unwrap().contains("100 bytes"));
+
+        let response = client
+            .post("/vks/v1/upload")
+            .header(ContentType::JSON)
+            .header(Header::new("Content-Length", body.len().to_string()))
+            .body(&body)
+            .dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(result["limit"], 100);
+
+        // Other routes keep their limits.
+        let response = client
+            .post("/upload/submit")
+            .header(ContentType::Form)
+            .header(Header::new("Content-Length", body.len().to_string()))
+            .body(&body)
+            .dispatch();
+        assert_ne!(response.status(), Status::PayloadTooLarge);
+    }
+
+    #[test]
+    fn request_timeout() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("request_timeout", 0));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+
+        let response = client.put("/").body(&tpk_serialized).dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("Timed out reading the request"));
+
+        let (status, _) = vks_publish_submit_json(&client, &tpk_serialized);
+        assert_eq!(status, Status::BadRequest);
+    }
+
+    #[test]
+    fn upload_multipart_too_large() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("body_limit_publish_form", 4096));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        // Without a Content-Length, the body is cut off while reading.
+        let (status, result) = vks_publish_submit_json(&client, &[b'x'; 8192]);
+        assert_eq!(status, Status::BadRequest);
+        assert_eq!(result["code"], "too-large");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let (status, result) = vks_publish_submit_json(&client, &tpk_serialized);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(result["key_fpr"], tpk.fingerprint().to_hex());
+    }
+
+    #[test]
+    fn upload_cached() {
+        let (tmpdir, client) = client().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+
+        let (status, result) = vks_publish_submit_json(&client, &tpk_serialized);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(
+            result["status"]["foo@invalid.example.com"],
+            "pending-verification"
+        );
+        let token = result["token"].as_str().unwrap().to_owned();
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        // The same bytes again are answered with the current state of
+        // the key.
+        let (status, result) = vks_publish_submit_json(&client, &tpk_serialized);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(result["key_fpr"], tpk.fingerprint().to_hex());
+        assert_eq!(result["status"]["foo@invalid.example.com"], "published");
+        assert_eq!(result["merge_diff"]["published_changed"], false);
+        assert!(result["token"].is_string());
+    }
+
+    #[test]
+    fn upload_cached_multiple() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("admin_token", "sekrit"));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let tpk_0 = build_cert("foo@invalid.example.com");
+        let tpk_1 = build_cert("bar@invalid.example.com");
+        let mut tpks_serialized = Vec::new();
+        tpk_0.serialize(&mut tpks_serialized).unwrap();
+        tpk_1.serialize(&mut tpks_serialized).unwrap();
+
+        let (status, result) = vks_publish_submit_json(&client, &tpks_serialized);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(result["key_fprs"].as_array().unwrap().len(), 2);
+
+        let response = client
+            .post(format!("/admin/v1/ban/{}", tpk_1.fingerprint().to_hex()))
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sekrit"))
+            .body(r#"{ "reason": "spam" }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        // The banned key is not reported as uploaded from the cache.
+        let (status, result) = vks_publish_submit_json(&client, &tpks_serialized);
+        assert_eq!(status, Status::Ok);
+        assert_eq!(
+            result["key_fprs"],
+            serde_json::json!([tpk_0.fingerprint().to_hex()])
+        );
+    }
+
+    #[test]
+    fn removal_notice() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("admin_token", "sekrit"));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let fpr = tpk.fingerprint().to_hex();
+        vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        let response = client
+            .post(format!("/admin/v1/ban/{}", fpr))
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sekrit"))
+            .body(r#"{ "reason": "court order", "notice": "Removed by court order." }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+
+        let response = client
+            .get(format!("/vks/v1/by-fingerprint/{}", fpr))
+            .dispatch();
+        assert_eq!(response.status(), Status::Gone);
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(result["notice"], "Removed by court order.");
+
+        check_response(
+            &client,
+            &format!("/pks/lookup?op=get&search={}", fpr),
+            Status::Gone,
+            "Removed by court order.",
+        );
+        check_response(
+            &client,
+            &format!("/search?q={}", fpr),
+            Status::Gone,
+            "Removed by court order.",
+        );
+
+        // Key IDs don't reveal anything.
+        check_null_response(
+            &client,
+            &format!("/vks/v1/by-keyid/{}", tpk.keyid().to_hex()),
+        );
+    }
+
+    #[test]
+    fn transparency_log() {
+        let (tmpdir, config) = configuration().unwrap();
+        let signer = CertBuilder::new()
+            .add_signing_subkey()
+            .generate()
+            .unwrap()
+            .0;
+        let signer_path = tmpdir.path().join("transparency.key");
+        signer
+            .as_tsk()
+            .serialize(&mut File::create(&signer_path).unwrap())
+            .unwrap();
+        let config = config.merge(("transparency_key", signer_path.to_str().unwrap()));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let fpr = tpk.fingerprint().to_hex();
+
+        check_response(
+            &client,
+            &format!("/vks/v1/transparency/proof/{}", fpr),
+            Status::NotFound,
+            "",
+        );
+
+        vks_publish_submit_get_token(&client, &tpk_serialized);
+
+        let response = client.get("/vks/v1/transparency/tree-head").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let head: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(head["tree_size"], 1);
+        assert!(head["signature"]
+            .as_str()
+            .unwrap()
+            .contains("BEGIN PGP SIGNATURE"));
+
+        let response = client
+            .get(format!("/vks/v1/transparency/proof/{}?tree_size=1", fpr))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let proof: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(proof["leaf_index"], 0);
+        assert!(proof["leaf"].as_str().unwrap().contains(&fpr));
+
+        let response = client
+            .get("/vks/v1/transparency/entries?start=0&end=10")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let entries: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(entries["entries"].as_array().unwrap().len(), 1);
+
+        check_response(
+            &client,
+            "/vks/v1/transparency/entries?start=0&end=100000",
+            Status::BadRequest,
+            "at most",
+        );
+
+        let response = client.get("/log/v1/sth").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let sth: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(sth["tree_size"], 1);
+        assert_eq!(sth["sha256_root_hash"], head["root_hash"]);
+        assert!(sth["tree_head_signature"]
+            .as_str()
+            .unwrap()
+            .contains("BEGIN PGP SIGNATURE"));
+
+        // With a single leaf, the root hash is the leaf hash.
+        let leaf_hash = sth["sha256_root_hash"].as_str().unwrap();
+        let response = client
+            .get(format!("/log/v1/inclusion/{}/{}", fpr, leaf_hash))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let proof: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(proof["leaf_index"], 0);
+        assert_eq!(proof["audit_path"].as_array().unwrap().len(), 0);
+        check_response(
+            &client,
+            &format!("/log/v1/inclusion/{}/{}", fpr, "00".repeat(32)),
+            Status::NotFound,
+            "no such leaf",
+        );
+
+        let response = client.get("/log/v1/consistency/1/1").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let proof: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(proof["consistency"].as_array().unwrap().len(), 0);
+        check_response(
+            &client,
+            "/log/v1/consistency/1/2",
+            Status::BadRequest,
+            "tree sizes",
+        );
+    }
+
+    #[test]
+    fn research_keys() {
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("research_tokens", vec!["sekrit"]));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
 
-        assert_consistency(client.rocket());
+        let mut fprs = vec![];
+        for address in &["foo@invalid.example.com", "bar@invalid.example.com"] {
+            let tpk = build_cert(address);
+            let mut tpk_serialized = Vec::new();
+            tpk.serialize(&mut tpk_serialized).unwrap();
+            vks_publish_submit_get_token(&client, &tpk_serialized);
+            fprs.push(tpk.fingerprint().to_hex());
+        }
+        fprs.sort();
+
+        let response = client.get("/research/v1/keys").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+
+        let get = |uri: String| {
+            let response = client
+                .get(uri)
+                .header(Header::new("Authorization", "Bearer sekrit"))
+                .dispatch();
+            assert_eq!(response.status(), Status::Ok);
+            let body = response.into_string().unwrap();
+            assert!(!body.contains("invalid.example.com"));
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        };
+        let page = get("/research/v1/keys?limit=1".to_owned());
+        assert_eq!(page["keys"][0]["fingerprint"], fprs[0]);
+        assert!(page["keys"][0]["created"].as_u64().unwrap() > 0);
+        assert!(page["keys"][0]["size"].as_u64().unwrap() > 0);
+        let cursor = page["next_cursor"].as_str().unwrap().to_owned();
+        let page = get(format!("/research/v1/keys?cursor={}&limit=10", cursor));
+        assert_eq!(page["keys"].as_array().unwrap().len(), 1);
+        assert_eq!(page["keys"][0]["fingerprint"], fprs[1]);
+        assert!(page["next_cursor"].is_null());
     }
 
     #[test]
-    fn upload_verify_lang() {
-        let (tmpdir, client) = client().unwrap();
+    fn research_domains() {
+        let (tmpdir, config) = configuration().unwrap();
         let filemail_into = tmpdir.path().join("filemail");
+        let config = config
+            .merge(("research_tokens", vec!["sekrit"]))
+            .merge(("domain_stats_threshold", 2));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
 
-        // Generate a key and upload it.
-        let tpk = build_cert("foo@invalid.example.com");
+        for address in &[
+            "foo@invalid.example.com",
+            "bar@invalid.example.com",
+            "baz@invalid.example.org",
+        ] {
+            let tpk = build_cert(address);
+            let mut tpk_serialized = Vec::new();
+            tpk.serialize(&mut tpk_serialized).unwrap();
+            let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+            check_verify_link(&client, &token, address, "");
+            check_mails_and_verify_email(&client, filemail_into.as_path());
+        }
 
-        let mut tpk_serialized = Vec::new();
-        tpk.serialize(&mut tpk_serialized).unwrap();
-        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        let response = client.get("/research/v1/domains").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
 
-        check_verify_link(&client, &token, "foo@invalid.example.com", "de");
-        let mail_content = pop_mail(&filemail_into).unwrap().unwrap();
-        assert!(mail_content.contains("Dies ist eine automatisierte Nachricht"));
-        assert!(mail_content.contains("Subject: =?utf-8?b?QmVzdMOkdGlnZQ==?= foo@invalid.example.com \n =?utf-8?b?ZsO8cg==?= deinen =?utf-8?b?U2NobMO8c3NlbA==?= auf \n local.connection"));
+        let response = client
+            .get("/research/v1/domains")
+            .header(Header::new("Authorization", "Bearer sekrit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let stats: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(stats["threshold"], 2);
+        assert_eq!(
+            stats["domains"],
+            serde_json::json!({ "invalid.example.com": 2 })
+        );
     }
 
     #[test]
-    fn upload_two() {
+    fn research_keys_disabled() {
         let (_tmpdir, config) = configuration().unwrap();
-
         let rocket = rocket_factory(rocket::custom(config)).unwrap();
         let client = Client::untracked(rocket).expect("valid rocket instance");
 
-        // Generate two keys and upload them.
-        let tpk_0 = build_cert("foo@invalid.example.com");
-        let tpk_1 = build_cert("bar@invalid.example.com");
+        let response = client
+            .get("/research/v1/keys")
+            .header(Header::new("Authorization", "Bearer sekrit"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
+    }
+
+    #[test]
+    fn keylist() {
+        use sequoia_openpgp::Packet;
+
+        let (tmpdir, config) = configuration().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+        let signer = CertBuilder::new()
+            .add_signing_subkey()
+            .generate()
+            .unwrap()
+            .0;
+        let signer_path = tmpdir.path().join("transparency.key");
+        signer
+            .as_tsk()
+            .serialize(&mut File::create(&signer_path).unwrap())
+            .unwrap();
+
+        let tpk_domain = build_cert("foo@invalid.example.com");
+        let tpk_fpr = build_cert("bar@other.example.org");
+        let config = config
+            .merge(("transparency_key", signer_path.to_str().unwrap()))
+            .merge((
+                "keylists",
+                serde_json::json!({
+                    "team": {
+                        "domains": ["invalid.example.com"],
+                        "fingerprints": [tpk_fpr.fingerprint().to_hex()],
+                        "comment": "Team keys",
+                    }
+                }),
+            ));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
 
         let mut tpk_serialized = Vec::new();
-        tpk_0.serialize(&mut tpk_serialized).unwrap();
-        tpk_1.serialize(&mut tpk_serialized).unwrap();
-        vks_publish_submit_multiple(&client, &tpk_serialized);
+        tpk_domain.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+        let mut tpk_serialized = Vec::new();
+        tpk_fpr.serialize(&mut tpk_serialized).unwrap();
+        vks_publish_submit_get_token(&client, &tpk_serialized);
 
-        // Prior to email confirmation, we should not be able to look
-        // them up by email address.
-        check_null_responses_by_email(&client, "foo@invalid.example.com");
-        check_null_responses_by_email(&client, "bar@invalid.example.com");
+        let response = client.get("/vks/v1/keylist/team/keylist.json").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let document = response.into_string().unwrap();
+        let keylist: serde_json::Value = serde_json::from_str(&document).unwrap();
+        assert_eq!(keylist["metadata"]["comment"], "Team keys");
+        assert!(keylist["metadata"]["signature_uri"]
+            .as_str()
+            .unwrap()
+            .ends_with("/vks/v1/keylist/team/keylist.json.asc"));
+        let keys = keylist["keys"].as_array().unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&serde_json::json!({
+            "fingerprint": tpk_domain.fingerprint().to_hex(),
+            "email": "foo@invalid.example.com",
+        })));
+        assert!(keys.contains(&serde_json::json!({
+            "fingerprint": tpk_fpr.fingerprint().to_hex(),
+        })));
 
-        // And check that we can get them back via the machine readable
-        // interface.
-        check_mr_responses_by_fingerprint(&client, &tpk_0, 0);
-        check_mr_responses_by_fingerprint(&client, &tpk_1, 0);
+        let response = client
+            .get("/vks/v1/keylist/team/keylist.json.asc")
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let signature = response.into_string().unwrap();
+        let mut signature = match Packet::from_bytes(signature.as_bytes()).unwrap() {
+            Packet::Signature(signature) => signature,
+            packet => panic!("expected a signature, got {:?}", packet),
+        };
+        let signing_key = signer.keys().subkeys().next().unwrap().key().clone();
+        signature
+            .verify_message(&signing_key, document.as_bytes())
+            .unwrap();
 
-        // And check that we can see the human-readable result page.
-        check_hr_responses_by_fingerprint(&client, &tpk_0, 0);
-        check_hr_responses_by_fingerprint(&client, &tpk_1, 0);
+        check_response(
+            &client,
+            "/vks/v1/keylist/nonexistent/keylist.json",
+            Status::NotFound,
+            "No such keylist",
+        );
     }
 
     #[test]
-    fn upload_verify_two() {
+    fn admin_ban_email() {
         let (tmpdir, config) = configuration().unwrap();
-        let filemail_into = tmpdir.path().join("filemail");
-
+        let config = config.merge(("admin_token", "sekrit"));
         let rocket = rocket_factory(rocket::custom(config)).unwrap();
         let client = Client::untracked(rocket).expect("valid rocket instance");
+        let filemail_into = tmpdir.path().join("filemail");
 
-        // Generate two keys and upload them.
-        let tpk_1 = build_cert("foo@invalid.example.com");
-        let tpk_2 = build_cert("bar@invalid.example.com");
-
-        let mut tpk_serialized_1 = Vec::new();
-        tpk_1.serialize(&mut tpk_serialized_1).unwrap();
-        let token_1 = vks_publish_submit_get_token(&client, &tpk_serialized_1);
-
-        let mut tpk_serialized_2 = Vec::new();
-        tpk_2.serialize(&mut tpk_serialized_2).unwrap();
-        let token_2 = vks_publish_json_get_token(&client, &tpk_serialized_2);
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, &filemail_into);
+        check_responses_by_email(&client, "foo@invalid.example.com", &tpk, 1);
 
-        // Prior to email confirmation, we should not be able to look
-        // them up by email address.
+        let response = client
+            .post("/admin/v1/ban-email/example.com")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sekrit"))
+            .body(r#"{ "reason": "spam" }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(
+            result["unpublished"],
+            serde_json::json!(["foo@invalid.example.com"])
+        );
+        assert_eq!(result["held"], serde_json::json!([]));
         check_null_responses_by_email(&client, "foo@invalid.example.com");
-        check_null_responses_by_email(&client, "bar@invalid.example.com");
-
-        // And check that we can get them back via the machine readable
-        // interface.
-        check_mr_responses_by_fingerprint(&client, &tpk_1, 0);
-        check_mr_responses_by_fingerprint(&client, &tpk_2, 0);
+        check_mr_responses_by_fingerprint(&client, &tpk, 0);
+        assert_consistency(client.rocket());
 
-        // And check that we can see the human-readable result page.
-        check_hr_responses_by_fingerprint(&client, &tpk_1, 0);
-        check_hr_responses_by_fingerprint(&client, &tpk_2, 0);
+        // No verification mail is sent for banned addresses.
+        let response = client
+            .post("/vks/v1/request-verify")
+            .header(ContentType::JSON)
+            .body(format!(
+                r#"{{"token":"{}","addresses":["foo@invalid.example.com"]}}"#,
+                token
+            ))
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+        let result: serde_json::Value =
+            serde_json::from_str(&response.into_string().unwrap()).unwrap();
+        assert_eq!(result["code"], "banned-domain");
+        assert!(pop_mail(&filemail_into).unwrap().is_none());
 
-        // Check the verification link
-        check_verify_link(&client, &token_1, "foo@invalid.example.com", "");
-        check_verify_link_json(&client, &token_2, "bar@invalid.example.com");
+        let response = client
+            .post("/admin/v1/ban-email/not a domain")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer sekrit"))
+            .body(r#"{ "reason": "spam" }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::BadRequest);
+    }
 
-        // Now check for the verification mails.
-        check_mails_and_verify_email(&client, &filemail_into);
-        check_mails_and_verify_email(&client, &filemail_into);
+    #[test]
+    fn admin_quarantine() {
+        use sequoia_openpgp::serialize::SerializeInto;
 
-        // Now lookups using the mail address should work.
-        check_responses_by_email(&client, "foo@invalid.example.com", &tpk_1, 1);
-        check_responses_by_email(&client, "bar@invalid.example.com", &tpk_2, 1);
+        let (_tmpdir, config) = configuration().unwrap();
+        let config = config.merge(("admin_token", "sekrit"));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+        let authorization = format!("Basic {}", base64::encode("admin:sekrit"));
+
+        let db = client.rocket().state::<KeyDatabase>().unwrap();
+        let tpk1 = build_cert("foo@invalid.example.com");
+        let fpr1 = tpk1.fingerprint().to_hex();
+        let tpk2 = build_cert("bar@invalid.example.com");
+        let fpr2 = tpk2.fingerprint().to_hex();
+        for (tpk, uploader) in &[(&tpk1, Some("c0ffee")), (&tpk2, None)] {
+            db.write_to_quarantine(
+                &tpk.fingerprint().try_into().unwrap(),
+                &tpk.armored().to_vec().unwrap(),
+                *uploader,
+            )
+            .unwrap();
+        }
 
-        // Request deletion of the bindings.
-        vks_manage(&client, "foo@invalid.example.com");
-        check_mails_and_confirm_deletion(&client, &filemail_into, "foo@invalid.example.com");
-        vks_manage(&client, "bar@invalid.example.com");
-        check_mails_and_confirm_deletion(&client, &filemail_into, "bar@invalid.example.com");
+        let response = client.get("/admin/quarantine").dispatch();
+        assert_eq!(response.status(), Status::Unauthorized);
+        assert!(response.headers().get_one("WWW-Authenticate").is_some());
 
-        // Now, we should no longer be able to look it up by email
-        // address.
-        check_null_responses_by_email(&client, "foo@invalid.example.com");
-        check_null_responses_by_email(&client, "bar@invalid.example.com");
+        let response = client
+            .get("/admin/quarantine")
+            .header(Header::new("Authorization", authorization.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let page = response.into_string().unwrap();
+        assert!(page.contains(&fpr1));
+        assert!(page.contains(&fpr2));
+        assert!(page.contains("foo@invalid.example.com"));
+        assert!(page.contains("c0ffee"));
+
+        // Cross-site form submissions are refused, and so are those
+        // of browsers that don't say where they come from.
+        let response = client
+            .post(format!("/admin/quarantine/{}/approve", fpr1))
+            .header(Header::new("Authorization", authorization.clone()))
+            .header(Header::new("Sec-Fetch-Site", "cross-site"))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
+        let response = client
+            .post(format!("/admin/quarantine/{}/approve", fpr1))
+            .header(Header::new("Authorization", authorization.clone()))
+            .dispatch();
+        assert_eq!(response.status(), Status::Forbidden);
 
-        // But lookup by fingerprint should still work.
-        check_mr_responses_by_fingerprint(&client, &tpk_1, 0);
-        check_mr_responses_by_fingerprint(&client, &tpk_2, 0);
+        // So are forms without the token of the session.
+        let session = csrf_session(&client);
+        let response = client
+            .post(format!("/admin/quarantine/{}/approve", fpr1))
+            .header(Header::new("Authorization", authorization.clone()))
+            .header(Header::new("Sec-Fetch-Site", "same-origin"))
+            .header(ContentType::Form)
+            .cookie(session.clone())
+            .body("csrf=forged")
+            .dispatch();
+        assert!(response
+            .into_string()
+            .unwrap()
+            .contains("session has expired"));
+        check_null_response(&client, &format!("/vks/v1/by-fingerprint/{}", fpr1));
 
-        // And check that we can see the human-readable result page.
-        check_hr_responses_by_fingerprint(&client, &tpk_1, 0);
-        check_hr_responses_by_fingerprint(&client, &tpk_2, 0);
+        let decision = format!("csrf={}", session.value());
+        let response = client
+            .post(format!("/admin/quarantine/{}/approve", fpr1))
+            .header(Header::new("Authorization", authorization.clone()))
+            .header(Header::new("Sec-Fetch-Site", "same-origin"))
+            .header(ContentType::Form)
+            .cookie(session.clone())
+            .body(&decision)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        check_mr_responses_by_fingerprint(&client, &tpk1, 0);
 
+        let response = client
+            .post(format!("/admin/quarantine/{}/reject", fpr2))
+            .header(Header::new("Authorization", authorization))
+            .header(Header::new("Sec-Fetch-Site", "same-origin"))
+            .header(ContentType::Form)
+            .cookie(session.clone())
+            .body(&decision)
+            .dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        let page = response.into_string().unwrap();
+        assert!(!page.contains("foo@invalid.example.com"));
+        assert!(!page.contains("bar@invalid.example.com"));
+        check_null_response(&client, &format!("/vks/v1/by-fingerprint/{}", fpr2));
         assert_consistency(client.rocket());
     }
 
     #[test]
-    fn upload_no_key() {
+    fn admin_disabled() {
         let (_tmpdir, client) = client().unwrap();
-        let response = vks_publish_submit_response(&client, b"");
-        assert_eq!(response.status(), Status::BadRequest);
+        let response = client
+            .post("/admin/v1/ban/CBCD8F030588653EEDD7E2659B7DD433F254904A")
+            .header(ContentType::JSON)
+            .header(Header::new("Authorization", "Bearer "))
+            .body(r#"{ "reason": "spam" }"#)
+            .dispatch();
+        assert_eq!(response.status(), Status::NotFound);
     }
 
     #[test]
@@ -1009,7 +4617,9 @@ pub mod tests {
         let token = vks_publish_submit_get_token(&client, &tpk_serialized);
 
         // Check the verification link
+        let session = csrf_session(&client);
         let encoded = ::url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("csrf", session.value())
             .append_pair("token", &token)
             .append_pair("address", "foo@invalid.example.com")
             .finish();
@@ -1018,6 +4628,7 @@ pub mod tests {
             .post("/upload/request-verify")
             .header(ContentType::Form)
             .header(Header::new("X-Is-Onion", "true"))
+            .cookie(session.clone())
             .body(encoded.as_bytes())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
@@ -1086,6 +4697,57 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn wkd_hosted_domains() {
+        let (tmpdir, config) = configuration().unwrap();
+        let filemail_into = tmpdir.path().join("filemail");
+        let mut policies = std::collections::HashMap::new();
+        policies.insert("Invalid.Example.com", "protocol-version: 14\n");
+        let config = config.merge(("wkd_domains", policies));
+        let rocket = rocket_factory(rocket::custom(config)).unwrap();
+        let client = Client::untracked(rocket).expect("valid rocket instance");
+
+        let tpk = build_cert("foo@invalid.example.com");
+        let mut tpk_serialized = Vec::new();
+        tpk.serialize(&mut tpk_serialized).unwrap();
+        let token = vks_publish_submit_get_token(&client, &tpk_serialized);
+        check_verify_link(&client, &token, "foo@invalid.example.com", "");
+        check_mails_and_verify_email(&client, filemail_into.as_path());
+
+        let (wkd_hash, _) = crate::database::wkd::encode_wkd("foo@invalid.example.com").unwrap();
+        let direct_uri = format!("/.well-known/openpgpkey/hu/{}", wkd_hash);
+        let get = |uri: &str, host: &'static str| {
+            client
+                .get(uri.to_owned())
+                .header(Header::new("Host", host))
+                .dispatch()
+        };
+
+        // Both the domain and its openpgpkey subdomain are served.
+        for host in &["invalid.example.com", "openpgpkey.invalid.example.com:443"] {
+            let response = get(&direct_uri, host);
+            assert_eq!(response.status(), Status::Ok);
+            let key = Cert::from_bytes(&response.into_bytes().unwrap()).unwrap();
+            assert_eq!(key.fingerprint(), tpk.fingerprint());
+
+            let response = get("/.well-known/openpgpkey/policy", host);
+            assert_eq!(response.status(), Status::Ok);
+            assert_eq!(response.into_string().unwrap(), "protocol-version: 14\n");
+        }
+        assert_eq!(get(&direct_uri, "example.org").status(), Status::NotFound);
+        assert_eq!(
+            get("/.well-known/openpgpkey/policy", "example.org").status(),
+            Status::NotFound
+        );
+
+        check_response(
+            &client,
+            "/.well-known/openpgpkey/invalid.example.com/policy",
+            Status::Ok,
+            "protocol-version: 14",
+        );
+    }
+
     /// Asserts that the given URI 404s.
     pub fn check_null_response(client: &Client, uri: &str) {
         let response = client.get(uri).dispatch();
@@ -1207,6 +4869,12 @@ pub mod tests {
             tpk,
             nr_uids,
         );
+        check_mr_response(
+            client,
+            &format!("/vks/v1/by-fingerprint/0x{}", fp.to_lowercase()),
+            tpk,
+            nr_uids,
+        );
         check_mr_response(
             client,
             &format!("/pks/lookup?op=get&options=mr&search={}", fp),
@@ -1323,7 +4991,9 @@ pub mod tests {
     }
 
     fn check_verify_link(client: &Client, token: &str, address: &str, lang: &'static str) {
+        let session = csrf_session(client);
         let encoded = ::url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("csrf", session.value())
             .append_pair("token", token)
             .append_pair("address", address)
             .finish();
@@ -1332,6 +5002,7 @@ pub mod tests {
             .post("/upload/request-verify")
             .header(ContentType::Form)
             .header(Header::new("Accept-Language", lang))
+            .cookie(session.clone())
             .body(encoded.as_bytes())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
@@ -1353,10 +5024,10 @@ pub mod tests {
         let pattern = format!("{}(/verify/[^ \t\n]*)", BASE_URI);
         let confirm_uri = pop_mail_capture_pattern(filemail_path, &pattern);
 
-        let response = client.post(&confirm_uri).dispatch();
+        let response = post_verify_link(client, &confirm_uri);
         assert_eq!(response.status(), Status::Ok);
 
-        let response_second = client.post(&confirm_uri).dispatch();
+        let response_second = post_verify_link(client, &confirm_uri);
         assert_eq!(response_second.status(), Status::BadRequest);
         assert!(response_second
             .into_string()
@@ -1364,6 +5035,33 @@ pub mod tests {
             .contains("already been verified"));
     }
 
+    /// Starts a browser session, whose token forms must carry along.
+    fn csrf_session(client: &Client) -> Cookie<'static> {
+        let response = client.get("/upload").dispatch();
+        let cookie = response
+            .headers()
+            .get("Set-Cookie")
+            .flat_map(|header| Cookie::parse(header.to_owned()))
+            .find(|cookie| cookie.name() == "hagrid_csrf")
+            .unwrap();
+        assert!(response.into_string().unwrap().contains(cookie.value()));
+        cookie
+    }
+
+    /// Confirms an address like the form behind a verification link.
+    fn post_verify_link<'a>(client: &'a Client, confirm_uri: &str) -> LocalResponse<'a> {
+        let session = csrf_session(client);
+        let encoded = ::url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("csrf", session.value())
+            .finish();
+        client
+            .post(confirm_uri.to_owned())
+            .header(ContentType::Form)
+            .cookie(session.clone())
+            .body(encoded.as_bytes())
+            .dispatch()
+    }
+
     fn check_mails_and_confirm_deletion(client: &Client, filemail_path: &Path, address: &str) {
         let pattern = format!("{}/manage/([^ \t\n]*)", BASE_URI);
         let token = pop_mail_capture_pattern(filemail_path, &pattern);
@@ -1412,6 +5110,32 @@ pub mod tests {
     }
 
     fn vks_publish_submit_response<'a>(client: &'a Client, data: &[u8]) -> LocalResponse<'a> {
+        let session = csrf_session(client);
+        let (ct, body) = vks_publish_submit_body(session.value(), data);
+        client
+            .post("/upload/submit")
+            .header(ct)
+            .cookie(session.clone())
+            .body(&body[..])
+            .dispatch()
+    }
+
+    fn vks_publish_submit_json(client: &Client, data: &[u8]) -> (Status, serde_json::Value) {
+        // Scripts asking for JSON need no session.
+        let (ct, body) = vks_publish_submit_body("", data);
+        let response = client
+            .post("/upload/submit")
+            .header(ct)
+            .header(Accept::JSON)
+            .body(&body[..])
+            .dispatch();
+        assert_eq!(response.content_type(), Some(ContentType::JSON));
+        let status = response.status();
+        let body = response.into_string().unwrap();
+        (status, serde_json::from_str(&body).unwrap())
+    }
+
+    fn vks_publish_submit_body(csrf_token: &str, data: &[u8]) -> (ContentType, Vec<u8>) {
         let ct = ContentType::with_params(
             "multipart",
             "form-data",
@@ -1421,10 +5145,10 @@ pub mod tests {
             ),
         );
 
-        let header = b"-----------------------------14733842173518794281682249499\r\n\
+        let csrf_field = b"-----------------------------14733842173518794281682249499\r\n\
               Content-Disposition: form-data; name=\"csrf\"\r\n\
-              \r\n\
-              \r\n\
+              \r\n";
+        let header = b"\r\n\
               -----------------------------14733842173518794281682249499\r\n\
               Content-Disposition: form-data; name=\"keytext\"; filename=\".k\"\r\n\
               Content-Type: application/octet-stream\r\n\
@@ -1432,14 +5156,12 @@ pub mod tests {
         let footer = b"\r\n-----------------------------14733842173518794281682249499--";
 
         let mut body = Vec::new();
+        body.extend_from_slice(csrf_field);
+        body.extend_from_slice(csrf_token.as_bytes());
         body.extend_from_slice(header);
         body.extend_from_slice(data);
         body.extend_from_slice(footer);
-        client
-            .post("/upload/submit")
-            .header(ct)
-            .body(&body[..])
-            .dispatch()
+        (ct, body)
     }
 
     fn vks_publish_shortcut_get_token(client: &Client, data: &[u8]) -> String {
@@ -1474,25 +5196,31 @@ pub mod tests {
     }
 
     fn vks_manage(client: &Client, search_term: &str) {
+        let session = csrf_session(client);
         let encoded = ::url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("csrf", session.value())
             .append_pair("search_term", search_term)
             .finish();
         let response = client
             .post("/manage")
             .header(ContentType::Form)
+            .cookie(session.clone())
             .body(encoded.as_bytes())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
     }
 
     fn vks_manage_delete(client: &Client, token: &str, address: &str) {
+        let session = csrf_session(client);
         let encoded = ::url::form_urlencoded::Serializer::new(String::new())
+            .append_pair("csrf", session.value())
             .append_pair("token", token)
             .append_pair("address", address)
             .finish();
         let response = client
             .post("/manage/unpublish")
             .header(ContentType::Form)
+            .cookie(session.clone())
             .body(encoded.as_bytes())
             .dispatch();
         assert_eq!(response.status(), Status::Ok);
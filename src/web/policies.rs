@@ -0,0 +1,88 @@
+use std::time::Duration;
+
+use rocket::figment::Figment;
+
+use crate::database::{KeyDatabase, PublicationPolicy, Reloadable};
+use crate::mail;
+use crate::rate_limiter::RateLimiter;
+use crate::web::limits::BodyLimits;
+use crate::web::quota::ApiQuotas;
+use crate::Result;
+
+/// The settings that can be reloaded from the configuration while the
+/// server is running: rate limits, mail domain lists, body limits,
+/// and the publication policy.
+///
+/// Everything else needs a restart.
+#[derive(Clone)]
+pub struct Policies {
+    body_limits: Reloadable<BodyLimits>,
+    api_quotas: ApiQuotas,
+    mail_rate_limit: Reloadable<Duration>,
+    domain_policy: Reloadable<mail::DomainPolicy>,
+    publication_policy: Reloadable<PublicationPolicy>,
+}
+
+impl Policies {
+    pub fn new(
+        body_limits: &Reloadable<BodyLimits>,
+        api_quotas: &ApiQuotas,
+        rate_limiter: &RateLimiter,
+        mail_service: &mail::Service,
+        db: &KeyDatabase,
+    ) -> Self {
+        Policies {
+            body_limits: body_limits.clone(),
+            api_quotas: api_quotas.clone(),
+            mail_rate_limit: rate_limiter.timeout(),
+            domain_policy: mail_service.domain_policy(),
+            publication_policy: db.publication_policy(),
+        }
+    }
+
+    /// Applies the policies from the given configuration.
+    ///
+    /// If any of them is invalid, none are changed.
+    pub fn reload(&self, config: &Figment) -> Result<()> {
+        let body_limits = super::configure_body_limits(config);
+        let (token_limits, anonymous_limit) = super::configure_api_rate_limits(config);
+        let mail_rate_limit = super::configure_mail_rate_limit(config)?;
+        let domain_policy = super::configure_domain_policy(config);
+        let publication_policy = super::configure_publication_policy(config)?;
+
+        self.body_limits.set(body_limits);
+        self.api_quotas.set_limits(token_limits, anonymous_limit);
+        self.mail_rate_limit
+            .set(Duration::from_secs(mail_rate_limit));
+        self.domain_policy.set(domain_policy);
+        self.publication_policy.set(publication_policy);
+        Ok(())
+    }
+
+    /// Reloads the policies from the configuration file whenever we
+    /// receive SIGHUP.
+    #[cfg(unix)]
+    pub fn reload_on_hangup(self) {
+        use rocket::tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                eprintln!("Not reloading policies on SIGHUP: {:?}", e);
+                return;
+            }
+        };
+        rocket::tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                match self.reload(&rocket::Config::figment()) {
+                    Ok(()) => println!("Reloaded policies"),
+                    Err(e) => eprintln!("Error reloading policies: {:?}", e),
+                }
+            }
+        });
+    }
+
+    /// There are no signals to reload on, use the admin API instead.
+    #[cfg(not(unix))]
+    pub fn reload_on_hangup(self) {}
+}
@@ -0,0 +1,89 @@
+//! Identity proofs, as used by Keyoxide.
+//!
+//! Key holders claim accounts and domains by adding notations to
+//! their self-signatures.  We only surface these claims: verifying
+//! them means fetching the claimed resources, which is left to
+//! clients.
+
+use rocket_i18n::I18n;
+use serde_json::json;
+
+use sequoia_openpgp::cert::amalgamation::ValidAmalgamation;
+use sequoia_openpgp::packet::Signature;
+use sequoia_openpgp::policy::StandardPolicy;
+use sequoia_openpgp::Cert;
+
+use std::convert::TryFrom;
+
+use crate::database::types::{Email, Fingerprint};
+use crate::database::{Database, KeyDatabase, Query};
+use crate::i18n_helpers::describe_query_error;
+use crate::web::MyResponse;
+
+/// Notation names of identity proofs, current and legacy.
+const PROOF_NOTATIONS: &[&str] = &["proof@ariadne.id", "proof@metacode.biz"];
+
+#[derive(Serialize, PartialEq)]
+pub struct IdentityProof {
+    pub uri: String,
+    /// The address of the user ID whose self-signature carries the
+    /// proof, if it is not on the key itself.
+    pub address: Option<String>,
+}
+
+fn proofs_in(sig: &Signature, address: Option<&Email>, proofs: &mut Vec<IdentityProof>) {
+    for name in PROOF_NOTATIONS {
+        for value in sig.notation(*name) {
+            let uri = match std::str::from_utf8(value) {
+                Ok(uri) => uri.to_string(),
+                Err(_) => continue,
+            };
+            let proof = IdentityProof {
+                uri,
+                address: address.map(|email| email.to_string()),
+            };
+            if !proofs.contains(&proof) {
+                proofs.push(proof);
+            }
+        }
+    }
+}
+
+/// Lists the identity proofs claimed in the current self-signatures
+/// of a key.
+pub fn identity_proofs(cert: &Cert) -> Vec<IdentityProof> {
+    let policy = StandardPolicy::new();
+    let vcert = match cert.with_policy(&policy, None) {
+        Ok(vcert) => vcert,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut proofs = Vec::new();
+    if let Ok(sig) = vcert.direct_key_signature() {
+        proofs_in(sig, None, &mut proofs);
+    }
+    for uid in vcert.userids() {
+        let email = Email::try_from(uid.userid()).ok();
+        proofs_in(uid.binding_signature(), email.as_ref(), &mut proofs);
+    }
+    proofs
+}
+
+#[get("/vks/v1/proofs/<fpr>")]
+pub fn vks_v1_proofs(db: &rocket::State<KeyDatabase>, i18n: I18n, fpr: String) -> MyResponse {
+    let query = match fpr.parse::<Fingerprint>() {
+        Ok(fpr) => Query::ByFingerprint(fpr),
+        Err(_) => return MyResponse::bad_request_plain("malformed fingerprint"),
+    };
+    let cert = match db.lookup(&query) {
+        Ok(Some(cert)) => cert,
+        Ok(None) => return MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
+        Err(e) => return MyResponse::ise(e),
+    };
+
+    MyResponse::json(json!({
+        "key_fpr": cert.fingerprint().to_hex(),
+        "verified": false,
+        "proofs": identity_proofs(&cert),
+    }))
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{Header, Method};
+use rocket::{Data, Request};
+use serde_json::json;
+
+use crate::mail_queue::unix_now;
+use crate::rate_limiter::RequestQuotas;
+use crate::web::bearer;
+use crate::web::client_ip::ClientIp;
+use crate::web::MyResponse;
+use crate::Result;
+
+/// How often the requests counted are saved.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Enforces request quotas on the machine-readable interfaces.
+///
+/// Clones share the quotas.
+#[derive(Clone)]
+pub struct ApiQuotas(Arc<RequestQuotas>);
+
+impl ApiQuotas {
+    pub fn new(quotas: RequestQuotas) -> Self {
+        ApiQuotas(Arc::new(quotas))
+    }
+
+    pub fn set_limits(&self, token_limits: HashMap<String, u32>, anonymous_limit: Option<u32>) {
+        self.0.set_limits(token_limits, anonymous_limit);
+    }
+
+    /// Restores the requests counted before a restart, if any.
+    pub fn load(&self, path: &Path) {
+        match self.0.load(path, unix_now()) {
+            Ok(()) => (),
+            Err(e) if is_not_found(&e) => (),
+            Err(e) => eprintln!("Error restoring API quotas: {:?}", e),
+        }
+    }
+
+    /// Saves the requests counted so far, so that restarting the
+    /// server doesn't reset the quotas.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        self.0.save(path)
+    }
+
+    fn is_request_api(&self, path: &str) -> bool {
+        path.starts_with("/vks/v1/") || path.starts_with("/pks/")
+    }
+}
+
+#[async_trait]
+impl Fairing for ApiQuotas {
+    fn info(&self) -> Info {
+        Info {
+            name: "API Quotas",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        if !self.0.is_enabled() || !self.is_request_api(request.uri().path().as_str()) {
+            return;
+        }
+
+        let token = bearer::token(request);
+        let client_ip = request
+            .guard::<ClientIp>()
+            .await
+            .succeeded()
+            .and_then(|ClientIp(client_ip)| client_ip);
+        if let Err(retry_after) = self.0.try_request(token, client_ip, unix_now()) {
+            request.set_uri(uri!(quota_exceeded(retry_after)));
+            request.set_method(Method::Get);
+        }
+    }
+}
+
+#[get("/quota/exceeded/<retry_after>")]
+pub fn quota_exceeded(retry_after: u64) -> MyResponse {
+    MyResponse::TooManyRequestsJson(
+        json!({
+            "error": "Too many requests, please try again later.",
+            "retry_after": retry_after,
+        }),
+        Header::new("Retry-After", retry_after.to_string()),
+    )
+}
+
+fn is_not_found(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<io::Error>()
+        .map_or(false, |e| e.kind() == io::ErrorKind::NotFound)
+}
@@ -0,0 +1,68 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::{Data, Request};
+
+use crate::web::maintenance;
+
+const READ_ONLY_MESSAGE: &str = "This server is read-only at the moment, please try again later.";
+
+/// Refuses requests that would change anything while switched on, e.g.
+/// on replicas, or while under attack.
+///
+/// Unlike maintenance mode, this is toggled at runtime, and pages
+/// that don't change anything are still served.  The admin API is
+/// exempt, so that it can be switched off again.
+#[derive(Clone)]
+pub struct ReadOnly(Arc<AtomicBool>);
+
+impl ReadOnly {
+    pub fn new(read_only: bool) -> Self {
+        ReadOnly(Arc::new(AtomicBool::new(read_only)))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, read_only: bool) {
+        self.0.store(read_only, Ordering::Relaxed);
+    }
+
+    fn is_write(path: &str, method: Method) -> bool {
+        if path.starts_with("/admin/") {
+            return false;
+        }
+        // Proceeding with a quick upload sends verification mail.
+        (method != Method::Get && method != Method::Head) || path.starts_with("/upload/")
+    }
+}
+
+#[async_trait]
+impl Fairing for ReadOnly {
+    fn info(&self) -> Info {
+        Info {
+            name: "Read-only Mode",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        let path = request.uri().path().as_str().to_owned();
+        if !self.is_enabled() || !Self::is_write(&path, request.method()) {
+            return;
+        }
+
+        let message = READ_ONLY_MESSAGE.to_owned();
+        if path.starts_with("/vks/v1/") || path.starts_with("/domain/v1/") {
+            request.set_uri(uri!(maintenance::maintenance_error_json(message)));
+        } else if path.starts_with("/pks/") || path == "/" {
+            request.set_uri(uri!(maintenance::maintenance_error_plain(message)));
+        } else {
+            request.set_uri(uri!(maintenance::maintenance_error_web(message)));
+        }
+        request.set_method(Method::Get);
+    }
+}
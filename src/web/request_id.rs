@@ -0,0 +1,44 @@
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Header;
+use rocket::{Data, Request, Response};
+use uuid::Uuid;
+
+/// Identifies a request in the server logs.
+///
+/// It is sent in the `X-Request-Id` header of every response, and
+/// shown on error pages, so that reports from users can be matched
+/// with the logs.
+pub struct RequestId(String);
+
+impl RequestId {
+    /// Returns the id of the given request, assigning one if needed.
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r str {
+        &request
+            .local_cache(|| RequestId(Uuid::new_v4().to_simple().to_string()))
+            .0
+    }
+}
+
+/// Assigns ids to requests, and returns them to clients.
+pub struct RequestIds;
+
+#[async_trait]
+impl Fairing for RequestIds {
+    fn info(&self) -> Info {
+        Info {
+            name: "Request IDs",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        RequestId::of(request);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new(
+            "X-Request-Id",
+            RequestId::of(request).to_owned(),
+        ));
+    }
+}
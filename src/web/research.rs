@@ -0,0 +1,174 @@
+//! Lets researchers enumerate the published keys, e.g. for
+//! measurement studies, without scraping.
+//!
+//! Access requires one of the tokens handed out by the operator.  Only
+//! fingerprints and metadata about the primary key are returned, never
+//! user IDs, so the API can't be used to harvest addresses.
+//!
+//! Organizations can also follow how many addresses in their domains
+//! were verified.  Domains with fewer than a threshold of them are left
+//! out, so that the counts don't reveal individuals.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use rocket::http::Status;
+use rocket::outcome::Outcome;
+use rocket::request;
+use serde_json::json;
+
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::Cert;
+
+use crate::database::types::Fingerprint;
+use crate::database::{Database, KeyDatabase};
+use crate::web::{self, bearer, MyResponse};
+use crate::Result;
+
+/// Keys returned by a single request, unless the client asks for
+/// fewer.
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+/// How long the counts per domain are reused, since counting walks
+/// the whole database.
+const DOMAIN_COUNTS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The secrets that authenticate researchers.
+///
+/// If no tokens are configured, the research API is disabled.
+pub struct ResearchTokens(Vec<String>);
+
+impl ResearchTokens {
+    pub fn new(tokens: Vec<String>) -> Self {
+        ResearchTokens(tokens.into_iter().filter(|t| !t.is_empty()).collect())
+    }
+}
+
+/// Counts of verified addresses per domain.
+pub struct DomainCounts {
+    /// Domains with fewer addresses are left out.
+    threshold: u64,
+    cache: Mutex<Option<(Instant, HashMap<String, u64>)>>,
+}
+
+impl DomainCounts {
+    pub fn new(threshold: u64) -> Self {
+        DomainCounts {
+            threshold,
+            cache: Mutex::new(None),
+        }
+    }
+
+    fn counts(&self, db: &KeyDatabase) -> Result<BTreeMap<String, u64>> {
+        let mut cache = self.cache.lock().unwrap();
+        let is_stale = cache.as_ref().map_or(true, |(counted, _)| {
+            counted.elapsed() >= DOMAIN_COUNTS_MAX_AGE
+        });
+        if is_stale {
+            *cache = Some((Instant::now(), db.count_published_by_domain()?));
+        }
+        let (_, counts) = cache.as_ref().expect("counted above");
+        Ok(counts
+            .iter()
+            .filter(|(_, count)| **count >= self.threshold)
+            .map(|(domain, count)| (domain.clone(), *count))
+            .collect())
+    }
+}
+
+/// Request guard for requests carrying a research token as bearer
+/// token.
+pub struct Researcher;
+
+#[async_trait]
+impl<'r> request::FromRequest<'r> for Researcher {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r request::Request<'_>,
+    ) -> request::Outcome<Self, Self::Error> {
+        let tokens = match request.rocket().state::<ResearchTokens>() {
+            Some(ResearchTokens(tokens)) if !tokens.is_empty() => tokens,
+            _ => return Outcome::Failure((Status::NotFound, ())),
+        };
+        let presented = bearer::token(request).unwrap_or("");
+        let valid = tokens.iter().any(|token| bearer::matches(presented, token));
+
+        if valid {
+            Outcome::Success(Researcher)
+        } else {
+            Outcome::Failure((Status::Unauthorized, ()))
+        }
+    }
+}
+
+/// Lists published keys in order of their fingerprints.  Pass the
+/// `next_cursor` of a response as `cursor` to get the next page.
+#[get("/research/v1/keys?<cursor>&<limit>")]
+pub fn keys(
+    _researcher: Researcher,
+    db: &rocket::State<KeyDatabase>,
+    cursor: Option<String>,
+    limit: Option<usize>,
+) -> MyResponse {
+    let cursor = match cursor.map(|cursor| cursor.parse::<Fingerprint>()) {
+        Some(Ok(fpr)) => Some(fpr),
+        Some(Err(_)) => return MyResponse::bad_request_json("malformed cursor"),
+        None => None,
+    };
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    let fprs = match db.published_fprs(cursor.as_ref(), limit) {
+        Ok(fprs) => fprs,
+        Err(e) => return MyResponse::ise(e),
+    };
+    let next_cursor = if fprs.len() == limit {
+        fprs.last().map(|fpr| fpr.to_string())
+    } else {
+        None
+    };
+    let keys: Vec<_> = fprs
+        .iter()
+        .filter_map(|fpr| {
+            let armored = db.by_primary_fpr(fpr)?;
+            // Keys can be removed while we list them.
+            let cert = Cert::from_bytes(armored.as_bytes()).ok()?;
+            let primary = cert.primary_key();
+            let created = primary
+                .creation_time()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |created| created.as_secs());
+            Some(json!({
+                "fingerprint": fpr.to_string(),
+                "algorithm": primary.pk_algo().to_string(),
+                "bits": primary.mpis().bits(),
+                "created": created,
+                "size": armored.len(),
+            }))
+        })
+        .collect();
+
+    MyResponse::json(json!({
+        "keys": keys,
+        "next_cursor": next_cursor,
+    }))
+}
+
+/// Returns the number of verified addresses per domain, for domains
+/// with at least the threshold of them.
+#[get("/research/v1/domains")]
+pub fn domains(
+    _researcher: Researcher,
+    db: &rocket::State<KeyDatabase>,
+    domain_counts: &rocket::State<DomainCounts>,
+) -> MyResponse {
+    match web::blocking(|| domain_counts.counts(db)) {
+        Ok(counts) => MyResponse::json(json!({
+            "threshold": domain_counts.threshold,
+            "domains": counts,
+        })),
+        Err(e) => MyResponse::ise(e),
+    }
+}
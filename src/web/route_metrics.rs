@@ -0,0 +1,39 @@
+use std::time::Instant;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Data, Request, Response};
+
+use crate::counters;
+
+/// Times requests, and counts responses by status class, per route.
+///
+/// Requests that fairings redirected, e.g. because of quotas, are
+/// counted for the route they were redirected to.  Those that match
+/// no route are counted as "unmatched".
+pub struct RouteMetrics;
+
+/// When the request arrived.
+struct RequestStart(Instant);
+
+#[async_trait]
+impl Fairing for RouteMetrics {
+    fn info(&self) -> Info {
+        Info {
+            name: "Route Metrics",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let start = request.local_cache(|| RequestStart(Instant::now())).0;
+        let route = request
+            .route()
+            .map(|route| route.uri.to_string())
+            .unwrap_or_else(|| "unmatched".to_owned());
+        counters::observe_response(&route, response.status().code, start.elapsed());
+    }
+}
@@ -0,0 +1,140 @@
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::{Data, Request, Response};
+use serde_json::json;
+
+use crate::database::Query;
+use crate::web::request_id::RequestId;
+
+/// Where the time of a request went, for the slow request log.
+///
+/// Handlers get it as a request guard, and time their database calls
+/// with it.
+pub struct RequestTimings {
+    start: Instant,
+    query: Mutex<Option<&'static str>>,
+    db: Mutex<Vec<(&'static str, Duration)>>,
+}
+
+impl RequestTimings {
+    fn new() -> Self {
+        RequestTimings {
+            start: Instant::now(),
+            query: Mutex::new(None),
+            db: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Records the type of key lookup.
+    pub fn set_query(&self, query: &Query) {
+        let query_type = match query {
+            Query::ByFingerprint(_) => "by-fingerprint",
+            Query::ByKeyID(_) => "by-keyid",
+            Query::ByEmail(_) => "by-email",
+            Query::InvalidShort() | Query::Invalid() => "invalid",
+        };
+        *self.query.lock().unwrap() = Some(query_type);
+    }
+
+    /// Runs a database call, recording how long it took.
+    pub fn db<T>(&self, call: &'static str, f: impl FnOnce() -> T) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.db.lock().unwrap().push((call, start.elapsed()));
+        result
+    }
+
+    /// Describes the request, without anything identifying the keys
+    /// or addresses it was about.
+    pub fn report(&self, request: &Request<'_>, status: u16) -> serde_json::Value {
+        let route = request
+            .route()
+            .map(|route| route.uri.to_string())
+            .unwrap_or_else(|| "unmatched".to_owned());
+        let db: Vec<_> = self
+            .db
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(call, duration)| json!({ "call": call, "ms": as_millis(*duration) }))
+            .collect();
+        json!({
+            "request_id": RequestId::of(request),
+            "method": request.method().as_str(),
+            "route": route,
+            "query": *self.query.lock().unwrap(),
+            "status": status,
+            "ms": as_millis(self.start.elapsed()),
+            "db": db,
+        })
+    }
+}
+
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[async_trait]
+impl<'r> FromRequest<'r> for &'r RequestTimings {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        Outcome::Success(request.local_cache(RequestTimings::new))
+    }
+}
+
+/// Logs requests that take longer than the threshold.
+pub struct SlowRequests {
+    pub threshold: Duration,
+}
+
+#[async_trait]
+impl Fairing for SlowRequests {
+    fn info(&self) -> Info {
+        Info {
+            name: "Slow Requests",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(RequestTimings::new);
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let timings = request.local_cache(RequestTimings::new);
+        if timings.start.elapsed() >= self.threshold {
+            eprintln!(
+                "Slow request: {}",
+                timings.report(request, response.status().code)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::web::tests::*;
+
+    #[test]
+    fn report() {
+        let (_tmpdir, client) = client().unwrap();
+        let request = client.get("/pks/lookup?op=get&search=0x0123456789ABCDEF");
+        let timings = RequestTimings::new();
+        timings.set_query(&Query::ByKeyID("0123456789ABCDEF".parse().unwrap()));
+        timings.db("lookup", || ());
+
+        let report = timings.report(request.inner(), 404);
+        assert_eq!(report["method"], "GET");
+        assert_eq!(report["query"], "by-keyid");
+        assert_eq!(report["status"], 404);
+        assert_eq!(report["db"][0]["call"], "lookup");
+        assert!(!report.to_string().contains("0123456789ABCDEF"));
+    }
+}
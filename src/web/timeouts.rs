@@ -0,0 +1,92 @@
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::outcome::Outcome;
+use rocket::request::{self, FromRequest};
+use rocket::tokio::time;
+use rocket::{Data, Request};
+
+use crate::Result;
+
+/// Timeouts for reading requests, in seconds.
+///
+/// Timeouts for reading headers and writing responses are left to
+/// the reverse proxy, as is closing idle connections, which Rocket's
+/// `keep_alive` setting covers otherwise.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// Reading the body of an upload.
+    pub read: u64,
+    /// Everything up to reading the body, from the request's arrival.
+    pub request: u64,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            read: 30,
+            request: 60,
+        }
+    }
+}
+
+/// When the request arrived.
+struct RequestStart(Instant);
+
+#[async_trait]
+impl Fairing for Timeouts {
+    fn info(&self) -> Info {
+        Info {
+            name: "Timeouts",
+            kind: Kind::Request,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        request.local_cache(|| RequestStart(Instant::now()));
+    }
+}
+
+/// Request guard for the point in time by which a handler must have
+/// read the request body.
+#[derive(Clone, Copy)]
+pub struct Deadline(Instant);
+
+#[async_trait]
+impl<'r> FromRequest<'r> for Deadline {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> request::Outcome<Self, Self::Error> {
+        let timeouts = request
+            .rocket()
+            .state::<Timeouts>()
+            .copied()
+            .unwrap_or_default();
+        let start = request.local_cache(|| RequestStart(Instant::now())).0;
+        let now = Instant::now();
+
+        let read_deadline = now + Duration::from_secs(timeouts.read);
+        let request_deadline = start + Duration::from_secs(timeouts.request);
+        Outcome::Success(Deadline(read_deadline.min(request_deadline)))
+    }
+}
+
+impl Deadline {
+    /// Reads a request body, giving up at the deadline.
+    pub async fn read<T, E>(
+        self,
+        body: impl Future<Output = std::result::Result<T, E>>,
+    ) -> Result<T>
+    where
+        E: Into<anyhow::Error>,
+    {
+        if Instant::now() >= self.0 {
+            return Err(anyhow!("Timed out reading the request"));
+        }
+        match time::timeout_at(self.0.into(), body).await {
+            Ok(result) => result.map_err(Into::into),
+            Err(_) => Err(anyhow!("Timed out reading the request")),
+        }
+    }
+}
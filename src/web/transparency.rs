@@ -0,0 +1,160 @@
+use rocket_i18n::I18n;
+use serde_json::json;
+
+use sequoia_openpgp::armor;
+use sequoia_openpgp::packet::signature::SignatureBuilder;
+use sequoia_openpgp::serialize::Serialize;
+use sequoia_openpgp::types::SignatureType;
+use sequoia_openpgp::Packet;
+
+use crate::database::types::Fingerprint;
+use crate::database::{Database, KeyDatabase, Query};
+use crate::i18n_helpers::describe_query_error;
+use crate::service_key::ServiceKey;
+use crate::web::MyResponse;
+use crate::Result;
+
+/// Entries returned by a single request for log entries.
+const MAX_ENTRIES: usize = 1000;
+
+/// Signs tree heads of the transparency log.
+pub struct TreeHeadSigner {
+    key: Option<Box<dyn ServiceKey>>,
+}
+
+impl TreeHeadSigner {
+    pub fn disabled() -> Self {
+        TreeHeadSigner { key: None }
+    }
+
+    pub fn new(key: impl ServiceKey + 'static) -> Self {
+        TreeHeadSigner {
+            key: Some(Box::new(key)),
+        }
+    }
+
+    /// Returns the fingerprint of the signing key, and an armored
+    /// detached signature over the message.
+    pub fn sign(&self, message: &str) -> Result<Option<(String, String)>> {
+        let key = match self.key {
+            Some(ref key) => key,
+            None => return Ok(None),
+        };
+        let mut signer = key.signer()?;
+        let sig = SignatureBuilder::new(SignatureType::Binary)
+            .sign_message(&mut *signer, message.as_bytes())?;
+
+        let mut writer = armor::Writer::new(Vec::new(), armor::Kind::Signature)?;
+        Packet::from(sig).serialize(&mut writer)?;
+        let armored = String::from_utf8(writer.finalize()?)?;
+        Ok(Some((key.public().fingerprint().to_hex(), armored)))
+    }
+}
+
+#[get("/vks/v1/transparency/tree-head")]
+pub fn tree_head(
+    db: &rocket::State<KeyDatabase>,
+    signer: &rocket::State<TreeHeadSigner>,
+) -> MyResponse {
+    let head = db.transparency_tree_head();
+    match signer.sign(&head.to_signed_message()) {
+        Ok(Some((signer, signature))) => MyResponse::json(json!({
+            "tree_size": head.tree_size,
+            "root_hash": head.root_hash,
+            "timestamp": head.timestamp,
+            "signer": signer,
+            "signature": signature,
+        })),
+        Ok(None) => MyResponse::json(json!(head)),
+        Err(e) => MyResponse::ise(e),
+    }
+}
+
+#[get("/vks/v1/transparency/proof/<fpr>?<tree_size>")]
+pub fn inclusion_proof(
+    db: &rocket::State<KeyDatabase>,
+    i18n: I18n,
+    fpr: String,
+    tree_size: Option<usize>,
+) -> MyResponse {
+    let fpr = match fpr.parse::<Fingerprint>() {
+        Ok(fpr) => fpr,
+        Err(_) => return MyResponse::bad_request_plain("malformed fingerprint"),
+    };
+    match db.transparency_inclusion_proof(&fpr, tree_size) {
+        Some(proof) => MyResponse::json(json!(proof)),
+        None => {
+            MyResponse::not_found_plain(describe_query_error(&i18n, &Query::ByFingerprint(fpr)))
+        }
+    }
+}
+
+/// The signed tree head, in the shape of RFC 6962's `get-sth`.
+#[get("/log/v1/sth")]
+pub fn sth(db: &rocket::State<KeyDatabase>, signer: &rocket::State<TreeHeadSigner>) -> MyResponse {
+    let head = db.transparency_tree_head();
+    let mut sth = json!({
+        "tree_size": head.tree_size,
+        "timestamp": head.timestamp,
+        "sha256_root_hash": head.root_hash,
+    });
+    match signer.sign(&head.to_signed_message()) {
+        Ok(Some((signer, signature))) => {
+            sth["signer"] = json!(signer);
+            sth["tree_head_signature"] = json!(signature);
+            MyResponse::json(sth)
+        }
+        Ok(None) => MyResponse::json(sth),
+        Err(e) => MyResponse::ise(e),
+    }
+}
+
+/// Proves inclusion of a leaf, like RFC 6962's `get-proof-by-hash`.
+#[get("/log/v1/inclusion/<fpr>/<leaf_hash>?<tree_size>")]
+pub fn inclusion_proof_by_hash(
+    db: &rocket::State<KeyDatabase>,
+    fpr: String,
+    leaf_hash: String,
+    tree_size: Option<usize>,
+) -> MyResponse {
+    let fpr = match fpr.parse::<Fingerprint>() {
+        Ok(fpr) => fpr,
+        Err(_) => return MyResponse::bad_request_plain("malformed fingerprint"),
+    };
+    match db.transparency_inclusion_proof_by_hash(&fpr, &leaf_hash, tree_size) {
+        Some(proof) => MyResponse::json(json!(proof)),
+        None => MyResponse::not_found_plain("no such leaf in the tree"),
+    }
+}
+
+/// Proves that the log was only appended to, like RFC 6962's
+/// `get-sth-consistency`.
+#[get("/log/v1/consistency/<first>/<second>")]
+pub fn consistency_proof(
+    db: &rocket::State<KeyDatabase>,
+    first: usize,
+    second: usize,
+) -> MyResponse {
+    match db.transparency_consistency_proof(first, second) {
+        Some(proof) => MyResponse::json(json!(proof)),
+        None => MyResponse::bad_request_plain(
+            "tree sizes must be ascending and at most the current size",
+        ),
+    }
+}
+
+#[get("/vks/v1/transparency/entries?<start>&<end>")]
+pub fn entries(db: &rocket::State<KeyDatabase>, start: usize, end: usize) -> MyResponse {
+    if end < start || end - start > MAX_ENTRIES {
+        return MyResponse::bad_request_plain(format!(
+            "at most {} entries can be requested at once",
+            MAX_ENTRIES
+        ));
+    }
+    let log = db.transparency_log();
+    let entries = log
+        .leaves()
+        .get(start..end.min(log.len()))
+        .unwrap_or_default();
+    MyResponse::json(json!({ "start": start, "entries": entries }))
+}
@@ -0,0 +1,181 @@
+//! Counts lookups by transport and result, for the stats page and the
+//! metrics.
+//!
+//! Nothing about the client or the query is kept, only how many
+//! lookups there were per day, so the counts can be published.  Days
+//! older than `KEEP_DAYS` are dropped.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::Method;
+use rocket::{Data, Request, Response};
+
+use crate::counters;
+use crate::mail_queue::unix_now;
+use crate::Result;
+
+/// How often the counts are saved.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+const DAY: u64 = 24 * 60 * 60;
+/// Days kept, including the current one.
+const KEEP_DAYS: u64 = 30;
+
+/// The number of lookups on one day, the start of which is given in
+/// seconds since the epoch.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bucket {
+    pub day: u64,
+    pub transport: String,
+    pub result: String,
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct Total {
+    pub transport: String,
+    pub result: String,
+    pub count: u64,
+}
+
+/// Clones share the counts.
+#[derive(Clone, Default)]
+pub struct UsageStats(Arc<Mutex<BTreeMap<(u64, String, String), u64>>>);
+
+impl UsageStats {
+    pub fn record(&self, transport: &str, result: &str, now: u64) {
+        counters::inc_lookup(transport, result);
+        let day = now - now % DAY;
+        let mut counts = self.0.lock().unwrap();
+        *counts
+            .entry((day, transport.to_owned(), result.to_owned()))
+            .or_default() += 1;
+    }
+
+    /// Returns the counts of the days kept.
+    pub fn buckets(&self, now: u64) -> Vec<Bucket> {
+        let oldest = (now - now % DAY).saturating_sub((KEEP_DAYS - 1) * DAY);
+        let mut counts = self.0.lock().unwrap();
+        counts.retain(|(day, _, _), _| *day >= oldest);
+        counts
+            .iter()
+            .map(|((day, transport, result), count)| Bucket {
+                day: *day,
+                transport: transport.clone(),
+                result: result.clone(),
+                count: *count,
+            })
+            .collect()
+    }
+
+    /// Sums up the counts of the days kept, per transport and result.
+    pub fn totals(&self, now: u64) -> Vec<Total> {
+        let mut totals: BTreeMap<(String, String), u64> = BTreeMap::new();
+        for bucket in self.buckets(now) {
+            *totals.entry((bucket.transport, bucket.result)).or_default() += bucket.count;
+        }
+        totals
+            .into_iter()
+            .map(|((transport, result), count)| Total {
+                transport,
+                result,
+                count,
+            })
+            .collect()
+    }
+
+    /// Restores the counts saved before a restart, if any.
+    pub fn load(&self, path: &Path) {
+        let buckets: Vec<Bucket> = match fs::read(path) {
+            Ok(data) => match serde_json::from_slice(&data) {
+                Ok(buckets) => buckets,
+                Err(e) => {
+                    eprintln!("Error restoring usage stats: {:?}", e);
+                    return;
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+            Err(e) => {
+                eprintln!("Error restoring usage stats: {:?}", e);
+                return;
+            }
+        };
+        let mut counts = self.0.lock().unwrap();
+        for bucket in buckets {
+            *counts
+                .entry((bucket.day, bucket.transport, bucket.result))
+                .or_default() += bucket.count;
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec(&self.buckets(unix_now()))?;
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+/// The transport of the lookup, if the request is one.
+struct Lookup(Option<&'static str>);
+
+#[async_trait]
+impl Fairing for UsageStats {
+    fn info(&self) -> Info {
+        Info {
+            name: "Usage Stats",
+            kind: Kind::Request | Kind::Response,
+        }
+    }
+
+    async fn on_request(&self, request: &mut Request<'_>, _: &mut Data<'_>) {
+        // Remember the lookup before other fairings rewrite the
+        // request, e.g. because of quotas.
+        let transport = transport(request.method(), request.uri().path().as_str());
+        request.local_cache(|| Lookup(transport));
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        if let Lookup(Some(transport)) = request.local_cache(|| Lookup(None)) {
+            self.record(transport, result(response.status().code), unix_now());
+        }
+    }
+}
+
+fn transport(method: Method, path: &str) -> Option<&'static str> {
+    if method != Method::Get && method != Method::Head {
+        return None;
+    }
+    if path == "/pks/lookup" {
+        Some("hkp")
+    } else if ["by-fingerprint", "by-keyid", "by-email"]
+        .iter()
+        .any(|kind| path.starts_with(&format!("/vks/v1/{}/", kind)))
+    {
+        Some("vks")
+    } else if path.starts_with("/.well-known/openpgpkey/") && path.contains("/hu/") {
+        Some("wkd")
+    } else if path == "/search" {
+        Some("web")
+    } else {
+        None
+    }
+}
+
+fn result(status: u16) -> &'static str {
+    match status {
+        200..=399 => "found",
+        404 => "not-found",
+        410 => "removed",
+        429 => "rate-limited",
+        400..=499 => "invalid",
+        _ => "error",
+    }
+}
@@ -3,11 +3,13 @@ use crate::Result;
 use crate::counters;
 use crate::database::types::{Email, Fingerprint};
 use crate::database::{
-    Database, EmailAddressStatus, ImportResult, KeyDatabase, StatefulTokens, TpkStatus,
+    BannedKey, Database, EmailAddressStatus, ImportResult, InsufficientSpace, KeyDatabase,
+    MalformedKey, MergeDiff, OversizedUnhashedArea, Query, StatefulTokens, TpkStatus, UidPolicy,
 };
 use crate::mail;
 use crate::rate_limiter::RateLimiter;
 use crate::tokens::{self, StatelessSerializable};
+use crate::upload_cache::{CachedUpload, UploadCache};
 use crate::web::RequestOrigin;
 
 use gettext_macros::i18n;
@@ -20,7 +22,7 @@ use sequoia_openpgp::Cert;
 
 use std::collections::HashMap;
 use std::convert::TryFrom;
-use std::io::Read;
+use std::io::{Cursor, Read};
 
 use self::response::*;
 
@@ -39,6 +41,7 @@ pub mod request {
 
 pub mod response {
     use crate::database::types::Email;
+    use crate::database::MergeDiff;
 
     #[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
     pub enum EmailStatus {
@@ -52,6 +55,62 @@ pub mod response {
         Revoked,
     }
 
+    /// Why an upload was rejected.
+    ///
+    /// This lets clients show their users something more specific
+    /// than the (translated) error message.
+    #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+    #[serde(rename_all = "kebab-case")]
+    pub enum RejectionCode {
+        /// The key data could not be parsed.
+        Unparseable,
+        /// The upload contained secret key material.
+        SecretKey,
+        /// The upload contained no key at all.
+        NoKey,
+        /// The key has no valid self-signature on any user ID or
+        /// subkey.
+        NoValidSelfsig,
+        /// The upload exceeds the size limit.
+        TooLarge,
+        /// The key's fingerprint is banned from this server.
+        BannedFingerprint,
+        /// An address of the key is in a banned domain.
+        BannedDomain,
+        /// The key has been flooded with junk data.
+        Flooded,
+        /// The upload session expired, or the token is invalid.
+        InvalidToken,
+        /// A verification mail could not be sent.
+        MailFailed,
+        /// The domain of an address does not accept mail.
+        NoMailDomain,
+        /// This server is out of space to store the key.
+        InsufficientStorage,
+        /// Something went wrong on our side.
+        Internal,
+    }
+
+    impl RejectionCode {
+        pub fn as_str(&self) -> &'static str {
+            match self {
+                RejectionCode::Unparseable => "unparseable",
+                RejectionCode::SecretKey => "secret-key",
+                RejectionCode::NoKey => "no-key",
+                RejectionCode::NoValidSelfsig => "no-valid-selfsig",
+                RejectionCode::TooLarge => "too-large",
+                RejectionCode::BannedFingerprint => "banned-fingerprint",
+                RejectionCode::BannedDomain => "banned-domain",
+                RejectionCode::Flooded => "flooded",
+                RejectionCode::InvalidToken => "invalid-token",
+                RejectionCode::MailFailed => "mail-failed",
+                RejectionCode::NoMailDomain => "no-mail-domain",
+                RejectionCode::InsufficientStorage => "insufficient-storage",
+                RejectionCode::Internal => "internal",
+            }
+        }
+    }
+
     use std::collections::HashMap;
 
     pub enum UploadResponse {
@@ -61,18 +120,22 @@ pub mod response {
             is_revoked: bool,
             status: HashMap<String, EmailStatus>,
             count_unparsed: usize,
+            dropped_uids: Vec<String>,
+            warnings: Vec<&'static str>,
+            /// What the upload changed, if anything was uploaded.
+            merge_diff: Option<MergeDiff>,
             is_new_key: bool,
             primary_uid: Option<Email>,
         },
         OkMulti {
             key_fprs: Vec<String>,
         },
-        Error(String),
+        Error(RejectionCode, String),
     }
 
     impl UploadResponse {
-        pub fn err(err: impl Into<String>) -> Self {
-            UploadResponse::Error(err.into())
+        pub fn err(code: RejectionCode, err: impl Into<String>) -> Self {
+            UploadResponse::Error(code, err.into())
         }
     }
 
@@ -102,14 +165,36 @@ pub fn process_key(
     i18n: &I18n,
     tokens_stateless: &tokens::Service,
     rate_limiter: &RateLimiter,
-    reader: impl Read + Send + Sync,
+    upload_cache: &UploadCache,
+    uploader: Option<&str>,
+    mut reader: impl Read + Send + Sync,
 ) -> response::UploadResponse {
+    let mut data = Vec::new();
+    if reader.read_to_end(&mut data).is_err() {
+        return UploadResponse::err(
+            RejectionCode::Unparseable,
+            i18n!(i18n.catalog, "Parsing of key data failed."),
+        );
+    }
+
+    let hash = UploadCache::hash(&data);
+    if let Some(response) =
+        process_key_cached(db, tokens_stateless, rate_limiter, upload_cache.get(&hash))
+    {
+        return response;
+    }
+
     // First, parse all Certs and error out if one fails.
-    let parser = match PacketParserBuilder::from_reader(reader)
+    let parser = match PacketParserBuilder::from_reader(Cursor::new(data))
         .and_then(|ppb| ppb.dearmor(Dearmor::Auto(ReaderMode::VeryTolerant)).build())
     {
         Ok(ppr) => CertParser::from(ppr),
-        Err(_) => return UploadResponse::err(i18n!(i18n.catalog, "Parsing of key data failed.")),
+        Err(_) => {
+            return UploadResponse::err(
+                RejectionCode::Unparseable,
+                i18n!(i18n.catalog, "Parsing of key data failed."),
+            )
+        }
     };
     let mut tpks = Vec::new();
     for tpk in parser {
@@ -117,29 +202,84 @@ pub fn process_key(
             Ok(t) => {
                 if t.is_tsk() {
                     counters::inc_key_upload("secret");
-                    return UploadResponse::err(i18n!(
-                        i18n.catalog,
-                        "Whoops, please don't upload secret keys!"
-                    ));
+                    return UploadResponse::err(
+                        RejectionCode::SecretKey,
+                        i18n!(i18n.catalog, "Whoops, please don't upload secret keys!"),
+                    );
                 }
                 t
             }
             Err(_) => {
-                return UploadResponse::err(i18n!(i18n.catalog, "Parsing of key data failed."));
+                return UploadResponse::err(
+                    RejectionCode::Unparseable,
+                    i18n!(i18n.catalog, "Parsing of key data failed."),
+                );
             }
         });
     }
 
     match tpks.len() {
-        0 => UploadResponse::err(i18n!(i18n.catalog, "No key uploaded.")),
+        0 => UploadResponse::err(
+            RejectionCode::NoKey,
+            i18n!(i18n.catalog, "No key uploaded."),
+        ),
         1 => process_key_single(
             db,
             i18n,
             tokens_stateless,
             rate_limiter,
+            upload_cache,
+            uploader,
+            hash,
             tpks.into_iter().next().unwrap(),
         ),
-        _ => process_key_multiple(db, tpks),
+        _ => process_key_multiple(db, upload_cache, uploader, hash, tpks),
+    }
+}
+
+/// Answers an upload we have merged recently, with the current state
+/// of the key.
+fn process_key_cached(
+    db: &KeyDatabase,
+    tokens_stateless: &tokens::Service,
+    rate_limiter: &RateLimiter,
+    cached: Option<CachedUpload>,
+) -> Option<response::UploadResponse> {
+    match cached? {
+        CachedUpload::Single { fpr, addresses } => {
+            // If the key is gone, e.g. because it was banned, the
+            // upload is processed again.
+            let tpk_status = db.get_tpk_status(&fpr, &addresses).ok()?;
+            counters::inc_key_upload("cached");
+            let verify_state = VerifyTpkState {
+                fpr,
+                addresses,
+                requested: vec![],
+            };
+            let token = tokens_stateless.create(&verify_state);
+            Some(show_upload_verify(
+                rate_limiter,
+                token,
+                tpk_status,
+                verify_state,
+                false,
+                Some(MergeDiff::default()),
+            ))
+        }
+        CachedUpload::Multiple { key_fprs } => {
+            // Likewise if any of the keys is gone.
+            let all_present = key_fprs.iter().all(|fpr| {
+                fpr.parse()
+                    .ok()
+                    .and_then(|fpr| db.lookup_primary_fingerprint(&Query::ByFingerprint(fpr)))
+                    .is_some()
+            });
+            if !all_present {
+                return None;
+            }
+            counters::inc_key_upload("cached");
+            Some(response::UploadResponse::OkMulti { key_fprs })
+        }
     }
 }
 
@@ -154,13 +294,35 @@ fn log_db_merge(import_result: Result<ImportResult>) -> Result<ImportResult> {
     import_result
 }
 
-fn process_key_multiple(db: &KeyDatabase, tpks: Vec<Cert>) -> response::UploadResponse {
+fn process_key_multiple(
+    db: &KeyDatabase,
+    upload_cache: &UploadCache,
+    uploader: Option<&str>,
+    hash: Vec<u8>,
+    tpks: Vec<Cert>,
+) -> response::UploadResponse {
+    let count = tpks.len();
     let key_fprs: Vec<_> = tpks
         .into_iter()
         .flat_map(|tpk| Fingerprint::try_from(tpk.fingerprint()).map(|fpr| (fpr, tpk)))
-        .flat_map(|(fpr, tpk)| log_db_merge(db.merge(tpk)).map(|_| fpr.to_string()))
+        .flat_map(|(fpr, tpk)| {
+            let import_result = db
+                .merge_with_diff(tpk, uploader)
+                .map(|(import_result, _)| import_result);
+            log_db_merge(import_result).map(|_| fpr.to_string())
+        })
         .collect();
 
+    // Only remember uploads that were merged completely.
+    if key_fprs.len() == count {
+        upload_cache.insert(
+            hash,
+            CachedUpload::Multiple {
+                key_fprs: key_fprs.clone(),
+            },
+        );
+    }
+
     response::UploadResponse::OkMulti { key_fprs }
 }
 
@@ -169,16 +331,56 @@ fn process_key_single(
     i18n: &I18n,
     tokens_stateless: &tokens::Service,
     rate_limiter: &RateLimiter,
+    upload_cache: &UploadCache,
+    uploader: Option<&str>,
+    hash: Vec<u8>,
     tpk: Cert,
 ) -> response::UploadResponse {
     let fp = Fingerprint::try_from(tpk.fingerprint()).unwrap();
 
-    let (tpk_status, is_new_key) = match log_db_merge(db.merge(tpk)) {
+    let (import_result, merge_diff) = match db.merge_with_diff(tpk, uploader) {
+        Ok((import_result, merge_diff)) => (Ok(import_result), Some(merge_diff)),
+        Err(e) => (Err(e), None),
+    };
+    let (tpk_status, is_new_key) = match log_db_merge(import_result) {
         Ok(ImportResult::New(tpk_status)) => (tpk_status, true),
         Ok(ImportResult::Updated(tpk_status)) => (tpk_status, false),
         Ok(ImportResult::Unchanged(tpk_status)) => (tpk_status, false),
+        Err(e) if e.downcast_ref::<OversizedUnhashedArea>().is_some() => {
+            return UploadResponse::err(
+                RejectionCode::Flooded,
+                i18n!(
+                    i18n.catalog,
+                    "This key contains signatures with too much unsigned data."
+                ),
+            )
+        }
+        Err(e) if e.downcast_ref::<BannedKey>().is_some() => {
+            return UploadResponse::err(
+                RejectionCode::BannedFingerprint,
+                i18n!(i18n.catalog, "This key has been banned from this server."),
+            )
+        }
+        Err(e) if e.downcast_ref::<MalformedKey>().is_some() => {
+            return UploadResponse::err(
+                RejectionCode::NoValidSelfsig,
+                i18n!(i18n.catalog, "This key has no valid identities or subkeys."),
+            )
+        }
+        Err(e) if e.downcast_ref::<InsufficientSpace>().is_some() => {
+            return UploadResponse::err(
+                RejectionCode::InsufficientStorage,
+                i18n!(
+                    i18n.catalog,
+                    "This server is out of space for new keys, please try again later."
+                ),
+            )
+        }
         Err(_) => {
-            return UploadResponse::err(i18n!(i18n.catalog, "Error processing uploaded key."))
+            return UploadResponse::err(
+                RejectionCode::Internal,
+                i18n!(i18n.catalog, "Error processing uploaded key."),
+            )
         }
     };
 
@@ -194,30 +396,44 @@ fn process_key_single(
             requested: vec![],
         }
     };
+    upload_cache.insert(
+        hash,
+        CachedUpload::Single {
+            fpr: verify_state.fpr.clone(),
+            addresses: verify_state.addresses.clone(),
+        },
+    );
 
     let token = tokens_stateless.create(&verify_state);
 
-    show_upload_verify(rate_limiter, token, tpk_status, verify_state, is_new_key)
+    show_upload_verify(
+        rate_limiter,
+        token,
+        tpk_status,
+        verify_state,
+        is_new_key,
+        merge_diff,
+    )
 }
 
 pub fn request_verify(
-    db: &rocket::State<KeyDatabase>,
+    db: &KeyDatabase,
     origin: &RequestOrigin,
-    token_stateful: &rocket::State<StatefulTokens>,
-    token_stateless: &rocket::State<tokens::Service>,
-    mail_service: &rocket::State<mail::Service>,
-    rate_limiter: &rocket::State<RateLimiter>,
+    token_stateful: &StatefulTokens,
+    token_stateless: &tokens::Service,
+    mail_service: &mail::Service,
+    rate_limiter: &RateLimiter,
     i18n: &I18n,
     token: String,
     addresses: Vec<String>,
 ) -> response::UploadResponse {
     let (verify_state, tpk_status) = match check_tpk_state(db, token_stateless, i18n, &token) {
         Ok(ok) => ok,
-        Err(e) => return UploadResponse::err(&e.to_string()),
+        Err(e) => return UploadResponse::err(RejectionCode::InvalidToken, e.to_string()),
     };
 
-    if tpk_status.is_revoked {
-        return show_upload_verify(rate_limiter, token, tpk_status, verify_state, false);
+    if tpk_status.is_revoked || db.uid_policy() != UidPolicy::VerifiedOnly {
+        return show_upload_verify(rate_limiter, token, tpk_status, verify_state, false, None);
     }
 
     let emails_requested: Vec<_> = addresses
@@ -225,6 +441,26 @@ pub fn request_verify(
         .map(|address| address.parse::<Email>())
         .flatten()
         .filter(|email| verify_state.addresses.contains(email))
+        .collect();
+
+    if let Some(email) = emails_requested
+        .iter()
+        .find(|email| db.is_email_banned(email))
+    {
+        return UploadResponse::err(
+            RejectionCode::BannedDomain,
+            i18n!(
+                i18n.catalog,
+                "Addresses in the domain of {} are not accepted by this server.";
+                email
+            ),
+        );
+    }
+
+    let emails_requested: Vec<_> = emails_requested
+        .into_iter()
+        .filter(|email| mail_service.is_domain_allowed(email))
+        .filter(|email| db.is_email_allowed_by_domain(email))
         .filter(|email| {
             tpk_status.email_status.iter().any(|(uid_email, status)| {
                 uid_email == email && *status == EmailAddressStatus::NotPublished
@@ -232,6 +468,20 @@ pub fn request_verify(
         })
         .collect();
 
+    if let Some(email) = emails_requested
+        .iter()
+        .find(|email| !mail_service.accepts_mail(email))
+    {
+        return UploadResponse::err(
+            RejectionCode::NoMailDomain,
+            i18n!(
+                i18n.catalog,
+                "No mail can be delivered to {}, the domain does not exist or accepts no mail.";
+                email
+            ),
+        );
+    }
+
     for email in emails_requested {
         let rate_limit_ok = rate_limiter.action_perform(format!("verify-{}", &email));
         if rate_limit_ok
@@ -245,11 +495,14 @@ pub fn request_verify(
             )
             .is_err()
         {
-            return UploadResponse::err(&format!("error sending email to {}", &email));
+            return UploadResponse::err(
+                RejectionCode::MailFailed,
+                format!("error sending email to {}", &email),
+            );
         }
     }
 
-    show_upload_verify(rate_limiter, token, tpk_status, verify_state, false)
+    show_upload_verify(rate_limiter, token, tpk_status, verify_state, false, None)
 }
 
 fn check_tpk_state(
@@ -292,9 +545,9 @@ fn send_verify_email(
 }
 
 pub fn verify_confirm(
-    db: &rocket::State<KeyDatabase>,
+    db: &KeyDatabase,
     i18n: &I18n,
-    token_service: &rocket::State<StatefulTokens>,
+    token_service: &StatefulTokens,
     token: String,
 ) -> response::PublishResponse {
     let (fingerprint, email) = match check_publish_token(db, token_service, token) {
@@ -308,6 +561,34 @@ pub fn verify_confirm(
     }
 }
 
+/// Returns the unpublished addresses of the key that a verification
+/// email was recently sent to.
+pub fn pending_addresses(
+    db: &KeyDatabase,
+    rate_limiter: &RateLimiter,
+    fpr: &Fingerprint,
+) -> Result<Vec<Email>> {
+    let tpk_full = db
+        .by_fpr_full(fpr)
+        .ok_or_else(|| anyhow!("Key not in database!"))
+        .and_then(|bytes| Cert::from_bytes(bytes.as_bytes()))?;
+    let addresses: Vec<Email> = tpk_full
+        .userids()
+        .flat_map(|binding| Email::try_from(binding.userid()))
+        .collect();
+    let tpk_status = db.get_tpk_status(fpr, &addresses)?;
+
+    Ok(tpk_status
+        .email_status
+        .into_iter()
+        .filter(|(email, status)| {
+            *status == EmailAddressStatus::NotPublished
+                && !rate_limiter.action_check(format!("verify-{}", email))
+        })
+        .map(|(email, _)| email)
+        .collect())
+}
+
 fn check_publish_token(
     db: &KeyDatabase,
     token_service: &StatefulTokens,
@@ -328,6 +609,7 @@ fn show_upload_verify(
     tpk_status: TpkStatus,
     verify_state: VerifyTpkState,
     is_new_key: bool,
+    merge_diff: Option<MergeDiff>,
 ) -> response::UploadResponse {
     let key_fpr = verify_state.fpr.to_string();
     if tpk_status.is_revoked {
@@ -335,6 +617,9 @@ fn show_upload_verify(
             token,
             key_fpr,
             count_unparsed: 0,
+            dropped_uids: vec![],
+            warnings: vec![],
+            merge_diff,
             is_revoked: true,
             status: HashMap::new(),
             is_new_key: false,
@@ -369,11 +654,24 @@ fn show_upload_verify(
         .cloned();
 
     let count_unparsed = tpk_status.unparsed_uids;
+    let dropped_uids = tpk_status
+        .dropped_uids
+        .iter()
+        .map(|email| email.to_string())
+        .collect();
+    let warnings = tpk_status
+        .warnings
+        .iter()
+        .map(|warning| warning.as_str())
+        .collect();
 
     response::UploadResponse::Ok {
         token,
         key_fpr,
         count_unparsed,
+        dropped_uids,
+        warnings,
+        merge_diff,
         is_revoked: false,
         status,
         is_new_key,
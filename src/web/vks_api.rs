@@ -1,18 +1,29 @@
+use qrcode::render::svg;
+use qrcode::{EcLevel, QrCode};
+use ring::digest;
 use rocket::http::{ContentType, Status};
 use rocket::request::Request;
 use rocket::response::{self, Responder, Response};
 use rocket::serde::json::Json;
 use rocket_i18n::{I18n, Translations};
+use sequoia_openpgp::parse::Parse;
+use sequoia_openpgp::serialize::SerializeInto;
+use sequoia_openpgp::Cert;
 use serde_json::json;
+use std::convert::TryFrom;
 use std::io::Cursor;
 
 use crate::database::types::{Email, Fingerprint, KeyID};
-use crate::database::{KeyDatabase, Query, StatefulTokens};
+use crate::database::{Database, KeyDatabase, Query, StatefulTokens, UidPolicy};
+use crate::i18n_helpers::describe_query_error;
 use crate::mail;
 use crate::rate_limiter::RateLimiter;
 use crate::tokens;
+use crate::upload_cache::UploadCache;
 
 use crate::web;
+use crate::web::client_ip::ClientIpHash;
+use crate::web::slow_requests::RequestTimings;
 use crate::web::vks;
 use crate::web::vks::response::*;
 use crate::web::{MyResponse, RequestOrigin};
@@ -20,6 +31,7 @@ use crate::web::{MyResponse, RequestOrigin};
 use rocket::serde::json::Error as JsonError;
 
 pub mod json {
+    use crate::database::{LintIssue, MergeDiff};
     use crate::web::vks::response::EmailStatus;
     use std::collections::HashMap;
 
@@ -40,17 +52,37 @@ pub mod json {
         pub token: String,
         pub key_fpr: String,
         pub status: HashMap<String, EmailStatus>,
+        /// Addresses not published because the key has too many user IDs.
+        #[serde(default)]
+        pub dropped_uids: Vec<String>,
+        /// Machine-readable warnings about the import.
+        #[serde(default)]
+        pub warnings: Vec<String>,
+        /// What the upload changed.
+        #[serde(default)]
+        pub merge_diff: Option<MergeDiff>,
+        /// Which user IDs this instance publishes, see `UidPolicy`.
+        pub uid_policy: String,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct LintResult {
+        pub key_fpr: String,
+        pub issues: Vec<LintIssue>,
     }
 }
 
 type JsonResult = Result<serde_json::Value, JsonErrorResponse>;
 
 #[derive(Debug)]
-pub struct JsonErrorResponse(Status, String);
+pub struct JsonErrorResponse(Status, String, Option<RejectionCode>);
 
 impl<'r> Responder<'r, 'static> for JsonErrorResponse {
     fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
-        let error_json = json!({"error": self.1});
+        let error_json = match self.2 {
+            Some(code) => json!({"error": self.1, "code": code}),
+            None => json!({"error": self.1}),
+        };
         Response::build()
             .status(self.0)
             .sized_body(None, Cursor::new(error_json.to_string()))
@@ -65,25 +97,45 @@ fn json_or_error<T>(data: Result<Json<T>, JsonError>) -> Result<Json<T>, JsonErr
         Err(JsonError::Io(_)) => Err(JsonErrorResponse(
             Status::InternalServerError,
             "i/o error!".to_owned(),
+            None,
         )),
-        Err(JsonError::Parse(_, e)) => Err(JsonErrorResponse(Status::BadRequest, e.to_string())),
+        Err(JsonError::Parse(_, e)) => {
+            Err(JsonErrorResponse(Status::BadRequest, e.to_string(), None))
+        }
     }
 }
 
-fn upload_ok_json(response: UploadResponse) -> Result<serde_json::Value, JsonErrorResponse> {
+fn upload_ok_json(
+    response: UploadResponse,
+    uid_policy: UidPolicy,
+) -> Result<serde_json::Value, JsonErrorResponse> {
     match response {
         UploadResponse::Ok {
             token,
             key_fpr,
             status,
+            dropped_uids,
+            warnings,
+            merge_diff,
             ..
         } => Ok(json!(json::UploadResult {
             token,
             key_fpr,
-            status
+            status,
+            dropped_uids,
+            warnings: warnings.into_iter().map(|w| w.to_owned()).collect(),
+            merge_diff,
+            uid_policy: uid_policy.as_str().to_owned(),
         })),
         UploadResponse::OkMulti { key_fprs } => Ok(json!(key_fprs)),
-        UploadResponse::Error(error) => Err(JsonErrorResponse(Status::BadRequest, error)),
+        UploadResponse::Error(RejectionCode::InsufficientStorage, error) => Err(JsonErrorResponse(
+            Status::InsufficientStorage,
+            error,
+            Some(RejectionCode::InsufficientStorage),
+        )),
+        UploadResponse::Error(code, error) => {
+            Err(JsonErrorResponse(Status::BadRequest, error, Some(code)))
+        }
     }
 }
 
@@ -92,14 +144,24 @@ pub fn upload_json(
     db: &rocket::State<KeyDatabase>,
     tokens_stateless: &rocket::State<tokens::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
+    upload_cache: &rocket::State<UploadCache>,
+    uploader: ClientIpHash,
     i18n: I18n,
     data: Result<Json<json::UploadRequest>, JsonError>,
 ) -> JsonResult {
     let data = json_or_error(data)?;
     use std::io::Cursor;
     let data_reader = Cursor::new(data.keytext.as_bytes());
-    let result = vks::process_key(db, &i18n, tokens_stateless, rate_limiter, data_reader);
-    upload_ok_json(result)
+    let result = vks::process_key(
+        db,
+        &i18n,
+        tokens_stateless,
+        rate_limiter,
+        upload_cache,
+        uploader.0.as_deref(),
+        data_reader,
+    );
+    upload_ok_json(result, db.uid_policy())
 }
 
 #[post("/vks/v1/upload", rank = 2)]
@@ -108,7 +170,7 @@ pub fn upload_fallback(origin: RequestOrigin) -> JsonErrorResponse {
         "expected application/json data. see {}/about/api for api docs.",
         origin.get_base_uri()
     );
-    JsonErrorResponse(Status::BadRequest, error_msg)
+    JsonErrorResponse(Status::BadRequest, error_msg, None)
 }
 
 fn get_locale(langs: &rocket::State<Translations>, locales: Vec<String>) -> I18n {
@@ -143,18 +205,20 @@ pub fn request_verify_json(
         locale,
     } = data.into_inner();
     let i18n = get_locale(langs, locale.unwrap_or_default());
-    let result = vks::request_verify(
-        db,
-        &origin,
-        token_stateful,
-        token_stateless,
-        mail_service,
-        rate_limiter,
-        &i18n,
-        token,
-        addresses,
-    );
-    upload_ok_json(result)
+    let result = web::blocking(|| {
+        vks::request_verify(
+            db,
+            &origin,
+            token_stateful,
+            token_stateless,
+            mail_service,
+            rate_limiter,
+            &i18n,
+            token,
+            addresses,
+        )
+    });
+    upload_ok_json(result, db.uid_policy())
 }
 
 #[post("/vks/v1/request-verify", rank = 2)]
@@ -163,40 +227,221 @@ pub fn request_verify_fallback(origin: RequestOrigin) -> JsonErrorResponse {
         "expected application/json data. see {}/about/api for api docs.",
         origin.get_base_uri()
     );
-    JsonErrorResponse(Status::BadRequest, error_msg)
+    JsonErrorResponse(Status::BadRequest, error_msg, None)
 }
 
-#[get("/vks/v1/by-fingerprint/<fpr>")]
+/// Returns the key, or with `?minimal=1` only its current
+/// components, for clients that care about every byte.
+#[get("/vks/v1/by-fingerprint/<fpr>?<minimal>")]
 pub fn vks_v1_by_fingerprint(
     db: &rocket::State<KeyDatabase>,
+    timings: &RequestTimings,
     i18n: I18n,
     fpr: String,
+    minimal: Option<String>,
 ) -> MyResponse {
     let query = match fpr.parse::<Fingerprint>() {
         Ok(fpr) => Query::ByFingerprint(fpr),
         Err(_) => return MyResponse::bad_request_plain("malformed fingerprint"),
     };
+    if let Some(notice) = db.removal_notice(&query) {
+        return MyResponse::gone_json(notice, &i18n);
+    }
 
-    web::key_to_response_plain(db, i18n, query)
+    match minimal.as_deref() {
+        Some("1") | Some("true") => key_to_response_minimal(db, timings, i18n, query),
+        _ => web::key_to_response_plain(db, timings, i18n, query),
+    }
 }
 
-#[get("/vks/v1/by-email/<email>")]
-pub fn vks_v1_by_email(db: &rocket::State<KeyDatabase>, i18n: I18n, email: String) -> MyResponse {
+fn key_to_response_minimal(
+    db: &rocket::State<KeyDatabase>,
+    timings: &RequestTimings,
+    i18n: I18n,
+    query: Query,
+) -> MyResponse {
+    timings.set_query(&query);
+    let fp = match timings.db("lookup_primary_fingerprint", || {
+        db.lookup_primary_fingerprint(&query)
+    }) {
+        Some(fp) => fp,
+        None => return MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
+    };
+
+    match timings.db("by_fpr_minimal", || db.by_fpr_minimal(&fp)) {
+        Ok(Some(armored)) => MyResponse::key(armored, &fp),
+        Ok(None) => MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
+        Err(e) => MyResponse::ise(e),
+    }
+}
+
+#[get("/vks/v1/lint/<fpr>")]
+pub fn vks_v1_lint(db: &rocket::State<KeyDatabase>, i18n: I18n, fpr: String) -> MyResponse {
+    let query = match fpr.parse::<Fingerprint>() {
+        Ok(fpr) => Query::ByFingerprint(fpr),
+        Err(_) => return MyResponse::bad_request_plain("malformed fingerprint"),
+    };
+
+    let issues = db
+        .lookup_primary_fingerprint(&query)
+        .and_then(|fpr| db.lint(&fpr).map(|issues| (fpr, issues)));
+    match issues {
+        Some((fpr, issues)) => MyResponse::json(json!(json::LintResult {
+            key_fpr: fpr.to_string(),
+            issues,
+        })),
+        None => MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
+    }
+}
+
+/// Parses an address from the path, falling back to the role
+/// address of a bare domain.
+fn email_query(db: &KeyDatabase, email: &str) -> Option<Query> {
     let email = email.replace("%40", "@");
-    let query = match email.parse::<Email>() {
-        Ok(email) => Query::ByEmail(email),
+    match email.parse::<Email>() {
+        Ok(email) => Some(Query::ByEmail(email)),
+        Err(_) => db.domain_role_email(&email).map(Query::ByEmail),
+    }
+}
+
+#[get("/vks/v1/by-email/<email>")]
+pub fn vks_v1_by_email(
+    db: &rocket::State<KeyDatabase>,
+    timings: &RequestTimings,
+    i18n: I18n,
+    email: String,
+) -> MyResponse {
+    let query = match email_query(db, &email) {
+        Some(query) => query,
+        None => return MyResponse::bad_request_plain("malformed e-mail address"),
+    };
+
+    web::key_to_response_plain(db, timings, i18n, query)
+}
+
+#[head("/vks/v1/by-email/<email>")]
+pub fn vks_v1_by_email_head(
+    db: &rocket::State<KeyDatabase>,
+    i18n: I18n,
+    email: String,
+) -> MyResponse {
+    let query = match email_query(db, &email) {
+        Some(query) => query,
+        None => return MyResponse::bad_request_plain("malformed e-mail address"),
+    };
+
+    let modified = db
+        .lookup_primary_fingerprint(&query)
+        .and_then(|fpr| db.published_modified(&fpr).map(|modified| (fpr, modified)));
+    match modified {
+        Some((fpr, modified)) => MyResponse::key_head(&fpr, modified),
+        None => MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
+    }
+}
+
+#[get("/vks/v1/exists/by-email/<email>")]
+pub fn vks_v1_exists_by_email(db: &rocket::State<KeyDatabase>, email: String) -> MyResponse {
+    let query = match email_query(db, &email) {
+        Some(query) => query,
+        None => return MyResponse::bad_request_plain("malformed e-mail address"),
+    };
+
+    MyResponse::exists(db.lookup_primary_fingerprint(&query).is_some())
+}
+
+/// Returns a QR code of the key's `OPENPGP4FPR:` URI, for verifying
+/// fingerprints from a phone.
+#[get("/vks/v1/qr/<fpr>")]
+pub fn vks_v1_qr(db: &rocket::State<KeyDatabase>, i18n: I18n, fpr: String) -> MyResponse {
+    let query = match fpr.parse::<Fingerprint>() {
+        Ok(fpr) => Query::ByFingerprint(fpr),
+        Err(_) => return MyResponse::bad_request_plain("malformed fingerprint"),
+    };
+    let fpr = match db.lookup_primary_fingerprint(&query) {
+        Some(fpr) => fpr,
+        None => return MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
+    };
+
+    let uri = format!("OPENPGP4FPR:{}", fpr);
+    let code = match QrCode::with_error_correction_level(uri, EcLevel::M) {
+        Ok(code) => code,
+        Err(e) => return MyResponse::ise(e.into()),
+    };
+    let image = code.render::<svg::Color>().min_dimensions(200, 200).build();
+    MyResponse::svg(image)
+}
+
+/// Returns the owner name of the OPENPGPKEY record for an address,
+/// see RFC 7929, section 3.
+fn dane_owner_name(email: &Email) -> String {
+    let (localpart, domain) = email.as_str().split_at(email.as_str().rfind('@').unwrap());
+    let hash = digest::digest(&digest::SHA256, localpart.as_bytes());
+    let hex: String = hash.as_ref()[..28]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}._openpgpkey.{}.", hex, &domain[1..])
+}
+
+/// Returns the record data for an address: the minimized key, with
+/// only the user ID carrying the address.
+fn dane_rdata(
+    db: &KeyDatabase,
+    fpr: &Fingerprint,
+    email: &Email,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let armored = match db.by_fpr_minimal(fpr)? {
+        Some(armored) => armored,
+        None => return Ok(None),
+    };
+    let tpk = Cert::from_bytes(armored.as_bytes())?
+        .retain_userids(|uid| Email::try_from(uid.userid()).ok().as_ref() == Some(email));
+    Ok(Some(tpk.to_vec()?))
+}
+
+/// Returns the DNS OPENPGPKEY record for a published address, both
+/// in presentation format and as binary record data.
+#[get("/vks/v1/dane/<email>")]
+pub fn vks_v1_dane(db: &rocket::State<KeyDatabase>, i18n: I18n, email: String) -> MyResponse {
+    let email = match email.replace("%40", "@").parse::<Email>() {
+        Ok(email) => email,
         Err(_) => return MyResponse::bad_request_plain("malformed e-mail address"),
     };
+    let query = Query::ByEmail(email.clone());
+
+    let rdata = match db.lookup_primary_fingerprint(&query) {
+        Some(fpr) => dane_rdata(db, &fpr, &email),
+        None => Ok(None),
+    };
+    let rdata = match rdata {
+        Ok(Some(rdata)) => rdata,
+        Ok(None) => return MyResponse::not_found_plain(describe_query_error(&i18n, &query)),
+        Err(e) => return MyResponse::ise(e),
+    };
 
-    web::key_to_response_plain(db, i18n, query)
+    let name = dane_owner_name(&email);
+    let rdata_base64 = base64::encode(&rdata);
+    let rdata_hex: String = rdata.iter().map(|b| format!("{:02x}", b)).collect();
+    MyResponse::json(json!({
+        "name": name,
+        "type": "OPENPGPKEY",
+        "presentation": format!("{} IN OPENPGPKEY {}", name, rdata_base64),
+        "generic": format!("{} IN TYPE61 \\# {} {}", name, rdata.len(), rdata_hex),
+        "rdata": rdata_base64,
+    }))
 }
 
 #[get("/vks/v1/by-keyid/<kid>")]
-pub fn vks_v1_by_keyid(db: &rocket::State<KeyDatabase>, i18n: I18n, kid: String) -> MyResponse {
+pub fn vks_v1_by_keyid(
+    db: &rocket::State<KeyDatabase>,
+    timings: &RequestTimings,
+    i18n: I18n,
+    kid: String,
+) -> MyResponse {
     let query = match kid.parse::<KeyID>() {
         Ok(keyid) => Query::ByKeyID(keyid),
         Err(_) => return MyResponse::bad_request_plain("malformed key id"),
     };
 
-    web::key_to_response_plain(db, i18n, query)
+    web::key_to_response_plain(db, timings, i18n, query)
 }
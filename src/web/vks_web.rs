@@ -5,43 +5,93 @@ use multipart::server::save::SaveResult::*;
 use multipart::server::Multipart;
 
 use gettext_macros::i18n;
-use rocket::data::ByteUnit;
 use rocket::form::Form;
 use rocket::form::ValueField;
-use rocket::http::ContentType;
+use rocket::http::{Accept, ContentType};
 use rocket::Data;
 use rocket_i18n::I18n;
 use url::percent_encoding::percent_decode;
 
-use crate::database::{Database, KeyDatabase, Query, StatefulTokens};
+use chrono::{DateTime, Utc};
+use sequoia_openpgp::cert::amalgamation::key::ValidErasedKeyAmalgamation;
+use sequoia_openpgp::cert::amalgamation::ValidAmalgamation;
+use sequoia_openpgp::crypto::mpi;
+use sequoia_openpgp::packet::{key, Key};
+use sequoia_openpgp::policy::StandardPolicy;
+use sequoia_openpgp::types::RevocationStatus;
+use sequoia_openpgp::Cert;
+
+use crate::database::types::{Email, Fingerprint};
+use crate::database::{Database, KeyDatabase, Query, Reloadable, StatefulTokens, UidPolicy};
 use crate::i18n_helpers::describe_query_error;
 use crate::mail;
 use crate::rate_limiter::RateLimiter;
 use crate::tokens;
-use crate::web::{MyResponse, RequestOrigin};
+use crate::upload_cache::UploadCache;
+use crate::web::client_ip::ClientIpHash;
+use crate::web::csrf;
+use crate::web::limits::BodyLimits;
+use crate::web::proofs;
+use crate::web::timeouts::Deadline;
+use crate::web::{self, MyResponse, RequestOrigin};
 
-use std::collections::HashMap;
-use std::io::Cursor;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::io::{Cursor, Read};
+use std::time::{Duration, SystemTime};
+use tempfile::NamedTempFile;
 
 use crate::web::vks;
 use crate::web::vks::response::*;
+use crate::web::vks_api;
 
-const UPLOAD_LIMIT: ByteUnit = ByteUnit::Mebibyte(1);
+/// Keys expiring within this period are flagged on the key page.
+const EXPIRY_WARNING_PERIOD: Duration = Duration::from_secs(30 * 24 * 60 * 60);
 
 mod forms {
     #[derive(FromForm, Deserialize)]
     pub struct VerifyRequest {
+        pub csrf: String,
         pub token: String,
         pub address: String,
     }
 
+    #[derive(FromForm)]
+    pub struct VerifyConfirm {
+        pub csrf: String,
+    }
+
     #[derive(Deserialize)]
     pub struct UploadRequest {
         pub keytext: String,
     }
 }
 
+mod json {
+    use crate::database::MergeDiff;
+    use std::collections::BTreeMap;
+
+    #[derive(Serialize)]
+    pub struct UploadStatus {
+        pub key_fpr: String,
+        pub token: String,
+        pub key_status: &'static str,
+        pub status: BTreeMap<String, &'static str>,
+        pub dropped_uids: Vec<String>,
+        pub warnings: Vec<&'static str>,
+        pub merge_diff: Option<MergeDiff>,
+        pub uid_policy: &'static str,
+    }
+
+    #[derive(Serialize)]
+    pub struct UploadMultiple {
+        pub key_fprs: Vec<String>,
+    }
+}
+
 mod template {
+    use crate::web::proofs::IdentityProof;
+
     #[derive(Serialize)]
     pub struct VerifyForm {
         pub token: String,
@@ -52,12 +102,49 @@ mod template {
         pub key_fpr: String,
         pub userid: String,
         pub userid_link: String,
+        pub key_fpr_link: String,
+        pub key_email_link: String,
+        pub pending: Vec<String>,
     }
 
     #[derive(Serialize)]
     pub struct Search {
         pub query: String,
         pub fpr: String,
+        pub algo: String,
+        pub created: String,
+        pub expires: Option<String>,
+        pub addresses: Vec<PublishedAddress>,
+    }
+
+    #[derive(Serialize)]
+    pub struct PublishedAddress {
+        pub address: String,
+        pub is_mixed_script: bool,
+    }
+
+    #[derive(Serialize)]
+    pub struct KeyPage {
+        pub fpr: String,
+        pub primary: KeyDetails,
+        pub subkeys: Vec<KeyDetails>,
+        pub addresses: Vec<PublishedAddress>,
+        pub proofs: Vec<IdentityProof>,
+    }
+
+    #[derive(Serialize)]
+    pub struct KeyDetails {
+        pub fpr: String,
+        pub algo: String,
+        pub created: String,
+        pub expires: Option<String>,
+        pub is_expired: bool,
+        pub is_expiring_soon: bool,
+        pub is_revoked: bool,
+        pub can_certify: bool,
+        pub can_sign: bool,
+        pub can_encrypt: bool,
+        pub can_authenticate: bool,
     }
 
     #[derive(Serialize)]
@@ -72,6 +159,8 @@ mod template {
         pub count_revoked: usize,
         pub count_unparsed_one: bool,
         pub count_unparsed: usize,
+        pub count_dropped_one: bool,
+        pub count_dropped: usize,
     }
 
     #[derive(Serialize)]
@@ -108,8 +197,8 @@ impl MyResponse {
                 "Uploaded {} keys. For verification, please upload keys individually.\n",
                 key_fprs.len()
             )),
-            UploadResponse::Error(error) => {
-                MyResponse::bad_request("400-plain", anyhow!(error), i18n, origin)
+            UploadResponse::Error(code, error) => {
+                MyResponse::upload_rejected("400-plain", code, error, i18n, origin)
             }
         }
     }
@@ -121,6 +210,7 @@ impl MyResponse {
                 key_fpr,
                 is_revoked,
                 count_unparsed,
+                dropped_uids,
                 status,
                 ..
             } => Self::upload_ok(
@@ -128,22 +218,75 @@ impl MyResponse {
                 key_fpr,
                 is_revoked,
                 count_unparsed,
+                dropped_uids.len(),
                 status,
                 i18n,
                 origin,
             ),
             UploadResponse::OkMulti { key_fprs } => Self::upload_ok_multi(key_fprs, i18n, origin),
-            UploadResponse::Error(error) => {
-                MyResponse::bad_request("upload/upload", anyhow!(error), i18n, origin)
+            UploadResponse::Error(code, error) => {
+                MyResponse::upload_rejected("upload/upload", code, error, i18n, origin)
             }
         }
     }
 
+    fn upload_response_json(
+        response: UploadResponse,
+        mail_service: &mail::Service,
+        uid_policy: UidPolicy,
+    ) -> Self {
+        match response {
+            UploadResponse::Ok {
+                token,
+                key_fpr,
+                is_revoked,
+                status,
+                dropped_uids,
+                warnings,
+                merge_diff,
+                ..
+            } => {
+                let status: BTreeMap<_, _> = status
+                    .into_iter()
+                    .map(|(email, status)| {
+                        let status = upload_email_status_json(mail_service, &email, status);
+                        (email, status)
+                    })
+                    .collect();
+                let key_status = if is_revoked {
+                    "revoked"
+                } else if status.values().any(|status| *status == "published") {
+                    "published"
+                } else {
+                    "published-without-identity"
+                };
+                let context = json::UploadStatus {
+                    key_fpr,
+                    token,
+                    key_status,
+                    status,
+                    dropped_uids,
+                    warnings,
+                    merge_diff,
+                    uid_policy: uid_policy.as_str(),
+                };
+                MyResponse::json(serde_json::to_value(context).unwrap())
+            }
+            UploadResponse::OkMulti { key_fprs } => {
+                let context = json::UploadMultiple { key_fprs };
+                MyResponse::json(serde_json::to_value(context).unwrap())
+            }
+            UploadResponse::Error(code, error) => MyResponse::upload_rejected_json(code, error),
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
     fn upload_ok(
         token: String,
         key_fpr: String,
         is_revoked: bool,
         count_unparsed: usize,
+        count_dropped: usize,
         uid_status: HashMap<String, EmailStatus>,
         i18n: I18n,
         origin: RequestOrigin,
@@ -185,6 +328,8 @@ impl MyResponse {
             count_revoked,
             count_unparsed_one: count_unparsed == 1,
             count_unparsed,
+            count_dropped_one: count_dropped == 1,
+            count_dropped,
         };
         MyResponse::ok("upload/upload-ok", context, i18n, origin)
     }
@@ -207,8 +352,44 @@ impl MyResponse {
     }
 }
 
+fn upload_email_status_json(
+    mail_service: &mail::Service,
+    email: &str,
+    status: EmailStatus,
+) -> &'static str {
+    match status {
+        EmailStatus::Published => "published",
+        EmailStatus::Revoked => "filtered",
+        EmailStatus::Unpublished | EmailStatus::Pending => match email.parse() {
+            Ok(email) if !mail_service.is_domain_allowed(&email) => "blocked-domain",
+            Ok(email) if mail_service.is_suppressed(&email) => "suppressed",
+            _ => "pending-verification",
+        },
+    }
+}
+
+fn wants_json(accept: Option<&Accept>) -> bool {
+    accept
+        .map(|accept| accept.preferred().media_type().is_json())
+        .unwrap_or(false)
+}
+
+/// Returns the session whose token an upload form must carry.
+/// Scripts asking for JSON don't have a session, and need none, as
+/// uploading a key takes no privileges.
+fn form_session<'a>(
+    accept: Option<&Accept>,
+    session: &'a csrf::Session,
+) -> Option<&'a csrf::Session> {
+    if wants_json(accept) {
+        None
+    } else {
+        Some(session)
+    }
+}
+
 #[get("/upload")]
-pub fn upload(origin: RequestOrigin, i18n: I18n) -> MyResponse {
+pub fn upload(origin: RequestOrigin, i18n: I18n, _session: csrf::Session) -> MyResponse {
     MyResponse::ok_bare("upload/upload", i18n, origin)
 }
 
@@ -218,12 +399,37 @@ pub async fn upload_post_form_data(
     origin: RequestOrigin,
     tokens_stateless: &rocket::State<tokens::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
+    upload_cache: &rocket::State<UploadCache>,
+    uploader: ClientIpHash,
+    body_limits: &rocket::State<Reloadable<BodyLimits>>,
+    deadline: Deadline,
+    mail_service: &rocket::State<mail::Service>,
     i18n: I18n,
+    accept: Option<&Accept>,
+    session: csrf::Session,
     cont_type: &ContentType,
     data: Data<'_>,
 ) -> MyResponse {
-    match process_upload(db, tokens_stateless, rate_limiter, &i18n, data, cont_type).await {
+    let result = process_upload(
+        db,
+        tokens_stateless,
+        rate_limiter,
+        upload_cache,
+        uploader.0.as_deref(),
+        &body_limits.get(),
+        deadline,
+        &i18n,
+        data,
+        cont_type,
+        form_session(accept, &session),
+    )
+    .await;
+    match result {
+        Ok(response) if wants_json(accept) => {
+            MyResponse::upload_response_json(response, mail_service, db.uid_policy())
+        }
         Ok(response) => MyResponse::upload_response(response, i18n, origin),
+        Err(err) if wants_json(accept) => MyResponse::bad_request_json(err.to_string()),
         Err(err) => MyResponse::bad_request("upload/upload", err, i18n, origin),
     }
 }
@@ -232,11 +438,28 @@ pub async fn process_post_form_data(
     db: &rocket::State<KeyDatabase>,
     tokens_stateless: &rocket::State<tokens::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
-    i18n: I18n,
+    upload_cache: &UploadCache,
+    uploader: Option<&str>,
+    body_limits: &BodyLimits,
+    deadline: Deadline,
+    i18n: &I18n,
     cont_type: &ContentType,
     data: Data<'_>,
 ) -> Result<UploadResponse> {
-    process_upload(db, tokens_stateless, rate_limiter, &i18n, data, cont_type).await
+    process_upload(
+        db,
+        tokens_stateless,
+        rate_limiter,
+        upload_cache,
+        uploader,
+        body_limits,
+        deadline,
+        i18n,
+        data,
+        cont_type,
+        None,
+    )
+    .await
 }
 
 #[get("/search?<q>")]
@@ -246,10 +469,15 @@ pub fn search(
     i18n: I18n,
     q: String,
 ) -> MyResponse {
-    match q.parse::<Query>() {
-        Ok(query) => key_to_response(db, origin, i18n, q, query),
-        Err(e) => MyResponse::bad_request("index", e, i18n, origin),
-    }
+    let query = match q.parse::<Query>() {
+        Ok(Query::Invalid()) => match db.domain_role_email(&q) {
+            Some(email) => Query::ByEmail(email),
+            None => Query::Invalid(),
+        },
+        Ok(query) => query,
+        Err(e) => return MyResponse::bad_request("index", e, i18n, origin),
+    };
+    key_to_response(db, origin, i18n, q, query)
 }
 
 fn key_to_response(
@@ -261,6 +489,8 @@ fn key_to_response(
 ) -> MyResponse {
     let fp = if let Some(fp) = db.lookup_primary_fingerprint(&query) {
         fp
+    } else if let Some(notice) = db.removal_notice(&query) {
+        return MyResponse::gone(notice, i18n, origin);
     } else if query.is_invalid() {
         return MyResponse::bad_request(
             "index",
@@ -272,30 +502,167 @@ fn key_to_response(
         return MyResponse::not_found(None, describe_query_error(&i18n, &query), i18n, origin);
     };
 
+    let cert = match db.lookup(&Query::ByFingerprint(fp.clone())) {
+        Ok(Some(cert)) => cert,
+        Ok(None) => {
+            return MyResponse::not_found(None, describe_query_error(&i18n, &query), i18n, origin)
+        }
+        Err(err) => return MyResponse::ise(err),
+    };
+
+    let policy = StandardPolicy::new();
+    let expires = cert
+        .with_policy(&policy, None)
+        .ok()
+        .and_then(|vcert| vcert.primary_key().key_expiration_time());
+
     let context = template::Search {
         query: query_string,
         fpr: fp.to_string(),
+        algo: describe_algorithm(cert.primary_key().key()),
+        created: format_date(cert.primary_key().creation_time()),
+        expires: expires.map(format_date),
+        addresses: published_addresses(&cert),
     };
 
     MyResponse::ok("found", context, i18n, origin)
 }
 
+#[get("/key/<fpr>")]
+pub fn key_page(
+    db: &rocket::State<KeyDatabase>,
+    origin: RequestOrigin,
+    i18n: I18n,
+    fpr: String,
+) -> MyResponse {
+    let query = match fpr.parse::<Fingerprint>() {
+        Ok(fpr) => Query::ByFingerprint(fpr),
+        Err(e) => return MyResponse::bad_request("index", e, i18n, origin),
+    };
+    if let Some(notice) = db.removal_notice(&query) {
+        return MyResponse::gone(notice, i18n, origin);
+    }
+    let cert = match db.lookup(&query) {
+        Ok(Some(cert)) => cert,
+        Ok(None) => {
+            return MyResponse::not_found(None, describe_query_error(&i18n, &query), i18n, origin)
+        }
+        Err(err) => return MyResponse::ise(err),
+    };
+
+    let policy = StandardPolicy::new();
+    let vcert = match cert.with_policy(&policy, None) {
+        Ok(vcert) => vcert,
+        Err(err) => return MyResponse::ise(err),
+    };
+    let now = SystemTime::now();
+    // Subkeys without a valid binding signature are unusable, and
+    // not shown.
+    let mut keys = vcert.keys().map(|ka| key_details(&ka, now));
+
+    let context = template::KeyPage {
+        fpr: cert.fingerprint().to_hex(),
+        primary: keys.next().expect("primary key is always valid"),
+        subkeys: keys.collect(),
+        addresses: published_addresses(&cert),
+        proofs: proofs::identity_proofs(&cert),
+    };
+
+    MyResponse::ok("key", context, i18n, origin)
+}
+
+fn key_details(
+    ka: &ValidErasedKeyAmalgamation<key::PublicParts>,
+    now: SystemTime,
+) -> template::KeyDetails {
+    let expires = ka.key_expiration_time();
+    template::KeyDetails {
+        fpr: ka.key().fingerprint().to_hex(),
+        algo: describe_algorithm(ka.key()),
+        created: format_date(ka.key().creation_time()),
+        expires: expires.map(format_date),
+        is_expired: expires.map_or(false, |t| t <= now),
+        is_expiring_soon: expires.map_or(false, |t| now < t && t <= now + EXPIRY_WARNING_PERIOD),
+        is_revoked: matches!(ka.revocation_status(), RevocationStatus::Revoked(_)),
+        can_certify: ka.for_certification(),
+        can_sign: ka.for_signing(),
+        can_encrypt: ka.for_transport_encryption() || ka.for_storage_encryption(),
+        can_authenticate: ka.for_authentication(),
+    }
+}
+
+/// Lists the addresses of a published key, whose user ids are all
+/// verified.
+fn published_addresses(cert: &Cert) -> Vec<template::PublishedAddress> {
+    let mut emails: Vec<Email> = cert
+        .userids()
+        .filter_map(|uid| Email::try_from(uid.userid()).ok())
+        .collect();
+    emails.sort();
+    emails.dedup();
+    emails
+        .into_iter()
+        .map(|email| template::PublishedAddress {
+            address: email.to_string(),
+            is_mixed_script: email.is_mixed_script(),
+        })
+        .collect()
+}
+
+/// Describes a key's algorithm, e.g. "EdDSA, Ed25519" or
+/// "RSA (Encrypt or Sign), 4096 bits".
+fn describe_algorithm<P, R>(key: &Key<P, R>) -> String
+where
+    P: key::KeyParts,
+    R: key::KeyRole,
+{
+    match key.mpis() {
+        mpi::PublicKey::EdDSA { curve, .. }
+        | mpi::PublicKey::ECDSA { curve, .. }
+        | mpi::PublicKey::ECDH { curve, .. } => format!("{}, {}", key.pk_algo(), curve),
+        mpis => match mpis.bits() {
+            Some(bits) => format!("{}, {} bits", key.pk_algo(), bits),
+            None => key.pk_algo().to_string(),
+        },
+    }
+}
+
+pub fn format_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format("%Y-%m-%d").to_string()
+}
+
 #[put("/", data = "<data>")]
 pub async fn quick_upload(
     db: &rocket::State<KeyDatabase>,
     tokens_stateless: &rocket::State<tokens::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
+    upload_cache: &rocket::State<UploadCache>,
+    uploader: ClientIpHash,
+    body_limits: &rocket::State<Reloadable<BodyLimits>>,
+    deadline: Deadline,
     i18n: I18n,
     origin: RequestOrigin,
     data: Data<'_>,
 ) -> MyResponse {
-    let buf = match data.open(UPLOAD_LIMIT).into_bytes().await {
-        Ok(buf) => buf.into_inner(),
-        Err(error) => return MyResponse::bad_request("400-plain", anyhow!(error), i18n, origin),
+    let buf = match deadline
+        .read(data.open(body_limits.get().raw_upload()).into_bytes())
+        .await
+    {
+        Ok(buf) if buf.is_complete() => buf.into_inner(),
+        Ok(_) => return MyResponse::upload_response_quick(upload_too_large(&i18n), i18n, origin),
+        Err(error) => return MyResponse::bad_request("400-plain", error, i18n, origin),
     };
 
     MyResponse::upload_response_quick(
-        vks::process_key(db, &i18n, tokens_stateless, rate_limiter, Cursor::new(buf)),
+        vks::process_key(
+            db,
+            &i18n,
+            tokens_stateless,
+            rate_limiter,
+            upload_cache,
+            uploader.0.as_deref(),
+            Cursor::new(buf),
+        ),
         i18n,
         origin,
     )
@@ -310,19 +677,22 @@ pub fn quick_upload_proceed(
     mail_service: &rocket::State<mail::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
     i18n: I18n,
+    _session: csrf::Session,
     token: String,
 ) -> MyResponse {
-    let result = vks::request_verify(
-        db,
-        &origin,
-        token_stateful,
-        token_stateless,
-        mail_service,
-        rate_limiter,
-        &i18n,
-        token,
-        vec![],
-    );
+    let result = web::blocking(|| {
+        vks::request_verify(
+            db,
+            &origin,
+            token_stateful,
+            token_stateless,
+            mail_service,
+            rate_limiter,
+            &i18n,
+            token,
+            vec![],
+        )
+    });
     MyResponse::upload_response(result, i18n, origin)
 }
 
@@ -336,11 +706,35 @@ pub async fn upload_post_form(
     origin: RequestOrigin,
     tokens_stateless: &rocket::State<tokens::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
+    upload_cache: &rocket::State<UploadCache>,
+    uploader: ClientIpHash,
+    body_limits: &rocket::State<Reloadable<BodyLimits>>,
+    deadline: Deadline,
+    mail_service: &rocket::State<mail::Service>,
     i18n: I18n,
+    accept: Option<&Accept>,
+    session: csrf::Session,
     data: Data<'_>,
 ) -> MyResponse {
-    match process_post_form(db, tokens_stateless, rate_limiter, &i18n, data).await {
+    match process_form(
+        db,
+        tokens_stateless,
+        rate_limiter,
+        upload_cache,
+        uploader.0.as_deref(),
+        &body_limits.get(),
+        deadline,
+        &i18n,
+        data,
+        form_session(accept, &session),
+    )
+    .await
+    {
+        Ok(response) if wants_json(accept) => {
+            MyResponse::upload_response_json(response, mail_service, db.uid_policy())
+        }
         Ok(response) => MyResponse::upload_response(response, i18n, origin),
+        Err(err) if wants_json(accept) => MyResponse::bad_request_json(err.to_string()),
         Err(err) => MyResponse::bad_request("upload/upload", err, i18n, origin),
     }
 }
@@ -349,38 +743,99 @@ pub async fn process_post_form(
     db: &KeyDatabase,
     tokens_stateless: &tokens::Service,
     rate_limiter: &RateLimiter,
+    upload_cache: &UploadCache,
+    uploader: Option<&str>,
+    body_limits: &BodyLimits,
+    deadline: Deadline,
     i18n: &I18n,
     data: Data<'_>,
+) -> Result<UploadResponse> {
+    process_form(
+        db,
+        tokens_stateless,
+        rate_limiter,
+        upload_cache,
+        uploader,
+        body_limits,
+        deadline,
+        i18n,
+        data,
+        None,
+    )
+    .await
+}
+
+async fn process_form(
+    db: &KeyDatabase,
+    tokens_stateless: &tokens::Service,
+    rate_limiter: &RateLimiter,
+    upload_cache: &UploadCache,
+    uploader: Option<&str>,
+    body_limits: &BodyLimits,
+    deadline: Deadline,
+    i18n: &I18n,
+    data: Data<'_>,
+    session: Option<&csrf::Session>,
 ) -> Result<UploadResponse> {
     // application/x-www-form-urlencoded
-    let buf = data.open(UPLOAD_LIMIT).into_bytes().await?;
+    let buf = deadline
+        .read(data.open(body_limits.publish_form()).into_bytes())
+        .await?;
+    if !buf.is_complete() {
+        return Ok(upload_too_large(i18n));
+    }
 
+    let mut keytext = None;
+    let mut csrf_token = String::new();
     for ValueField { name, value } in Form::values(&*String::from_utf8_lossy(&buf)) {
         let decoded_value = percent_decode(value.as_bytes())
             .decode_utf8()
             .map_err(|_| anyhow!("`Content-Type: application/x-www-form-urlencoded` not valid"))?;
 
-        if name.to_string().as_str() == "keytext" {
-            return Ok(vks::process_key(
-                db,
-                i18n,
-                tokens_stateless,
-                rate_limiter,
-                Cursor::new(decoded_value.as_bytes()),
-            ));
+        match name.to_string().as_str() {
+            "keytext" if keytext.is_none() => keytext = Some(decoded_value.into_owned()),
+            "csrf" => csrf_token = decoded_value.into_owned(),
+            _ => (),
         }
     }
 
-    Err(anyhow!("No keytext found"))
+    if let Some(session) = session {
+        session.check(&csrf_token, i18n)?;
+    }
+    match keytext {
+        Some(keytext) => Ok(vks::process_key(
+            db,
+            i18n,
+            tokens_stateless,
+            rate_limiter,
+            upload_cache,
+            uploader,
+            Cursor::new(keytext.as_bytes()),
+        )),
+        None => Err(anyhow!("No keytext found")),
+    }
 }
 
+fn upload_too_large(i18n: &I18n) -> UploadResponse {
+    UploadResponse::err(
+        RejectionCode::TooLarge,
+        i18n!(i18n.catalog, "Key data is too large."),
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn process_upload(
     db: &KeyDatabase,
     tokens_stateless: &tokens::Service,
     rate_limiter: &RateLimiter,
+    upload_cache: &UploadCache,
+    uploader: Option<&str>,
+    body_limits: &BodyLimits,
+    deadline: Deadline,
     i18n: &I18n,
     data: Data<'_>,
     cont_type: &ContentType,
+    session: Option<&csrf::Session>,
 ) -> Result<UploadResponse> {
     // multipart/form-data
     let (_, boundary) = cont_type
@@ -393,15 +848,46 @@ async fn process_upload(
             )
         })?;
 
+    // Spool the body to disk as it comes in, so that large uploads
+    // are rejected at the limit without holding them in memory.
+    let spool = NamedTempFile::new()?;
+    let written = deadline
+        .read(
+            data.open(body_limits.publish_form())
+                .into_file(spool.path()),
+        )
+        .await?;
+    if !written.is_complete() {
+        return Ok(upload_too_large(i18n));
+    }
+
     // saves all fields, any field longer than 10kB goes to a temporary directory
     // Entries could implement FromData though that would give zero control over
     // how the files are saved; Multipart would be a good impl candidate though
-    let data = Cursor::new(data.open(UPLOAD_LIMIT).into_bytes().await?.value);
-    match Multipart::with_body(data, boundary).save().temp() {
-        Full(entries) => process_multipart(db, tokens_stateless, rate_limiter, i18n, entries),
-        Partial(partial, _) => {
-            process_multipart(db, tokens_stateless, rate_limiter, i18n, partial.entries)
-        }
+    match Multipart::with_body(spool.reopen()?, boundary)
+        .save()
+        .temp()
+    {
+        Full(entries) => process_multipart(
+            db,
+            tokens_stateless,
+            rate_limiter,
+            upload_cache,
+            uploader,
+            i18n,
+            entries,
+            session,
+        ),
+        Partial(partial, _) => process_multipart(
+            db,
+            tokens_stateless,
+            rate_limiter,
+            upload_cache,
+            uploader,
+            i18n,
+            partial.entries,
+            session,
+        ),
         Error(err) => Err(err.into()),
     }
 }
@@ -410,9 +896,20 @@ fn process_multipart(
     db: &KeyDatabase,
     tokens_stateless: &tokens::Service,
     rate_limiter: &RateLimiter,
+    upload_cache: &UploadCache,
+    uploader: Option<&str>,
     i18n: &I18n,
     entries: Entries,
+    session: Option<&csrf::Session>,
 ) -> Result<UploadResponse> {
+    if let Some(session) = session {
+        let mut csrf_token = String::new();
+        if let Some(field) = entries.fields.get("csrf").and_then(|ent| ent.first()) {
+            field.data.readable()?.read_to_string(&mut csrf_token)?;
+        }
+        session.check(&csrf_token, i18n)?;
+    }
+
     match entries.fields.get("keytext") {
         Some(ent) if ent.len() == 1 => {
             let reader = ent[0].data.readable()?;
@@ -421,6 +918,8 @@ fn process_multipart(
                 i18n,
                 tokens_stateless,
                 rate_limiter,
+                upload_cache,
+                uploader,
                 reader,
             ))
         }
@@ -442,20 +941,30 @@ pub fn request_verify_form(
     mail_service: &rocket::State<mail::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
     i18n: I18n,
+    session: csrf::Session,
     request: Form<forms::VerifyRequest>,
 ) -> MyResponse {
-    let forms::VerifyRequest { token, address } = request.into_inner();
-    let result = vks::request_verify(
-        db,
-        &origin,
-        token_stateful,
-        token_stateless,
-        mail_service,
-        rate_limiter,
-        &i18n,
+    let forms::VerifyRequest {
+        csrf,
         token,
-        vec![address],
-    );
+        address,
+    } = request.into_inner();
+    if let Err(err) = session.check(&csrf, &i18n) {
+        return MyResponse::bad_request("400", err, i18n, origin);
+    }
+    let result = web::blocking(|| {
+        vks::request_verify(
+            db,
+            &origin,
+            token_stateful,
+            token_stateless,
+            mail_service,
+            rate_limiter,
+            &i18n,
+            token,
+            vec![address],
+        )
+    });
     MyResponse::upload_response(result, i18n, origin)
 }
 
@@ -472,41 +981,66 @@ pub fn request_verify_form_data(
     mail_service: &rocket::State<mail::Service>,
     rate_limiter: &rocket::State<RateLimiter>,
     i18n: I18n,
+    session: csrf::Session,
     request: Form<forms::VerifyRequest>,
 ) -> MyResponse {
-    let forms::VerifyRequest { token, address } = request.into_inner();
-    let result = vks::request_verify(
-        db,
-        &origin,
-        token_stateful,
-        token_stateless,
-        mail_service,
-        rate_limiter,
-        &i18n,
+    let forms::VerifyRequest {
+        csrf,
         token,
-        vec![address],
-    );
+        address,
+    } = request.into_inner();
+    if let Err(err) = session.check(&csrf, &i18n) {
+        return MyResponse::bad_request("400", err, i18n, origin);
+    }
+    let result = web::blocking(|| {
+        vks::request_verify(
+            db,
+            &origin,
+            token_stateful,
+            token_stateless,
+            mail_service,
+            rate_limiter,
+            &i18n,
+            token,
+            vec![address],
+        )
+    });
     MyResponse::upload_response(result, i18n, origin)
 }
 
-#[post("/verify/<token>")]
+#[post("/verify/<token>", data = "<request>")]
 pub fn verify_confirm(
     db: &rocket::State<KeyDatabase>,
     origin: RequestOrigin,
     token_service: &rocket::State<StatefulTokens>,
     rate_limiter: &rocket::State<RateLimiter>,
     i18n: I18n,
+    session: csrf::Session,
     token: String,
+    request: Form<forms::VerifyConfirm>,
 ) -> MyResponse {
+    if let Err(err) = session.check(&request.csrf, &i18n) {
+        return MyResponse::bad_request("400", err, i18n, origin);
+    }
     let rate_limit_id = format!("verify-token-{}", &token);
     match vks::verify_confirm(db, &i18n, token_service, token) {
         PublishResponse::Ok { fingerprint, email } => {
             rate_limiter.action_perform(rate_limit_id);
             let userid_link = uri!(search(q = &email)).to_string();
+            let key_fpr_link = uri!(vks_api::vks_v1_by_fingerprint(&fingerprint, _)).to_string();
+            let key_email_link = uri!(vks_api::vks_v1_by_email(&email)).to_string();
+            let pending = fingerprint
+                .parse::<Fingerprint>()
+                .and_then(|fpr| vks::pending_addresses(db, rate_limiter, &fpr))
+                .map(|emails| emails.iter().map(|email| email.to_string()).collect())
+                .unwrap_or_default();
             let context = template::Verify {
                 userid: email,
                 key_fpr: fingerprint,
                 userid_link,
+                key_fpr_link,
+                key_email_link,
+                pending,
             };
 
             MyResponse::ok("upload/publish-result", context, i18n, origin)
@@ -526,7 +1060,12 @@ pub fn verify_confirm(
 }
 
 #[get("/verify/<token>")]
-pub fn verify_confirm_form(origin: RequestOrigin, i18n: I18n, token: String) -> MyResponse {
+pub fn verify_confirm_form(
+    origin: RequestOrigin,
+    i18n: I18n,
+    _session: csrf::Session,
+    token: String,
+) -> MyResponse {
     MyResponse::ok(
         "upload/verification-form",
         template::VerifyForm { token },
@@ -1,6 +1,67 @@
+use std::collections::HashMap;
+
+use rocket::outcome::Outcome;
+use rocket::request;
+
 use crate::database::{Database, KeyDatabase};
 use crate::web::MyResponse;
 
+/// The domains this server is the WKD source of truth for, with the
+/// contents of their policy files.
+///
+/// Requests using the direct method, or sent to the `openpgpkey`
+/// subdomain as in the advanced method, are only answered for these
+/// domains.
+pub struct WkdDomains(HashMap<String, String>);
+
+impl WkdDomains {
+    pub fn new(policies: HashMap<String, String>) -> Self {
+        WkdDomains(
+            policies
+                .into_iter()
+                .map(|(domain, policy)| (domain.to_lowercase(), policy))
+                .collect(),
+        )
+    }
+
+    fn policy(&self, domain: &str) -> String {
+        self.0
+            .get(&domain.to_lowercase())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// Request guard for requests to a hosted domain, or to its
+/// `openpgpkey` subdomain.
+pub struct WkdHost(String);
+
+#[async_trait]
+impl<'r> request::FromRequest<'r> for WkdHost {
+    type Error = ();
+
+    async fn from_request(
+        request: &'r request::Request<'_>,
+    ) -> request::Outcome<Self, Self::Error> {
+        let domains = request.rocket().state::<WkdDomains>().unwrap();
+        let host = request
+            .headers()
+            .get_one("Host")
+            .and_then(|host| host.split(':').next())
+            .unwrap_or_default()
+            .to_lowercase();
+        let domain = host.strip_prefix("openpgpkey.").unwrap_or(&host);
+
+        if domains.0.contains_key(domain) {
+            Outcome::Success(WkdHost(domain.to_owned()))
+        } else if domains.0.contains_key(&host) {
+            Outcome::Success(WkdHost(host))
+        } else {
+            Outcome::Forward(())
+        }
+    }
+}
+
 // WKD queries
 #[get("/.well-known/openpgpkey/<domain>/hu/<wkd_hash>")]
 pub fn wkd_query(db: &rocket::State<KeyDatabase>, domain: String, wkd_hash: String) -> MyResponse {
@@ -10,9 +71,25 @@ pub fn wkd_query(db: &rocket::State<KeyDatabase>, domain: String, wkd_hash: Stri
     }
 }
 
+// WKD queries using the direct method, which has no domain in the
+// path.  Ranked below wkd_policy, which matches the same paths.
+#[get("/.well-known/openpgpkey/hu/<wkd_hash>", rank = 2)]
+pub fn wkd_query_direct(
+    db: &rocket::State<KeyDatabase>,
+    host: WkdHost,
+    wkd_hash: String,
+) -> MyResponse {
+    wkd_query(db, host.0, wkd_hash)
+}
+
 // Policy requests.
-// 200 response with an empty body.
-#[get("/.well-known/openpgpkey/<_domain>/policy")]
-pub fn wkd_policy(_domain: String) -> MyResponse {
-    MyResponse::plain("".to_string())
+// 200 response with the configured policy, empty by default.
+#[get("/.well-known/openpgpkey/<domain>/policy")]
+pub fn wkd_policy(domains: &rocket::State<WkdDomains>, domain: String) -> MyResponse {
+    MyResponse::plain(domains.policy(&domain))
+}
+
+#[get("/.well-known/openpgpkey/policy")]
+pub fn wkd_policy_direct(domains: &rocket::State<WkdDomains>, host: WkdHost) -> MyResponse {
+    MyResponse::plain(domains.policy(&host.0))
 }